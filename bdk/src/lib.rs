@@ -0,0 +1,629 @@
+//! Bitcoin Dev Kit (BDK) blockchain backend.
+//!
+//! [`Backend`] implements BDK's [`Blockchain`] trait using the client's compact filter
+//! sync and rescan engine, giving BDK wallets a self-hosted backend that doesn't rely on
+//! Electrum or Esplora servers.
+//!
+//! Wallet syncs rescan the chain for the wallet's cached scripts, from the wallet's last
+//! sync, or from the backend's birth height for the first sync. Matching blocks are
+//! fetched from the network, and their relevant transactions are stored in the wallet's
+//! database. Transactions confirmed in the scanned range, or in blocks disconnected during
+//! the scan, are first removed from the database, so that re-organized transactions don't
+//! linger.
+//!
+//! When a scan finds a script within [`STOP_GAP`] of the last cached script of a keychain,
+//! and the keychain's descriptor was given with [`Backend::descriptors`], more scripts are
+//! derived and cached, and the scan is repeated. Otherwise, only the scripts cached by the
+//! wallet are scanned for.
+//!
+//! ```no_run
+//! # use nakamoto_client::handle::Handle;
+//! # fn run<H: Handle>(handle: H, wallet: bdk::Wallet<bdk::database::MemoryDatabase>) {
+//! use bdk::KeychainKind;
+//!
+//! let backend = nakamoto_bdk::Backend::new(handle, 0).descriptors(
+//!     wallet.get_descriptor_for_keychain(KeychainKind::External).clone(),
+//!     Some(wallet.get_descriptor_for_keychain(KeychainKind::Internal).clone()),
+//! );
+//!
+//! wallet.sync(&backend, bdk::SyncOptions::default()).unwrap();
+//! # }
+//! ```
+#![deny(missing_docs, unsafe_code)]
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+use crossbeam_channel as chan;
+use thiserror::Error;
+
+use bdk::bitcoin::secp256k1::Secp256k1;
+use bdk::blockchain::{
+    Blockchain, Capability, GetBlockHash, GetHeight, GetTx, Progress, WalletSync,
+};
+use bdk::database::{BatchDatabase, BatchOperations};
+use bdk::descriptor::{AsDerived, ExtendedDescriptor};
+use bdk::miniscript::DescriptorTrait;
+use bdk::{BlockTime, FeeRate, KeychainKind, LocalUtxo, TransactionDetails};
+
+use nakamoto_client::handle::{self, Handle};
+use nakamoto_client::protocol::fees::FeeEstimate;
+use nakamoto_client::Event;
+use nakamoto_common::bitcoin::{BlockHeader, OutPoint, Transaction, Txid};
+use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::error::Classify;
+
+/// Number of blocks before the last sync height that are scanned again on the next sync,
+/// in case they were re-organized.
+pub const REORG_DEPTH: Height = 6;
+/// Default number of unused scripts that must be cached after the last used script of a
+/// keychain.
+pub const STOP_GAP: u32 = 20;
+/// Maximum number of times a scan is repeated after caching more scripts.
+pub const MAX_SCANS: usize = 100;
+
+/// An adapter error.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// A client handle error.
+    #[error("client handle error: {0}")]
+    Handle(#[from] handle::Error),
+    /// A block was not found in the active chain.
+    #[error("block at height {0} not found")]
+    NotFound(Height),
+}
+
+impl Classify for Error {
+    fn code(&self) -> u32 {
+        match self {
+            Self::Handle(err) => err.code(),
+            Self::NotFound(_) => 3029,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Handle(err) => err.is_transient(),
+            Self::NotFound(_) => false,
+        }
+    }
+}
+
+impl From<Error> for bdk::Error {
+    fn from(err: Error) -> Self {
+        bdk::Error::Generic(err.to_string())
+    }
+}
+
+/// A BDK blockchain backend, given a client handle.
+#[derive(Debug)]
+pub struct Backend<H> {
+    handle: H,
+    /// Height from which wallets are scanned on their first sync.
+    birth: Height,
+    /// Number of unused scripts that must be cached after the last used script.
+    stop_gap: u32,
+    /// Descriptors of the wallet's keychains, used to derive more scripts.
+    descriptors: HashMap<KeychainKind, ExtendedDescriptor>,
+    /// Transactions matched or broadcast by this backend.
+    transactions: Mutex<HashMap<Txid, Transaction>>,
+    /// Latest fee estimate, and the height of the block it was computed from.
+    fees: Mutex<Option<(Height, FeeEstimate)>>,
+}
+
+impl<H: Handle> Backend<H> {
+    /// Create a new backend, given a client handle and the height from which wallets
+    /// should be scanned on their first sync, eg. the wallet's birth height.
+    pub fn new(handle: H, birth: Height) -> Self {
+        Self {
+            handle,
+            birth,
+            stop_gap: STOP_GAP,
+            descriptors: HashMap::new(),
+            transactions: Mutex::new(HashMap::new()),
+            fees: Mutex::new(None),
+        }
+    }
+
+    /// Set the number of unused scripts that must be cached after the last used script of
+    /// a keychain. Defaults to [`STOP_GAP`].
+    pub fn stop_gap(mut self, stop_gap: u32) -> Self {
+        self.stop_gap = stop_gap;
+        self
+    }
+
+    /// Set the descriptors of the wallet's external and internal keychains, so that more
+    /// scripts can be derived when scans find scripts near the last cached one.
+    pub fn descriptors(
+        mut self,
+        external: ExtendedDescriptor,
+        internal: Option<ExtendedDescriptor>,
+    ) -> Self {
+        self.descriptors.insert(KeychainKind::External, external);
+        if let Some(internal) = internal {
+            self.descriptors.insert(KeychainKind::Internal, internal);
+        }
+        self
+    }
+
+    /// Rescan the chain for the database's scripts, starting at the given height. Returns
+    /// whether more scripts were cached, in which case the scan should be repeated.
+    fn scan<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        start: Height,
+        progress: &dyn Progress,
+    ) -> Result<bool, bdk::Error> {
+        let (tip, _) = self.handle.get_tip().map_err(Error::from)?;
+        if start > tip {
+            progress.update(100., None)?;
+            return Ok(false);
+        }
+        let scripts = database.iter_script_pubkeys(None)?;
+        let events = self.handle.subscribe();
+        let id = self
+            .handle
+            .rescan(start..=tip, scripts.into_iter())
+            .map_err(Error::from)?;
+
+        // Matched blocks are processed in order once the scan is done, so that outputs are
+        // known by the time they are spent.
+        let mut matched = BTreeMap::new();
+        // Lowest height from which transactions are no longer known to be confirmed.
+        let mut stale = start;
+        let mut completed = false;
+        let mut synced = 0;
+
+        while !completed || synced < tip {
+            match events
+                .recv()
+                .map_err(|e| Error::from(handle::Error::from(e)))?
+            {
+                Event::BlockMatched {
+                    header,
+                    block,
+                    transactions,
+                } if block.height >= start => {
+                    matched.insert(block.height, (header, transactions));
+                }
+                Event::BlockDisconnected { block, .. } => {
+                    matched.retain(|h, _| *h < block.height);
+                    stale = stale.min(block.height);
+                }
+                Event::FeeEstimated { block, fees } => {
+                    let mut latest = self.fees.lock().unwrap();
+
+                    if !matches!(*latest, Some((h, _)) if h > block.height) {
+                        *latest = Some((block.height, fees));
+                    }
+                }
+                Event::RescanCompleted { id: other, .. } if other == id => {
+                    completed = true;
+                }
+                Event::Synced { height, .. } => {
+                    synced = height;
+                    progress.update(
+                        (height.saturating_sub(start) as f32 / (tip - start + 1) as f32 * 100.)
+                            .min(100.),
+                        None,
+                    )?;
+                }
+                _ => {}
+            }
+        }
+
+        // Transactions still confirmed are added back from the matched blocks.
+        disconnect(database, stale)?;
+
+        let mut last = HashMap::new();
+        for (height, (header, transactions)) in matched {
+            for tx in transactions {
+                process(database, &tx, height, &header, &mut last)?;
+                self.transactions.lock().unwrap().insert(tx.txid(), tx);
+            }
+        }
+        for (&keychain, &index) in &last {
+            if !matches!(database.get_last_index(keychain)?, Some(i) if i >= index) {
+                log::debug!("Setting {:?} index to {}", keychain, index);
+                database.set_last_index(keychain, index)?;
+            }
+        }
+        if extend(database, &self.descriptors, &last, self.stop_gap)? {
+            return Ok(true);
+        }
+        progress.update(100., None)?;
+
+        Ok(false)
+    }
+}
+
+impl<H: Handle> Blockchain for Backend<H> {
+    fn get_capabilities(&self) -> HashSet<Capability> {
+        vec![Capability::FullHistory].into_iter().collect()
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<(), bdk::Error> {
+        self.handle
+            .submit_transaction(tx.clone())
+            .map_err(Error::from)?;
+        self.transactions
+            .lock()
+            .unwrap()
+            .insert(tx.txid(), tx.clone());
+
+        Ok(())
+    }
+
+    /// Estimate the fee rate using the fees of the latest block seen during a sync. Targets
+    /// of one block use the block's highest fee rate, targets of up to six blocks use its
+    /// median fee rate, and longer targets its lowest fee rate.
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, bdk::Error> {
+        let rate = match &*self.fees.lock().unwrap() {
+            Some((_, fees)) if target <= 1 => fees.high,
+            Some((_, fees)) if target <= 6 => fees.median,
+            Some((_, fees)) => fees.low,
+            None => return Ok(FeeRate::default_min_relay_fee()),
+        };
+        Ok(FeeRate::from_sat_per_vb(rate as f32))
+    }
+}
+
+impl<H: Handle> GetHeight for Backend<H> {
+    fn get_height(&self) -> Result<u32, bdk::Error> {
+        let (height, _) = self.handle.get_tip().map_err(Error::from)?;
+
+        Ok(height as u32)
+    }
+}
+
+impl<H: Handle> GetTx for Backend<H> {
+    /// Only transactions matched or broadcast by this backend are known.
+    fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, bdk::Error> {
+        Ok(self.transactions.lock().unwrap().get(txid).cloned())
+    }
+}
+
+impl<H: Handle> GetBlockHash for Backend<H> {
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash, bdk::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        let height = height as Height;
+
+        self.handle
+            .query_tree(move |tree| {
+                transmit
+                    .send(tree.get_block_by_height(height).map(|h| h.block_hash()))
+                    .ok();
+            })
+            .map_err(Error::from)?;
+
+        receive
+            .recv()
+            .map_err(|e| Error::from(handle::Error::from(e)))?
+            .ok_or_else(|| Error::NotFound(height).into())
+    }
+}
+
+impl<H: Handle> WalletSync for Backend<H> {
+    fn wallet_setup<D: BatchDatabase>(
+        &self,
+        database: &mut D,
+        progress: Box<dyn Progress>,
+    ) -> Result<(), bdk::Error> {
+        let start = match database.get_sync_time()? {
+            Some(sync) => (sync.block_time.height as Height)
+                .saturating_sub(REORG_DEPTH)
+                .max(self.birth),
+            None => self.birth,
+        };
+        for _ in 0..MAX_SCANS {
+            if !self.scan(database, start, progress.as_ref())? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Cache more scripts for keychains whose last used index is within the stop gap of
+/// their last cached script, if their descriptor is known. Returns whether any scripts
+/// were cached.
+fn extend<D: BatchDatabase>(
+    database: &mut D,
+    descriptors: &HashMap<KeychainKind, ExtendedDescriptor>,
+    last: &HashMap<KeychainKind, u32>,
+    stop_gap: u32,
+) -> Result<bool, bdk::Error> {
+    let secp = Secp256k1::new();
+    let mut extended = false;
+
+    for (keychain, index) in last {
+        let Some(descriptor) = descriptors.get(keychain) else {
+            continue;
+        };
+        if !descriptor.is_deriveable() {
+            continue;
+        }
+        let cached = database.iter_script_pubkeys(Some(*keychain))?.len() as u32;
+        let needed = index + 1 + stop_gap;
+
+        if cached >= needed {
+            continue;
+        }
+        log::debug!(
+            "Caching {:?} scripts {} to {}",
+            keychain,
+            cached,
+            needed - 1
+        );
+        let mut updates = database.begin_batch();
+        for i in cached..needed {
+            let script = descriptor.as_derived(i, &secp).script_pubkey();
+            updates.set_script_pubkey(&script, *keychain, i)?;
+        }
+        database.commit_batch(updates)?;
+        extended = true;
+    }
+    Ok(extended)
+}
+
+/// Remove the transactions confirmed at or above the given height from the database, along
+/// with their outputs. Our outputs they spent are unspent again.
+fn disconnect<D: BatchDatabase>(database: &mut D, height: Height) -> Result<(), bdk::Error> {
+    let mut stale = database
+        .iter_txs(true)?
+        .into_iter()
+        .filter(|tx| matches!(tx.confirmation_time, Some(ref t) if t.height as Height >= height))
+        .collect::<Vec<_>>();
+    // Later transactions are removed first, since they may spend outputs of earlier ones.
+    stale.sort_by_key(|tx| std::cmp::Reverse(tx.confirmation_time.as_ref().map(|t| t.height)));
+
+    for details in stale {
+        let txid = details.txid;
+        let mut updates = database.begin_batch();
+
+        log::debug!("Removing transaction {} from disconnected blocks", txid);
+
+        if let Some(tx) = details.transaction {
+            for input in &tx.input {
+                if let Some(utxo) = database.get_utxo(&input.previous_output)? {
+                    updates.set_utxo(&LocalUtxo {
+                        is_spent: false,
+                        ..utxo
+                    })?;
+                }
+            }
+            for vout in 0..tx.output.len() {
+                updates.del_utxo(&OutPoint::new(txid, vout as u32))?;
+            }
+        }
+        updates.del_tx(&txid, true)?;
+        database.commit_batch(updates)?;
+    }
+    Ok(())
+}
+
+/// Store a confirmed transaction in the database, if it is relevant to the wallet. Updates
+/// the last derivation index used by each keychain.
+fn process<D: BatchDatabase>(
+    database: &mut D,
+    tx: &Transaction,
+    height: Height,
+    header: &BlockHeader,
+    last: &mut HashMap<KeychainKind, u32>,
+) -> Result<(), bdk::Error> {
+    let txid = tx.txid();
+    let mut updates = database.begin_batch();
+    let (mut received, mut sent) = (0, 0);
+    let (mut inputs, mut outputs) = (0, 0);
+    // Whether all spent outputs are known, and thus the fee.
+    let mut complete = true;
+
+    for input in &tx.input {
+        let Some(prevout) = database
+            .get_raw_tx(&input.previous_output.txid)?
+            .and_then(|tx| tx.output.get(input.previous_output.vout as usize).cloned())
+        else {
+            complete = false;
+            continue;
+        };
+        inputs += prevout.value;
+
+        if let Some((keychain, _)) = database.get_path_from_script_pubkey(&prevout.script_pubkey)? {
+            sent += prevout.value;
+            updates.set_utxo(&LocalUtxo {
+                outpoint: input.previous_output,
+                txout: prevout,
+                keychain,
+                is_spent: true,
+            })?;
+        }
+    }
+    for (vout, output) in tx.output.iter().enumerate() {
+        outputs += output.value;
+
+        if let Some((keychain, index)) =
+            database.get_path_from_script_pubkey(&output.script_pubkey)?
+        {
+            received += output.value;
+            updates.set_utxo(&LocalUtxo {
+                outpoint: OutPoint::new(txid, vout as u32),
+                txout: output.clone(),
+                keychain,
+                is_spent: false,
+            })?;
+            last.entry(keychain)
+                .and_modify(|i| *i = (*i).max(index))
+                .or_insert(index);
+        }
+    }
+
+    if received > 0 || sent > 0 {
+        log::debug!("Saving transaction {} confirmed at height {}", txid, height);
+
+        updates.set_tx(&TransactionDetails {
+            txid,
+            transaction: Some(tx.clone()),
+            received,
+            sent,
+            confirmation_time: BlockTime::new(Some(height as u32), Some(header.time as u64)),
+            fee: complete.then(|| inputs.saturating_sub(outputs)),
+        })?;
+    }
+    database.commit_batch(updates)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bdk::database::{Database, MemoryDatabase};
+    use nakamoto_common::bitcoin::blockdata::constants;
+    use nakamoto_common::bitcoin::{Network, Script, TxIn, TxOut, Witness};
+
+    fn transaction(inputs: Vec<OutPoint>, outputs: Vec<(u64, Script)>) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: inputs
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    script_sig: Script::new(),
+                    sequence: 0xffffffff,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: outputs
+                .into_iter()
+                .map(|(value, script_pubkey)| TxOut {
+                    value,
+                    script_pubkey,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_process() {
+        let header = constants::genesis_block(Network::Regtest).header;
+        let ours = Script::from(vec![0x51]);
+        let change = Script::from(vec![0x52]);
+        let theirs = Script::from(vec![0x53]);
+        let mut database = MemoryDatabase::new();
+        let mut last = HashMap::new();
+
+        database
+            .set_script_pubkey(&ours, KeychainKind::External, 3)
+            .unwrap();
+        database
+            .set_script_pubkey(&change, KeychainKind::Internal, 1)
+            .unwrap();
+
+        // An unrelated transaction is ignored.
+        let unrelated = transaction(vec![OutPoint::default()], vec![(1, theirs.clone())]);
+        process(&mut database, &unrelated, 1, &header, &mut last).unwrap();
+        assert!(database.iter_txs(false).unwrap().is_empty());
+
+        // Funds received from an unknown output.
+        let funding = transaction(vec![OutPoint::default()], vec![(10_000, ours)]);
+        let outpoint = OutPoint::new(funding.txid(), 0);
+        process(&mut database, &funding, 2, &header, &mut last).unwrap();
+
+        let details = database.get_tx(&funding.txid(), false).unwrap().unwrap();
+        assert_eq!(details.received, 10_000);
+        assert_eq!(details.sent, 0);
+        assert_eq!(details.fee, None);
+        assert_eq!(details.confirmation_time.unwrap().height, 2);
+        assert!(!database.get_utxo(&outpoint).unwrap().unwrap().is_spent);
+
+        // Funds sent, with change.
+        let spending = transaction(vec![outpoint], vec![(6_000, theirs), (3_000, change)]);
+        process(&mut database, &spending, 3, &header, &mut last).unwrap();
+
+        let details = database.get_tx(&spending.txid(), false).unwrap().unwrap();
+        assert_eq!(details.received, 3_000);
+        assert_eq!(details.sent, 10_000);
+        assert_eq!(details.fee, Some(1_000));
+        assert!(database.get_utxo(&outpoint).unwrap().unwrap().is_spent);
+
+        assert_eq!(last.get(&KeychainKind::External), Some(&3));
+        assert_eq!(last.get(&KeychainKind::Internal), Some(&1));
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let header = constants::genesis_block(Network::Regtest).header;
+        let ours = Script::from(vec![0x51]);
+        let theirs = Script::from(vec![0x53]);
+        let mut database = MemoryDatabase::new();
+        let mut last = HashMap::new();
+
+        database
+            .set_script_pubkey(&ours, KeychainKind::External, 0)
+            .unwrap();
+
+        let funding = transaction(vec![OutPoint::default()], vec![(10_000, ours)]);
+        let outpoint = OutPoint::new(funding.txid(), 0);
+        let spending = transaction(vec![outpoint], vec![(9_000, theirs)]);
+
+        process(&mut database, &funding, 2, &header, &mut last).unwrap();
+        process(&mut database, &spending, 3, &header, &mut last).unwrap();
+        assert!(database.get_utxo(&outpoint).unwrap().unwrap().is_spent);
+
+        // The spending transaction's block is disconnected.
+        disconnect(&mut database, 3).unwrap();
+        assert!(database.get_tx(&spending.txid(), false).unwrap().is_none());
+        assert!(database.get_tx(&funding.txid(), false).unwrap().is_some());
+        assert!(!database.get_utxo(&outpoint).unwrap().unwrap().is_spent);
+
+        // Both blocks are disconnected.
+        process(&mut database, &spending, 3, &header, &mut last).unwrap();
+        disconnect(&mut database, 2).unwrap();
+        assert!(database.iter_txs(false).unwrap().is_empty());
+        assert!(database.iter_utxos().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_extend() {
+        let descriptor: ExtendedDescriptor = "wpkh(tpubD6NzVbkrYhZ4XHndKkuB8FifXm8r5FQHwrN6oZuWCz13qb93rtgKvD4PQsqC4HP4yhV3tA2fqr2RbY5mNXfM7RxXUoeABoDtsFUq2zJq6YK/0/*)"
+            .parse()
+            .unwrap();
+        let descriptors = HashMap::from([(KeychainKind::External, descriptor.clone())]);
+        let secp = Secp256k1::new();
+        let mut database = MemoryDatabase::new();
+
+        for i in 0..10 {
+            let script = descriptor.as_derived(i, &secp).script_pubkey();
+            database
+                .set_script_pubkey(&script, KeychainKind::External, i)
+                .unwrap();
+        }
+        let scripts = |database: &MemoryDatabase| {
+            database
+                .iter_script_pubkeys(Some(KeychainKind::External))
+                .unwrap()
+                .len()
+        };
+
+        // Scripts far from the last cached one don't need more to be cached.
+        let last = HashMap::from([(KeychainKind::External, 2)]);
+        assert!(!extend(&mut database, &descriptors, &last, 5).unwrap());
+        assert_eq!(scripts(&database), 10);
+
+        // Scripts near the last cached one do.
+        let last = HashMap::from([(KeychainKind::External, 7)]);
+        assert!(extend(&mut database, &descriptors, &last, 5).unwrap());
+        assert_eq!(scripts(&database), 13);
+        assert_eq!(
+            database
+                .get_path_from_script_pubkey(&descriptor.as_derived(12, &secp).script_pubkey())
+                .unwrap(),
+            Some((KeychainKind::External, 12))
+        );
+
+        // Keychains without a known descriptor are left alone.
+        let last = HashMap::from([(KeychainKind::Internal, 0)]);
+        assert!(!extend(&mut database, &descriptors, &last, 5).unwrap());
+    }
+}
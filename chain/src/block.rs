@@ -1,5 +1,6 @@
 //! Block and blockchain related functionality.
 pub mod cache;
+pub mod pow;
 pub mod store;
 
 pub use nakamoto_common::bitcoin::blockdata::block::{Block, BlockHeader};
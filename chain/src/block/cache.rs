@@ -8,7 +8,8 @@
 pub mod test;
 
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 
 use nakamoto_common::bitcoin;
 use nakamoto_common::bitcoin::blockdata::block::BlockHeader;
@@ -28,12 +29,16 @@ use nakamoto_common::block::{
 };
 use nakamoto_common::nonempty::NonEmpty;
 
+use super::pow::{self, Verifier};
+
 /// A block that is being stored by the block cache.
 #[derive(Debug, Clone, Copy)]
 struct CachedBlock {
     pub height: Height,
     pub hash: BlockHash,
     pub header: BlockHeader,
+    /// Total work of the chain, up to and including this block.
+    pub chain_work: Work,
 }
 
 impl std::ops::Deref for CachedBlock {
@@ -72,6 +77,7 @@ pub struct BlockCache<S: Store> {
     orphans: HashMap<BlockHash, BlockHeader>,
     checkpoints: BTreeMap<Height, BlockHash>,
     params: Params,
+    verifier: Arc<dyn Verifier>,
     store: S,
 }
 
@@ -92,6 +98,7 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
                 height: 0,
                 hash: genesis.block_hash(),
                 header: genesis,
+                chain_work: genesis.work(),
             },
             Vec::with_capacity(length - 1),
         ));
@@ -104,6 +111,7 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             headers,
             orphans,
             params,
+            verifier: Arc::new(pow::Sequential),
             checkpoints,
             store,
         };
@@ -121,6 +129,62 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         Ok(cache)
     }
 
+    /// Use the given proof-of-work verifier. Headers are verified with
+    /// [`pow::Sequential`] by default.
+    pub fn verifier(mut self, verifier: Arc<dyn Verifier>) -> Self {
+        self.verifier = verifier;
+        self
+    }
+
+    /// Verify the integrity of a header store, before loading it. Checks that all stored
+    /// headers connect and match the checkpoints, and that the headers at the sampled
+    /// heights have valid proof-of-work.
+    ///
+    /// Returns the height of the first invalid header along with the reason it is invalid,
+    /// or `None` if the store is consistent. The store can be rolled back to the height
+    /// before it and loaded.
+    pub fn verify(
+        store: &S,
+        params: &Params,
+        checkpoints: &[(Height, BlockHash)],
+        sample: &HashSet<Height>,
+    ) -> Result<Option<(Height, Error)>, Error> {
+        let checkpoints = checkpoints.iter().cloned().collect::<HashMap<_, _>>();
+        let mut prev = store.genesis().block_hash();
+
+        for (result, expected) in store.iter().skip(1).zip(1..) {
+            let (height, header) = match result {
+                Ok(result) => result,
+                Err(err) => return Ok(Some((expected, err.into()))),
+            };
+            let hash = header.block_hash();
+
+            if header.prev_blockhash != prev {
+                return Ok(Some((height, Error::BlockMissing(header.prev_blockhash))));
+            }
+            if let Some(checkpoint) = checkpoints.get(&height) {
+                if &hash != checkpoint {
+                    return Ok(Some((height, Error::InvalidBlockHash(hash, height))));
+                }
+            }
+            if sample.contains(&height) {
+                let target = header.target();
+
+                if target > params.pow_limit {
+                    return Ok(Some((
+                        height,
+                        Error::InvalidBlockTarget(target, params.pow_limit),
+                    )));
+                }
+                if header.validate_pow(&target).is_err() {
+                    return Ok(Some((height, Error::InvalidBlockPoW)));
+                }
+            }
+            prev = hash;
+        }
+        Ok(None)
+    }
+
     /// Iterate over a range of blocks.
     ///
     /// # Errors
@@ -169,10 +233,22 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
 
     /// Import a block into the tree. Performs header validation. This function may trigger
     /// a chain re-org.
+    #[cfg(test)]
     fn import_block(
         &mut self,
         header: BlockHeader,
         clock: &impl Clock,
+    ) -> Result<ImportResult, Error> {
+        self.import(header, clock, false)
+    }
+
+    /// Import a block into the tree. If `verified` is set, the block's proof-of-work was
+    /// already verified against its own target, eg. as part of a batch.
+    fn import(
+        &mut self,
+        header: BlockHeader,
+        clock: &impl Clock,
+        verified: bool,
     ) -> Result<ImportResult, Error> {
         let hash = header.block_hash();
         let tip = self.chain.last();
@@ -192,24 +268,9 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         // is greater than the minimum allowed for this network.
         //
         // We do this because it's cheap to verify and prevents flooding attacks.
-        let target = header.target();
-        match header.validate_pow(&target) {
-            Ok(_) => {
-                let limit = self.params.pow_limit;
-                if target > limit {
-                    return Err(Error::InvalidBlockTarget(target, limit));
-                }
-            }
-            Err(bitcoin::util::Error::BlockBadProofOfWork) => {
-                return Err(Error::InvalidBlockPoW);
-            }
-            Err(bitcoin::util::Error::BlockBadTarget) => unreachable! {
-                // The only way to get a 'bad target' error is to pass a different target
-                // than the one specified in the header.
-            },
-            Err(_) => unreachable! {
-                // We've handled all possible errors above.
-            },
+        if !verified {
+            self.verifier
+                .verify(&header, &header.target(), &self.params.pow_limit)?;
         }
 
         if let Some(height) = self.headers.get(&header.prev_blockhash) {
@@ -374,6 +435,7 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             height: candidate.fork_height,
             hash: candidate.fork_hash,
             header: candidate.fork_header,
+            chain_work: self.chain_work(candidate.fork_height),
         };
 
         for header in candidate.headers.iter() {
@@ -383,6 +445,7 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
                 height: tip.height + 1,
                 hash: header.block_hash(),
                 header: *header,
+                chain_work: tip.chain_work + header.work(),
             };
         }
         Ok(())
@@ -401,9 +464,9 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             && (tip.height + 1) % self.params.difficulty_adjustment_interval() != 0
         {
             if header.time > tip.time + self.params.pow_target_spacing as BlockTime * 2 {
-                block::pow_limit_bits(&self.params.network)
+                block::pow_limit_bits(&self.params)
             } else {
-                self.next_min_difficulty_target(&self.params)
+                self.next_min_difficulty_target(tip.height, &self.params)
             }
         } else {
             self.next_difficulty_target(tip.height, tip.time, tip.target(), &self.params)
@@ -446,13 +509,16 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         Ok(())
     }
 
-    /// Get the next minimum-difficulty target. Only valid in testnet and regtest networks.
-    fn next_min_difficulty_target(&self, params: &Params) -> Bits {
+    /// Get the next minimum-difficulty target, for a block building on the given height.
+    /// Only valid in testnet and regtest networks.
+    fn next_min_difficulty_target(&self, from: Height, params: &Params) -> Bits {
         assert!(params.allow_min_difficulty_blocks);
 
-        let pow_limit_bits = block::pow_limit_bits(&params.network);
+        let pow_limit_bits = block::pow_limit_bits(params);
+        // Skip the blocks above the given height, if any.
+        let skip = self.height().saturating_sub(from) as usize;
 
-        for (height, header) in self.iter().rev() {
+        for (height, header) in self.iter().rev().skip(skip) {
             if header.bits != pow_limit_bits
                 || height % self.params.difficulty_adjustment_interval() == 0
             {
@@ -499,10 +565,13 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
 
         self.headers.insert(hash, height);
         self.orphans.remove(&hash);
+        let chain_work = self.chain.last().chain_work + header.work();
+
         self.chain.push(CachedBlock {
             height,
             hash,
             header,
+            chain_work,
         });
     }
 
@@ -526,8 +595,16 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
         let mut best_hash = self.chain.last().hash;
         let mut best_header = self.chain.last().header;
 
-        for (i, header) in chain.enumerate() {
-            match self.import_block(header, context) {
+        // Verify the PoW of all blocks in one batch, before importing any of them. Blocks
+        // before the first invalid one are still imported.
+        let chain = chain.collect::<Vec<_>>();
+        let (chain, invalid) = match self.verifier.verify_batch(&chain, &self.params.pow_limit) {
+            Ok(()) => (chain.as_slice(), None),
+            Err((i, err)) => (&chain[..i], Some((i, err))),
+        };
+
+        for (i, header) in chain.iter().copied().enumerate() {
+            match self.import(header, context, true) {
                 Ok(ImportResult::TipChanged(header, hash, height, r, c)) => {
                     seen.extend(c.iter().map(|(_, h)| h.block_hash()));
                     reverted.extend(r.into_iter().map(|(i, h)| ((i, h.block_hash()), h)));
@@ -543,6 +620,9 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
                 Err(err) => return Err(Error::BlockImportAborted(err.into(), i, self.height())),
             }
         }
+        if let Some((i, err)) = invalid {
+            return Err(Error::BlockImportAborted(err.into(), i, self.height()));
+        }
 
         if !connected.is_empty() {
             // Don't return reverted blocks if they were seen as connected at some point, since
@@ -596,6 +676,66 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
             Ok(ImportResult::TipUnchanged)
         }
     }
+
+    /// Revalidate an active chain block, and repair its index and stored header.
+    fn revalidate<C: Clock>(&mut self, height: Height, clock: &C) -> Result<bool, Error> {
+        // The genesis block is not validated, nor stored.
+        if height == 0 {
+            return Ok(false);
+        }
+        let (parent, block) = match (
+            self.chain.get(height as usize - 1),
+            self.chain.get(height as usize),
+        ) {
+            (Some(parent), Some(block)) => (*parent, *block),
+            _ => return Err(Error::InvalidBlockHeight(height)),
+        };
+        if block.prev_blockhash != parent.hash {
+            return Err(Error::BlockMissing(block.prev_blockhash));
+        }
+        self.validate(&parent, &block.header, clock)?;
+
+        let hash = block.header.block_hash();
+        let mut repaired = false;
+
+        if block.hash != hash || self.headers.get(&hash) != Some(&height) {
+            self.headers.remove(&block.hash);
+            self.headers.insert(hash, height);
+            self.chain.tail[height as usize - 1].hash = hash;
+
+            repaired = true;
+        }
+
+        match self.store.get(height) {
+            Ok(header) if header == block.header => {}
+            _ => {
+                // Re-write the store from the first mismatching header.
+                self.store.rollback(height - 1)?;
+                self.store.put(
+                    self.chain.tail[height as usize - 1..]
+                        .iter()
+                        .map(|b| b.header),
+                )?;
+
+                repaired = true;
+            }
+        }
+        Ok(repaired)
+    }
+
+    /// Truncate the active chain, discarding the blocks above the given height.
+    fn truncate(&mut self, height: Height) -> Result<Vec<(Height, BlockHeader)>, Error> {
+        let mut discarded = Vec::new();
+
+        for (block, height) in self.chain.tail.drain(height as usize..).zip(height + 1..) {
+            self.headers.remove(&block.hash);
+            discarded.push((height, block.header));
+        }
+        self.store.rollback(height)?;
+        discarded.reverse();
+
+        Ok(discarded)
+    }
 }
 
 impl<S: Store<Header = BlockHeader>> BlockReader for BlockCache<S> {
@@ -612,6 +752,11 @@ impl<S: Store<Header = BlockHeader>> BlockReader for BlockCache<S> {
         self.chain.get(height as usize).map(|b| &b.header)
     }
 
+    /// Get the consensus parameters of the chain.
+    fn params(&self) -> &Params {
+        &self.params
+    }
+
     /// Find a branch.
     fn find_branch(&self, to: &BlockHash) -> Option<(Height, NonEmpty<BlockHeader>)> {
         // Check active chain first. If there's a match, the path to return is just the block
@@ -668,6 +813,43 @@ impl<S: Store<Header = BlockHeader>> BlockReader for BlockCache<S> {
         self.chain.last().height
     }
 
+    /// Get the total work of the active chain, up to and including the given height.
+    /// Cached for every block, so it doesn't have to be summed.
+    fn chain_work(&self, height: Height) -> Work {
+        self.chain
+            .get(height as usize)
+            .unwrap_or_else(|| self.chain.last())
+            .chain_work
+    }
+
+    /// Get the known forks off the active chain. Each stale block that doesn't have
+    /// a known child is the tip of a fork.
+    fn forks(&self) -> Vec<tree::Fork> {
+        let parents = self
+            .orphans
+            .values()
+            .map(|h| h.prev_blockhash)
+            .collect::<BTreeSet<_>>();
+
+        self.orphans
+            .keys()
+            .filter(|hash| !parents.contains(*hash))
+            .filter_map(|tip| self.fork(tip))
+            .map(|candidate| {
+                let branch = Branch(&candidate.headers);
+                let header = *candidate.headers.last().expect("branches are never empty");
+
+                tree::Fork {
+                    tip: candidate.tip,
+                    header,
+                    height: candidate.fork_height + candidate.headers.len() as Height,
+                    fork_height: candidate.fork_height,
+                    work: self.chain_work(candidate.fork_height) + branch.work(),
+                }
+            })
+            .collect()
+    }
+
     /// Get the height of the last checkpoint block.
     fn last_checkpoint(&self) -> Height {
         let height = self.height();
@@ -704,6 +886,7 @@ impl<S: Store<Header = BlockHeader>> BlockReader for BlockCache<S> {
     ///   alone.
     /// * When locators *are* provided, but none of them are known, it is equivalent to having
     ///   the genesis hash as locator.
+    /// * Locators on known forks of the active chain are treated as their fork point.
     ///
     fn locate_headers(
         &self,
@@ -718,14 +901,12 @@ impl<S: Store<Header = BlockHeader>> BlockReader for BlockCache<S> {
             return vec![];
         }
 
-        // Start from the highest locator hash that is on our active chain.
-        // We don't respond with anything if none of the locators were found.
-        let start = if let Some(hash) = locators.iter().find(|h| self.contains(h)) {
-            let (height, _) = self.get_block(hash).unwrap();
-            height
-        } else {
-            0
-        };
+        // Start from the highest locator hash that is on our active chain, or from where
+        // it forks off our active chain, if it's on a known fork.
+        let start = locators
+            .iter()
+            .find_map(|h| self.common_ancestor(h))
+            .map_or(0, |(height, _)| height);
 
         let start = start + 1;
         let stop = self
@@ -1,8 +1,8 @@
 use super::BlockCache;
 
 use nakamoto_common::block::time::{AdjustedTime, Clock, LocalTime};
-use nakamoto_common::block::tree::{BlockReader, BlockTree, Error, ImportResult};
-use nakamoto_common::block::{BlockTime, Height, Target};
+use nakamoto_common::block::tree::{self, BlockReader, BlockTree, Error, ImportResult};
+use nakamoto_common::block::{BlockTime, Height, Target, Work};
 use nakamoto_common::nonempty::NonEmpty;
 
 use nakamoto_test::assert_matches;
@@ -11,7 +11,7 @@ use nakamoto_test::block::cache::model;
 
 use crate::block::store::{self, Store};
 
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::iter;
 use std::net;
 use std::sync::{Arc, RwLock};
@@ -80,9 +80,21 @@ impl BlockTree for HeightCache {
     fn extend_tip<C>(&mut self, _header: BlockHeader, _context: &C) -> Result<ImportResult, Error> {
         unimplemented!()
     }
+
+    fn revalidate<C>(&mut self, _height: Height, _context: &C) -> Result<bool, Error> {
+        unimplemented!()
+    }
+
+    fn truncate(&mut self, _height: Height) -> Result<Vec<(Height, BlockHeader)>, Error> {
+        unimplemented!()
+    }
 }
 
 impl BlockReader for HeightCache {
+    fn params(&self) -> &Params {
+        unimplemented!()
+    }
+
     fn get_block(&self, _hash: &BlockHash) -> Option<(Height, &BlockHeader)> {
         unimplemented!()
     }
@@ -95,6 +107,10 @@ impl BlockReader for HeightCache {
         unimplemented!()
     }
 
+    fn forks(&self) -> Vec<tree::Fork> {
+        unimplemented!()
+    }
+
     fn last_checkpoint(&self) -> Height {
         0
     }
@@ -552,6 +568,105 @@ fn test_from_store() {
     }
 }
 
+#[test]
+fn test_verify() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let headers = store::File::open(&*nakamoto_test::headers::PATH, genesis)
+        .unwrap()
+        .iter()
+        .map(|r| r.map(|(_, h)| h))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let height = headers.len() as Height - 1;
+    let sample = (0..=height).collect::<HashSet<_>>();
+
+    let store = store::Memory::new(NonEmpty::from_vec(headers.clone()).unwrap());
+    assert_matches!(BlockCache::verify(&store, &params, &[], &sample), Ok(None));
+
+    // A checkpoint that doesn't match.
+    assert_matches!(
+        BlockCache::verify(&store, &params, &[(9, BlockHash::default())], &sample),
+        Ok(Some((9, Error::InvalidBlockHash(_, 9))))
+    );
+
+    // A header that doesn't connect.
+    let mut broken = headers.clone();
+    broken.remove(12);
+    let store = store::Memory::new(NonEmpty::from_vec(broken).unwrap());
+    assert_matches!(
+        BlockCache::verify(&store, &params, &[], &sample),
+        Ok(Some((12, Error::BlockMissing(_))))
+    );
+
+    // A header with invalid proof-of-work is only found if sampled.
+    let mut broken = headers;
+    broken[height as usize].nonce += 1;
+    let store = store::Memory::new(NonEmpty::from_vec(broken).unwrap());
+    assert_matches!(
+        BlockCache::verify(&store, &params, &[], &HashSet::new()),
+        Ok(None)
+    );
+    assert_matches!(
+        BlockCache::verify(&store, &params, &[], &sample),
+        Ok(Some((h, Error::InvalidBlockPoW))) if h == height
+    );
+}
+
+#[test]
+fn test_revalidate() {
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let headers = store::File::open(&*nakamoto_test::headers::PATH, genesis)
+        .unwrap()
+        .iter()
+        .map(|r| r.map(|(_, h)| h))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let store = store::Memory::new(NonEmpty::from_vec(headers).unwrap());
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let height = cache.height();
+
+    for h in 0..=height {
+        assert!(!cache.revalidate(h, &ctx).unwrap(), "block {} is valid", h);
+    }
+    assert_matches!(
+        cache.revalidate(height + 1, &ctx),
+        Err(Error::InvalidBlockHeight(h)) if h == height + 1
+    );
+
+    // A missing index entry is repaired.
+    let hash = cache.chain[7].hash;
+    cache.headers.remove(&hash);
+    assert!(!cache.contains(&hash));
+    assert!(cache.revalidate(7, &ctx).unwrap());
+    assert_eq!(cache.get_block(&hash).map(|(h, _)| h), Some(7));
+
+    // Missing stored headers are repaired.
+    cache.store.rollback(9).unwrap();
+    assert!(cache.revalidate(10, &ctx).unwrap());
+    assert_eq!(cache.store.height().unwrap(), height);
+    assert!(!cache.revalidate(10, &ctx).unwrap());
+
+    // Truncating returns the discarded blocks, from the tip down.
+    let (tip, _) = cache.tip();
+    let discarded = cache.truncate(5).unwrap();
+
+    assert_eq!(discarded.len() as Height, height - 5);
+    assert_eq!(
+        discarded.first().map(|(h, b)| (*h, b.block_hash())),
+        Some((height, tip))
+    );
+    assert_eq!(discarded.last().map(|(h, _)| *h), Some(6));
+    assert_eq!(cache.height(), 5);
+    assert_eq!(cache.store.height().unwrap(), 5);
+    assert!(!cache.contains(&tip));
+}
+
 #[test]
 fn test_median_time_past() {
     let network = bitcoin::Network::Bitcoin;
@@ -568,6 +683,43 @@ fn test_median_time_past() {
     assert_eq!(cache.median_time_past(4), headers[2].time);
     assert_eq!(cache.median_time_past(11), headers[5].time);
     assert_eq!(cache.median_time_past(13), headers[7].time);
+
+    for h in 0..cache.height() {
+        assert_eq!(cache.median_time(h), cache.median_time_past(h + 1));
+    }
+    assert_eq!(
+        cache.median_time(cache.height() + 1),
+        cache.median_time(cache.height())
+    );
+}
+
+#[test]
+fn test_find_height_by_time() {
+    use nakamoto_common::block::time::MAX_FUTURE_BLOCK_TIME;
+
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::File::open(&*nakamoto_test::headers::PATH, genesis).unwrap();
+
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let height = cache.height();
+    // Median time past of the blocks up to and including the given height.
+    let mtp = |h: Height| cache.median_time_past(h + 1);
+
+    assert_eq!(cache.find_height_by_time(0), 0);
+    assert_eq!(cache.find_height_by_time(genesis.time), 0);
+    assert_eq!(cache.find_height_by_time(BlockTime::MAX), height);
+
+    for h in (1..height).step_by(7) {
+        let time = mtp(h) + MAX_FUTURE_BLOCK_TIME;
+        let found = cache.find_height_by_time(time);
+
+        // All blocks after the one found are too recent.
+        assert!(found < h);
+        assert!(mtp(found + 1) >= mtp(h));
+        assert!(found == 0 || mtp(found) < mtp(h));
+    }
 }
 
 #[quickcheck]
@@ -1003,6 +1155,214 @@ fn test_cache_import_back_and_forth() {
     assert_matches!(r, ImportResult::TipChanged { .. });
 }
 
+#[test]
+fn test_cache_chain_work() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+
+    let g = &mut fastrand::Rng::new();
+    // The cached chain work must match the work summed over the active chain.
+    let check = |cache: &BlockCache<_>| {
+        let mut work = Work::default();
+
+        for (height, header) in cache.iter() {
+            work = work + header.work();
+            assert_eq!(cache.chain_work(height), work);
+        }
+        assert_eq!(cache.chain_work(cache.height() + 1), work);
+    };
+    check(&cache);
+
+    // a0 <- a1 <- a2 *
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+    check(&cache);
+
+    // a0 <- a1 <- a2
+    //           \
+    //            <- b2 <- b3 *
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+
+    cache.import_blocks(a0.branch([&b2, &b3]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b3.hash);
+    check(&cache);
+
+    cache.rollback(1).unwrap();
+    check(&cache);
+}
+
+#[test]
+fn test_cache_forks() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+
+    let g = &mut fastrand::Rng::new();
+
+    // a0 <- a1 <- a2 <- a3 <- a4 *
+    //           \     \
+    //            \      <- c3
+    //             <- b2 <- b3
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let a4 = a3.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let c3 = a2.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+    cache.import_blocks(a0.branch([&a3, &a4]), &ctx).unwrap();
+    assert!(cache.forks().is_empty());
+
+    cache.import_blocks(a0.branch([&b2, &b3]), &ctx).unwrap();
+    cache.import_block(c3.block(), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a4.hash);
+
+    let mut forks = cache.forks();
+    forks.sort_by_key(|f| f.fork_height);
+
+    assert_eq!(forks.len(), 2);
+    assert_eq!(forks[0].tip, b3.hash);
+    assert_eq!(forks[0].header, b3.block());
+    assert_eq!(forks[0].height, 3);
+    assert_eq!(forks[0].fork_height, 1);
+    assert_eq!(forks[1].tip, c3.hash);
+    assert_eq!(forks[1].height, 3);
+    assert_eq!(forks[1].fork_height, 2);
+
+    // All blocks have the same difficulty, so forks of equal height have equal work.
+    for fork in forks {
+        assert_eq!(fork.work, cache.chain_work(3));
+        assert!(fork.work < cache.chain_work(cache.height()));
+    }
+}
+
+#[test]
+fn test_cache_ancestors() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+
+    let g = &mut fastrand::Rng::new();
+
+    // a0 <- a1 <- a2 <- a3 <- a4 *
+    //           \
+    //            <- b2 <- b3
+    //                 \
+    //                  <- c3
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let a4 = a3.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+    let c3 = b2.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a4]), &ctx).unwrap();
+    cache.import_blocks(a0.branch([&b2, &b3]), &ctx).unwrap();
+    cache.import_block(c3.block(), &ctx).unwrap();
+    assert_eq!(cache.tip().0, a4.hash);
+
+    let ancestors = cache
+        .iter_ancestors(&b3.hash)
+        .unwrap()
+        .map(|(height, header)| (height, header.block_hash()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        ancestors,
+        vec![(3, b3.hash), (2, b2.hash), (1, a1.hash), (0, a0.hash)]
+    );
+    assert_eq!(
+        cache.iter_ancestors(&a2.hash).unwrap().count(),
+        3,
+        "Blocks on the active chain are their own first ancestor"
+    );
+    assert!(cache.iter_ancestors(&BlockHash::default()).is_none());
+
+    assert_eq!(
+        BlockReader::range(&cache, 1..3).collect::<Vec<_>>(),
+        vec![(1, a1.hash), (2, a2.hash)]
+    );
+    assert_eq!(BlockReader::range(&cache, 4..9).count(), 1);
+
+    assert_eq!(cache.fork_point(&a2.hash, &a4.hash), Some((2, a2.hash)));
+    assert_eq!(cache.fork_point(&b3.hash, &c3.hash), Some((2, b2.hash)));
+    assert_eq!(cache.fork_point(&c3.hash, &a4.hash), Some((1, a1.hash)));
+    assert_eq!(cache.fork_point(&b2.hash, &b3.hash), Some((2, b2.hash)));
+    assert_eq!(cache.fork_point(&b3.hash, &BlockHash::default()), None);
+
+    assert_eq!(cache.common_ancestor(&c3.hash), Some((1, a1.hash)));
+    assert_eq!(cache.common_ancestor(&a3.hash), Some((3, a3.hash)));
+
+    // Locators on a known fork resume from the fork point.
+    let headers = cache.locate_headers(&[b3.hash, a0.hash], BlockHash::default(), 2);
+    assert_eq!(headers, vec![a2.block(), a3.block()]);
+
+    let headers = cache.locate_headers(&[c3.hash], BlockHash::default(), 1);
+    assert_eq!(headers, vec![a2.block()]);
+}
+
+#[test]
+fn test_cache_anchors() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+
+    let g = &mut fastrand::Rng::new();
+
+    // a0 <- a1 <- a2
+    //          \
+    //           <- b2 <- b3 *
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+
+    let anchor = cache.anchor(2).unwrap();
+    assert_eq!(anchor.hash, a2.hash);
+    assert_eq!(anchor.parent, a1.hash);
+    assert!(cache.is_anchored(&anchor));
+    assert!(cache.anchor(1).unwrap().is_parent_of(&anchor));
+    assert_eq!(cache.anchor(3), None);
+
+    cache.import_blocks(a0.branch([&b2, &b3]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b3.hash);
+
+    // The block at height 2 was replaced.
+    assert!(!cache.is_anchored(&anchor));
+    assert!(cache.is_anchored(&cache.anchor(2).unwrap()));
+    assert_eq!(cache.anchor(2).unwrap().hash, b2.hash);
+    assert!(cache.is_anchored(&cache.anchor(1).unwrap()));
+
+    // Anchors must match the height of the block.
+    let mut anchor = cache.anchor(3).unwrap();
+    anchor.height = 4;
+    assert!(!cache.is_anchored(&anchor));
+}
+
 #[test]
 fn test_cache_import_equal_difficulty_blocks() {
     let mut headers = vec![
@@ -0,0 +1,198 @@
+//! Proof-of-work validation backends.
+//!
+//! The proof-of-work of block headers is checked before they are considered for the active
+//! chain, which makes it the main cost of importing headers during initial sync. Checks are
+//! abstracted behind the [`Verifier`] trait, so that they can be batched, parallelized, or
+//! replaced altogether, eg. by a backend verifying the proof-of-work of a different hash
+//! function on a chain sharing Bitcoin's header format.
+//!
+//! Headers are verified one `headers` message at a time. In a batch, the target of a
+//! header is only decoded from its compact representation when it differs from the previous
+//! header's, which is rare outside of retarget boundaries.
+use std::fmt;
+
+use nakamoto_common::bitcoin::blockdata::block::BlockHeader;
+use nakamoto_common::bitcoin_hashes::Hash;
+use nakamoto_common::block::tree::Error;
+use nakamoto_common::block::{Bits, Target};
+
+/// Verifies the proof-of-work of block headers, without context, ie. that a header's hash
+/// meets its own target, and that the target is within the proof-of-work limit. Whether the
+/// target is the one expected at the header's height is checked separately.
+pub trait Verifier: fmt::Debug + Send + Sync {
+    /// Verify the proof-of-work of a header against its target.
+    fn verify(&self, header: &BlockHeader, target: &Target, limit: &Target) -> Result<(), Error>;
+
+    /// Verify the proof-of-work of a batch of headers. Returns the index of the first invalid
+    /// header along with the reason it is invalid, if any.
+    fn verify_batch(&self, headers: &[BlockHeader], limit: &Target) -> Result<(), (usize, Error)> {
+        let mut targets = Targets::default();
+
+        for (i, header) in headers.iter().enumerate() {
+            self.verify(header, targets.get(header.bits), limit)
+                .map_err(|e| (i, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Verifies headers one after the other, on the calling thread.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Sequential;
+
+impl Verifier for Sequential {
+    fn verify(&self, header: &BlockHeader, target: &Target, limit: &Target) -> Result<(), Error> {
+        self::verify(header, target, limit)
+    }
+}
+
+/// Verifies batches of headers in parallel on a thread pool. Small batches, eg. new blocks
+/// announced at the tip, are verified on the calling thread, as they aren't worth the
+/// overhead. Useful during initial sync on machines with cores to spare.
+#[cfg(feature = "rayon")]
+#[derive(Debug, Clone)]
+pub struct Parallel {
+    pool: std::sync::Arc<rayon::ThreadPool>,
+    min_batch: usize,
+}
+
+#[cfg(feature = "rayon")]
+impl Parallel {
+    /// Batches smaller than this are verified on the calling thread.
+    pub const MIN_BATCH: usize = 128;
+
+    /// Create a verifier with a pool of the given number of threads. A value of zero picks
+    /// the number of logical CPUs.
+    pub fn new(threads: usize) -> Result<Self, rayon::ThreadPoolBuildError> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("pow#{}", i))
+            .build()?;
+
+        Ok(Self {
+            pool: std::sync::Arc::new(pool),
+            min_batch: Self::MIN_BATCH,
+        })
+    }
+
+    /// Set the minimum batch size to verify in parallel.
+    pub fn min_batch(mut self, min_batch: usize) -> Self {
+        self.min_batch = min_batch;
+        self
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl Verifier for Parallel {
+    fn verify(&self, header: &BlockHeader, target: &Target, limit: &Target) -> Result<(), Error> {
+        self::verify(header, target, limit)
+    }
+
+    fn verify_batch(&self, headers: &[BlockHeader], limit: &Target) -> Result<(), (usize, Error)> {
+        use rayon::prelude::*;
+
+        if headers.len() < self.min_batch {
+            return Sequential.verify_batch(headers, limit);
+        }
+        let chunk = headers
+            .len()
+            .div_ceil(self.pool.current_num_threads())
+            .max(1);
+
+        // Nb. Each chunk stops at its first invalid header, and the lowest index wins, so
+        // the result is the same as when verifying sequentially.
+        self.pool.install(|| {
+            headers
+                .par_chunks(chunk)
+                .enumerate()
+                .map(|(n, headers)| {
+                    Sequential
+                        .verify_batch(headers, limit)
+                        .map_err(|(i, e)| (n * chunk + i, e))
+                })
+                .collect::<Vec<_>>()
+                .into_iter()
+                .collect()
+        })
+    }
+}
+
+/// Decoded targets of a batch of headers. Consecutive headers mostly share the same target,
+/// so only the last one is kept.
+#[derive(Default)]
+struct Targets {
+    last: Option<(Bits, Target)>,
+}
+
+impl Targets {
+    fn get(&mut self, bits: Bits) -> &Target {
+        match self.last {
+            Some((b, _)) if b == bits => {}
+            _ => {
+                self.last = Some((bits, BlockHeader::u256_from_compact_target(bits)));
+            }
+        }
+        self.last.as_ref().map(|(_, t)| t).unwrap()
+    }
+}
+
+/// Check that a header's hash meets the given target, and that the target is within the limit.
+pub fn verify(header: &BlockHeader, target: &Target, limit: &Target) -> Result<(), Error> {
+    let mut bytes = header.block_hash().into_inner();
+    bytes.reverse();
+
+    if Target::from_be_bytes(bytes) > *target {
+        return Err(Error::InvalidBlockPoW);
+    }
+    if target > limit {
+        return Err(Error::InvalidBlockTarget(*target, *limit));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use nakamoto_common::network::Network;
+    use nakamoto_test::BITCOIN_HEADERS;
+
+    #[test]
+    fn test_verify_batch() {
+        let limit = Network::Mainnet.params().pow_limit;
+        let mut headers = BITCOIN_HEADERS.tail.clone();
+
+        assert!(Sequential.verify_batch(&headers, &limit).is_ok());
+
+        headers[7].nonce += 1;
+        headers[9].nonce += 1;
+        assert!(matches!(
+            Sequential.verify_batch(&headers, &limit),
+            Err((7, Error::InvalidBlockPoW))
+        ));
+
+        let lower = limit >> 64;
+        assert!(matches!(
+            Sequential.verify_batch(&headers, &lower),
+            Err((0, Error::InvalidBlockTarget(t, l))) if t == headers[0].target() && l == lower
+        ));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_parallel() {
+        let limit = Network::Mainnet.params().pow_limit;
+        let mut headers = BITCOIN_HEADERS.tail.clone();
+        let parallel = Parallel::new(4).unwrap().min_batch(0);
+
+        assert!(parallel.verify_batch(&headers, &limit).is_ok());
+
+        let last = headers.len() - 1;
+        headers[last].nonce += 1;
+        headers[3].nonce += 1;
+        assert!(matches!(
+            parallel.verify_batch(&headers, &limit),
+            Err((3, Error::InvalidBlockPoW))
+        ));
+    }
+}
@@ -2,8 +2,10 @@
 
 pub use nakamoto_common::block::store::*;
 
+pub mod flusher;
 pub mod io;
 pub mod memory;
 
-pub use io::File;
+pub use flusher::Flusher;
+pub use io::{File, Fsync};
 pub use memory::Memory;
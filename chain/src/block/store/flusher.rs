@@ -0,0 +1,446 @@
+//! Background flushing of store writes.
+//!
+//! A [`Flusher`] wraps a store, and applies writes to it on a dedicated thread, so that
+//! slow disks don't stall the caller. Writes are queued in order, and consecutive appends
+//! are coalesced into a single write.
+use std::fmt;
+use std::io;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use nakamoto_common::block::store::{Error, Store};
+use nakamoto_common::block::Height;
+
+/// A store operation, applied by the flusher thread.
+enum Op<H> {
+    /// Append headers.
+    Put(Vec<H>),
+    /// Rollback to the given height.
+    Rollback(Height),
+    /// Sync changes to disk, and reply with the result.
+    Sync(mpsc::Sender<Result<(), Error>>),
+    /// Reply once all previous operations were applied.
+    Barrier(mpsc::Sender<()>),
+}
+
+/// A store that applies writes in the background.
+///
+/// Appends and rollbacks return as soon as they are queued, unless the queue is full.
+/// Reads wait for queued writes to be applied. Dropping the flusher waits for all queued
+/// writes.
+///
+/// Write errors are sticky: once a write fails, no further operations are applied, since
+/// they would be applied on top of a partial write, and every following call returns an
+/// error.
+pub struct Flusher<S: Store> {
+    genesis: S::Header,
+    /// Height of the store, including queued writes.
+    height: Height,
+    store: Arc<Mutex<S>>,
+    ops: Option<mpsc::SyncSender<Op<S::Header>>>,
+    /// First error encountered by the flusher thread, if any. Once set, it stays set.
+    error: Arc<Mutex<Option<Error>>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl<S: Store> fmt::Debug for Flusher<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Flusher")
+            .field("height", &self.height)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> Flusher<S>
+where
+    S: Store + Send + 'static,
+    S::Header: Copy + Send + 'static,
+{
+    /// Start flushing writes to the given store in the background. At most `capacity`
+    /// writes are queued, after which writing blocks until the queue has room.
+    pub fn spawn(store: S, capacity: usize) -> Result<Self, Error> {
+        let genesis = store.genesis();
+        let height = store.height()?;
+        let store = Arc::new(Mutex::new(store));
+        let error = Arc::new(Mutex::new(None));
+        let (ops, receive) = mpsc::sync_channel(capacity);
+        let thread = thread::Builder::new()
+            .name(String::from("store-flusher"))
+            .spawn({
+                let store = store.clone();
+                let error = error.clone();
+
+                move || self::run(receive, store, error, height)
+            })?;
+
+        Ok(Self {
+            genesis,
+            height,
+            store,
+            ops: Some(ops),
+            error,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl<S: Store> Flusher<S> {
+    /// Queue an operation.
+    fn send(&self, op: Op<S::Header>) -> Result<(), Error> {
+        if let Some(err) = self.error.lock().unwrap().as_ref() {
+            return Err(self::failed(err));
+        }
+        self.ops
+            .as_ref()
+            .and_then(|ops| ops.send(op).ok())
+            .ok_or_else(|| Error::Io(io::Error::other("store flusher thread exited")))
+    }
+
+    /// Wait for all queued operations to be applied, and access the store.
+    fn flushed(&self) -> Result<std::sync::MutexGuard<'_, S>, Error> {
+        let (reply, done) = mpsc::channel();
+
+        self.send(Op::Barrier(reply))?;
+        done.recv()
+            .map_err(|_| Error::Io(io::Error::other("store flusher thread exited")))?;
+
+        Ok(self.store.lock().unwrap())
+    }
+}
+
+impl<S: Store> Drop for Flusher<S> {
+    fn drop(&mut self) {
+        // Closing the queue stops the thread once all queued writes are applied.
+        self.ops.take();
+
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+impl<S> Store for Flusher<S>
+where
+    S: Store,
+    S::Header: Copy + 'static,
+{
+    type Header = S::Header;
+
+    fn genesis(&self) -> Self::Header {
+        self.genesis
+    }
+
+    fn put<I: Iterator<Item = Self::Header>>(&mut self, headers: I) -> Result<Height, Error> {
+        let headers = headers.collect::<Vec<_>>();
+        let height = self.height + headers.len() as Height;
+
+        self.send(Op::Put(headers))?;
+        self.height = height;
+
+        Ok(height)
+    }
+
+    fn get(&self, height: Height) -> Result<Self::Header, Error> {
+        self.flushed()?.get(height)
+    }
+
+    fn rollback(&mut self, height: Height) -> Result<(), Error> {
+        self.send(Op::Rollback(height))?;
+        self.height = height;
+
+        Ok(())
+    }
+
+    /// Wait for all queued writes to be applied, and sync them to disk.
+    fn sync(&mut self) -> Result<(), Error> {
+        let (reply, result) = mpsc::channel();
+
+        self.send(Op::Sync(reply))?;
+        result
+            .recv()
+            .map_err(|_| Error::Io(io::Error::other("store flusher thread exited")))?
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<(Height, Self::Header), Error>>> {
+        match self.flushed() {
+            Ok(store) => store.iter(),
+            Err(err) => Box::new(std::iter::once(Err(err))),
+        }
+    }
+
+    fn len(&self) -> Result<usize, Error> {
+        Ok(self.height as usize + 1)
+    }
+
+    fn height(&self) -> Result<Height, Error> {
+        Ok(self.height)
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        self.flushed()?.check()
+    }
+
+    fn heal(&self) -> Result<(), Error> {
+        self.flushed()?.heal()
+    }
+}
+
+/// Apply queued operations to the store, until the queue is closed. After an operation
+/// fails, the following ones are dropped.
+fn run<S: Store>(
+    ops: mpsc::Receiver<Op<S::Header>>,
+    store: Arc<Mutex<S>>,
+    error: Arc<Mutex<Option<Error>>>,
+    mut height: Height,
+) {
+    // Headers queued for appending, starting at `height + 1`.
+    let mut pending = Vec::new();
+    let fail = |err: Error| {
+        log::error!("Error writing to store: {}", err);
+        error.lock().unwrap().get_or_insert(err);
+    };
+    let failed = || error.lock().unwrap().as_ref().map(self::failed);
+
+    while let Ok(op) = ops.recv() {
+        let mut store = store.lock().unwrap();
+
+        // Coalesce all the operations that are already queued.
+        for op in std::iter::once(op).chain(ops.try_iter()) {
+            if let Some(err) = failed() {
+                match op {
+                    Op::Sync(reply) => {
+                        reply.send(Err(err)).ok();
+                    }
+                    Op::Barrier(reply) => {
+                        reply.send(()).ok();
+                    }
+                    Op::Put(_) | Op::Rollback(_) => {}
+                }
+                continue;
+            }
+            match op {
+                Op::Put(headers) => {
+                    pending.extend(headers);
+                }
+                Op::Rollback(to) if to >= height => {
+                    // Only pending headers are rolled back, so they don't need writing.
+                    pending.truncate((to - height) as usize);
+                }
+                Op::Rollback(to) => {
+                    match self::flush(&mut *store, &mut pending, &mut height)
+                        .and_then(|()| store.rollback(to))
+                    {
+                        Ok(()) => height = to,
+                        Err(err) => fail(err),
+                    }
+                }
+                Op::Sync(reply) => {
+                    let result = self::flush(&mut *store, &mut pending, &mut height)
+                        .and_then(|()| store.sync());
+
+                    if let Err(err) = result {
+                        fail(err);
+                        reply
+                            .send(Err(failed().expect("the error was just set")))
+                            .ok();
+                    } else {
+                        reply.send(Ok(())).ok();
+                    }
+                }
+                Op::Barrier(reply) => {
+                    if let Err(err) = self::flush(&mut *store, &mut pending, &mut height) {
+                        fail(err);
+                    }
+                    reply.send(()).ok();
+                }
+            }
+        }
+        if failed().is_none() {
+            if let Err(err) = self::flush(&mut *store, &mut pending, &mut height) {
+                fail(err);
+            }
+        }
+    }
+}
+
+/// Copy a store error, to return it from every call once the flusher has failed.
+fn failed(err: &Error) -> Error {
+    match err {
+        Error::Io(e) => Error::Io(io::Error::new(e.kind(), e.to_string())),
+        Error::Decoding(_) => {
+            Error::Io(io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+        }
+        Error::Corruption => Error::Corruption,
+        Error::Locked => Error::Locked,
+    }
+}
+
+/// Append the pending headers to the store.
+fn flush<S: Store>(
+    store: &mut S,
+    pending: &mut Vec<S::Header>,
+    height: &mut Height,
+) -> Result<(), Error> {
+    if pending.is_empty() {
+        return Ok(());
+    }
+    *height += pending.len() as Height;
+    store.put(pending.drain(..)).map(|_| ())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use nakamoto_common::nonempty::NonEmpty;
+
+    use crate::block::store::{File, Memory};
+    use crate::block::BlockHeader;
+
+    /// A store whose writes fail on demand, and which counts the writes applied to it.
+    struct Faulty {
+        store: Memory<BlockHeader>,
+        fail: Arc<AtomicBool>,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl Faulty {
+        fn write(&self) -> Result<(), Error> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(Error::Io(io::Error::other("disk failure")));
+            }
+            self.writes.fetch_add(1, Ordering::SeqCst);
+
+            Ok(())
+        }
+    }
+
+    impl Store for Faulty {
+        type Header = BlockHeader;
+
+        fn genesis(&self) -> BlockHeader {
+            self.store.genesis()
+        }
+
+        fn put<I: Iterator<Item = BlockHeader>>(&mut self, headers: I) -> Result<Height, Error> {
+            self.write()?;
+            self.store.put(headers)
+        }
+
+        fn get(&self, height: Height) -> Result<BlockHeader, Error> {
+            self.store.get(height)
+        }
+
+        fn rollback(&mut self, height: Height) -> Result<(), Error> {
+            self.write()?;
+            self.store.rollback(height)
+        }
+
+        fn sync(&mut self) -> Result<(), Error> {
+            self.store.sync()
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = Result<(Height, BlockHeader), Error>>> {
+            self.store.iter()
+        }
+
+        fn len(&self) -> Result<usize, Error> {
+            self.store.len()
+        }
+
+        fn height(&self) -> Result<Height, Error> {
+            self.store.height()
+        }
+
+        fn check(&self) -> Result<(), Error> {
+            self.store.check()
+        }
+
+        fn heal(&self) -> Result<(), Error> {
+            self.store.heal()
+        }
+    }
+
+    fn header(nonce: u32) -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            prev_blockhash: Default::default(),
+            merkle_root: Default::default(),
+            bits: 0x2ffffff,
+            time: 39123818,
+            nonce,
+        }
+    }
+
+    #[test]
+    fn test_flusher() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("headers.db");
+        let store = File::open(&path, header(0)).unwrap();
+        let mut flusher = Flusher::spawn(store, 4).unwrap();
+
+        for i in 1..=32 {
+            assert_eq!(
+                flusher.put(std::iter::once(header(i))).unwrap(),
+                i as Height
+            );
+        }
+        flusher.rollback(30).unwrap();
+        flusher.put((31..=33).map(header)).unwrap();
+        flusher.rollback(10).unwrap();
+        flusher.put((11..=12).map(header)).unwrap();
+
+        assert_eq!(flusher.height().unwrap(), 12);
+        assert_eq!(flusher.get(12).unwrap(), header(12));
+        assert_eq!(
+            flusher.iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+            (0..=12)
+                .map(|i| (i as Height, header(i)))
+                .collect::<Vec<_>>()
+        );
+        flusher.put((13..=20).map(header)).unwrap();
+        flusher.sync().unwrap();
+        flusher.put((21..=25).map(header)).unwrap();
+        drop(flusher);
+
+        // All queued writes are applied before the flusher is dropped.
+        let store = File::open(&path, header(0)).unwrap();
+
+        assert_eq!(store.height().unwrap(), 25);
+        assert_eq!(store.get(25).unwrap(), header(25));
+    }
+
+    #[test]
+    fn test_flusher_error() {
+        let fail = Arc::new(AtomicBool::new(false));
+        let writes = Arc::new(AtomicUsize::new(0));
+        let store = Faulty {
+            store: Memory::new(NonEmpty::new(header(0))),
+            fail: fail.clone(),
+            writes: writes.clone(),
+        };
+        let mut flusher = Flusher::spawn(store, 4).unwrap();
+
+        flusher.put((1..=4).map(header)).unwrap();
+        flusher.sync().unwrap();
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+
+        // The failed write is reported by the next call.
+        fail.store(true, Ordering::SeqCst);
+        flusher.put((5..=8).map(header)).unwrap();
+        // Whether or not this is queued before the failure, it is never applied.
+        flusher.rollback(2).ok();
+        assert!(flusher.sync().is_err());
+
+        // The error is sticky, even once the store recovers, and nothing else is applied.
+        fail.store(false, Ordering::SeqCst);
+        assert!(flusher.put((9..=10).map(header)).is_err());
+        assert!(flusher.rollback(2).is_err());
+        assert!(flusher.sync().is_err());
+        assert!(flusher.get(1).is_err());
+        assert!(flusher.iter().next().unwrap().is_err());
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+    }
+}
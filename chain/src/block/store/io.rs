@@ -68,11 +68,23 @@ impl<H: Decodable> Iterator for Iter<H> {
     }
 }
 
+/// Policy determining when writes to a file store are flushed to disk.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Fsync {
+    /// Flush to disk after every write. Safest, but slowest.
+    Always,
+    /// Only flush to disk when [`Store::sync`] is called, and otherwise leave it
+    /// to the operating system.
+    #[default]
+    Manual,
+}
+
 /// A `Store` backed by a single file.
 #[derive(Debug)]
 pub struct File<H> {
     file: fs::File,
     genesis: H,
+    fsync: Fsync,
 }
 
 impl<H> File<H> {
@@ -83,7 +95,11 @@ impl<H> File<H> {
             .read(true)
             .append(true)
             .open(path)
-            .map(|file| Self { file, genesis })
+            .map(|file| Self {
+                file,
+                genesis,
+                fsync: Fsync::default(),
+            })
     }
 
     /// Create a new file store at the given path, with the provided genesis header.
@@ -94,7 +110,27 @@ impl<H> File<H> {
             .append(true)
             .open(path)?;
 
-        Ok(Self { file, genesis })
+        Ok(Self {
+            file,
+            genesis,
+            fsync: Fsync::default(),
+        })
+    }
+
+    /// Set the policy for flushing writes to disk.
+    pub fn fsync(mut self, fsync: Fsync) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// Take an exclusive advisory lock on the store file, so that other processes
+    /// can't write to it concurrently. Fails with [`Error::Locked`] if the file is
+    /// already locked. The lock is released when the store is dropped.
+    pub fn lock(&self) -> Result<(), Error> {
+        self.file.try_lock().map_err(|err| match err {
+            fs::TryLockError::WouldBlock => Error::Locked,
+            fs::TryLockError::Error(err) => Error::Io(err),
+        })
     }
 }
 
@@ -108,7 +144,12 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
 
     /// Append a block to the end of the file.
     fn put<I: Iterator<Item = Self::Header>>(&mut self, headers: I) -> Result<Height, Error> {
-        self::put(&mut self.file, headers)
+        let height = self::put(&mut self.file, headers)?;
+
+        if self.fsync == Fsync::Always {
+            self.sync()?;
+        }
+        Ok(height)
     }
 
     /// Get the block at the given height. Returns `io::ErrorKind::UnexpectedEof` if
@@ -128,9 +169,12 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
     fn rollback(&mut self, height: Height) -> Result<(), Error> {
         let size = mem::size_of::<H>();
 
-        self.file
-            .set_len((height) * size as u64)
-            .map_err(Error::from)
+        self.file.set_len((height) * size as u64)?;
+
+        if self.fsync == Fsync::Always {
+            self.sync()?;
+        }
+        Ok(())
     }
 
     /// Flush changes to disk.
@@ -340,6 +384,29 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_lock() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("headers.db");
+        let genesis = BlockHeader {
+            version: 1,
+            prev_blockhash: Default::default(),
+            merkle_root: Default::default(),
+            bits: 0x2ffffff,
+            time: 39123818,
+            nonce: 0,
+        };
+
+        let alice = File::open(&path, genesis).unwrap();
+        let bob = File::open(&path, genesis).unwrap();
+
+        alice.lock().unwrap();
+        assert!(matches!(bob.lock(), Err(Error::Locked)));
+
+        drop(alice);
+        bob.lock().unwrap();
+    }
+
     #[test]
     fn test_corrupt_file() {
         let mut store = store("headers.db");
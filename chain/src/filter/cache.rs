@@ -47,7 +47,7 @@ impl Decodable for StoredHeader {
 impl Genesis for StoredHeader {
     fn genesis(network: Network) -> Self {
         Self {
-            hash: FilterHash::genesis(network),
+            hash: FilterHash::genesis(network.clone()),
             header: FilterHeader::genesis(network),
         }
     }
@@ -72,27 +72,78 @@ impl<S: Store<Header = StoredHeader>> FilterCache<S> {
             headers,
         })
     }
+
+    /// Verify the filter header chain, and roll it back to the last valid header if an
+    /// invalid header is found. Returns the height of the first invalid header, if any.
+    pub fn repair(
+        &mut self,
+        network: Network,
+    ) -> Result<Option<Height>, nakamoto_common::block::store::Error> {
+        self.repair_from(network, None)
+    }
+
+    /// Like [`FilterCache::repair`], but only verifies the headers above the given header,
+    /// which was verified earlier, eg. by a previous run. If that header is no longer in the
+    /// chain, the whole chain is verified.
+    pub fn repair_from(
+        &mut self,
+        network: Network,
+        verified: Option<(Height, FilterHeader)>,
+    ) -> Result<Option<Height>, nakamoto_common::block::store::Error> {
+        match self.first_invalid(network, verified) {
+            Some(0) => Err(nakamoto_common::block::store::Error::Corruption),
+            Some(height) => {
+                self.header_store.rollback(height - 1)?;
+                self.headers.tail.truncate(height as usize - 1);
+
+                Ok(Some(height))
+            }
+            None => Ok(None),
+        }
+    }
 }
 
 impl<S> FilterCache<S> {
     /// Verify the filter header chain. Returns `true` if the chain is valid.
     pub fn verify(&self, network: Network) -> Result<(), store::Error> {
+        match self.first_invalid(network, None) {
+            Some(_) => Err(store::Error::Integrity),
+            None => Ok(()),
+        }
+    }
+
+    /// Get the height of the first filter header that doesn't commit to its parent. Headers
+    /// up to the given verified header are skipped, if it's still in the chain.
+    fn first_invalid(
+        &self,
+        network: Network,
+        verified: Option<(Height, FilterHeader)>,
+    ) -> Option<Height> {
         let mut prev_header = FilterHeader::default();
 
         if self.headers.first().header != FilterHeader::genesis(network) {
-            return Err(store::Error::Integrity);
+            return Some(0);
         }
+        let start = match verified {
+            Some((height, header))
+                if self.headers.get(height as usize).map(|h| h.header) == Some(header) =>
+            {
+                prev_header = header;
+                height as usize + 1
+            }
+            _ => 0,
+        };
 
-        for stored_header in self.headers.iter() {
+        for (height, stored_header) in self.headers.iter().enumerate().skip(start) {
             let expected = stored_header.hash.filter_header(&prev_header);
             let actual = stored_header.header;
 
             if actual != expected {
-                return Err(store::Error::Integrity);
+                return Some(height as Height);
             }
             prev_header = actual;
         }
-        Ok(())
+        None
     }
 }
 
@@ -150,3 +201,39 @@ impl<S: Store<Header = StoredHeader>> Filters for FilterCache<S> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::store::Memory;
+    use nakamoto_common::bitcoin_hashes::Hash;
+
+    #[test]
+    fn test_repair_from() {
+        let network = Network::Regtest;
+        let mut cache =
+            FilterCache::from(Memory::<StoredHeader>::genesis(network.clone())).unwrap();
+        let mut prev = FilterHeader::genesis(network.clone());
+        let headers = (1..=10u8)
+            .map(|i| {
+                let hash = FilterHash::hash(&[i]);
+                prev = hash.filter_header(&prev);
+                (hash, prev)
+            })
+            .collect::<Vec<_>>();
+
+        cache.import_headers(headers).unwrap();
+        cache.headers.tail[7].header = FilterHeader::default();
+
+        // The corrupted header is below the verified one, so it goes unnoticed.
+        let verified = (9, cache.headers.tail[8].header);
+        assert_eq!(
+            cache.repair_from(network.clone(), Some(verified)).unwrap(),
+            None
+        );
+        // Unless the verified header is no longer in the chain.
+        let unknown = (9, FilterHeader::default());
+        assert_eq!(cache.repair_from(network, Some(unknown)).unwrap(), Some(8));
+        assert_eq!(cache.height(), 7);
+    }
+}
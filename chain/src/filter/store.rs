@@ -2,6 +2,8 @@
 
 use thiserror::Error;
 
+use nakamoto_common::error::Classify;
+
 pub use nakamoto_common::block::filter::{BlockFilter, FilterHash, FilterHeader, Filters};
 pub use nakamoto_common::block::store::Store;
 
@@ -14,3 +16,15 @@ pub enum Error {
     #[error("filter store is corrupted")]
     Integrity,
 }
+
+impl Classify for Error {
+    fn code(&self) -> u32 {
+        match self {
+            Self::Integrity => 2004,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,518 @@
+//! Async adapter over a client handle.
+//!
+//! Every call is made from a pool of background worker threads, with the result delivered
+//! over a futures channel, so that it can be `await`ed from any executor, eg. Tokio or
+//! async-std. Workers are reused across calls; a new worker is only started when all
+//! existing workers are busy, eg. waiting on [`AsyncHandle::wait_for_height`].
+//! Event subscriptions are bridged to [`Stream`]s in the same way.
+//!
+//! Available with the `async` feature.
+use std::net;
+use std::ops::RangeInclusive;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time;
+
+use futures::channel::{mpsc, oneshot};
+use futures::stream::Stream;
+
+use nakamoto_common::bitcoin::network::constants::ServiceFlags;
+use nakamoto_common::bitcoin::util::merkleblock::MerkleBlock;
+use nakamoto_common::bitcoin::{Script, Transaction, Txid};
+use nakamoto_common::block::filter::BlockFilter;
+use nakamoto_common::block::time::LocalDuration;
+use nakamoto_common::block::{Anchor, Block, BlockHash, BlockHeader, BlockTime, Height};
+use nakamoto_common::nonempty::NonEmpty;
+use nakamoto_p2p::protocol::{self, FilterEvent, Link, RescanId};
+
+use crate::client::chan;
+use crate::event::Event;
+use crate::handle::{Error, Handle};
+use crate::ots;
+use crate::proof::SpvProof;
+use crate::stats::ChainStats;
+
+/// A blocking handle call, run by a worker. Returns the delivery of its result, which is
+/// run once the worker is marked idle, so that a follow-up call can reuse the same worker.
+type Job<H> = Box<dyn FnOnce(&H) -> Box<dyn FnOnce() + Send> + Send>;
+
+/// Pool of worker threads running blocking handle calls.
+#[derive(Debug, Clone)]
+struct Workers<H> {
+    jobs: chan::Sender<Job<H>>,
+    queue: chan::Receiver<Job<H>>,
+    /// Number of workers waiting for a job.
+    idle: Arc<AtomicUsize>,
+}
+
+impl<H: Clone + Send + 'static> Workers<H> {
+    fn new() -> Self {
+        let (jobs, queue) = chan::unbounded();
+
+        Self {
+            jobs,
+            queue,
+            idle: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Queue a job, starting a new worker if none is idle.
+    fn submit(&self, handle: &H, job: Job<H>) {
+        let idle = self
+            .idle
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+
+        if idle.is_err() {
+            let handle = handle.clone();
+            let queue = self.queue.clone();
+            let idle = self.idle.clone();
+
+            thread::spawn(move || {
+                // Workers exit once every copy of the async handle is dropped.
+                while let Ok(job) = queue.recv() {
+                    let reply = job(&handle);
+
+                    idle.fetch_add(1, Ordering::SeqCst);
+                    reply();
+                }
+            });
+        }
+        // The queue can't be disconnected, since we hold a receiver.
+        self.jobs.send(job).ok();
+    }
+}
+
+/// An async wrapper around a [`Handle`].
+#[derive(Debug, Clone)]
+pub struct AsyncHandle<H> {
+    handle: H,
+    workers: Workers<H>,
+}
+
+impl<H: Handle + 'static> AsyncHandle<H> {
+    /// Wrap a client handle.
+    pub fn new(handle: H) -> Self {
+        Self {
+            handle,
+            workers: Workers::new(),
+        }
+    }
+
+    /// Get the underlying blocking handle.
+    pub fn handle(&self) -> &H {
+        &self.handle
+    }
+
+    /// Get the tip of the chain.
+    pub async fn get_tip(&self) -> Result<(Height, BlockHeader), Error> {
+        self.spawn(|h| h.get_tip()).await
+    }
+
+    /// Get the proof-of-work of the active chain and of known forks.
+    /// See [`Handle::get_chain_work`].
+    pub async fn get_chain_work(&self) -> Result<protocol::ChainWork, Error> {
+        self.spawn(|h| h.get_chain_work()).await
+    }
+
+    /// Get a full block from the network. The block is delivered on the
+    /// [`AsyncHandle::blocks`] stream.
+    pub async fn get_block(&self, hash: BlockHash) -> Result<(), Error> {
+        self.spawn(move |h| h.get_block(&hash)).await
+    }
+
+    /// Verify a merkle proof against the active chain.
+    /// See [`Handle::verify_merkle_block`].
+    pub async fn verify_merkle_block(
+        &self,
+        block: MerkleBlock,
+    ) -> Result<(Height, Vec<Txid>), Error> {
+        self.spawn(move |h| h.verify_merkle_block(&block)).await
+    }
+
+    /// Get an SPV proof of one of our recently confirmed transactions.
+    /// See [`Handle::get_spv_proof`].
+    pub async fn get_spv_proof(&self, txid: Txid) -> Result<Option<SpvProof>, Error> {
+        self.spawn(move |h| h.get_spv_proof(txid)).await
+    }
+
+    /// Verify an OpenTimestamps proof against the active chain.
+    /// See [`Handle::verify_ots`].
+    pub async fn verify_ots(&self, proof: Vec<u8>) -> Result<ots::Verified, Error> {
+        self.spawn(move |h| h.verify_ots(&proof)).await
+    }
+
+    /// Get compact filters from the network. Filters are delivered on the
+    /// [`AsyncHandle::filters`] stream.
+    pub async fn get_filters(&self, range: RangeInclusive<Height>) -> Result<(), Error> {
+        self.spawn(move |h| h.get_filters(range)).await
+    }
+
+    /// Submit a transaction to the network.
+    pub async fn submit_transaction(
+        &self,
+        tx: Transaction,
+    ) -> Result<NonEmpty<net::SocketAddr>, Error> {
+        self.spawn(move |h| h.submit_transaction(tx)).await
+    }
+
+    /// Replace a submitted transaction with a conflicting transaction, eg. to bump its fee.
+    pub async fn replace_transaction(
+        &self,
+        txid: Txid,
+        replacement: Transaction,
+    ) -> Result<NonEmpty<net::SocketAddr>, Error> {
+        self.spawn(move |h| h.replace_transaction(txid, replacement))
+            .await
+    }
+
+    /// Submit a package of dependent transactions to the network.
+    pub async fn submit_package(
+        &self,
+        txs: Vec<Transaction>,
+    ) -> Result<Vec<(Txid, protocol::TxStatus)>, Error> {
+        self.spawn(move |h| h.submit_package(txs)).await
+    }
+
+    /// Connect to the designated peer address. See [`Handle::connect`].
+    pub async fn connect(&self, addr: net::SocketAddr, persistent: bool) -> Result<Link, Error> {
+        self.spawn(move |h| h.connect(addr, persistent)).await
+    }
+
+    /// Get our persistent peers. See [`Handle::get_added_peers`].
+    pub async fn get_added_peers(&self) -> Result<Vec<net::SocketAddr>, Error> {
+        self.spawn(|h| h.get_added_peers()).await
+    }
+
+    /// Enable or disable network activity. See [`Handle::set_network_active`].
+    pub async fn set_network_active(&self, active: bool) -> Result<(), Error> {
+        self.spawn(move |h| h.set_network_active(active)).await
+    }
+
+    /// Sync for the given duration, then idle. See [`Handle::sync_burst`].
+    pub async fn sync_burst(&self, duration: LocalDuration) -> Result<(), Error> {
+        self.spawn(move |h| h.sync_burst(duration)).await
+    }
+
+    /// Enable or disable low-data mode. See [`Handle::set_low_data`].
+    pub async fn set_low_data(&self, enabled: bool) -> Result<(), Error> {
+        self.spawn(move |h| h.set_low_data(enabled)).await
+    }
+
+    /// Get the bandwidth used in each network mode. See [`Handle::get_bandwidth`].
+    pub async fn get_bandwidth(&self) -> Result<protocol::Bandwidth, Error> {
+        self.spawn(|h| h.get_bandwidth()).await
+    }
+
+    /// Get statistics of the messages exchanged with peers, per command.
+    /// See [`Handle::get_message_stats`].
+    pub async fn get_message_stats(&self) -> Result<protocol::MessageStats, Error> {
+        self.spawn(|h| h.get_message_stats()).await
+    }
+
+    /// Disconnect from the designated peer address.
+    pub async fn disconnect(&self, addr: net::SocketAddr) -> Result<(), Error> {
+        self.spawn(move |h| h.disconnect(addr)).await
+    }
+
+    /// Ban an address. See [`Handle::ban`].
+    pub async fn ban(
+        &self,
+        addr: net::IpAddr,
+        duration: Option<LocalDuration>,
+    ) -> Result<(), Error> {
+        self.spawn(move |h| h.ban(addr, duration)).await
+    }
+
+    /// Lift the ban on an address.
+    pub async fn unban(&self, addr: net::IpAddr) -> Result<(), Error> {
+        self.spawn(move |h| h.unban(addr)).await
+    }
+
+    /// Get the list of banned addresses.
+    pub async fn list_bans(&self) -> Result<Vec<protocol::Ban>, Error> {
+        self.spawn(|h| h.list_bans()).await
+    }
+
+    /// Wait for the given number of peers to be connected with the given services.
+    pub async fn wait_for_peers(
+        &self,
+        count: usize,
+        required_services: impl Into<ServiceFlags>,
+    ) -> Result<Vec<(net::SocketAddr, Height, ServiceFlags)>, Error> {
+        let services = required_services.into();
+
+        self.spawn(move |h| h.wait_for_peers(count, services)).await
+    }
+
+    /// Wait for the node's active chain to reach a certain height.
+    pub async fn wait_for_height(
+        &self,
+        height: Height,
+        timeout: time::Duration,
+    ) -> Result<BlockHash, Error> {
+        self.spawn(move |h| h.wait_for_height(height, timeout))
+            .await
+    }
+
+    /// Wait for the node's filter header chain to reach a certain height.
+    pub async fn wait_for_filters(
+        &self,
+        height: Height,
+        timeout: time::Duration,
+    ) -> Result<Height, Error> {
+        self.spawn(move |h| h.wait_for_filters(height, timeout))
+            .await
+    }
+
+    /// Wait for a transaction to be confirmed with the given number of confirmations.
+    /// See [`Handle::wait_for_confirmation`].
+    pub async fn wait_for_confirmation(
+        &self,
+        txid: Txid,
+        depth: Height,
+        timeout: time::Duration,
+    ) -> Result<(Height, BlockHash), Error> {
+        self.spawn(move |h| h.wait_for_confirmation(txid, depth, timeout))
+            .await
+    }
+
+    /// Estimate a wallet's birth height from its birthday.
+    /// See [`Handle::birth_height`].
+    pub async fn birth_height(&self, birthday: BlockTime) -> Result<Height, Error> {
+        self.spawn(move |h| h.birth_height(birthday)).await
+    }
+
+    /// Compute statistics of the given range of the active chain.
+    /// See [`Handle::chain_stats`].
+    pub async fn chain_stats(
+        &self,
+        range: RangeInclusive<Height>,
+    ) -> Result<Option<ChainStats>, Error> {
+        self.spawn(move |h| h.chain_stats(range)).await
+    }
+
+    /// Check whether a block is still part of the active chain.
+    /// See [`Handle::is_anchored`].
+    pub async fn is_anchored(&self, anchor: Anchor) -> Result<bool, Error> {
+        self.spawn(move |h| h.is_anchored(anchor)).await
+    }
+
+    /// Find the first block in the given range that uses one of the given scripts.
+    /// See [`Handle::first_use`].
+    pub async fn first_use(
+        &self,
+        range: RangeInclusive<Height>,
+        watch: Vec<Script>,
+    ) -> Result<Option<Height>, Error> {
+        self.spawn(move |h| h.first_use(range, watch.into_iter()))
+            .await
+    }
+
+    /// Rescan the given range of blocks for the given scripts. Returns a stream of the
+    /// filter events that are part of this rescan, which ends when the rescan completes,
+    /// is canceled, or is replaced by another rescan.
+    pub async fn rescan(
+        &self,
+        range: RangeInclusive<Height>,
+        watch: Vec<Script>,
+    ) -> Result<Rescan, Error> {
+        // Subscribe before starting the rescan, so that no event is missed.
+        let events = self.handle.events();
+        let id = self
+            .spawn(move |h| h.rescan(range, watch.into_iter()))
+            .await?;
+        let (sender, receiver) = mpsc::unbounded();
+
+        thread::spawn(move || {
+            // Whether the events we receive are part of our rescan.
+            let mut scanning = false;
+
+            while let Ok(event) = events.recv() {
+                let protocol::Event::Filter(event) = event else {
+                    continue;
+                };
+                match event {
+                    FilterEvent::RescanStarted { id: other, .. } if other == id => {
+                        scanning = true;
+                    }
+                    FilterEvent::RescanStarted { .. } if scanning => {
+                        break;
+                    }
+                    FilterEvent::RescanCompleted { id: other, .. }
+                    | FilterEvent::RescanCanceled { id: other, .. }
+                        if other == id =>
+                    {
+                        sender.unbounded_send(event).ok();
+                        break;
+                    }
+                    _ => {
+                        if scanning && sender.unbounded_send(event).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(Rescan { id, receiver })
+    }
+
+    /// Pause the rescan with the given id.
+    pub async fn pause_rescan(&self, id: RescanId) -> Result<(), Error> {
+        self.spawn(move |h| h.pause_rescan(id)).await
+    }
+
+    /// Resume the rescan with the given id.
+    pub async fn resume_rescan(&self, id: RescanId) -> Result<(), Error> {
+        self.spawn(move |h| h.resume_rescan(id)).await
+    }
+
+    /// Cancel the rescan with the given id.
+    pub async fn cancel_rescan(&self, id: RescanId) -> Result<(), Error> {
+        self.spawn(move |h| h.cancel_rescan(id)).await
+    }
+
+    /// Stream of blocks received.
+    pub fn blocks(&self) -> Subscription<(Block, Height)> {
+        Subscription::new(self.handle.blocks())
+    }
+
+    /// Stream of compact filters received.
+    pub fn filters(&self) -> Subscription<(BlockFilter, BlockHash, Height)> {
+        Subscription::new(self.handle.filters())
+    }
+
+    /// Stream of client events.
+    pub fn subscribe(&self) -> Subscription<Event> {
+        Subscription::new(self.handle.subscribe())
+    }
+
+    /// Stream of protocol events.
+    pub fn events(&self) -> Subscription<protocol::Event> {
+        Subscription::new(self.handle.events())
+    }
+
+    /// Shutdown the node process.
+    pub async fn shutdown(self) -> Result<(), Error> {
+        self.spawn(|h| h.clone().shutdown()).await
+    }
+
+    /// Run a blocking handle call on a worker thread, and return its result.
+    async fn spawn<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&H) -> Result<T, Error> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+
+        self.workers.submit(
+            &self.handle,
+            Box::new(move |h| {
+                let result = f(h);
+
+                Box::new(move || {
+                    sender.send(result).ok();
+                })
+            }),
+        );
+        receiver.await.map_err(|_| Error::Disconnected)?
+    }
+}
+
+/// A stream of events, bridged from a blocking subscription.
+#[derive(Debug)]
+pub struct Subscription<T> {
+    receiver: mpsc::UnboundedReceiver<T>,
+}
+
+impl<T: Send + 'static> Subscription<T> {
+    fn new(events: chan::Receiver<T>) -> Self {
+        let (sender, receiver) = mpsc::unbounded();
+
+        thread::spawn(move || {
+            while let Ok(event) = events.recv() {
+                if sender.unbounded_send(event).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self { receiver }
+    }
+}
+
+impl<T> Stream for Subscription<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+/// A running rescan, as a stream of its filter events.
+#[derive(Debug)]
+pub struct Rescan {
+    /// Rescan identifier.
+    pub id: RescanId,
+
+    receiver: mpsc::UnboundedReceiver<FilterEvent>,
+}
+
+impl Stream for Rescan {
+    type Item = FilterEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<FilterEvent>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_workers_reused() {
+        let workers = Workers::<()>::new();
+        let (sender, receiver) = chan::unbounded();
+
+        // Calls made one after the other all run on the same worker.
+        for _ in 0..8 {
+            let sender = sender.clone();
+            let (done, wait) = chan::bounded(0);
+
+            workers.submit(
+                &(),
+                Box::new(move |_| {
+                    sender.send(thread::current().id()).unwrap();
+                    Box::new(move || done.send(()).unwrap())
+                }),
+            );
+            wait.recv().unwrap();
+        }
+        let ids = receiver.try_iter().collect::<Vec<_>>();
+
+        assert_eq!(ids.len(), 8);
+        assert!(ids.iter().all(|id| *id == ids[0]));
+
+        // A call doesn't wait behind a blocked worker.
+        let (unblock, blocked) = chan::bounded::<()>(0);
+        let (done, wait) = chan::bounded(0);
+
+        workers.submit(
+            &(),
+            Box::new(move |_| {
+                blocked.recv().unwrap();
+                Box::new(|| {})
+            }),
+        );
+        workers.submit(
+            &(),
+            Box::new(move |_| Box::new(move || done.send(()).unwrap())),
+        );
+        wait.recv_timeout(time::Duration::from_secs(1)).unwrap();
+        unblock.send(()).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+//! Persistent ban list.
+//!
+//! Addresses banned through the client are saved to disk, so that bans survive restarts.
+//! The list is kept up to date by processing the protocol's ban events, and is loaded
+//! into the protocol configuration whenever the protocol is started.
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::{fs, io, net};
+
+use microserde::json::{Number, Value};
+
+use nakamoto_common::block::time::LocalTime;
+use nakamoto_p2p::protocol::{self, Ban, PeerEvent};
+
+use crate::kv;
+
+/// Ban list, backed by a key-value store namespace. Keys are addresses, and values are
+/// ban expiry times in seconds, or empty for permanent bans.
+#[derive(Debug)]
+pub struct Bans {
+    namespace: kv::Namespace,
+    bans: BTreeMap<net::IpAddr, Option<LocalTime>>,
+}
+
+impl Bans {
+    /// Open the ban list stored in the given namespace.
+    pub fn open(namespace: kv::Namespace) -> io::Result<Self> {
+        let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+        let mut bans = BTreeMap::new();
+
+        for (key, value) in namespace.entries() {
+            let addr = net::IpAddr::from_str(&key).map_err(|_| invalid())?;
+            let until = match value.as_slice() {
+                [] => None,
+                bytes => Some(LocalTime::from_secs(u64::from_le_bytes(
+                    bytes.try_into().map_err(|_| invalid())?,
+                ))),
+            };
+            bans.insert(addr, until);
+        }
+        Ok(Self { namespace, bans })
+    }
+
+    /// Import a ban list saved as JSON by earlier versions, and remove it. Does nothing if
+    /// the file doesn't exist.
+    pub fn import(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let s = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let invalid = || io::Error::from(io::ErrorKind::InvalidData);
+        let Value::Object(obj) = microserde::json::from_str(&s).map_err(|_| invalid())? else {
+            return Err(invalid());
+        };
+        let mut batch = self.namespace.batch();
+
+        for (k, v) in obj.into_iter() {
+            let addr = net::IpAddr::from_str(k.as_str()).map_err(|_| invalid())?;
+            let until = match v {
+                Value::Null => None,
+                Value::Number(Number::U64(secs)) => Some(LocalTime::from_secs(secs)),
+                _ => return Err(invalid()),
+            };
+            batch = batch.put(addr.to_string(), Self::encode(until));
+            self.bans.insert(addr, until);
+        }
+        batch.commit()?;
+        fs::remove_file(path)
+    }
+
+    fn encode(until: Option<LocalTime>) -> Vec<u8> {
+        until.map_or(Vec::new(), |t| {
+            (t.block_time() as u64).to_le_bytes().to_vec()
+        })
+    }
+
+    /// Iterate over all bans, including expired ones.
+    pub fn iter(&self) -> impl Iterator<Item = Ban> + '_ {
+        self.bans.iter().map(|(addr, until)| Ban {
+            addr: *addr,
+            until: *until,
+        })
+    }
+
+    /// Add a ban, replacing any existing ban on the same address, and save.
+    pub fn insert(&mut self, ban: Ban) -> io::Result<()> {
+        self.namespace
+            .put(ban.addr.to_string(), Self::encode(ban.until))?;
+        self.bans.insert(ban.addr, ban.until);
+
+        Ok(())
+    }
+
+    /// Remove a ban, and save. Returns `false` if the address wasn't banned.
+    pub fn remove(&mut self, addr: &net::IpAddr) -> io::Result<bool> {
+        if self.bans.remove(addr).is_none() {
+            return Ok(false);
+        }
+        self.namespace.remove(&addr.to_string())
+    }
+
+    /// Update the ban list based on a protocol event.
+    pub fn process(&mut self, event: &protocol::Event) -> io::Result<()> {
+        match event {
+            protocol::Event::Peer(PeerEvent::Banned(ban)) => self.insert(*ban),
+            protocol::Event::Peer(PeerEvent::Unbanned(addr)) => self.remove(addr).map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Ban list shared between the client's event publisher and the client.
+/// Bans are only persisted once a [`Bans`] store is set.
+#[derive(Debug, Clone, Default)]
+pub struct Shared(Arc<Mutex<Option<Bans>>>);
+
+impl Shared {
+    /// Set the ban store.
+    pub fn set(&self, bans: Bans) {
+        *self.0.lock().unwrap() = Some(bans);
+    }
+
+    /// Get all persisted bans.
+    pub fn list(&self) -> Vec<Ban> {
+        self.0
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|bans| bans.iter().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl protocol::event::Publisher for Shared {
+    fn publish(&mut self, event: protocol::Event) {
+        if let Some(bans) = self.0.lock().unwrap().as_mut() {
+            if let Err(err) = bans.process(&event) {
+                log::error!("Failed to save ban list: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = kv::Store::open(tmp.path().join("meta.db")).unwrap();
+        let permanent = Ban {
+            addr: [88, 88, 88, 88].into(),
+            until: None,
+        };
+        let temporary = Ban {
+            addr: net::Ipv6Addr::LOCALHOST.into(),
+            until: Some(LocalTime::from_secs(1_700_000_000)),
+        };
+
+        let mut bans = Bans::open(store.namespace("bans")).unwrap();
+        assert_eq!(bans.iter().count(), 0);
+
+        bans.process(&protocol::Event::Peer(PeerEvent::Banned(permanent)))
+            .unwrap();
+        bans.process(&protocol::Event::Peer(PeerEvent::Banned(temporary)))
+            .unwrap();
+
+        let loaded = Bans::open(store.namespace("bans")).unwrap();
+        assert_eq!(
+            loaded.iter().collect::<Vec<_>>(),
+            vec![permanent, temporary]
+        );
+
+        bans.process(&protocol::Event::Peer(PeerEvent::Unbanned(permanent.addr)))
+            .unwrap();
+
+        let loaded = Bans::open(store.namespace("bans")).unwrap();
+        assert_eq!(loaded.iter().collect::<Vec<_>>(), vec![temporary]);
+    }
+
+    #[test]
+    fn test_import() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("bans.json");
+        let store = kv::Store::open(tmp.path().join("meta.db")).unwrap();
+
+        fs::write(&path, "{\"88.88.88.88\":null,\"::1\":1700000000}\n").unwrap();
+
+        let mut bans = Bans::open(store.namespace("bans")).unwrap();
+        bans.import(&path).unwrap();
+        assert!(!path.exists());
+
+        let loaded = Bans::open(store.namespace("bans")).unwrap();
+        assert_eq!(
+            loaded.iter().collect::<Vec<_>>(),
+            vec![
+                Ban {
+                    addr: [88, 88, 88, 88].into(),
+                    until: None,
+                },
+                Ban {
+                    addr: net::Ipv6Addr::LOCALHOST.into(),
+                    until: Some(LocalTime::from_secs(1_700_000_000)),
+                },
+            ]
+        );
+    }
+}
@@ -4,21 +4,30 @@ use std::collections::HashMap;
 use std::env;
 use std::fs;
 use std::io;
+use std::iter;
 use std::net;
-use std::ops::RangeInclusive;
-use std::path::PathBuf;
+use std::ops::{Bound, RangeBounds, RangeInclusive};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::{self, SystemTime};
 
 pub use crossbeam_channel as chan;
 
-use nakamoto_chain::block::{store, Block};
+pub use nakamoto_chain::block::pow;
+use nakamoto_chain::block::store::{self, Fsync};
+use nakamoto_chain::block::Block;
 use nakamoto_chain::filter;
-use nakamoto_chain::filter::cache::FilterCache;
+use nakamoto_chain::filter::cache::{FilterCache, FilterHeader, Filters as _};
 use nakamoto_chain::{block::cache::BlockCache, filter::BlockFilter};
 
+use nakamoto_common::bitcoin::consensus::encode;
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::message::NetworkMessage;
 use nakamoto_common::bitcoin::network::Address;
+use nakamoto_common::bitcoin::{Script, Txid};
 use nakamoto_common::block::store::{Genesis as _, Store as _};
 use nakamoto_common::block::time::{AdjustedTime, RefClock};
 use nakamoto_common::block::tree::{self, BlockReader, ImportResult};
@@ -26,34 +35,177 @@ use nakamoto_common::block::{BlockHash, BlockHeader, Height, Transaction};
 use nakamoto_common::nonempty::NonEmpty;
 use nakamoto_common::p2p::peer::{Source, Store as _};
 
+pub use nakamoto_common::block::Anchor;
+pub use nakamoto_common::error::{Category, Classify};
 pub use nakamoto_common::network::{Network, Services};
-pub use nakamoto_common::p2p::Domain;
+pub use nakamoto_common::p2p::{Domain, DomainPolicy, PeerAddr};
 
 use nakamoto_p2p as p2p;
+use nakamoto_p2p::protocol::capture::Capture;
 use nakamoto_p2p::protocol::Link;
 use nakamoto_p2p::protocol::Protocol;
+use nakamoto_p2p::protocol::RescanId;
 
 pub use nakamoto_p2p::event;
-pub use nakamoto_p2p::protocol::{self, Command, CommandError, Peer};
-pub use nakamoto_p2p::traits::Reactor;
+pub use nakamoto_p2p::protocol::{self, Command, CommandError, ConfigUpdate, Peer};
+pub use nakamoto_p2p::traits::{Keepalive, Reactor, ReactorConfig};
 
+pub use crate::ban;
+pub use crate::config::{ClientConfig, Profile};
 pub use crate::error::Error;
 pub use crate::event::Event;
+pub use crate::fleet;
 pub use crate::handle;
+pub use crate::journal;
+pub use crate::kv;
+pub use crate::notify;
 pub use crate::peer;
+pub use crate::rescan;
 pub use crate::spv;
+pub use crate::stats;
+
+/// How long to wait before restarting a failed reactor.
+pub const RESTART_DELAY: time::Duration = time::Duration::from_secs(1);
+/// Number of stored block headers to check the proof-of-work of, when loading the store.
+pub const VERIFY_SAMPLE_SIZE: usize = 1000;
+/// Number of store writes that can be queued for the background flusher, before writing
+/// blocks.
+pub const FLUSH_QUEUE_SIZE: usize = 1024;
+/// Key under which the last verified filter header is kept, in the `sync` namespace.
+const VERIFIED_FILTER_HEADER: &str = "filters";
+/// Number of events that can be queued for installed publishers, before events are
+/// dropped for them.
+pub const PUBLISH_QUEUE_SIZE: usize = 4096;
+
+/// The protocol run by [`Client::run`], with its state loaded from disk.
+type ClientProtocol = Protocol<
+    BlockCache<store::Flusher<store::File<BlockHeader>>>,
+    FilterCache<FilterStore>,
+    peer::Cache,
+    RefClock<AdjustedTime<net::SocketAddr>>,
+>;
+
+/// Filter header store.
+///
+/// In headers-only mode, filter headers are never synced, so only the genesis filter
+/// header is kept, in memory, and no filter store is created on disk.
+enum FilterStore {
+    /// Filter headers stored on disk, and written in the background.
+    File(store::Flusher<store::File<filter::cache::StoredHeader>>),
+    /// Filter headers kept in memory.
+    Memory(store::Memory<filter::cache::StoredHeader>),
+}
+
+impl store::Store for FilterStore {
+    type Header = filter::cache::StoredHeader;
+
+    fn genesis(&self) -> Self::Header {
+        match self {
+            Self::File(s) => s.genesis(),
+            Self::Memory(s) => s.genesis(),
+        }
+    }
+
+    fn put<I: Iterator<Item = Self::Header>>(
+        &mut self,
+        headers: I,
+    ) -> Result<Height, store::Error> {
+        match self {
+            Self::File(s) => s.put(headers),
+            Self::Memory(s) => s.put(headers),
+        }
+    }
+
+    fn get(&self, height: Height) -> Result<Self::Header, store::Error> {
+        match self {
+            Self::File(s) => s.get(height),
+            Self::Memory(s) => s.get(height),
+        }
+    }
+
+    fn rollback(&mut self, height: Height) -> Result<(), store::Error> {
+        match self {
+            Self::File(s) => s.rollback(height),
+            Self::Memory(s) => s.rollback(height),
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), store::Error> {
+        match self {
+            Self::File(s) => s.sync(),
+            Self::Memory(s) => s.sync(),
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<(Height, Self::Header), store::Error>>> {
+        match self {
+            Self::File(s) => s.iter(),
+            Self::Memory(s) => s.iter(),
+        }
+    }
+
+    fn len(&self) -> Result<usize, store::Error> {
+        match self {
+            Self::File(s) => s.len(),
+            Self::Memory(s) => s.len(),
+        }
+    }
+
+    fn height(&self) -> Result<Height, store::Error> {
+        match self {
+            Self::File(s) => s.height(),
+            Self::Memory(s) => s.height(),
+        }
+    }
+
+    fn check(&self) -> Result<(), store::Error> {
+        match self {
+            Self::File(s) => s.check(),
+            Self::Memory(s) => s.check(),
+        }
+    }
+
+    fn heal(&self) -> Result<(), store::Error> {
+        match self {
+            Self::File(s) => s.heal(),
+            Self::Memory(s) => s.heal(),
+        }
+    }
+}
 
 /// Client configuration.
 #[derive(Debug, Clone)]
 pub struct Config {
     /// Client protocol configuration.
     pub protocol: protocol::Config,
-    /// Client listen addresses.
+    /// Client listen addresses. A listener is bound on each address, so IPv4 and IPv6
+    /// addresses can be listened on together.
     pub listen: Vec<net::SocketAddr>,
     /// Client home path, where runtime data is stored, eg. block headers and filters.
     pub root: PathBuf,
     /// Client name. Used for logging only.
     pub name: &'static str,
+    /// Policy for flushing block and filter header writes to disk.
+    pub fsync: Fsync,
+    /// Network reactor configuration.
+    pub reactor: ReactorConfig,
+    /// Whether to keep a journal of client events. See [`journal`].
+    pub journal: bool,
+    /// Notification publisher configuration, if enabled. See [`notify`].
+    pub notify: Option<notify::Config>,
+    /// How many times in a row the reactor is restarted after a failure, before giving up.
+    pub max_restarts: usize,
+    /// How long the reactor has to run without failing for the restart count to be reset.
+    /// Rare failures of a long-running client are then always recovered from.
+    pub stable_uptime: time::Duration,
+    /// Path of an asmap file, used to group peer addresses by autonomous system. Relative
+    /// paths are resolved against the network's data directory. See [`protocol::asmap`].
+    pub asmap: Option<PathBuf>,
+    /// Fleet configuration, if the client is part of a fleet. See [`fleet`].
+    pub fleet: Option<fleet::Config>,
+    /// Block header proof-of-work verifier. With the `rayon` feature, `pow::Parallel`
+    /// speeds up initial sync on machines with cores to spare.
+    pub verifier: Arc<dyn pow::Verifier>,
 }
 
 impl Config {
@@ -91,23 +243,55 @@ impl Default for Config {
             listen: vec![([0, 0, 0, 0], 0).into()],
             root: PathBuf::from(env::var("HOME").unwrap_or_default()),
             name: "client",
+            fsync: Fsync::default(),
+            reactor: ReactorConfig::default(),
+            journal: false,
+            notify: None,
+            max_restarts: 8,
+            stable_uptime: time::Duration::from_secs(60 * 10),
+            asmap: None,
+            fleet: None,
+            verifier: Arc::new(pow::Sequential),
         }
     }
 }
 
+/// Publishers installed by the user, in addition to the client's own.
+type Installed = Arc<Mutex<Vec<Box<dyn protocol::event::Publisher>>>>;
+
 /// The client's event publisher.
+///
+/// The client's own publishers run on the reactor thread, since they're quick. Installed
+/// publishers, eg. writing to a socket, run on a dedicated thread instead, so that they
+/// can't stall the reactor. If they fall behind by more than [`PUBLISH_QUEUE_SIZE`]
+/// events, events are dropped for them.
 pub struct Publisher {
     publishers: Vec<Box<dyn protocol::event::Publisher>>,
+    installed: chan::Sender<protocol::Event>,
 }
 
 impl Publisher {
-    fn new() -> Self {
-        Self {
+    pub(crate) fn new(installed: Installed) -> io::Result<Self> {
+        let (sender, receiver) = chan::bounded::<protocol::Event>(PUBLISH_QUEUE_SIZE);
+
+        // The thread exits once the publisher is dropped.
+        thread::Builder::new()
+            .name(String::from("event-publisher"))
+            .spawn(move || {
+                for e in receiver {
+                    for p in installed.lock().unwrap().iter_mut() {
+                        p.publish(e.clone());
+                    }
+                }
+            })?;
+
+        Ok(Self {
             publishers: Vec::new(),
-        }
+            installed: sender,
+        })
     }
 
-    fn register(mut self, publisher: impl protocol::event::Publisher + 'static) -> Self {
+    pub(crate) fn register(mut self, publisher: impl protocol::event::Publisher + 'static) -> Self {
         self.publishers.push(Box::new(publisher));
         self
     }
@@ -118,6 +302,110 @@ impl protocol::event::Publisher for Publisher {
         for p in self.publishers.iter_mut() {
             p.publish(e.clone());
         }
+        if let Err(chan::TrySendError::Full(e)) = self.installed.try_send(e) {
+            log::warn!(
+                "Installed event publishers are falling behind, dropping {}",
+                e.kind()
+            );
+        }
+    }
+}
+
+/// Tracks the confirmations of a transaction from client events, accounting for re-orgs.
+/// See [`handle::Handle::wait_for_confirmation`].
+#[derive(Debug)]
+pub(crate) struct Confirmations {
+    /// The transaction.
+    pub(crate) txid: Txid,
+    /// Number of confirmations to reach. Must be greater than zero.
+    pub(crate) depth: Height,
+    /// Height and hash of the block confirming the transaction, if any.
+    pub(crate) confirmed: Option<(Height, BlockHash)>,
+    /// Height of the active chain.
+    pub(crate) tip: Height,
+}
+
+impl Confirmations {
+    /// Get the confirming block, if the transaction has enough confirmations.
+    pub(crate) fn reached(&self) -> Option<(Height, BlockHash)> {
+        self.confirmed
+            .filter(|(height, _)| self.tip >= *height && self.tip - height + 1 >= self.depth)
+    }
+
+    /// Process a client event, and get the confirming block, if the transaction now has
+    /// enough confirmations.
+    pub(crate) fn process(&mut self, event: Event) -> Option<(Height, BlockHash)> {
+        match event {
+            Event::TxStatusChanged {
+                txid,
+                status: spv::TxStatus::Confirmed { block },
+            } if txid == self.txid => {
+                self.confirmed = Some((block.height, block.hash));
+            }
+            Event::TxStatusChanged {
+                txid,
+                status: spv::TxStatus::Reverted,
+            } if txid == self.txid => {
+                self.confirmed = None;
+            }
+            // Transactions paying to watched scripts are found in matching blocks.
+            Event::BlockMatched {
+                block,
+                transactions,
+                ..
+            } if transactions.iter().any(|tx| tx.txid() == self.txid) => {
+                self.confirmed = Some((block.height, block.hash));
+            }
+            Event::BlockConnected { block, .. } => {
+                self.tip = self.tip.max(block.height);
+            }
+            Event::BlockDisconnected { block, .. } => {
+                self.tip = block.height - 1;
+
+                if self.confirmed.is_some_and(|(h, _)| h >= block.height) {
+                    self.confirmed = None;
+                }
+            }
+            _ => {}
+        }
+        self.reached()
+    }
+}
+
+/// Re-seeds the address book from DNS whenever the protocol attempts to reconnect to
+/// peers after a network partition.
+struct Reseeder<H> {
+    handle: H,
+    network: Network,
+}
+
+impl<H: handle::Handle + Clone + Sync + 'static> protocol::event::Publisher for Reseeder<H> {
+    fn publish(&mut self, event: protocol::Event) {
+        let protocol::Event::Resync(protocol::ResyncEvent::Reconnecting { attempt, .. }) = event
+        else {
+            return;
+        };
+        let handle = self.handle.clone();
+        let network = self.network.clone();
+
+        // Nb. Resolving seeds blocks, so it mustn't be done on the reactor thread.
+        thread::spawn(move || {
+            let addrs = network
+                .seeds()
+                .iter()
+                .filter_map(|seed| {
+                    net::ToSocketAddrs::to_socket_addrs(&(*seed, network.port())).ok()
+                })
+                .flatten()
+                .collect::<Vec<_>>();
+
+            log::info!(
+                "Resolved {} address(es) from DNS seeds (attempt {})",
+                addrs.len(),
+                attempt
+            );
+            handle.import_seeds(addrs).ok();
+        });
     }
 }
 
@@ -130,6 +418,17 @@ pub struct Client<R: Reactor<Publisher>> {
     subscriber: event::Subscriber<Event>,
     shutdown: chan::Sender<()>,
     seeds: Vec<net::SocketAddr>,
+    journal: journal::Shared,
+    rescans: rescan::Shared,
+    bans: ban::Shared,
+    installed: Installed,
+    /// Dropped with the client, which disconnects the watchdog of all its handles.
+    /// Nothing is ever sent on this channel.
+    _alive: chan::Sender<()>,
+    watchdog: chan::Receiver<()>,
+    shutting_down: Arc<AtomicBool>,
+    /// Whether the client runs in headers-only mode. Set when the client is run.
+    headers_only: Arc<AtomicBool>,
 
     reactor: R,
 }
@@ -160,12 +459,22 @@ impl<R: Reactor<Publisher>> Client<R> {
                 p.emit((filter, block_hash, height));
             }
         });
+        let rescans = rescan::Shared::default();
         let (publisher, subscriber) = event::broadcast({
-            let mut spv = spv::Mapper::new();
+            let mut spv = spv::Mapper::with_rescans(rescans.clone());
             move |e, p| spv.process(e, p)
         });
 
-        let publisher = Publisher::new()
+        let journal = journal::Shared::default();
+        let bans = ban::Shared::default();
+        let installed = Installed::default();
+
+        // The journal, rescan tasks and ban list are updated first, so that they are up
+        // to date by the time subscribers receive an event.
+        let publisher = Publisher::new(installed.clone())?
+            .register(journal.clone())
+            .register(rescans.clone())
+            .register(bans.clone())
             .register(event_pub)
             .register(blocks_pub)
             .register(filters_pub)
@@ -173,6 +482,7 @@ impl<R: Reactor<Publisher>> Client<R> {
 
         let seeds = Vec::new();
         let (shutdown, shutdown_recv) = chan::bounded(1);
+        let (alive, watchdog) = chan::bounded(0);
         let reactor = R::new(publisher, commands, shutdown_recv)?;
 
         Ok(Self {
@@ -184,6 +494,14 @@ impl<R: Reactor<Publisher>> Client<R> {
             subscriber,
             seeds,
             shutdown,
+            journal,
+            rescans,
+            bans,
+            installed,
+            _alive: alive,
+            watchdog,
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            headers_only: Arc::new(AtomicBool::new(false)),
         })
     }
 
@@ -196,36 +514,213 @@ impl<R: Reactor<Publisher>> Client<R> {
         Ok(())
     }
 
+    /// Install an additional publisher of protocol events, eg. a
+    /// [`protocol::event::JsonLines`] writer or a [`protocol::event::Callback`]. Installed
+    /// publishers run on a dedicated thread, and receive events after all of the client's
+    /// subscribers. Use [`protocol::event::Publisher::filter`] to only publish some events.
+    pub fn install(&self, publisher: impl protocol::event::Publisher + 'static) {
+        self.installed.lock().unwrap().push(Box::new(publisher));
+    }
+
     /// Start the client process. This function is meant to be run in its own thread.
-    pub fn run(mut self, config: Config) -> Result<(), Error> {
+    pub fn run(mut self, config: Config) -> Result<(), Error>
+    where
+        R: 'static,
+    {
+        // Without authentication, the trusted peer would be disconnected at every handshake,
+        // and blocks never fetched.
+        if config.protocol.trusted_peer_identity.is_some() && !R::AUTHENTICATES_PEERS {
+            return Err(crate::config::Error::TrustedPeerIdentityUnsupported.into());
+        }
         let home = config.root.join(".nakamoto");
-        let network = config.protocol.network;
+        let network = config.protocol.network.clone();
         let dir = home.join(network.as_str());
         let listen = config.listen.clone();
 
+        self.headers_only
+            .store(config.protocol.headers_only, Ordering::SeqCst);
+
         fs::create_dir_all(&dir)?;
 
-        let genesis = network.genesis();
-        let params = network.params();
+        // Nb. The lock is held until the client exits, and prevents other client instances
+        // from using the same data directory.
+        let _lock = self::lock(&dir.join("LOCK"), network.clone())?;
 
         log::info!("Initializing client ({:?})..", network);
         log::info!("Genesis block hash is {}", network.genesis_hash());
 
+        if config.journal {
+            let path = dir.join("events.journal");
+            let journal = journal::Journal::open(&path, config.fsync)?;
+
+            log::info!(
+                "Journaling events to {:?} from sequence number {}",
+                path,
+                journal.next_seq()
+            );
+            self.journal.set(journal);
+        }
+        // Nb. The publisher stops accepting subscribers when dropped, as the client exits.
+        let _publisher = if let Some(notify) = &config.notify {
+            let notifier = notify::Notifier::bind(notify)?;
+
+            log::info!("Publishing notifications on {}", notifier.local_addr()?);
+            Some(notifier.spawn(self.subscriber.subscribe())?)
+        } else {
+            None
+        };
+        match &config.fleet {
+            Some(fleet::Config::Leader {
+                listen,
+                key,
+                interval,
+            }) => {
+                let leader = fleet::Leader::bind(*listen, *key, *interval)?;
+
+                log::info!(
+                    "Publishing signed tips to fleet on {}",
+                    leader.local_addr()?
+                );
+                leader.spawn(self.handle());
+            }
+            Some(fleet::Config::Member {
+                leader,
+                key,
+                max_lag,
+            }) => {
+                let emitter = self.subscriber.emitter();
+
+                log::info!("Comparing tips with fleet leader {}", leader);
+                fleet::Member::new(*leader, *key, *max_lag)
+                    .spawn(self.handle(), move |e| emitter.emit(e));
+            }
+            None => {}
+        }
+
+        // In connect-only mode, peers from the address book are never connected to.
+        if !config.protocol.connect_only && !network.seeds().is_empty() {
+            self.install(Reseeder {
+                handle: self.handle(),
+                network,
+            });
+        }
+
+        let meta = kv::Store::open(dir.join("meta.db"))?;
+
+        let mut rescans = rescan::Tasks::open(meta.namespace("rescans"))?;
+        rescans.import(dir.join("rescans.db"))?;
+        self.rescans.set(rescans);
+
+        let mut bans = ban::Bans::open(meta.namespace("bans")).map_err(Error::BanList)?;
+        bans.import(dir.join("bans.json")).map_err(Error::BanList)?;
+        self.bans.set(bans);
+        self.reactor.configure(config.reactor.clone());
+
+        // Supervise the reactor: if it fails or panics, tear down its sockets, and run it
+        // again with the protocol state reloaded from disk.
+        let mut restarts = 0;
+        loop {
+            let protocol = self.load(&config, &dir, &meta)?;
+
+            for task in self.rescans.list()? {
+                // Resume rescans that were interrupted.
+                if task.status == rescan::Status::Running {
+                    log::info!(
+                        "Resuming rescan {} from height {} ({} pending block(s))",
+                        task.id,
+                        task.next,
+                        task.pending.len()
+                    );
+                    for cmd in task.resume() {
+                        self.handle.send(cmd).map_err(|_| Error::Channel)?;
+                    }
+                    R::wake(&self.reactor.waker())?;
+                }
+            }
+
+            let started = time::Instant::now();
+            let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                self.reactor.run(&listen, protocol)
+            }));
+            let err = match result {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(err)) => Error::from(err),
+                Err(payload) => Error::Panic(self::panic_message(payload)),
+            };
+            if started.elapsed() >= config.stable_uptime {
+                restarts = 0;
+            }
+            if restarts >= config.max_restarts {
+                return Err(err);
+            }
+            restarts += 1;
+
+            log::error!(
+                "Reactor failed: {}, restarting ({}/{})..",
+                err,
+                restarts,
+                config.max_restarts
+            );
+            self.reactor.reset();
+            thread::sleep(RESTART_DELAY);
+            self.subscriber.emitter().emit(Event::Restarted {
+                restarts,
+                reason: err.to_string(),
+            });
+        }
+    }
+
+    /// Load the protocol state from disk.
+    fn load(&self, config: &Config, dir: &Path, meta: &kv::Store) -> Result<ClientProtocol, Error> {
+        let network = config.protocol.network.clone();
+        let genesis = network.genesis();
+        let params = network.params();
+        let checkpoints = network.checkpoints().collect::<Vec<_>>();
+        let rng = fastrand::Rng::new();
+
         let path = dir.join("headers.db");
-        let store = match store::File::create(&path, genesis) {
+        let store = match store::File::create(&path, genesis).map(|s| s.fsync(config.fsync)) {
             Ok(store) => {
                 log::info!("Initializing new block store {:?}", path);
                 store
             }
             Err(store::Error::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists => {
                 log::info!("Found existing store {:?}", path);
-                let store = store::File::open(path, genesis)?;
+                let mut store = store::File::open(path, genesis)?.fsync(config.fsync);
 
                 if store.check().is_err() {
                     log::warn!("Corruption detected in header store, healing..");
                     store.heal()?; // Rollback store to the last valid header.
                 }
                 log::info!("Store height = {}", store.height()?);
+                log::info!("Verifying block headers..");
+
+                // Nb. Checking the proof-of-work of every header would slow down startup
+                // noticeably, so we only check a sample of headers, and the tip.
+                let height = store.height()?;
+                let sample = iter::repeat_with(|| rng.u64(1..=height.max(1)))
+                    .take(VERIFY_SAMPLE_SIZE)
+                    .chain(iter::once(height))
+                    .collect();
+
+                if let Some((invalid, err)) =
+                    BlockCache::verify(&store, &params, &checkpoints, &sample)?
+                {
+                    let height = invalid - 1;
+
+                    log::warn!(
+                        "Invalid header found at height {}: {}, truncating store..",
+                        invalid,
+                        err
+                    );
+                    store.rollback(height)?;
+
+                    self.subscriber.emitter().emit(Event::StoreRecovered {
+                        store: "headers",
+                        height,
+                        reason: err.to_string(),
+                    });
+                }
                 log::info!("Loading block headers from store..");
 
                 store
@@ -234,23 +729,102 @@ impl<R: Reactor<Publisher>> Client<R> {
         };
 
         let local_time = SystemTime::now().into();
-        let checkpoints = network.checkpoints().collect::<Vec<_>>();
         let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
-        let cache = BlockCache::from(store, params, &checkpoints)?;
-        let rng = fastrand::Rng::new();
+        store.lock()?;
+        // Store writes are applied in the background, so that slow disks don't stall the
+        // protocol.
+        let store = store::Flusher::spawn(store, FLUSH_QUEUE_SIZE)?;
+        let cache =
+            BlockCache::from(store, params, &checkpoints)?.verifier(config.verifier.clone());
+
+        let filters = if config.protocol.headers_only {
+            log::info!("Skipping block filters in headers-only mode..");
+
+            FilterCache::from(FilterStore::Memory(store::Memory::genesis(network.clone())))?
+        } else {
+            self.load_filters(config, dir, meta, cache.height())?
+        };
+
+        log::info!("Loading peer addresses..");
+
+        let mut peers = peer::Cache::open(meta.namespace("peers")).map_err(Error::PeerStore)?;
+        peers
+            .import(dir.join("peers.json"))
+            .map_err(Error::PeerStore)?;
+
+        let cfpeers = peers
+            .iter()
+            .filter(|(_, ka)| ka.addr.services.has(ServiceFlags::COMPACT_FILTERS))
+            .count();
+
+        log::info!(
+            "{} peer(s) found.. {} with compact filters support",
+            peers.len(),
+            cfpeers
+        );
+        log::trace!("{:#?}", peers);
+
+        if config.protocol.connect.is_empty() && peers.is_empty() {
+            log::info!("Address book is empty. Trying DNS seeds..");
+            peers.seed(
+                network.seeds().iter().map(|s| (*s, network.port())),
+                Source::Dns,
+            )?;
+            peers.flush()?;
+
+            log::info!("{} seeds added to address book", peers.len());
+        }
+
+        let mut protocol = config.protocol.clone();
+        protocol.bans.extend(self.bans.list());
+
+        if let Some(path) = &config.asmap {
+            let path = dir.join(path);
+            let asmap = protocol::asmap::Asmap::load(&path)?;
+
+            log::info!("Loaded asmap {:?} ({} bytes)", path, asmap.len());
+            protocol.asmap = Some(asmap);
+        }
+        // Nb. The bucketing key is kept across restarts, so that addresses stay in the same
+        // buckets.
+        protocol.addrmgr_key =
+            Some(peer::bucket_key(&meta.namespace("addrman"), &rng).map_err(Error::PeerStore)?);
 
+        Ok(Protocol::new(
+            cache,
+            filters,
+            peers,
+            RefClock::from(clock),
+            rng,
+            protocol,
+        ))
+    }
+
+    /// Load the filter header store, repairing it if necessary. Filter headers above the
+    /// given block header height are rolled back.
+    fn load_filters(
+        &self,
+        config: &Config,
+        dir: &Path,
+        meta: &kv::Store,
+        height: Height,
+    ) -> Result<FilterCache<FilterStore>, Error> {
         log::info!("Initializing block filters..");
 
-        let cfheaders_genesis = filter::cache::StoredHeader::genesis(network);
+        let network = config.protocol.network.clone();
+        let cfheaders_genesis = filter::cache::StoredHeader::genesis(network.clone());
         let cfheaders_path = dir.join("filters.db");
-        let cfheaders_store = match store::File::create(&cfheaders_path, cfheaders_genesis) {
+        let mut cfheaders_store = match store::File::create(&cfheaders_path, cfheaders_genesis)
+            .map(|s| s.fsync(config.fsync))
+        {
             Ok(store) => {
                 log::info!("Initializing new filter header store {:?}", cfheaders_path);
                 store
             }
             Err(store::Error::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists => {
                 log::info!("Found existing store {:?}", cfheaders_path);
-                let store = store::File::open(cfheaders_path, cfheaders_genesis)?;
+                let store =
+                    store::File::open(cfheaders_path, cfheaders_genesis)?.fsync(config.fsync);
 
                 if store.check().is_err() {
                     log::warn!("Corruption detected in filter store, healing..");
@@ -264,64 +838,55 @@ impl<R: Reactor<Publisher>> Client<R> {
             Err(err) => return Err(err.into()),
         };
 
-        let filters = FilterCache::from(cfheaders_store)?;
-        log::info!("Verifying filter headers..");
-        filters.verify(network)?; // Verify store integrity.
-
-        log::info!("Loading peer addresses..");
-
-        let peers_path = dir.join("peers.json");
-        let mut peers = match peer::Cache::create(&peers_path) {
-            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
-                log::info!("Found existing peer cache {:?}", peers_path);
-                let cache = peer::Cache::open(&peers_path).map_err(Error::PeerStore)?;
-                let cfpeers = cache
-                    .iter()
-                    .filter(|(_, ka)| ka.addr.services.has(ServiceFlags::COMPACT_FILTERS))
-                    .count();
+        // Filter headers can't be ahead of block headers.
+        if cfheaders_store.height()? > height {
+            log::warn!("Filter headers are ahead of block headers, truncating store..");
+            cfheaders_store.rollback(height)?;
 
-                log::info!(
-                    "{} peer(s) found.. {} with compact filters support",
-                    cache.len(),
-                    cfpeers
-                );
-                cache
-            }
-            Err(err) => {
-                return Err(Error::PeerStore(err));
-            }
-            Ok(cache) => {
-                log::info!("Initializing new peer address cache {:?}", peers_path);
-                cache
-            }
-        };
+            self.subscriber.emitter().emit(Event::StoreRecovered {
+                store: "filters",
+                height,
+                reason: String::from("filter headers are ahead of block headers"),
+            });
+        }
 
-        log::trace!("{:#?}", peers);
+        cfheaders_store.lock()?;
+        let cfheaders_store = store::Flusher::spawn(cfheaders_store, FLUSH_QUEUE_SIZE)?;
+        let mut filters = FilterCache::from(FilterStore::File(cfheaders_store))?;
+
+        // Filter headers verified by a previous run are not verified again, which would
+        // take a while with a long chain.
+        let sync = meta.namespace("sync");
+        let verified = sync
+            .get(VERIFIED_FILTER_HEADER)
+            .and_then(|bytes| encode::deserialize::<(Height, FilterHeader)>(&bytes).ok());
+        log::info!(
+            "Verifying filter headers from height {}..",
+            verified.map_or(0, |(height, _)| height)
+        );
 
-        if config.protocol.connect.is_empty() && peers.is_empty() {
-            log::info!("Address book is empty. Trying DNS seeds..");
-            peers.seed(
-                network.seeds().iter().map(|s| (*s, network.port())),
-                Source::Dns,
-            )?;
-            peers.flush()?;
+        if let Some(invalid) = filters.repair_from(network.clone(), verified)? {
+            let height = invalid - 1;
 
-            log::info!("{} seeds added to address book", peers.len());
+            log::warn!(
+                "Invalid filter header found at height {}, truncated store to height {}",
+                invalid,
+                height
+            );
+            self.subscriber.emitter().emit(Event::StoreRecovered {
+                store: "filters",
+                height,
+                reason: String::from("filter header doesn't commit to its parent"),
+            });
         }
-
-        self.reactor.run(
-            &listen,
-            Protocol::new(
-                cache,
-                filters,
-                peers,
-                RefClock::from(clock),
-                rng,
-                config.protocol,
-            ),
+        // Nb. After a repair, the filter header chain is valid up to its tip.
+        let (_, tip) = filters.tip();
+        sync.put(
+            VERIFIED_FILTER_HEADER,
+            encode::serialize(&(filters.height(), *tip)),
         )?;
 
-        Ok(())
+        Ok(filters)
     }
 
     /// Start the client process, supplying the block cache. This function is meant to be run in
@@ -342,15 +907,53 @@ impl<R: Reactor<Publisher>> Client<R> {
             waker: self.reactor.waker(),
             commands: self.handle.clone(),
             timeout: time::Duration::from_secs(60),
+            deadline: None,
             blocks: self.blocks.clone(),
             filters: self.filters.clone(),
             subscriber: self.subscriber.clone(),
             shutdown: self.shutdown.clone(),
+            journal: self.journal.clone(),
+            rescans: self.rescans.clone(),
+            watchdog: self.watchdog.clone(),
+            shutting_down: self.shutting_down.clone(),
+            headers_only: self.headers_only.clone(),
         }
     }
 }
 
+/// Take an exclusive advisory lock on the given file, creating it if needed.
+/// Returns [`Error::AlreadyRunning`] if the lock is held by another client.
+fn lock(path: &Path, network: Network) -> Result<fs::File, Error> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(fs::TryLockError::WouldBlock) => Err(Error::AlreadyRunning(network)),
+        Err(fs::TryLockError::Error(err)) => Err(err.into()),
+    }
+}
+
+/// Get the message of a panic payload.
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        String::from("unknown panic")
+    }
+}
+
 /// An instance of [`handle::Handle`] for [`Client`].
+///
+/// Handles are cheap to clone, and can be shared between threads. Commands from all
+/// handles are sent over a single synchronized channel, while each call to
+/// [`handle::Handle::events`], [`handle::Handle::subscribe`] and friends creates a new,
+/// independent subscription, so that handles don't steal each other's events.
 pub struct Handle<R: Reactor<Publisher>> {
     commands: chan::Sender<Command>,
     events: event::Subscriber<protocol::Event>,
@@ -359,13 +962,16 @@ pub struct Handle<R: Reactor<Publisher>> {
     subscriber: event::Subscriber<Event>,
     waker: R::Waker,
     timeout: time::Duration,
+    deadline: Option<time::Duration>,
     shutdown: chan::Sender<()>,
+    journal: journal::Shared,
+    rescans: rescan::Shared,
+    watchdog: chan::Receiver<()>,
+    shutting_down: Arc<AtomicBool>,
+    headers_only: Arc<AtomicBool>,
 }
 
-impl<R: Reactor<Publisher>> Clone for Handle<R>
-where
-    R::Waker: Sync,
-{
+impl<R: Reactor<Publisher>> Clone for Handle<R> {
     fn clone(&self) -> Self {
         Self {
             blocks: self.blocks.clone(),
@@ -374,27 +980,40 @@ where
             filters: self.filters.clone(),
             subscriber: self.subscriber.clone(),
             timeout: self.timeout,
+            deadline: self.deadline,
             waker: self.waker.clone(),
             shutdown: self.shutdown.clone(),
+            journal: self.journal.clone(),
+            rescans: self.rescans.clone(),
+            watchdog: self.watchdog.clone(),
+            shutting_down: self.shutting_down.clone(),
+            headers_only: self.headers_only.clone(),
         }
     }
 }
 
-impl<R: Reactor<Publisher>> Handle<R>
-where
-    R::Waker: Sync,
-{
+impl<R: Reactor<Publisher>> Handle<R> {
     /// Set the timeout for operations that wait on the network.
     pub fn set_timeout(&mut self, timeout: time::Duration) {
         self.timeout = timeout;
     }
 
+    /// Get a handle that gives up on queries that aren't answered by the client within
+    /// the given deadline, returning [`handle::Error::Timeout`]. Without a deadline,
+    /// queries wait until they are answered, or until the client stops.
+    pub fn with_deadline(&self, deadline: time::Duration) -> Self {
+        Self {
+            deadline: Some(deadline),
+            ..self.clone()
+        }
+    }
+
     /// Get connected peers.
     pub fn get_peers(&self, services: impl Into<ServiceFlags>) -> Result<Vec<Peer>, handle::Error> {
         let (sender, recvr) = chan::bounded(1);
         self._command(Command::GetPeers(services.into(), sender))?;
 
-        Ok(recvr.recv()?)
+        self._recv(recvr)
     }
 
     /// Get block by height.
@@ -405,35 +1024,77 @@ where
         let (sender, recvr) = chan::bounded(1);
         self._command(Command::GetBlockByHeight(height, sender))?;
 
-        Ok(recvr.recv()?)
+        self._recv(recvr)
     }
 
     /// Send a command to the command channel, and wake up the event loop.
     fn _command(&self, cmd: Command) -> Result<(), handle::Error> {
-        self.commands.send(cmd)?;
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return Err(handle::Error::ShuttingDown);
+        }
+        self.commands.send(cmd).map_err(|_| self.disconnected())?;
         R::wake(&self.waker)?;
 
         Ok(())
     }
+
+    /// Wait for the reply to a command. Fails if the deadline is reached, or if the
+    /// client stops before replying, instead of blocking forever.
+    fn _recv<T>(&self, reply: chan::Receiver<T>) -> Result<T, handle::Error> {
+        let deadline = self.deadline.map(chan::after).unwrap_or_else(chan::never);
+
+        chan::select! {
+            recv(reply) -> result => result.map_err(|_| self.disconnected()),
+            // Nothing is ever sent on this channel; it only disconnects once the
+            // client is dropped.
+            recv(self.watchdog) -> _ => reply.try_recv().map_err(|_| self.disconnected()),
+            recv(deadline) -> _ => Err(handle::Error::Timeout),
+        }
+    }
+
+    /// The error returned when the client can no longer be reached.
+    fn disconnected(&self) -> handle::Error {
+        if self.shutting_down.load(Ordering::SeqCst) {
+            handle::Error::ShuttingDown
+        } else {
+            handle::Error::Disconnected
+        }
+    }
 }
 
-impl<R: Reactor<Publisher>> handle::Handle for Handle<R>
-where
-    R::Waker: Sync,
-{
+impl<R: Reactor<Publisher>> handle::Handle for Handle<R> {
     fn get_tip(&self) -> Result<(Height, BlockHeader), handle::Error> {
         let (transmit, receive) = chan::bounded::<(Height, BlockHeader)>(1);
         self.command(Command::GetTip(transmit))?;
 
-        Ok(receive.recv()?)
+        self._recv(receive)
+    }
+
+    fn query_peers(&self) -> Result<Vec<protocol::PeerInfo>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::QueryPeers(transmit))?;
+
+        self._recv(receive)
+    }
+
+    fn get_chain_work(&self) -> Result<protocol::ChainWork, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetChainWork(transmit))?;
+
+        self._recv(receive)
+    }
+
+    fn list_bans(&self) -> Result<Vec<protocol::Ban>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::ListBans(transmit))?;
+
+        self._recv(receive)
     }
 
     fn query_tree(
         &self,
         query: impl Fn(&dyn BlockReader) + Send + Sync + 'static,
     ) -> Result<(), handle::Error> {
-        use std::sync::Arc;
-
         self.command(Command::QueryTree(Arc::new(query)))?;
 
         Ok(())
@@ -450,7 +1111,7 @@ where
             transmit.send(t.find_branch(&to)).ok();
         })?;
 
-        Ok(receive.recv()?)
+        self._recv(receive)
     }
 
     fn get_block(&self, hash: &BlockHash) -> Result<(), handle::Error> {
@@ -467,7 +1128,7 @@ where
         let (transmit, receive) = chan::bounded(1);
         self.command(Command::GetFilters(range, transmit))?;
 
-        receive.recv()?.map_err(handle::Error::GetFilters)
+        self._recv(receive)?.map_err(handle::Error::GetFilters)
     }
 
     fn blocks(&self) -> chan::Receiver<(Block, Height)> {
@@ -482,6 +1143,10 @@ where
         self.subscriber.subscribe()
     }
 
+    fn subscription(&self, capacity: usize) -> event::Subscription<Event> {
+        self.subscriber.subscription(capacity)
+    }
+
     fn command(&self, cmd: Command) -> Result<(), handle::Error> {
         self._command(cmd)
     }
@@ -494,19 +1159,38 @@ where
         let (transmit, receive) = chan::bounded(1);
         self.command(Command::Broadcast(msg, predicate, transmit))?;
 
-        Ok(receive.recv()?)
+        self._recv(receive)
     }
 
     fn query(&self, msg: NetworkMessage) -> Result<Option<net::SocketAddr>, handle::Error> {
         let (transmit, receive) = chan::bounded::<Option<net::SocketAddr>>(1);
         self.command(Command::Query(msg, transmit))?;
 
-        Ok(receive.recv()?)
+        self._recv(receive)
     }
 
-    fn connect(&self, addr: net::SocketAddr) -> Result<Link, handle::Error> {
+    fn capture(&self, path: Option<PathBuf>) -> Result<(), handle::Error> {
+        let capture = match path {
+            Some(path) => {
+                let file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?;
+
+                // Nb. Captures are flushed line by line, so that they are complete even
+                // if the client crashes.
+                Some(Capture::new(io::LineWriter::new(file)))
+            }
+            None => None,
+        };
+        self.command(Command::Capture(capture))?;
+
+        Ok(())
+    }
+
+    fn connect(&self, addr: net::SocketAddr, persistent: bool) -> Result<Link, handle::Error> {
         let events = self.events();
-        self.command(Command::Connect(addr))?;
+        self.command(Command::Connect(addr, persistent))?;
 
         event::wait(
             &events,
@@ -523,6 +1207,34 @@ where
         .map_err(handle::Error::from)
     }
 
+    fn connect_any(
+        &self,
+        addrs: &[net::SocketAddr],
+    ) -> Result<(net::SocketAddr, Link), handle::Error> {
+        let events = self.events();
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::ConnectAny(addrs.to_vec(), transmit))?;
+
+        let dialing = self._recv(receive)?;
+        if dialing.is_empty() {
+            return Err(CommandError::NoAddresses.into());
+        }
+
+        event::wait(
+            &events,
+            |e| match e {
+                protocol::Event::Peer(protocol::PeerEvent::Connected(a, link))
+                    if dialing.contains(&a) =>
+                {
+                    Some((a, link))
+                }
+                _ => None,
+            },
+            self.timeout,
+        )
+        .map_err(handle::Error::from)
+    }
+
     fn disconnect(&self, addr: net::SocketAddr) -> Result<(), handle::Error> {
         let events = self.events();
 
@@ -550,7 +1262,7 @@ where
         let (transmit, receive) = chan::bounded::<Result<ImportResult, tree::Error>>(1);
         self.command(Command::ImportHeaders(headers, transmit))?;
 
-        Ok(receive.recv()?)
+        self._recv(receive)
     }
 
     fn import_addresses(&self, addrs: Vec<Address>) -> Result<(), handle::Error> {
@@ -566,7 +1278,28 @@ where
         let (transmit, receive) = chan::bounded(1);
         self.command(Command::SubmitTransaction(tx, transmit))?;
 
-        receive.recv()?.map_err(handle::Error::Command)
+        self._recv(receive)?.map_err(handle::Error::Command)
+    }
+
+    fn replace_transaction(
+        &self,
+        txid: Txid,
+        replacement: Transaction,
+    ) -> Result<NonEmpty<net::SocketAddr>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::ReplaceTransaction(txid, replacement, transmit))?;
+
+        self._recv(receive)?.map_err(handle::Error::Command)
+    }
+
+    fn submit_package(
+        &self,
+        txs: Vec<Transaction>,
+    ) -> Result<Vec<(Txid, protocol::TxStatus)>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::SubmitPackage(txs, transmit))?;
+
+        self._recv(receive)?.map_err(handle::Error::Command)
     }
 
     fn wait<F, T>(&self, f: F) -> Result<T, handle::Error>
@@ -626,32 +1359,171 @@ where
         .map_err(handle::Error::from)
     }
 
-    fn wait_for_height(&self, h: Height) -> Result<BlockHash, handle::Error> {
+    fn wait_for_height(
+        &self,
+        h: Height,
+        timeout: time::Duration,
+    ) -> Result<BlockHash, handle::Error> {
         let events = self.events();
+        let deadline = time::Instant::now() + timeout;
 
-        match self.get_block_by_height(h)? {
-            Some(e) => Ok(e.block_hash()),
-            None => event::wait(
+        loop {
+            if let Some(header) = self.get_block_by_height(h)? {
+                return Ok(header.block_hash());
+            }
+            // Nb. The chain may sync past the target height in one go, so we look up the
+            // block hash once it's synced, rather than matching on the event height.
+            event::wait(
                 &events,
                 |e| match e {
-                    protocol::Event::Chain(protocol::ChainEvent::Synced(hash, height))
-                        if height == h =>
+                    protocol::Event::Chain(protocol::ChainEvent::Synced(_, height))
+                        if height >= h =>
                     {
-                        Some(hash)
+                        Some(())
                     }
                     _ => None,
                 },
-                self.timeout,
-            )
-            .map_err(handle::Error::from),
+                deadline.saturating_duration_since(time::Instant::now()),
+            )?;
         }
     }
 
+    fn wait_for_filters(
+        &self,
+        h: Height,
+        timeout: time::Duration,
+    ) -> Result<Height, handle::Error> {
+        let events = self.events();
+        let height = self.get_filter_tip()?;
+
+        if height >= h {
+            return Ok(height);
+        }
+        event::wait(
+            &events,
+            |e| match e {
+                protocol::Event::Filter(protocol::FilterEvent::FilterHeadersImported {
+                    height,
+                    ..
+                })
+                | protocol::Event::Filter(protocol::FilterEvent::Synced(height))
+                    if height >= h =>
+                {
+                    Some(height)
+                }
+                _ => None,
+            },
+            timeout,
+        )
+        .map_err(handle::Error::from)
+    }
+
+    fn wait_for_confirmation(
+        &self,
+        txid: Txid,
+        depth: Height,
+        timeout: time::Duration,
+    ) -> Result<(Height, BlockHash), handle::Error> {
+        if depth == 0 {
+            return Err(handle::Error::InvalidArgument(
+                "the confirmation depth must be greater than zero",
+            ));
+        }
+        let events = self.subscribe();
+
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetConfirmation(txid, transmit))?;
+
+        let confirmed = self._recv(receive)?;
+        let (tip, _) = self.get_tip()?;
+        let mut confirmations = Confirmations {
+            txid,
+            depth,
+            confirmed,
+            tip,
+        };
+
+        if let Some(result) = confirmations.reached() {
+            return Ok(result);
+        }
+        event::wait(&events, |e| confirmations.process(e), timeout).map_err(handle::Error::from)
+    }
+
     fn events(&self) -> chan::Receiver<protocol::Event> {
         self.events.subscribe()
     }
 
+    fn replay_from(&self, seq: u64) -> Result<Vec<journal::Entry>, handle::Error> {
+        self.journal.replay_from(seq).map_err(handle::Error::from)
+    }
+
+    fn rescan(
+        &self,
+        range: impl RangeBounds<Height>,
+        watch: impl Iterator<Item = Script>,
+    ) -> Result<RescanId, handle::Error> {
+        // Filters are never fetched in headers-only mode, so the rescan would never end.
+        if self.headers_only.load(Ordering::SeqCst) {
+            return Err(protocol::GetFiltersError::Disabled.into());
+        }
+        let id = fastrand::u64(..);
+        let watch = watch.collect::<Vec<_>>();
+        let (from, to) = (range.start_bound().cloned(), range.end_bound().cloned());
+
+        // Resolve the start height now, so that the task can be resumed from it.
+        let start = match from {
+            Bound::Included(h) => h,
+            Bound::Excluded(h) => h + 1,
+            Bound::Unbounded => self.get_tip()?.0 + 1,
+        };
+        let end = match to {
+            Bound::Included(h) => Some(h),
+            Bound::Excluded(h) => Some(
+                h.checked_sub(1)
+                    .ok_or(handle::Error::InvalidArgument("empty rescan range"))?,
+            ),
+            Bound::Unbounded => None,
+        };
+        if end.is_some_and(|end| end < start) {
+            return Err(handle::Error::InvalidArgument("empty rescan range"));
+        }
+        self.rescans
+            .insert(rescan::Task::new(id, start, end, watch.clone()))?;
+        self.command(Command::Rescan {
+            id,
+            from: Bound::Included(start),
+            to,
+            watch,
+        })?;
+
+        Ok(id)
+    }
+
+    fn resume_rescan(&self, id: RescanId) -> Result<(), handle::Error> {
+        match self.rescans.get(&id) {
+            // Paused tasks may not be known to the protocol if the client was restarted,
+            // so we restart them from their saved progress.
+            Some(task) if task.status == rescan::Status::Paused => task
+                .resume()
+                .into_iter()
+                .try_for_each(|cmd| self.command(cmd)),
+            // The task may have been paused without our knowing it yet.
+            Some(task) if task.status == rescan::Status::Running => {
+                self.command(Command::ResumeRescan(id))
+            }
+            // Canceled tasks are removed, so an unknown task has ended too.
+            Some(_) => Err(rescan::Error::Ended(id).into()),
+            None if self.rescans.is_enabled() => Err(rescan::Error::Ended(id).into()),
+            None => self.command(Command::ResumeRescan(id)),
+        }
+    }
+
+    fn rescans(&self) -> Result<Vec<rescan::Task>, handle::Error> {
+        self.rescans.list().map_err(handle::Error::from)
+    }
+
     fn shutdown(self) -> Result<(), handle::Error> {
+        self.shutting_down.store(true, Ordering::SeqCst);
         self.shutdown.send(())?;
         R::wake(&self.waker)?;
 
@@ -0,0 +1,655 @@
+//! Client configuration builder.
+//!
+//! Provides a [`ClientConfig`] builder which starts from one of a few sane
+//! [`Profile`]s, and validates the resulting configuration.
+//!
+//! ```
+//! use nakamoto_client::config::{ClientConfig, Profile};
+//! use nakamoto_client::Network;
+//!
+//! let cfg = ClientConfig::new(Network::Testnet, Profile::Mobile)
+//!     .target_outbound_peers(6)
+//!     .build()
+//!     .unwrap();
+//!
+//! assert_eq!(cfg.protocol.target_outbound_peers, 6);
+//! ```
+use std::net;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time;
+
+use thiserror::Error;
+
+use nakamoto_chain::block::store::Fsync;
+use nakamoto_common::block::time::LocalDuration;
+use nakamoto_common::error::Classify;
+use nakamoto_common::network::Network;
+use nakamoto_common::p2p::{Domain, DomainPolicy, PeerAddr};
+use nakamoto_p2p::protocol::scheduler::{Cadence, Schedule};
+use nakamoto_p2p::protocol::{ratelimit, Fingerprint, QueueLimits};
+use nakamoto_p2p::traits::{Keepalive, ReactorConfig};
+
+use crate::client::{pow, Config};
+
+/// A configuration validation error.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The client would never connect to, or accept connections from any peer.
+    #[error("client is configured with no outbound or inbound peer connections")]
+    NoPeers,
+    /// More persistent peers were specified than the outbound peer target.
+    #[error("{connect} persistent peer(s) exceed the outbound peer target of {target}")]
+    TooManyPersistentPeers {
+        /// Number of persistent peers.
+        connect: usize,
+        /// Target outbound peer connections.
+        target: usize,
+    },
+    /// No communication domain was specified.
+    #[error("at least one communication domain is required")]
+    NoDomains,
+    /// Listen addresses were specified, but inbound connections are disabled.
+    #[error("listen addresses were specified, but inbound peer connections are disabled")]
+    ListenWithoutInbound,
+    /// The ping timeout is zero.
+    #[error("ping timeout must be greater than zero")]
+    PingTimeout,
+    /// Connect-only mode was enabled, but no peers to connect to were specified.
+    #[error("connect-only mode requires at least one peer to connect to")]
+    ConnectOnlyWithoutPeers,
+    /// The reactor read buffer is too small to be useful.
+    #[error("read buffer size of {0} byte(s) is too small")]
+    ReadBufferSize(usize),
+    /// Mempool prefetch was enabled, but no trusted peer was specified.
+    #[error("mempool prefetch requires a trusted peer")]
+    MempoolPrefetchWithoutTrustedPeer,
+    /// An external port was specified, but inbound connections are disabled.
+    #[error("an external port was specified, but inbound peer connections are disabled")]
+    ExternalPortWithoutInbound,
+    /// Filters can't be served in headers-only mode, since they aren't synced.
+    #[error("filters can't be served in headers-only mode")]
+    ServeFiltersHeadersOnly,
+    /// Mempool prefetch is meaningless in headers-only mode, since nothing is watched.
+    #[error("mempool prefetch is unavailable in headers-only mode")]
+    MempoolPrefetchHeadersOnly,
+    /// An identity was pinned for the trusted peer, but no trusted peer was specified.
+    #[error("trusted peer identity requires a trusted peer")]
+    TrustedPeerIdentityWithoutTrustedPeer,
+    /// An identity was pinned for the trusted peer, but the reactor doesn't authenticate
+    /// peers, so it could never be verified.
+    #[error("trusted peer identity requires a reactor that authenticates peers")]
+    TrustedPeerIdentityUnsupported,
+    /// A peer to connect to is on a network we have no transport for, eg. Tor.
+    #[error("peer {0} is unreachable, since its network isn't supported")]
+    UnreachablePeer(PeerAddr),
+}
+
+impl Classify for Error {
+    fn code(&self) -> u32 {
+        match self {
+            Self::NoPeers => 4000,
+            Self::TooManyPersistentPeers { .. } => 4001,
+            Self::NoDomains => 4002,
+            Self::ListenWithoutInbound => 4003,
+            Self::PingTimeout => 4004,
+            Self::ConnectOnlyWithoutPeers => 4005,
+            Self::ReadBufferSize(_) => 4006,
+            Self::MempoolPrefetchWithoutTrustedPeer => 4007,
+            Self::ExternalPortWithoutInbound => 4008,
+            Self::ServeFiltersHeadersOnly => 4009,
+            Self::MempoolPrefetchHeadersOnly => 4010,
+            Self::TrustedPeerIdentityWithoutTrustedPeer => 4011,
+            Self::TrustedPeerIdentityUnsupported => 4012,
+            Self::UnreachablePeer(_) => 4025,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        false
+    }
+}
+
+/// A configuration profile, suited to a certain kind of environment.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Profile {
+    /// Constrained devices, eg. phones. Uses fewer connections, smaller caches, and doesn't
+    /// accept inbound connections.
+    Mobile,
+    /// Personal computers. These are the library defaults.
+    #[default]
+    Desktop,
+    /// Always-on machines with plenty of bandwidth. Accepts more connections and flushes
+    /// all writes to disk.
+    Server,
+}
+
+/// Smallest read buffer size accepted.
+const MIN_READ_BUFFER_SIZE: usize = 1024;
+
+/// Client configuration builder.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    config: Config,
+    connect: Vec<PeerAddr>,
+}
+
+impl ClientConfig {
+    /// Create a new configuration builder for the given network, based on a profile.
+    pub fn new(network: Network, profile: Profile) -> Self {
+        let mut config = Config::new(network);
+
+        match profile {
+            Profile::Mobile => {
+                config.listen = vec![];
+                config.fsync = Fsync::Manual;
+                config.protocol.target_outbound_peers = 4;
+                config.protocol.max_inbound_peers = 0;
+                config.protocol.target_block_relay_peers = 0;
+                config.protocol.schedule.feeler = None;
+                config.protocol.direct_fetch = false;
+                config.protocol.ping_timeout = LocalDuration::from_secs(60);
+                config.protocol.filter_cache_size = 1024 * 256;
+                config.protocol.queue_limits = QueueLimits {
+                    peer: 1024 * 1024,
+                    total: 8 * 1024 * 1024,
+                };
+                config.reactor = ReactorConfig {
+                    wait_timeout: LocalDuration::from_mins(60),
+                    read_buffer_size: 1024 * 64,
+                    ..ReactorConfig::default()
+                };
+            }
+            Profile::Desktop => {}
+            Profile::Server => {
+                config.fsync = Fsync::Always;
+                config.protocol.target_outbound_peers = 12;
+                config.protocol.max_inbound_peers = 125;
+                config.protocol.filter_cache_size = 1024 * 1024 * 16;
+                config.reactor = ReactorConfig {
+                    wait_timeout: LocalDuration::from_mins(60),
+                    read_buffer_size: 1024 * 256,
+                    ..ReactorConfig::default()
+                };
+            }
+        }
+        Self {
+            config,
+            connect: Vec::new(),
+        }
+    }
+
+    /// Set the client home path, where runtime data is stored.
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.config.root = root.into();
+        self
+    }
+
+    /// Set the client name. Used for logging only.
+    pub fn name(mut self, name: &'static str) -> Self {
+        self.config.name = name;
+        self
+    }
+
+    /// Set the addresses to listen on for inbound connections.
+    pub fn listen(mut self, listen: impl IntoIterator<Item = net::SocketAddr>) -> Self {
+        self.config.listen = listen.into_iter().collect();
+        self
+    }
+
+    /// Set the peers to connect to, and persist connections with. Besides socket addresses,
+    /// these can be addresses on overlay networks, eg. I2P, in which case the network's
+    /// domain must also be supported.
+    pub fn connect(mut self, connect: impl IntoIterator<Item = impl Into<PeerAddr>>) -> Self {
+        self.connect = connect.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Only connect to the peers set with [`ClientConfig::connect`], refusing all
+    /// other connections and disabling address gossip.
+    pub fn connect_only(mut self, connect_only: bool) -> Self {
+        self.config.protocol.connect_only = connect_only;
+        self
+    }
+
+    /// Set the peer to download full blocks from. Blocks are never requested from
+    /// other peers.
+    pub fn trusted_peer(mut self, peer: net::SocketAddr) -> Self {
+        self.config.protocol.trusted_peer = Some(peer);
+        self
+    }
+
+    /// Pin the identity of the trusted peer. The trusted peer is disconnected unless the
+    /// transport authenticates it with this identity.
+    ///
+    /// Requires a reactor that authenticates peers, see
+    /// [`Reactor::AUTHENTICATES_PEERS`](nakamoto_p2p::traits::Reactor::AUTHENTICATES_PEERS).
+    /// The client refuses to run otherwise, since the trusted peer could never connect.
+    pub fn trusted_peer_identity(mut self, fingerprint: Fingerprint) -> Self {
+        self.config.protocol.trusted_peer_identity = Some(fingerprint);
+        self
+    }
+
+    /// Download the given number of random decoy blocks along with every block, within
+    /// a budget of bytes per hour.
+    pub fn decoy_blocks(mut self, count: usize, budget: usize) -> Self {
+        self.config.protocol.decoy_blocks = count;
+        self.config.protocol.decoy_budget = budget;
+        self
+    }
+
+    /// Download new blocks as soon as they're announced, while watching for matches at the
+    /// tip, instead of waiting for their filter to match. Reduces latency at the cost of
+    /// bandwidth.
+    pub fn direct_fetch(mut self, enabled: bool) -> Self {
+        self.config.protocol.direct_fetch = enabled;
+        self
+    }
+
+    /// Ask the trusted peer for unconfirmed transactions paying to watched scripts, so that
+    /// incoming payments are reported before they are confirmed. This reveals the watched
+    /// scripts to the trusted peer, and unconfirmed transactions may never be confirmed.
+    pub fn mempool_prefetch(mut self, enabled: bool) -> Self {
+        self.config.protocol.mempool_prefetch = enabled;
+        self
+    }
+
+    /// Only sync and serve block headers. Compact filters aren't fetched, and blocks
+    /// aren't downloaded, which keeps memory and disk usage to a minimum. Suitable for
+    /// applications that only need a trustworthy tip, eg. to verify merkle proofs. The
+    /// filter cache size is ignored in this mode.
+    pub fn headers_only(mut self, enabled: bool) -> Self {
+        self.config.protocol.headers_only = enabled;
+        self
+    }
+
+    /// Set the port peers can reach us on from the outside, eg. the port forwarded to one
+    /// of our listen addresses. Our external address is then advertised to peers.
+    pub fn external_port(mut self, port: u16) -> Self {
+        self.config.protocol.external_port = Some(port);
+        self
+    }
+
+    /// Group peer addresses by autonomous system, using the given asmap file. Relative paths
+    /// are resolved against the network's data directory, eg. [`ASMAP_FILE`] refers to the
+    /// file of that name in the data directory.
+    ///
+    /// [`ASMAP_FILE`]: nakamoto_p2p::protocol::asmap::ASMAP_FILE
+    pub fn asmap(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.asmap = Some(path.into());
+        self
+    }
+
+    /// Set the block header proof-of-work verifier, eg. `pow::Parallel` with the `rayon`
+    /// feature, to verify headers on a thread pool during initial sync.
+    pub fn verifier(mut self, verifier: Arc<dyn pow::Verifier>) -> Self {
+        self.config.verifier = verifier;
+        self
+    }
+
+    /// Set the supported communication domains.
+    pub fn domains(mut self, domains: impl IntoIterator<Item = Domain>) -> Self {
+        self.config.protocol.domains = domains.into_iter().collect();
+        self
+    }
+
+    /// Set the policy for choosing the domain of outbound connections.
+    pub fn domain_policy(mut self, policy: DomainPolicy) -> Self {
+        self.config.protocol.domain_policy = policy;
+        self
+    }
+
+    /// Set the target number of outbound peer connections.
+    pub fn target_outbound_peers(mut self, target: usize) -> Self {
+        self.config.protocol.target_outbound_peers = target;
+        self
+    }
+
+    /// Set the maximum number of inbound peer connections.
+    pub fn max_inbound_peers(mut self, max: usize) -> Self {
+        self.config.protocol.max_inbound_peers = max;
+        self
+    }
+
+    /// Set the target number of block-relay-only outbound peer connections. These
+    /// peers don't relay transactions or addresses, which makes them harder to discover.
+    pub fn target_block_relay_peers(mut self, target: usize) -> Self {
+        self.config.protocol.target_block_relay_peers = target;
+        self
+    }
+
+    /// Set the time between feeler connections, which are used to check that addresses
+    /// in the address book are reachable. Set to `None` to disable feelers.
+    pub fn feeler_interval(mut self, interval: Option<LocalDuration>) -> Self {
+        self.config.protocol.schedule.feeler = interval.map(Cadence::every);
+        self
+    }
+
+    /// Set the cadences of periodic protocol tasks, eg. pings, address requests and
+    /// transaction rebroadcasts, and the jitter added to each.
+    pub fn schedule(mut self, schedule: Schedule) -> Self {
+        self.config.protocol.schedule = schedule;
+        self
+    }
+
+    /// Set the number of expected block intervals without a new block header, after
+    /// which the tip is considered stale and [`crate::Event::StaleTip`] is emitted.
+    pub fn stale_tip_factor(mut self, factor: u32) -> Self {
+        self.config.protocol.stale_tip_factor = factor;
+        self
+    }
+
+    /// Set the size in bytes of the compact filter cache.
+    pub fn filter_cache_size(mut self, size: usize) -> Self {
+        self.config.protocol.filter_cache_size = size;
+        self
+    }
+
+    /// Serve compact filters and filter headers to peers, advertising the
+    /// `NODE_COMPACT_FILTERS` service. Only cached filters are served, so the filter
+    /// cache size determines how many recent filters are available.
+    pub fn serve_filters(mut self, serve: bool) -> Self {
+        self.config.protocol.serve_filters = serve;
+        self
+    }
+
+    /// Set the per-peer rate limits on expensive messages. Peers that keep exceeding
+    /// them are eventually disconnected.
+    pub fn rate_limits(mut self, limits: ratelimit::Config) -> Self {
+        self.config.protocol.rate_limits = limits;
+        self
+    }
+
+    /// Set the limits on the number of bytes queued for sending to peers. Peers whose
+    /// queue grows past the limit, usually because they stopped reading, are disconnected.
+    pub fn queue_limits(mut self, limits: QueueLimits) -> Self {
+        self.config.protocol.queue_limits = limits;
+        self
+    }
+
+    /// Set the time peers have to complete the handshake, after which they are disconnected.
+    pub fn handshake_timeout(mut self, timeout: LocalDuration) -> Self {
+        self.config.protocol.handshake_timeout = timeout;
+        self
+    }
+
+    /// Set the ping timeout, after which unresponsive peers are disconnected.
+    pub fn ping_timeout(mut self, timeout: LocalDuration) -> Self {
+        self.config.protocol.ping_timeout = timeout;
+        self
+    }
+
+    /// Set the policy for flushing store writes to disk.
+    pub fn fsync(mut self, fsync: Fsync) -> Self {
+        self.config.fsync = fsync;
+        self
+    }
+
+    /// Keep a journal of client events, which can be replayed with
+    /// [`Handle::replay_from`](crate::handle::Handle::replay_from).
+    pub fn journal(mut self, journal: bool) -> Self {
+        self.config.journal = journal;
+        self
+    }
+
+    /// Join a fleet of clients, either as its leader, or as a member. See [`crate::fleet`].
+    pub fn fleet(mut self, fleet: crate::fleet::Config) -> Self {
+        self.config.fleet = Some(fleet);
+        self
+    }
+
+    /// Set how many times in a row the reactor is restarted after a failure, before giving up.
+    pub fn max_restarts(mut self, max_restarts: usize) -> Self {
+        self.config.max_restarts = max_restarts;
+        self
+    }
+
+    /// Set how long the reactor has to run without failing for the restart count to be
+    /// reset.
+    pub fn stable_uptime(mut self, uptime: time::Duration) -> Self {
+        self.config.stable_uptime = uptime;
+        self
+    }
+
+    /// Set the maximum amount of time the reactor waits for i/o.
+    pub fn wait_timeout(mut self, timeout: LocalDuration) -> Self {
+        self.config.reactor.wait_timeout = timeout;
+        self
+    }
+
+    /// Set the socket read buffer size, in bytes.
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.config.reactor.read_buffer_size = size;
+        self
+    }
+
+    /// Set whether Nagle's algorithm is disabled on peer sockets (`TCP_NODELAY`).
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.config.reactor.nodelay = nodelay;
+        self
+    }
+
+    /// Set the TCP keepalive parameters of peer sockets, or disable keepalive with `None`.
+    pub fn keepalive(mut self, keepalive: Option<Keepalive>) -> Self {
+        self.config.reactor.keepalive = keepalive;
+        self
+    }
+
+    /// Set the time to wait for an outbound connection to be established.
+    pub fn dial_timeout(mut self, timeout: LocalDuration) -> Self {
+        self.config.reactor.dial_timeout = timeout;
+        self
+    }
+
+    /// Validate and return the configuration.
+    pub fn build(self) -> Result<Config, Error> {
+        let mut cfg = self.config;
+
+        cfg.protocol.connect = self
+            .connect
+            .into_iter()
+            .map(|addr| addr.socket_addr().ok_or(Error::UnreachablePeer(addr)))
+            .collect::<Result<_, _>>()?;
+
+        let target = cfg.protocol.target_outbound_peers;
+        let connect = cfg.protocol.connect.len();
+
+        if target == 0 && connect == 0 && cfg.protocol.max_inbound_peers == 0 {
+            return Err(Error::NoPeers);
+        }
+        if connect > target {
+            return Err(Error::TooManyPersistentPeers { connect, target });
+        }
+        if cfg.protocol.connect_only && connect == 0 {
+            return Err(Error::ConnectOnlyWithoutPeers);
+        }
+        if cfg.protocol.domains.is_empty() {
+            return Err(Error::NoDomains);
+        }
+        if !cfg.listen.is_empty() && cfg.protocol.max_inbound_peers == 0 {
+            return Err(Error::ListenWithoutInbound);
+        }
+        if cfg.protocol.ping_timeout == LocalDuration::from_secs(0) {
+            return Err(Error::PingTimeout);
+        }
+        if cfg.reactor.read_buffer_size < MIN_READ_BUFFER_SIZE {
+            return Err(Error::ReadBufferSize(cfg.reactor.read_buffer_size));
+        }
+        if cfg.protocol.mempool_prefetch && cfg.protocol.trusted_peer.is_none() {
+            return Err(Error::MempoolPrefetchWithoutTrustedPeer);
+        }
+        if cfg.protocol.external_port.is_some() && cfg.protocol.max_inbound_peers == 0 {
+            return Err(Error::ExternalPortWithoutInbound);
+        }
+        if cfg.protocol.headers_only && cfg.protocol.serve_filters {
+            return Err(Error::ServeFiltersHeadersOnly);
+        }
+        if cfg.protocol.headers_only && cfg.protocol.mempool_prefetch {
+            return Err(Error::MempoolPrefetchHeadersOnly);
+        }
+        if cfg.protocol.trusted_peer_identity.is_some() && cfg.protocol.trusted_peer.is_none() {
+            return Err(Error::TrustedPeerIdentityWithoutTrustedPeer);
+        }
+        Ok(cfg)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_profiles() {
+        let mobile = ClientConfig::new(Network::Mainnet, Profile::Mobile)
+            .build()
+            .unwrap();
+        let desktop = ClientConfig::new(Network::Mainnet, Profile::Desktop)
+            .build()
+            .unwrap();
+        let server = ClientConfig::new(Network::Mainnet, Profile::Server)
+            .build()
+            .unwrap();
+
+        assert!(mobile.listen.is_empty());
+        assert!(mobile.protocol.target_outbound_peers < desktop.protocol.target_outbound_peers);
+        assert!(desktop.protocol.max_inbound_peers < server.protocol.max_inbound_peers);
+        assert_eq!(server.fsync, Fsync::Always);
+        assert_eq!(desktop.reactor, ReactorConfig::default());
+    }
+
+    #[test]
+    fn test_validation() {
+        let addr: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+
+        assert_eq!(
+            ClientConfig::new(Network::Mainnet, Profile::Mobile)
+                .target_outbound_peers(0)
+                .build()
+                .unwrap_err(),
+            Error::NoPeers
+        );
+        assert_eq!(
+            ClientConfig::new(Network::Mainnet, Profile::Mobile)
+                .target_outbound_peers(0)
+                .connect([addr])
+                .build()
+                .unwrap_err(),
+            Error::TooManyPersistentPeers {
+                connect: 1,
+                target: 0
+            }
+        );
+        assert_eq!(
+            ClientConfig::new(Network::Mainnet, Profile::Desktop)
+                .connect_only(true)
+                .build()
+                .unwrap_err(),
+            Error::ConnectOnlyWithoutPeers
+        );
+
+        let onion: PeerAddr = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion:8333"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            ClientConfig::new(Network::Mainnet, Profile::Desktop)
+                .connect([onion.clone()])
+                .connect_only(true)
+                .build()
+                .unwrap_err(),
+            Error::UnreachablePeer(onion)
+        );
+
+        let i2p: PeerAddr = format!("{}.b32.i2p:0", "a".repeat(52)).parse().unwrap();
+        let cfg = ClientConfig::new(Network::Mainnet, Profile::Desktop)
+            .connect([i2p.clone()])
+            .connect_only(true)
+            .build()
+            .unwrap();
+        assert_eq!(cfg.protocol.connect, vec![i2p.socket_addr().unwrap()]);
+        assert_eq!(
+            ClientConfig::new(Network::Mainnet, Profile::Desktop)
+                .domains([])
+                .build()
+                .unwrap_err(),
+            Error::NoDomains
+        );
+        assert_eq!(
+            ClientConfig::new(Network::Mainnet, Profile::Mobile)
+                .listen([addr])
+                .build()
+                .unwrap_err(),
+            Error::ListenWithoutInbound
+        );
+        assert_eq!(
+            ClientConfig::new(Network::Mainnet, Profile::Server)
+                .read_buffer_size(0)
+                .build()
+                .unwrap_err(),
+            Error::ReadBufferSize(0)
+        );
+        assert_eq!(
+            ClientConfig::new(Network::Mainnet, Profile::Desktop)
+                .mempool_prefetch(true)
+                .build()
+                .unwrap_err(),
+            Error::MempoolPrefetchWithoutTrustedPeer
+        );
+        assert_eq!(
+            ClientConfig::new(Network::Mainnet, Profile::Mobile)
+                .external_port(8333)
+                .build()
+                .unwrap_err(),
+            Error::ExternalPortWithoutInbound
+        );
+        assert_eq!(
+            ClientConfig::new(Network::Mainnet, Profile::Server)
+                .headers_only(true)
+                .serve_filters(true)
+                .build()
+                .unwrap_err(),
+            Error::ServeFiltersHeadersOnly
+        );
+        assert_eq!(
+            ClientConfig::new(Network::Mainnet, Profile::Desktop)
+                .trusted_peer(addr)
+                .mempool_prefetch(true)
+                .headers_only(true)
+                .build()
+                .unwrap_err(),
+            Error::MempoolPrefetchHeadersOnly
+        );
+        assert_eq!(
+            ClientConfig::new(Network::Mainnet, Profile::Desktop)
+                .trusted_peer_identity(Fingerprint::new([0; 32]))
+                .build()
+                .unwrap_err(),
+            Error::TrustedPeerIdentityWithoutTrustedPeer
+        );
+    }
+
+    #[test]
+    fn test_headers_only() {
+        let cfg = ClientConfig::new(Network::Mainnet, Profile::Desktop)
+            .headers_only(true)
+            .build()
+            .unwrap();
+
+        assert!(cfg.protocol.headers_only);
+
+        // Disabling headers-only mode leaves the other settings as they were.
+        let default = ClientConfig::new(Network::Mainnet, Profile::Desktop)
+            .build()
+            .unwrap();
+        let cfg = ClientConfig::new(Network::Mainnet, Profile::Desktop)
+            .headers_only(true)
+            .headers_only(false)
+            .build()
+            .unwrap();
+
+        assert!(!cfg.protocol.headers_only);
+        assert_eq!(
+            cfg.protocol.filter_cache_size,
+            default.protocol.filter_cache_size
+        );
+    }
+}
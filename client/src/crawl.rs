@@ -0,0 +1,438 @@
+//! Network crawler, for building seed lists.
+//!
+//! The crawler performs a lightweight handshake with every peer address it learns about,
+//! starting from the network's DNS seeds, asks each reachable peer for more addresses, and
+//! records the services and best height the peer advertises. Peers are disconnected as soon
+//! as they've answered, and no blocks or filters are downloaded.
+//!
+//! Reachable peers offering the required services, eg. compact filters, can be exported as a
+//! seed list with [`export`], one address per line, to bootstrap deployments.
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{self, ToSocketAddrs};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use thiserror::Error;
+
+use nakamoto_common::bitcoin::consensus::encode;
+use nakamoto_common::bitcoin::network::address::Address;
+use nakamoto_common::bitcoin::network::constants::ServiceFlags;
+use nakamoto_common::bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use nakamoto_common::bitcoin::network::message_network::VersionMessage;
+use nakamoto_common::block::Height;
+use nakamoto_common::network::Network;
+use nakamoto_common::p2p::Domain;
+use nakamoto_p2p::protocol::{self, PROTOCOL_VERSION, USER_AGENT};
+use nakamoto_p2p::stream;
+
+use crate::client::chan;
+
+/// How long to wait on a peer before giving up, by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum number of addresses visited, by default.
+pub const DEFAULT_MAX_PEERS: usize = 1000;
+/// Number of peers visited concurrently, by default.
+pub const DEFAULT_CONCURRENCY: usize = 32;
+
+/// Size of the buffer used to decode peer messages.
+const DECODER_CAPACITY: usize = 1024 * 64;
+
+/// A crawler error, when visiting a single peer.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// An I/O error.
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    /// A message could not be decoded.
+    #[error("decoding error: {0}")]
+    Decode(#[from] encode::Error),
+    /// The peer sent a message for another network.
+    #[error("received message with invalid magic: {0}")]
+    Magic(u32),
+}
+
+/// Crawler configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Network to crawl.
+    pub network: Network,
+    /// Addresses to start crawling from. If empty, the network's DNS seeds are used.
+    pub seeds: Vec<net::SocketAddr>,
+    /// Communication domains to crawl.
+    pub domains: Vec<Domain>,
+    /// Only crawl globally routable addresses. Disable to crawl local networks.
+    pub routable_only: bool,
+    /// Maximum number of addresses visited.
+    pub max_peers: usize,
+    /// Number of peers visited concurrently.
+    pub concurrency: usize,
+    /// How long to wait on a peer before giving up.
+    pub timeout: Duration,
+}
+
+impl Config {
+    /// Create a new crawler configuration for the given network.
+    pub fn new(network: Network) -> Self {
+        Self {
+            network,
+            seeds: Vec::new(),
+            domains: Domain::all(),
+            routable_only: true,
+            max_peers: DEFAULT_MAX_PEERS,
+            concurrency: DEFAULT_CONCURRENCY,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Check whether a discovered address should be visited.
+    fn is_crawlable(&self, addr: &net::SocketAddr) -> bool {
+        if addr.port() == 0 || !self.domains.contains(&Domain::for_address(addr)) {
+            return false;
+        }
+        !self.routable_only
+            || (protocol::is_routable(&addr.ip()) && !protocol::is_local(&addr.ip()))
+    }
+}
+
+/// A reachable peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    /// Peer address.
+    pub addr: net::SocketAddr,
+    /// Services offered by the peer.
+    pub services: ServiceFlags,
+    /// Peer protocol version.
+    pub version: u32,
+    /// Peer user agent.
+    pub user_agent: String,
+    /// Peer best height, as advertised.
+    pub height: Height,
+}
+
+/// Crawl the network, and return the peers that were reachable.
+pub fn crawl(config: &Config) -> Vec<Peer> {
+    let seeds = if config.seeds.is_empty() {
+        let port = config.network.port();
+
+        config
+            .network
+            .seeds()
+            .iter()
+            .filter_map(|seed| match (*seed, port).to_socket_addrs() {
+                Ok(addrs) => Some(addrs),
+                Err(err) => {
+                    log::warn!("Failed to resolve seed {}: {}", seed, err);
+                    None
+                }
+            })
+            .flatten()
+            .collect()
+    } else {
+        config.seeds.clone()
+    };
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::new();
+
+    for addr in seeds.into_iter().take(config.max_peers) {
+        if seen.insert(addr) {
+            queue.push_back(addr);
+        }
+    }
+
+    let (jobs, work) = chan::unbounded::<net::SocketAddr>();
+    let (results, done) = chan::unbounded();
+    let mut reachable = Vec::new();
+    let mut pending = 0;
+
+    thread::scope(|s| {
+        for _ in 0..config.concurrency.max(1) {
+            let (work, results) = (work.clone(), results.clone());
+
+            s.spawn(move || {
+                for addr in work {
+                    results.send((addr, visit(addr, config))).ok();
+                }
+            });
+        }
+
+        loop {
+            while pending < config.concurrency.max(1) {
+                let Some(addr) = queue.pop_front() else {
+                    break;
+                };
+                jobs.send(addr).ok();
+                pending += 1;
+            }
+            if pending == 0 {
+                break;
+            }
+            let Ok((addr, result)) = done.recv() else {
+                break;
+            };
+            pending -= 1;
+
+            match result {
+                Ok((peer, addrs)) => {
+                    log::debug!(
+                        "{}: Reachable: services = {}, height = {}, user agent = {:?}",
+                        addr,
+                        peer.services,
+                        peer.height,
+                        peer.user_agent
+                    );
+                    for addr in addrs {
+                        if seen.len() >= config.max_peers {
+                            break;
+                        }
+                        if config.is_crawlable(&addr) && seen.insert(addr) {
+                            queue.push_back(addr);
+                        }
+                    }
+                    reachable.push(peer);
+                }
+                Err(err) => {
+                    log::debug!("{}: Unreachable: {}", addr, err);
+                }
+            }
+        }
+        // Let the workers exit.
+        drop(jobs);
+    });
+    log::info!(
+        "Crawled {} address(es), {} reachable",
+        seen.len(),
+        reachable.len()
+    );
+
+    reachable
+}
+
+/// Write the addresses of the peers offering the given services to a seed list file, one
+/// address per line. Peers with a protocol version we don't support are skipped. Returns
+/// the number of addresses written.
+pub fn export(peers: &[Peer], services: ServiceFlags, path: &Path) -> io::Result<usize> {
+    let mut addrs = peers
+        .iter()
+        .filter(|p| p.services.has(services) && p.version >= protocol::MIN_PROTOCOL_VERSION)
+        .map(|p| p.addr)
+        .collect::<Vec<_>>();
+    addrs.sort();
+    addrs.dedup();
+
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    for addr in &addrs {
+        writeln!(file, "{}", addr)?;
+    }
+    file.flush()?;
+
+    Ok(addrs.len())
+}
+
+/// Handshake with a peer, and ask it for addresses. Returns the peer and the addresses
+/// it sent us.
+fn visit(addr: net::SocketAddr, config: &Config) -> Result<(Peer, Vec<net::SocketAddr>), Error> {
+    let stream = net::TcpStream::connect_timeout(&addr, config.timeout)?;
+    let mut conn = Connection::new(stream, config.network.clone(), config.timeout)?;
+    let deadline = Instant::now() + config.timeout;
+
+    conn.send(NetworkMessage::Version(version(addr)))?;
+
+    let (mut peer, mut verack) = (None, false);
+    while peer.is_none() || !verack {
+        match conn.receive(deadline)? {
+            NetworkMessage::Version(msg) => {
+                conn.send(NetworkMessage::Verack)?;
+                peer = Some(Peer {
+                    addr,
+                    services: msg.services,
+                    version: msg.version,
+                    user_agent: msg.user_agent,
+                    height: msg.start_height.max(0) as Height,
+                });
+            }
+            NetworkMessage::Verack => verack = true,
+            _ => {}
+        }
+    }
+    let peer = peer.expect("the version message was received");
+    let deadline = Instant::now() + config.timeout;
+    let mut addrs = Vec::new();
+
+    conn.send(NetworkMessage::GetAddr)?;
+
+    // Peers may announce their own address before answering, so we wait for a response
+    // with more than one address. Not getting one doesn't make the peer unreachable.
+    while addrs.len() <= 1 {
+        match conn.receive(deadline) {
+            Ok(NetworkMessage::Addr(msg)) => {
+                addrs.extend(msg.into_iter().filter_map(|(_, a)| a.socket_addr().ok()));
+            }
+            Ok(NetworkMessage::Ping(nonce)) => conn.send(NetworkMessage::Pong(nonce))?,
+            Ok(_) => {}
+            Err(Error::Io(err)) if err.kind() == io::ErrorKind::TimedOut => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok((peer, addrs))
+}
+
+/// Create the `version` message sent to a peer.
+fn version(addr: net::SocketAddr) -> VersionMessage {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+
+    VersionMessage {
+        version: PROTOCOL_VERSION,
+        services: ServiceFlags::NONE,
+        timestamp,
+        receiver: Address::new(&addr, ServiceFlags::NONE),
+        sender: Address::new(&([0, 0, 0, 0], 0).into(), ServiceFlags::NONE),
+        nonce: fastrand::u64(..),
+        user_agent: USER_AGENT.to_owned(),
+        start_height: 0,
+        relay: false,
+    }
+}
+
+/// A blocking peer connection.
+struct Connection {
+    stream: net::TcpStream,
+    decoder: stream::Decoder,
+    magic: u32,
+}
+
+impl Connection {
+    fn new(stream: net::TcpStream, network: Network, timeout: Duration) -> io::Result<Self> {
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        Ok(Self {
+            stream,
+            decoder: stream::Decoder::new(DECODER_CAPACITY),
+            magic: network.magic(),
+        })
+    }
+
+    fn send(&mut self, payload: NetworkMessage) -> Result<(), Error> {
+        let msg = RawNetworkMessage {
+            magic: self.magic,
+            payload,
+        };
+        self.stream.write_all(&encode::serialize(&msg))?;
+
+        Ok(())
+    }
+
+    /// Receive the next message. Fails with [`io::ErrorKind::TimedOut`] once the deadline
+    /// has passed.
+    fn receive(&mut self, deadline: Instant) -> Result<NetworkMessage, Error> {
+        let mut buf = [0; 1024 * 4];
+
+        loop {
+            if let Some(msg) = self.decoder.decode_next::<RawNetworkMessage>()? {
+                if msg.magic != self.magic {
+                    return Err(Error::Magic(msg.magic));
+                }
+                return Ok(msg.payload);
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::from(io::ErrorKind::TimedOut).into());
+            }
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => self.decoder.input(&buf[..n]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return Err(io::Error::from(io::ErrorKind::TimedOut).into());
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Run a fake peer that answers a single `getaddr` with the given addresses.
+    fn fake_peer(
+        services: ServiceFlags,
+        addrs: Vec<net::SocketAddr>,
+    ) -> (net::SocketAddr, thread::JoinHandle<()>) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let local = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, remote) = listener.accept().unwrap();
+            let mut conn = Connection::new(stream, Network::Regtest, DEFAULT_TIMEOUT).unwrap();
+            let deadline = Instant::now() + DEFAULT_TIMEOUT;
+
+            while let Ok(msg) = conn.receive(deadline) {
+                match msg {
+                    NetworkMessage::Version(_) => {
+                        let mut version = version(remote);
+                        version.services = services;
+                        version.start_height = 144;
+
+                        conn.send(NetworkMessage::Version(version)).unwrap();
+                        conn.send(NetworkMessage::Verack).unwrap();
+                    }
+                    NetworkMessage::GetAddr => {
+                        let addrs = addrs
+                            .iter()
+                            .map(|a| (0, Address::new(a, ServiceFlags::NETWORK)))
+                            .collect();
+                        conn.send(NetworkMessage::Addr(addrs)).unwrap();
+                    }
+                    _ => {}
+                }
+            }
+        });
+        (local, handle)
+    }
+
+    #[test]
+    fn test_crawl() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("seeds.txt");
+        let full = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS;
+
+        // An unreachable address.
+        let unreachable = net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (bob, bob_handle) = fake_peer(ServiceFlags::NETWORK, vec![]);
+        let (alice, alice_handle) = fake_peer(full, vec![bob, unreachable]);
+
+        let config = Config {
+            seeds: vec![alice],
+            routable_only: false,
+            timeout: Duration::from_secs(1),
+            ..Config::new(Network::Regtest)
+        };
+        let mut peers = crawl(&config);
+        peers.sort_by_key(|p| p.addr != alice);
+
+        alice_handle.join().unwrap();
+        bob_handle.join().unwrap();
+
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].addr, alice);
+        assert_eq!(peers[0].services, full);
+        assert_eq!(peers[0].height, 144);
+        assert_eq!(peers[0].user_agent, USER_AGENT);
+        assert_eq!(peers[1].addr, bob);
+
+        // Only peers with compact filters are exported.
+        assert_eq!(export(&peers, full, &path).unwrap(), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", alice));
+
+        // Local addresses are only crawled if asked to.
+        assert!(!Config::new(Network::Regtest).is_crawlable(&bob));
+        assert!(config.is_crawlable(&bob));
+    }
+}
@@ -8,6 +8,8 @@ use nakamoto_chain as chain;
 use nakamoto_common as common;
 use nakamoto_p2p as p2p;
 
+use common::error::{self, Classify};
+
 use p2p::protocol::Command;
 
 /// A client error.
@@ -34,11 +36,70 @@ pub enum Error {
     /// An error coming from the peer store.
     #[error("error loading peers: {0}")]
     PeerStore(io::Error),
+    /// An error opening the event journal.
+    #[error("error opening event journal: {0}")]
+    Journal(#[from] crate::journal::Error),
+    /// An error loading the ban list.
+    #[error("error loading ban list: {0}")]
+    BanList(io::Error),
+    /// An error loading the asmap.
+    #[error("error loading asmap: {0}")]
+    Asmap(#[from] p2p::protocol::asmap::Error),
+    /// An error loading rescan tasks.
+    #[error("error loading rescan tasks: {0}")]
+    Rescan(#[from] crate::rescan::Error),
+    /// An invalid client configuration.
+    #[error("invalid configuration: {0}")]
+    Config(#[from] crate::config::Error),
+    /// A client is already running on this network, either in this process, or in
+    /// another process using the same data directory.
+    #[error("a client is already running on {0:?}")]
+    AlreadyRunning(common::network::Network),
+    /// The reactor panicked.
+    #[error("reactor panicked: {0}")]
+    Panic(String),
     /// A communication channel error.
     #[error("command channel disconnected")]
     Channel,
 }
 
+impl Classify for Error {
+    fn code(&self) -> u32 {
+        match self {
+            Self::Handle(err) => err.code(),
+            Self::P2p(err) => err.code(),
+            Self::Chain(err) => err.code(),
+            Self::BlockStore(err) => err.code(),
+            Self::Config(err) => err.code(),
+            Self::FilterStore(err) => err.code(),
+            Self::Journal(err) => err.code(),
+            Self::Rescan(err) => err.code(),
+            Self::PeerStore(_) => 2005,
+            Self::BanList(_) => 2007,
+            Self::AlreadyRunning(_) => 2010,
+            Self::Io(_) => 2011,
+            Self::Asmap(_) => 4015,
+            Self::Panic(_) => 5002,
+            Self::Channel => 5003,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Handle(err) => err.is_transient(),
+            Self::P2p(err) => err.is_transient(),
+            Self::Chain(err) => err.is_transient(),
+            Self::BlockStore(err) => err.is_transient(),
+            Self::Journal(err) => err.is_transient(),
+            Self::Rescan(err) => err.is_transient(),
+            Self::Io(err) => error::is_transient_io(err),
+            // The other client may shut down.
+            Self::AlreadyRunning(_) => true,
+            _ => false,
+        }
+    }
+}
+
 impl From<chan::SendError<Command>> for Error {
     fn from(_: chan::SendError<Command>) -> Self {
         Self::Channel
@@ -50,3 +111,31 @@ impl From<chan::RecvError> for Error {
         Self::Channel
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::error::Category;
+    use p2p::protocol::{CommandError, GetFiltersError};
+
+    use crate::{config, handle};
+
+    #[test]
+    fn test_classify() {
+        let err = Error::from(handle::Error::from(CommandError::NotConnected));
+        assert_eq!(err.code(), 1002);
+        assert_eq!(err.category(), Category::Network);
+        assert!(err.is_transient());
+
+        let err = Error::from(handle::Error::from(GetFiltersError::Disabled));
+        assert_eq!(err.category(), Category::Config);
+        assert!(!err.is_transient());
+
+        let err = Error::from(config::Error::NoDomains);
+        assert_eq!(err.category(), Category::Config);
+        assert!(!err.is_transient());
+
+        assert_eq!(Error::Channel.category(), Category::Internal);
+        assert!(!Error::Panic(String::from("boom")).is_transient());
+    }
+}
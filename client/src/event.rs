@@ -5,10 +5,12 @@ use std::sync::Arc;
 
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::{Transaction, Txid};
-use nakamoto_common::block::{BlockHash, BlockHeader, Height};
+use nakamoto_common::block::time::LocalDuration;
+use nakamoto_common::block::{Anchor, BlockHash, BlockHeader, Height};
 use nakamoto_p2p::protocol::fees::FeeEstimate;
-use nakamoto_p2p::protocol::{DisconnectReason, Link, PeerId};
+use nakamoto_p2p::protocol::{DisconnectReason, Link, PeerId, RescanId};
 
+use crate::fleet::{Divergence, Tip};
 use crate::spv::TxStatus;
 
 /// Event emitted by the client.
@@ -59,6 +61,10 @@ pub enum Event {
         user_agent: String,
         /// Negotiated protocol version.
         version: u32,
+        /// Whether the peer relays transactions.
+        relay: bool,
+        /// The address the peer advertised for itself, if any.
+        advertised_addr: Option<PeerId>,
     },
     /// The best known height amongst connected peers has been updated.
     /// Note that there is no guarantee that this height really exists;
@@ -68,14 +74,45 @@ pub enum Event {
         /// Best block height known.
         height: Height,
     },
+    /// No new block header was accepted for a while. This could mean that we are being
+    /// eclipsed by our peers, or that we're offline. When this happens, peers are asked
+    /// for new headers, and inactive peers are rotated out.
+    StaleTip {
+        /// Time since our tip was last updated.
+        age: LocalDuration,
+    },
+    /// We reconnected to the network after losing all outbound peers, and reconciled our
+    /// tip with it. Summarizes what changed while we were partitioned: blocks reverted
+    /// and connected during reconciliation are also reported with
+    /// [`Event::BlockDisconnected`] and [`Event::BlockConnected`].
+    Resynced {
+        /// Peer our tip was reconciled with.
+        peer: PeerId,
+        /// Time spent without any outbound peer.
+        offline: LocalDuration,
+        /// Number of reconnection attempts.
+        attempts: u32,
+        /// Block header height before the partition.
+        from: Height,
+        /// Block header height after reconciliation.
+        height: Height,
+        /// Block hash of our new tip.
+        tip: BlockHash,
+        /// Number of blocks removed from the active chain.
+        reverted: usize,
+        /// Number of blocks added to the active chain.
+        connected: usize,
+        /// Filter header height before the partition.
+        from_filter_height: Height,
+        /// Filter header height after reconciliation.
+        filter_height: Height,
+    },
     /// A block was added to the main chain.
     BlockConnected {
         /// Block header.
         header: BlockHeader,
-        /// Block hash.
-        hash: BlockHash,
-        /// Height of the block.
-        height: Height,
+        /// The connected block.
+        block: Anchor,
     },
     /// One of the blocks of the main chain was reverted, due to a re-org.
     /// These events will fire from the latest block starting from the tip, to the earliest.
@@ -83,29 +120,30 @@ pub enum Event {
     BlockDisconnected {
         /// Header of the block.
         header: BlockHeader,
-        /// Block hash.
-        hash: BlockHash,
-        /// Height of the block when it was part of the main chain.
-        height: Height,
+        /// The disconnected block, at its height when it was part of the main chain.
+        block: Anchor,
     },
     /// A block has matched one of the filters and is ready to be processed.
     /// This event usually precedes [`Event::TxStatusChanged`] events.
     BlockMatched {
-        /// Hash of the matching block.
-        hash: BlockHash,
+        /// The matching block.
+        block: Anchor,
         /// Block header.
         header: BlockHeader,
-        /// Block height.
-        height: Height,
         /// Transactions in this block.
         transactions: Vec<Transaction>,
     },
+    /// An unconfirmed transaction paying to a watched script was found in the trusted peer's
+    /// mempool. Only emitted with mempool prefetch enabled. The transaction may never be
+    /// confirmed: it can be double-spent or replaced by its sender.
+    MempoolMatched {
+        /// The unconfirmed transaction.
+        transaction: Transaction,
+    },
     /// Transaction fee rate estimated for a block.
     FeeEstimated {
-        /// Block hash of the estimate.
-        block: BlockHash,
-        /// Block height of the estimate.
-        height: Height,
+        /// Block of the estimate.
+        block: Anchor,
         /// Fee estimate.
         fees: FeeEstimate,
     },
@@ -113,10 +151,8 @@ pub enum Event {
     /// the corresponding block was scheduled for download, and a [`Event::BlockMatched`]
     /// event will eventually be fired.
     FilterProcessed {
-        /// Corresponding block hash.
-        block: BlockHash,
-        /// Filter height (same as block).
-        height: Height,
+        /// Corresponding block. The filter height is the block height.
+        block: Anchor,
         /// Whether or not this filter matched any of the watched scripts.
         matched: bool,
         /// Whether or not this filter is valid.
@@ -129,6 +165,13 @@ pub enum Event {
         /// The new transaction status.
         status: TxStatus,
     },
+    /// A rescan has completed.
+    RescanCompleted {
+        /// Rescan identifier.
+        id: RescanId,
+        /// Last height processed by the rescan.
+        height: Height,
+    },
     /// Compact filters have been synced and processed up to this point and matching blocks have
     /// been fetched.
     ///
@@ -140,6 +183,35 @@ pub enum Event {
         /// Tip of our block header chain.
         tip: Height,
     },
+    /// The client's reactor failed, and was restarted with its state reloaded from disk.
+    /// Peers are reconnected, and interrupted rescans are resumed.
+    Restarted {
+        /// Number of restarts in a row, ie. since the reactor last ran for long enough
+        /// to be considered stable.
+        restarts: usize,
+        /// Reason for the restart.
+        reason: String,
+    },
+    /// Corruption was found in a store when loading it, and the store was truncated to its
+    /// last consistent height. The truncated headers are synced again from peers.
+    StoreRecovered {
+        /// Name of the store, eg. `headers` or `filters`.
+        store: &'static str,
+        /// Height the store was truncated to.
+        height: Height,
+        /// Reason the store was truncated.
+        reason: String,
+    },
+    /// Our tip diverged from the tip signed by the fleet leader. Only emitted by fleet
+    /// members, when the kind of divergence changes. See [`crate::fleet`].
+    TipDiverged {
+        /// The leader's tip.
+        leader: Tip,
+        /// Our tip.
+        tip: Tip,
+        /// How our tip diverges.
+        divergence: Divergence,
+    },
 }
 
 impl fmt::Display for Event {
@@ -148,39 +220,72 @@ impl fmt::Display for Event {
             Self::Ready { .. } => {
                 write!(fmt, "ready to process events and commands")
             }
-            Self::BlockConnected { hash, height, .. } => {
-                write!(fmt, "block {} connected at height {}", hash, height)
+            Self::BlockConnected { block, .. } => {
+                write!(fmt, "block {} connected", block)
+            }
+            Self::BlockDisconnected { block, .. } => {
+                write!(fmt, "block {} disconnected", block)
             }
-            Self::BlockDisconnected { hash, height, .. } => {
-                write!(fmt, "block {} disconnected at height {}", hash, height)
+            Self::BlockMatched { block, .. } => {
+                write!(fmt, "block {} ready to be processed", block)
             }
-            Self::BlockMatched { hash, height, .. } => {
+            Self::MempoolMatched { transaction } => {
                 write!(
                     fmt,
-                    "block {} ready to be processed at height {}",
-                    hash, height
+                    "unconfirmed transaction {} matched in the mempool",
+                    transaction.txid()
                 )
             }
-            Self::FeeEstimated { fees, height, .. } => {
+            Self::FeeEstimated { fees, block } => {
                 write!(
                     fmt,
                     "transaction median fee rate for block #{} is {} sat/vB",
-                    height, fees.median,
+                    block.height, fees.median,
                 )
             }
-            Self::FilterProcessed {
-                height, matched, ..
-            } => {
+            Self::FilterProcessed { block, matched, .. } => {
                 write!(
                     fmt,
                     "filter processed at height {} (match = {})",
-                    height, matched
+                    block.height, matched
                 )
             }
             Self::TxStatusChanged { txid, status } => {
                 write!(fmt, "transaction {} status changed: {}", txid, status)
             }
+            Self::RescanCompleted { id, height } => {
+                write!(fmt, "rescan {} completed at height {}", id, height)
+            }
             Self::Synced { height, .. } => write!(fmt, "filters synced up to height {}", height),
+            Self::Restarted { restarts, reason } => {
+                write!(
+                    fmt,
+                    "client restarted ({}) after failure: {}",
+                    restarts, reason
+                )
+            }
+            Self::StoreRecovered {
+                store,
+                height,
+                reason,
+            } => {
+                write!(
+                    fmt,
+                    "{} store truncated to height {}: {}",
+                    store, height, reason
+                )
+            }
+            Self::TipDiverged {
+                leader,
+                tip,
+                divergence,
+            } => {
+                write!(
+                    fmt,
+                    "tip {} at height {} diverged from fleet leader's tip {} at height {} ({:?})",
+                    tip.hash, tip.height, leader.hash, leader.height, divergence
+                )
+            }
             Self::PeerConnected { addr, link } => {
                 write!(fmt, "peer {} connected ({:?})", &addr, link)
             }
@@ -194,6 +299,29 @@ impl fmt::Display for Event {
             Self::PeerHeightUpdated { height } => {
                 write!(fmt, "peer height updated to {}", height)
             }
+            Self::StaleTip { age } => {
+                write!(
+                    fmt,
+                    "tip is stale (last updated {} minute(s) ago)",
+                    age.as_mins()
+                )
+            }
+            Self::Resynced {
+                peer,
+                offline,
+                from,
+                height,
+                reverted,
+                connected,
+                ..
+            } => {
+                write!(
+                    fmt,
+                    "resynced with {} after {} offline: height {} -> {} \
+                     ({} block(s) connected, {} reverted)",
+                    peer, offline, from, height, connected, reverted
+                )
+            }
             Self::PeerDisconnected { addr, reason } => {
                 write!(fmt, "disconnected from {} ({})", &addr, reason)
             }
@@ -201,11 +329,12 @@ impl fmt::Display for Event {
                 addr,
                 height,
                 services,
+                user_agent,
                 ..
             } => write!(
                 fmt,
-                "peer {} negotiated with services {} and height {}..",
-                addr, services, height
+                "peer {} negotiated with services {} and height {} ({})..",
+                addr, services, height, user_agent
             ),
         }
     }
@@ -0,0 +1,577 @@
+//! Tip attestations, for fleets of clients.
+//!
+//! Operators running many clients can designate one trusted client as the fleet's
+//! *leader*. The leader periodically signs its tip, ie. its height, block hash and total
+//! proof-of-work, with a configured key, and publishes the signed tip to the other clients
+//! of the fleet, its *members*, one JSON object per line.
+//!
+//! Members connect to the leader, check the signature against the leader's public key,
+//! and compare the leader's tip with their own. When they disagree, eg. because a member
+//! is on a different fork, or is lagging behind, [`Event::TipDiverged`] is emitted.
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::net;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use microserde::json::{Number, Object, Value};
+use thiserror::Error;
+
+use nakamoto_common::bitcoin::secp256k1::{self, schnorr, KeyPair, Message, Secp256k1};
+use nakamoto_common::bitcoin::secp256k1::{SecretKey, XOnlyPublicKey};
+use nakamoto_common::bitcoin_hashes::hex::{FromHex, ToHex};
+use nakamoto_common::bitcoin_hashes::{sha256, Hash, HashEngine};
+use nakamoto_common::block::{BlockHash, Height, Work};
+
+use crate::client::{chan, Event};
+use crate::handle::{self, Handle};
+
+/// Tag prepended to signed messages, so that signatures can't be reused elsewhere.
+const MESSAGE_TAG: &[u8] = b"nakamoto/fleet/tip";
+/// How long a write to a member may block before it is disconnected.
+pub const WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(3);
+/// Time to wait before reconnecting to the leader.
+pub const RECONNECT_DELAY: time::Duration = time::Duration::from_secs(5);
+/// How often members check that the client is still running while waiting for the leader.
+pub const PROBE_INTERVAL: time::Duration = time::Duration::from_secs(10);
+/// How often the leader checks for new members, and whether it should stop.
+pub const ACCEPT_INTERVAL: time::Duration = time::Duration::from_millis(100);
+/// Maximum length of a line sent by the leader. Signed tips are much shorter.
+pub const MAX_LINE_SIZE: usize = 4 * 1024;
+
+/// A fleet error.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// An I/O error.
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    /// A signed tip could not be decoded.
+    #[error("invalid signed tip: {0}")]
+    Decode(String),
+    /// A signature is invalid.
+    #[error("invalid signature: {0}")]
+    Signature(#[from] secp256k1::Error),
+}
+
+/// Fleet configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Config {
+    /// Sign our tip and publish it to members connecting on the given address.
+    Leader {
+        /// Address to listen on for members.
+        listen: net::SocketAddr,
+        /// Key to sign our tip with.
+        key: SecretKey,
+        /// Time between signed tips.
+        interval: time::Duration,
+    },
+    /// Compare our tip with the tips signed by the leader.
+    Member {
+        /// Address of the leader.
+        leader: net::SocketAddr,
+        /// Public key of the leader.
+        key: XOnlyPublicKey,
+        /// Number of blocks we may lag behind the leader before diverging.
+        max_lag: Height,
+    },
+}
+
+/// A chain tip.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Tip {
+    /// Height of the tip.
+    pub height: Height,
+    /// Block hash of the tip.
+    pub hash: BlockHash,
+    /// Total proof-of-work of the chain.
+    pub work: Work,
+}
+
+/// How our tip diverges from the leader's.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    /// The leader's tip isn't in our active chain, even though we have as many blocks,
+    /// or as much work: we're on a different fork.
+    Fork,
+    /// We're behind the leader by the given number of blocks.
+    Behind(Height),
+}
+
+impl Divergence {
+    /// Compare our tip with the leader's. The leader may be behind us, but its tip must
+    /// then be in our active chain.
+    pub fn check(leader: &Tip, ours: &Tip, in_chain: bool, max_lag: Height) -> Option<Self> {
+        if in_chain {
+            None
+        } else if ours.height >= leader.height || ours.work >= leader.work {
+            Some(Self::Fork)
+        } else if leader.height - ours.height > max_lag {
+            Some(Self::Behind(leader.height - ours.height))
+        } else {
+            None
+        }
+    }
+
+    /// Whether the kind of divergence changed. Lagging further behind isn't a change,
+    /// since the lag grows with every block until we catch up.
+    fn changed(old: Option<Self>, new: Option<Self>) -> bool {
+        old.map(|d| mem::discriminant(&d)) != new.map(|d| mem::discriminant(&d))
+    }
+}
+
+/// A tip signed by the fleet leader.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedTip {
+    /// The leader's tip.
+    pub tip: Tip,
+    /// Time at which the tip was signed, in seconds since Epoch. Used to discard replayed
+    /// tips.
+    pub time: u64,
+    /// Signature over the tip and time.
+    pub signature: schnorr::Signature,
+}
+
+impl SignedTip {
+    /// Sign a tip.
+    pub fn sign(tip: Tip, time: u64, key: &SecretKey) -> Self {
+        let secp = Secp256k1::signing_only();
+        let keypair = KeyPair::from_secret_key(&secp, *key);
+        let aux = [(); 32].map(|_| fastrand::u8(..));
+        let signature = secp.sign_schnorr_with_aux_rand(&Self::message(&tip, time), &keypair, &aux);
+
+        Self {
+            tip,
+            time,
+            signature,
+        }
+    }
+
+    /// Verify the tip's signature.
+    pub fn verify(&self, key: &XOnlyPublicKey) -> Result<(), Error> {
+        Secp256k1::verification_only()
+            .verify_schnorr(&self.signature, &Self::message(&self.tip, self.time), key)
+            .map_err(Error::from)
+    }
+
+    /// Encode the signed tip as a JSON object, on a single line.
+    pub fn to_json(&self) -> String {
+        let object: Object = [
+            ("height", Value::Number(Number::U64(self.tip.height))),
+            ("hash", Value::String(self.tip.hash.to_string())),
+            ("work", Value::String(self.tip.work.to_be_bytes().to_hex())),
+            ("time", Value::Number(Number::U64(self.time))),
+            ("signature", Value::String(self.signature.as_ref().to_hex())),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_owned(), v))
+        .collect();
+
+        microserde::json::to_string(&Value::Object(object))
+    }
+
+    /// Decode a signed tip encoded with [`SignedTip::to_json`]. Doesn't verify the signature.
+    pub fn from_json(s: &str) -> Result<Self, Error> {
+        let decode = |field: &str| Error::Decode(format!("missing or invalid `{}`", field));
+        let object = match microserde::json::from_str::<Value>(s) {
+            Ok(Value::Object(object)) => object,
+            _ => return Err(Error::Decode(String::from("not a JSON object"))),
+        };
+        let number = |field: &str| match object.get(field) {
+            Some(Value::Number(Number::U64(n))) => Ok(*n),
+            _ => Err(decode(field)),
+        };
+        let string = |field: &str| match object.get(field) {
+            Some(Value::String(s)) => Ok(s.as_str()),
+            _ => Err(decode(field)),
+        };
+
+        let hash = BlockHash::from_str(string("hash")?).map_err(|_| decode("hash"))?;
+        let work = Vec::<u8>::from_hex(string("work")?)
+            .ok()
+            .and_then(|bytes| Work::from_be_slice(&bytes).ok())
+            .ok_or_else(|| decode("work"))?;
+        let signature = Vec::<u8>::from_hex(string("signature")?)
+            .ok()
+            .and_then(|bytes| schnorr::Signature::from_slice(&bytes).ok())
+            .ok_or_else(|| decode("signature"))?;
+
+        Ok(Self {
+            tip: Tip {
+                height: number("height")?,
+                hash,
+                work,
+            },
+            time: number("time")?,
+            signature,
+        })
+    }
+
+    /// The message signed by the leader.
+    fn message(tip: &Tip, time: u64) -> Message {
+        let mut engine = sha256::Hash::engine();
+
+        engine.input(MESSAGE_TAG);
+        engine.input(&tip.height.to_le_bytes());
+        engine.input(&tip.hash[..]);
+        engine.input(&tip.work.to_be_bytes());
+        engine.input(&time.to_le_bytes());
+
+        let hash = sha256::Hash::from_engine(engine);
+
+        Message::from_slice(&hash[..]).expect("SignedTip::message: hashes are valid messages")
+    }
+}
+
+/// Signs our tip and publishes it to the fleet's members.
+#[derive(Debug)]
+pub struct Leader {
+    listener: net::TcpListener,
+    key: SecretKey,
+    interval: time::Duration,
+    members: Arc<Mutex<Vec<net::TcpStream>>>,
+    /// The last signed tip, sent to members as soon as they connect.
+    last: Arc<Mutex<Option<String>>>,
+}
+
+impl Leader {
+    /// Listen for members on the given address.
+    pub fn bind(
+        listen: net::SocketAddr,
+        key: SecretKey,
+        interval: time::Duration,
+    ) -> io::Result<Self> {
+        let listener = net::TcpListener::bind(listen)?;
+        // The listener is polled, so that the accept thread can notice when to stop.
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            key,
+            interval,
+            members: Arc::new(Mutex::new(Vec::new())),
+            last: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Address members can connect to.
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept members and publish our signed tip periodically, in background threads.
+    /// Publishing stops when the client stops, at which point the listening socket and
+    /// member connections are closed.
+    pub fn spawn<H: Handle + 'static>(self, handle: H) {
+        let listener = self.listener;
+        let members = self.members.clone();
+        let last = self.last.clone();
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        thread::spawn({
+            let stopped = stopped.clone();
+
+            move || loop {
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        if stopped.load(Ordering::SeqCst) {
+                            return;
+                        }
+                        thread::sleep(ACCEPT_INTERVAL);
+                        continue;
+                    }
+                    Err(err) => {
+                        log::error!("Failed to accept fleet member: {}", err);
+                        continue;
+                    }
+                };
+                // Accepted streams may inherit the listener's non-blocking mode.
+                let mut stream = match stream
+                    .set_nonblocking(false)
+                    .and_then(|_| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+                {
+                    Ok(()) => stream,
+                    Err(err) => {
+                        log::error!("Failed to accept fleet member: {}", err);
+                        continue;
+                    }
+                };
+                log::debug!("Fleet member connected: {:?}", stream.peer_addr());
+
+                if let Some(line) = &*last.lock().unwrap() {
+                    if stream.write_all(line.as_bytes()).is_err() {
+                        continue;
+                    }
+                }
+                members.lock().unwrap().push(stream);
+            }
+        });
+
+        let (members, last, key, interval) = (self.members, self.last, self.key, self.interval);
+
+        thread::spawn(move || loop {
+            match handle.get_chain_work() {
+                Ok(chain) => {
+                    let tip = Tip {
+                        height: chain.height,
+                        hash: chain.tip,
+                        work: chain.work,
+                    };
+                    let line = SignedTip::sign(tip, self::now(), &key).to_json() + "\n";
+
+                    members.lock().unwrap().retain_mut(|s| {
+                        if let Err(err) = s.write_all(line.as_bytes()) {
+                            log::debug!("Fleet member disconnected: {}", err);
+                            return false;
+                        }
+                        true
+                    });
+                    *last.lock().unwrap() = Some(line);
+                }
+                Err(handle::Error::Disconnected | handle::Error::ShuttingDown) => {
+                    stopped.store(true, Ordering::SeqCst);
+                    members.lock().unwrap().clear();
+
+                    return;
+                }
+                Err(err) => log::error!("Failed to get tip to sign: {}", err),
+            }
+            thread::sleep(interval);
+        });
+    }
+}
+
+/// Compares our tip with the tips signed by the leader.
+#[derive(Debug, Clone)]
+pub struct Member {
+    leader: net::SocketAddr,
+    key: XOnlyPublicKey,
+    max_lag: Height,
+}
+
+impl Member {
+    /// Create a new member of the fleet led by the given leader.
+    pub fn new(leader: net::SocketAddr, key: XOnlyPublicKey, max_lag: Height) -> Self {
+        Self {
+            leader,
+            key,
+            max_lag,
+        }
+    }
+
+    /// Connect to the leader and compare its tips with ours, in a background thread,
+    /// reconnecting when disconnected. Divergences are passed to the `emit` function,
+    /// every time their kind changes. Stops when the client stops.
+    pub fn spawn<H: Handle + 'static>(self, handle: H, emit: impl Fn(Event) + Send + 'static) {
+        thread::spawn(move || {
+            let alive = |handle: &H| {
+                !matches!(
+                    handle.get_tip(),
+                    Err(handle::Error::Disconnected | handle::Error::ShuttingDown)
+                )
+            };
+            let mut last = 0;
+            let mut divergence = None;
+
+            while alive(&handle) {
+                let stream = match net::TcpStream::connect(self.leader)
+                    .and_then(|s| s.set_read_timeout(Some(PROBE_INTERVAL)).map(|_| s))
+                {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::debug!("Failed to connect to fleet leader {}: {}", self.leader, err);
+                        thread::sleep(RECONNECT_DELAY);
+                        continue;
+                    }
+                };
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+
+                loop {
+                    let limit = (MAX_LINE_SIZE - line.len()) as u64;
+
+                    match reader.by_ref().take(limit).read_line(&mut line) {
+                        Ok(0) => break,
+                        Ok(_) if !line.ends_with('\n') && line.len() >= MAX_LINE_SIZE => {
+                            log::warn!("Fleet leader sent a line that is too long, disconnecting");
+                            break;
+                        }
+                        Ok(_) => {}
+                        Err(err)
+                            if matches!(
+                                err.kind(),
+                                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                            ) =>
+                        {
+                            // Partial lines are kept until the rest is read.
+                            if !alive(&handle) {
+                                return;
+                            }
+                            continue;
+                        }
+                        Err(err) => {
+                            log::debug!("Fleet leader disconnected: {}", err);
+                            break;
+                        }
+                    }
+                    let signed = match SignedTip::from_json(line.trim_end())
+                        .and_then(|s| s.verify(&self.key).map(|_| s))
+                    {
+                        Ok(signed) => signed,
+                        Err(err) => {
+                            log::warn!("Invalid tip received from fleet leader: {}", err);
+                            line.clear();
+                            continue;
+                        }
+                    };
+                    line.clear();
+
+                    // Discard tips that aren't newer than the last one, eg. replayed tips.
+                    if signed.time <= last {
+                        continue;
+                    }
+                    last = signed.time;
+
+                    match self.compare(&handle, &signed.tip) {
+                        Ok((tip, new)) => {
+                            if Divergence::changed(divergence, new) {
+                                if let Some(divergence) = new {
+                                    emit(Event::TipDiverged {
+                                        leader: signed.tip,
+                                        tip,
+                                        divergence,
+                                    });
+                                } else {
+                                    log::info!("Tip converged with fleet leader");
+                                }
+                                divergence = new;
+                            }
+                        }
+                        Err(handle::Error::Disconnected | handle::Error::ShuttingDown) => return,
+                        Err(err) => log::error!("Failed to compare tip with leader: {}", err),
+                    }
+                }
+                drop(reader);
+                thread::sleep(RECONNECT_DELAY);
+            }
+        });
+    }
+
+    /// Compare the leader's tip with ours. Returns our tip, and how it diverges, if it does.
+    fn compare<H: Handle>(
+        &self,
+        handle: &H,
+        leader: &Tip,
+    ) -> Result<(Tip, Option<Divergence>), handle::Error> {
+        let chain = handle.get_chain_work()?;
+        let ours = Tip {
+            height: chain.height,
+            hash: chain.tip,
+            work: chain.work,
+        };
+        let (hash, height) = (leader.hash, leader.height);
+        let (transmit, receive) = chan::bounded(1);
+
+        handle.query_tree(move |tree| {
+            let in_chain = tree.get_block(&hash).is_some_and(|(h, _)| h == height);
+            transmit.send(in_chain).ok();
+        })?;
+        let in_chain = receive.recv()?;
+
+        Ok((
+            ours,
+            Divergence::check(leader, &ours, in_chain, self.max_lag),
+        ))
+    }
+}
+
+/// Current time, in seconds since Epoch.
+fn now() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tip(height: Height, work: u64) -> Tip {
+        Tip {
+            height,
+            hash: BlockHash::hash(&height.to_le_bytes()),
+            work: Work::from_u64(work).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let secp = Secp256k1::new();
+        let key = SecretKey::from_slice(&[7; 32]).unwrap();
+        let public = XOnlyPublicKey::from_keypair(&KeyPair::from_secret_key(&secp, key));
+        let signed = SignedTip::sign(tip(42, 1337), 1_600_000_000, &key);
+
+        signed.verify(&public).unwrap();
+
+        let decoded = SignedTip::from_json(&signed.to_json()).unwrap();
+        assert_eq!(decoded, signed);
+        decoded.verify(&public).unwrap();
+
+        // Tampering with the tip invalidates the signature.
+        let mut tampered = signed.clone();
+        tampered.tip.height += 1;
+        assert!(matches!(tampered.verify(&public), Err(Error::Signature(_))));
+
+        let mut tampered = signed;
+        tampered.time += 1;
+        assert!(tampered.verify(&public).is_err());
+
+        assert!(matches!(
+            SignedTip::from_json(r#"{"height":1}"#),
+            Err(Error::Decode(_))
+        ));
+        assert!(SignedTip::from_json("[]").is_err());
+    }
+
+    #[test]
+    fn test_divergence() {
+        let leader = tip(100, 1000);
+
+        // The leader's tip is in our chain.
+        assert_eq!(Divergence::check(&leader, &tip(100, 1000), true, 0), None);
+        assert_eq!(Divergence::check(&leader, &tip(120, 1200), true, 0), None);
+        // We're not at the leader's tip, but have as many blocks.
+        assert_eq!(
+            Divergence::check(&leader, &tip(100, 900), false, 0),
+            Some(Divergence::Fork)
+        );
+        // We're not at the leader's tip, but have as much work.
+        assert_eq!(
+            Divergence::check(&leader, &tip(90, 1000), false, 0),
+            Some(Divergence::Fork)
+        );
+        // We're behind.
+        assert_eq!(Divergence::check(&leader, &tip(90, 900), false, 10), None);
+        assert_eq!(
+            Divergence::check(&leader, &tip(89, 890), false, 10),
+            Some(Divergence::Behind(11))
+        );
+    }
+
+    #[test]
+    fn test_divergence_changed() {
+        use Divergence::*;
+
+        assert!(Divergence::changed(None, Some(Behind(11))));
+        assert!(Divergence::changed(Some(Behind(11)), Some(Fork)));
+        assert!(Divergence::changed(Some(Fork), None));
+        // The lag growing or shrinking isn't reported again.
+        assert!(!Divergence::changed(Some(Behind(11)), Some(Behind(12))));
+        assert!(!Divergence::changed(Some(Fork), Some(Fork)));
+        assert!(!Divergence::changed(None, None));
+    }
+}
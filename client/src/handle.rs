@@ -2,23 +2,41 @@
 //! protocol instance.
 use std::net;
 use std::ops::{RangeBounds, RangeInclusive};
+use std::path::{Path, PathBuf};
+use std::time;
 
 use crossbeam_channel as chan;
 use thiserror::Error;
 
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::Address;
-use nakamoto_common::bitcoin::Script;
+use nakamoto_common::bitcoin::util::merkleblock::{MerkleBlock, MerkleBlockError};
+use nakamoto_common::bitcoin::{Script, Txid};
 
 use nakamoto_common::bitcoin::network::message::NetworkMessage;
 use nakamoto_common::block::filter::BlockFilter;
+use nakamoto_common::block::time::LocalDuration;
 use nakamoto_common::block::tree::{BlockReader, ImportResult};
-use nakamoto_common::block::{self, Block, BlockHash, BlockHeader, Height, Transaction};
+use nakamoto_common::block::{
+    self, Anchor, Block, BlockHash, BlockHeader, BlockTime, Height, Transaction,
+};
+use nakamoto_common::error::{self, Classify};
 use nakamoto_common::nonempty::NonEmpty;
+use nakamoto_p2p::event::Subscription;
+use nakamoto_p2p::protocol::log_filter::{self, LogFilter};
 use nakamoto_p2p::protocol::Link;
-use nakamoto_p2p::protocol::{self, Command, CommandError, GetFiltersError, Peer};
+use nakamoto_p2p::protocol::{
+    self, Ban, Bandwidth, ChainWork, Command, CommandError, ConfigUpdate, GetFiltersError,
+    MessageStats, Peer, PeerInfo, RescanId, TxStatus,
+};
 
 use crate::client::Event;
+use crate::journal;
+use crate::ots::{self, DetachedTimestamp};
+use crate::proof::SpvProof;
+use crate::rescan;
+use crate::snapshot::{self, Snapshot};
+use crate::stats::ChainStats;
 
 /// An error resulting from a handle method.
 #[derive(Error, Debug)]
@@ -35,9 +53,70 @@ pub enum Error {
     /// The operation timed out.
     #[error("the operation timed out")]
     Timeout,
+    /// The client is shutting down.
+    #[error("the client is shutting down")]
+    ShuttingDown,
     /// An I/O error occured.
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    /// A snapshot error occured.
+    #[error("snapshot error: {0}")]
+    Snapshot(#[from] snapshot::Error),
+    /// An event journal error occured.
+    #[error("journal error: {0}")]
+    Journal(#[from] journal::Error),
+    /// A rescan task error occured.
+    #[error("rescan error: {0}")]
+    Rescan(#[from] rescan::Error),
+    /// A log filter is invalid.
+    #[error("invalid log filter: {0}")]
+    LogFilter(#[from] log_filter::Error),
+    /// A block is not in the active chain.
+    #[error("block {0} is not in the active chain")]
+    BlockNotFound(BlockHash),
+    /// A merkle proof is invalid.
+    #[error("invalid merkle proof: {0:?}")]
+    InvalidMerkleProof(MerkleBlockError),
+    /// An OpenTimestamps proof could not be verified.
+    #[error("timestamp verification failed: {0}")]
+    Ots(#[from] ots::Error),
+    /// An argument is invalid.
+    #[error("invalid argument: {0}")]
+    InvalidArgument(&'static str),
+}
+
+impl Classify for Error {
+    fn code(&self) -> u32 {
+        match self {
+            Self::Command(err) => err.code(),
+            Self::GetFilters(err) => err.code(),
+            Self::Journal(err) => err.code(),
+            Self::Rescan(err) => err.code(),
+            Self::Snapshot(err) => err.code(),
+            Self::Timeout => 1005,
+            Self::Io(_) => 2013,
+            Self::BlockNotFound(_) => 3007,
+            Self::InvalidMerkleProof(_) => 3008,
+            Self::Ots(_) => 3009,
+            Self::LogFilter(_) => 4016,
+            Self::InvalidArgument(_) => 4014,
+            Self::ShuttingDown => 5001,
+            Self::Disconnected => 5004,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Command(err) => err.is_transient(),
+            Self::GetFilters(err) => err.is_transient(),
+            Self::Journal(err) => err.is_transient(),
+            Self::Rescan(err) => err.is_transient(),
+            Self::Snapshot(err) => err.is_transient(),
+            Self::Io(err) => error::is_transient_io(err),
+            Self::Timeout => true,
+            _ => false,
+        }
+    }
 }
 
 impl From<chan::RecvError> for Error {
@@ -75,6 +154,87 @@ pub trait Handle: Sized + Send + Sync + Clone {
         &self,
         query: impl Fn(&dyn BlockReader) + Send + Sync + 'static,
     ) -> Result<(), Error>;
+    /// Get the median time past of the blocks leading up to and including the given height.
+    ///
+    /// See [`BlockReader::median_time`].
+    fn median_time(&self, height: Height) -> Result<BlockTime, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.query_tree(move |tree| {
+            transmit.send(tree.median_time(height)).ok();
+        })?;
+
+        Ok(receive.recv()?)
+    }
+    /// Verify a merkle proof against the active chain. Returns the height of the block
+    /// the proof commits to, and the transactions it proves are included in the block.
+    ///
+    /// This only requires block headers, and so works in headers-only mode.
+    fn verify_merkle_block(&self, block: &MerkleBlock) -> Result<(Height, Vec<Txid>), Error> {
+        let hash = block.header.block_hash();
+        let (transmit, receive) = chan::bounded(1);
+        self.query_tree(move |tree| {
+            transmit.send(tree.get_block(&hash).map(|(h, _)| h)).ok();
+        })?;
+        let height = receive.recv()?.ok_or(Error::BlockNotFound(hash))?;
+
+        let mut matches = Vec::new();
+        let mut indexes = Vec::new();
+        block
+            .extract_matches(&mut matches, &mut indexes)
+            .map_err(Error::InvalidMerkleProof)?;
+
+        Ok((height, matches))
+    }
+    /// Get an SPV proof of one of our recently confirmed transactions, which can be
+    /// verified without trusting the client. Returns `None` if the transaction isn't
+    /// known to be confirmed in the active chain.
+    ///
+    /// Only transactions submitted through the client are tracked, and only until they
+    /// are buried past a certain depth.
+    fn get_spv_proof(&self, txid: Txid) -> Result<Option<SpvProof>, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetProof(txid, transmit))?;
+
+        let (height, merkle_block) = match receive.recv()? {
+            Some(proof) => proof,
+            None => return Ok(None),
+        };
+        let hash = merkle_block.header.block_hash();
+
+        let (transmit, receive) = chan::bounded(1);
+        self.query_tree(move |tree| {
+            // The block may have been reverted in the meantime.
+            let headers = match tree.get_block(&hash) {
+                Some((h, _)) if h == height => Some(
+                    (height + 1..=tree.height())
+                        .filter_map(|h| tree.get_block_by_height(h).copied())
+                        .collect::<Vec<_>>(),
+                ),
+                _ => None,
+            };
+            transmit.send(headers).ok();
+        })?;
+
+        Ok(receive.recv()?.map(|headers| SpvProof {
+            height,
+            merkle_block,
+            headers,
+        }))
+    }
+    /// Verify an OpenTimestamps proof, ie. the contents of an `.ots` file, against the
+    /// active chain. Returns the earliest block the timestamped digest is committed to.
+    ///
+    /// The returned digest must be checked against the digest of the timestamped file.
+    /// See [`ots`].
+    fn verify_ots(&self, proof: &[u8]) -> Result<ots::Verified, Error> {
+        let proof = DetachedTimestamp::from_bytes(proof)?;
+        let (transmit, receive) = chan::bounded(1);
+        self.query_tree(move |tree| {
+            transmit.send(proof.verify(tree)).ok();
+        })?;
+
+        Ok(receive.recv()??)
+    }
     /// Find a branch from the active chain to the given (stale) block.
     ///
     /// See [BlockReader::find_branch](`nakamoto_common::block::tree::BlockReader::find_branch`).
@@ -86,30 +246,56 @@ pub trait Handle: Sized + Send + Sync + Clone {
     fn filters(&self) -> chan::Receiver<(BlockFilter, BlockHash, Height)>;
     /// Subscribe to SPV events.
     fn subscribe(&self) -> chan::Receiver<Event>;
+    /// Subscribe to SPV events, queuing at most `capacity` events. Events are tagged with
+    /// sequence numbers, and if the subscriber falls behind, the oldest events are dropped.
+    /// Dropped events are reported by [`Subscription::gap`], so that state derived from
+    /// events can be reconciled, eg. with a rescan.
+    fn subscription(&self, capacity: usize) -> Subscription<Event>;
     /// Send a command to the client.
     fn command(&self, cmd: Command) -> Result<(), Error>;
-    /// Rescan the blockchain for matching scripts.
+    /// Rescan the blockchain for matching scripts. Returns the id of the rescan, which
+    /// is used in rescan events and to pause, resume or cancel it. Starting a rescan
+    /// replaces any existing one.
     ///
     /// If a "reorg" takes place, filters up to the start of the provided range
     /// will be re-fetched and scanned.
+    ///
+    /// Fails if the range is empty.
     fn rescan(
         &self,
         range: impl RangeBounds<Height>,
         watch: impl Iterator<Item = Script>,
-    ) -> Result<(), Error> {
-        // TODO: Handle invalid/empty ranges.
-
+    ) -> Result<RescanId, Error> {
+        let id = fastrand::u64(..);
         let from = range.start_bound().cloned();
         let to = range.end_bound().cloned();
 
         self.command(Command::Rescan {
+            id,
             from,
             to,
             watch: watch.collect(),
         })?;
 
-        Ok(())
+        Ok(id)
+    }
+    /// Pause a rescan. Has no effect if the rescan isn't running.
+    fn pause_rescan(&self, id: RescanId) -> Result<(), Error> {
+        self.command(Command::PauseRescan(id))
     }
+    /// Resume a paused rescan. Has no effect if the rescan is running. When rescan tasks
+    /// are persisted, fails if the rescan has completed or was canceled.
+    fn resume_rescan(&self, id: RescanId) -> Result<(), Error> {
+        self.command(Command::ResumeRescan(id))
+    }
+    /// Cancel a rescan.
+    fn cancel_rescan(&self, id: RescanId) -> Result<(), Error> {
+        self.command(Command::CancelRescan(id))
+    }
+    /// Get all rescan tasks, with their progress. Fails if rescan tasks aren't persisted.
+    ///
+    /// See [`rescan`].
+    fn rescans(&self) -> Result<Vec<rescan::Task>, Error>;
     /// Update the watchlist with the provided scripts.
     ///
     /// Note that this won't trigger a rescan of any existing blocks. To avoid
@@ -122,6 +308,214 @@ pub trait Handle: Sized + Send + Sync + Clone {
 
         Ok(())
     }
+    /// Get a snapshot of all connected peers' state, eg. their traffic, latency and
+    /// misbehavior score.
+    fn query_peers(&self) -> Result<Vec<PeerInfo>, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::QueryPeers(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+    /// Get the proof-of-work of the active chain and of known forks, along with the best
+    /// height advertised by peers. Useful to detect when the node is on a minority fork,
+    /// or behind the network.
+    fn get_chain_work(&self) -> Result<ChainWork, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetChainWork(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+    /// Get the height of the filter header chain.
+    fn get_filter_tip(&self) -> Result<Height, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetFilterTip(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+    /// Ban an address for the given duration, or permanently if no duration is given.
+    /// Existing connections to the address are dropped. Bans are persisted across restarts.
+    fn ban(&self, addr: net::IpAddr, duration: Option<LocalDuration>) -> Result<(), Error> {
+        self.command(Command::Ban(addr, duration))
+    }
+    /// Lift the ban on an address.
+    fn unban(&self, addr: net::IpAddr) -> Result<(), Error> {
+        self.command(Command::Unban(addr))
+    }
+    /// Get the list of banned addresses.
+    fn list_bans(&self) -> Result<Vec<Ban>, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::ListBans(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+    /// Set the log levels per target at runtime, eg. `nakamoto_p2p::protocol::cbfmgr=trace`
+    /// to turn on trace logging for the filter manager while diagnosing a sync issue.
+    ///
+    /// See [`protocol::log_filter`] for the syntax.
+    fn set_log_filter(&self, filter: &str) -> Result<(), Error> {
+        filter.parse::<LogFilter>()?;
+        self.command(Command::SetLogFilter(filter.to_owned()))
+    }
+    /// Update the client configuration at runtime, without restarting it.
+    ///
+    /// A [`protocol::Event::ConfigUpdated`] event is emitted once the update is applied.
+    fn set_config(&self, update: ConfigUpdate) -> Result<(), Error> {
+        self.command(Command::SetConfig(update))
+    }
+    /// Revalidate the block header and filter header chains from the given height, in the
+    /// background, repairing or truncating them where they are found to be corrupt. Use `0`
+    /// to reindex from genesis, or a checkpoint height to skip the blocks before it.
+    ///
+    /// Progress is reported with [`protocol::Event::Reindex`] events.
+    fn reindex(&self, from: Height) -> Result<(), Error> {
+        self.command(Command::Reindex(from))
+    }
+    /// Write a JSON snapshot of the internal protocol state to the file at the given path,
+    /// eg. to attach to a report of a stuck sync. Peer addresses are replaced with
+    /// pseudonyms, and watched scripts and transactions are only counted.
+    fn dump_state(&self, path: PathBuf) -> Result<(), Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::DumpState(transmit))?;
+
+        // The file is written here rather than by the protocol, so as not to block it.
+        std::fs::write(path, receive.recv()?)?;
+
+        Ok(())
+    }
+    /// Estimate the birth height of a wallet, given its birthday as a UNIX timestamp, using
+    /// the block header chain. The estimate is conservative, ie. the returned height is
+    /// before any block that could have been mined after the birthday, provided our
+    /// header chain is synced past it.
+    ///
+    /// See [`BlockReader::find_height_by_time`].
+    fn birth_height(&self, birthday: BlockTime) -> Result<Height, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.query_tree(move |tree| {
+            transmit.send(tree.find_height_by_time(birthday)).ok();
+        })?;
+
+        Ok(receive.recv()?)
+    }
+    /// Compute statistics of the given range of the active chain, per difficulty epoch:
+    /// difficulty, average block interval and estimated hashrate. Returns `None` if the
+    /// range starts above the tip. See [`stats`](crate::stats).
+    fn chain_stats(&self, range: RangeInclusive<Height>) -> Result<Option<ChainStats>, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.query_tree(move |tree| {
+            transmit.send(ChainStats::compute(tree, range.clone())).ok();
+        })?;
+
+        Ok(receive.recv()?)
+    }
+    /// Check whether a block received in an event is still part of the active chain. Blocks
+    /// disconnected by a re-org are no longer anchored, even if another block was connected
+    /// at the same height.
+    fn is_anchored(&self, anchor: Anchor) -> Result<bool, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.query_tree(move |tree| {
+            transmit.send(tree.is_anchored(&anchor)).ok();
+        })?;
+
+        Ok(receive.recv()?)
+    }
+    /// Find the first block in the given range that uses one of the given scripts, by
+    /// scanning compact filters. This can be used to refine a birth height estimated with
+    /// [`Handle::birth_height`], since there is no need to scan blocks before a wallet's
+    /// first use. Returns `None` if no filter in the range matched.
+    ///
+    /// The scan is done with a rescan, which replaces any running rescan, and is canceled
+    /// once a match is found. Blocks until the filters for the range are processed.
+    fn first_use(
+        &self,
+        range: RangeInclusive<Height>,
+        watch: impl Iterator<Item = Script>,
+    ) -> Result<Option<Height>, Error> {
+        use protocol::FilterEvent;
+
+        let events = self.events();
+        let id = self.rescan(range, watch)?;
+        // Whether the filter events we receive are part of our rescan.
+        let mut scanning = false;
+
+        let result = loop {
+            match events.recv()? {
+                protocol::Event::Filter(FilterEvent::RescanStarted { id: other, .. }) => {
+                    scanning = other == id;
+                }
+                protocol::Event::Filter(FilterEvent::FilterProcessed {
+                    block,
+                    matched: true,
+                    ..
+                }) if scanning => {
+                    break Some(block.height);
+                }
+                protocol::Event::Filter(
+                    FilterEvent::RescanCompleted { id: other, .. }
+                    | FilterEvent::RescanCanceled { id: other, .. },
+                ) if other == id => {
+                    break None;
+                }
+                _ => {}
+            }
+        };
+        self.cancel_rescan(id)?;
+
+        Ok(result)
+    }
+    /// Export the synced chain state to a snapshot file, which can be imported by
+    /// another client to skip the initial sync. Returns the height of the snapshot.
+    fn export_snapshot(&self, path: &Path) -> Result<Height, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.query_tree(move |tree| {
+            transmit.send(tree.checkpoints()).ok();
+        })?;
+        let checkpoints = receive.recv()?;
+
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::ExportHeaders(transmit))?;
+        let (headers, filter_headers) = receive.recv()?;
+
+        let snapshot = Snapshot {
+            headers,
+            filter_headers,
+            checkpoints,
+        };
+        snapshot.save(path)?;
+
+        Ok(snapshot.height())
+    }
+    /// Import a snapshot file created with [`Handle::export_snapshot`]. The snapshot is
+    /// checked against our network and checkpoints before being imported. Returns the
+    /// height of the snapshot.
+    fn import_snapshot(&self, path: &Path) -> Result<Height, Error> {
+        let snapshot = Snapshot::load(path)?;
+
+        let (transmit, receive) = chan::bounded(1);
+        self.query_tree(move |tree| {
+            transmit
+                .send((tree.genesis().block_hash(), tree.checkpoints()))
+                .ok();
+        })?;
+        let (genesis, checkpoints) = receive.recv()?;
+
+        snapshot.verify(genesis, &checkpoints)?;
+
+        let height = snapshot.height();
+        let Snapshot {
+            headers,
+            filter_headers,
+            ..
+        } = snapshot;
+
+        self.import_headers(headers.into_iter().skip(1).collect())?
+            .map_err(snapshot::Error::from)?;
+
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::ImportFilterHeaders(filter_headers, transmit))?;
+        receive.recv()?.map_err(snapshot::Error::from)?;
+
+        Ok(height)
+    }
     /// Broadcast a message to peers matching the predicate.
     /// To only broadcast to outbound peers, use [`Peer::is_outbound`].
     fn broadcast(
@@ -132,14 +526,92 @@ pub trait Handle: Sized + Send + Sync + Clone {
     /// Send a message to a random *outbound* peer. Return the chosen
     /// peer or nothing if no peer was available.
     fn query(&self, msg: NetworkMessage) -> Result<Option<net::SocketAddr>, Error>;
-    /// Connect to the designated peer address.
-    fn connect(&self, addr: net::SocketAddr) -> Result<Link, Error>;
-    /// Disconnect from the designated peer address.
+    /// Start capturing the raw messages exchanged with peers, appending them to the file
+    /// at the given path, one JSON object per line. Stop capturing with `None`.
+    fn capture(&self, path: Option<PathBuf>) -> Result<(), Error>;
+    /// Connect to the designated peer address. Persistent peers are reconnected to
+    /// whenever they disconnect, like peers added with `addnode` in Bitcoin Core, until
+    /// they are disconnected with [`Handle::disconnect`].
+    fn connect(&self, addr: net::SocketAddr, persistent: bool) -> Result<Link, Error>;
+    /// Connect to a peer reachable at any of the given addresses, eg. the addresses a
+    /// host name resolves to. Addresses are dialed in parallel with staggered starts, and
+    /// the first connection established is kept. Returns the address connected to.
+    fn connect_any(&self, addrs: &[net::SocketAddr]) -> Result<(net::SocketAddr, Link), Error>;
+    /// Disconnect from the designated peer address. Persistent peers aren't reconnected
+    /// to anymore.
     fn disconnect(&self, addr: net::SocketAddr) -> Result<(), Error>;
+    /// Get our persistent peers, ie. the peers we were configured to connect to, and the
+    /// peers added with [`Handle::connect`].
+    fn get_added_peers(&self) -> Result<Vec<net::SocketAddr>, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetAddedPeers(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+    /// Enable or disable network activity, eg. when the device enters airplane mode.
+    /// While disabled, all peers are disconnected and no connections are made or accepted.
+    /// The block and filter stores are left intact, and syncing resumes once network
+    /// activity is enabled again.
+    fn set_network_active(&self, active: bool) -> Result<(), Error> {
+        self.command(Command::SetNetworkActive(active))
+    }
+    /// Sync for the given duration, then disconnect from all peers and suspend timers until
+    /// the next burst, eg. when a mobile app is woken up by the OS for background work.
+    /// A [`protocol::Event::Quiesced`] event is emitted when the burst ends.
+    fn sync_burst(&self, duration: LocalDuration) -> Result<(), Error> {
+        self.command(Command::SyncBurst(duration))
+    }
+    /// Enable or disable low-data mode, eg. when the device switches between metered and
+    /// unmetered connectivity. In low-data mode, only block headers are synced: compact
+    /// filter and block downloads are deferred until low-data mode is disabled.
+    fn set_low_data(&self, enabled: bool) -> Result<(), Error> {
+        self.command(Command::SetLowData(enabled))
+    }
+    /// Get the bandwidth used since startup, in normal operation and in low-data mode.
+    fn get_bandwidth(&self) -> Result<Bandwidth, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetBandwidth(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+    /// Get the number and size of the messages exchanged with peers since startup, and
+    /// when the last one was, per command, eg. to find out what dominates bandwidth during sync.
+    fn get_message_stats(&self) -> Result<MessageStats, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetMessageStats(transmit))?;
+
+        Ok(receive.recv()?)
+    }
     /// Submit a transaction to the network.
     ///
     /// Returns the peer(s) the transaction was announced to, or an error if no peers were found.
     fn submit_transaction(&self, tx: Transaction) -> Result<NonEmpty<net::SocketAddr>, Error>;
+    /// Replace a submitted, unconfirmed transaction with a conflicting transaction, eg. to
+    /// bump its fee. Both transactions are tracked until either is confirmed, at which point
+    /// the other is reported as [`TxStatus::Stale`](crate::spv::TxStatus::Stale).
+    ///
+    /// Returns the peer(s) the replacement was announced to.
+    fn replace_transaction(
+        &self,
+        txid: Txid,
+        replacement: Transaction,
+    ) -> Result<NonEmpty<net::SocketAddr>, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::ReplaceTransaction(txid, replacement, transmit))?;
+
+        Ok(receive.recv()??)
+    }
+    /// Submit a package of dependent transactions to the network, eg. a parent and a child
+    /// paying for it. Parents are always announced and served before their children, and
+    /// the package is re-announced as a unit.
+    ///
+    /// Returns the status of each transaction, in announcement order.
+    fn submit_package(&self, txs: Vec<Transaction>) -> Result<Vec<(Txid, TxStatus)>, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::SubmitPackage(txs, transmit))?;
+
+        Ok(receive.recv()??)
+    }
     /// Import block headers into the node.
     /// This may cause the node to broadcast header or inventory messages to its peers.
     fn import_headers(
@@ -148,6 +620,11 @@ pub trait Handle: Sized + Send + Sync + Clone {
     ) -> Result<Result<ImportResult, block::tree::Error>, Error>;
     /// Import peer addresses into the node's address book.
     fn import_addresses(&self, addrs: Vec<Address>) -> Result<(), Error>;
+    /// Add addresses resolved from DNS seeds to the node's address book. Unlike with
+    /// [`Handle::import_addresses`], the services of these addresses aren't known.
+    fn import_seeds(&self, addrs: Vec<net::SocketAddr>) -> Result<(), Error> {
+        self.command(Command::ImportSeeds(addrs))
+    }
     /// Wait for the given predicate to be fulfilled.
     fn wait<F: FnMut(protocol::Event) -> Option<T>, T>(&self, f: F) -> Result<T, Error>;
     /// Wait for a given number of peers to be connected with the given services.
@@ -156,11 +633,34 @@ pub trait Handle: Sized + Send + Sync + Clone {
         count: usize,
         required_services: impl Into<ServiceFlags>,
     ) -> Result<Vec<(net::SocketAddr, Height, ServiceFlags)>, Error>;
-    /// Wait for the node's active chain to reach a certain height. The hash at that height
-    /// is returned.
-    fn wait_for_height(&self, h: Height) -> Result<BlockHash, Error>;
+    /// Wait for the node's active chain to reach a certain height, or for the timeout to
+    /// elapse. The hash at that height is returned.
+    fn wait_for_height(&self, h: Height, timeout: time::Duration) -> Result<BlockHash, Error>;
+    /// Wait for the node's filter header chain to reach a certain height, or for the timeout
+    /// to elapse. The filter header chain height is returned.
+    fn wait_for_filters(&self, h: Height, timeout: time::Duration) -> Result<Height, Error>;
+    /// Wait for a transaction to be confirmed with the given number of confirmations, or for
+    /// the timeout to elapse. A `depth` of one is reached as soon as the transaction is
+    /// included in a block. Re-orgs reverting the transaction reset the count. The height and
+    /// hash of the confirming block are returned. Fails if `depth` is zero.
+    ///
+    /// The transaction must have been submitted with [`Handle::submit_transaction`], or pay
+    /// to a watched script. Transactions confirmed before the call are only found if they
+    /// were submitted, and were confirmed recently.
+    fn wait_for_confirmation(
+        &self,
+        txid: Txid,
+        depth: Height,
+        timeout: time::Duration,
+    ) -> Result<(Height, BlockHash), Error>;
     /// Listen on events.
     fn events(&self) -> chan::Receiver<protocol::Event>;
+    /// Get the journaled events starting from the given sequence number, to catch up
+    /// after a crash. Fails if the client wasn't configured with an event journal, or if
+    /// the entries were compacted, in which case the consumer should rescan.
+    ///
+    /// See [`journal`].
+    fn replay_from(&self, seq: u64) -> Result<Vec<journal::Entry>, Error>;
     /// Shutdown the node process.
     fn shutdown(self) -> Result<(), Error>;
 }
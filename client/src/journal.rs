@@ -0,0 +1,467 @@
+//! Append-only journal of client events.
+//!
+//! When enabled, high-level chain and transaction events are appended to a journal file,
+//! each with a sequence number. Downstream consumers, eg. wallet databases, can record the
+//! sequence number of the last event they processed, and after a crash, catch up with
+//! [`Handle::replay_from`](crate::handle::Handle::replay_from) instead of rescanning the
+//! chain.
+//!
+//! Each entry is stored as its sequence number, followed by the consensus-encoded event.
+//! If the client stops in the middle of a write, the truncated entry is discarded the next
+//! time the journal is opened.
+//!
+//! The journal only keeps the latest [`MAX_ENTRIES`] entries: older entries are dropped
+//! when the journal is opened, and while it is in use, once it holds twice as many.
+//! Consumers that fall further behind have to rescan.
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+
+use nakamoto_chain::block::store::Fsync;
+use nakamoto_common::bitcoin::consensus::encode::{self, Decodable, Encodable};
+use nakamoto_common::bitcoin::Txid;
+use nakamoto_common::block::{BlockHash, BlockHeader, Height};
+use nakamoto_common::error::{self, Classify};
+use nakamoto_p2p::protocol;
+
+/// Maximum number of entries kept in a journal.
+pub const MAX_ENTRIES: usize = 100_000;
+
+/// A journal error.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// An I/O error.
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    /// A journal entry could not be decoded.
+    #[error("decoding error: {0}")]
+    Decode(#[from] encode::Error),
+    /// The journal is not enabled.
+    #[error("the event journal is not enabled")]
+    Disabled,
+    /// The entries asked for were dropped from the journal. The oldest entry left has the
+    /// given sequence number.
+    #[error("journal entries before {0} were compacted")]
+    Compacted(u64),
+}
+
+impl Classify for Error {
+    fn code(&self) -> u32 {
+        match self {
+            Self::Io(_) => 2006,
+            Self::Decode(_) => 2014,
+            Self::Disabled => 4017,
+            Self::Compacted(_) => 2023,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Io(err) => error::is_transient_io(err),
+            Self::Decode(_) | Self::Disabled | Self::Compacted(_) => false,
+        }
+    }
+}
+
+/// A journaled event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A block was added to the main chain.
+    BlockConnected {
+        /// Block header.
+        header: BlockHeader,
+        /// Height of the block.
+        height: Height,
+    },
+    /// A block was reverted from the main chain, due to a re-org.
+    BlockDisconnected {
+        /// Block header.
+        header: BlockHeader,
+        /// Height of the block when it was part of the main chain.
+        height: Height,
+    },
+    /// A transaction was confirmed.
+    TxConfirmed {
+        /// The transaction ID.
+        txid: Txid,
+        /// The block in which it was confirmed.
+        block: BlockHash,
+        /// The height at which it was confirmed.
+        height: Height,
+    },
+}
+
+impl Event {
+    /// Get the journaled event corresponding to a protocol event, if any.
+    pub fn from_protocol(event: &protocol::Event) -> Option<Self> {
+        match event {
+            protocol::Event::Chain(protocol::ChainEvent::BlockConnected { header, height }) => {
+                Some(Self::BlockConnected {
+                    header: *header,
+                    height: *height,
+                })
+            }
+            protocol::Event::Chain(protocol::ChainEvent::BlockDisconnected { header, height }) => {
+                Some(Self::BlockDisconnected {
+                    header: *header,
+                    height: *height,
+                })
+            }
+            protocol::Event::Inventory(protocol::InventoryEvent::Confirmed {
+                transaction,
+                block,
+            }) => Some(Self::TxConfirmed {
+                txid: transaction.txid(),
+                block: block.hash,
+                height: block.height,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Encodable for Event {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        match self {
+            Self::BlockConnected { header, height } => {
+                let mut len = 0u8.consensus_encode(&mut w)?;
+                len += header.consensus_encode(&mut w)?;
+                len += height.consensus_encode(&mut w)?;
+                Ok(len)
+            }
+            Self::BlockDisconnected { header, height } => {
+                let mut len = 1u8.consensus_encode(&mut w)?;
+                len += header.consensus_encode(&mut w)?;
+                len += height.consensus_encode(&mut w)?;
+                Ok(len)
+            }
+            Self::TxConfirmed {
+                txid,
+                block,
+                height,
+            } => {
+                let mut len = 2u8.consensus_encode(&mut w)?;
+                len += txid.consensus_encode(&mut w)?;
+                len += block.consensus_encode(&mut w)?;
+                len += height.consensus_encode(&mut w)?;
+                Ok(len)
+            }
+        }
+    }
+}
+
+impl Decodable for Event {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        match u8::consensus_decode(&mut d)? {
+            0 => Ok(Self::BlockConnected {
+                header: BlockHeader::consensus_decode(&mut d)?,
+                height: Height::consensus_decode(&mut d)?,
+            }),
+            1 => Ok(Self::BlockDisconnected {
+                header: BlockHeader::consensus_decode(&mut d)?,
+                height: Height::consensus_decode(&mut d)?,
+            }),
+            2 => Ok(Self::TxConfirmed {
+                txid: Txid::consensus_decode(&mut d)?,
+                block: BlockHash::consensus_decode(&mut d)?,
+                height: Height::consensus_decode(&mut d)?,
+            }),
+            _ => Err(encode::Error::ParseFailed("unknown journal event")),
+        }
+    }
+}
+
+/// A journal entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Sequence number of the entry. Sequence numbers start at zero and increase by
+    /// one with every entry.
+    pub seq: u64,
+    /// The journaled event.
+    pub event: Event,
+}
+
+impl Encodable for Entry {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = self.seq.consensus_encode(&mut w)?;
+        len += self.event.consensus_encode(&mut w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for Entry {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        Ok(Self {
+            seq: u64::consensus_decode(&mut d)?,
+            event: Event::consensus_decode(&mut d)?,
+        })
+    }
+}
+
+/// An append-only event journal, backed by a single file.
+#[derive(Debug)]
+pub struct Journal {
+    file: fs::File,
+    path: PathBuf,
+    next: u64,
+    fsync: Fsync,
+    /// Number of entries in the journal.
+    len: usize,
+    /// Maximum number of entries kept when compacting.
+    limit: usize,
+}
+
+impl Journal {
+    /// Open the journal at the given path, creating it if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>, fsync: Fsync) -> Result<Self, Error> {
+        Self::with_limit(path, fsync, MAX_ENTRIES)
+    }
+
+    /// Open the journal at the given path, keeping at most `limit` entries.
+    pub fn with_limit(path: impl AsRef<Path>, fsync: Fsync, limit: usize) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        let (entries, len) = self::read(&fs::read(&path)?)?;
+
+        if len < file.metadata()?.len() {
+            log::warn!(
+                "Discarding truncated entry at the end of event journal {:?}",
+                path
+            );
+            file.set_len(len)?;
+        }
+        let next = entries.last().map(|e| e.seq + 1).unwrap_or_default();
+        let mut journal = Self {
+            file,
+            path,
+            next,
+            fsync,
+            len: entries.len(),
+            limit,
+        };
+
+        if journal.len > limit {
+            journal.compact(entries)?;
+        }
+        Ok(journal)
+    }
+
+    /// Sequence number of the next entry.
+    pub fn next_seq(&self) -> u64 {
+        self.next
+    }
+
+    /// Append an event to the journal. Returns the sequence number of the new entry.
+    pub fn append(&mut self, event: Event) -> Result<u64, Error> {
+        let seq = self.next;
+        let bytes = encode::serialize(&Entry { seq, event });
+
+        self.file.write_all(&bytes)?;
+        if self.fsync == Fsync::Always {
+            self.file.sync_data()?;
+        }
+        self.next += 1;
+        self.len += 1;
+
+        // Nb. Compacting rewrites the whole journal, so we let it grow to twice its limit
+        // before doing so.
+        if self.len >= self.limit.saturating_mul(2) {
+            let (entries, _) = self::read(&fs::read(&self.path)?)?;
+            self.compact(entries)?;
+        }
+        Ok(seq)
+    }
+
+    /// Get all entries with a sequence number greater than or equal to the given one.
+    /// Fails if some of these entries were compacted.
+    pub fn replay_from(&self, seq: u64) -> Result<Vec<Entry>, Error> {
+        let (entries, _) = self::read(&fs::read(&self.path)?)?;
+
+        match entries.first() {
+            Some(first) if seq < first.seq => Err(Error::Compacted(first.seq)),
+            _ => Ok(entries.into_iter().filter(|e| e.seq >= seq).collect()),
+        }
+    }
+
+    /// Rewrite the journal with the latest entries only. The journal is replaced
+    /// atomically, so that a crash never leaves it half-written.
+    /// See [`nakamoto_common::fs::atomic_write`].
+    fn compact(&mut self, entries: Vec<Entry>) -> Result<(), Error> {
+        let dropped = entries.len().saturating_sub(self.limit);
+        let mut bytes = Vec::new();
+
+        for entry in &entries[dropped..] {
+            entry.consensus_encode(&mut bytes)?;
+        }
+        nakamoto_common::fs::atomic_write(&self.path, &bytes)?;
+
+        self.file = fs::OpenOptions::new().append(true).open(&self.path)?;
+        self.len = entries.len() - dropped;
+
+        log::debug!(
+            "Compacted event journal {:?}: dropped {} entries",
+            self.path,
+            dropped
+        );
+
+        Ok(())
+    }
+}
+
+/// Decode journal entries. Returns the entries and the length of the bytes decoded,
+/// which is shorter than the input if the last entry is truncated.
+fn read(bytes: &[u8]) -> Result<(Vec<Entry>, u64), Error> {
+    let mut cursor = io::Cursor::new(bytes);
+    let mut entries = Vec::new();
+    let mut len = 0;
+
+    while len < bytes.len() as u64 {
+        match Entry::consensus_decode(&mut cursor) {
+            Ok(entry) => {
+                entries.push(entry);
+                len = cursor.position();
+            }
+            Err(encode::Error::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok((entries, len))
+}
+
+/// An event journal shared between the client's event publisher and its handles.
+/// Events are only journaled once a [`Journal`] is set.
+#[derive(Debug, Clone, Default)]
+pub struct Shared(Arc<Mutex<Option<Journal>>>);
+
+impl Shared {
+    /// Set the journal to write to.
+    pub fn set(&self, journal: Journal) {
+        *self.0.lock().unwrap() = Some(journal);
+    }
+
+    /// Get all entries with a sequence number greater than or equal to the given one.
+    pub fn replay_from(&self, seq: u64) -> Result<Vec<Entry>, Error> {
+        self.0
+            .lock()
+            .unwrap()
+            .as_ref()
+            .ok_or(Error::Disabled)?
+            .replay_from(seq)
+    }
+}
+
+impl protocol::event::Publisher for Shared {
+    fn publish(&mut self, event: protocol::Event) {
+        if let Some(event) = Event::from_protocol(&event) {
+            if let Some(journal) = self.0.lock().unwrap().as_mut() {
+                if let Err(err) = journal.append(event) {
+                    log::error!("Failed to append to event journal: {}", err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use nakamoto_common::bitcoin_hashes::Hash;
+    use nakamoto_test::BITCOIN_HEADERS;
+
+    #[test]
+    fn test_append_replay() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("events.journal");
+        let headers = &BITCOIN_HEADERS.tail;
+
+        let mut journal = Journal::open(&path, Fsync::Manual).unwrap();
+        for (i, header) in headers.iter().take(3).enumerate() {
+            let height = i as Height + 1;
+            let seq = journal
+                .append(Event::BlockConnected {
+                    header: *header,
+                    height,
+                })
+                .unwrap();
+            assert_eq!(seq, i as u64);
+        }
+        journal
+            .append(Event::TxConfirmed {
+                txid: Txid::from_inner([0; 32]),
+                block: headers[2].block_hash(),
+                height: 3,
+            })
+            .unwrap();
+
+        let entries = journal.replay_from(2).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].seq, 2);
+        assert!(matches!(
+            entries[1].event,
+            Event::TxConfirmed { height: 3, .. }
+        ));
+
+        // Simulate a crash in the middle of a write.
+        drop(journal);
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&5u64.to_le_bytes())
+            .unwrap();
+
+        let mut journal = Journal::open(&path, Fsync::Manual).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(journal.next_seq(), 4);
+        assert_eq!(journal.replay_from(0).unwrap().len(), 4);
+
+        journal
+            .append(Event::BlockDisconnected {
+                header: headers[2],
+                height: 3,
+            })
+            .unwrap();
+        assert_eq!(journal.replay_from(4).unwrap()[0].seq, 4);
+    }
+
+    #[test]
+    fn test_compact() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("events.journal");
+        let headers = &BITCOIN_HEADERS.tail;
+        let event = |height: Height| Event::BlockConnected {
+            header: headers[height as usize],
+            height,
+        };
+
+        let mut journal = Journal::with_limit(&path, Fsync::Manual, 2).unwrap();
+        for height in 0..3 {
+            journal.append(event(height)).unwrap();
+        }
+        assert_eq!(journal.replay_from(0).unwrap().len(), 3);
+
+        // The journal is compacted once it holds twice as many entries as its limit.
+        journal.append(event(3)).unwrap();
+        assert!(matches!(journal.replay_from(0), Err(Error::Compacted(2))));
+        assert_eq!(journal.replay_from(2).unwrap().len(), 2);
+
+        journal.append(event(4)).unwrap();
+        assert_eq!(journal.replay_from(2).unwrap().len(), 3);
+        drop(journal);
+
+        // It is also compacted when opened, and sequence numbers carry on.
+        let mut journal = Journal::with_limit(&path, Fsync::Manual, 1).unwrap();
+        assert!(matches!(journal.replay_from(2), Err(Error::Compacted(4))));
+        assert_eq!(journal.replay_from(4).unwrap()[0].seq, 4);
+        assert_eq!(journal.append(event(5)).unwrap(), 5);
+    }
+}
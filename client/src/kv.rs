@@ -0,0 +1,248 @@
+//! Namespaced key-value store for client metadata.
+//!
+//! Subsystems that need to persist a handful of values, eg. the ban list, rescan tasks or
+//! the address book, get their own [`Namespace`] in a single store file, instead of each
+//! inventing a file format. The whole store is kept in memory and rewritten on every write,
+//! so it is only meant for small amounts of data.
+//!
+//! Data that grows with the chain or is appended to often keeps its own store: block and
+//! filter headers, the event [`journal`](crate::journal), and wallets, whose store is
+//! managed by the wallet itself. Transactions pending broadcast are not persisted.
+//!
+//! Writes are atomic: the store is written to a temporary file which then replaces the
+//! previous one, so that a crash never leaves it half-written. Several writes to a
+//! namespace can be grouped with a [`Batch`], to be applied all at once or not at all.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{fs, io};
+
+use nakamoto_common::bitcoin::consensus::encode::{self, Decodable, Encodable, VarInt};
+
+/// Key-value store, backed by a single file. Cloning the store is cheap, and clones share
+/// the same underlying data.
+#[derive(Debug, Clone)]
+pub struct Store {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    path: PathBuf,
+    entries: BTreeMap<(String, String), Vec<u8>>,
+}
+
+impl Inner {
+    fn save(&self) -> io::Result<()> {
+        let mut bytes = Vec::new();
+
+        VarInt(self.entries.len() as u64).consensus_encode(&mut bytes)?;
+        for ((namespace, key), value) in &self.entries {
+            namespace.consensus_encode(&mut bytes)?;
+            key.consensus_encode(&mut bytes)?;
+            value.consensus_encode(&mut bytes)?;
+        }
+        nakamoto_common::fs::atomic_write(&self.path, &bytes)
+    }
+}
+
+impl Store {
+    /// Open the store at the given path. Returns an empty store if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut entries = BTreeMap::new();
+
+        match fs::read(&path) {
+            Ok(bytes) => {
+                let mut cursor = io::Cursor::new(bytes);
+                let invalid = |e: encode::Error| io::Error::new(io::ErrorKind::InvalidData, e);
+
+                for _ in 0..VarInt::consensus_decode(&mut cursor).map_err(invalid)?.0 {
+                    let namespace = String::consensus_decode(&mut cursor).map_err(invalid)?;
+                    let key = String::consensus_decode(&mut cursor).map_err(invalid)?;
+                    let value = Vec::<u8>::consensus_decode(&mut cursor).map_err(invalid)?;
+
+                    entries.insert((namespace, key), value);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Inner { path, entries })),
+        })
+    }
+
+    /// Get a namespace of the store.
+    pub fn namespace(&self, name: impl Into<String>) -> Namespace {
+        Namespace {
+            name: name.into(),
+            store: self.clone(),
+        }
+    }
+}
+
+/// A namespace of a [`Store`]. Keys of different namespaces never collide.
+#[derive(Debug, Clone)]
+pub struct Namespace {
+    name: String,
+    store: Store,
+}
+
+impl Namespace {
+    /// Get the value of a key.
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let inner = self.store.inner.lock().unwrap();
+
+        inner
+            .entries
+            .get(&(self.name.clone(), key.to_owned()))
+            .cloned()
+    }
+
+    /// Get all entries of the namespace, ordered by key.
+    pub fn entries(&self) -> Vec<(String, Vec<u8>)> {
+        let inner = self.store.inner.lock().unwrap();
+
+        inner
+            .entries
+            .iter()
+            .filter(|((ns, _), _)| *ns == self.name)
+            .map(|((_, key), value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Set the value of a key, and save.
+    pub fn put(&self, key: impl Into<String>, value: impl Into<Vec<u8>>) -> io::Result<()> {
+        self.batch().put(key, value).commit()
+    }
+
+    /// Remove a key, and save. Returns `false` if the key wasn't set.
+    pub fn remove(&self, key: &str) -> io::Result<bool> {
+        if self.get(key).is_none() {
+            return Ok(false);
+        }
+        self.batch().remove(key).commit()?;
+
+        Ok(true)
+    }
+
+    /// Start a batch of writes to this namespace.
+    pub fn batch(&self) -> Batch<'_> {
+        Batch {
+            namespace: self,
+            clear: false,
+            ops: Vec::new(),
+        }
+    }
+}
+
+/// A batch of writes to a namespace, which are saved atomically on [`Batch::commit`].
+#[derive(Debug)]
+#[must_use = "batches must be committed"]
+pub struct Batch<'a> {
+    namespace: &'a Namespace,
+    clear: bool,
+    ops: Vec<(String, Option<Vec<u8>>)>,
+}
+
+impl Batch<'_> {
+    /// Set the value of a key.
+    pub fn put(mut self, key: impl Into<String>, value: impl Into<Vec<u8>>) -> Self {
+        self.ops.push((key.into(), Some(value.into())));
+        self
+    }
+
+    /// Remove a key.
+    pub fn remove(mut self, key: impl Into<String>) -> Self {
+        self.ops.push((key.into(), None));
+        self
+    }
+
+    /// Remove all keys of the namespace, before applying the other writes.
+    pub fn clear(mut self) -> Self {
+        self.clear = true;
+        self
+    }
+
+    /// Apply the writes and save the store. If saving fails, none of the writes are applied.
+    pub fn commit(self) -> io::Result<()> {
+        let name = &self.namespace.name;
+        let mut inner = self.namespace.store.inner.lock().unwrap();
+        let mut entries = inner.entries.clone();
+
+        if self.clear {
+            entries.retain(|(ns, _), _| ns != name);
+        }
+        for (key, value) in self.ops {
+            let key = (name.clone(), key);
+
+            match value {
+                Some(value) => entries.insert(key, value),
+                None => entries.remove(&key),
+            };
+        }
+        let previous = std::mem::replace(&mut inner.entries, entries);
+
+        if let Err(err) = inner.save() {
+            inner.entries = previous;
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_namespaces() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("meta.db");
+        let store = Store::open(&path).unwrap();
+        let alice = store.namespace("alice");
+        let bob = store.namespace("bob");
+
+        alice.put("key", b"alice".to_vec()).unwrap();
+        bob.put("key", b"bob".to_vec()).unwrap();
+        bob.put("other", b"bob".to_vec()).unwrap();
+
+        assert_eq!(alice.get("key"), Some(b"alice".to_vec()));
+        assert!(alice.remove("key").unwrap());
+        assert!(!alice.remove("key").unwrap());
+
+        let loaded = Store::open(&path).unwrap();
+        assert_eq!(loaded.namespace("alice").entries(), vec![]);
+        assert_eq!(loaded.namespace("bob").entries(), bob.entries());
+        assert_eq!(bob.entries().len(), 2);
+    }
+
+    #[test]
+    fn test_batch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("meta.db");
+        let store = Store::open(&path).unwrap();
+        let ns = store.namespace("ns");
+
+        ns.batch()
+            .put("a", vec![1])
+            .put("b", vec![2])
+            .remove("a")
+            .commit()
+            .unwrap();
+        assert_eq!(ns.entries(), vec![("b".to_owned(), vec![2])]);
+
+        ns.batch().clear().put("c", vec![3]).commit().unwrap();
+        assert_eq!(
+            Store::open(&path).unwrap().namespace("ns").entries(),
+            vec![("c".to_owned(), vec![3])]
+        );
+
+        // Failed batches aren't applied.
+        fs::create_dir(path.with_extension("tmp")).unwrap();
+        assert!(ns.put("d", vec![4]).is_err());
+        assert_eq!(ns.get("d"), None);
+    }
+}
@@ -2,14 +2,30 @@
 #![allow(clippy::inconsistent_struct_constructor)]
 #![allow(clippy::type_complexity)]
 #![deny(missing_docs, unsafe_code)]
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod ban;
 pub mod client;
+pub mod config;
+pub mod crawl;
 pub mod error;
 pub mod event;
+pub mod fleet;
 pub mod handle;
+pub mod journal;
+pub mod kv;
+pub mod notify;
+pub mod ots;
 pub mod peer;
+pub mod proof;
+pub mod rescan;
+pub mod set;
+pub mod snapshot;
 pub mod spv;
+pub mod stats;
 
 pub use client::*;
+pub use set::ClientSet;
 
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,343 @@
+//! Notifications of chain events to external processes.
+//!
+//! When enabled, the client listens for TCP connections on the configured address, and
+//! publishes notifications to all connected subscribers, one JSON object per line, so that
+//! processes written in other languages can react to chain events. This is similar to
+//! Bitcoin Core's ZeroMQ interface, with the following topics:
+//!
+//! * `rawblockheader`: a block was connected to the main chain. Includes the block's
+//!   height, hash, parent hash, and hex-encoded header.
+//! * `filtermatch`: a block filter matched the watched scripts. Includes the block's
+//!   height, hash and parent hash.
+//! * `txconfirmed`: a transaction submitted by the client was confirmed. Includes the
+//!   transaction ID, and the height, hash and parent hash of the block.
+//!
+//! Blocks are identified by their height and hash, rather than their height alone, as the
+//! block at a given height can be replaced in a re-org.
+//!
+//! Each notification carries its `topic`, and a `sequence` number that is incremented
+//! with every notification of the same topic, which subscribers can use to detect missed
+//! notifications. Subscribers that don't keep up are disconnected.
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::net;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use microserde::json::{Number, Object, Value};
+
+use nakamoto_common::bitcoin::consensus::encode;
+use nakamoto_common::block::Anchor;
+
+use crate::client::{chan, Event};
+use crate::spv::TxStatus;
+
+/// How long a write to a subscriber may block before it is disconnected.
+pub const WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(3);
+
+/// A notification topic.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Topic {
+    /// A block was connected.
+    RawBlockHeader,
+    /// A block filter matched.
+    FilterMatch,
+    /// A transaction was confirmed.
+    TxConfirmed,
+}
+
+impl Topic {
+    /// All topics.
+    pub const ALL: [Topic; 3] = [Self::RawBlockHeader, Self::FilterMatch, Self::TxConfirmed];
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RawBlockHeader => write!(f, "rawblockheader"),
+            Self::FilterMatch => write!(f, "filtermatch"),
+            Self::TxConfirmed => write!(f, "txconfirmed"),
+        }
+    }
+}
+
+impl FromStr for Topic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|t| t.to_string() == s)
+            .ok_or_else(|| format!("unknown notification topic `{}`", s))
+    }
+}
+
+/// Notification configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Address to listen on for subscribers.
+    pub listen: net::SocketAddr,
+    /// Topics to publish.
+    pub topics: Vec<Topic>,
+}
+
+impl Config {
+    /// Publish all topics to subscribers connecting on the given address.
+    pub fn new(listen: net::SocketAddr) -> Self {
+        Self {
+            listen,
+            topics: Topic::ALL.to_vec(),
+        }
+    }
+}
+
+/// Get the notification corresponding to a client event, if any, without its sequence
+/// number.
+pub fn notification(event: &Event) -> Option<(Topic, Object)> {
+    let string = |s: &dyn fmt::Display| Value::String(s.to_string());
+    let anchor = |a: &Anchor| {
+        [
+            ("height", Value::Number(Number::U64(a.height))),
+            ("hash", string(&a.hash)),
+            ("parent", string(&a.parent)),
+        ]
+    };
+
+    let (topic, fields) = match event {
+        Event::BlockConnected { header, block } => (
+            Topic::RawBlockHeader,
+            anchor(block)
+                .into_iter()
+                .chain([("header", Value::String(encode::serialize_hex(header)))])
+                .collect(),
+        ),
+        Event::FilterProcessed {
+            block,
+            matched: true,
+            valid: true,
+        } => (Topic::FilterMatch, anchor(block).to_vec()),
+        Event::TxStatusChanged {
+            txid,
+            status: TxStatus::Confirmed { block },
+        } => (
+            Topic::TxConfirmed,
+            [("txid", string(txid))]
+                .into_iter()
+                .chain(anchor(block))
+                .collect(),
+        ),
+        _ => return None,
+    };
+    let object = [("topic", string(&topic))]
+        .into_iter()
+        .chain(fields)
+        .map(|(k, v)| (k.to_owned(), v))
+        .collect();
+
+    Some((topic, object))
+}
+
+/// Publishes notifications to subscribers.
+#[derive(Debug)]
+pub struct Notifier {
+    listener: net::TcpListener,
+    topics: Vec<Topic>,
+    subscribers: Arc<Mutex<Vec<net::TcpStream>>>,
+}
+
+impl Notifier {
+    /// Listen for subscribers on the configured address.
+    pub fn bind(config: &Config) -> io::Result<Self> {
+        Ok(Self {
+            listener: net::TcpListener::bind(config.listen)?,
+            topics: config.topics.clone(),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    /// Address subscribers can connect to.
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept subscribers and publish notifications of the given events, in background
+    /// threads. Publishing stops when the event channel disconnects, and subscribers stop
+    /// being accepted when the returned [`Publisher`] is dropped.
+    pub fn spawn(self, events: chan::Receiver<Event>) -> io::Result<Publisher> {
+        let subscribers = self.subscribers.clone();
+        let listener = self.listener;
+        let addr = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let accept = thread::spawn({
+            let stopped = stopped.clone();
+
+            move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    match stream.and_then(|s| s.set_write_timeout(Some(WRITE_TIMEOUT)).map(|_| s)) {
+                        Ok(stream) => {
+                            log::debug!(
+                                "Notification subscriber connected: {:?}",
+                                stream.peer_addr()
+                            );
+                            subscribers.lock().unwrap().push(stream);
+                        }
+                        Err(err) => {
+                            log::error!("Failed to accept notification subscriber: {}", err)
+                        }
+                    }
+                }
+            }
+        });
+
+        let subscribers = self.subscribers;
+        let topics = self.topics;
+
+        thread::spawn(move || {
+            let mut sequences = HashMap::<Topic, u64>::new();
+
+            for event in events {
+                let Some((topic, mut object)) = notification(&event) else {
+                    continue;
+                };
+                if !topics.contains(&topic) {
+                    continue;
+                }
+                let seq = sequences.entry(topic).or_default();
+                object.insert("sequence".to_owned(), Value::Number(Number::U64(*seq)));
+                *seq += 1;
+
+                let line = microserde::json::to_string(&Value::Object(object)) + "\n";
+
+                subscribers.lock().unwrap().retain_mut(|s| {
+                    if let Err(err) = s.write_all(line.as_bytes()) {
+                        log::debug!("Notification subscriber disconnected: {}", err);
+                        return false;
+                    }
+                    true
+                });
+            }
+        });
+
+        Ok(Publisher {
+            addr,
+            stopped,
+            accept: Some(accept),
+        })
+    }
+}
+
+/// A running notifier, returned by [`Notifier::spawn`]. Dropping it stops accepting
+/// subscribers, and waits for the thread accepting them to exit.
+#[derive(Debug)]
+pub struct Publisher {
+    addr: net::SocketAddr,
+    stopped: Arc<AtomicBool>,
+    accept: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        let Some(accept) = self.accept.take() else {
+            return;
+        };
+        self.stopped.store(true, Ordering::SeqCst);
+
+        // Wake up the accept thread, which is blocked waiting for a connection.
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                net::SocketAddr::V4(_) => net::Ipv4Addr::LOCALHOST.into(),
+                net::SocketAddr::V6(_) => net::Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        if let Err(err) = net::TcpStream::connect(addr) {
+            log::error!("Failed to stop accepting notification subscribers: {}", err);
+            return;
+        }
+        accept.join().ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+
+    use nakamoto_common::bitcoin::blockdata::constants;
+    use nakamoto_common::bitcoin::Network;
+
+    #[test]
+    fn test_notifier() {
+        let header = constants::genesis_block(Network::Regtest).header;
+        let anchor = Anchor::new(0, &header);
+        let hash = anchor.hash;
+        let notifier = Notifier::bind(&Config {
+            listen: ([127, 0, 0, 1], 0).into(),
+            topics: vec![Topic::RawBlockHeader, Topic::FilterMatch],
+        })
+        .unwrap();
+        let stream = net::TcpStream::connect(notifier.local_addr().unwrap()).unwrap();
+        let subscribers = notifier.subscribers.clone();
+        let (sender, events) = chan::unbounded();
+
+        let publisher = notifier.spawn(events).unwrap();
+
+        // Wait for the subscriber to be accepted, so that it receives all notifications.
+        while subscribers.lock().unwrap().is_empty() {
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        for event in [
+            Event::BlockConnected {
+                header,
+                block: anchor,
+            },
+            // Not a configured topic.
+            Event::TxStatusChanged {
+                txid: Default::default(),
+                status: TxStatus::Confirmed { block: anchor },
+            },
+            // Not a match.
+            Event::FilterProcessed {
+                block: anchor,
+                matched: false,
+                valid: true,
+            },
+            Event::FilterProcessed {
+                block: anchor,
+                matched: true,
+                valid: true,
+            },
+        ] {
+            sender.send(event).unwrap();
+        }
+
+        let mut lines = BufReader::new(stream).lines();
+        let line = lines.next().unwrap().unwrap();
+        assert!(line.contains(r#""topic":"rawblockheader""#));
+        assert!(line.contains(r#""sequence":0"#));
+        assert!(line.contains(&format!(r#""header":"{}""#, encode::serialize_hex(&header))));
+
+        let line = lines.next().unwrap().unwrap();
+        assert!(line.contains(r#""topic":"filtermatch""#));
+        assert!(line.contains(&format!(r#""hash":"{}""#, hash)));
+        assert!(line.contains(&format!(r#""parent":"{}""#, header.prev_blockhash)));
+        assert!(line.contains(r#""sequence":0"#));
+
+        // Once stopped, new subscribers aren't accepted.
+        let addr = publisher.addr;
+        drop(publisher);
+        assert!(net::TcpStream::connect(addr).is_err());
+
+        assert_eq!("txconfirmed".parse(), Ok(Topic::TxConfirmed));
+        assert!("hashblock".parse::<Topic>().is_err());
+    }
+}
@@ -0,0 +1,549 @@
+//! OpenTimestamps proof verification.
+//!
+//! An OpenTimestamps proof commits the digest of a file to the merkle root of one or more
+//! Bitcoin blocks, through a sequence of hashing and concatenation operations. Since only
+//! block headers are needed to check these commitments, proofs can be verified by a
+//! headers-only client. See [`Handle::verify_ots`](crate::handle::Handle::verify_ots).
+//!
+//! Only detached proofs, ie. `.ots` files, are supported.
+use thiserror::Error;
+
+use nakamoto_common::bitcoin_hashes::hex::ToHex;
+use nakamoto_common::bitcoin_hashes::{ripemd160, sha1, sha256, Hash};
+use nakamoto_common::block::tree::BlockReader;
+use nakamoto_common::block::{BlockHash, BlockTime, Height};
+
+/// Magic bytes identifying a detached proof.
+const MAGIC: &[u8] = b"\x00OpenTimestamps\x00\x00Proof\x00\xbf\x89\xe2\xe8\x84\xe8\x92\x94";
+/// Supported proof format version.
+pub const VERSION: u64 = 1;
+
+/// Tag of Bitcoin block header attestations.
+const BITCOIN_TAG: [u8; 8] = [0x05, 0x88, 0x96, 0x0d, 0x73, 0xd7, 0x19, 0x01];
+/// Tag of pending attestations, which have yet to be upgraded by a calendar server.
+const PENDING_TAG: [u8; 8] = [0x83, 0xdf, 0xe3, 0x0d, 0x2e, 0xf9, 0x0c, 0x8e];
+
+/// Maximum length of a message or operation argument, in bytes.
+const MAX_MSG_LENGTH: usize = 4096;
+/// Maximum length of an attestation payload, in bytes.
+const MAX_PAYLOAD_LENGTH: usize = 8192;
+/// Maximum nesting depth of a timestamp.
+const MAX_DEPTH: usize = 256;
+
+/// An OpenTimestamps error.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The data is not a detached proof.
+    #[error("not an OpenTimestamps proof")]
+    Magic,
+    /// The proof format version is not supported.
+    #[error("unsupported proof version {0}")]
+    Version(u64),
+    /// The proof ended unexpectedly.
+    #[error("unexpected end of proof")]
+    Eof,
+    /// A variable-length integer is invalid.
+    #[error("invalid variable-length integer")]
+    Varint,
+    /// An operation is unknown, or not supported.
+    #[error("unknown or unsupported operation 0x{0:02x}")]
+    Op(u8),
+    /// A message, argument or payload exceeds the maximum length.
+    #[error("message or payload too long")]
+    TooLong,
+    /// The timestamp is nested too deeply.
+    #[error("timestamp is nested too deeply")]
+    TooDeep,
+    /// There is data after the end of the proof.
+    #[error("trailing data after proof")]
+    TrailingData,
+    /// A Bitcoin attestation doesn't match the merkle root of the block at its height.
+    #[error("attestation doesn't match the merkle root of the block at height {0}")]
+    MerkleRootMismatch(Height),
+    /// None of the Bitcoin attestations are in the active chain, eg. because the proof is
+    /// pending, or the chain isn't synced up to the attested height.
+    #[error("no attestation could be verified against the active chain")]
+    Unconfirmed,
+}
+
+/// A timestamp operation, applied to a message to produce the next message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// SHA-1 hash. Only secure when followed by a SHA-256 hash.
+    Sha1,
+    /// RIPEMD-160 hash.
+    Ripemd160,
+    /// SHA-256 hash.
+    Sha256,
+    /// Append the argument to the message.
+    Append(Vec<u8>),
+    /// Prepend the argument to the message.
+    Prepend(Vec<u8>),
+    /// Reverse the message.
+    Reverse,
+    /// Hex-encode the message.
+    Hexlify,
+}
+
+impl Op {
+    /// Apply the operation to a message.
+    pub fn apply(&self, msg: &[u8]) -> Result<Vec<u8>, Error> {
+        let result = match self {
+            Self::Sha1 => sha1::Hash::hash(msg).to_vec(),
+            Self::Ripemd160 => ripemd160::Hash::hash(msg).to_vec(),
+            Self::Sha256 => sha256::Hash::hash(msg).to_vec(),
+            Self::Append(arg) => [msg, arg].concat(),
+            Self::Prepend(arg) => [arg, msg].concat(),
+            Self::Reverse => msg.iter().rev().copied().collect(),
+            Self::Hexlify => msg.to_hex().into_bytes(),
+        };
+        if result.len() > MAX_MSG_LENGTH {
+            return Err(Error::TooLong);
+        }
+        Ok(result)
+    }
+
+    /// Length of the digests produced by the operation, if it's a hash.
+    pub fn digest_len(&self) -> Option<usize> {
+        match self {
+            Self::Sha1 | Self::Ripemd160 => Some(20),
+            Self::Sha256 => Some(32),
+            _ => None,
+        }
+    }
+
+    fn read(tag: u8, reader: &mut Reader) -> Result<Self, Error> {
+        match tag {
+            0x02 => Ok(Self::Sha1),
+            0x03 => Ok(Self::Ripemd160),
+            0x08 => Ok(Self::Sha256),
+            0xf0 => Ok(Self::Append(reader.read_arg()?)),
+            0xf1 => Ok(Self::Prepend(reader.read_arg()?)),
+            0xf2 => Ok(Self::Reverse),
+            0xf3 => Ok(Self::Hexlify),
+            // Includes KECCAK-256, which we have no implementation of.
+            _ => Err(Error::Op(tag)),
+        }
+    }
+}
+
+/// A claim that a message existed at a certain time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Attestation {
+    /// The message is the merkle root of the Bitcoin block at the given height.
+    Bitcoin {
+        /// Block height.
+        height: Height,
+    },
+    /// The message was submitted to a calendar server, and will eventually be committed
+    /// to the blockchain. The proof can then be upgraded from the calendar.
+    Pending {
+        /// Calendar server URI.
+        uri: String,
+    },
+    /// An attestation we don't know about, eg. to a different blockchain.
+    Unknown {
+        /// Attestation tag.
+        tag: [u8; 8],
+        /// Attestation payload.
+        payload: Vec<u8>,
+    },
+}
+
+impl Attestation {
+    fn read(reader: &mut Reader) -> Result<Self, Error> {
+        let mut tag = [0; 8];
+        tag.copy_from_slice(reader.read_bytes(8)?);
+
+        let payload = reader.read_varbytes(MAX_PAYLOAD_LENGTH)?;
+        let mut payload_reader = Reader::new(payload);
+
+        let attestation = match tag {
+            BITCOIN_TAG => Self::Bitcoin {
+                height: payload_reader.read_varuint()?,
+            },
+            PENDING_TAG => Self::Pending {
+                uri: String::from_utf8_lossy(payload_reader.read_varbytes(MAX_PAYLOAD_LENGTH)?)
+                    .into_owned(),
+            },
+            _ => {
+                return Ok(Self::Unknown {
+                    tag,
+                    payload: payload.to_vec(),
+                })
+            }
+        };
+        if !payload_reader.is_empty() {
+            return Err(Error::TrailingData);
+        }
+        Ok(attestation)
+    }
+}
+
+/// A tree of operations leading from a message to attestations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timestamp {
+    /// The message being timestamped.
+    pub msg: Vec<u8>,
+    /// Attestations of the message.
+    pub attestations: Vec<Attestation>,
+    /// Operations on the message, each leading to a timestamp of the result.
+    pub ops: Vec<(Op, Timestamp)>,
+}
+
+impl Timestamp {
+    /// Get all Bitcoin attestations in the tree, along with the message they attest to.
+    pub fn bitcoin_attestations(&self) -> Vec<(Height, &[u8])> {
+        let mut attestations = self
+            .attestations
+            .iter()
+            .filter_map(|a| match a {
+                Attestation::Bitcoin { height } => Some((*height, self.msg.as_slice())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        for (_, timestamp) in &self.ops {
+            attestations.extend(timestamp.bitcoin_attestations());
+        }
+        attestations
+    }
+
+    fn read(reader: &mut Reader, msg: Vec<u8>, depth: usize) -> Result<Self, Error> {
+        if depth > MAX_DEPTH {
+            return Err(Error::TooDeep);
+        }
+        let mut timestamp = Self {
+            msg,
+            attestations: Vec::new(),
+            ops: Vec::new(),
+        };
+
+        // Every branch but the last is prefixed with `0xff`.
+        loop {
+            let (tag, last) = match reader.read_byte()? {
+                0xff => (reader.read_byte()?, false),
+                tag => (tag, true),
+            };
+
+            if tag == 0x00 {
+                timestamp.attestations.push(Attestation::read(reader)?);
+            } else {
+                let op = Op::read(tag, reader)?;
+                let result = op.apply(&timestamp.msg)?;
+
+                timestamp
+                    .ops
+                    .push((op, Self::read(reader, result, depth + 1)?));
+            }
+            if last {
+                return Ok(timestamp);
+            }
+        }
+    }
+}
+
+/// A detached proof, ie. the timestamp of a file's digest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetachedTimestamp {
+    /// Hash operation used to compute the file digest.
+    pub file_op: Op,
+    /// Timestamp of the file digest.
+    pub timestamp: Timestamp,
+}
+
+impl DetachedTimestamp {
+    /// Decode a detached proof, eg. the contents of an `.ots` file.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader::new(bytes);
+
+        if reader.read_bytes(MAGIC.len()).map_err(|_| Error::Magic)? != MAGIC {
+            return Err(Error::Magic);
+        }
+        let version = reader.read_varuint()?;
+        if version != VERSION {
+            return Err(Error::Version(version));
+        }
+
+        let tag = reader.read_byte()?;
+        let file_op = Op::read(tag, &mut reader)?;
+        let len = file_op.digest_len().ok_or(Error::Op(tag))?;
+        let digest = reader.read_bytes(len)?.to_vec();
+        let timestamp = Timestamp::read(&mut reader, digest, 0)?;
+
+        if !reader.is_empty() {
+            return Err(Error::TrailingData);
+        }
+        Ok(Self { file_op, timestamp })
+    }
+
+    /// The file digest. Must be checked against the digest of the timestamped file.
+    pub fn digest(&self) -> &[u8] {
+        &self.timestamp.msg
+    }
+
+    /// Verify the proof's Bitcoin attestations against the given block tree. Returns the
+    /// earliest block the file digest is committed to.
+    ///
+    /// Attestations to heights the tree hasn't reached yet are ignored, but any
+    /// attestation that doesn't match the active chain fails the verification.
+    pub fn verify(&self, tree: &dyn BlockReader) -> Result<Verified, Error> {
+        let mut attestations = self.timestamp.bitcoin_attestations();
+        attestations.sort_by_key(|(height, _)| *height);
+
+        let mut verified = None;
+        for (height, msg) in attestations {
+            let header = match tree.get_block_by_height(height) {
+                Some(header) => header,
+                None => continue,
+            };
+            // Messages are compared with the merkle root in internal byte order.
+            if msg != &header.merkle_root[..] {
+                return Err(Error::MerkleRootMismatch(height));
+            }
+            verified.get_or_insert(Verified {
+                digest: self.digest().to_vec(),
+                height,
+                block: header.block_hash(),
+                time: header.time,
+            });
+        }
+        verified.ok_or(Error::Unconfirmed)
+    }
+}
+
+/// A verified timestamp.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verified {
+    /// The timestamped file digest.
+    pub digest: Vec<u8>,
+    /// Height of the block the digest is committed to.
+    pub height: Height,
+    /// Hash of the block the digest is committed to.
+    pub block: BlockHash,
+    /// Time of the block the digest is committed to. The file existed before this time.
+    pub time: BlockTime,
+}
+
+/// Proof reader.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.data.len() {
+            return Err(Error::Eof);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+
+        Ok(bytes)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    /// Read an unsigned LEB128 integer.
+    fn read_varuint(&mut self) -> Result<u64, Error> {
+        let mut value = 0u64;
+
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_byte()?;
+            let bits = (byte & 0x7f) as u64;
+
+            if bits << shift >> shift != bits {
+                return Err(Error::Varint);
+            }
+            value |= bits << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error::Varint)
+    }
+
+    fn read_varbytes(&mut self, max: usize) -> Result<&'a [u8], Error> {
+        let len = self.read_varuint()?;
+        if len > max as u64 {
+            return Err(Error::TooLong);
+        }
+        self.read_bytes(len as usize)
+    }
+
+    /// Read an operation argument, which can't be empty.
+    fn read_arg(&mut self) -> Result<Vec<u8>, Error> {
+        match self.read_varbytes(MAX_MSG_LENGTH)? {
+            [] => Err(Error::Eof),
+            arg => Ok(arg.to_vec()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use nakamoto_common::bitcoin::blockdata::constants::genesis_block;
+    use nakamoto_common::bitcoin::consensus::serialize;
+    use nakamoto_common::bitcoin::Network;
+    use nakamoto_test::block::cache::model;
+
+    /// Encode a proof header for the given file contents.
+    fn header(file: &[u8]) -> Vec<u8> {
+        [MAGIC, &[VERSION as u8, 0x08], &sha256::Hash::hash(file)[..]].concat()
+    }
+
+    fn bitcoin(height: u8) -> Vec<u8> {
+        [&[0x00][..], &BITCOIN_TAG, &[1, height]].concat()
+    }
+
+    fn pending(uri: &str) -> Vec<u8> {
+        let payload = [&[uri.len() as u8][..], uri.as_bytes()].concat();
+        [&[0x00][..], &PENDING_TAG, &[payload.len() as u8], &payload].concat()
+    }
+
+    #[test]
+    fn test_verify() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let tree = model::Cache::new(genesis.header);
+        // The genesis block's merkle root is the double-SHA256 of its coinbase.
+        let file = serialize(&genesis.txdata[0]);
+
+        let proof = [header(&file), vec![0x08], bitcoin(0)].concat();
+        let ots = DetachedTimestamp::from_bytes(&proof).unwrap();
+
+        assert_eq!(ots.digest(), &sha256::Hash::hash(&file)[..]);
+        assert_eq!(
+            ots.verify(&tree).unwrap(),
+            Verified {
+                digest: ots.digest().to_vec(),
+                height: 0,
+                block: genesis.block_hash(),
+                time: genesis.header.time,
+            }
+        );
+
+        // Attestations beyond the tip and pending attestations are ignored.
+        let proof = [
+            header(&file),
+            vec![0xff],
+            pending("https://alice.btc.calendar.opentimestamps.org"),
+            vec![0xff, 0x08],
+            bitcoin(1),
+            vec![0x08],
+            bitcoin(0),
+        ]
+        .concat();
+        let ots = DetachedTimestamp::from_bytes(&proof).unwrap();
+
+        assert_eq!(ots.timestamp.attestations.len(), 1);
+        assert_eq!(ots.timestamp.ops.len(), 2);
+        assert_eq!(ots.verify(&tree).unwrap().height, 0);
+
+        // Without the second hash, the attestation doesn't match the merkle root.
+        let proof = [header(&file), bitcoin(0)].concat();
+        assert_eq!(
+            DetachedTimestamp::from_bytes(&proof)
+                .unwrap()
+                .verify(&tree)
+                .unwrap_err(),
+            Error::MerkleRootMismatch(0)
+        );
+
+        let proof = [
+            header(&file),
+            pending("https://finney.calendar.eternitywall.com"),
+        ]
+        .concat();
+        assert_eq!(
+            DetachedTimestamp::from_bytes(&proof)
+                .unwrap()
+                .verify(&tree)
+                .unwrap_err(),
+            Error::Unconfirmed
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let file = b"hello world";
+
+        assert_eq!(
+            DetachedTimestamp::from_bytes(b"hello").unwrap_err(),
+            Error::Magic
+        );
+        assert_eq!(
+            DetachedTimestamp::from_bytes(&header(file)).unwrap_err(),
+            Error::Eof
+        );
+        assert_eq!(
+            DetachedTimestamp::from_bytes(&[header(file), vec![0x67], bitcoin(0)].concat())
+                .unwrap_err(),
+            Error::Op(0x67)
+        );
+        assert_eq!(
+            DetachedTimestamp::from_bytes(&[header(file), bitcoin(0), vec![0]].concat())
+                .unwrap_err(),
+            Error::TrailingData
+        );
+        assert_eq!(
+            DetachedTimestamp::from_bytes(&[MAGIC, &[2][..]].concat()).unwrap_err(),
+            Error::Version(2)
+        );
+
+        // Operations can't be nested indefinitely.
+        let proof = [header(file), vec![0xf2; MAX_DEPTH + 1], bitcoin(0)].concat();
+        assert_eq!(
+            DetachedTimestamp::from_bytes(&proof).unwrap_err(),
+            Error::TooDeep
+        );
+    }
+
+    #[test]
+    fn test_ops() {
+        let msg = b"abc";
+
+        assert_eq!(Op::Append(b"de".to_vec()).apply(msg).unwrap(), b"abcde");
+        assert_eq!(Op::Prepend(b"de".to_vec()).apply(msg).unwrap(), b"deabc");
+        assert_eq!(Op::Reverse.apply(msg).unwrap(), b"cba");
+        assert_eq!(Op::Hexlify.apply(msg).unwrap(), b"616263");
+        assert_eq!(
+            Op::Sha1.apply(msg).unwrap().to_hex(),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            Op::Ripemd160.apply(msg).unwrap().to_hex(),
+            "8eb208f7e05d987a9b044a8e98c6b087f15a0bfc"
+        );
+        assert_eq!(
+            Op::Hexlify.apply(&[0; MAX_MSG_LENGTH]).unwrap_err(),
+            Error::TooLong
+        );
+    }
+
+    #[test]
+    fn test_varuint() {
+        assert_eq!(Reader::new(&[0x00]).read_varuint().unwrap(), 0);
+        assert_eq!(Reader::new(&[0x7f]).read_varuint().unwrap(), 127);
+        assert_eq!(Reader::new(&[0x80, 0x01]).read_varuint().unwrap(), 128);
+        assert_eq!(
+            Reader::new(&[0xe5, 0x8e, 0x26]).read_varuint().unwrap(),
+            624485
+        );
+        assert_eq!(
+            Reader::new(&[0xff; 11]).read_varuint().unwrap_err(),
+            Error::Varint
+        );
+    }
+}
@@ -1,72 +1,80 @@
 //! Client-related peer functionality.
 use std::collections::HashMap;
 use std::path::Path;
+use std::str::FromStr;
 use std::{fs, io, net};
 
+use microserde::json::Value;
+
+use crate::kv;
+
 pub use nakamoto_common::p2p::peer::*;
 
-/// A file-backed implementation of [`Store`].
+/// Address book, backed by a key-value store namespace. Keys are IP addresses, and
+/// values are JSON-encoded known addresses.
 #[derive(Debug)]
 pub struct Cache {
     addrs: HashMap<net::IpAddr, KnownAddress>,
-    file: fs::File,
+    namespace: kv::Namespace,
 }
 
 impl Cache {
-    /// Open an existing cache.
-    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
-        fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(path)
-            .and_then(Self::from)
-    }
-
-    /// Create a new cache.
-    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
-        let file = fs::OpenOptions::new()
-            .create_new(true)
-            .write(true)
-            .open(path)?;
-
-        Ok(Self {
-            file,
-            addrs: HashMap::new(),
-        })
-    }
-
-    /// Create a new cache from a file.
-    pub fn from(mut file: fs::File) -> io::Result<Self> {
-        use io::Read;
-        use microserde::json::Value;
-        use std::str::FromStr;
-
-        let mut s = String::new();
+    /// Open the address book stored in the given namespace.
+    pub fn open(namespace: kv::Namespace) -> io::Result<Self> {
         let mut addrs = HashMap::new();
 
-        file.read_to_string(&mut s)?;
+        for (key, value) in namespace.entries() {
+            let ip = net::IpAddr::from_str(&key).map_err(|_| invalid())?;
+            let s = String::from_utf8(value).map_err(|_| invalid())?;
+            let val = microserde::json::from_str(&s).map_err(|_| invalid())?;
+            let ka = KnownAddress::from_json(val).map_err(|_| invalid())?;
 
-        if !s.is_empty() {
-            let val = microserde::json::from_str(&s)
-                .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
-
-            match val {
-                Value::Object(ary) => {
-                    for (k, v) in ary.into_iter() {
-                        let ka = KnownAddress::from_json(v)
-                            .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
-                        let ip = net::IpAddr::from_str(k.as_str())
-                            .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+            addrs.insert(ip, ka);
+        }
+        Ok(Self { addrs, namespace })
+    }
 
-                        addrs.insert(ip, ka);
-                    }
-                }
-                _ => return Err(io::ErrorKind::InvalidData.into()),
+    /// Import an address book saved as JSON by earlier versions, and remove it. Does
+    /// nothing if the file doesn't exist.
+    pub fn import(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let s = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        if !s.trim().is_empty() {
+            let Value::Object(obj) = microserde::json::from_str(&s).map_err(|_| invalid())? else {
+                return Err(invalid());
+            };
+            for (k, v) in obj.into_iter() {
+                let ka = KnownAddress::from_json(v).map_err(|_| invalid())?;
+                let ip = net::IpAddr::from_str(k.as_str()).map_err(|_| invalid())?;
+
+                self.addrs.insert(ip, ka);
             }
+            self.flush()?;
         }
+        fs::remove_file(path)
+    }
+}
 
-        Ok(Self { file, addrs })
+/// Get the secret key used to place addresses in buckets, stored in the given namespace.
+/// A new key is drawn and stored if there is none yet.
+pub fn bucket_key(namespace: &kv::Namespace, rng: &fastrand::Rng) -> io::Result<[u8; 32]> {
+    if let Some(value) = namespace.get("key") {
+        return value.try_into().map_err(|_| invalid());
     }
+    let mut key = [0; 32];
+    key.iter_mut().for_each(|b| *b = rng.u8(..));
+    namespace.put("key", key)?;
+
+    Ok(key)
+}
+
+fn invalid() -> io::Error {
+    io::Error::from(io::ErrorKind::InvalidData)
 }
 
 impl Store for Cache {
@@ -99,23 +107,13 @@ impl Store for Cache {
     }
 
     fn flush<'a>(&mut self) -> io::Result<()> {
-        use io::{Seek, Write};
-        use microserde::json::Value;
-
-        let peers: microserde::json::Object = self
+        let batch = self
             .addrs
             .iter()
-            .map(|(ip, ka)| (ip.to_string(), ka.to_json()))
-            .collect();
-        let s = microserde::json::to_string(&Value::Object(peers));
-
-        self.file.set_len(0)?;
-        self.file.seek(io::SeekFrom::Start(0))?;
-        self.file.write_all(s.as_bytes())?;
-        self.file.write_all(&[b'\n'])?;
-        self.file.sync_data()?;
-
-        Ok(())
+            .fold(self.namespace.batch().clear(), |b, (ip, ka)| {
+                b.put(ip.to_string(), microserde::json::to_string(&ka.to_json()))
+            });
+        batch.commit()
     }
 }
 
@@ -129,10 +127,8 @@ mod test {
     #[test]
     fn test_empty() {
         let tmp = tempfile::tempdir().unwrap();
-        let path = tmp.path().join("cache");
-
-        Cache::create(&path).unwrap();
-        let cache = Cache::open(&path).unwrap();
+        let store = kv::Store::open(tmp.path().join("meta.db")).unwrap();
+        let cache = Cache::open(store.namespace("peers")).unwrap();
 
         assert!(cache.is_empty());
     }
@@ -140,11 +136,12 @@ mod test {
     #[test]
     fn test_save_and_load() {
         let tmp = tempfile::tempdir().unwrap();
-        let path = tmp.path().join("cache");
+        let path = tmp.path().join("meta.db");
         let mut expected = Vec::new();
 
         {
-            let mut cache = Cache::create(&path).unwrap();
+            let store = kv::Store::open(&path).unwrap();
+            let mut cache = Cache::open(store.namespace("peers")).unwrap();
 
             for i in 32..48 {
                 let ip = net::IpAddr::from([127, 0, 0, i]);
@@ -168,7 +165,8 @@ mod test {
         }
 
         {
-            let cache = Cache::open(&path).unwrap();
+            let store = kv::Store::open(&path).unwrap();
+            let cache = Cache::open(store.namespace("peers")).unwrap();
             let mut actual = cache
                 .iter()
                 .map(|(i, ka)| (*i, ka.clone()))
@@ -180,4 +178,48 @@ mod test {
             assert_eq!(actual, expected);
         }
     }
+
+    #[test]
+    fn test_bucket_key() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("meta.db");
+        let rng = fastrand::Rng::new();
+        let key = bucket_key(&kv::Store::open(&path).unwrap().namespace("addrman"), &rng).unwrap();
+
+        assert_eq!(
+            bucket_key(&kv::Store::open(&path).unwrap().namespace("addrman"), &rng).unwrap(),
+            key
+        );
+    }
+
+    #[test]
+    fn test_import() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("peers.json");
+        let store = kv::Store::open(tmp.path().join("meta.db")).unwrap();
+        let ip = net::IpAddr::from([88, 88, 88, 88]);
+        let ka = KnownAddress::new(
+            Address::new(&(ip, 8333).into(), ServiceFlags::NETWORK),
+            Source::Dns,
+            None,
+        );
+        let json = microserde::json::to_string(&Value::Object(
+            [(ip.to_string(), ka.to_json())].into_iter().collect(),
+        ));
+        fs::write(&path, json).unwrap();
+
+        let mut cache = Cache::open(store.namespace("peers")).unwrap();
+        cache.import(&path).unwrap();
+
+        assert!(!path.exists());
+        assert_eq!(cache.get(&ip), Some(&ka));
+        assert_eq!(
+            Cache::open(store.namespace("peers")).unwrap().get(&ip),
+            Some(&ka)
+        );
+
+        // Importing again does nothing, since the file was removed.
+        cache.import(&path).unwrap();
+        assert_eq!(cache.len(), 1);
+    }
 }
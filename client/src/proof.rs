@@ -0,0 +1,180 @@
+//! SPV proofs of transaction inclusion.
+//!
+//! An SPV proof shows that a transaction was included in a block, and how many blocks were
+//! built on top of it. It can be exported from a client with
+//! [`Handle::get_spv_proof`](crate::handle::Handle::get_spv_proof), and verified by
+//! third parties, eg. sidechains or auditors, without trusting the client.
+//!
+//! The merkle proof is encoded the same way as the output of Bitcoin Core's
+//! `gettxoutproof`, so that it can be used with existing tools.
+use std::io;
+
+use thiserror::Error;
+
+use nakamoto_common::bitcoin::consensus::encode::{self, Decodable, Encodable, VarInt};
+use nakamoto_common::bitcoin::util::merkleblock::{MerkleBlock, MerkleBlockError};
+use nakamoto_common::bitcoin::Txid;
+use nakamoto_common::block::{BlockHeader, Height};
+
+/// An SPV proof error.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The proof could not be decoded.
+    #[error("decoding error: {0}")]
+    Decode(#[from] encode::Error),
+    /// The merkle proof is invalid.
+    #[error("invalid merkle proof: {0:?}")]
+    InvalidMerkleProof(MerkleBlockError),
+    /// The merkle proof doesn't match exactly one transaction.
+    #[error("merkle proof matches {0} transaction(s), expected one")]
+    Matches(usize),
+    /// A block header doesn't connect to the previous one, or has invalid proof-of-work.
+    #[error("invalid block header at height {0}")]
+    InvalidHeader(Height),
+}
+
+/// A proof that a transaction was confirmed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpvProof {
+    /// Height of the block the transaction was confirmed in.
+    pub height: Height,
+    /// Merkle proof of the transaction's inclusion, along with the block header.
+    pub merkle_block: MerkleBlock,
+    /// Headers of the blocks built on top of the confirming block, up to the tip.
+    pub headers: Vec<BlockHeader>,
+}
+
+impl SpvProof {
+    /// Number of confirmations of the transaction.
+    pub fn confirmations(&self) -> Height {
+        self.headers.len() as Height + 1
+    }
+
+    /// Verify the proof. Returns the confirmed transaction.
+    ///
+    /// This checks the merkle proof, and that the headers form a chain with valid
+    /// proof-of-work. It doesn't check that the headers meet the network's difficulty
+    /// target: the verifier should compare them with its own view of the chain.
+    pub fn verify(&self) -> Result<Txid, Error> {
+        let mut matches = Vec::new();
+        let mut indexes = Vec::new();
+
+        self.merkle_block
+            .extract_matches(&mut matches, &mut indexes)
+            .map_err(Error::InvalidMerkleProof)?;
+
+        let txid = match matches.as_slice() {
+            [txid] => *txid,
+            _ => return Err(Error::Matches(matches.len())),
+        };
+
+        let mut prev: Option<&BlockHeader> = None;
+        let chain = std::iter::once(&self.merkle_block.header).chain(&self.headers);
+
+        for (height, header) in (self.height..).zip(chain) {
+            if prev.is_some_and(|p| p.block_hash() != header.prev_blockhash)
+                || header.validate_pow(&header.target()).is_err()
+            {
+                return Err(Error::InvalidHeader(height));
+            }
+            prev = Some(header);
+        }
+        Ok(txid)
+    }
+
+    /// Encode the proof.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode::serialize(self)
+    }
+
+    /// Decode a proof encoded with [`SpvProof::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Ok(encode::deserialize(bytes)?)
+    }
+}
+
+impl Encodable for SpvProof {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = self.height.consensus_encode(&mut w)?;
+        len += self.merkle_block.consensus_encode(&mut w)?;
+        len += VarInt(self.headers.len() as u64).consensus_encode(&mut w)?;
+        for header in &self.headers {
+            len += header.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for SpvProof {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let height = Height::consensus_decode(&mut d)?;
+        let merkle_block = MerkleBlock::consensus_decode(&mut d)?;
+
+        let count = VarInt::consensus_decode(&mut d)?.0;
+        let mut headers = Vec::new();
+        for _ in 0..count {
+            headers.push(BlockHeader::consensus_decode(&mut d)?);
+        }
+
+        Ok(Self {
+            height,
+            merkle_block,
+            headers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use nakamoto_common::network::Network;
+    use nakamoto_test::block::gen;
+
+    fn proof() -> (SpvProof, Txid) {
+        let mut rng = fastrand::Rng::new();
+        let chain = gen::blockchain(Network::Regtest.genesis_block(), 8, &mut rng);
+        let block = &chain.tail[3];
+        let txid = block.txdata[block.txdata.len() - 1].txid();
+
+        let proof = SpvProof {
+            height: 4,
+            merkle_block: MerkleBlock::from_block_with_predicate(block, |t| *t == txid),
+            headers: chain.tail[4..].iter().map(|b| b.header).collect(),
+        };
+        (proof, txid)
+    }
+
+    #[test]
+    fn test_verify() {
+        let (proof, txid) = proof();
+
+        assert_eq!(proof.confirmations(), 5);
+        assert_eq!(proof.verify().unwrap(), txid);
+
+        let mut broken = proof.clone();
+        broken.headers.remove(1);
+        assert!(matches!(broken.verify(), Err(Error::InvalidHeader(6))));
+
+        let mut broken = proof.clone();
+        broken.merkle_block.header.merkle_root = Default::default();
+        assert!(matches!(broken.verify(), Err(Error::InvalidMerkleProof(_))));
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let (proof, txid) = proof();
+        let bytes = proof.to_bytes();
+        // Nb. Decoded merkle proofs are padded to a whole number of bytes, so we don't
+        // compare them directly.
+        let decoded = SpvProof::from_bytes(&bytes).unwrap();
+
+        assert_eq!(decoded.to_bytes(), bytes);
+        assert_eq!(decoded.headers, proof.headers);
+        assert_eq!(decoded.verify().unwrap(), txid);
+        assert!(matches!(
+            SpvProof::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(Error::Decode(_))
+        ));
+    }
+}
@@ -0,0 +1,628 @@
+//! Persistent rescan tasks.
+//!
+//! Rescans started through the client are recorded as tasks, along with their progress,
+//! ie. the height up to which filters and matching blocks were processed. Tasks are saved
+//! to disk, so that a client that is stopped in the middle of a rescan resumes it from
+//! where it left off, instead of restarting from the birth height.
+//!
+//! Progress is saved whenever a matching block is processed, every [`SAVE_INTERVAL`]
+//! filters, and whenever a task is paused, resumed, canceled or completed. Matching blocks
+//! that weren't processed yet are saved along with the task, so that a resumed task
+//! fetches them directly, instead of re-processing all filters from the first of them.
+//! Since a task may be resumed from a slightly earlier height than where it stopped,
+//! processing a block more than once must be harmless to consumers.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+
+use nakamoto_common::bitcoin::consensus::encode::{self, Decodable, Encodable, VarInt};
+use nakamoto_common::bitcoin::Script;
+use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::error::{self, Classify};
+use nakamoto_p2p::protocol::{self, Command, RescanId};
+
+use crate::kv;
+
+/// Number of processed filters after which task progress is saved.
+pub const SAVE_INTERVAL: Height = 1000;
+/// Version of the legacy tasks file format. Files written before versioning start with
+/// the task count, which is never large enough to begin with the `0xff` marker.
+const VERSION: (u8, u8) = (0xff, 1);
+
+/// A rescan task error.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// An I/O error.
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    /// The tasks could not be decoded.
+    #[error("decoding error: {0}")]
+    Decode(#[from] encode::Error),
+    /// Rescan tasks are not persisted.
+    #[error("rescan tasks are not persisted")]
+    Disabled,
+    /// The rescan has completed, was canceled, or never existed, and can't be resumed.
+    #[error("rescan {0} has ended and can't be resumed")]
+    Ended(RescanId),
+}
+
+impl Classify for Error {
+    fn code(&self) -> u32 {
+        match self {
+            Self::Io(_) => 2008,
+            Self::Decode(_) => 2015,
+            Self::Disabled => 4018,
+            Self::Ended(_) => 4026,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Io(err) => error::is_transient_io(err),
+            Self::Decode(_) | Self::Disabled | Self::Ended(_) => false,
+        }
+    }
+}
+
+/// Status of a rescan task.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// The task is running, or will be resumed when the client starts.
+    Running,
+    /// The task is paused.
+    Paused,
+    /// The task has completed.
+    Completed,
+}
+
+/// A rescan task.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Task {
+    /// Task identifier.
+    pub id: RescanId,
+    /// Height from which the rescan started.
+    pub start: Height,
+    /// Height at which the rescan stops, if any.
+    pub end: Option<Height>,
+    /// Scripts to match on.
+    pub watch: Vec<Script>,
+    /// Height from which to resume the rescan. All filters and matching blocks below
+    /// this height were processed.
+    pub current: Height,
+    /// Height after the last processed filter.
+    pub next: Height,
+    /// Matching blocks below [`Task::next`] that weren't processed yet.
+    pub pending: Vec<(Height, BlockHash)>,
+    /// Height of the last filter that matched, if any.
+    pub last_match: Option<Height>,
+    /// Task status.
+    pub status: Status,
+}
+
+impl Task {
+    /// Create a new running task.
+    pub fn new(id: RescanId, start: Height, end: Option<Height>, watch: Vec<Script>) -> Self {
+        Self {
+            id,
+            start,
+            end,
+            watch,
+            current: start,
+            next: start,
+            pending: Vec::new(),
+            last_match: None,
+            status: Status::Running,
+        }
+    }
+
+    /// Get the commands that resume this task: the rescan, from the height after the
+    /// last processed filter, followed by requests for the pending blocks.
+    pub fn resume(&self) -> Vec<Command> {
+        let rescan = Command::Rescan {
+            id: self.id,
+            from: Bound::Included(self.next),
+            to: self.end.map_or(Bound::Unbounded, Bound::Included),
+            watch: self.watch.clone(),
+        };
+        std::iter::once(rescan)
+            .chain(
+                self.pending
+                    .iter()
+                    .map(|(_, hash)| Command::GetBlock(*hash)),
+            )
+            .collect()
+    }
+}
+
+impl Encodable for Task {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = self.id.consensus_encode(&mut w)?;
+        len += self.start.consensus_encode(&mut w)?;
+        len += encode_height(&self.end, &mut w)?;
+        len += VarInt(self.watch.len() as u64).consensus_encode(&mut w)?;
+        for script in &self.watch {
+            len += script.consensus_encode(&mut w)?;
+        }
+        len += self.current.consensus_encode(&mut w)?;
+        len += encode_height(&self.last_match, &mut w)?;
+        len += match self.status {
+            Status::Running => 0u8,
+            Status::Paused => 1u8,
+            Status::Completed => 2u8,
+        }
+        .consensus_encode(&mut w)?;
+        len += self.next.consensus_encode(&mut w)?;
+        len += VarInt(self.pending.len() as u64).consensus_encode(&mut w)?;
+        for (height, hash) in &self.pending {
+            len += height.consensus_encode(&mut w)?;
+            len += hash.consensus_encode(&mut w)?;
+        }
+
+        Ok(len)
+    }
+}
+
+impl Task {
+    /// Decode a task saved before the file format was versioned. These tasks don't
+    /// record their pending blocks.
+    fn decode_v0<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let id = RescanId::consensus_decode(&mut d)?;
+        let start = Height::consensus_decode(&mut d)?;
+        let end = decode_height(&mut d)?;
+        let mut watch = Vec::new();
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            watch.push(Script::consensus_decode(&mut d)?);
+        }
+        let current = Height::consensus_decode(&mut d)?;
+        let last_match = decode_height(&mut d)?;
+        let status = match u8::consensus_decode(&mut d)? {
+            0 => Status::Running,
+            1 => Status::Paused,
+            2 => Status::Completed,
+            _ => return Err(encode::Error::ParseFailed("unknown rescan task status")),
+        };
+
+        Ok(Self {
+            id,
+            start,
+            end,
+            watch,
+            current,
+            next: current,
+            pending: Vec::new(),
+            last_match,
+            status,
+        })
+    }
+}
+
+impl Decodable for Task {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let mut task = Task::decode_v0(&mut d)?;
+
+        task.next = Height::consensus_decode(&mut d)?;
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            let height = Height::consensus_decode(&mut d)?;
+            let hash = BlockHash::consensus_decode(&mut d)?;
+
+            task.pending.push((height, hash));
+        }
+        Ok(task)
+    }
+}
+
+fn encode_height<W: io::Write>(height: &Option<Height>, mut w: W) -> Result<usize, io::Error> {
+    match height {
+        Some(h) => Ok(true.consensus_encode(&mut w)? + h.consensus_encode(&mut w)?),
+        None => false.consensus_encode(&mut w),
+    }
+}
+
+fn decode_height<D: io::Read>(mut d: D) -> Result<Option<Height>, encode::Error> {
+    if bool::consensus_decode(&mut d)? {
+        Ok(Some(Height::consensus_decode(&mut d)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Rescan tasks, backed by a key-value store namespace. Tracks the progress of the
+/// running task by processing protocol events.
+#[derive(Debug)]
+pub struct Tasks {
+    namespace: kv::Namespace,
+    tasks: BTreeMap<RescanId, Task>,
+    /// The task currently running in the protocol, if any.
+    running: Option<RescanId>,
+    /// The height after the last processed filter.
+    next: Height,
+    /// Matched blocks we are awaiting to be processed.
+    pending: BTreeMap<Height, BlockHash>,
+    /// Number of filters processed since the last save.
+    unsaved: Height,
+}
+
+impl Tasks {
+    /// Open the tasks stored in the given namespace.
+    pub fn open(namespace: kv::Namespace) -> Result<Self, Error> {
+        let mut tasks = BTreeMap::new();
+
+        for (_, value) in namespace.entries() {
+            let task: Task = encode::deserialize(&value)?;
+            tasks.insert(task.id, task);
+        }
+
+        Ok(Self {
+            namespace,
+            tasks,
+            running: None,
+            next: 0,
+            pending: BTreeMap::new(),
+            unsaved: 0,
+        })
+    }
+
+    /// Import the tasks saved in a file by earlier versions, and remove the file. Does
+    /// nothing if the file doesn't exist.
+    pub fn import(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        if bytes.first() == Some(&VERSION.0) {
+            let mut cursor = io::Cursor::new(&bytes[2..]);
+
+            if bytes.get(1) != Some(&VERSION.1) {
+                return Err(encode::Error::ParseFailed("unknown rescan tasks version").into());
+            }
+            for _ in 0..VarInt::consensus_decode(&mut cursor)?.0 {
+                let task = Task::consensus_decode(&mut cursor)?;
+                self.tasks.insert(task.id, task);
+            }
+        } else {
+            let mut cursor = io::Cursor::new(bytes);
+
+            for _ in 0..VarInt::consensus_decode(&mut cursor)?.0 {
+                let task = Task::decode_v0(&mut cursor)?;
+                self.tasks.insert(task.id, task);
+            }
+        }
+        self.save()?;
+
+        Ok(fs::remove_file(path)?)
+    }
+
+    /// Get a task.
+    pub fn get(&self, id: &RescanId) -> Option<&Task> {
+        self.tasks.get(id)
+    }
+
+    /// Iterate over all tasks.
+    pub fn iter(&self) -> impl Iterator<Item = &Task> {
+        self.tasks.values()
+    }
+
+    /// Add a task, replacing any task with the same id, and save.
+    pub fn insert(&mut self, task: Task) -> Result<(), Error> {
+        self.tasks.insert(task.id, task);
+        self.save()
+    }
+
+    /// Save all tasks, replacing the previously saved tasks.
+    pub fn save(&mut self) -> Result<(), Error> {
+        let mut batch = self.namespace.batch().clear();
+
+        for task in self.tasks.values() {
+            batch = batch.put(task.id.to_string(), encode::serialize(task));
+        }
+        batch.commit()?;
+        self.unsaved = 0;
+
+        Ok(())
+    }
+
+    /// Update task progress based on a protocol event.
+    pub fn process(&mut self, event: &protocol::Event) -> Result<(), Error> {
+        use protocol::{FilterEvent, InventoryEvent};
+
+        match event {
+            protocol::Event::Filter(FilterEvent::RescanStarted { id, start, .. }) => {
+                // Starting a rescan replaces the running one, which can later be resumed.
+                if let Some(task) = self.running.and_then(|r| self.tasks.get_mut(&r)) {
+                    if task.id != *id && task.status == Status::Running {
+                        task.status = Status::Paused;
+                    }
+                }
+                self.start(*id, *start);
+            }
+            protocol::Event::Filter(FilterEvent::RescanResumed { id, height }) => {
+                self.start(*id, *height);
+            }
+            protocol::Event::Filter(FilterEvent::FilterProcessed { block, matched, .. }) => {
+                let Some(task) = self.running.and_then(|r| self.tasks.get_mut(&r)) else {
+                    return Ok(());
+                };
+                if *matched {
+                    self.pending.insert(block.height, block.hash);
+                    task.last_match = Some(block.height);
+                }
+                self.next = block.height + 1;
+                self.unsaved += 1;
+                Self::update(task, self.next, &self.pending);
+
+                if self.unsaved < SAVE_INTERVAL {
+                    return Ok(());
+                }
+            }
+            protocol::Event::Inventory(InventoryEvent::BlockProcessed { height, .. }) => {
+                let Some(task) = self.running.and_then(|r| self.tasks.get_mut(&r)) else {
+                    return Ok(());
+                };
+                if self.pending.remove(height).is_none() {
+                    return Ok(());
+                }
+                Self::update(task, self.next, &self.pending);
+            }
+            protocol::Event::Filter(FilterEvent::RescanPaused { id, .. }) => {
+                if let Some(task) = self.tasks.get_mut(id) {
+                    task.status = Status::Paused;
+                }
+            }
+            protocol::Event::Filter(FilterEvent::RescanCanceled { id, .. }) => {
+                self.tasks.remove(id);
+            }
+            protocol::Event::Filter(FilterEvent::RescanCompleted { id, height }) => {
+                if let Some(task) = self.tasks.get_mut(id) {
+                    task.status = Status::Completed;
+                    task.current = Height::max(task.current, *height);
+                }
+            }
+            _ => return Ok(()),
+        }
+        self.save()
+    }
+
+    fn start(&mut self, id: RescanId, height: Height) {
+        self.running = Some(id);
+        self.next = height;
+        self.pending.clear();
+
+        if let Some(task) = self.tasks.get_mut(&id) {
+            // Blocks below the start height won't be matched again, so we keep
+            // awaiting them.
+            self.pending
+                .extend(task.pending.iter().copied().filter(|(h, _)| *h < height));
+            task.status = Status::Running;
+
+            Self::update(task, self.next, &self.pending);
+        }
+    }
+
+    fn update(task: &mut Task, next: Height, pending: &BTreeMap<Height, BlockHash>) {
+        task.next = next;
+        task.pending = pending.iter().map(|(h, b)| (*h, *b)).collect();
+        task.current = pending.keys().next().copied().unwrap_or(next);
+    }
+}
+
+/// Rescan tasks shared between the client's event publisher and its handles.
+/// Tasks are only tracked once a [`Tasks`] store is set.
+#[derive(Debug, Clone, Default)]
+pub struct Shared(Arc<Mutex<Option<Tasks>>>);
+
+impl Shared {
+    /// Set the task store.
+    pub fn set(&self, tasks: Tasks) {
+        *self.0.lock().unwrap() = Some(tasks);
+    }
+
+    /// Add a task. Does nothing if tasks are not persisted.
+    pub fn insert(&self, task: Task) -> Result<(), Error> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(tasks) => tasks.insert(task),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether tasks are persisted.
+    pub fn is_enabled(&self) -> bool {
+        self.0.lock().unwrap().is_some()
+    }
+
+    /// Get a task.
+    pub fn get(&self, id: &RescanId) -> Option<Task> {
+        self.0.lock().unwrap().as_ref()?.get(id).cloned()
+    }
+
+    /// Get all tasks.
+    pub fn list(&self) -> Result<Vec<Task>, Error> {
+        Ok(self
+            .0
+            .lock()
+            .unwrap()
+            .as_ref()
+            .ok_or(Error::Disabled)?
+            .iter()
+            .cloned()
+            .collect())
+    }
+}
+
+impl protocol::event::Publisher for Shared {
+    fn publish(&mut self, event: protocol::Event) {
+        if let Some(tasks) = self.0.lock().unwrap().as_mut() {
+            if let Err(err) = tasks.process(&event) {
+                log::error!("Failed to save rescan tasks: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use nakamoto_common::bitcoin::hashes::Hash as _;
+    use nakamoto_common::block::Anchor;
+    use nakamoto_common::network::Network;
+    use nakamoto_test::block::gen;
+
+    fn filter(height: Height, matched: bool) -> protocol::Event {
+        protocol::Event::Filter(protocol::FilterEvent::FilterProcessed {
+            block: Anchor {
+                height,
+                hash: hash(height),
+                parent: hash(height.saturating_sub(1)),
+            },
+            matched,
+            valid: true,
+            cached: false,
+        })
+    }
+
+    fn hash(height: Height) -> BlockHash {
+        BlockHash::hash(&height.to_le_bytes())
+    }
+
+    #[test]
+    fn test_progress() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("meta.db");
+        let load = || Tasks::open(kv::Store::open(&path).unwrap().namespace("rescans")).unwrap();
+        let mut rng = fastrand::Rng::new();
+        let mut tasks = load();
+        let block = gen::block(&Network::Regtest.genesis(), &mut rng);
+
+        tasks
+            .insert(Task::new(1, 10, None, vec![gen::script(&mut rng)]))
+            .unwrap();
+        tasks
+            .process(&protocol::Event::Filter(
+                protocol::FilterEvent::RescanStarted {
+                    id: 1,
+                    start: 10,
+                    end: None,
+                },
+            ))
+            .unwrap();
+
+        tasks.process(&filter(10, false)).unwrap();
+        tasks.process(&filter(11, true)).unwrap();
+        tasks.process(&filter(12, false)).unwrap();
+
+        // We can't resume past the matched block until it's processed.
+        assert_eq!(tasks.get(&1).unwrap().current, 11);
+        assert_eq!(tasks.get(&1).unwrap().last_match, Some(11));
+        assert_eq!(load().get(&1).unwrap().current, 10);
+
+        // But the block is fetched directly when resuming, and filters are only
+        // processed from where we left off.
+        tasks.save().unwrap();
+        let saved = load();
+        let task = saved.get(&1).unwrap();
+        assert_eq!(task.pending, vec![(11, hash(11))]);
+        assert!(matches!(
+            task.resume().as_slice(),
+            [
+                Command::Rescan {
+                    from: Bound::Included(13),
+                    ..
+                },
+                Command::GetBlock(h)
+            ] if *h == hash(11)
+        ));
+
+        tasks
+            .process(&protocol::Event::Inventory(
+                protocol::InventoryEvent::BlockProcessed {
+                    block,
+                    height: 11,
+                    fees: None,
+                },
+            ))
+            .unwrap();
+
+        let saved = load();
+        let task = saved.get(&1).unwrap();
+        assert_eq!(task.current, 13);
+        assert_eq!(task.status, Status::Running);
+        assert!(task.pending.is_empty());
+        assert!(matches!(
+            task.resume().as_slice(),
+            [Command::Rescan {
+                id: 1,
+                from: Bound::Included(13),
+                to: Bound::Unbounded,
+                ..
+            }]
+        ));
+
+        // Starting another rescan pauses the running task.
+        tasks.insert(Task::new(2, 0, Some(5), vec![])).unwrap();
+        tasks
+            .process(&protocol::Event::Filter(
+                protocol::FilterEvent::RescanStarted {
+                    id: 2,
+                    start: 0,
+                    end: Some(5),
+                },
+            ))
+            .unwrap();
+        assert_eq!(tasks.get(&1).unwrap().status, Status::Paused);
+
+        tasks
+            .process(&protocol::Event::Filter(
+                protocol::FilterEvent::RescanCompleted { id: 2, height: 5 },
+            ))
+            .unwrap();
+        tasks
+            .process(&protocol::Event::Filter(
+                protocol::FilterEvent::RescanCanceled { id: 1, height: 13 },
+            ))
+            .unwrap();
+
+        let saved = load();
+        assert!(saved.get(&1).is_none());
+        assert_eq!(saved.get(&2).unwrap().status, Status::Completed);
+    }
+
+    #[test]
+    fn test_import() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("rescans.db");
+        let store = kv::Store::open(tmp.path().join("meta.db")).unwrap();
+        let task = Task {
+            current: 7,
+            ..Task::new(1, 5, Some(9), vec![])
+        };
+        // Tasks saved before the file format was versioned.
+        let mut bytes = Vec::new();
+        VarInt(1).consensus_encode(&mut bytes).unwrap();
+        task.id.consensus_encode(&mut bytes).unwrap();
+        task.start.consensus_encode(&mut bytes).unwrap();
+        encode_height(&task.end, &mut bytes).unwrap();
+        VarInt(0).consensus_encode(&mut bytes).unwrap();
+        task.current.consensus_encode(&mut bytes).unwrap();
+        encode_height(&None, &mut bytes).unwrap();
+        0u8.consensus_encode(&mut bytes).unwrap();
+        fs::write(&path, bytes).unwrap();
+
+        let mut tasks = Tasks::open(store.namespace("rescans")).unwrap();
+        tasks.import(&path).unwrap();
+        assert!(!path.exists());
+
+        let loaded = Tasks::open(store.namespace("rescans")).unwrap();
+        let task = loaded.get(&1).unwrap();
+        assert_eq!(task.current, 7);
+        assert_eq!(task.next, 7);
+        assert_eq!(Some(task), tasks.get(&1));
+    }
+}
@@ -0,0 +1,98 @@
+//! Run several clients, each on a different network, in a single process.
+//!
+//! Each client has its own stores and reactor, and runs on its own thread, managed
+//! by the [`ClientSet`]. Nb. nothing is shared between clients: each one runs its own
+//! reactor on a dedicated OS thread, along with the background threads of a single
+//! client, eg. to flush its block and filter header stores. The cost of a set is thus
+//! that of running its clients in separate processes, minus the processes themselves.
+use std::collections::HashMap;
+use std::thread;
+
+use nakamoto_common::network::Network;
+
+use crate::client::{Client, Config, Handle, Publisher, Reactor};
+use crate::error::Error;
+use crate::handle::Handle as _;
+
+/// A set of clients, at most one per network.
+pub struct ClientSet<R: Reactor<Publisher>> {
+    clients: HashMap<Network, (Handle<R>, thread::JoinHandle<Result<(), Error>>)>,
+}
+
+impl<R> ClientSet<R>
+where
+    R: Reactor<Publisher> + Send + 'static,
+{
+    /// Create a new, empty client set.
+    pub fn new() -> Self {
+        Self {
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Create a client from the given configuration and run it in the background, on a
+    /// new OS thread. Returns an error if a client is already running on the configured
+    /// network.
+    pub fn spawn(&mut self, config: Config) -> Result<Handle<R>, Error> {
+        let network = config.protocol.network.clone();
+
+        if self.clients.contains_key(&network) {
+            return Err(Error::AlreadyRunning(network));
+        }
+        let client = Client::<R>::new()?;
+        let handle = client.handle();
+        let thread = thread::Builder::new()
+            .name(format!("nakamoto-{}", network.as_str()))
+            .spawn(move || client.run(config))?;
+
+        self.clients.insert(network, (handle.clone(), thread));
+
+        Ok(handle)
+    }
+
+    /// Get the handle of the client running on the given network.
+    pub fn get(&self, network: &Network) -> Option<&Handle<R>> {
+        self.clients.get(network).map(|(h, _)| h)
+    }
+
+    /// Iterate over the networks with a running client.
+    pub fn networks(&self) -> impl Iterator<Item = &Network> {
+        self.clients.keys()
+    }
+
+    /// Shutdown all clients and wait for them to terminate.
+    /// Returns the first error encountered, if any.
+    pub fn shutdown(self) -> Result<(), Error> {
+        let mut result = Ok(());
+
+        for (network, (handle, thread)) in self.clients {
+            // Nb. The client may have already exited, in which case there is nothing to
+            // shut down, and its result is obtained by joining its thread.
+            handle.shutdown().ok();
+
+            match thread.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => {
+                    log::error!("Client on {} exited with error: {}", network.as_str(), err);
+
+                    if result.is_ok() {
+                        result = Err(err);
+                    }
+                }
+                Err(_) => {
+                    log::error!("Client on {} panicked", network.as_str());
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<R> Default for ClientSet<R>
+where
+    R: Reactor<Publisher> + Send + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
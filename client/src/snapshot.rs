@@ -0,0 +1,356 @@
+//! Snapshots of the synced chain state.
+//!
+//! A snapshot is a portable archive of the block headers, filter headers and checkpoints
+//! of a client, which another client can import to skip the initial sync. See
+//! [`Handle::export_snapshot`](crate::handle::Handle::export_snapshot) and
+//! [`Handle::import_snapshot`](crate::handle::Handle::import_snapshot).
+//!
+//! Block headers are fully validated on import, but filter headers can't be validated
+//! against proof-of-work, and are hence only as trustworthy as the snapshot's source.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use thiserror::Error;
+
+use nakamoto_common::bitcoin::consensus::encode::{self, Decodable, Encodable, VarInt};
+use nakamoto_common::bitcoin_hashes::{sha256d, Hash};
+use nakamoto_common::block::filter::{FilterHash, FilterHeader};
+use nakamoto_common::block::{tree, BlockHash, BlockHeader, Height};
+use nakamoto_common::error::{self, Classify};
+use nakamoto_p2p::protocol::ImportFilterHeadersError;
+
+/// Magic bytes identifying a snapshot file.
+const MAGIC: &[u8; 4] = b"NKSS";
+
+/// Snapshot format version.
+pub const VERSION: u32 = 1;
+
+/// A snapshot error.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// An I/O error.
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    /// The snapshot could not be decoded.
+    #[error("decoding error: {0}")]
+    Decode(#[from] encode::Error),
+    /// The file is not a snapshot.
+    #[error("not a snapshot file")]
+    Magic,
+    /// The snapshot format version is not supported.
+    #[error("unsupported snapshot version {0}")]
+    Version(u32),
+    /// The snapshot checksum doesn't match its contents.
+    #[error("snapshot checksum mismatch")]
+    Checksum,
+    /// The snapshot is for a different network.
+    #[error("snapshot genesis {found} doesn't match expected genesis {expected}")]
+    Network {
+        /// Our genesis block hash.
+        expected: BlockHash,
+        /// The snapshot's genesis block hash.
+        found: BlockHash,
+    },
+    /// A block header doesn't connect to the previous one.
+    #[error("invalid block header at height {0}")]
+    InvalidHeader(Height),
+    /// A filter header doesn't connect to the previous one.
+    #[error("invalid filter header at height {0}")]
+    InvalidFilterHeader(Height),
+    /// A block header doesn't match a checkpoint.
+    #[error("block header at height {0} doesn't match checkpoint")]
+    Checkpoint(Height),
+    /// The block headers could not be imported.
+    #[error("error importing block headers: {0}")]
+    Import(#[from] tree::Error),
+    /// The filter headers could not be imported.
+    #[error("error importing filter headers: {0}")]
+    ImportFilters(#[from] ImportFilterHeadersError),
+}
+
+impl Classify for Error {
+    fn code(&self) -> u32 {
+        match self {
+            Self::Io(_) => 2009,
+            Self::Decode(_) => 2016,
+            Self::Magic => 3014,
+            Self::Version(_) => 3015,
+            Self::Checksum => 3016,
+            Self::Network { .. } => 3017,
+            Self::InvalidHeader(_) => 3018,
+            Self::InvalidFilterHeader(_) => 3019,
+            Self::Checkpoint(_) => 3020,
+            Self::Import(err) => err.code(),
+            Self::ImportFilters(err) => err.code(),
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Io(err) => error::is_transient_io(err),
+            Self::Import(err) => err.is_transient(),
+            Self::ImportFilters(err) => err.is_transient(),
+            _ => false,
+        }
+    }
+}
+
+/// A snapshot of the synced chain state.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Block headers of the active chain, starting from the genesis.
+    pub headers: Vec<BlockHeader>,
+    /// Filter headers, starting from the genesis.
+    pub filter_headers: Vec<(FilterHash, FilterHeader)>,
+    /// Checkpoints known to the exporting client.
+    pub checkpoints: BTreeMap<Height, BlockHash>,
+}
+
+impl Snapshot {
+    /// Height of the snapshot's block header chain.
+    pub fn height(&self) -> Height {
+        self.headers.len().saturating_sub(1) as Height
+    }
+
+    /// Height of the snapshot's filter header chain.
+    pub fn filter_height(&self) -> Height {
+        self.filter_headers.len().saturating_sub(1) as Height
+    }
+
+    /// Write the snapshot to a file.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+
+        self.write(&mut file)?;
+        file.flush()?;
+
+        Ok(())
+    }
+
+    /// Read a snapshot from a file.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let file = io::BufReader::new(fs::File::open(path)?);
+
+        Self::read(file)
+    }
+
+    /// Write the snapshot to the given writer.
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        let body = encode::serialize(self);
+        let checksum = sha256d::Hash::hash(&body);
+
+        writer.write_all(MAGIC)?;
+        VERSION.consensus_encode(&mut writer)?;
+        writer.write_all(&body)?;
+        writer.write_all(&checksum[..])?;
+
+        Ok(())
+    }
+
+    /// Read a snapshot from the given reader. Checks the snapshot integrity, but doesn't
+    /// validate its contents. See [`Snapshot::verify`].
+    pub fn read<R: Read>(mut reader: R) -> Result<Self, Error> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+
+        if &magic != MAGIC {
+            return Err(Error::Magic);
+        }
+        let version = u32::consensus_decode(&mut reader)?;
+        if version != VERSION {
+            return Err(Error::Version(version));
+        }
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+
+        if bytes.len() < sha256d::Hash::LEN {
+            return Err(Error::Checksum);
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - sha256d::Hash::LEN);
+
+        if sha256d::Hash::hash(body)[..] != checksum[..] {
+            return Err(Error::Checksum);
+        }
+        encode::deserialize(body).map_err(Error::from)
+    }
+
+    /// Verify that the snapshot belongs to the chain with the given genesis, that its
+    /// header chains are connected, and that it matches the given checkpoints, as well as
+    /// its own. An empty snapshot is valid, and imports nothing.
+    pub fn verify(
+        &self,
+        genesis: BlockHash,
+        checkpoints: &BTreeMap<Height, BlockHash>,
+    ) -> Result<(), Error> {
+        if let Some(found) = self.headers.first().map(|h| h.block_hash()) {
+            if found != genesis {
+                return Err(Error::Network {
+                    expected: genesis,
+                    found,
+                });
+            }
+        }
+        for (height, pair) in self.headers.windows(2).enumerate() {
+            if pair[1].prev_blockhash != pair[0].block_hash() {
+                return Err(Error::InvalidHeader(height as Height + 1));
+            }
+        }
+        for (height, pair) in self.filter_headers.windows(2).enumerate() {
+            let ((_, prev), (hash, header)) = (pair[0], pair[1]);
+
+            if hash.filter_header(&prev) != header {
+                return Err(Error::InvalidFilterHeader(height as Height + 1));
+            }
+        }
+        if self.filter_headers.len() > self.headers.len() {
+            return Err(Error::InvalidFilterHeader(self.headers.len() as Height));
+        }
+        for (height, hash) in checkpoints.iter().chain(self.checkpoints.iter()) {
+            match self.headers.get(*height as usize) {
+                Some(header) if &header.block_hash() != hash => {
+                    return Err(Error::Checkpoint(*height));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Encodable for Snapshot {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = VarInt(self.headers.len() as u64).consensus_encode(&mut w)?;
+        for header in &self.headers {
+            len += header.consensus_encode(&mut w)?;
+        }
+        len += VarInt(self.filter_headers.len() as u64).consensus_encode(&mut w)?;
+        for (hash, header) in &self.filter_headers {
+            len += hash.consensus_encode(&mut w)?;
+            len += header.consensus_encode(&mut w)?;
+        }
+        len += VarInt(self.checkpoints.len() as u64).consensus_encode(&mut w)?;
+        for (height, hash) in &self.checkpoints {
+            len += height.consensus_encode(&mut w)?;
+            len += hash.consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Snapshot {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let count = VarInt::consensus_decode(&mut d)?.0;
+        let mut headers = Vec::new();
+        for _ in 0..count {
+            headers.push(BlockHeader::consensus_decode(&mut d)?);
+        }
+
+        let count = VarInt::consensus_decode(&mut d)?.0;
+        let mut filter_headers = Vec::new();
+        for _ in 0..count {
+            let hash = FilterHash::consensus_decode(&mut d)?;
+            let header = FilterHeader::consensus_decode(&mut d)?;
+
+            filter_headers.push((hash, header));
+        }
+
+        let count = VarInt::consensus_decode(&mut d)?.0;
+        let mut checkpoints = BTreeMap::new();
+        for _ in 0..count {
+            let height = Height::consensus_decode(&mut d)?;
+            let hash = BlockHash::consensus_decode(&mut d)?;
+
+            checkpoints.insert(height, hash);
+        }
+
+        Ok(Self {
+            headers,
+            filter_headers,
+            checkpoints,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use nakamoto_common::block::store::Genesis as _;
+    use nakamoto_common::network::Network;
+    use nakamoto_test::block::gen;
+
+    fn snapshot(network: Network) -> Snapshot {
+        let mut rng = fastrand::Rng::new();
+        let chain = gen::blockchain(network.genesis_block(), 32, &mut rng);
+        let genesis = FilterHeader::genesis(network.clone());
+        let filter_headers = gen::cfheaders_from_blocks(genesis, chain.tail.iter().take(16));
+
+        Snapshot {
+            headers: chain.iter().map(|b| b.header).collect(),
+            filter_headers: std::iter::once((FilterHash::genesis(network), genesis))
+                .chain(filter_headers)
+                .collect(),
+            checkpoints: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_write_read() {
+        let network = Network::Regtest;
+        let snapshot = snapshot(network);
+        let mut bytes = Vec::new();
+
+        snapshot.write(&mut bytes).unwrap();
+        assert_eq!(Snapshot::read(&bytes[..]).unwrap(), snapshot);
+
+        // Flip a bit in the body.
+        bytes[64] ^= 1;
+        assert!(matches!(Snapshot::read(&bytes[..]), Err(Error::Checksum)));
+        assert!(matches!(Snapshot::read(&b"NKWS"[..]), Err(Error::Magic)));
+
+        let empty = Snapshot::default();
+        let mut bytes = Vec::new();
+
+        empty.write(&mut bytes).unwrap();
+        assert_eq!(Snapshot::read(&bytes[..]).unwrap(), empty);
+    }
+
+    #[test]
+    fn test_verify() {
+        let network = Network::Regtest;
+        let genesis = network.genesis_hash();
+        let snapshot = snapshot(network);
+
+        snapshot.verify(genesis, &BTreeMap::new()).unwrap();
+        Snapshot::default()
+            .verify(genesis, &BTreeMap::new())
+            .unwrap();
+
+        assert!(matches!(
+            snapshot.verify(Network::Mainnet.genesis_hash(), &BTreeMap::new()),
+            Err(Error::Network { .. })
+        ));
+
+        let mut invalid = snapshot.clone();
+        invalid.headers[8].nonce += 1;
+        assert!(matches!(
+            invalid.verify(genesis, &BTreeMap::new()),
+            Err(Error::InvalidHeader(9))
+        ));
+
+        let mut invalid = snapshot.clone();
+        invalid.filter_headers[4].1 = invalid.filter_headers[3].1;
+        assert!(matches!(
+            invalid.verify(genesis, &BTreeMap::new()),
+            Err(Error::InvalidFilterHeader(4))
+        ));
+
+        let checkpoints = [(12, BlockHash::default())].into_iter().collect();
+        assert!(matches!(
+            snapshot.verify(genesis, &checkpoints),
+            Err(Error::Checkpoint(12))
+        ));
+    }
+}
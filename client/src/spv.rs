@@ -1,6 +1,7 @@
 //! SPV event mapper.
 #![allow(clippy::manual_range_contains, clippy::new_without_default)]
 
+pub mod channels;
 pub mod utxos;
 
 #[cfg(test)]
@@ -13,11 +14,12 @@ use p2p::event::Emitter;
 
 use nakamoto_common::bitcoin::{Block, Txid};
 
-use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::block::{Anchor, Height};
 use nakamoto_p2p as p2p;
 use p2p::protocol;
 
 use crate::client::Event;
+use crate::rescan;
 
 /// Transaction status of a given transaction.
 #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq)]
@@ -36,15 +38,19 @@ pub enum TxStatus {
     /// Transaction was included in a block. This event is fired after
     /// a block from the main chain is scanned.
     Confirmed {
-        /// Height at which it was included.
-        height: Height,
-        /// Hash of the block in which it was included.
-        block: BlockHash,
+        /// Block in which it was included.
+        block: Anchor,
     },
     /// A transaction that was previously confirmed, and is now reverted due to a
     /// re-org. Note that this event can only fire if the originally confirmed tx
     /// is still in memory.
     Reverted,
+    /// Transaction was replaced by a conflicting transaction submitted by us, eg. to bump
+    /// its fee, and is no longer announced. Either transaction may still be confirmed.
+    Replaced {
+        /// Transaction replacing the given transaction.
+        replaced_by: Txid,
+    },
     /// Transaction was replaced by another transaction, and will probably never
     /// be included in a block. This can happen if an RBF transaction is replaced by one with
     /// a higher fee, or if a transaction is reverted and a conflicting transaction replaces
@@ -53,7 +59,7 @@ pub enum TxStatus {
         /// Transaction replacing the given transaction and causing it to be stale.
         replaced_by: Txid,
         /// Block of the included transaction.
-        block: BlockHash,
+        block: Anchor,
     },
 }
 
@@ -64,16 +70,17 @@ impl fmt::Display for TxStatus {
             Self::Acknowledged { peer } => {
                 write!(fmt, "transaction was acknowledged by peer {}", peer)
             }
-            Self::Confirmed { height, block } => write!(
-                fmt,
-                "transaction was included in block {} at height {}",
-                block, height
-            ),
+            Self::Confirmed { block } => {
+                write!(fmt, "transaction was included in block {}", block)
+            }
             Self::Reverted => write!(fmt, "transaction has been reverted"),
+            Self::Replaced { replaced_by } => {
+                write!(fmt, "transaction was replaced by {}", replaced_by)
+            }
             Self::Stale { replaced_by, block } => write!(
                 fmt,
                 "transaction was replaced by {} in block {}",
-                replaced_by, block
+                replaced_by, block.hash
             ),
         }
     }
@@ -94,11 +101,19 @@ pub struct Mapper {
     block_height: Height,
     /// Filter heights that have been matched, and for which we are awaiting a block to process.
     pending: HashSet<Height>,
+    /// Rescan tasks, used to find the blocks a resumed rescan is still awaiting.
+    rescans: rescan::Shared,
 }
 
 impl Mapper {
     /// Create a new SPV event mapper.
     pub fn new() -> Self {
+        Self::with_rescans(rescan::Shared::default())
+    }
+
+    /// Create a new SPV event mapper which keeps awaiting the matched blocks of
+    /// resumed rescan tasks.
+    pub fn with_rescans(rescans: rescan::Shared) -> Self {
         let tip = 0;
         let sync_height = 0;
         let filter_height = 0;
@@ -111,6 +126,7 @@ impl Mapper {
             filter_height,
             block_height,
             pending,
+            rescans,
         }
     }
 
@@ -140,6 +156,8 @@ impl Mapper {
                 user_agent,
                 height,
                 version,
+                relay,
+                advertised_addr,
             }) => {
                 emitter.emit(Event::PeerNegotiated {
                     addr,
@@ -148,6 +166,8 @@ impl Mapper {
                     user_agent,
                     height,
                     version,
+                    relay,
+                    advertised_addr,
                 });
             }
             protocol::Event::Peer(protocol::PeerEvent::Disconnected(addr, reason)) => {
@@ -156,21 +176,55 @@ impl Mapper {
             protocol::Event::Chain(protocol::ChainEvent::PeerHeightUpdated { height }) => {
                 emitter.emit(Event::PeerHeightUpdated { height });
             }
+            protocol::Event::Chain(protocol::ChainEvent::StaleTip { age }) => {
+                emitter.emit(Event::StaleTip { age });
+            }
+            protocol::Event::Resync(protocol::ResyncEvent::Resynced {
+                peer,
+                offline,
+                attempts,
+                from,
+                height,
+                tip,
+                reverted,
+                connected,
+                from_filter_height,
+                filter_height,
+            }) => {
+                emitter.emit(Event::Resynced {
+                    peer,
+                    offline,
+                    attempts,
+                    from,
+                    height,
+                    tip,
+                    reverted,
+                    connected,
+                    from_filter_height,
+                    filter_height,
+                });
+            }
             protocol::Event::Chain(protocol::ChainEvent::Synced(_, height)) => {
                 self.tip = height;
             }
             protocol::Event::Chain(protocol::ChainEvent::BlockConnected { header, height }) => {
                 emitter.emit(Event::BlockConnected {
                     header,
-                    hash: header.block_hash(),
-                    height,
+                    block: Anchor::new(height, &header),
                 });
             }
             protocol::Event::Chain(protocol::ChainEvent::BlockDisconnected { header, height }) => {
+                // Filters and blocks at or above this height will be processed again
+                // once the new branch is connected.
+                self.pending.retain(|h| *h < height);
+
+                self.filter_height = self.filter_height.min(height - 1);
+                self.block_height = self.block_height.min(height - 1);
+                self.sync_height = self.sync_height.min(height - 1);
+
                 emitter.emit(Event::BlockDisconnected {
                     header,
-                    hash: header.block_hash(),
-                    height,
+                    block: Anchor::new(height, &header),
                 });
             }
             protocol::Event::Inventory(protocol::InventoryEvent::BlockProcessed {
@@ -178,47 +232,87 @@ impl Mapper {
                 height,
                 fees,
             }) => {
-                let hash = self.process_block(block, height, emitter);
+                let block = self.process_block(block, height, emitter);
 
                 if let Some(fees) = fees {
-                    emitter.emit(Event::FeeEstimated {
-                        block: hash,
-                        height,
-                        fees,
-                    });
+                    emitter.emit(Event::FeeEstimated { block, fees });
                 }
             }
             protocol::Event::Inventory(protocol::InventoryEvent::Confirmed {
                 transaction,
-                height,
                 block,
             }) => {
                 emitter.emit(Event::TxStatusChanged {
                     txid: transaction.txid(),
-                    status: TxStatus::Confirmed { height, block },
+                    status: TxStatus::Confirmed { block },
                 });
             }
+            protocol::Event::Inventory(protocol::InventoryEvent::Reverted { transaction }) => {
+                emitter.emit(Event::TxStatusChanged {
+                    txid: transaction.txid(),
+                    status: TxStatus::Reverted,
+                });
+            }
+            protocol::Event::Inventory(protocol::InventoryEvent::Replaced {
+                txid,
+                replaced_by,
+            }) => {
+                emitter.emit(Event::TxStatusChanged {
+                    txid,
+                    status: TxStatus::Replaced { replaced_by },
+                });
+            }
+            protocol::Event::Inventory(protocol::InventoryEvent::Stale {
+                txid,
+                replaced_by,
+                block,
+            }) => {
+                emitter.emit(Event::TxStatusChanged {
+                    txid,
+                    status: TxStatus::Stale { replaced_by, block },
+                });
+            }
+            protocol::Event::Inventory(protocol::InventoryEvent::MempoolMatched {
+                transaction,
+                ..
+            }) => {
+                emitter.emit(Event::MempoolMatched { transaction });
+            }
             protocol::Event::Inventory(protocol::InventoryEvent::Acknowledged { txid, peer }) => {
                 emitter.emit(Event::TxStatusChanged {
                     txid,
                     status: TxStatus::Acknowledged { peer },
                 });
             }
-            protocol::Event::Filter(protocol::FilterEvent::RescanStarted { start, .. }) => {
+            protocol::Event::Filter(protocol::FilterEvent::RescanStarted { id, start, .. }) => {
                 self.pending.clear();
 
+                // A resumed rescan starts after blocks that were matched but not yet
+                // processed. These are fetched again, so we keep awaiting them.
+                if let Some(task) = self.rescans.get(&id) {
+                    self.pending
+                        .extend(task.pending.iter().map(|(h, _)| *h).filter(|h| *h < start));
+                }
+                let height = self
+                    .pending
+                    .iter()
+                    .min()
+                    .map_or(start, |h| h.saturating_sub(1));
+
                 self.filter_height = start;
-                self.sync_height = start;
-                self.block_height = start;
+                self.sync_height = height;
+                self.block_height = height;
+            }
+            protocol::Event::Filter(protocol::FilterEvent::RescanCompleted { id, height }) => {
+                emitter.emit(Event::RescanCompleted { id, height });
             }
             protocol::Event::Filter(protocol::FilterEvent::FilterProcessed {
                 block,
-                height,
                 matched,
                 valid,
                 ..
             }) => {
-                self.process_filter(block, height, matched, valid, emitter);
+                self.process_filter(block, matched, valid, emitter);
             }
             _ => {}
         }
@@ -253,42 +347,36 @@ impl Mapper {
     // PRIVATE METHODS /////////////////////////////////////////////////////////
 
     // TODO: Instead of receiving the block, fetch it if matched.
-    fn process_block(
-        &mut self,
-        block: Block,
-        height: Height,
-        emitter: &Emitter<Event>,
-    ) -> BlockHash {
-        let hash = block.block_hash();
+    fn process_block(&mut self, block: Block, height: Height, emitter: &Emitter<Event>) -> Anchor {
+        let anchor = Anchor::new(height, &block.header);
 
         if !self.pending.remove(&height) {
             // Received unexpected block.
-            return hash;
+            return anchor;
         }
 
-        log::debug!("Received block {} at height {}", hash, height);
+        log::debug!("Received block {}", anchor);
         debug_assert!(height >= self.block_height);
 
         self.block_height = height;
 
         emitter.emit(Event::BlockMatched {
-            height,
-            hash,
+            block: anchor,
             header: block.header,
             transactions: block.txdata,
         });
 
-        hash
+        anchor
     }
 
     fn process_filter(
         &mut self,
-        block: BlockHash,
-        height: Height,
+        block: Anchor,
         matched: bool,
         valid: bool,
         emitter: &Emitter<Event>,
     ) {
+        let height = block.height;
         debug_assert!(height >= self.filter_height);
 
         if matched {
@@ -298,7 +386,6 @@ impl Mapper {
         self.filter_height = height;
 
         emitter.emit(Event::FilterProcessed {
-            height,
             matched,
             valid,
             block,
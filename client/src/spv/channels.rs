@@ -0,0 +1,282 @@
+//! Lightning channel monitoring.
+//!
+//! Lightning nodes need to know when their channel funding transactions confirm, and when
+//! a channel is closed, ie. when its funding output is spent, in particular when it's
+//! spent by a revoked commitment transaction, which must be answered with a penalty
+//! transaction before the revoked outputs' timelock expires.
+//!
+//! The [`ChannelMonitor`] keeps track of registered channels, and turns the client's
+//! [`Event`]s into [`ChannelEvent`]s. Compact filters commit to the scripts of spent
+//! outputs, so watching a channel's funding script is enough to be notified of the blocks
+//! spending it. The scripts returned by [`ChannelMonitor::scripts`] must be watched by the
+//! client, eg. with [`crate::handle::Handle::watch`].
+//!
+//! Breaches are detected either by transaction ID, for revoked commitments whose ID is
+//! known, or by script, for commitments paying to one of the channel's revoked commitment
+//! scripts, eg. the `to_local` output scripts of revoked states.
+use std::collections::{HashMap, HashSet};
+
+use nakamoto_common::bitcoin::{OutPoint, Script, Transaction, Txid};
+use nakamoto_common::block::Height;
+
+use crate::client::Event;
+
+/// Confirmation depth after which transactions are considered final, and no longer tracked.
+pub const DEFAULT_FINAL_DEPTH: Height = 6;
+
+/// An event concerning a monitored channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelEvent {
+    /// The channel's funding transaction was confirmed.
+    FundingConfirmed {
+        /// Funding output of the channel.
+        channel: OutPoint,
+        /// Height of the block including the funding transaction.
+        height: Height,
+        /// Confirmation depth.
+        depth: Height,
+    },
+    /// The channel was closed by a transaction spending its funding output.
+    Closed {
+        /// Funding output of the channel.
+        channel: OutPoint,
+        /// Transaction spending the funding output.
+        transaction: Transaction,
+        /// Height of the block including the closing transaction.
+        height: Height,
+        /// Confirmation depth.
+        depth: Height,
+    },
+    /// The channel's funding output was spent by a revoked commitment transaction. A
+    /// penalty transaction should be broadcast as soon as possible.
+    Breach {
+        /// Funding output of the channel.
+        channel: OutPoint,
+        /// The revoked commitment transaction.
+        transaction: Transaction,
+        /// Height of the block including the commitment transaction.
+        height: Height,
+        /// Confirmation depth.
+        depth: Height,
+    },
+    /// A transaction reported by an earlier event gained a confirmation.
+    Confirmation {
+        /// Funding output of the channel.
+        channel: OutPoint,
+        /// The confirmed transaction.
+        txid: Txid,
+        /// Confirmation depth.
+        depth: Height,
+    },
+    /// A transaction reported by an earlier event was reverted by a re-org.
+    Reverted {
+        /// Funding output of the channel.
+        channel: OutPoint,
+        /// The reverted transaction.
+        txid: Txid,
+    },
+}
+
+/// A monitored channel.
+#[derive(Debug, Clone, Default)]
+struct Channel {
+    /// Script of the funding output.
+    funding_script: Script,
+    /// IDs of revoked commitment transactions.
+    revoked: HashSet<Txid>,
+    /// Output scripts of revoked commitment transactions.
+    revoked_scripts: HashSet<Script>,
+    /// Funding transaction confirmation height.
+    funding: Option<Height>,
+    /// Closing transaction and its confirmation height.
+    closing: Option<(Txid, Height)>,
+}
+
+/// Monitors Lightning channels, given the client's events.
+#[derive(Debug, Clone)]
+pub struct ChannelMonitor {
+    channels: HashMap<OutPoint, Channel>,
+    /// Height of the header chain tip.
+    tip: Height,
+    /// Depth after which confirmations are no longer reported.
+    final_depth: Height,
+}
+
+impl Default for ChannelMonitor {
+    fn default() -> Self {
+        Self::new(DEFAULT_FINAL_DEPTH)
+    }
+}
+
+impl ChannelMonitor {
+    /// Create a new monitor, reporting confirmations up to the given depth.
+    pub fn new(final_depth: Height) -> Self {
+        Self {
+            channels: HashMap::new(),
+            tip: 0,
+            final_depth,
+        }
+    }
+
+    /// Monitor a channel, given its funding output and the output's script. Returns `false`
+    /// if the channel was already monitored.
+    pub fn watch(&mut self, funding: OutPoint, funding_script: Script) -> bool {
+        if self.channels.contains_key(&funding) {
+            return false;
+        }
+        self.channels.insert(
+            funding,
+            Channel {
+                funding_script,
+                ..Channel::default()
+            },
+        );
+        true
+    }
+
+    /// Stop monitoring a channel, eg. once its closing transaction is final and all its
+    /// outputs are claimed. Returns `false` if the channel wasn't monitored.
+    pub fn unwatch(&mut self, funding: &OutPoint) -> bool {
+        self.channels.remove(funding).is_some()
+    }
+
+    /// Register a revoked commitment transaction of a channel. Returns `false` if the
+    /// channel isn't monitored.
+    pub fn revoke(&mut self, funding: &OutPoint, commitment: Txid) -> bool {
+        self.channels
+            .get_mut(funding)
+            .map(|c| c.revoked.insert(commitment))
+            .is_some()
+    }
+
+    /// Register output scripts of revoked commitment transactions of a channel, eg. their
+    /// `to_local` output scripts. A transaction spending the funding output and paying to
+    /// one of these scripts is reported as a breach. Returns `false` if the channel isn't
+    /// monitored.
+    pub fn revoke_scripts(
+        &mut self,
+        funding: &OutPoint,
+        scripts: impl IntoIterator<Item = Script>,
+    ) -> bool {
+        self.channels
+            .get_mut(funding)
+            .map(|c| c.revoked_scripts.extend(scripts))
+            .is_some()
+    }
+
+    /// Scripts that should be watched by the client.
+    pub fn scripts(&self) -> impl Iterator<Item = &Script> {
+        self.channels.values().map(|c| &c.funding_script)
+    }
+
+    /// Process a client event, and return the resulting channel events.
+    pub fn process(&mut self, event: &Event) -> Vec<ChannelEvent> {
+        let mut events = Vec::new();
+
+        match event {
+            Event::BlockConnected { block, .. } => {
+                self.tip = block.height;
+
+                for (channel, c) in &self.channels {
+                    let confirmed = c
+                        .funding
+                        .map(|h| (channel.txid, h))
+                        .into_iter()
+                        .chain(c.closing);
+
+                    for (txid, height) in confirmed {
+                        let depth = self.depth(height);
+
+                        if depth > 1 && depth <= self.final_depth {
+                            events.push(ChannelEvent::Confirmation {
+                                channel: *channel,
+                                txid,
+                                depth,
+                            });
+                        }
+                    }
+                }
+            }
+            Event::BlockDisconnected { block, .. } => {
+                let height = &block.height;
+                self.tip = height - 1;
+
+                for (channel, c) in self.channels.iter_mut() {
+                    if let Some((txid, _)) = c.closing.filter(|(_, h)| h >= height) {
+                        c.closing = None;
+                        events.push(ChannelEvent::Reverted {
+                            channel: *channel,
+                            txid,
+                        });
+                    }
+                    if c.funding.filter(|h| h >= height).is_some() {
+                        c.funding = None;
+                        events.push(ChannelEvent::Reverted {
+                            channel: *channel,
+                            txid: channel.txid,
+                        });
+                    }
+                }
+            }
+            Event::BlockMatched {
+                block,
+                transactions,
+                ..
+            } => {
+                let height = &block.height;
+                let depth = self.depth(*height);
+
+                for tx in transactions {
+                    let txid = tx.txid();
+
+                    for (channel, c) in self.channels.iter_mut() {
+                        if txid == channel.txid && c.funding.is_none() {
+                            c.funding = Some(*height);
+                            events.push(ChannelEvent::FundingConfirmed {
+                                channel: *channel,
+                                height: *height,
+                                depth,
+                            });
+                        }
+                        if c.closing.is_some()
+                            || !tx.input.iter().any(|i| i.previous_output == *channel)
+                        {
+                            continue;
+                        }
+                        c.closing = Some((txid, *height));
+
+                        let breach = c.revoked.contains(&txid)
+                            || tx
+                                .output
+                                .iter()
+                                .any(|o| c.revoked_scripts.contains(&o.script_pubkey));
+                        let (channel, transaction, height) = (*channel, tx.clone(), *height);
+
+                        events.push(if breach {
+                            ChannelEvent::Breach {
+                                channel,
+                                transaction,
+                                height,
+                                depth,
+                            }
+                        } else {
+                            ChannelEvent::Closed {
+                                channel,
+                                transaction,
+                                height,
+                                depth,
+                            }
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+        events
+    }
+
+    /// Confirmation depth of a transaction included at the given height.
+    fn depth(&self, height: Height) -> Height {
+        (self.tip + 1).saturating_sub(height).max(1)
+    }
+}
@@ -117,7 +117,7 @@ fn test_peer_connection_failed() {
     let remote = ([44, 44, 44, 44], 8333).into();
     let events = handle.subscribe();
 
-    client.protocol.command(Command::Connect(remote));
+    client.protocol.command(Command::Connect(remote, false));
     client.protocol.attempted(&remote);
     client.step();
 
@@ -273,8 +273,7 @@ fn prop_client_side_filtering(birth: Height, height: Height, seed: u64) -> TestR
 
         mock.subscriber.broadcast(protocol::Event::Filter(
             protocol::FilterEvent::FilterProcessed {
-                block: block.block_hash(),
-                height: h,
+                block: Anchor::new(h, &block.header),
                 matched,
                 cached: false,
                 valid: true,
@@ -334,21 +333,245 @@ fn test_tx_status_ordering() {
         TxStatus::Acknowledged {
             peer: ([0, 0, 0, 0], 0).into()
         } < TxStatus::Confirmed {
-            height: 0,
-            block: BlockHash::default(),
+            block: Anchor::default(),
         }
     );
     assert!(
         TxStatus::Confirmed {
-            height: 0,
-            block: BlockHash::default(),
+            block: Anchor::default(),
         } < TxStatus::Reverted
     );
     assert!(
         TxStatus::Reverted
             < TxStatus::Stale {
                 replaced_by: Default::default(),
-                block: BlockHash::default()
+                block: Anchor::default(),
             }
     );
 }
+
+#[test]
+fn test_channel_monitor() {
+    use nakamoto_common::bitcoin::blockdata::constants;
+    use nakamoto_common::bitcoin::{Script, Transaction, TxIn, TxOut, Witness};
+
+    use super::channels::{ChannelEvent, ChannelMonitor};
+
+    let header = constants::genesis_block(nakamoto_common::bitcoin::Network::Regtest).header;
+    let tx = |input: OutPoint, script: &Script| Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: input,
+            script_sig: Script::new(),
+            sequence: 0xffffffff,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: 100_000,
+            script_pubkey: script.clone(),
+        }],
+    };
+    let connected = |height| Event::BlockConnected {
+        header,
+        block: Anchor::new(height, &header),
+    };
+    let matched = |height, transactions| Event::BlockMatched {
+        block: Anchor::new(height, &header),
+        header,
+        transactions,
+    };
+    let disconnected = |height| Event::BlockDisconnected {
+        header,
+        block: Anchor::new(height, &header),
+    };
+
+    let funding_script = Script::from(vec![0x00, 0x20, 0x01]);
+    let revoked_script = Script::from(vec![0x00, 0x20, 0x02]);
+    let funding = tx(OutPoint::default(), &funding_script);
+    let channel = OutPoint {
+        txid: funding.txid(),
+        vout: 0,
+    };
+    let mut monitor = ChannelMonitor::new(3);
+
+    assert!(monitor.watch(channel, funding_script.clone()));
+    assert!(!monitor.watch(channel, funding_script.clone()));
+    assert!(monitor.revoke_scripts(&channel, [revoked_script.clone()]));
+    assert_eq!(monitor.scripts().collect::<Vec<_>>(), vec![&funding_script]);
+
+    // The funding transaction confirms, and gains confirmations up to the final depth.
+    monitor.process(&connected(10));
+    assert_eq!(
+        monitor.process(&matched(10, vec![funding.clone()])),
+        vec![ChannelEvent::FundingConfirmed {
+            channel,
+            height: 10,
+            depth: 1
+        }]
+    );
+    for (height, depth) in [(11, 2), (12, 3)] {
+        assert_eq!(
+            monitor.process(&connected(height)),
+            vec![ChannelEvent::Confirmation {
+                channel,
+                txid: channel.txid,
+                depth
+            }]
+        );
+    }
+    assert!(monitor.process(&connected(13)).is_empty());
+
+    // The channel is closed with a revoked commitment, found after the block is connected.
+    let commitment = tx(channel, &revoked_script);
+    monitor.process(&connected(14));
+    assert_eq!(
+        monitor.process(&matched(14, vec![commitment.clone()])),
+        vec![ChannelEvent::Breach {
+            channel,
+            transaction: commitment.clone(),
+            height: 14,
+            depth: 1
+        }]
+    );
+
+    // The commitment is reverted, and a cooperative close confirms instead.
+    assert_eq!(
+        monitor.process(&disconnected(14)),
+        vec![ChannelEvent::Reverted {
+            channel,
+            txid: commitment.txid()
+        }]
+    );
+    let close = tx(channel, &Script::from(vec![0x51]));
+    monitor.process(&connected(14));
+    monitor.process(&connected(15));
+    assert_eq!(
+        monitor.process(&matched(14, vec![close.clone()])),
+        vec![ChannelEvent::Closed {
+            channel,
+            transaction: close,
+            height: 14,
+            depth: 2
+        }]
+    );
+
+    // Breaches are also detected by transaction ID.
+    let mut monitor = ChannelMonitor::default();
+    monitor.watch(channel, funding_script);
+    monitor.revoke(&channel, commitment.txid());
+    monitor.revoke_scripts(&channel, []);
+    assert!(matches!(
+        monitor.process(&matched(20, vec![commitment]))[..],
+        [ChannelEvent::Breach { height: 20, .. }]
+    ));
+    assert!(monitor.unwatch(&channel));
+}
+
+#[test]
+fn test_resume_pending_blocks() {
+    use nakamoto_common::bitcoin::hashes::Hash as _;
+    use nakamoto_common::block::{Anchor, BlockHash};
+    use p2p::event;
+    use p2p::protocol::event::Publisher as _;
+    use p2p::protocol::{FilterEvent, InventoryEvent};
+
+    use crate::{kv, rescan};
+
+    let tmp = tempfile::tempdir().unwrap();
+    let mut rng = fastrand::Rng::new();
+    let block = gen::block(&Network::Regtest.genesis(), &mut rng);
+    let hash = BlockHash::hash(&[11]);
+
+    // A rescan was interrupted after matching block #11, and processing filters up to #12.
+    let mut task = rescan::Task::new(1, 10, None, vec![gen::script(&mut rng)]);
+    task.current = 11;
+    task.next = 13;
+    task.pending = vec![(11, hash)];
+
+    let mut rescans = rescan::Shared::default();
+    let mut tasks = rescan::Tasks::open(
+        kv::Store::open(tmp.path().join("meta.db"))
+            .unwrap()
+            .namespace("rescans"),
+    )
+    .unwrap();
+    tasks.insert(task).unwrap();
+    rescans.set(tasks);
+
+    let mut mapper = Mapper::with_rescans(rescans.clone());
+    let (mut publisher, subscriber) = event::broadcast(move |e, p| mapper.process(e, p));
+    let events = subscriber.subscribe();
+    let mut publish = |event: protocol::Event| {
+        rescans.publish(event.clone());
+        publisher.broadcast(event);
+    };
+
+    // On restart, the rescan resumes after the last processed filter, and the pending
+    // block is fetched again.
+    publish(protocol::Event::Filter(FilterEvent::RescanStarted {
+        id: 1,
+        start: 13,
+        end: None,
+    }));
+    publish(protocol::Event::Filter(FilterEvent::FilterProcessed {
+        block: Anchor {
+            height: 13,
+            hash: BlockHash::hash(&[13]),
+            parent: BlockHash::hash(&[12]),
+        },
+        matched: false,
+        valid: true,
+        cached: false,
+    }));
+    assert_matches!(events.try_recv(), Ok(Event::FilterProcessed { .. }));
+    assert!(
+        events.try_recv().is_err(),
+        "We're not synced until the pending block is processed"
+    );
+
+    publish(protocol::Event::Inventory(InventoryEvent::BlockProcessed {
+        block,
+        height: 11,
+        fees: None,
+    }));
+    assert_matches!(
+        events.try_recv(),
+        Ok(Event::BlockMatched { block, .. }) if block.height == 11
+    );
+    assert_matches!(events.try_recv(), Ok(Event::Synced { height: 13, .. }));
+}
+
+#[test]
+fn test_resynced_event() {
+    use nakamoto_common::bitcoin::hashes::Hash as _;
+    use nakamoto_common::block::time::LocalDuration;
+    use nakamoto_common::block::BlockHash;
+    use p2p::event;
+    use p2p::protocol::event::Publisher as _;
+    use p2p::protocol::ResyncEvent;
+
+    let mut mapper = Mapper::new();
+    let (mut publisher, subscriber) = event::broadcast(move |e, p| mapper.process(e, p));
+    let events = subscriber.subscribe();
+    let peer = ([44, 44, 44, 44], 8333).into();
+    let tip = BlockHash::hash(&[42]);
+
+    publisher.broadcast(protocol::Event::Resync(ResyncEvent::Resynced {
+        peer,
+        offline: LocalDuration::from_mins(30),
+        attempts: 3,
+        from: 40,
+        height: 42,
+        tip,
+        reverted: 1,
+        connected: 3,
+        from_filter_height: 40,
+        filter_height: 42,
+    }));
+    assert_matches!(
+        events.try_recv(),
+        Ok(Event::Resynced { peer: p, height: 42, tip: t, reverted: 1, connected: 3, .. })
+        if p == peer && t == tip
+    );
+}
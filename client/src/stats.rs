@@ -0,0 +1,188 @@
+//! Header chain statistics.
+//!
+//! Statistics are computed from block headers alone, per difficulty epoch, ie. per retarget
+//! period of the network, of `2016` blocks on mainnet. They are useful for dashboards, and as a sanity check
+//! that a synced chain looks like the network it is supposed to be, eg. that mainnet blocks
+//! are ten minutes apart on average. See [`Handle::chain_stats`].
+//!
+//! Hashrate estimates are derived from the work of the blocks in a span of the chain and
+//! the time it took to mine them, as reported by block timestamps, which miners are free to
+//! skew. Estimates over short spans are therefore very noisy.
+//!
+//! [`Handle::chain_stats`]: crate::handle::Handle::chain_stats
+use std::ops::RangeInclusive;
+
+use nakamoto_common::bitcoin::blockdata::block::BlockHeader;
+use nakamoto_common::bitcoin::consensus::params::Params;
+use nakamoto_common::block::tree::BlockReader;
+use nakamoto_common::block::{Bits, Height, Target};
+
+/// Statistics of a range of the header chain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainStats {
+    /// Heights of the blocks covered. Clamped to the active chain.
+    pub heights: RangeInclusive<Height>,
+    /// Difficulty epochs overlapping the range, oldest first. The first and last epochs
+    /// may only be partly covered.
+    pub epochs: Vec<Epoch>,
+    /// Average time between blocks over the range, in seconds.
+    pub interval: f64,
+    /// Estimated network hashrate over the range, in hashes per second.
+    pub hashrate: f64,
+}
+
+/// Statistics of a difficulty epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Epoch {
+    /// Height of the first block of the epoch, ie. the block at which the difficulty was
+    /// last adjusted.
+    pub start: Height,
+    /// Heights of the blocks of the epoch covered by the statistics.
+    pub heights: RangeInclusive<Height>,
+    /// Difficulty target of the epoch, in compact form, as set by its first block.
+    /// On test networks, some blocks may be mined at the minimum difficulty instead.
+    pub bits: Bits,
+    /// Difficulty of the epoch, relative to the lowest possible difficulty.
+    pub difficulty: f64,
+    /// Average time between blocks, in seconds.
+    pub interval: f64,
+    /// Estimated network hashrate, in hashes per second.
+    pub hashrate: f64,
+}
+
+impl ChainStats {
+    /// Compute the statistics of a range of the active chain. Returns `None` if the range
+    /// is empty, or starts above the tip.
+    pub fn compute(tree: &dyn BlockReader, range: RangeInclusive<Height>) -> Option<Self> {
+        let params = tree.params();
+        let epoch_length = params.difficulty_adjustment_interval();
+        let (start, end) = (*range.start(), (*range.end()).min(tree.height()));
+
+        if start > end || epoch_length == 0 {
+            return None;
+        }
+        let mut epochs = Vec::new();
+        let mut from = start;
+
+        while from <= end {
+            let epoch = from - from % epoch_length;
+            let to = (epoch + epoch_length - 1).min(end);
+            let bits = tree.get_block_by_height(epoch)?.bits;
+            let (interval, hashrate) = self::span(tree, from..=to)?;
+
+            epochs.push(Epoch {
+                start: epoch,
+                heights: from..=to,
+                bits,
+                difficulty: self::difficulty(bits, params),
+                interval,
+                hashrate,
+            });
+            from = to + 1;
+        }
+        let (interval, hashrate) = self::span(tree, start..=end)?;
+
+        Some(Self {
+            heights: start..=end,
+            epochs,
+            interval,
+            hashrate,
+        })
+    }
+}
+
+/// Get the difficulty of a compact target, relative to the network's proof-of-work limit.
+/// Like the target, the limit is taken in its compact form, eg. `0x1d00ffff` on mainnet.
+pub fn difficulty(bits: Bits, params: &Params) -> f64 {
+    let max = BlockHeader::compact_target_from_u256(&params.pow_limit);
+    let max = self::to_f64(BlockHeader::u256_from_compact_target(max));
+    let target = self::to_f64(BlockHeader::u256_from_compact_target(bits));
+
+    max / target
+}
+
+/// Compute the average block interval and hashrate of a span of blocks. The time spent
+/// mining the first block is measured from its parent, if any.
+fn span(tree: &dyn BlockReader, heights: RangeInclusive<Height>) -> Option<(f64, f64)> {
+    let (start, end) = (*heights.start(), *heights.end());
+    let first = start.saturating_sub(1);
+    let elapsed =
+        tree.get_block_by_height(end)?.time as i64 - tree.get_block_by_height(first)?.time as i64;
+    let blocks = end - first;
+
+    if blocks == 0 {
+        // Only the genesis block was requested, there is no interval to measure.
+        return Some((0., 0.));
+    }
+    let work = tree.chain_work(end) - tree.chain_work(first);
+    // Nb. Timestamps aren't monotonic, so the elapsed time may not be positive.
+    let elapsed = elapsed.max(1) as f64;
+
+    Some((elapsed / blocks as f64, self::to_f64(work) / elapsed))
+}
+
+/// Convert a 256-bit integer to a float.
+fn to_f64(n: Target) -> f64 {
+    n.0.iter()
+        .rev()
+        .fold(0., |acc, word| acc * 2f64.powi(64) + *word as f64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use nakamoto_chain::block::cache::BlockCache;
+    use nakamoto_chain::block::store;
+    use nakamoto_common::network::Network;
+    use nakamoto_test::BITCOIN_HEADERS;
+
+    #[test]
+    fn test_difficulty() {
+        let params = Network::Mainnet.params();
+
+        assert_eq!(difficulty(0x1d00ffff, &params), 1.);
+        // Difficulty of the first mainnet retarget, at height 32256.
+        assert!((difficulty(0x1d00d86a, &params) - 1.18).abs() < 0.01);
+        // The easiest regtest target.
+        assert_eq!(difficulty(0x207fffff, &Network::Regtest.params()), 1.);
+    }
+
+    #[test]
+    fn test_chain_stats() {
+        // Retarget every ten blocks.
+        let params = Params {
+            pow_target_timespan: 10 * 600,
+            ..Network::Mainnet.params()
+        };
+        let store = store::Memory::new(BITCOIN_HEADERS.clone());
+        let tree = BlockCache::from(store, params, &[]).unwrap();
+        let height = tree.height();
+
+        assert_eq!(ChainStats::compute(&tree, height + 1..=height + 2), None);
+
+        let stats = ChainStats::compute(&tree, 0..=height + 1).unwrap();
+        assert_eq!(stats.heights, 0..=height);
+        assert_eq!(stats.epochs.len() as Height, height / 10 + 1);
+        assert_eq!(stats.epochs[1].start, 10);
+        assert_eq!(stats.epochs[1].heights, 10..=19);
+        assert!(stats.epochs.iter().all(|e| e.difficulty == 1.));
+
+        // Early mainnet blocks were mined by a handful of CPUs, at a difficulty of one,
+        // ie. about 2^32 hashes per block.
+        let expected = 2f64.powi(32) / stats.interval;
+        assert!((stats.hashrate - expected).abs() / expected < 0.01);
+
+        // Partial epochs.
+        let stats = ChainStats::compute(&tree, 15..=25).unwrap();
+        assert_eq!(
+            stats
+                .epochs
+                .iter()
+                .map(|e| e.heights.clone())
+                .collect::<Vec<_>>(),
+            vec![15..=19, 20..=25]
+        );
+        assert_eq!(stats.epochs[0].start, 10);
+    }
+}
@@ -1,8 +1,14 @@
+#[cfg(all(test, feature = "bitcoind"))]
+mod bitcoind;
 #[cfg(test)]
 pub mod mock;
+#[cfg(test)]
+mod reorg;
 
 use std::collections::HashMap;
 use std::net;
+use std::ops::Bound;
+use std::path::Path;
 use std::thread;
 use std::time;
 
@@ -11,18 +17,100 @@ use nakamoto_chain::block::store;
 use nakamoto_chain::filter::cache::FilterCache;
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::block::time::AdjustedTime;
-use nakamoto_common::block::Height;
+use nakamoto_common::block::{Anchor, Height};
 use nakamoto_common::network::Services;
 use nakamoto_p2p::protocol;
 use nakamoto_p2p::protocol::Protocol;
 use nakamoto_test::{logger, BITCOIN_HEADERS};
 
-use crate::client::{self, event, Client, Config};
+use crate::client::{self, chan, event, Client, Config};
 use crate::error;
 use crate::handle::Handle as _;
+use crate::snapshot::Snapshot;
 
 type Reactor = nakamoto_net_poll::Reactor<net::TcpStream, client::Publisher>;
 
+/// Configuration of a client storing its data in the given directory, without listening
+/// for or connecting to peers.
+fn config(dir: impl AsRef<Path>) -> Config {
+    Config {
+        root: dir.as_ref().to_path_buf(),
+        listen: vec![],
+        protocol: protocol::Config {
+            // Avoid bootstrapping from DNS seeds.
+            connect: vec![([127, 0, 0, 1], 1).into()],
+            ..protocol::Config::default()
+        },
+        ..Config::default()
+    }
+}
+
+/// A reactor that panics on its first `FAILURES` runs, after running for `UPTIME_MS`
+/// milliseconds. Since reactors are constructed by the client, the failure schedule is
+/// set through type parameters.
+struct FailingReactor<const FAILURES: usize, const UPTIME_MS: u64> {
+    inner: Reactor,
+    runs: usize,
+}
+
+impl<const FAILURES: usize, const UPTIME_MS: u64> FailingReactor<FAILURES, UPTIME_MS> {
+    /// Number of runs that fail.
+    const FAILURES: usize = FAILURES;
+    /// How long failing runs last.
+    const UPTIME: time::Duration = time::Duration::from_millis(UPTIME_MS);
+}
+
+/// A reactor that panics the first time it is run.
+type FlakyReactor = FailingReactor<1, 0>;
+/// A reactor that fails its first few runs, after running for a while.
+type UnstableReactor = FailingReactor<3, 100>;
+
+impl<const FAILURES: usize, const UPTIME_MS: u64> client::Reactor<client::Publisher>
+    for FailingReactor<FAILURES, UPTIME_MS>
+{
+    type Waker = <Reactor as client::Reactor<client::Publisher>>::Waker;
+
+    fn new(
+        publisher: client::Publisher,
+        commands: chan::Receiver<client::Command>,
+        shutdown: chan::Receiver<()>,
+    ) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            inner: Reactor::new(publisher, commands, shutdown)?,
+            runs: 0,
+        })
+    }
+
+    fn configure(&mut self, config: client::ReactorConfig) {
+        self.inner.configure(config)
+    }
+
+    fn run<P: nakamoto_p2p::traits::Protocol>(
+        &mut self,
+        listen_addrs: &[net::SocketAddr],
+        protocol: P,
+    ) -> Result<(), nakamoto_p2p::error::Error> {
+        self.runs += 1;
+        if self.runs <= Self::FAILURES {
+            thread::sleep(Self::UPTIME);
+            panic!("FailingReactor::run: reactor failure");
+        }
+        self.inner.run(listen_addrs, protocol)
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset()
+    }
+
+    fn wake(waker: &Self::Waker) -> std::io::Result<()> {
+        Reactor::wake(waker)
+    }
+
+    fn waker(&self) -> Self::Waker {
+        self.inner.waker()
+    }
+}
+
 fn network(
     cfgs: &[Config],
 ) -> Result<
@@ -80,7 +168,7 @@ fn network(
 
     for (i, (handle, _, _)) in handles.iter().enumerate() {
         for (_, peer, _) in handles.iter().skip(i + 1) {
-            handle.connect(*peer).unwrap();
+            handle.connect(*peer, false).unwrap();
         }
     }
 
@@ -118,15 +206,168 @@ fn test_full_sync() {
         .expect("command is successful")
         .expect("chain is valid");
 
-    for (mut node, _, thread) in nodes.into_iter() {
-        node.set_timeout(std::time::Duration::from_secs(5));
-        assert_eq!(node.wait_for_height(height).unwrap(), hash);
+    for (node, _, thread) in nodes.into_iter() {
+        assert_eq!(
+            node.wait_for_height(height, time::Duration::from_secs(5))
+                .unwrap(),
+            hash
+        );
 
         node.shutdown().unwrap();
         thread.join().unwrap();
     }
 }
 
+#[test]
+fn test_installed_publishers() {
+    use std::sync::{Arc, Mutex};
+
+    use protocol::event::{Callback, Publisher as _};
+
+    let (release, released) = chan::bounded::<()>(0);
+    let (sender, receiver) = chan::unbounded();
+    let (installed_sender, installed_receiver) = chan::unbounded();
+    let installed: Vec<Box<dyn protocol::event::Publisher>> =
+        vec![Box::new(Callback(move |e: &protocol::Event| {
+            // Block until released, like a publisher writing to a stalled socket.
+            released.recv().ok();
+            installed_sender.send(e.kind()).ok();
+        }))];
+    let mut publisher = client::Publisher::new(Arc::new(Mutex::new(installed)))
+        .unwrap()
+        .register(sender);
+
+    // A stalled installed publisher doesn't hold up publishing.
+    publisher.publish(protocol::Event::Initializing);
+    publisher.publish(protocol::Event::Quiesced);
+
+    assert_eq!(
+        receiver.try_iter().map(|e| e.kind()).collect::<Vec<_>>(),
+        vec!["initializing", "quiesced"]
+    );
+    assert!(installed_receiver.try_recv().is_err());
+
+    release.send(()).unwrap();
+    release.send(()).unwrap();
+
+    assert_eq!(
+        installed_receiver.iter().take(2).collect::<Vec<_>>(),
+        vec!["initializing", "quiesced"]
+    );
+}
+
+#[test]
+fn test_confirmations() {
+    use nakamoto_common::bitcoin::blockdata::constants;
+    use nakamoto_common::bitcoin::{Network, Transaction};
+
+    use crate::client::{Confirmations, Event};
+    use crate::spv::TxStatus;
+
+    let genesis = constants::genesis_block(Network::Regtest);
+    let header = genesis.header;
+    let tx = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![],
+        output: vec![],
+    };
+    let txid = tx.txid();
+    let connected = |height| Event::BlockConnected {
+        header,
+        block: Anchor::new(height, &header),
+    };
+    let disconnected = |height| Event::BlockDisconnected {
+        header,
+        block: Anchor::new(height, &header),
+    };
+    let mut confirmations = Confirmations {
+        txid,
+        depth: 3,
+        confirmed: None,
+        tip: 9,
+    };
+
+    assert_eq!(confirmations.process(connected(10)), None);
+    assert_eq!(
+        confirmations.process(Event::TxStatusChanged {
+            txid,
+            status: TxStatus::Confirmed {
+                block: Anchor::new(10, &header),
+            },
+        }),
+        None
+    );
+    assert_eq!(confirmations.process(connected(11)), None);
+
+    // A re-org reverting the confirming block resets the count.
+    assert_eq!(confirmations.process(disconnected(11)), None);
+    assert_eq!(confirmations.process(disconnected(10)), None);
+    assert_eq!(confirmations.confirmed, None);
+    assert_eq!(confirmations.tip, 9);
+
+    // The transaction is confirmed again on the new chain, in a matching block.
+    assert_eq!(confirmations.process(connected(10)), None);
+    assert_eq!(confirmations.process(connected(11)), None);
+    assert_eq!(
+        confirmations.process(Event::BlockMatched {
+            header,
+            block: Anchor::new(11, &header),
+            transactions: vec![tx],
+        }),
+        None
+    );
+    assert_eq!(confirmations.process(connected(12)), None);
+    assert_eq!(
+        confirmations.process(connected(13)),
+        Some((11, header.block_hash()))
+    );
+
+    // Other transactions are ignored.
+    let mut confirmations = Confirmations {
+        txid,
+        depth: 1,
+        confirmed: None,
+        tip: 9,
+    };
+    assert_eq!(
+        confirmations.process(Event::TxStatusChanged {
+            txid: genesis.txdata[0].txid(),
+            status: TxStatus::Confirmed {
+                block: Anchor::new(9, &header),
+            },
+        }),
+        None
+    );
+}
+
+#[test]
+fn test_wait_for_confirmation_depth() {
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+
+    assert!(matches!(
+        handle.wait_for_confirmation(Default::default(), 0, time::Duration::from_secs(1)),
+        Err(crate::handle::Error::InvalidArgument(_))
+    ));
+}
+
+#[test]
+fn test_rescan_empty_range() {
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+
+    for range in [
+        (Bound::Included(0), Bound::Excluded(0)),
+        (Bound::Included(5), Bound::Included(3)),
+    ] {
+        assert!(matches!(
+            handle.rescan(range, std::iter::empty()),
+            Err(crate::handle::Error::InvalidArgument(_))
+        ));
+    }
+}
+
 #[test]
 fn test_wait_for_peers() {
     logger::init(log::Level::Debug);
@@ -152,13 +393,189 @@ fn test_wait_for_peers() {
     assert_eq!(peers.len(), nodes.len() - 1);
 }
 
+#[test]
+fn test_wait_for_filters() {
+    let mut nodes = network(&[Config::default()]).unwrap();
+    let (handle, _, thread) = nodes.pop().unwrap();
+
+    assert_eq!(
+        handle
+            .wait_for_filters(0, time::Duration::from_secs(5))
+            .unwrap(),
+        0
+    );
+    assert!(matches!(
+        handle.wait_for_filters(1, time::Duration::from_millis(100)),
+        Err(crate::handle::Error::Timeout)
+    ));
+    assert!(matches!(
+        handle.wait_for_height(1, time::Duration::from_millis(100)),
+        Err(crate::handle::Error::Timeout)
+    ));
+
+    handle.shutdown().unwrap();
+    thread.join().unwrap();
+}
+
+#[test]
+fn test_verify_merkle_block() {
+    use nakamoto_common::bitcoin::blockdata::constants::genesis_block;
+    use nakamoto_common::bitcoin::util::merkleblock::MerkleBlock;
+
+    let cfg = Config {
+        protocol: protocol::Config {
+            headers_only: true,
+            ..protocol::Config::default()
+        },
+        ..Config::default()
+    };
+    let mut nodes = network(std::slice::from_ref(&cfg)).unwrap();
+    let (handle, _, thread) = nodes.pop().unwrap();
+
+    let genesis = genesis_block(cfg.protocol.network.into());
+    let txid = genesis.txdata[0].txid();
+    let proof = MerkleBlock::from_block_with_predicate(&genesis, |t| *t == txid);
+
+    assert_eq!(handle.verify_merkle_block(&proof).unwrap(), (0, vec![txid]));
+
+    // Blocks outside of the active chain can't be verified.
+    let mut unknown = proof;
+    unknown.header.nonce += 1;
+
+    assert!(matches!(
+        handle.verify_merkle_block(&unknown),
+        Err(crate::handle::Error::BlockNotFound(_))
+    ));
+
+    handle.shutdown().unwrap();
+    thread.join().unwrap();
+}
+
+#[test]
+fn test_fleet() {
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    use nakamoto_common::bitcoin::secp256k1::{KeyPair, Secp256k1, SecretKey, XOnlyPublicKey};
+    use nakamoto_common::block::BlockHash;
+
+    use crate::fleet::{Divergence, Leader, Member, SignedTip, Tip};
+
+    let secp = Secp256k1::new();
+    let key = SecretKey::from_slice(&[1; 32]).unwrap();
+    let public = XOnlyPublicKey::from_keypair(&KeyPair::from_secret_key(&secp, key));
+    let mut nodes = network(&[Config::default()]).unwrap();
+    let (handle, _, thread) = nodes.pop().unwrap();
+    let (_, genesis) = handle.get_tip().unwrap();
+
+    // The leader publishes its signed tip.
+    let leader = Leader::bind(
+        ([127, 0, 0, 1], 0).into(),
+        key,
+        time::Duration::from_millis(10),
+    )
+    .unwrap();
+    let leader_addr = leader.local_addr().unwrap();
+    let stream = net::TcpStream::connect(leader_addr).unwrap();
+    leader.spawn(handle.clone());
+
+    let line = BufReader::new(stream).lines().next().unwrap().unwrap();
+    let signed = SignedTip::from_json(&line).unwrap();
+
+    signed.verify(&public).unwrap();
+    assert_eq!(signed.tip.height, 0);
+    assert_eq!(signed.tip.hash, genesis.block_hash());
+
+    // Members report tips that aren't in their active chain.
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let (events, receiver) = chan::unbounded();
+    Member::new(listener.local_addr().unwrap(), public, 0).spawn(handle.clone(), move |e| {
+        events.send(e).ok();
+    });
+
+    let (mut stream, _) = listener.accept().unwrap();
+    let fork = Tip {
+        height: 0,
+        hash: BlockHash::default(),
+        ..signed.tip
+    };
+    let forged = SignedTip::sign(
+        fork,
+        signed.time + 1,
+        &SecretKey::from_slice(&[2; 32]).unwrap(),
+    );
+    let valid = SignedTip::sign(fork, signed.time + 2, &key);
+
+    for tip in [forged, valid] {
+        stream.write_all((tip.to_json() + "\n").as_bytes()).unwrap();
+    }
+
+    match receiver.recv_timeout(time::Duration::from_secs(5)).unwrap() {
+        client::Event::TipDiverged {
+            leader,
+            tip,
+            divergence,
+        } => {
+            assert_eq!(leader, fork);
+            assert_eq!(tip.hash, genesis.block_hash());
+            assert_eq!(divergence, Divergence::Fork);
+        }
+        other => panic!("unexpected event {:?}", other),
+    }
+    // Only the validly signed tip is reported.
+    assert!(receiver
+        .recv_timeout(time::Duration::from_millis(100))
+        .is_err());
+
+    // Members disconnect from leaders sending over-long lines.
+    stream
+        .write_all(&[b'a'; crate::fleet::MAX_LINE_SIZE + 1])
+        .unwrap();
+    stream
+        .set_read_timeout(Some(time::Duration::from_secs(5)))
+        .unwrap();
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+
+    handle.shutdown().unwrap();
+    thread.join().unwrap();
+
+    // The leader stops listening once the client stops.
+    let deadline = time::Instant::now() + time::Duration::from_secs(5);
+    while net::TcpStream::connect(leader_addr).is_ok() {
+        assert!(
+            time::Instant::now() < deadline,
+            "the leader is still listening"
+        );
+        thread::sleep(time::Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_wait_for_confirmation() {
+    use nakamoto_common::bitcoin::Txid;
+
+    let mut nodes = network(&[Config::default()]).unwrap();
+    let (handle, _, thread) = nodes.pop().unwrap();
+
+    // Unknown transactions are never confirmed.
+    assert!(matches!(
+        handle.wait_for_confirmation(Txid::default(), 1, time::Duration::from_millis(100)),
+        Err(crate::handle::Error::Timeout)
+    ));
+    assert_eq!(handle.get_spv_proof(Txid::default()).unwrap(), None);
+
+    handle.shutdown().unwrap();
+    thread.join().unwrap();
+}
+
 #[test]
 fn test_send_handle() {
     let client: Client<Reactor> = Client::new().unwrap();
     let handle = client.handle();
 
     thread::spawn(move || {
-        handle.wait_for_height(1).unwrap();
+        handle
+            .wait_for_height(1, time::Duration::from_secs(60))
+            .unwrap();
     });
 }
 
@@ -214,6 +631,72 @@ fn test_multiple_handle_events() {
     .unwrap();
 }
 
+#[test]
+fn test_concurrent_handles() {
+    fn assert_shareable<T: Clone + Send + Sync + 'static>(_: &T) {}
+
+    let cfg = protocol::Config::default();
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+    let store = store::Memory::new((genesis, vec![]).into());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let filters = FilterCache::from(store::Memory::default()).unwrap();
+    let headers = BITCOIN_HEADERS.tail[..32].to_vec();
+    let synced = headers.len() as Height;
+
+    assert_shareable(&handle);
+
+    let events = (0..8).map(|_| handle.events()).collect::<Vec<_>>();
+    let threads = events
+        .into_iter()
+        .map(|events| {
+            let handle = handle.clone();
+
+            thread::spawn(move || {
+                let height = event::wait(
+                    &events,
+                    |e| match e {
+                        protocol::Event::Chain(protocol::ChainEvent::Synced(_, height))
+                            if height == synced =>
+                        {
+                            Some(height)
+                        }
+                        _ => None,
+                    },
+                    time::Duration::from_secs(4),
+                )
+                .unwrap();
+
+                for _ in 0..16 {
+                    let (tip, _) = handle.get_tip().unwrap();
+                    assert_eq!(tip, height);
+                }
+                height
+            })
+        })
+        .collect::<Vec<_>>();
+
+    thread::spawn(|| {
+        let local_time = time::SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+        let rng = fastrand::Rng::new();
+
+        client.run_with(
+            vec![],
+            Protocol::new(cache, filters, HashMap::new(), clock, rng, cfg),
+        )
+    });
+
+    // Every handle receives its own copy of the events triggered by this import.
+    handle.import_headers(headers).unwrap().unwrap();
+
+    for th in threads {
+        assert_eq!(th.join().unwrap(), synced);
+    }
+}
+
 #[test]
 fn test_handle_shutdown() {
     let cfg = protocol::Config::default();
@@ -254,6 +737,61 @@ fn test_client_dropped() {
     ));
 }
 
+#[test]
+fn test_handle_deadline() {
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+
+    // The client isn't running, so the query is never answered.
+    assert!(matches!(
+        handle
+            .with_deadline(time::Duration::from_millis(50))
+            .get_tip(),
+        Err(client::handle::Error::Timeout)
+    ));
+
+    // Once the client is gone, pending queries fail instead of blocking forever.
+    let th = thread::spawn(move || handle.get_tip());
+    thread::sleep(time::Duration::from_millis(50));
+    drop(client);
+
+    assert!(matches!(
+        th.join().unwrap(),
+        Err(client::handle::Error::Disconnected)
+    ));
+}
+
+#[test]
+fn test_handle_shutting_down() {
+    let cfg = protocol::Config::default();
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+    let store = store::Memory::new((genesis, vec![]).into());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let filters = FilterCache::from(store::Memory::default()).unwrap();
+
+    let th = thread::spawn(|| {
+        let local_time = time::SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+        let rng = fastrand::Rng::new();
+
+        client.run_with(
+            vec![],
+            Protocol::new(cache, filters, HashMap::new(), clock, rng, cfg),
+        )
+    });
+    handle.get_tip().unwrap();
+    handle.clone().shutdown().unwrap();
+
+    assert!(matches!(
+        handle.get_tip(),
+        Err(client::handle::Error::ShuttingDown)
+    ));
+    th.join().unwrap().unwrap();
+}
+
 #[test]
 fn test_query_headers() {
     let cfg = protocol::Config::default();
@@ -290,4 +828,534 @@ fn test_query_headers() {
 
     assert_eq!(header, BITCOIN_HEADERS.tail.first().cloned());
     assert!(found);
+
+    let anchor = Anchor::new(height, &BITCOIN_HEADERS.tail[0]);
+    assert!(handle.is_anchored(anchor).unwrap());
+    assert!(!handle
+        .is_anchored(Anchor {
+            height: height + 1,
+            ..anchor
+        })
+        .unwrap());
+}
+
+#[test]
+fn test_client_set() {
+    use nakamoto_common::network::Network;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let mut set = crate::ClientSet::<Reactor>::new();
+    let network_config = |network| {
+        let mut cfg = config(tmp.path());
+
+        cfg.protocol.network = network;
+        cfg
+    };
+
+    let testnet = set.spawn(network_config(Network::Testnet)).unwrap();
+    let regtest = set.spawn(network_config(Network::Regtest)).unwrap();
+
+    assert!(matches!(
+        set.spawn(network_config(Network::Testnet)),
+        Err(error::Error::AlreadyRunning(Network::Testnet))
+    ));
+    assert_eq!(set.networks().count(), 2);
+
+    for (handle, network) in [(testnet, Network::Testnet), (regtest, Network::Regtest)] {
+        let (height, tip) = handle.get_tip().unwrap();
+
+        assert_eq!(height, 0);
+        assert_eq!(tip, network.genesis());
+        assert!(tmp.path().join(".nakamoto").join(network.as_str()).exists());
+    }
+    set.shutdown().unwrap();
+}
+
+#[test]
+fn test_trusted_peer_identity_unsupported() {
+    use nakamoto_common::network::Network;
+    use nakamoto_p2p::protocol::Fingerprint;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let mut cfg = Config::new(Network::Regtest);
+
+    cfg.root = tmp.path().to_path_buf();
+    cfg.protocol.trusted_peer = Some(([127, 0, 0, 1], 18444).into());
+    cfg.protocol.trusted_peer_identity = Some(Fingerprint::new([1; 32]));
+
+    // The poll reactor doesn't authenticate peers.
+    assert!(matches!(
+        Client::<Reactor>::new().unwrap().run(cfg),
+        Err(error::Error::Config(
+            crate::config::Error::TrustedPeerIdentityUnsupported
+        ))
+    ));
+    assert!(!tmp.path().join(".nakamoto").exists());
+}
+
+#[test]
+fn test_data_dir_lock() {
+    use nakamoto_common::network::Network;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let mut cfg = config(tmp.path());
+
+    cfg.protocol.network = Network::Regtest;
+
+    let client = Client::<Reactor>::new().unwrap();
+    let handle = client.handle();
+    let thread = thread::spawn({
+        let cfg = cfg.clone();
+        move || client.run(cfg)
+    });
+    // Wait for the client to be running.
+    handle.get_tip().unwrap();
+
+    // A second client can't use the same data directory.
+    assert!(matches!(
+        Client::<Reactor>::new().unwrap().run(cfg.clone()),
+        Err(error::Error::AlreadyRunning(Network::Regtest))
+    ));
+
+    handle.shutdown().unwrap();
+    thread.join().unwrap().unwrap();
+
+    // Once the first client exits, the data directory can be used again.
+    let client = Client::<Reactor>::new().unwrap();
+    let handle = client.handle();
+    let thread = thread::spawn(move || client.run(cfg));
+
+    handle.get_tip().unwrap();
+    handle.shutdown().unwrap();
+    thread.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_snapshot_export_import() {
+    let tmp = tempfile::tempdir().unwrap();
+    let path = tmp.path().join("snapshot");
+    let headers = BITCOIN_HEADERS.tail.clone();
+    let height = headers.len() as Height;
+
+    let spawn = |name: &str| {
+        let cfg = config(tmp.path().join(name));
+
+        let client = Client::<Reactor>::new().unwrap();
+        let handle = client.handle();
+        let thread = thread::spawn(move || client.run(cfg).unwrap());
+
+        (handle, thread)
+    };
+
+    let (alice, alice_thread) = spawn("alice");
+    alice
+        .import_headers(headers)
+        .expect("command is successful")
+        .expect("chain is valid");
+
+    assert_eq!(alice.export_snapshot(&path).unwrap(), height);
+
+    let (bob, bob_thread) = spawn("bob");
+    let empty = tmp.path().join("empty");
+
+    // An empty snapshot imports nothing.
+    Snapshot::default().save(&empty).unwrap();
+    assert_eq!(bob.import_snapshot(&empty).unwrap(), 0);
+    assert_eq!(bob.import_snapshot(&path).unwrap(), height);
+    assert_eq!(bob.get_tip().unwrap(), alice.get_tip().unwrap());
+
+    for (handle, thread) in [(alice, alice_thread), (bob, bob_thread)] {
+        handle.shutdown().unwrap();
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn test_event_journal() {
+    let tmp = tempfile::tempdir().unwrap();
+    let headers = BITCOIN_HEADERS.tail.clone();
+    let height = headers.len() as Height;
+    let cfg = Config {
+        journal: true,
+        ..config(tmp.path())
+    };
+
+    let client = Client::<Reactor>::new().unwrap();
+    let handle = client.handle();
+    let events = handle.events();
+    let thread = thread::spawn(move || client.run(cfg).unwrap());
+
+    handle
+        .import_headers(headers)
+        .expect("command is successful")
+        .expect("chain is valid");
+
+    // Events are journaled in order, so once the chain is synced, all blocks are journaled.
+    event::wait(
+        &events,
+        |e| match e {
+            protocol::Event::Chain(protocol::ChainEvent::Synced(_, h)) if h == height => Some(()),
+            _ => None,
+        },
+        time::Duration::from_secs(5),
+    )
+    .unwrap();
+
+    let entries = handle.replay_from(0).unwrap();
+    assert_eq!(entries.len(), height as usize);
+    assert!(entries.iter().enumerate().all(|(i, e)| e.seq == i as u64));
+    assert_eq!(
+        handle.replay_from(height as u64 - 1).unwrap()[0].event,
+        crate::journal::Event::BlockConnected {
+            header: *BITCOIN_HEADERS.tail.last().unwrap(),
+            height,
+        }
+    );
+
+    handle.shutdown().unwrap();
+    thread.join().unwrap();
+}
+
+#[test]
+fn test_headers_only() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut cfg = config(tmp.path());
+
+    cfg.protocol.headers_only = true;
+    let dir = tmp
+        .path()
+        .join(".nakamoto")
+        .join(cfg.protocol.network.as_str());
+
+    let client = Client::<Reactor>::new().unwrap();
+    let handle = client.handle();
+    let thread = thread::spawn(move || client.run(cfg).unwrap());
+
+    handle
+        .import_headers(BITCOIN_HEADERS.tail.clone())
+        .expect("command is successful")
+        .expect("chain is valid");
+
+    assert!(matches!(
+        handle.rescan(0.., std::iter::empty()),
+        Err(crate::handle::Error::GetFilters(
+            protocol::GetFiltersError::Disabled
+        ))
+    ));
+    assert!(dir.join("headers.db").exists());
+    assert!(!dir.join("filters.db").exists());
+
+    handle.shutdown().unwrap();
+    thread.join().unwrap();
+}
+
+#[test]
+fn test_rescan_tasks() {
+    use crate::rescan::Status;
+    use nakamoto_test::block::gen;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let mut rng = fastrand::Rng::new();
+    let watch = vec![gen::script(&mut rng)];
+
+    let spawn = || {
+        let cfg = config(tmp.path());
+        let client = Client::<Reactor>::new().unwrap();
+        let handle = client.handle();
+        let events = handle.events();
+        let thread = thread::spawn(move || client.run(cfg).unwrap());
+
+        event::wait(
+            &events,
+            |e| match e {
+                protocol::Event::Ready { .. } => Some(()),
+                _ => None,
+            },
+            time::Duration::from_secs(5),
+        )
+        .unwrap();
+
+        (handle, events, thread)
+    };
+    let wait = |events: &chan::Receiver<protocol::Event>, f: fn(&protocol::FilterEvent) -> bool| {
+        event::wait(
+            events,
+            |e| match e {
+                protocol::Event::Filter(e) if f(&e) => Some(()),
+                _ => None,
+            },
+            time::Duration::from_secs(5),
+        )
+        .unwrap()
+    };
+
+    let (handle, events, thread) = spawn();
+    let id = handle.rescan(5.., watch.clone().into_iter()).unwrap();
+    wait(&events, |e| {
+        matches!(e, protocol::FilterEvent::RescanStarted { start: 5, .. })
+    });
+
+    let tasks = handle.rescans().unwrap();
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(tasks[0].id, id);
+    assert_eq!(tasks[0].watch, watch);
+    assert_eq!(tasks[0].status, Status::Running);
+
+    handle.shutdown().unwrap();
+    thread.join().unwrap();
+
+    // The interrupted rescan is resumed when the client restarts.
+    let (handle, events, thread) = spawn();
+    wait(&events, |e| {
+        matches!(e, protocol::FilterEvent::RescanStarted { start: 5, .. })
+    });
+    handle.pause_rescan(id).unwrap();
+    wait(&events, |e| {
+        matches!(e, protocol::FilterEvent::RescanPaused { .. })
+    });
+    assert_eq!(handle.rescans().unwrap()[0].status, Status::Paused);
+
+    handle.shutdown().unwrap();
+    thread.join().unwrap();
+
+    // Paused rescans can be resumed after a restart, and canceled.
+    let (handle, events, thread) = spawn();
+    assert_eq!(handle.rescans().unwrap()[0].status, Status::Paused);
+
+    handle.resume_rescan(id).unwrap();
+    wait(&events, |e| {
+        matches!(e, protocol::FilterEvent::RescanStarted { start: 5, .. })
+    });
+    assert_eq!(handle.rescans().unwrap()[0].status, Status::Running);
+
+    handle.cancel_rescan(id).unwrap();
+    wait(&events, |e| {
+        matches!(e, protocol::FilterEvent::RescanCanceled { .. })
+    });
+    assert!(handle.rescans().unwrap().is_empty());
+
+    // Canceled rescans can't be resumed.
+    assert!(matches!(
+        handle.resume_rescan(id),
+        Err(crate::handle::Error::Rescan(crate::rescan::Error::Ended(other))) if other == id
+    ));
+
+    handle.shutdown().unwrap();
+    thread.join().unwrap();
+}
+
+#[test]
+fn test_birth_height() {
+    let cfg = protocol::Config::default();
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+    let headers = BITCOIN_HEADERS.tail.clone();
+    let store = store::Memory::new((genesis, headers.clone()).into());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let filters = FilterCache::from(store::Memory::default()).unwrap();
+
+    thread::spawn(|| {
+        let local_time = time::SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+        let rng = fastrand::Rng::new();
+
+        client.run_with(
+            vec![],
+            Protocol::new(cache, filters, HashMap::new(), clock, rng, cfg),
+        )
+    });
+
+    // Blocks are 1-indexed in `headers`, since the genesis is not included.
+    let height = 100;
+    let birthday = headers[height - 1].time;
+    let birth = handle.birth_height(birthday).unwrap();
+
+    assert!(birth < height as Height);
+    assert!(headers[birth as usize..]
+        .iter()
+        .all(|h| h.time + 60 * 60 * 4 > birthday));
+    assert_eq!(handle.birth_height(genesis.time).unwrap(), 0);
+    assert_eq!(
+        handle.birth_height(u32::MAX).unwrap(),
+        headers.len() as Height
+    );
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async_handle() {
+    use futures::executor::block_on;
+    use futures::StreamExt as _;
+
+    use crate::asynchronous::AsyncHandle;
+
+    let cfg = protocol::Config::default();
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = AsyncHandle::new(client.handle());
+    let store = store::Memory::new((genesis, vec![]).into());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let filters = FilterCache::from(store::Memory::default()).unwrap();
+    let headers = BITCOIN_HEADERS.tail[..8].to_vec();
+    let mut events = handle.events();
+
+    thread::spawn(|| {
+        let local_time = time::SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+        let rng = fastrand::Rng::new();
+
+        client.run_with(
+            vec![],
+            Protocol::new(cache, filters, HashMap::new(), clock, rng, cfg),
+        )
+    });
+
+    block_on(async {
+        handle.handle().import_headers(headers).unwrap().unwrap();
+
+        while let Some(event) = events.next().await {
+            if let protocol::Event::Chain(protocol::ChainEvent::Synced(_, 8)) = event {
+                break;
+            }
+        }
+        assert_eq!(handle.get_tip().await.unwrap().0, 8);
+
+        // Without any peers, the rescan only ends once it is canceled.
+        let mut rescan = handle.rescan(1..=8, vec![]).await.unwrap();
+        handle.cancel_rescan(rescan.id).await.unwrap();
+
+        let mut last = None;
+        while let Some(event) = rescan.next().await {
+            last = Some(event);
+        }
+        assert!(matches!(
+            last,
+            Some(protocol::FilterEvent::RescanCanceled { id, .. }) if id == rescan.id
+        ));
+    });
+}
+
+#[test]
+fn test_reactor_restart() {
+    let tmp = tempfile::tempdir().unwrap();
+    let cfg = config(tmp.path());
+
+    let client = Client::<FlakyReactor>::new().unwrap();
+    let handle = client.handle();
+    let events = handle.subscribe();
+    let thread = thread::spawn({
+        let cfg = cfg.clone();
+        move || client.run(cfg)
+    });
+
+    event::wait(
+        &events,
+        |e| match e {
+            client::Event::Restarted { restarts: 1, .. } => Some(()),
+            _ => None,
+        },
+        time::Duration::from_secs(5),
+    )
+    .unwrap();
+    assert_eq!(handle.get_tip().unwrap().0, 0);
+
+    handle.shutdown().unwrap();
+    thread.join().unwrap().unwrap();
+
+    // Without restarts, the reactor failure is returned.
+    let client = Client::<FlakyReactor>::new().unwrap();
+    let cfg = Config {
+        max_restarts: 0,
+        ..cfg
+    };
+    assert!(matches!(client.run(cfg), Err(error::Error::Panic(_))));
+}
+
+#[test]
+fn test_reactor_restart_stable() {
+    let tmp = tempfile::tempdir().unwrap();
+    let cfg = Config {
+        max_restarts: 1,
+        ..config(tmp.path())
+    };
+
+    // Every failure is the first in a row, since the reactor ran for long enough.
+    let client = Client::<UnstableReactor>::new().unwrap();
+    let handle = client.handle();
+    let events = handle.subscribe();
+    let thread = thread::spawn({
+        let cfg = Config {
+            stable_uptime: UnstableReactor::UPTIME / 2,
+            ..cfg.clone()
+        };
+        move || client.run(cfg)
+    });
+
+    for _ in 0..UnstableReactor::FAILURES {
+        let restarts = event::wait(
+            &events,
+            |e| match e {
+                client::Event::Restarted { restarts, .. } => Some(restarts),
+                _ => None,
+            },
+            time::Duration::from_secs(5),
+        )
+        .unwrap();
+
+        assert_eq!(restarts, 1);
+    }
+    assert_eq!(handle.get_tip().unwrap().0, 0);
+
+    handle.shutdown().unwrap();
+    thread.join().unwrap().unwrap();
+
+    // Otherwise, the client gives up once the restarts are exhausted.
+    let client = Client::<UnstableReactor>::new().unwrap();
+    assert!(matches!(client.run(cfg), Err(error::Error::Panic(_))));
+}
+
+#[test]
+fn test_store_recovery() {
+    use nakamoto_common::block::store::Store as _;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let cfg = config(tmp.path());
+    let network = cfg.protocol.network.clone();
+    let dir = tmp.path().join(".nakamoto").join(network.as_str());
+
+    // Store a header chain that is broken after height 10.
+    {
+        let headers = &BITCOIN_HEADERS.tail;
+
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut store = store::File::create(dir.join("headers.db"), network.genesis()).unwrap();
+        store
+            .put(headers[..10].iter().chain(&headers[11..20]).cloned())
+            .unwrap();
+    }
+
+    let client = Client::<Reactor>::new().unwrap();
+    let handle = client.handle();
+    let events = handle.subscribe();
+    let thread = thread::spawn(move || client.run(cfg).unwrap());
+
+    let (store, height) = event::wait(
+        &events,
+        |e| match e {
+            client::Event::StoreRecovered { store, height, .. } => Some((store, height)),
+            _ => None,
+        },
+        time::Duration::from_secs(5),
+    )
+    .unwrap();
+
+    assert_eq!(store, "headers");
+    assert_eq!(height, 10);
+    assert_eq!(handle.get_tip().unwrap().0, 10);
+
+    handle.shutdown().unwrap();
+    thread.join().unwrap();
 }
@@ -0,0 +1,367 @@
+//! Differential tests against bitcoind.
+//!
+//! These tests run the client against a regtest `bitcoind`, drive the chain from bitcoind's
+//! side, ie. mining blocks, broadcasting transactions and re-organizing the chain with
+//! `invalidateblock`, and check after every step that the client's view of the chain matches
+//! what bitcoind reports with `getblockchaininfo` and `getblockfilter`.
+//!
+//! They require the `bitcoind` feature, and the `bitcoind` and `bitcoin-cli` binaries, which
+//! are looked up in `PATH` unless set with the `BITCOIND` and `BITCOIN_CLI` environment
+//! variables. The random scenario uses the seed in `NAKAMOTO_DIFF_SEED` if set:
+//!
+//! ```text
+//! BITCOIND=/path/to/bitcoind cargo test -p nakamoto-client --features bitcoind bitcoind
+//! ```
+use std::env;
+use std::net;
+use std::process;
+use std::thread;
+use std::time;
+
+use microserde::json::{Number, Value};
+
+use nakamoto_common::bitcoin::consensus::encode;
+use nakamoto_common::bitcoin::{Transaction, Txid};
+use nakamoto_common::bitcoin_hashes::hex::FromHex;
+use nakamoto_common::block::filter::FilterHeader;
+use nakamoto_common::block::{BlockHash, Height, Work};
+use nakamoto_common::network::Network;
+use nakamoto_p2p::protocol;
+use nakamoto_test::logger;
+
+use crate::client::{chan, Command, Config};
+use crate::handle::Handle as _;
+
+use super::Reactor;
+
+/// Time to wait for the client to catch up with bitcoind.
+const TIMEOUT: time::Duration = time::Duration::from_secs(30);
+/// Number of blocks to mine before coinbase outputs can be spent.
+const COINBASE_MATURITY: Height = 100;
+
+type Handle = crate::client::Handle<Reactor>;
+
+/// A regtest bitcoind process, stopped when dropped.
+struct Bitcoind {
+    process: process::Child,
+    dir: tempfile::TempDir,
+    p2p: net::SocketAddr,
+    rpc_port: u16,
+}
+
+impl Bitcoind {
+    /// Start bitcoind, and wait for it to accept RPC calls.
+    fn spawn() -> Self {
+        let bin = env::var("BITCOIND").unwrap_or_else(|_| String::from("bitcoind"));
+        let dir = tempfile::tempdir().unwrap();
+        let p2p = net::SocketAddr::from(([127, 0, 0, 1], self::free_port()));
+        let rpc_port = self::free_port();
+
+        let process = process::Command::new(&bin)
+            .arg("-regtest")
+            .arg(format!("-datadir={}", dir.path().display()))
+            .arg(format!("-port={}", p2p.port()))
+            .arg(format!("-rpcport={}", rpc_port))
+            .arg(format!("-bind={}", p2p))
+            .args([
+                "-server",
+                "-listen",
+                "-blockfilterindex",
+                "-peerblockfilters",
+                "-fallbackfee=0.0001",
+                "-printtoconsole=0",
+            ])
+            .stdout(process::Stdio::null())
+            .spawn()
+            .unwrap_or_else(|err| {
+                panic!(
+                    "failed to run `{}`: {}; set `BITCOIND` to the path of a bitcoind binary",
+                    bin, err
+                )
+            });
+        let node = Self {
+            process,
+            dir,
+            p2p,
+            rpc_port,
+        };
+        node.cli(&["-rpcwait", "createwallet", "nakamoto"]);
+        node
+    }
+
+    /// Run `bitcoin-cli` with the given arguments, and return its output.
+    fn cli(&self, args: &[&str]) -> String {
+        let bin = env::var("BITCOIN_CLI").unwrap_or_else(|_| String::from("bitcoin-cli"));
+        let output = process::Command::new(&bin)
+            .arg("-regtest")
+            .arg(format!("-datadir={}", self.dir.path().display()))
+            .arg(format!("-rpcport={}", self.rpc_port))
+            .args(args)
+            .output()
+            .unwrap_or_else(|err| {
+                panic!(
+                    "failed to run `{}`: {}; set `BITCOIN_CLI` to the path of a bitcoin-cli binary",
+                    bin, err
+                )
+            });
+
+        assert!(
+            output.status.success(),
+            "`bitcoin-cli {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    }
+
+    /// Call an RPC method returning JSON.
+    fn json(&self, args: &[&str]) -> Value {
+        let output = self.cli(args);
+
+        microserde::json::from_str(&output)
+            .unwrap_or_else(|_| panic!("`{}` returned invalid JSON: {}", args[0], output))
+    }
+
+    /// Mine blocks, each to a new address, so that blocks mined on top of the same parent
+    /// never collide. Returns the block hashes.
+    fn mine(&self, count: Height) -> Vec<BlockHash> {
+        (0..count)
+            .map(|_| {
+                let addr = self.cli(&["getnewaddress"]);
+                let hashes = self.json(&["generatetoaddress", "1", &addr]);
+
+                match &hashes {
+                    Value::Array(hashes) => self::hash(&hashes[0]),
+                    _ => panic!("unexpected `generatetoaddress` output: {:?}", hashes),
+                }
+            })
+            .collect()
+    }
+
+    /// Create and sign a transaction paying to our wallet, without broadcasting it.
+    fn transaction(&self) -> Transaction {
+        let addr = self.cli(&["getnewaddress"]);
+        let raw = self.cli(&["createrawtransaction", "[]", &format!("{{\"{}\":1}}", addr)]);
+        let funded = self.json(&["fundrawtransaction", &raw]);
+        let signed = self.json(&[
+            "signrawtransactionwithwallet",
+            self::string(self::field(&funded, "hex")),
+        ]);
+        let bytes = Vec::<u8>::from_hex(self::string(self::field(&signed, "hex"))).unwrap();
+
+        encode::deserialize(&bytes).unwrap()
+    }
+
+    /// Check whether a transaction is in bitcoind's mempool.
+    fn in_mempool(&self, txid: &Txid) -> bool {
+        match self.json(&["getrawmempool"]) {
+            Value::Array(txids) => txids.iter().any(|t| self::string(t) == txid.to_string()),
+            other => panic!("unexpected `getrawmempool` output: {:?}", other),
+        }
+    }
+
+    /// Re-organize the chain: invalidate the last `depth` blocks, and mine a longer branch.
+    fn reorg(&self, depth: Height) -> Vec<BlockHash> {
+        let height = self::number(self::field(&self.json(&["getblockchaininfo"]), "blocks"));
+        let fork = self.cli(&["getblockhash", &(height - depth + 1).to_string()]);
+
+        self.cli(&["invalidateblock", &fork]);
+        self.mine(depth + 1)
+    }
+}
+
+impl Drop for Bitcoind {
+    fn drop(&mut self) {
+        let bin = env::var("BITCOIN_CLI").unwrap_or_else(|_| String::from("bitcoin-cli"));
+        let stopped = process::Command::new(bin)
+            .arg("-regtest")
+            .arg(format!("-datadir={}", self.dir.path().display()))
+            .arg(format!("-rpcport={}", self.rpc_port))
+            .arg("stop")
+            .output()
+            .is_ok_and(|o| o.status.success());
+
+        if !stopped {
+            self.process.kill().ok();
+        }
+        self.process.wait().ok();
+    }
+}
+
+/// Get a free local port.
+fn free_port() -> u16 {
+    net::TcpListener::bind((net::Ipv4Addr::LOCALHOST, 0))
+        .and_then(|l| l.local_addr())
+        .unwrap()
+        .port()
+}
+
+fn field<'a>(value: &'a Value, key: &str) -> &'a Value {
+    match value {
+        Value::Object(object) => object
+            .get(key)
+            .unwrap_or_else(|| panic!("missing field `{}` in {:?}", key, object)),
+        other => panic!("expected an object, got {:?}", other),
+    }
+}
+
+fn string(value: &Value) -> &str {
+    match value {
+        Value::String(s) => s,
+        other => panic!("expected a string, got {:?}", other),
+    }
+}
+
+fn number(value: &Value) -> Height {
+    match value {
+        Value::Number(Number::U64(n)) => *n,
+        other => panic!("expected a number, got {:?}", other),
+    }
+}
+
+fn hash(value: &Value) -> BlockHash {
+    BlockHash::from_hex(self::string(value)).unwrap()
+}
+
+/// Start a client connected to the given bitcoind only.
+fn client(node: &Bitcoind) -> (Handle, thread::JoinHandle<()>) {
+    let network = Network::Regtest;
+    let cfg = Config {
+        protocol: protocol::Config {
+            connect_only: true,
+            ..protocol::Config::from("nakamoto", network, vec![node.p2p])
+        },
+        ..Config::default()
+    };
+    let (handle, _, thread) = super::network(&[cfg]).unwrap().pop().unwrap();
+
+    (handle, thread)
+}
+
+/// Wait for the client to sync with bitcoind, and check that their views of the chain match:
+/// the tip, chain work, and the filter header at the tip.
+fn assert_synced(node: &Bitcoind, handle: &Handle) {
+    let info = node.json(&["getblockchaininfo"]);
+    let height = self::number(self::field(&info, "blocks"));
+    let best = self::hash(self::field(&info, "bestblockhash"));
+    let work = Vec::<u8>::from_hex(self::string(self::field(&info, "chainwork"))).unwrap();
+    let deadline = time::Instant::now() + TIMEOUT;
+
+    let chain = loop {
+        let chain = handle.get_chain_work().unwrap();
+
+        if chain.tip == best {
+            break chain;
+        }
+        assert!(
+            time::Instant::now() < deadline,
+            "client tip {} at height {} diverged from bitcoind tip {} at height {}",
+            chain.tip,
+            chain.height,
+            best,
+            height
+        );
+        thread::sleep(time::Duration::from_millis(100));
+    };
+    assert_eq!(chain.height, height);
+    assert_eq!(chain.work, Work::from_be_bytes(work.try_into().unwrap()));
+
+    handle.wait_for_filters(height, TIMEOUT).unwrap();
+
+    let (transmit, receive) = chan::bounded(1);
+    handle.command(Command::ExportHeaders(transmit)).unwrap();
+    let (_, filter_headers) = receive.recv().unwrap();
+    let (_, header) = filter_headers[height as usize];
+    let expected = node.json(&["getblockfilter", &best.to_string()]);
+
+    assert_eq!(
+        header,
+        FilterHeader::from_hex(self::string(self::field(&expected, "header"))).unwrap()
+    );
+}
+
+#[test]
+fn test_bitcoind_sync() {
+    logger::init(log::Level::Debug);
+
+    let node = Bitcoind::spawn();
+    node.mine(COINBASE_MATURITY + 1);
+
+    let (handle, thread) = self::client(&node);
+    assert_synced(&node, &handle);
+
+    // New blocks are followed.
+    node.mine(3);
+    assert_synced(&node, &handle);
+
+    // Transactions submitted by the client reach bitcoind's mempool, and are confirmed.
+    let tx = node.transaction();
+    let txid = tx.txid();
+
+    handle.submit_transaction(tx).unwrap();
+
+    let deadline = time::Instant::now() + TIMEOUT;
+    while !node.in_mempool(&txid) {
+        assert!(
+            time::Instant::now() < deadline,
+            "transaction {} not relayed",
+            txid
+        );
+        thread::sleep(time::Duration::from_millis(100));
+    }
+    let hash = node.mine(1)[0];
+
+    assert_eq!(
+        handle.wait_for_confirmation(txid, 1, TIMEOUT).unwrap().1,
+        hash
+    );
+    assert_synced(&node, &handle);
+
+    // Re-orgs are followed, and the filter header chain is rolled back.
+    node.reorg(2);
+    assert_synced(&node, &handle);
+
+    handle.shutdown().unwrap();
+    thread.join().unwrap();
+}
+
+#[test]
+fn test_bitcoind_random() {
+    logger::init(log::Level::Debug);
+
+    let seed = env::var("NAKAMOTO_DIFF_SEED")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| fastrand::u64(..));
+    let rng = fastrand::Rng::with_seed(seed);
+
+    log::info!("Running random differential test with seed {}", seed);
+
+    let node = Bitcoind::spawn();
+    node.mine(COINBASE_MATURITY + 1);
+
+    let (handle, thread) = self::client(&node);
+    assert_synced(&node, &handle);
+
+    for _ in 0..16 {
+        match rng.u8(..4) {
+            0 => {
+                node.mine(rng.u64(1..=6));
+            }
+            1 => {
+                node.cli(&["sendtoaddress", &node.cli(&["getnewaddress"]), "0.1"]);
+            }
+            2 => {
+                handle.submit_transaction(node.transaction()).unwrap();
+                node.mine(1);
+            }
+            _ => {
+                node.reorg(rng.u64(1..=3));
+            }
+        }
+        assert_synced(&node, &handle);
+    }
+
+    handle.shutdown().unwrap();
+    thread.join().unwrap();
+}
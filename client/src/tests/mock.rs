@@ -1,6 +1,8 @@
 use std::collections::HashMap;
 use std::net;
 use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::time;
 
 use nakamoto_chain::block::Block;
 use nakamoto_chain::filter::BlockFilter;
@@ -9,6 +11,7 @@ use nakamoto_common::bitcoin::consensus::encode::Encodable as _;
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
 use nakamoto_common::bitcoin::network::Address;
+use nakamoto_common::bitcoin::Txid;
 use nakamoto_common::block::filter::FilterHeader;
 use nakamoto_common::block::store::Genesis as _;
 use nakamoto_common::block::time::{AdjustedTime, LocalTime};
@@ -29,6 +32,8 @@ use nakamoto_p2p::traits::Protocol as _;
 
 use crate::client::{chan, Event};
 use crate::handle::{self, Handle};
+use crate::journal;
+use crate::rescan;
 use crate::spv;
 
 pub struct Client {
@@ -65,7 +70,7 @@ impl Client {
     pub fn handle(&self) -> TestHandle {
         TestHandle {
             tip: (0, self.network.genesis()),
-            network: self.network,
+            network: self.network.clone(),
             events: self.events_.clone(),
             blocks: self.blocks_.clone(),
             filters: self.filters_.clone(),
@@ -113,7 +118,7 @@ impl Default for Client {
         let network = Network::default();
         let protocol = {
             let tree = model::Cache::new(network.genesis());
-            let cfilters = model::FilterCache::new(FilterHeader::genesis(network));
+            let cfilters = model::FilterCache::new(FilterHeader::genesis(network.clone()));
             let peers = HashMap::new();
             let time = LocalTime::now();
             let clock = AdjustedTime::new(time);
@@ -190,6 +195,10 @@ impl Handle for TestHandle {
         self.subscriber.subscribe()
     }
 
+    fn subscription(&self, capacity: usize) -> event::Subscription<Event> {
+        self.subscriber.subscription(capacity)
+    }
+
     fn command(&self, cmd: Command) -> Result<(), handle::Error> {
         log::debug!("Sending {:?}", cmd);
         self.commands.send(cmd).map_err(handle::Error::from)
@@ -207,7 +216,18 @@ impl Handle for TestHandle {
         unimplemented!()
     }
 
-    fn connect(&self, _addr: net::SocketAddr) -> Result<Link, handle::Error> {
+    fn capture(&self, _path: Option<PathBuf>) -> Result<(), handle::Error> {
+        unimplemented!()
+    }
+
+    fn connect(&self, _addr: net::SocketAddr, _persistent: bool) -> Result<Link, handle::Error> {
+        unimplemented!()
+    }
+
+    fn connect_any(
+        &self,
+        _addrs: &[net::SocketAddr],
+    ) -> Result<(net::SocketAddr, Link), handle::Error> {
         unimplemented!()
     }
 
@@ -255,7 +275,28 @@ impl Handle for TestHandle {
         unimplemented!()
     }
 
-    fn wait_for_height(&self, _h: Height) -> Result<BlockHash, handle::Error> {
+    fn wait_for_height(
+        &self,
+        _h: Height,
+        _timeout: time::Duration,
+    ) -> Result<BlockHash, handle::Error> {
+        unimplemented!()
+    }
+
+    fn wait_for_filters(
+        &self,
+        _h: Height,
+        _timeout: time::Duration,
+    ) -> Result<Height, handle::Error> {
+        unimplemented!()
+    }
+
+    fn wait_for_confirmation(
+        &self,
+        _txid: Txid,
+        _depth: Height,
+        _timeout: time::Duration,
+    ) -> Result<(Height, BlockHash), handle::Error> {
         unimplemented!()
     }
 
@@ -263,6 +304,14 @@ impl Handle for TestHandle {
         self.events.clone()
     }
 
+    fn replay_from(&self, _seq: u64) -> Result<Vec<journal::Entry>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn rescans(&self) -> Result<Vec<rescan::Task>, handle::Error> {
+        unimplemented!()
+    }
+
     fn shutdown(self) -> Result<(), handle::Error> {
         Ok(())
     }
@@ -0,0 +1,586 @@
+//! Longest-chain simulations.
+//!
+//! These tests drive the full client stack, ie. the block tree, the filter header chain
+//! and the SPV event mapper, through scripted chain re-organizations, and check that the
+//! client-side wallet state converges to the state implied by the remote's best chain.
+//!
+//! The remote peer is scripted: it answers header, filter and block requests from the set
+//! of blocks it knows about, and announces new tips when told to.
+use std::collections::{BTreeMap, HashMap};
+use std::net;
+use std::ops::Bound;
+
+use nakamoto_chain::block::cache::BlockCache;
+use nakamoto_chain::block::store;
+use nakamoto_chain::filter::cache::{FilterCache, StoredHeader};
+
+use nakamoto_common::bitcoin::blockdata::transaction::{OutPoint, TxIn, TxOut};
+use nakamoto_common::bitcoin::consensus::encode::Encodable as _;
+use nakamoto_common::bitcoin::network::address::Address;
+use nakamoto_common::bitcoin::network::constants::ServiceFlags;
+use nakamoto_common::bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use nakamoto_common::bitcoin::network::message_blockdata::Inventory;
+use nakamoto_common::bitcoin::network::message_filter::{CFHeaders, CFilter};
+use nakamoto_common::bitcoin::network::message_network::VersionMessage;
+use nakamoto_common::bitcoin::{Script, Transaction, Txid, Witness};
+use nakamoto_common::block::filter::{FilterHash, FilterHeader};
+use nakamoto_common::block::store::Genesis as _;
+use nakamoto_common::block::time::{AdjustedTime, LocalDuration, LocalTime};
+use nakamoto_common::block::{Anchor, Block, BlockHash, BlockHeader, Height};
+use nakamoto_common::network::Network;
+use nakamoto_common::p2p::peer::KnownAddress;
+use nakamoto_test::block::gen;
+
+use nakamoto_p2p::event;
+use nakamoto_p2p::protocol::{self, Command, Link, PeerId, Protocol};
+use nakamoto_p2p::stream::Decoder;
+use nakamoto_p2p::traits::Protocol as _;
+
+use crate::client::{chan, Event};
+use crate::spv::utxos::Utxos;
+use crate::spv::{Mapper, TxStatus};
+
+/// The protocol under test.
+type Client = Protocol<
+    BlockCache<store::Memory<BlockHeader>>,
+    FilterCache<store::Memory<StoredHeader>>,
+    HashMap<net::IpAddr, KnownAddress>,
+    AdjustedTime<net::SocketAddr>,
+>;
+
+/// A simulated client connected to a single scripted remote peer.
+struct Sim {
+    network: Network,
+    client: Client,
+    publisher: event::Broadcast<protocol::Event, Event>,
+    events: chan::Receiver<Event>,
+    remote: PeerId,
+    inbox: Decoder,
+    time: LocalTime,
+    rng: fastrand::Rng,
+
+    /// All blocks known to the remote.
+    blocks: HashMap<BlockHash, Block>,
+    /// The remote's best chain, starting with the genesis.
+    chain: Vec<BlockHash>,
+
+    /// Script owned by the wallet.
+    script: Script,
+    /// Transactions matched by the client, keyed by block height.
+    matched: BTreeMap<Height, Vec<Transaction>>,
+    /// Latest status of tracked transactions.
+    statuses: HashMap<Txid, TxStatus>,
+    /// Height up to which the client reported being synced.
+    synced: Height,
+}
+
+impl Sim {
+    fn new(seed: u64) -> Self {
+        let network = Network::Regtest;
+        let time = LocalTime::now();
+        let cfg = protocol::Config {
+            network: network.clone(),
+            params: network.params(),
+            // We don't actually have the required services, but we pretend to
+            // for testing purposes.
+            services: ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS,
+            ..protocol::Config::default()
+        };
+        let genesis = network.genesis_block();
+        let client = {
+            let store = store::Memory::new((genesis.header, vec![]).into());
+            let tree = BlockCache::from(store, cfg.params.clone(), &[]).unwrap();
+            let filters = FilterCache::from(store::Memory::genesis(network.clone())).unwrap();
+            let clock = AdjustedTime::new(time);
+
+            Protocol::new(
+                tree,
+                filters,
+                HashMap::new(),
+                clock,
+                fastrand::Rng::with_seed(seed),
+                cfg,
+            )
+        };
+        let mut mapper = Mapper::new();
+        let (publisher, subscriber) = event::broadcast(move |e, p| mapper.process(e, p));
+        let events = subscriber.subscribe();
+        let mut rng = fastrand::Rng::with_seed(seed);
+        let script = gen::script(&mut rng);
+        let hash = genesis.block_hash();
+
+        Self {
+            network: network.clone(),
+            client,
+            publisher,
+            events,
+            remote: ([88, 88, 88, 88], network.port()).into(),
+            inbox: Decoder::new(1024),
+            time,
+            rng,
+            blocks: HashMap::from([(hash, genesis)]),
+            chain: vec![hash],
+            script,
+            matched: BTreeMap::new(),
+            statuses: HashMap::new(),
+            synced: 0,
+        }
+    }
+
+    /// Connect to the remote peer and start scanning for the wallet script.
+    fn start(&mut self) {
+        let local: net::SocketAddr = ([0, 0, 0, 0], 0).into();
+
+        self.client.initialize(self.time);
+        self.client.command(Command::Connect(self.remote, false));
+        self.client.attempted(&self.remote);
+        self.client.connected(self.remote, &local, Link::Outbound);
+        self.client.command(Command::Rescan {
+            id: 0,
+            from: Bound::Unbounded,
+            to: Bound::Unbounded,
+            watch: vec![self.script.clone()],
+        });
+        self.run();
+    }
+
+    /// Current height of the remote's best chain.
+    fn height(&self) -> Height {
+        self.chain.len() as Height - 1
+    }
+
+    /// Hash of the remote's best block at the given height.
+    fn hash(&self, height: Height) -> BlockHash {
+        self.chain[height as usize]
+    }
+
+    /// Mine a block with the given transactions on top of the given parent. The coinbase
+    /// pays to the wallet. Returns the block hash.
+    ///
+    /// If the resulting branch has more work than the remote's best chain, it becomes the
+    /// new best chain and is announced to the client.
+    fn mine(&mut self, parent: BlockHash, transactions: Vec<Transaction>) -> BlockHash {
+        let mut coinbase = gen::coinbase(&mut self.rng);
+        coinbase.output[0].script_pubkey = self.script.clone();
+
+        let prev = self.blocks[&parent].header;
+        let block = gen::block_with(
+            &prev,
+            std::iter::once(coinbase).chain(transactions).collect(),
+            &mut self.rng,
+        );
+        let hash = block.block_hash();
+
+        self.blocks.insert(hash, block);
+
+        let branch = self.branch(hash);
+        if branch.len() > self.chain.len() {
+            self.chain = branch;
+            self.announce();
+        }
+        hash
+    }
+
+    /// Mine `count` empty blocks on top of the given parent. Returns the last block hash.
+    fn extend(&mut self, mut parent: BlockHash, count: usize) -> BlockHash {
+        for _ in 0..count {
+            parent = self.mine(parent, vec![]);
+        }
+        parent
+    }
+
+    /// Create a transaction spending the given output of the given transaction, paying
+    /// half to the wallet and half to a foreign script.
+    fn spend(&mut self, tx: &Transaction, vout: u32) -> Transaction {
+        let value = tx.output[vout as usize].value;
+
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: tx.txid(),
+                    vout,
+                },
+                // Used as the previous output script when generating filters.
+                script_sig: tx.output[vout as usize].script_pubkey.clone(),
+                sequence: 0xffffffff,
+                witness: Witness::new(),
+            }],
+            output: vec![
+                TxOut {
+                    value: value / 2,
+                    script_pubkey: self.script.clone(),
+                },
+                TxOut {
+                    value: value / 2,
+                    script_pubkey: gen::script(&mut self.rng),
+                },
+            ],
+        }
+    }
+
+    /// Submit a transaction through the client.
+    fn submit(&mut self, tx: &Transaction) {
+        let (reply, _) = chan::bounded(1);
+
+        self.client
+            .command(Command::SubmitTransaction(tx.clone(), reply));
+        self.statuses.insert(tx.txid(), TxStatus::Unconfirmed);
+        self.run();
+    }
+
+    /// The branch ending in the given block, starting with the genesis.
+    fn branch(&self, tip: BlockHash) -> Vec<BlockHash> {
+        let mut branch = vec![tip];
+        let mut hash = tip;
+
+        while let Some(block) = self.blocks.get(&hash) {
+            hash = block.header.prev_blockhash;
+            if self.blocks.contains_key(&hash) {
+                branch.push(hash);
+            }
+        }
+        branch.reverse();
+        branch
+    }
+
+    /// Filter hashes and headers of the given branch, starting with the genesis.
+    fn cfheaders(&self, branch: &[BlockHash]) -> Vec<(FilterHash, FilterHeader)> {
+        let genesis = (
+            FilterHash::genesis(self.network.clone()),
+            FilterHeader::genesis(self.network.clone()),
+        );
+        let blocks = branch[1..].iter().map(|h| &self.blocks[h]);
+
+        std::iter::once(genesis)
+            .chain(gen::cfheaders_from_blocks(genesis.1, blocks))
+            .collect()
+    }
+
+    /// Announce the remote's best chain to the client.
+    fn announce(&mut self) {
+        let headers = self.chain[1..]
+            .iter()
+            .map(|h| self.blocks[h].header)
+            .collect();
+
+        self.send(NetworkMessage::Headers(headers));
+        self.run();
+    }
+
+    fn send(&mut self, payload: NetworkMessage) {
+        let mut bytes = Vec::new();
+
+        RawNetworkMessage {
+            magic: self.network.magic(),
+            payload,
+        }
+        .consensus_encode(&mut bytes)
+        .unwrap();
+
+        self.client.received_bytes(&self.remote, &bytes);
+    }
+
+    /// Reply to a message sent by the client, as the remote peer.
+    fn reply(&mut self, msg: NetworkMessage) {
+        match msg {
+            NetworkMessage::Version(version) => {
+                self.send(NetworkMessage::Version(VersionMessage {
+                    version: protocol::PROTOCOL_VERSION,
+                    services: ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS,
+                    timestamp: self.time.block_time() as i64,
+                    receiver: version.sender,
+                    sender: Address::new(&self.remote, ServiceFlags::NONE),
+                    nonce: self.rng.u64(..),
+                    user_agent: "/sim/".to_owned(),
+                    start_height: self.height() as i32,
+                    relay: true,
+                }));
+                self.send(NetworkMessage::Verack);
+            }
+            NetworkMessage::Ping(nonce) => {
+                self.send(NetworkMessage::Pong(nonce));
+            }
+            NetworkMessage::GetHeaders(msg) => {
+                // Start from the fork point, or from the genesis if none of the locators
+                // are on our best chain.
+                let start = msg
+                    .locator_hashes
+                    .iter()
+                    .find_map(|h| self.chain.iter().position(|c| c == h))
+                    .unwrap_or(0);
+                let headers = self.chain[start + 1..]
+                    .iter()
+                    .take_while(|h| **h != msg.stop_hash)
+                    .map(|h| self.blocks[h].header)
+                    .collect();
+
+                self.send(NetworkMessage::Headers(headers));
+            }
+            NetworkMessage::GetCFHeaders(msg) => {
+                let branch = self.branch(msg.stop_hash);
+                let cfheaders = self.cfheaders(&branch);
+                let start = msg.start_height as usize;
+                let (_, previous_filter_header) = cfheaders[start - 1];
+                let filter_hashes = cfheaders[start..].iter().map(|(h, _)| *h).collect();
+
+                self.send(NetworkMessage::CFHeaders(CFHeaders {
+                    filter_type: msg.filter_type,
+                    stop_hash: msg.stop_hash,
+                    previous_filter_header,
+                    filter_hashes,
+                }));
+            }
+            NetworkMessage::GetCFilters(msg) => {
+                let branch = self.branch(msg.stop_hash);
+
+                for hash in &branch[msg.start_height as usize..] {
+                    let filter = gen::cfilter(&self.blocks[hash]).content;
+
+                    self.send(NetworkMessage::CFilter(CFilter {
+                        filter_type: msg.filter_type,
+                        block_hash: *hash,
+                        filter,
+                    }));
+                }
+            }
+            NetworkMessage::GetData(inventory) => {
+                for inv in inventory {
+                    if let Inventory::Block(hash) | Inventory::WitnessBlock(hash) = inv {
+                        if let Some(block) = self.blocks.get(&hash).cloned() {
+                            self.send(NetworkMessage::Block(block));
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Run the client until it has nothing left to say to the remote, waking it up
+    /// whenever it goes quiet, so that queued requests are sent out.
+    fn run(&mut self) {
+        let mut woken = false;
+
+        loop {
+            for io in self.client.drain() {
+                match io {
+                    protocol::Io::Event(event) => self.publisher.broadcast(event),
+                    protocol::Io::Disconnect(addr, reason) => {
+                        panic!("Sim::run: client disconnected from {}: {}", addr, reason)
+                    }
+                    _ => {}
+                }
+            }
+            for event in self.events.try_iter().collect::<Vec<_>>() {
+                self.process(event);
+            }
+
+            let mut bytes = Vec::new();
+            self.client.write(&self.remote, &mut bytes).unwrap();
+
+            if bytes.is_empty() {
+                if woken {
+                    break;
+                }
+                self.client.wake();
+                woken = true;
+
+                continue;
+            }
+            woken = false;
+            self.inbox.input(&bytes);
+
+            while let Some(msg) = self.inbox.decode_next::<RawNetworkMessage>().unwrap() {
+                self.reply(msg.payload);
+            }
+        }
+    }
+
+    /// Let some time pass, so that timers fire.
+    fn elapse(&mut self, duration: LocalDuration) {
+        self.time = self.time + duration;
+        self.client.tick(self.time);
+        self.client.wake();
+        self.run();
+    }
+
+    /// Update the wallet state from a client event.
+    fn process(&mut self, event: Event) {
+        match event {
+            Event::BlockMatched {
+                block,
+                transactions,
+                ..
+            } => {
+                self.matched.insert(block.height, transactions);
+            }
+            Event::BlockDisconnected { block, .. } => {
+                self.matched.split_off(&block.height);
+            }
+            Event::TxStatusChanged { txid, status } => {
+                self.statuses.insert(txid, status);
+            }
+            Event::Synced { height, .. } => {
+                self.synced = height;
+            }
+            _ => {}
+        }
+    }
+
+    /// UTXOs of the wallet, as seen by the client.
+    fn utxos(&self) -> Utxos {
+        let mut utxos = Utxos::new();
+
+        for tx in self.matched.values().flatten() {
+            utxos.apply(tx, std::slice::from_ref(&self.script));
+        }
+        utxos
+    }
+
+    /// UTXOs of the wallet, according to the remote's best chain.
+    fn expected(&self) -> Utxos {
+        let mut utxos = Utxos::new();
+
+        for hash in &self.chain[1..] {
+            for tx in &self.blocks[hash].txdata {
+                utxos.apply(tx, std::slice::from_ref(&self.script));
+            }
+        }
+        utxos
+    }
+
+    /// Block in which a transaction was confirmed in the remote's best chain.
+    fn confirmation(&self, txid: &Txid) -> Option<Anchor> {
+        self.chain.iter().enumerate().find_map(|(height, hash)| {
+            let block = &self.blocks[hash];
+
+            block
+                .txdata
+                .iter()
+                .any(|tx| tx.txid() == *txid)
+                .then(|| Anchor::new(height as Height, &block.header))
+        })
+    }
+
+    /// Check that the client converged on the remote's best chain.
+    fn assert_converged(&mut self) {
+        let (reply, tip) = chan::bounded(1);
+        self.client.command(Command::GetTip(reply));
+        let (_, tip) = tip.recv().unwrap();
+
+        assert_eq!(
+            tip.block_hash(),
+            *self.chain.last().unwrap(),
+            "block tree converged"
+        );
+        assert_eq!(self.synced, self.height(), "filters and blocks converged");
+
+        let utxos = self.utxos();
+        let expected = self.expected();
+
+        assert_eq!(*utxos, *expected, "UTXO set converged");
+        assert_eq!(utxos.balance(), expected.balance(), "balance converged");
+
+        for (txid, status) in &self.statuses {
+            match self.confirmation(txid) {
+                Some(block) => {
+                    assert_eq!(*status, TxStatus::Confirmed { block }, "{}", txid)
+                }
+                None => assert!(
+                    !matches!(status, TxStatus::Confirmed { .. }),
+                    "{} is not confirmed in the best chain",
+                    txid
+                ),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_deep_reorg() {
+    let mut sim = Sim::new(fastrand::u64(..));
+    sim.start();
+
+    // Fund the wallet.
+    let funding = sim.mine(sim.hash(0), vec![]);
+    let funding_tx = sim.blocks[&funding].txdata[0].clone();
+    let tip = sim.extend(funding, 4);
+    sim.assert_converged();
+
+    // Two spends, one that will end up in both branches, and one that only makes it
+    // into the shorter branch.
+    let spend = sim.spend(&funding_tx, 0);
+    let change = sim.spend(&spend, 0);
+
+    sim.submit(&spend);
+    sim.submit(&change);
+
+    let fork = sim.height();
+    let confirmed = sim.mine(tip, vec![spend.clone()]);
+    let confirmed = sim.mine(confirmed, vec![change.clone()]);
+    let stale = sim.extend(confirmed, 4);
+    sim.assert_converged();
+
+    assert_eq!(sim.height(), fork + 6);
+    assert_eq!(sim.chain.last(), Some(&stale));
+    assert!(matches!(
+        sim.statuses[&change.txid()],
+        TxStatus::Confirmed { .. }
+    ));
+
+    // A longer branch forks off before the spends were confirmed. It confirms the first
+    // spend at a different height, and doesn't include the second one.
+    let branch = sim.extend(tip, 3);
+    let branch = sim.mine(branch, vec![spend.clone()]);
+    let branch = sim.extend(branch, 3);
+    assert_eq!(sim.chain.last(), Some(&branch));
+    sim.assert_converged();
+
+    assert_eq!(sim.statuses[&change.txid()], TxStatus::Reverted);
+    assert_eq!(
+        sim.confirmation(&spend.txid()).map(|b| b.height),
+        Some(fork + 4)
+    );
+
+    // The reverted transaction is re-announced, and eventually confirmed.
+    sim.elapse(LocalDuration::from_mins(1));
+    let tip = sim.mine(branch, vec![change.clone()]);
+    assert_eq!(sim.chain.last(), Some(&tip));
+    sim.assert_converged();
+}
+
+#[test]
+fn test_reorg_back_and_forth() {
+    let mut sim = Sim::new(fastrand::u64(..));
+    sim.start();
+
+    let funding = sim.mine(sim.hash(0), vec![]);
+    let funding_tx = sim.blocks[&funding].txdata[0].clone();
+    let base = sim.extend(funding, 2);
+
+    let spend = sim.spend(&funding_tx, 0);
+    sim.submit(&spend);
+
+    // Three competing branches, each overtaking the previous one. The spend is confirmed
+    // in the first and last branches only.
+    let a = sim.mine(base, vec![spend.clone()]);
+    let a = sim.extend(a, 2);
+    assert_eq!(sim.chain.last(), Some(&a));
+    sim.assert_converged();
+
+    let b = sim.extend(base, 4);
+    assert_eq!(sim.chain.last(), Some(&b));
+    sim.assert_converged();
+    assert_eq!(sim.statuses[&spend.txid()], TxStatus::Reverted);
+
+    // Switch back to the first branch.
+    let a = sim.extend(a, 3);
+    assert_eq!(sim.chain.last(), Some(&a));
+    sim.assert_converged();
+    assert!(matches!(
+        sim.statuses[&spend.txid()],
+        TxStatus::Confirmed { .. }
+    ));
+}
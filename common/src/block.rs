@@ -7,6 +7,10 @@ pub mod store;
 pub mod time;
 pub mod tree;
 
+use std::fmt;
+
+use bitcoin::consensus::params::Params;
+
 pub use bitcoin::blockdata::block::{Block, BlockHeader};
 pub use bitcoin::blockdata::transaction::Transaction;
 pub use bitcoin::hash_types::BlockHash;
@@ -26,6 +30,45 @@ pub type Height = u64;
 /// Block time (seconds since Epoch).
 pub type BlockTime = u32;
 
+/// A block of a specific chain, identified by its height and hash, along with the hash of its
+/// parent.
+///
+/// Heights alone are ambiguous across re-orgs, since a block at a given height can be replaced
+/// by another, and hashes alone don't say where a block sits in the chain. Events referencing
+/// blocks carry anchors, so that they can be checked against the active chain later on, with
+/// [`tree::BlockReader::is_anchored`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Anchor {
+    /// Height of the block.
+    pub height: Height,
+    /// Hash of the block.
+    pub hash: BlockHash,
+    /// Hash of the parent block.
+    pub parent: BlockHash,
+}
+
+impl Anchor {
+    /// Create an anchor from a block header and its height.
+    pub fn new(height: Height, header: &BlockHeader) -> Self {
+        Self {
+            height,
+            hash: header.block_hash(),
+            parent: header.prev_blockhash,
+        }
+    }
+
+    /// Check whether this anchor is the parent of the given anchor.
+    pub fn is_parent_of(&self, other: &Anchor) -> bool {
+        other.height == self.height + 1 && other.parent == self.hash
+    }
+}
+
+impl fmt::Display for Anchor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at height {}", self.hash, self.height)
+    }
+}
+
 /// Get the locator indexes starting from a given height, and going backwards, exponentially
 /// backing off.
 ///
@@ -55,12 +98,7 @@ pub fn locators_indexes(mut from: Height) -> Vec<Height> {
     indexes
 }
 
-/// Get the proof-of-work limit for the network, in bits.
-pub fn pow_limit_bits(network: &bitcoin::Network) -> Bits {
-    match network {
-        bitcoin::Network::Bitcoin => 0x1d00ffff,
-        bitcoin::Network::Testnet => 0x1d00ffff,
-        bitcoin::Network::Regtest => 0x207fffff,
-        bitcoin::Network::Signet => 0x1e0377ae,
-    }
+/// Get the proof-of-work limit of the given consensus parameters, in bits.
+pub fn pow_limit_bits(params: &Params) -> Bits {
+    BlockHeader::compact_target_from_u256(&params.pow_limit)
 }
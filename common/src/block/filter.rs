@@ -10,6 +10,7 @@ pub use bitcoin::util::bip158::BlockFilter;
 
 use super::Height;
 use crate::block::store::{self, Genesis};
+use crate::error::Classify;
 use crate::network::Network;
 
 impl Genesis for FilterHeader {
@@ -47,6 +48,22 @@ pub enum Error {
     Store(#[from] store::Error),
 }
 
+impl Classify for Error {
+    fn code(&self) -> u32 {
+        match self {
+            Self::NotFound(_) => 2012,
+            Self::Store(err) => err.code(),
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::NotFound(_) => false,
+            Self::Store(err) => err.is_transient(),
+        }
+    }
+}
+
 /// A trait for types that provide read/write access to compact block filters, and filter headers.
 pub trait Filters {
     /// Get filter headers given a block height range.
@@ -30,8 +30,8 @@ pub const REGTEST: &[u8; 32] = &[
 #[rustfmt::skip]
 /// Bitcoin signet genesis hash.
 pub const SIGNET: &[u8; 32] = &[
-    0x00, 0x00, 0x00, 0x08, 0x81, 0x98, 0x73, 0xe9,
-    0x25, 0x42, 0x2c, 0x1f, 0xf0, 0xf9, 0x9f, 0x7c,
-    0xc9, 0xbb, 0xb2, 0x32, 0xaf, 0x63, 0xa0, 0x77,
-    0xa4, 0x80, 0xa3, 0x63, 0x3b, 0xee, 0x1e, 0xf6,
+    0xf6, 0x1e, 0xee, 0x3b, 0x63, 0xa3, 0x80, 0xa4,
+    0x77, 0xa0, 0x63, 0xaf, 0x32, 0xb2, 0xbb, 0xc9,
+    0x7c, 0x9f, 0xf9, 0xf0, 0x1f, 0x2c, 0x42, 0x25,
+    0xe9, 0x73, 0x98, 0x81, 0x08, 0x00, 0x00, 0x00,
 ];
@@ -8,6 +8,7 @@ use bitcoin::hash_types::FilterHash;
 use bitcoin::util::bip158::BlockFilter;
 use thiserror::Error;
 
+use crate::error::{self, Classify};
 use crate::network::Network;
 use crate::source;
 
@@ -23,6 +24,28 @@ pub enum Error {
     /// A data-corruption error.
     #[error("error: the store data is corrupt")]
     Corruption,
+    /// The store is locked by another process.
+    #[error("the store is locked by another process")]
+    Locked,
+}
+
+impl Classify for Error {
+    fn code(&self) -> u32 {
+        match self {
+            Self::Io(_) => 2000,
+            Self::Decoding(_) => 2001,
+            Self::Corruption => 2002,
+            Self::Locked => 2003,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Io(err) => error::is_transient_io(err),
+            Self::Locked => true,
+            Self::Decoding(_) | Self::Corruption => false,
+        }
+    }
 }
 
 /// Represents an object (such as a header), that has a genesis.
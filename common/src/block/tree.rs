@@ -9,8 +9,9 @@ use bitcoin::hash_types::BlockHash;
 use thiserror::Error;
 
 use crate::block::store;
-use crate::block::time::Clock;
-use crate::block::{Bits, BlockTime, Height, Target, Work};
+use crate::block::time::{Clock, MAX_FUTURE_BLOCK_TIME, MEDIAN_TIME_SPAN};
+use crate::block::{Anchor, Bits, BlockTime, Height, Target, Work};
+use crate::error::Classify;
 use crate::nonempty::NonEmpty;
 
 /// An error related to the block tree.
@@ -53,6 +54,33 @@ pub enum Error {
     Store(#[from] store::Error),
 }
 
+impl Classify for Error {
+    fn code(&self) -> u32 {
+        match self {
+            Self::InvalidBlockPoW => 3000,
+            Self::InvalidBlockTarget(_, _) => 3001,
+            Self::InvalidBlockHash(_, _) => 3002,
+            Self::InvalidBlockHeight(_) => 3003,
+            Self::InvalidBlockTime(_, _) => 3004,
+            Self::DuplicateBlock(_) => 3005,
+            Self::BlockMissing(_) => 3006,
+            Self::BlockImportAborted(err, _, _) => err.code(),
+            Self::Store(err) => err.code(),
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            // Blocks in the future may become valid, and missing parents may arrive.
+            Self::InvalidBlockTime(_, ordering) => ordering.is_gt(),
+            Self::BlockMissing(_) => true,
+            Self::BlockImportAborted(err, _, _) => err.is_transient(),
+            Self::Store(err) => err.is_transient(),
+            _ => false,
+        }
+    }
+}
+
 /// A generic block header.
 pub trait Header {
     /// Return the proof-of-work of this header.
@@ -101,6 +129,21 @@ impl<'a, H: Header> Branch<'a, H> {
     }
 }
 
+/// A known fork off the active chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fork {
+    /// Hash of the best block of the fork.
+    pub tip: BlockHash,
+    /// Header of the best block of the fork.
+    pub header: BlockHeader,
+    /// Height of the best block of the fork.
+    pub height: Height,
+    /// Height of the last block shared with the active chain.
+    pub fork_height: Height,
+    /// Total proof-of-work of the fork, from genesis to its tip.
+    pub work: Work,
+}
+
 /// A representation of all known blocks that keeps track of the longest chain.
 pub trait BlockTree: BlockReader {
     /// Import a chain of block headers into the block tree.
@@ -116,6 +159,13 @@ pub trait BlockTree: BlockReader {
         header: BlockHeader,
         context: &C,
     ) -> Result<ImportResult, Error>;
+    /// Revalidate the active chain block at the given height against its parent, repairing
+    /// the block's index and stored header if they don't match the chain. Returns `true`
+    /// if anything had to be repaired.
+    fn revalidate<C: Clock>(&mut self, height: Height, context: &C) -> Result<bool, Error>;
+    /// Truncate the active chain to the given height, discarding all blocks above it.
+    /// Returns the discarded blocks, ordered from the tip down.
+    fn truncate(&mut self, height: Height) -> Result<Vec<(Height, BlockHeader)>, Error>;
 }
 
 /// Read block header state.
@@ -137,16 +187,88 @@ pub trait BlockReader {
     }
     /// Iterate over the longest chain, starting from genesis, including heights.
     fn iter<'a>(&'a self) -> Box<dyn DoubleEndedIterator<Item = (Height, BlockHeader)> + 'a>;
-    /// Iterate over a range of blocks.
+    /// Iterate over a range of blocks of the longest chain. Heights above the tip are
+    /// ignored.
     fn range<'a>(
         &'a self,
         range: std::ops::Range<Height>,
     ) -> Box<dyn Iterator<Item = (Height, BlockHash)> + 'a> {
-        Box::new(
-            self.iter()
-                .map(|(height, header)| (height, header.block_hash()))
-                .skip(range.start as usize)
-                .take((range.end - range.start) as usize),
+        Box::new(range.map_while(move |height| {
+            self.get_block_by_height(height)
+                .map(|header| (height, header.block_hash()))
+        }))
+    }
+    /// Iterate over the ancestors of a block, starting with the block itself and going back
+    /// to genesis. The block may be on the longest chain, or on a known fork of it.
+    ///
+    /// Returns `None` if the block is unknown, or doesn't connect to the longest chain.
+    fn iter_ancestors<'a>(
+        &'a self,
+        hash: &BlockHash,
+    ) -> Option<Box<dyn Iterator<Item = (Height, BlockHeader)> + 'a>> {
+        // Nb. The first header of the branch is on the longest chain.
+        let (fork_height, branch) = self.find_branch(hash)?;
+        let tip = fork_height + branch.tail.len() as Height;
+        let stale = (fork_height + 1..=tip)
+            .rev()
+            .zip(branch.tail.into_iter().rev());
+        let active = (0..=fork_height)
+            .rev()
+            .map_while(move |height| self.get_block_by_height(height).map(|h| (height, *h)));
+
+        Some(Box::new(stale.chain(active)))
+    }
+    /// Find the last block shared by the ancestries of two blocks, ie. the point at which
+    /// they fork. If one block is an ancestor of the other, it is returned. The blocks may
+    /// be on the longest chain, or on known forks of it.
+    ///
+    /// Returns `None` if either block is unknown, or doesn't connect to the longest chain.
+    fn fork_point(&self, a: &BlockHash, b: &BlockHash) -> Option<(Height, BlockHash)> {
+        if let (Some((x, _)), Some((y, _))) = (self.get_block(a), self.get_block(b)) {
+            return Some(if x <= y { (x, *a) } else { (y, *b) });
+        }
+        let mut a = self.iter_ancestors(a)?.peekable();
+        let mut b = self.iter_ancestors(b)?.peekable();
+
+        loop {
+            let ((x, h), (y, k)) = (*a.peek()?, *b.peek()?);
+
+            if x > y {
+                a.next();
+            } else if y > x {
+                b.next();
+            } else {
+                let hash = h.block_hash();
+                if hash == k.block_hash() {
+                    return Some((x, hash));
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+    /// Find the last block of the longest chain that the given block descends from. For
+    /// blocks on the longest chain, this is the block itself.
+    ///
+    /// Returns `None` if the block is unknown, or doesn't connect to the longest chain.
+    fn common_ancestor(&self, hash: &BlockHash) -> Option<(Height, BlockHash)> {
+        // The branch starts at the fork block, or is the block itself if it's on the
+        // longest chain.
+        self.find_branch(hash)
+            .map(|(height, branch)| (height, branch.first().block_hash()))
+    }
+    /// Get the anchor of the block at the given height of the longest chain.
+    fn anchor(&self, height: Height) -> Option<Anchor> {
+        self.get_block_by_height(height)
+            .map(|header| Anchor::new(height, header))
+    }
+    /// Check whether an anchor, eg. one received in an event, is still part of the longest
+    /// chain. Returns `false` once the anchored block was disconnected by a re-org, even if
+    /// a block was connected at the same height since.
+    fn is_anchored(&self, anchor: &Anchor) -> bool {
+        matches!(
+            self.get_block(&anchor.hash),
+            Some((height, header)) if height == anchor.height && header.prev_blockhash == anchor.parent
         )
     }
     /// Return the height of the longest chain.
@@ -162,6 +284,58 @@ pub trait BlockReader {
                 .expect("the best block is always present"),
         )
     }
+    /// Find a conservative starting height for scanning the chain for activity that
+    /// happened after the given time, eg. a wallet's birthday.
+    ///
+    /// Block timestamps aren't monotonic, so the search is done over the median time past
+    /// of each block, which is. Since the median time past lags behind block times, and
+    /// block times may be up to [`MAX_FUTURE_BLOCK_TIME`] ahead of the time a block was
+    /// mined, the result is the height of the last block with a median time past earlier
+    /// than the given time minus [`MAX_FUTURE_BLOCK_TIME`].
+    ///
+    /// [`MAX_FUTURE_BLOCK_TIME`]: crate::block::time::MAX_FUTURE_BLOCK_TIME
+    fn find_height_by_time(&self, time: BlockTime) -> Height {
+        let time = time.saturating_sub(MAX_FUTURE_BLOCK_TIME);
+
+        // Binary search for the first block that isn't earlier than the given time.
+        let (mut low, mut high) = (0, self.height() + 1);
+        while low < high {
+            let mid = low + (high - low) / 2;
+
+            if self.median_time(mid) < time {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low.saturating_sub(1)
+    }
+    /// Median time past of the blocks leading up to and including the given height, ie. the
+    /// median timestamp of the block and the ones preceding it, up to [`MEDIAN_TIME_SPAN`]
+    /// blocks. This is the clock used by time-based timelocks. Heights above the tip are
+    /// treated as the tip.
+    fn median_time(&self, height: Height) -> BlockTime {
+        let height = height.min(self.height());
+        let mut times = (height.saturating_sub(MEDIAN_TIME_SPAN - 1)..=height)
+            .filter_map(|h| self.get_block_by_height(h))
+            .map(|h| h.time)
+            .collect::<Vec<_>>();
+
+        times.sort_unstable();
+        times[times.len() / 2]
+    }
+    /// Compute the total proof-of-work of the active chain, up to and including the
+    /// given height.
+    fn chain_work(&self, height: Height) -> Work {
+        (0..=height)
+            .filter_map(|h| self.get_block_by_height(h))
+            .fold(Work::default(), |work, header| work + header.work())
+    }
+    /// Get the known forks off the active chain, with their best block.
+    /// Forks that don't connect to the active chain are not returned.
+    fn forks(&self) -> Vec<Fork>;
+    /// Get the consensus parameters of the chain.
+    fn params(&self) -> &Params;
     /// Get the height of the last checkpoint block.
     fn last_checkpoint(&self) -> Height;
     /// Known checkpoints.
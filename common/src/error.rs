@@ -0,0 +1,242 @@
+//! Error classification shared by all crates.
+//!
+//! Errors are given stable numeric codes, grouped by [`Category`], and classified as
+//! transient or not, so that callers such as FFI layers or retry logic can react to
+//! them programmatically instead of matching on error messages.
+//!
+//! Codes never change meaning once assigned, and are only ever added. The category of
+//! a code is given by its thousands digit, eg. `2003` is a [`Category::Store`] error.
+//! Each error variant has its own code, except for variants wrapping another classified
+//! error, which take the code of the wrapped error.
+//!
+//! Any classified error can be converted into an [`Error`], which groups errors by
+//! category, eg. to handle all [`StoreError`]s the same way, regardless of where they
+//! come from.
+use std::fmt;
+use std::io;
+
+use thiserror::Error;
+
+/// Broad category of an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    /// Peer-to-peer networking errors, eg. not being connected to any peer.
+    Network,
+    /// Errors reading or writing persisted data, eg. the block header store.
+    Store,
+    /// Invalid data, eg. a block header with invalid proof-of-work.
+    Validation,
+    /// Invalid configuration or usage.
+    Config,
+    /// Internal errors, eg. a client that shut down.
+    Internal,
+}
+
+impl Category {
+    /// Get the category of an error code.
+    pub fn of(code: u32) -> Self {
+        match code / 1000 {
+            1 => Self::Network,
+            2 => Self::Store,
+            3 => Self::Validation,
+            4 => Self::Config,
+            _ => Self::Internal,
+        }
+    }
+}
+
+/// An error that can be classified.
+pub trait Classify: std::error::Error {
+    /// Stable numeric code of the error.
+    fn code(&self) -> u32;
+
+    /// Whether the error is transient, ie. retrying the operation later may succeed.
+    fn is_transient(&self) -> bool;
+
+    /// Category of the error.
+    fn category(&self) -> Category {
+        Category::of(self.code())
+    }
+}
+
+/// Define an error type for one category of errors.
+macro_rules! category {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Debug)]
+        pub struct $name(Box<dyn Classify + Send + Sync>);
+
+        impl $name {
+            /// Get the underlying error.
+            pub fn inner(&self) -> &(dyn Classify + Send + Sync + 'static) {
+                &*self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl std::error::Error for $name {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+                self.0.source()
+            }
+        }
+
+        impl Classify for $name {
+            fn code(&self) -> u32 {
+                self.0.code()
+            }
+
+            fn is_transient(&self) -> bool {
+                self.0.is_transient()
+            }
+        }
+    };
+}
+
+category!(
+    /// A [`Category::Network`] error.
+    NetworkError
+);
+category!(
+    /// A [`Category::Store`] error.
+    StoreError
+);
+category!(
+    /// A [`Category::Validation`] error.
+    ValidationError
+);
+category!(
+    /// A [`Category::Config`] error.
+    ConfigError
+);
+category!(
+    /// A [`Category::Internal`] error.
+    InternalError
+);
+
+/// A classified error, grouped by category.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// A networking error.
+    #[error(transparent)]
+    Network(NetworkError),
+    /// A storage error.
+    #[error(transparent)]
+    Store(StoreError),
+    /// A validation error.
+    #[error(transparent)]
+    Validation(ValidationError),
+    /// A configuration or usage error.
+    #[error(transparent)]
+    Config(ConfigError),
+    /// An internal error.
+    #[error(transparent)]
+    Internal(InternalError),
+}
+
+impl Error {
+    /// Stable numeric code of the error.
+    pub fn code(&self) -> u32 {
+        self.inner().code()
+    }
+
+    /// Whether the error is transient, ie. retrying the operation later may succeed.
+    pub fn is_transient(&self) -> bool {
+        self.inner().is_transient()
+    }
+
+    /// Category of the error.
+    pub fn category(&self) -> Category {
+        match self {
+            Self::Network(_) => Category::Network,
+            Self::Store(_) => Category::Store,
+            Self::Validation(_) => Category::Validation,
+            Self::Config(_) => Category::Config,
+            Self::Internal(_) => Category::Internal,
+        }
+    }
+
+    /// Get the underlying error.
+    pub fn inner(&self) -> &(dyn Classify + Send + Sync + 'static) {
+        match self {
+            Self::Network(err) => err.inner(),
+            Self::Store(err) => err.inner(),
+            Self::Validation(err) => err.inner(),
+            Self::Config(err) => err.inner(),
+            Self::Internal(err) => err.inner(),
+        }
+    }
+}
+
+impl<E: Classify + Send + Sync + 'static> From<E> for Error {
+    fn from(err: E) -> Self {
+        match err.category() {
+            Category::Network => Self::Network(NetworkError(Box::new(err))),
+            Category::Store => Self::Store(StoreError(Box::new(err))),
+            Category::Validation => Self::Validation(ValidationError(Box::new(err))),
+            Category::Config => Self::Config(ConfigError(Box::new(err))),
+            Category::Internal => Self::Internal(InternalError(Box::new(err))),
+        }
+    }
+}
+
+/// Check whether an I/O error is transient.
+pub fn is_transient_io(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::AddrInUse
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block::store;
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(Category::of(1002), Category::Network);
+        assert_eq!(Category::of(3000), Category::Validation);
+        assert_eq!(Category::of(5000), Category::Internal);
+
+        let err = store::Error::Io(io::Error::from(io::ErrorKind::Interrupted));
+        assert_eq!(err.code(), 2000);
+        assert_eq!(err.category(), Category::Store);
+        assert!(err.is_transient());
+
+        let err = store::Error::Io(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(!err.is_transient());
+        assert!(!store::Error::Corruption.is_transient());
+        assert!(store::Error::Locked.is_transient());
+    }
+
+    #[test]
+    fn test_hierarchy() {
+        let err = Error::from(store::Error::Locked);
+        assert!(matches!(err, Error::Store(_)));
+        assert_eq!(err.code(), 2003);
+        assert_eq!(err.category(), Category::Store);
+        assert!(err.is_transient());
+        assert_eq!(err.to_string(), store::Error::Locked.to_string());
+
+        let err = Error::from(crate::block::tree::Error::Store(store::Error::Corruption));
+        assert!(matches!(err, Error::Store(_)));
+        assert_eq!(err.code(), 2002);
+
+        let err = Error::from(crate::block::tree::Error::InvalidBlockPoW);
+        assert!(matches!(err, Error::Validation(_)));
+        assert!(!err.is_transient());
+    }
+}
@@ -0,0 +1,32 @@
+//! File system utilities.
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Atomically replace the contents of a file.
+///
+/// The data is written to a temporary file next to the destination and flushed to disk,
+/// before the temporary file is renamed over the destination. The parent directory is
+/// then synced, so that the rename itself is durable. After a crash or a power loss, the
+/// file holds either its previous or its new contents, never a partial write.
+pub fn atomic_write(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp, path)?;
+
+    // Directories can't be opened as files on all platforms, eg. Windows.
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
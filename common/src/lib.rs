@@ -3,6 +3,8 @@
 #![deny(missing_docs, unsafe_code)]
 pub mod block;
 pub mod collections;
+pub mod error;
+pub mod fs;
 pub mod network;
 pub mod p2p;
 
@@ -1,8 +1,11 @@
 //! Bitcoin peer network. Eg. *Mainnet*.
 
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
 use bitcoin::blockdata::block::{Block, BlockHeader};
-use bitcoin::consensus::params::Params;
-use bitcoin::hash_types::BlockHash;
+use bitcoin::consensus::params::Params as ConsensusParams;
+use bitcoin::hash_types::{BlockHash, FilterHeader};
 use bitcoin::network::constants::ServiceFlags;
 use bitcoin_hashes::hex::FromHex;
 
@@ -35,7 +38,7 @@ impl Default for Services {
 }
 
 /// Bitcoin peer network.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Network {
     /// Bitcoin Mainnet.
     Mainnet,
@@ -45,6 +48,72 @@ pub enum Network {
     Regtest,
     /// Bitcoin signet.
     Signet,
+    /// A chain other than Bitcoin's, sharing its block header format, eg. a research network.
+    Custom(Arc<Params>),
+}
+
+/// Chain parameters.
+///
+/// Supplying these as a [`Network::Custom`] network allows syncing chains other than
+/// Bitcoin's, as long as they share its block header format and proof-of-work rules. The
+/// parameters of the built-in networks are given by [`Network::chain_params`], and can serve
+/// as a starting point:
+///
+/// ```
+/// use nakamoto_common::network::{Network, Params};
+///
+/// use std::sync::Arc;
+///
+/// let network = Network::Custom(Arc::new(Params {
+///     name: "research",
+///     magic: 0x0b5e4c4e,
+///     port: 28333,
+///     ..Network::Regtest.chain_params()
+/// }));
+///
+/// assert_eq!(network.as_str(), "research");
+/// assert_eq!(network.genesis_hash(), Network::Regtest.genesis_hash());
+/// ```
+///
+/// Chains are identified by their name, magic and genesis block hash.
+#[derive(Debug, Clone)]
+pub struct Params {
+    /// Short name of the chain, eg. used as the name of its data directory.
+    pub name: &'static str,
+    /// Genesis block.
+    pub genesis: Block,
+    /// Network magic number, sent with every peer-to-peer message.
+    pub magic: u32,
+    /// Default listen port.
+    pub port: u16,
+    /// DNS seeds.
+    pub seeds: &'static [&'static str],
+    /// Block header checkpoints.
+    pub checkpoints: Vec<(Height, BlockHash)>,
+    /// Compact filter header checkpoints.
+    pub filter_checkpoints: Vec<(Height, FilterHeader)>,
+    /// Consensus parameters, eg. the proof-of-work limit and the retarget interval.
+    /// Their `network` is used for address formats, and to pick which Bitcoin-specific
+    /// rules apply, eg. `Regtest` for a chain without difficulty retargeting.
+    pub consensus: ConsensusParams,
+}
+
+impl PartialEq for Params {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.magic == other.magic
+            && self.genesis.block_hash() == other.genesis.block_hash()
+    }
+}
+
+impl Eq for Params {}
+
+impl Hash for Params {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.magic.hash(state);
+        self.genesis.block_hash().hash(state);
+    }
 }
 
 impl Default for Network {
@@ -60,6 +129,7 @@ impl From<Network> for bitcoin::Network {
             Network::Testnet => Self::Testnet,
             Network::Regtest => Self::Regtest,
             Network::Signet => Self::Signet,
+            Network::Custom(params) => params.consensus.network,
         }
     }
 }
@@ -83,6 +153,7 @@ impl Network {
             Network::Testnet => 18333,
             Network::Regtest => 18334,
             Network::Signet => 38333,
+            Network::Custom(params) => params.port,
         }
     }
 
@@ -95,6 +166,7 @@ impl Network {
             Network::Testnet => &checkpoints::TESTNET,
             Network::Regtest => &checkpoints::REGTEST,
             Network::Signet => &checkpoints::SIGNET,
+            Network::Custom(params) => return Box::new(params.checkpoints.clone().into_iter()),
         }
         .iter()
         .cloned()
@@ -106,6 +178,29 @@ impl Network {
         Box::new(iter)
     }
 
+    /// Compact filter header checkpoints.
+    pub fn filter_checkpoints(&self) -> Box<dyn Iterator<Item = (Height, FilterHeader)>> {
+        use crate::block::checkpoints::filters;
+
+        let iter = match self {
+            Network::Mainnet => filters::MAINNET,
+            Network::Testnet => filters::TESTNET,
+            Network::Regtest => filters::REGTEST,
+            Network::Signet => filters::SIGNET,
+            Network::Custom(params) => {
+                return Box::new(params.filter_checkpoints.clone().into_iter())
+            }
+        }
+        .iter()
+        .cloned()
+        .map(|(height, header)| {
+            let header = FilterHeader::from_hex(header).unwrap();
+            (height, header)
+        });
+
+        Box::new(iter)
+    }
+
     /// Return the short string representation of this network.
     pub fn as_str(&self) -> &'static str {
         match self {
@@ -113,11 +208,12 @@ impl Network {
             Network::Testnet => "testnet",
             Network::Regtest => "regtest",
             Network::Signet => "signet",
+            Network::Custom(params) => params.name,
         }
     }
 
     /// DNS seeds. Used to bootstrap the client's address book.
-    pub fn seeds(&self) -> &[&str] {
+    pub fn seeds(&self) -> &'static [&'static str] {
         match self {
             Network::Mainnet => &[
                 "seed.bitcoin.sipa.be",          // Pieter Wuille
@@ -139,6 +235,24 @@ impl Network {
             ],
             Network::Regtest => &[], // No seeds
             Network::Signet => &["seed.signet.bitcoin.sprovoost.nl"],
+            Network::Custom(params) => params.seeds,
+        }
+    }
+
+    /// Get the chain parameters of this network.
+    pub fn chain_params(&self) -> Params {
+        if let Network::Custom(params) = self {
+            return Params::clone(params);
+        }
+        Params {
+            name: self.as_str(),
+            genesis: self.genesis_block(),
+            magic: self.magic(),
+            port: self.port(),
+            seeds: self.seeds(),
+            checkpoints: self.checkpoints().collect(),
+            filter_checkpoints: self.filter_checkpoints().collect(),
+            consensus: self.params(),
         }
     }
 }
@@ -162,7 +276,10 @@ impl Network {
     pub fn genesis_block(&self) -> Block {
         use bitcoin::blockdata::constants;
 
-        constants::genesis_block((*self).into())
+        match self {
+            Self::Custom(params) => params.genesis.clone(),
+            _ => constants::genesis_block(self.clone().into()),
+        }
     }
 
     /// Get the hash of the genesis block of this network.
@@ -175,6 +292,7 @@ impl Network {
             Self::Testnet => genesis::TESTNET,
             Self::Regtest => genesis::REGTEST,
             Self::Signet => genesis::SIGNET,
+            Self::Custom(params) => return params.genesis.block_hash(),
         };
         BlockHash::from(
             sha256d::Hash::from_slice(hash)
@@ -183,12 +301,62 @@ impl Network {
     }
 
     /// Get the consensus parameters for this network.
-    pub fn params(&self) -> Params {
-        Params::new((*self).into())
+    pub fn params(&self) -> ConsensusParams {
+        match self {
+            Self::Custom(params) => params.consensus.clone(),
+            _ => ConsensusParams::new(self.clone().into()),
+        }
     }
 
     /// Get the network magic number for this network.
     pub fn magic(&self) -> u32 {
-        bitcoin::Network::from(*self).magic()
+        match self {
+            Self::Custom(params) => params.magic,
+            _ => bitcoin::Network::from(self.clone()).magic(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block;
+
+    #[test]
+    fn test_chain_params() {
+        for network in [
+            Network::Mainnet,
+            Network::Testnet,
+            Network::Regtest,
+            Network::Signet,
+        ] {
+            let custom = Network::Custom(Arc::new(network.chain_params()));
+
+            assert_eq!(custom.as_str(), network.as_str());
+            assert_eq!(custom.magic(), network.magic());
+            assert_eq!(custom.port(), network.port());
+            assert_eq!(custom.genesis_hash(), network.genesis_hash());
+            assert_eq!(custom.seeds(), network.seeds());
+            assert!(custom.checkpoints().eq(network.checkpoints()));
+            assert!(custom.filter_checkpoints().eq(network.filter_checkpoints()));
+            assert_eq!(custom.params().pow_limit, network.params().pow_limit);
+            assert_eq!(
+                bitcoin::Network::from(custom),
+                bitcoin::Network::from(network)
+            );
+        }
+        assert_eq!(
+            block::pow_limit_bits(&Network::Mainnet.params()),
+            0x1d00ffff
+        );
+        assert_eq!(
+            block::pow_limit_bits(&Network::Testnet.params()),
+            0x1d00ffff
+        );
+        assert_eq!(
+            block::pow_limit_bits(&Network::Regtest.params()),
+            0x207fffff
+        );
+        assert_eq!(block::pow_limit_bits(&Network::Signet.params()), 0x1e0377ae);
     }
 }
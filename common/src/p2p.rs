@@ -1,19 +1,34 @@
 //! P2P-related types
+use std::fmt;
 use std::net;
+use std::str::FromStr;
 
+use bitcoin::network::address::AddrV2;
+use thiserror::Error;
+
+pub mod base32;
+pub mod i2p;
 pub mod peer;
+pub mod tor;
 
 /// Communication domain of a network socket.
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum Domain {
     /// IPv4.
     IPV4,
     /// IPv6.
     IPV6,
+    /// CJDNS, an encrypted IPv6 mesh network, with addresses in `fc00::/8`. Only reachable
+    /// if the host has a cjdns interface.
+    CJDNS,
+    /// I2P, an anonymous overlay network. Peers are identified by a synthetic IPv6
+    /// address, see [`i2p`]. Only reachable through the SAM bridge of an I2P router.
+    I2P,
 }
 
 impl Domain {
-    /// All domains.
+    /// All domains reachable over the internet. This excludes [`Domain::CJDNS`] and
+    /// [`Domain::I2P`], which require a cjdns interface and an I2P router.
     pub fn all() -> Vec<Self> {
         vec![Self::IPV4, Self::IPV6]
     }
@@ -22,7 +37,187 @@ impl Domain {
     pub const fn for_address(address: &net::SocketAddr) -> Domain {
         match address {
             net::SocketAddr::V4(_) => Domain::IPV4,
+            net::SocketAddr::V6(addr) if addr.ip().octets()[0] == 0xfc => Domain::CJDNS,
+            net::SocketAddr::V6(addr) if i2p::is_i2p(addr.ip()) => Domain::I2P,
             net::SocketAddr::V6(_) => Domain::IPV6,
         }
     }
 }
+
+/// Policy for choosing the domain of outbound peer connections.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum DomainPolicy {
+    /// Connect to addresses of any supported domain.
+    #[default]
+    Any,
+    /// Prefer addresses of the given domain. Addresses of other domains are only used
+    /// when none of the preferred domain are available.
+    Prefer(Domain),
+    /// Spread connections across supported domains, by connecting to addresses of the
+    /// domain with the fewest outbound connections first.
+    Diverse,
+}
+
+/// Address of a peer, as given by a user, eg. on the command line. Unlike a socket
+/// address, this can be the address of a peer on an overlay network, such as Tor or I2P.
+///
+/// Parsed from `<host>:<port>`, where the host is an IP address, an I2P address ending in
+/// `.b32.i2p`, or a Tor v3 address ending in `.onion`. IPv6 addresses are enclosed in
+/// square brackets.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PeerAddr {
+    /// Network address, as advertised in `addrv2` messages (BIP 155).
+    pub addr: AddrV2,
+    /// Port.
+    pub port: u16,
+}
+
+impl PeerAddr {
+    /// Suffix of I2P host names.
+    pub const I2P_SUFFIX: &'static str = ".b32.i2p";
+    /// Suffix of Tor host names.
+    pub const TOR_SUFFIX: &'static str = ".onion";
+
+    /// Get the socket address used to identify and connect to this peer, if we have a
+    /// transport for its network. I2P peers get a synthetic address, see [`i2p`]. Tor
+    /// peers can't be reached, and have none.
+    pub fn socket_addr(&self) -> Option<net::SocketAddr> {
+        match &self.addr {
+            AddrV2::Ipv4(ip) => Some(net::SocketAddr::from((*ip, self.port))),
+            AddrV2::Ipv6(ip) | AddrV2::Cjdns(ip) => Some(net::SocketAddr::from((*ip, self.port))),
+            AddrV2::I2p(hash) => Some(i2p::socket_addr(*hash)),
+            AddrV2::TorV2(_) | AddrV2::TorV3(_) | AddrV2::Unknown(_, _) => None,
+        }
+    }
+}
+
+impl From<net::SocketAddr> for PeerAddr {
+    fn from(addr: net::SocketAddr) -> Self {
+        let port = addr.port();
+        let addr = match addr {
+            net::SocketAddr::V4(addr) => AddrV2::Ipv4(*addr.ip()),
+            net::SocketAddr::V6(addr) if Domain::for_address(&addr.into()) == Domain::CJDNS => {
+                AddrV2::Cjdns(*addr.ip())
+            }
+            net::SocketAddr::V6(addr) => match i2p::resolve(&addr.into()) {
+                Some(hash) => AddrV2::I2p(hash),
+                None => AddrV2::Ipv6(*addr.ip()),
+            },
+        };
+        Self { addr, port }
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.addr {
+            AddrV2::Ipv4(ip) => write!(f, "{}:{}", ip, self.port),
+            AddrV2::Ipv6(ip) | AddrV2::Cjdns(ip) => write!(f, "[{}]:{}", ip, self.port),
+            AddrV2::I2p(hash) => {
+                write!(
+                    f,
+                    "{}{}:{}",
+                    base32::encode(hash),
+                    Self::I2P_SUFFIX,
+                    self.port
+                )
+            }
+            AddrV2::TorV3(key) => {
+                // The host name encodes the public key, a two-byte checksum, and the version.
+                let mut bytes = key.to_vec();
+                bytes.extend(tor::checksum(key));
+                bytes.push(tor::VERSION);
+
+                write!(
+                    f,
+                    "{}{}:{}",
+                    base32::encode(&bytes),
+                    Self::TOR_SUFFIX,
+                    self.port
+                )
+            }
+            AddrV2::TorV2(_) | AddrV2::Unknown(_, _) => write!(f, "{:?}:{}", self.addr, self.port),
+        }
+    }
+}
+
+/// Error parsing a [`PeerAddr`].
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("invalid peer address `{0}`")]
+pub struct ParsePeerAddrError(String);
+
+impl FromStr for PeerAddr {
+    type Err = ParsePeerAddrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParsePeerAddrError(s.to_owned());
+
+        if let Ok(addr) = s.parse::<net::SocketAddr>() {
+            return Ok(addr.into());
+        }
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse().map_err(|_| invalid())?;
+        let host = host.to_ascii_lowercase();
+
+        let addr = if let Some(b32) = host.strip_suffix(Self::I2P_SUFFIX) {
+            let bytes = base32::decode(b32).ok_or_else(invalid)?;
+
+            AddrV2::I2p(bytes.try_into().map_err(|_| invalid())?)
+        } else if let Some(b32) = host.strip_suffix(Self::TOR_SUFFIX) {
+            let bytes = base32::decode(b32).ok_or_else(invalid)?;
+            let bytes = <[u8; 35]>::try_from(bytes.as_slice()).map_err(|_| invalid())?;
+            let (key, rest) = bytes.split_at(32);
+            let key = <[u8; 32]>::try_from(key).expect("the key is 32 bytes");
+
+            if rest[..2] != tor::checksum(&key) || rest[2] != tor::VERSION {
+                return Err(invalid());
+            }
+            AddrV2::TorV3(key)
+        } else {
+            return Err(invalid());
+        };
+        Ok(Self { addr, port })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_peer_addr() {
+        let onion = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion:8333";
+        let addr = onion.parse::<PeerAddr>().unwrap();
+
+        assert!(matches!(addr.addr, AddrV2::TorV3(_)));
+        assert_eq!(addr.to_string(), onion);
+        assert_eq!(addr.socket_addr(), None);
+
+        // A single character off makes the checksum invalid.
+        assert!(
+            "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczae.onion:8333"
+                .parse::<PeerAddr>()
+                .is_err()
+        );
+
+        let i2p = format!("{}.b32.i2p:0", base32::encode(&[0x5a; 32]));
+        let addr = i2p.parse::<PeerAddr>().unwrap();
+
+        assert_eq!(addr.addr, AddrV2::I2p([0x5a; 32]));
+        assert_eq!(addr.to_string(), i2p);
+        assert_eq!(
+            addr.socket_addr().map(|a| Domain::for_address(&a)),
+            Some(Domain::I2P)
+        );
+        assert_eq!(PeerAddr::from(addr.socket_addr().unwrap()), addr);
+
+        for s in ["88.18.22.1:8333", "[2001:db8::1]:8333", "[fc00::1]:8333"] {
+            let addr = s.parse::<PeerAddr>().unwrap();
+
+            assert_eq!(addr.to_string(), s);
+            assert_eq!(addr.socket_addr(), Some(s.parse().unwrap()));
+        }
+        assert!("example.com:8333".parse::<PeerAddr>().is_err());
+        assert!("88.18.22.1".parse::<PeerAddr>().is_err());
+    }
+}
@@ -0,0 +1,61 @@
+//! Unpadded, lower-case base32 (RFC 4648), as used by I2P and Tor addresses.
+
+/// Alphabet of the encoding.
+const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Encode bytes.
+pub fn encode(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    let mut n = 0u32;
+    let mut bits = 0;
+
+    for b in bytes {
+        n = n << 8 | *b as u32;
+        bits += 8;
+
+        while bits >= 5 {
+            bits -= 5;
+            s.push(ALPHABET[(n >> bits) as usize & 0x1f] as char);
+        }
+    }
+    if bits > 0 {
+        s.push(ALPHABET[(n << (5 - bits)) as usize & 0x1f] as char);
+    }
+    s
+}
+
+/// Decode a string, ignoring case. Returns `None` if it has characters outside of the
+/// alphabet.
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len() * 5 / 8);
+    let mut n = 0u32;
+    let mut bits = 0;
+
+    for c in s.bytes() {
+        let v = ALPHABET.iter().position(|a| *a == c.to_ascii_lowercase())? as u32;
+
+        n = n << 5 | v;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((n >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encoding() {
+        for bytes in [&b""[..], b"f", b"fo", b"foo", b"foob", b"fooba", b"foobar"] {
+            assert_eq!(decode(&encode(bytes)).unwrap(), bytes);
+        }
+        assert_eq!(encode(b"foobar"), "mzxw6ytboi");
+        assert_eq!(decode("MZXW6YTBOI").unwrap(), b"foobar");
+        assert!(decode("mzxw1").is_none());
+    }
+}
@@ -0,0 +1,87 @@
+//! I2P peer addresses.
+//!
+//! I2P peers have no IP address: they are reached by the SHA-256 hash of their
+//! destination, which they advertise with `addrv2` (BIP 155). Since peers are otherwise
+//! identified by socket address, I2P peers are given a synthetic IPv6 address, made of
+//! the `fd60:db4d:ddb5::/48` prefix used for I2P by Bitcoin Core ("GarliCat") followed by
+//! the first ten bytes of their address hash, and the port `0`.
+//!
+//! The full address hash is needed to reach a peer, so every synthetic address handed out
+//! is recorded in a process-wide table, from which it can be resolved.
+use std::collections::HashMap;
+use std::net;
+use std::sync::{OnceLock, RwLock};
+
+/// Prefix of synthetic I2P addresses.
+pub const PREFIX: [u8; 6] = [0xfd, 0x60, 0xdb, 0x4d, 0xdd, 0xb5];
+
+/// Synthetic addresses handed out so far, and the I2P address hashes they stand for.
+static ADDRESSES: OnceLock<RwLock<HashMap<net::Ipv6Addr, [u8; 32]>>> = OnceLock::new();
+
+fn addresses() -> &'static RwLock<HashMap<net::Ipv6Addr, [u8; 32]>> {
+    ADDRESSES.get_or_init(RwLock::default)
+}
+
+/// Check whether an IPv6 address is a synthetic I2P address.
+pub const fn is_i2p(ip: &net::Ipv6Addr) -> bool {
+    let octets = ip.octets();
+    let mut i = 0;
+
+    while i < PREFIX.len() {
+        if octets[i] != PREFIX[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Get the synthetic socket address of an I2P peer, given its address hash, and record
+/// it so that it can be resolved.
+pub fn socket_addr(hash: [u8; 32]) -> net::SocketAddr {
+    let mut octets = [0; 16];
+
+    octets[..PREFIX.len()].copy_from_slice(&PREFIX);
+    octets[PREFIX.len()..].copy_from_slice(&hash[..16 - PREFIX.len()]);
+
+    let ip = net::Ipv6Addr::from(octets);
+    addresses().write().unwrap().insert(ip, hash);
+
+    net::SocketAddr::from((ip, 0))
+}
+
+/// Get the I2P address hash of a synthetic socket address, if it was handed out by
+/// [`socket_addr`].
+pub fn resolve(addr: &net::SocketAddr) -> Option<[u8; 32]> {
+    match addr {
+        net::SocketAddr::V6(addr) if is_i2p(addr.ip()) => {
+            addresses().read().unwrap().get(addr.ip()).copied()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::p2p::Domain;
+
+    #[test]
+    fn test_socket_addr() {
+        let hash = [0x4f; 32];
+        let addr = socket_addr(hash);
+
+        assert_eq!(
+            addr.to_string(),
+            "[fd60:db4d:ddb5:4f4f:4f4f:4f4f:4f4f:4f4f]:0"
+        );
+        assert_eq!(Domain::for_address(&addr), Domain::I2P);
+        assert_eq!(resolve(&addr), Some(hash));
+
+        // Addresses that weren't handed out can't be resolved.
+        let unknown = net::SocketAddr::from(([0xfd60, 0xdb4d, 0xddb5, 1, 2, 3, 4, 5], 0));
+        assert_eq!(Domain::for_address(&unknown), Domain::I2P);
+        assert_eq!(resolve(&unknown), None);
+        assert_eq!(resolve(&([88, 88, 88, 88], 8333).into()), None);
+    }
+}
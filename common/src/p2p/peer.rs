@@ -8,7 +8,10 @@ use microserde as serde;
 use bitcoin::network::address::Address;
 use bitcoin::network::constants::ServiceFlags;
 
+use bitcoin_hashes::hex::{FromHex, ToHex};
+
 use crate::block::time::LocalTime;
+use crate::p2p::{i2p, Domain};
 
 /// Peer store.
 ///
@@ -236,6 +239,10 @@ impl KnownAddress {
         let mut obj = Object::new();
 
         obj.insert("address".to_owned(), Value::String(address));
+        // I2P peers can't be reached by their synthetic address alone.
+        if let Some(hash) = self.addr.socket_addr().ok().and_then(|a| i2p::resolve(&a)) {
+            obj.insert("i2p".to_owned(), Value::String(hash.to_hex()));
+        }
         obj.insert("services".to_owned(), Value::Number(Number::U64(services)));
         obj.insert(
             "last_success".to_owned(),
@@ -290,6 +297,16 @@ impl KnownAddress {
             Some(Value::String(addr)) => addr.parse().unwrap(),
             _ => return Err(serde::Error),
         };
+        let addr = match obj.get("i2p") {
+            Some(Value::String(hash)) => {
+                let hash =
+                    <[u8; 32]>::try_from(Vec::<u8>::from_hex(hash).map_err(|_| serde::Error)?)
+                        .map_err(|_| serde::Error)?;
+                i2p::socket_addr(hash)
+            }
+            None => addr,
+            _ => return Err(serde::Error),
+        };
         let services = match obj.get("services") {
             Some(Value::Number(Number::U64(srv))) => ServiceFlags::from(*srv),
             _ => return Err(serde::Error),
@@ -349,8 +366,14 @@ impl KnownAddress {
 pub trait AddressSource {
     /// Sample a random peer address. Returns `None` if there are no addresses left.
     fn sample(&mut self, services: ServiceFlags) -> Option<(Address, Source)>;
+    /// Sample a random peer address of the given domain. Returns `None` if there are no
+    /// such addresses left.
+    fn sample_in(&mut self, services: ServiceFlags, domain: Domain) -> Option<(Address, Source)>;
     /// Record an address of ours as seen by a remote peer.
     fn record_local_address(&mut self, addr: net::SocketAddr);
+    /// Record an address of ours as reported by an outbound peer, to detect the address
+    /// we're reachable on from the outside.
+    fn record_external_address(&mut self, addr: net::SocketAddr, from: net::SocketAddr);
     /// Return an iterator over random peer addresses.
     fn iter(&mut self, services: ServiceFlags) -> Box<dyn Iterator<Item = (Address, Source)> + '_>;
 }
@@ -364,10 +387,26 @@ pub mod test {
             self.pop_front()
         }
 
+        fn sample_in(
+            &mut self,
+            _services: ServiceFlags,
+            domain: Domain,
+        ) -> Option<(Address, Source)> {
+            let ix = std::collections::VecDeque::iter(self).position(|(addr, _)| {
+                addr.socket_addr()
+                    .is_ok_and(|a| Domain::for_address(&a) == domain)
+            })?;
+            self.remove(ix)
+        }
+
         fn record_local_address(&mut self, _addr: net::SocketAddr) {
             // Do nothing.
         }
 
+        fn record_external_address(&mut self, _addr: net::SocketAddr, _from: net::SocketAddr) {
+            // Do nothing.
+        }
+
         fn iter(
             &mut self,
             _services: ServiceFlags,
@@ -399,4 +438,30 @@ mod tests {
 
         assert_eq!(ka, deserialized);
     }
+
+    #[test]
+    fn test_known_address_i2p() {
+        let hash = [0x9e; 32];
+        let ka = KnownAddress::new(
+            Address::new(&i2p::socket_addr(hash), ServiceFlags::NETWORK),
+            Source::Imported,
+            None,
+        );
+        let mut value = ka.to_json();
+
+        // The address hash is restored from the store, not from the synthetic address.
+        if let serde::json::Value::Object(obj) = &mut value {
+            obj.insert(
+                "address".to_owned(),
+                serde::json::Value::String("[::1]:0".to_owned()),
+            );
+        }
+        let deserialized = KnownAddress::from_json(value).unwrap();
+
+        assert_eq!(ka, deserialized);
+        assert_eq!(
+            i2p::resolve(&deserialized.addr.socket_addr().unwrap()),
+            Some(hash)
+        );
+    }
 }
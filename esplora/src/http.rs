@@ -0,0 +1,180 @@
+//! Minimal HTTP/1.1 support.
+//!
+//! Only what the API needs is supported: one request per connection, with an optional
+//! body whose length is given by the `Content-Length` header.
+use std::io::{self, BufRead, Read, Write};
+
+/// Maximum size of a request body, large enough for any standard transaction.
+pub const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+/// Maximum length of the request line and of each header line.
+pub const MAX_LINE_SIZE: usize = 8 * 1024;
+/// Maximum number of request headers.
+const MAX_HEADERS: usize = 64;
+
+/// An HTTP request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// Request method, eg. `GET`.
+    pub method: String,
+    /// Request path, without the query string.
+    pub path: String,
+    /// Request body.
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Read a request.
+    pub fn read<R: BufRead>(mut r: R) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+        let mut line = String::new();
+
+        read_line(&mut r, &mut line)?;
+
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target), Some(_version)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid("invalid request line"));
+        };
+        let method = method.to_owned();
+        let path = target.split('?').next().unwrap_or_default().to_owned();
+        let mut length = 0;
+
+        for _ in 0..=MAX_HEADERS {
+            line.clear();
+            read_line(&mut r, &mut line)?;
+
+            let header = line.trim_end();
+            if header.is_empty() {
+                let mut body = vec![0; length];
+                r.read_exact(&mut body)?;
+
+                return Ok(Self { method, path, body });
+            }
+            let Some((name, value)) = header.split_once(':') else {
+                return Err(invalid("invalid header"));
+            };
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid("invalid content length"))?;
+
+                if length > MAX_BODY_SIZE {
+                    return Err(invalid("request body too large"));
+                }
+            }
+        }
+        Err(invalid("too many headers"))
+    }
+}
+
+/// Read a line of at most [`MAX_LINE_SIZE`] bytes, including the line terminator.
+fn read_line<R: BufRead>(r: &mut R, line: &mut String) -> io::Result<()> {
+    let n = r.by_ref().take(MAX_LINE_SIZE as u64).read_line(line)?;
+
+    if n == MAX_LINE_SIZE && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    Ok(())
+}
+
+/// An HTTP response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    /// Status code.
+    pub status: u16,
+    /// Content type of the body.
+    pub content_type: &'static str,
+    /// Response body.
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// A plain text response.
+    pub fn text(status: u16, body: impl ToString) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    /// A JSON response.
+    pub fn json(value: &microserde::json::Value) -> Self {
+        Self {
+            status: 200,
+            content_type: "application/json",
+            body: microserde::json::to_string(value).into_bytes(),
+        }
+    }
+
+    /// Write the response, and signal that the connection will be closed.
+    pub fn write<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        };
+        write!(
+            w,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason,
+            self.content_type,
+            self.body.len()
+        )?;
+        w.write_all(&self.body)?;
+        w.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let req = Request::read(
+            "POST /tx?x=1 HTTP/1.1\r\nHost: localhost\r\ncontent-length: 4\r\n\r\nbeefcafe"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/tx");
+        assert_eq!(req.body, b"beef");
+
+        let req = Request::read("GET /blocks/tip/height HTTP/1.1\r\n\r\n".as_bytes()).unwrap();
+        assert_eq!(req.path, "/blocks/tip/height");
+        assert!(req.body.is_empty());
+
+        assert!(Request::read("GET\r\n\r\n".as_bytes()).is_err());
+        assert!(Request::read(
+            format!(
+                "POST /tx HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+                MAX_BODY_SIZE + 1
+            )
+            .as_bytes()
+        )
+        .is_err());
+        assert!(Request::read(
+            format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_SIZE)).as_bytes()
+        )
+        .is_err());
+        assert!(Request::read(
+            format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_LINE_SIZE)).as_bytes()
+        )
+        .is_err());
+
+        let mut out = Vec::new();
+        Response::text(404, "Not found").write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: 9\r\nConnection: close\r\n\r\nNot found"
+        );
+    }
+}
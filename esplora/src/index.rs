@@ -0,0 +1,264 @@
+//! Index of the transactions of addresses derived from registered descriptors.
+//!
+//! Addresses are derived from each descriptor up to a gap limit past the last used
+//! address, like in the wallet. Transactions are indexed by the scripts they pay to or
+//! spend from.
+use std::collections::{BTreeSet, HashMap};
+
+use nakamoto_common::bitcoin::{BlockHash, OutPoint, Script, Transaction, TxOut, Txid};
+use nakamoto_common::block::{BlockTime, Height};
+use nakamoto_wallet::keychain::{self, Descriptor, Keychain};
+
+/// Block in which a transaction was confirmed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Confirmation {
+    /// Block height.
+    pub height: Height,
+    /// Block hash.
+    pub block: BlockHash,
+    /// Block timestamp.
+    pub time: BlockTime,
+}
+
+/// An indexed transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The transaction.
+    pub transaction: Transaction,
+    /// Confirmation of the transaction, if confirmed.
+    pub confirmation: Option<Confirmation>,
+}
+
+/// An unspent output of a watched address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Utxo {
+    /// The output's outpoint.
+    pub outpoint: OutPoint,
+    /// The output.
+    pub output: TxOut,
+    /// Confirmation of the transaction creating the output, if confirmed.
+    pub confirmation: Option<Confirmation>,
+}
+
+/// Transactions of watched addresses.
+#[derive(Debug, Clone)]
+pub struct Index {
+    keychains: Vec<Keychain>,
+    transactions: HashMap<Txid, Entry>,
+    /// Transactions paying to or spending from each script.
+    scripts: HashMap<Script, BTreeSet<Txid>>,
+}
+
+impl Index {
+    /// Create an index of the addresses derived from the given descriptors.
+    pub fn new(
+        descriptors: impl IntoIterator<Item = Descriptor>,
+        gap_limit: u32,
+    ) -> Result<Self, keychain::Error> {
+        let keychains = descriptors
+            .into_iter()
+            .map(|d| Keychain::new(d, gap_limit))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            keychains,
+            transactions: HashMap::new(),
+            scripts: HashMap::new(),
+        })
+    }
+
+    /// Scripts of the watched addresses.
+    pub fn scripts(&self) -> impl Iterator<Item = &Script> {
+        self.keychains.iter().flat_map(|k| k.scripts())
+    }
+
+    /// Check whether a script is watched.
+    pub fn is_watched(&self, script: &Script) -> bool {
+        self.keychains.iter().any(|k| k.index_of(script).is_some())
+    }
+
+    /// Get an indexed transaction.
+    pub fn get(&self, txid: &Txid) -> Option<&Entry> {
+        self.transactions.get(txid)
+    }
+
+    /// Index a transaction if it pays to or spends from a watched address. Returns the
+    /// scripts of newly derived addresses, which must be watched.
+    pub fn insert(
+        &mut self,
+        transaction: Transaction,
+        confirmation: Option<Confirmation>,
+    ) -> Result<Vec<Script>, keychain::Error> {
+        let txid = transaction.txid();
+        let mut scripts = Vec::new();
+        let mut derived = Vec::new();
+
+        for input in &transaction.input {
+            if let Some(output) = self.output(&input.previous_output) {
+                if self.is_watched(&output.script_pubkey) {
+                    scripts.push(output.script_pubkey.clone());
+                }
+            }
+        }
+        for output in &transaction.output {
+            if self.is_watched(&output.script_pubkey) {
+                for keychain in &mut self.keychains {
+                    derived.extend(keychain.mark_used(&output.script_pubkey)?);
+                }
+                scripts.push(output.script_pubkey.clone());
+            }
+        }
+        if scripts.is_empty() {
+            return Ok(derived);
+        }
+        for script in scripts {
+            self.scripts.entry(script).or_default().insert(txid);
+        }
+        self.transactions.insert(
+            txid,
+            Entry {
+                transaction,
+                confirmation,
+            },
+        );
+        Ok(derived)
+    }
+
+    /// Mark transactions confirmed at or above the given height as unconfirmed.
+    pub fn disconnect(&mut self, height: Height) {
+        for entry in self.transactions.values_mut() {
+            if matches!(entry.confirmation, Some(c) if c.height >= height) {
+                entry.confirmation = None;
+            }
+        }
+    }
+
+    /// Transactions paying to or spending from a script, unconfirmed transactions first,
+    /// then newest first.
+    pub fn transactions(&self, script: &Script) -> Vec<&Entry> {
+        let mut entries = self
+            .scripts
+            .get(script)
+            .into_iter()
+            .flatten()
+            .filter_map(|txid| self.transactions.get(txid))
+            .collect::<Vec<_>>();
+
+        entries
+            .sort_by_key(|e| std::cmp::Reverse(e.confirmation.map_or(Height::MAX, |c| c.height)));
+        entries
+    }
+
+    /// Unspent outputs paying to a script.
+    pub fn utxos(&self, script: &Script) -> Vec<Utxo> {
+        let entries = self.transactions(script);
+        let spent = entries
+            .iter()
+            .flat_map(|e| e.transaction.input.iter().map(|i| i.previous_output))
+            .collect::<BTreeSet<_>>();
+
+        entries
+            .iter()
+            .flat_map(|e| {
+                let txid = e.transaction.txid();
+
+                e.transaction
+                    .output
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, o)| &o.script_pubkey == script)
+                    .map(move |(vout, output)| Utxo {
+                        outpoint: OutPoint::new(txid, vout as u32),
+                        output: output.clone(),
+                        confirmation: e.confirmation,
+                    })
+            })
+            .filter(|u| !spent.contains(&u.outpoint))
+            .collect()
+    }
+
+    /// Get an output of an indexed transaction.
+    fn output(&self, outpoint: &OutPoint) -> Option<&TxOut> {
+        self.transactions
+            .get(&outpoint.txid)
+            .and_then(|e| e.transaction.output.get(outpoint.vout as usize))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use nakamoto_common::bitcoin::{TxIn, Witness};
+
+    // Master key of BIP 32 test vector 1.
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    fn transaction(inputs: Vec<OutPoint>, outputs: Vec<(u64, Script)>) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: inputs
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    script_sig: Script::new(),
+                    sequence: 0xffffffff,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: outputs
+                .into_iter()
+                .map(|(value, script_pubkey)| TxOut {
+                    value,
+                    script_pubkey,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_index() {
+        let descriptor = Descriptor::from_str(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        let mut index = Index::new([descriptor.clone()], 2).unwrap();
+        let ours = descriptor.derive(1).unwrap().script_pubkey();
+        let theirs = Script::from(vec![0x51]);
+
+        assert_eq!(index.scripts().count(), 2);
+
+        let confirmation = Confirmation {
+            height: 10,
+            block: BlockHash::default(),
+            time: 1_600_000_000,
+        };
+        let unrelated = transaction(vec![OutPoint::default()], vec![(1, theirs.clone())]);
+        assert!(index.insert(unrelated.clone(), None).unwrap().is_empty());
+        assert!(index.get(&unrelated.txid()).is_none());
+
+        // Using the second address slides the window.
+        let funding = transaction(vec![OutPoint::default()], vec![(10_000, ours.clone())]);
+        let outpoint = OutPoint::new(funding.txid(), 0);
+        assert_eq!(
+            index
+                .insert(funding.clone(), Some(confirmation))
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(index.scripts().count(), 4);
+        assert_eq!(index.utxos(&ours)[0].outpoint, outpoint);
+
+        let spending = transaction(vec![outpoint], vec![(9_000, theirs)]);
+        index.insert(spending.clone(), None).unwrap();
+
+        let txs = index.transactions(&ours);
+        assert_eq!(txs.len(), 2);
+        assert_eq!(txs[0].transaction, spending);
+        assert!(index.utxos(&ours).is_empty());
+
+        index.disconnect(10);
+        assert_eq!(index.get(&funding.txid()).unwrap().confirmation, None);
+    }
+}
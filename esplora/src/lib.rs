@@ -0,0 +1,533 @@
+//! Esplora-compatible HTTP API.
+//!
+//! A [`Server`] exposes a subset of the [Esplora REST API] backed by the client, so that
+//! tooling written against Esplora can run against a local light client. Since the client
+//! doesn't index the blockchain, address endpoints are only served for addresses derived
+//! from the descriptors registered with the server's [`Index`].
+//!
+//! The supported endpoints are:
+//!
+//! * `GET /blocks/tip/height` and `GET /blocks/tip/hash`
+//! * `GET /block-height/:height`
+//! * `GET /block/:hash/header` and `GET /block/:hash/status`
+//! * `GET /tx/:txid`, `GET /tx/:txid/hex` and `GET /tx/:txid/status`, for indexed
+//!   transactions
+//! * `POST /tx`
+//! * `GET /address/:address/txs` and `GET /address/:address/utxo`
+//! * `GET /fee-estimates`
+//!
+//! Client metrics are served at `GET /metrics`, in the Prometheus text format.
+//!
+//! [Esplora REST API]: https://github.com/Blockstream/esplora/blob/master/API.md
+#![deny(missing_docs, unsafe_code)]
+pub mod http;
+pub mod index;
+
+use std::io::{self, BufReader};
+use std::net;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use crossbeam_channel as chan;
+use microserde::json::{Number, Object, Value};
+use thiserror::Error;
+
+use nakamoto_client::handle::{self, Handle};
+use nakamoto_client::protocol::fees::FeeEstimate;
+use nakamoto_client::protocol::{MessageStats, MessageUsage};
+use nakamoto_client::Event;
+use nakamoto_common::bitcoin::consensus::encode;
+use nakamoto_common::bitcoin::hashes::hex::{FromHex, ToHex};
+use nakamoto_common::bitcoin::{Address, Transaction, Txid};
+use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_wallet::keychain;
+
+pub use http::{Request, Response};
+pub use index::Index;
+
+/// How long to wait for a client to send its request.
+pub const READ_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+/// Maximum number of connections served at the same time. Connections over the limit
+/// are answered with a `503`.
+pub const MAX_CONNECTIONS: usize = 32;
+
+/// A server error.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// A client handle error.
+    #[error("client handle error: {0}")]
+    Handle(#[from] handle::Error),
+    /// An I/O error.
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    /// An address derivation error.
+    #[error("keychain error: {0}")]
+    Keychain(#[from] keychain::Error),
+}
+
+/// An Esplora API server.
+#[derive(Debug, Clone)]
+pub struct Server<H> {
+    handle: H,
+    index: Arc<Mutex<Index>>,
+    /// Latest fee estimate.
+    fees: Arc<Mutex<Option<FeeEstimate>>>,
+    /// Number of connections being served.
+    connections: Arc<AtomicUsize>,
+}
+
+impl<H: Handle + 'static> Server<H> {
+    /// Create a new server, given a client handle and the index of watched addresses.
+    pub fn new(handle: H, index: Index) -> Self {
+        Self {
+            handle,
+            index: Arc::new(Mutex::new(index)),
+            fees: Arc::new(Mutex::new(None)),
+            connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Start scanning the chain for the watched addresses from the given height. The
+    /// scan follows the chain as new blocks are connected.
+    pub fn scan(&self, from: Height) -> Result<(), Error> {
+        let scripts = self
+            .index
+            .lock()
+            .unwrap()
+            .scripts()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        self.handle.rescan(from.., scripts.into_iter())?;
+
+        Ok(())
+    }
+
+    /// Process a client event, updating the index.
+    pub fn process(&self, event: &Event) -> Result<(), Error> {
+        match event {
+            Event::BlockMatched {
+                block,
+                header,
+                transactions,
+            } => {
+                let mut index = self.index.lock().unwrap();
+                let mut derived = Vec::new();
+
+                for tx in transactions {
+                    let confirmation = index::Confirmation {
+                        height: block.height,
+                        block: block.hash,
+                        time: header.time,
+                    };
+                    derived.extend(index.insert(tx.clone(), Some(confirmation))?);
+                }
+                if !derived.is_empty() {
+                    self.handle.watch(derived.into_iter())?;
+                }
+            }
+            Event::BlockDisconnected { block, .. } => {
+                self.index.lock().unwrap().disconnect(block.height);
+            }
+            Event::FeeEstimated { fees, .. } => {
+                *self.fees.lock().unwrap() = Some(fees.clone());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Process client events and serve requests on the given listener, until the client
+    /// shuts down.
+    pub fn run(self, listener: net::TcpListener) -> Result<(), Error> {
+        let events = self.handle.subscribe();
+        let server = self.clone();
+
+        thread::spawn(move || {
+            for event in events {
+                if let Err(err) = server.process(&event) {
+                    log::error!("Failed to process event: {}", err);
+                }
+            }
+        });
+        self.serve(listener)
+    }
+
+    /// Serve requests on the given listener. Each connection is served on its own thread,
+    /// up to [`MAX_CONNECTIONS`] at a time.
+    pub fn serve(&self, listener: net::TcpListener) -> Result<(), Error> {
+        log::info!("Listening on {}..", listener.local_addr()?);
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::error!("Failed to accept connection: {}", err);
+                    continue;
+                }
+            };
+            if self.connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                self.connections.fetch_sub(1, Ordering::SeqCst);
+                log::debug!("Too many connections, rejecting request");
+
+                Response::text(503, "Too many connections")
+                    .write(&stream)
+                    .ok();
+                continue;
+            }
+            let server = self.clone();
+
+            thread::spawn(move || {
+                if let Err(err) = server.connection(stream) {
+                    log::debug!("Connection error: {}", err);
+                }
+                server.connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+        Ok(())
+    }
+
+    /// Respond to a request.
+    pub fn respond(&self, req: &Request) -> Response {
+        let parts = req.path.trim_matches('/').split('/').collect::<Vec<_>>();
+        let result = match (req.method.as_str(), parts.as_slice()) {
+            ("GET", ["blocks", "tip", "height"]) => self
+                .handle
+                .get_tip()
+                .map(|(height, _)| Response::text(200, height))
+                .map_err(Error::from),
+            ("GET", ["blocks", "tip", "hash"]) => self
+                .handle
+                .get_tip()
+                .map(|(_, header)| Response::text(200, header.block_hash()))
+                .map_err(Error::from),
+            ("GET", ["block-height", height]) => match height.parse() {
+                Ok(height) => self.block_hash(height),
+                Err(_) => Ok(Response::text(400, "Invalid block height")),
+            },
+            ("GET", ["block", hash, "header"]) => self.block_header(hash),
+            ("GET", ["block", hash, "status"]) => self.block_status(hash),
+            ("GET", ["tx", txid]) => Ok(self.tx(txid, |tx, status| {
+                let mut obj = transaction(tx);
+                obj.insert("status".to_owned(), status);
+                Response::json(&Value::Object(obj))
+            })),
+            ("GET", ["tx", txid, "hex"]) => {
+                Ok(self.tx(txid, |tx, _| Response::text(200, encode::serialize_hex(tx))))
+            }
+            ("GET", ["tx", txid, "status"]) => {
+                Ok(self.tx(txid, |_, status| Response::json(&status)))
+            }
+            ("POST", ["tx"]) => self.broadcast(&req.body),
+            ("GET", ["address", address, "txs"]) => Ok(self.address(address, |index, script| {
+                let txs = index
+                    .transactions(script)
+                    .into_iter()
+                    .map(|e| {
+                        let mut obj = transaction(&e.transaction);
+                        obj.insert("status".to_owned(), status(e.confirmation));
+                        Value::Object(obj)
+                    })
+                    .collect();
+                Response::json(&Value::Array(txs))
+            })),
+            ("GET", ["address", address, "utxo"]) => Ok(self.address(address, |index, script| {
+                let utxos = index
+                    .utxos(script)
+                    .into_iter()
+                    .map(|u| {
+                        Value::Object(object([
+                            ("txid", Value::String(u.outpoint.txid.to_string())),
+                            ("vout", number(u.outpoint.vout as u64)),
+                            ("status", status(u.confirmation)),
+                            ("value", number(u.output.value)),
+                        ]))
+                    })
+                    .collect();
+                Response::json(&Value::Array(utxos))
+            })),
+            ("GET", ["fee-estimates"]) => {
+                let fees = self.fees.lock().unwrap().clone();
+                let estimates = fees.map_or_else(Object::new, |fees| {
+                    object([
+                        ("1", number(fees.high)),
+                        ("6", number(fees.median)),
+                        ("144", number(fees.low)),
+                    ])
+                });
+                Ok(Response::json(&Value::Object(estimates)))
+            }
+            ("GET", ["metrics"]) => self
+                .handle
+                .get_message_stats()
+                .map(|stats| Response::text(200, metrics(&stats)))
+                .map_err(Error::from),
+            ("GET", _) | ("POST", _) => Ok(Response::text(404, "Not found")),
+            _ => Ok(Response::text(405, "Method not allowed")),
+        };
+
+        result.unwrap_or_else(|err| Response::text(500, err))
+    }
+
+    /// Read a request from a connection, and write the response.
+    fn connection(&self, stream: net::TcpStream) -> Result<(), Error> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+        let response = match Request::read(BufReader::new(&stream)) {
+            Ok(req) => {
+                log::debug!("{} {}", req.method, req.path);
+                self.respond(&req)
+            }
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => Response::text(400, err),
+            Err(err) => return Err(err.into()),
+        };
+        response.write(&stream)?;
+
+        Ok(())
+    }
+
+    /// Run a query on the block tree, and wait for its result.
+    fn query<T: Send + 'static>(
+        &self,
+        query: impl Fn(&dyn nakamoto_common::block::tree::BlockReader) -> T + Send + Sync + 'static,
+    ) -> Result<T, Error> {
+        let (transmit, receive) = chan::bounded(1);
+
+        self.handle.query_tree(move |tree| {
+            transmit.send(query(tree)).ok();
+        })?;
+
+        Ok(receive.recv().map_err(handle::Error::from)?)
+    }
+
+    fn block_hash(&self, height: Height) -> Result<Response, Error> {
+        let hash =
+            self.query(move |tree| tree.get_block_by_height(height).map(|h| h.block_hash()))?;
+
+        Ok(hash.map_or_else(
+            || Response::text(404, "Block not found"),
+            |hash| Response::text(200, hash),
+        ))
+    }
+
+    fn block_header(&self, hash: &str) -> Result<Response, Error> {
+        let Ok(hash) = BlockHash::from_str(hash) else {
+            return Ok(Response::text(400, "Invalid hash"));
+        };
+        let header = self.query(move |tree| tree.get_block(&hash).map(|(_, h)| *h))?;
+
+        Ok(header.map_or_else(
+            || Response::text(404, "Block not found"),
+            |header| Response::text(200, encode::serialize_hex(&header)),
+        ))
+    }
+
+    fn block_status(&self, hash: &str) -> Result<Response, Error> {
+        let Ok(hash) = BlockHash::from_str(hash) else {
+            return Ok(Response::text(400, "Invalid hash"));
+        };
+        let status = self.query(move |tree| {
+            tree.get_block(&hash).map(|(height, _)| {
+                let next = tree.get_block_by_height(height + 1).map(|h| h.block_hash());
+                (height, next)
+            })
+        })?;
+        let obj = match status {
+            Some((height, next)) => object([
+                ("in_best_chain", Value::Bool(true)),
+                ("height", number(height)),
+                (
+                    "next_best",
+                    next.map_or(Value::Null, |h| Value::String(h.to_string())),
+                ),
+            ]),
+            None => object([("in_best_chain", Value::Bool(false))]),
+        };
+        Ok(Response::json(&Value::Object(obj)))
+    }
+
+    fn tx(&self, txid: &str, f: impl FnOnce(&Transaction, Value) -> Response) -> Response {
+        let Ok(txid) = Txid::from_str(txid) else {
+            return Response::text(400, "Invalid transaction id");
+        };
+        match self.index.lock().unwrap().get(&txid) {
+            Some(entry) => f(&entry.transaction, status(entry.confirmation)),
+            None => Response::text(404, "Transaction not found"),
+        }
+    }
+
+    fn broadcast(&self, body: &[u8]) -> Result<Response, Error> {
+        let tx = std::str::from_utf8(body)
+            .ok()
+            .and_then(|hex| Vec::<u8>::from_hex(hex.trim()).ok())
+            .and_then(|bytes| encode::deserialize::<Transaction>(&bytes).ok());
+        let Some(tx) = tx else {
+            return Ok(Response::text(400, "Invalid transaction"));
+        };
+        let txid = tx.txid();
+
+        match self.handle.submit_transaction(tx.clone()) {
+            Ok(_) => {
+                let derived = self.index.lock().unwrap().insert(tx, None)?;
+                if !derived.is_empty() {
+                    self.handle.watch(derived.into_iter())?;
+                }
+                Ok(Response::text(200, txid))
+            }
+            Err(handle::Error::Command(err)) => Ok(Response::text(400, err)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn address(
+        &self,
+        address: &str,
+        f: impl FnOnce(&Index, &nakamoto_common::bitcoin::Script) -> Response,
+    ) -> Response {
+        let Ok(address) = Address::from_str(address) else {
+            return Response::text(400, "Invalid address");
+        };
+        let script = address.script_pubkey();
+        let index = self.index.lock().unwrap();
+
+        if !index.is_watched(&script) {
+            return Response::text(404, "Address not watched");
+        }
+        f(&index, &script)
+    }
+}
+
+/// Render message statistics in the Prometheus text format.
+fn metrics(stats: &MessageStats) -> String {
+    let mut out = String::new();
+
+    for (name, help, value) in [
+        (
+            "nakamoto_messages_total",
+            "Number of messages exchanged with peers.",
+            (|u| u.count) as fn(&MessageUsage) -> u64,
+        ),
+        (
+            "nakamoto_message_bytes_total",
+            "Size of the messages exchanged with peers, in bytes.",
+            |u| u.bytes,
+        ),
+    ] {
+        out.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} counter\n",
+            name, help, name
+        ));
+
+        for (direction, usage) in [("inbound", &stats.inbound), ("outbound", &stats.outbound)] {
+            for (cmd, u) in usage {
+                out.push_str(&format!(
+                    "{}{{direction=\"{}\",command=\"{}\"}} {}\n",
+                    name,
+                    direction,
+                    cmd,
+                    value(u)
+                ));
+            }
+        }
+    }
+    out
+}
+
+/// Build a JSON object from its fields.
+fn object<const N: usize>(fields: [(&str, Value); N]) -> Object {
+    fields.into_iter().map(|(k, v)| (k.to_owned(), v)).collect()
+}
+
+/// A JSON number.
+fn number(n: impl Into<u64>) -> Value {
+    Value::Number(Number::U64(n.into()))
+}
+
+/// The Esplora status object of a transaction.
+fn status(confirmation: Option<index::Confirmation>) -> Value {
+    Value::Object(match confirmation {
+        Some(c) => object([
+            ("confirmed", Value::Bool(true)),
+            ("block_height", number(c.height)),
+            ("block_hash", Value::String(c.block.to_string())),
+            ("block_time", number(c.time)),
+        ]),
+        None => object([("confirmed", Value::Bool(false))]),
+    })
+}
+
+/// The Esplora representation of a transaction, without its status, and without the
+/// previous outputs of its inputs, which aren't known.
+fn transaction(tx: &Transaction) -> Object {
+    let vin = tx
+        .input
+        .iter()
+        .map(|i| {
+            Value::Object(object([
+                ("txid", Value::String(i.previous_output.txid.to_string())),
+                ("vout", number(i.previous_output.vout)),
+                ("scriptsig", Value::String(i.script_sig.to_hex())),
+                (
+                    "witness",
+                    Value::Array(
+                        i.witness
+                            .iter()
+                            .map(|w| Value::String(w.to_hex()))
+                            .collect(),
+                    ),
+                ),
+                ("is_coinbase", Value::Bool(i.previous_output.is_null())),
+                ("sequence", number(i.sequence)),
+            ]))
+        })
+        .collect();
+    let vout = tx
+        .output
+        .iter()
+        .map(|o| {
+            Value::Object(object([
+                ("scriptpubkey", Value::String(o.script_pubkey.to_hex())),
+                ("value", number(o.value)),
+            ]))
+        })
+        .collect();
+
+    object([
+        ("txid", Value::String(tx.txid().to_string())),
+        ("version", Value::Number(Number::I64(tx.version as i64))),
+        ("locktime", number(tx.lock_time)),
+        ("vin", Value::Array(vin)),
+        ("vout", Value::Array(vout)),
+        ("size", number(tx.size() as u64)),
+        ("weight", number(tx.weight() as u64)),
+    ])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let mut stats = MessageStats::default();
+        stats.inbound.insert(
+            "headers",
+            MessageUsage {
+                count: 2,
+                bytes: 212,
+                last: None,
+            },
+        );
+        let metrics = metrics(&stats);
+
+        assert!(metrics.contains("# TYPE nakamoto_messages_total counter\n"));
+        assert!(metrics
+            .contains("nakamoto_messages_total{direction=\"inbound\",command=\"headers\"} 2\n"));
+        assert!(metrics.contains(
+            "nakamoto_message_bytes_total{direction=\"inbound\",command=\"headers\"} 212\n"
+        ));
+    }
+}
@@ -0,0 +1,469 @@
+//! Lightning Dev Kit (LDK) chain backend.
+//!
+//! Adapters allowing nakamoto to be used as the chain source of an LDK-based Lightning
+//! node:
+//!
+//! * [`Filter`] implements [`chain::Filter`], registering the scripts LDK is interested in
+//!   with the client, so that blocks spending or paying to them are matched by compact
+//!   filters.
+//! * [`Confirmer`] drives [`chain::Confirm`] implementations, eg. `ChannelManager` and
+//!   `ChainMonitor`, from the client's events. This is the interface recommended by LDK
+//!   for light clients, since only matching blocks need to be fetched.
+//! * [`Listener`] drives [`chain::Listen`] implementations, connecting every block of the
+//!   chain in order, with only the transactions of matching blocks.
+//! * [`Source`] implements [`BlockSource`], for use with `lightning-block-sync`.
+//!
+//! A typical setup subscribes to the client's events, and passes each event to a
+//! [`Confirmer`]:
+//!
+//! ```no_run
+//! # use nakamoto_client::handle::Handle;
+//! # fn run<H: Handle>(handle: H, manager: &dyn lightning::chain::Confirm) {
+//! let events = handle.subscribe();
+//! let mut confirmer = nakamoto_ldk::Confirmer::new();
+//!
+//! for event in events {
+//!     confirmer.process(&event, &[manager]);
+//! }
+//! # }
+//! ```
+#![deny(missing_docs, unsafe_code)]
+use std::collections::{BTreeMap, HashMap};
+use std::time;
+
+use crossbeam_channel as chan;
+use thiserror::Error;
+
+use lightning::chain::{self, WatchedOutput};
+use lightning_block_sync::{
+    AsyncBlockSourceResult, BlockHeaderData, BlockSource, BlockSourceError,
+};
+
+use nakamoto_client::handle::{self, Handle};
+use nakamoto_client::Event;
+use nakamoto_common::bitcoin::{Block, BlockHeader, Script, Transaction, Txid};
+use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::error::Classify;
+
+/// How long to wait for a block requested from the network.
+pub const BLOCK_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+/// An adapter error.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// A client handle error.
+    #[error("client handle error: {0}")]
+    Handle(#[from] handle::Error),
+    /// A block was not found.
+    #[error("block {0} not found")]
+    NotFound(BlockHash),
+    /// A block could not be fetched in time.
+    #[error("timed out fetching block {0}")]
+    Timeout(BlockHash),
+}
+
+impl Classify for Error {
+    fn code(&self) -> u32 {
+        match self {
+            Self::Handle(err) => err.code(),
+            Self::Timeout(_) => 1007,
+            Self::NotFound(_) => 3028,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Handle(err) => err.is_transient(),
+            Self::Timeout(_) => true,
+            Self::NotFound(_) => false,
+        }
+    }
+}
+
+/// Registers the transactions and outputs LDK is interested in with the client.
+///
+/// Registration doesn't block: the scripts are added to the client's watch list, and are
+/// matched against compact filters from then on. Since blocks are fetched in full when they
+/// match, transactions spending watched outputs in the same block are always delivered,
+/// and [`chain::Filter::register_output`] never returns a transaction.
+#[derive(Debug, Clone)]
+pub struct Filter<H> {
+    handle: H,
+}
+
+impl<H: Handle> Filter<H> {
+    /// Create a new filter, given a client handle.
+    pub fn new(handle: H) -> Self {
+        Self { handle }
+    }
+
+    fn watch(&self, script: &Script) {
+        if let Err(err) = self.handle.watch(std::iter::once(script.clone())) {
+            log::error!("Failed to watch script {}: {}", script, err);
+        }
+    }
+}
+
+impl<H: Handle> chain::Filter for Filter<H> {
+    fn register_tx(&self, _txid: &Txid, script_pubkey: &Script) {
+        self.watch(script_pubkey);
+    }
+
+    fn register_output(&self, output: WatchedOutput) -> Option<(usize, Transaction)> {
+        // Compact filters commit to the scripts of spent outputs, so watching the output's
+        // script is enough to match the blocks spending it.
+        self.watch(&output.script_pubkey);
+
+        None
+    }
+}
+
+/// Drives [`chain::Confirm`] implementations from client events.
+///
+/// Transactions of matching blocks are reported as confirmed, and transactions confirmed in
+/// disconnected blocks are reported as unconfirmed. The best block is only updated once
+/// compact filters were processed up to it, since LDK expects all the relevant
+/// transactions up to the best block to have been reported. The confirmer must therefore
+/// process events from the start, to know the headers of the blocks filters are synced to.
+#[derive(Debug, Default)]
+pub struct Confirmer {
+    /// Heights at which reported transactions were confirmed.
+    confirmed: HashMap<Txid, Height>,
+    /// Headers of the connected blocks that filters weren't synced to yet.
+    connected: BTreeMap<Height, BlockHeader>,
+}
+
+impl Confirmer {
+    /// Create a new confirmer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Process a client event, and notify the given listeners.
+    pub fn process(&mut self, event: &Event, listeners: &[&dyn chain::Confirm]) {
+        match event {
+            Event::BlockConnected { header, block } => {
+                self.connected.insert(block.height, *header);
+            }
+            Event::Synced { height, .. } => {
+                if let Some(header) = self.connected.get(height) {
+                    for listener in listeners {
+                        listener.best_block_updated(header, *height as u32);
+                    }
+                }
+                self.connected = self.connected.split_off(height);
+            }
+            Event::BlockDisconnected { block, .. } => {
+                for listener in listeners {
+                    for txid in listener.get_relevant_txids() {
+                        if matches!(self.confirmed.get(&txid), Some(h) if *h >= block.height) {
+                            listener.transaction_unconfirmed(&txid);
+                        }
+                    }
+                }
+                self.confirmed.retain(|_, h| *h < block.height);
+                self.connected.split_off(&block.height);
+            }
+            Event::BlockMatched {
+                header,
+                block,
+                transactions,
+            } => {
+                let txdata = transactions.iter().enumerate().collect::<Vec<_>>();
+
+                for listener in listeners {
+                    listener.transactions_confirmed(header, &txdata, block.height as u32);
+                }
+                for tx in transactions {
+                    self.confirmed.insert(tx.txid(), block.height);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Drives [`chain::Listen`] implementations from client events.
+///
+/// Blocks are connected in order, once compact filters have been processed up to them:
+/// blocks that matched are connected with their transactions, and other blocks are
+/// connected without transactions.
+#[derive(Debug)]
+pub struct Listener<H> {
+    handle: H,
+    /// Height of the last block connected to the listeners.
+    height: Height,
+    /// Matching blocks not yet connected to the listeners.
+    matched: BTreeMap<Height, (BlockHeader, Vec<Transaction>)>,
+}
+
+impl<H: Handle> Listener<H> {
+    /// Create a new listener, given the height of the listeners' best block.
+    pub fn new(handle: H, height: Height) -> Self {
+        Self {
+            handle,
+            height,
+            matched: BTreeMap::new(),
+        }
+    }
+
+    /// Height of the last block connected to the listeners.
+    pub fn height(&self) -> Height {
+        self.height
+    }
+
+    /// Process a client event, and notify the given listeners.
+    pub fn process(
+        &mut self,
+        event: &Event,
+        listeners: &[&dyn chain::Listen],
+    ) -> Result<(), Error> {
+        match event {
+            Event::BlockMatched {
+                header,
+                block,
+                transactions,
+            } if block.height > self.height => {
+                self.matched
+                    .insert(block.height, (*header, transactions.clone()));
+            }
+            Event::BlockDisconnected { header, block } => {
+                self.matched.retain(|h, _| *h < block.height);
+
+                if block.height <= self.height {
+                    for listener in listeners {
+                        listener.block_disconnected(header, block.height as u32);
+                    }
+                    self.height = block.height - 1;
+                }
+            }
+            Event::Synced { height, .. } if *height > self.height => {
+                let (transmit, receive) = chan::bounded(1);
+                let range = self.height + 1..=*height;
+
+                self.handle.query_tree(move |tree| {
+                    let headers = range
+                        .clone()
+                        .map_while(|h| tree.get_block_by_height(h).map(|b| (h, *b)))
+                        .collect::<Vec<_>>();
+                    transmit.send(headers).ok();
+                })?;
+
+                for (height, header) in receive.recv().map_err(handle::Error::from)? {
+                    let transactions = self
+                        .matched
+                        .remove(&height)
+                        .map(|(_, txs)| txs)
+                        .unwrap_or_default();
+                    let txdata = transactions.iter().enumerate().collect::<Vec<_>>();
+
+                    for listener in listeners {
+                        listener.filtered_block_connected(&header, &txdata, height as u32);
+                    }
+                    self.height = height;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// A [`BlockSource`] backed by the client's header chain. Blocks are fetched from the
+/// network on demand.
+///
+/// Note that requests are served synchronously, ie. the returned futures block until
+/// the client replies.
+#[derive(Debug, Clone)]
+pub struct Source<H> {
+    handle: H,
+}
+
+impl<H: Handle> Source<H> {
+    /// Create a new block source, given a client handle.
+    pub fn new(handle: H) -> Self {
+        Self { handle }
+    }
+
+    /// Get a block header, along with its height and chain work. Headers of stale blocks,
+    /// eg. blocks that were disconnected in a re-org, are served too, so that LDK can find
+    /// the fork point when its best block is no longer in the active chain.
+    pub fn header(&self, hash: &BlockHash) -> Result<BlockHeaderData, Error> {
+        let (transmit, receive) = chan::bounded(1);
+        let hash = *hash;
+
+        self.handle.query_tree(move |tree| {
+            // Headers in the active chain are returned on their own, and stale headers
+            // along with the branch they are on, starting from the fork point.
+            let data = tree.find_branch(&hash).map(|(fork_height, branch)| {
+                let chainwork = branch
+                    .tail
+                    .iter()
+                    .fold(tree.chain_work(fork_height), |work, h| work + h.work());
+
+                BlockHeaderData {
+                    header: *branch.last(),
+                    height: (fork_height + branch.tail.len() as Height) as u32,
+                    chainwork,
+                }
+            });
+            transmit.send(data).ok();
+        })?;
+
+        receive
+            .recv()
+            .map_err(handle::Error::from)?
+            .ok_or(Error::NotFound(hash))
+    }
+
+    /// Fetch a block from the network.
+    pub fn block(&self, hash: &BlockHash) -> Result<Block, Error> {
+        let blocks = self.handle.blocks();
+        self.handle.get_block(hash)?;
+
+        let deadline = time::Instant::now() + BLOCK_TIMEOUT;
+        loop {
+            let timeout = deadline.saturating_duration_since(time::Instant::now());
+
+            match blocks.recv_timeout(timeout) {
+                Ok((block, _)) if block.block_hash() == *hash => return Ok(block),
+                Ok(_) => continue,
+                Err(chan::RecvTimeoutError::Timeout) => return Err(Error::Timeout(*hash)),
+                Err(err) => return Err(handle::Error::from(err).into()),
+            }
+        }
+    }
+}
+
+impl<H: Handle> BlockSource for Source<H> {
+    fn get_header<'a>(
+        &'a self,
+        header_hash: &'a BlockHash,
+        _height_hint: Option<u32>,
+    ) -> AsyncBlockSourceResult<'a, BlockHeaderData> {
+        Box::pin(async move { self.header(header_hash).map_err(error) })
+    }
+
+    fn get_block<'a>(&'a self, header_hash: &'a BlockHash) -> AsyncBlockSourceResult<'a, Block> {
+        Box::pin(async move { self.block(header_hash).map_err(error) })
+    }
+
+    fn get_best_block(&self) -> AsyncBlockSourceResult<'_, (BlockHash, Option<u32>)> {
+        Box::pin(async move {
+            let (height, header) = self.handle.get_tip().map_err(|e| error(e.into()))?;
+
+            Ok((header.block_hash(), Some(height as u32)))
+        })
+    }
+}
+
+/// Convert an adapter error to a block source error.
+fn error(err: Error) -> BlockSourceError {
+    match err {
+        Error::NotFound(_) => BlockSourceError::persistent(err),
+        Error::Handle(_) | Error::Timeout(_) => BlockSourceError::transient(err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::cell::RefCell;
+
+    use lightning::chain::transaction::TransactionData;
+    use nakamoto_common::bitcoin::blockdata::constants;
+    use nakamoto_common::bitcoin::{Network, OutPoint, TxIn, TxOut, Witness};
+    use nakamoto_common::block::Anchor;
+
+    /// Records the calls made to it.
+    #[derive(Default)]
+    struct Recorder {
+        calls: RefCell<Vec<String>>,
+        relevant: Vec<Txid>,
+    }
+
+    impl chain::Confirm for Recorder {
+        fn transactions_confirmed(&self, _: &BlockHeader, txdata: &TransactionData, height: u32) {
+            self.calls
+                .borrow_mut()
+                .push(format!("confirmed {} at {}", txdata.len(), height));
+        }
+
+        fn transaction_unconfirmed(&self, txid: &Txid) {
+            self.calls
+                .borrow_mut()
+                .push(format!("unconfirmed {}", txid));
+        }
+
+        fn best_block_updated(&self, _: &BlockHeader, height: u32) {
+            self.calls.borrow_mut().push(format!("best {}", height));
+        }
+
+        fn get_relevant_txids(&self) -> Vec<Txid> {
+            self.relevant.clone()
+        }
+    }
+
+    #[test]
+    fn test_confirmer() {
+        let header = constants::genesis_block(Network::Regtest).header;
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: 1,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let recorder = Recorder {
+            relevant: vec![tx.txid()],
+            ..Recorder::default()
+        };
+        let mut confirmer = Confirmer::new();
+
+        for event in [
+            Event::BlockConnected {
+                header,
+                block: Anchor::new(7, &header),
+            },
+            Event::BlockMatched {
+                header,
+                block: Anchor::new(7, &header),
+                transactions: vec![tx.clone()],
+            },
+            // The best block is only updated once filters are synced.
+            Event::Synced { height: 7, tip: 7 },
+            // A block above the confirmation is disconnected.
+            Event::BlockDisconnected {
+                header,
+                block: Anchor::new(8, &header),
+            },
+            Event::BlockDisconnected {
+                header,
+                block: Anchor::new(7, &header),
+            },
+            Event::BlockDisconnected {
+                header,
+                block: Anchor::new(6, &header),
+            },
+        ] {
+            confirmer.process(&event, &[&recorder]);
+        }
+
+        assert_eq!(
+            recorder.calls.into_inner(),
+            vec![
+                "confirmed 1 at 7".to_owned(),
+                "best 7".to_owned(),
+                format!("unconfirmed {}", tx.txid()),
+            ]
+        );
+    }
+}
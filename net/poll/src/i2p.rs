@@ -0,0 +1,728 @@
+//! I2P transport, over the SAM v3 bridge of a local I2P router.
+//!
+//! A [`Session`] is created with the router, and lives as long as its control socket is
+//! open. Peers are dialed with [`Session::connect`], given the address they advertised
+//! via `addrv2` (BIP 155), and incoming connections are accepted with [`Session::accept`].
+//! Once a stream is established, it carries plain P2P traffic, like a TCP connection.
+//!
+//! Unlike the reactor, SAM calls are blocking: establishing a connection through the I2P
+//! network can take several seconds, so they are run on dedicated threads by a
+//! [`Transport`], which hands the established streams over to the reactor.
+//!
+//! I2P peers have no IP address, so they are identified by a synthetic socket address,
+//! see [`nakamoto_common::p2p::i2p`].
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time;
+
+use crossbeam_channel as chan;
+
+use nakamoto_common::bitcoin::network::address::AddrV2;
+use nakamoto_common::bitcoin_hashes::{sha256, Hash};
+use nakamoto_common::p2p::base32;
+use nakamoto_common::p2p::i2p as addresses;
+use nakamoto_p2p::protocol::Link;
+
+/// SAM protocol version we speak.
+pub const SAM_VERSION: &str = "3.1";
+/// Default address of the SAM bridge of an I2P router.
+pub const DEFAULT_SAM_ADDRESS: net::SocketAddr =
+    net::SocketAddr::V4(net::SocketAddrV4::new(net::Ipv4Addr::LOCALHOST, 7656));
+/// Maximum time to wait for a reply from the SAM bridge. Building tunnels to a peer can
+/// take a while.
+pub const SAM_TIMEOUT: time::Duration = time::Duration::from_secs(3 * 60);
+/// Maximum length of a SAM reply line.
+const MAX_LINE_LENGTH: usize = 65536;
+/// Length of the fixed part of a destination, before its certificate.
+const DESTINATION_LENGTH: usize = 384;
+/// Alphabet of the base64 encoding used by I2P.
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-~";
+
+/// An I2P address, ie. the SHA-256 hash of a destination, as advertised in `addrv2`
+/// messages. Displayed as `<base32>.b32.i2p`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Address([u8; 32]);
+
+impl Address {
+    /// Suffix of I2P address host names.
+    pub const SUFFIX: &'static str = ".b32.i2p";
+
+    /// Create an address from its bytes.
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Get the I2P address of an `addrv2` address, if it is one.
+    pub fn from_addrv2(addr: &AddrV2) -> Option<Self> {
+        match addr {
+            AddrV2::I2p(bytes) => Some(Self(*bytes)),
+            _ => None,
+        }
+    }
+
+    /// Get the `addrv2` address.
+    pub fn to_addrv2(&self) -> AddrV2 {
+        AddrV2::I2p(self.0)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", base32::encode(&self.0), Self::SUFFIX)
+    }
+}
+
+impl FromStr for Address {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || invalid_data(format!("invalid I2P address `{}`", s));
+        let b32 = s.strip_suffix(Self::SUFFIX).ok_or_else(invalid)?;
+        let bytes = base32::decode(b32).ok_or_else(invalid)?;
+        let bytes = <[u8; 32]>::try_from(bytes.as_slice()).map_err(|_| invalid())?;
+
+        Ok(Self(bytes))
+    }
+}
+
+/// A full I2P destination, ie. the public keys and certificate of an I2P endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Destination(Vec<u8>);
+
+impl Destination {
+    /// Decode a destination, or the destination part of a private key, given in I2P's
+    /// base64 encoding.
+    pub fn from_base64(s: &str) -> io::Result<Self> {
+        let bytes = base64::decode(s).ok_or_else(|| invalid_data("invalid I2P destination"))?;
+
+        Self::from_prefix(&bytes)
+    }
+
+    /// Get the destination at the start of the given bytes, eg. a private key.
+    fn from_prefix(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = || invalid_data("truncated I2P destination");
+        // The certificate is made of a type byte, and a two-byte length.
+        let cert = bytes
+            .get(DESTINATION_LENGTH + 1..DESTINATION_LENGTH + 3)
+            .ok_or_else(invalid)?;
+        let len = DESTINATION_LENGTH + 3 + u16::from_be_bytes([cert[0], cert[1]]) as usize;
+        let bytes = bytes.get(..len).ok_or_else(invalid)?;
+
+        Ok(Self(bytes.to_vec()))
+    }
+
+    /// Get the I2P address of this destination.
+    pub fn address(&self) -> Address {
+        Address(sha256::Hash::hash(&self.0).into_inner())
+    }
+
+    /// Encode the destination in I2P's base64 encoding.
+    pub fn to_base64(&self) -> String {
+        base64::encode(&self.0)
+    }
+}
+
+/// A SAM session with an I2P router. Connections are made from, and accepted on, the
+/// session's destination.
+#[derive(Debug)]
+pub struct Session {
+    /// Address of the SAM bridge.
+    sam: net::SocketAddr,
+    /// Session identifier.
+    id: String,
+    /// Session private key, in I2P's base64 encoding.
+    private_key: String,
+    /// Our destination.
+    destination: Destination,
+    /// Control socket. The session is closed when this socket is.
+    #[allow(dead_code)]
+    control: net::TcpStream,
+}
+
+impl Session {
+    /// Create a session with the SAM bridge at the given address. If no private key is
+    /// given, a transient destination is created, and our I2P address will change with
+    /// every session.
+    pub fn create(sam: net::SocketAddr, private_key: Option<&str>) -> io::Result<Self> {
+        let id = format!("nakamoto-{:016x}", fastrand::u64(..));
+        let mut control = self::handshake(&sam)?;
+
+        let reply = self::command(
+            &mut control,
+            &format!(
+                "SESSION CREATE STYLE=STREAM ID={} DESTINATION={} SIGNATURE_TYPE=7 \
+                 i2cp.leaseSetEncType=4,0",
+                id,
+                private_key.unwrap_or("TRANSIENT"),
+            ),
+        )?;
+        let private_key = match (reply.get("DESTINATION"), private_key) {
+            (Some(key), _) => key.to_owned(),
+            (None, Some(key)) => key.to_owned(),
+            (None, None) => return Err(invalid_data("SAM session reply has no destination")),
+        };
+        let destination = Destination::from_base64(&private_key)?;
+
+        // The session lasts as long as the control socket, however long that is.
+        control.set_read_timeout(None)?;
+
+        log::debug!(
+            target: "net",
+            "I2P session {} created with address {}",
+            id,
+            destination.address()
+        );
+
+        Ok(Self {
+            sam,
+            id,
+            private_key,
+            destination,
+            control,
+        })
+    }
+
+    /// Get our I2P address.
+    pub fn address(&self) -> Address {
+        self.destination.address()
+    }
+
+    /// Get the session private key, to re-create the session with the same address.
+    pub fn private_key(&self) -> &str {
+        &self.private_key
+    }
+
+    /// Connect to a peer. Blocks until the connection is established.
+    pub fn connect(&self, addr: &Address) -> io::Result<net::TcpStream> {
+        let mut stream = self::handshake(&self.sam)?;
+
+        let reply = self::command(&mut stream, &format!("NAMING LOOKUP NAME={}", addr))?;
+        let dest = reply
+            .get("VALUE")
+            .ok_or_else(|| invalid_data("SAM naming reply has no value"))?;
+
+        self::command(
+            &mut stream,
+            &format!(
+                "STREAM CONNECT ID={} DESTINATION={} SILENT=false",
+                self.id, dest
+            ),
+        )?;
+        stream.set_read_timeout(None)?;
+
+        Ok(stream)
+    }
+
+    /// Accept a connection from a peer. Blocks until a peer connects, and returns the
+    /// stream along with the peer's destination.
+    pub fn accept(&self) -> io::Result<(net::TcpStream, Destination)> {
+        let mut stream = self::handshake(&self.sam)?;
+
+        self::command(
+            &mut stream,
+            &format!("STREAM ACCEPT ID={} SILENT=false", self.id),
+        )?;
+        // Wait for as long as it takes for a peer to connect. The peer destination is sent
+        // first, followed by optional port information.
+        stream.set_read_timeout(None)?;
+
+        let line = self::read_line(&mut stream)?;
+        let dest = line
+            .split_whitespace()
+            .next()
+            .ok_or_else(|| invalid_data("SAM accept reply has no destination"))?;
+        let dest = Destination::from_base64(dest)?;
+
+        Ok((stream, dest))
+    }
+}
+
+/// A connection attempt through a [`Transport`] that completed.
+#[derive(Debug)]
+pub enum Connection {
+    /// A connection to or from a peer was established.
+    Established {
+        /// Synthetic address of the peer.
+        addr: net::SocketAddr,
+        /// Stream carrying the peer's traffic.
+        stream: net::TcpStream,
+        /// Whether we dialed the peer, or the peer dialed us.
+        link: Link,
+    },
+    /// A peer could not be dialed.
+    Failed {
+        /// Synthetic address of the peer.
+        addr: net::SocketAddr,
+        /// Connection error.
+        error: io::Error,
+    },
+}
+
+/// Runs the blocking calls of an I2P [`Session`] on their own threads, so that the reactor
+/// isn't held up by them. The reactor is woken up whenever a [`Connection`] is ready.
+///
+/// The session is created in the background, and dial requests made in the meantime are
+/// queued. Nb. threads blocked on the SAM bridge only exit once their call returns.
+#[derive(Debug)]
+pub struct Transport {
+    dials: chan::Sender<(net::SocketAddr, Address)>,
+    connections: chan::Receiver<Connection>,
+}
+
+impl Transport {
+    /// Create a session with the SAM bridge at the given address, accept inbound
+    /// connections, and dial peers as requested.
+    pub fn spawn(sam: net::SocketAddr, waker: Arc<popol::Waker>) -> io::Result<Self> {
+        let (dials, requests) = chan::unbounded::<(net::SocketAddr, Address)>();
+        let (connected, connections) = chan::unbounded();
+
+        thread::Builder::new()
+            .name(String::from("i2p"))
+            .spawn(move || {
+                let notify = move |conn: Connection| {
+                    if connected.send(conn).is_ok() {
+                        waker.wake().ok();
+                    }
+                };
+                let session = match Session::create(sam, None) {
+                    Ok(session) => Arc::new(session),
+                    Err(err) => {
+                        log::error!(target: "net", "Failed to create I2P session: {}", err);
+
+                        for (addr, _) in requests {
+                            notify(Connection::Failed {
+                                addr,
+                                error: io::Error::new(err.kind(), err.to_string()),
+                            });
+                        }
+                        return;
+                    }
+                };
+                log::info!(target: "net", "Listening on {}", session.address());
+
+                {
+                    let session = session.clone();
+                    let notify = notify.clone();
+
+                    thread::Builder::new()
+                        .name(String::from("i2p-accept"))
+                        .spawn(move || loop {
+                            match session.accept() {
+                                Ok((stream, dest)) => notify(Connection::Established {
+                                    addr: addresses::socket_addr(dest.address().0),
+                                    stream,
+                                    link: Link::Inbound,
+                                }),
+                                Err(err) => {
+                                    log::error!(
+                                        target: "net",
+                                        "Failed to accept I2P connection: {}", err
+                                    );
+                                    break;
+                                }
+                            }
+                        })
+                        .ok();
+                }
+
+                for (addr, peer) in requests {
+                    let session = session.clone();
+                    let notify = notify.clone();
+
+                    thread::spawn(move || {
+                        notify(match session.connect(&peer) {
+                            Ok(stream) => Connection::Established {
+                                addr,
+                                stream,
+                                link: Link::Outbound,
+                            },
+                            Err(error) => Connection::Failed { addr, error },
+                        })
+                    });
+                }
+            })?;
+
+        Ok(Self { dials, connections })
+    }
+
+    /// Dial the I2P peer with the given synthetic address. Returns an error if the
+    /// address was never handed out, and can't be resolved.
+    pub fn dial(&self, addr: net::SocketAddr) -> io::Result<()> {
+        let peer = addresses::resolve(&addr).map(Address::new).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::AddrNotAvailable, "unknown I2P address")
+        })?;
+        self.dials
+            .send((addr, peer))
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "I2P session is closed"))
+    }
+
+    /// Get the connections that completed since the last call.
+    pub fn connections(&self) -> chan::TryIter<'_, Connection> {
+        self.connections.try_iter()
+    }
+}
+
+/// A reply from the SAM bridge, eg. `SESSION STATUS RESULT=OK DESTINATION=...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Reply {
+    /// Reply topic, eg. `SESSION STATUS`.
+    topic: String,
+    /// Reply key-value pairs.
+    pairs: Vec<(String, String)>,
+}
+
+impl Reply {
+    /// Get the value of a key.
+    fn get(&self, key: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Turn an unsuccessful reply into an error.
+    fn result(self) -> io::Result<Self> {
+        let kind = match self.get("RESULT") {
+            Some("OK") => return Ok(self),
+            Some("CANT_REACH_PEER") | Some("PEER_NOT_FOUND") => io::ErrorKind::ConnectionRefused,
+            Some("TIMEOUT") => io::ErrorKind::TimedOut,
+            Some("DUPLICATED_ID") | Some("DUPLICATED_DEST") => io::ErrorKind::AlreadyExists,
+            Some("INVALID_ID") | Some("INVALID_KEY") | Some("KEY_NOT_FOUND") => {
+                io::ErrorKind::InvalidInput
+            }
+            _ => io::ErrorKind::Other,
+        };
+        Err(io::Error::new(
+            kind,
+            format!(
+                "SAM {} error: {} {}",
+                self.topic,
+                self.get("RESULT").unwrap_or("unknown"),
+                self.get("MESSAGE").unwrap_or_default(),
+            ),
+        ))
+    }
+}
+
+impl FromStr for Reply {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let topic = match (words.next(), words.next()) {
+            (Some(a), Some(b)) => format!("{} {}", a, b),
+            _ => return Err(invalid_data(format!("malformed SAM reply `{}`", s))),
+        };
+        let mut pairs = Vec::new();
+        let mut rest = s.trim().splitn(3, ' ').nth(2).unwrap_or_default().trim();
+
+        while !rest.is_empty() {
+            let (key, value) = rest
+                .split_once('=')
+                .ok_or_else(|| invalid_data(format!("malformed SAM reply `{}`", s)))?;
+            // Values with spaces, eg. error messages, are quoted.
+            let (value, tail) = if let Some(quoted) = value.strip_prefix('"') {
+                quoted
+                    .split_once('"')
+                    .ok_or_else(|| invalid_data(format!("malformed SAM reply `{}`", s)))?
+            } else {
+                value.split_once(' ').unwrap_or((value, ""))
+            };
+            pairs.push((key.to_owned(), value.to_owned()));
+            rest = tail.trim_start();
+        }
+        Ok(Self { topic, pairs })
+    }
+}
+
+/// Connect to the SAM bridge, and agree on a protocol version.
+fn handshake(sam: &net::SocketAddr) -> io::Result<net::TcpStream> {
+    let mut stream = net::TcpStream::connect_timeout(sam, SAM_TIMEOUT)?;
+
+    stream.set_read_timeout(Some(SAM_TIMEOUT))?;
+    stream.set_write_timeout(Some(SAM_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+
+    self::command(
+        &mut stream,
+        &format!("HELLO VERSION MIN={} MAX={}", SAM_VERSION, SAM_VERSION),
+    )?;
+
+    Ok(stream)
+}
+
+/// Send a command to the SAM bridge, and wait for a successful reply.
+fn command(stream: &mut net::TcpStream, cmd: &str) -> io::Result<Reply> {
+    stream.write_all(cmd.as_bytes())?;
+    stream.write_all(b"\n")?;
+
+    self::read_line(stream)?.parse::<Reply>()?.result()
+}
+
+/// Read a line from the SAM bridge. Reads byte by byte, so as not to consume any of the
+/// peer data that may follow.
+fn read_line(stream: &mut impl Read) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if byte[0] == b'\n' {
+            break;
+        }
+        if line.len() >= MAX_LINE_LENGTH {
+            return Err(invalid_data("SAM reply is too long"));
+        }
+        line.push(byte[0]);
+    }
+    String::from_utf8(line).map_err(|_| invalid_data("SAM reply is not valid UTF-8"))
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Base64, with I2P's alphabet.
+mod base64 {
+    use super::BASE64_ALPHABET;
+
+    pub fn encode(bytes: &[u8]) -> String {
+        let mut s = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+        for chunk in bytes.chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - i * 8));
+
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    s.push(BASE64_ALPHABET[(n >> (18 - i * 6)) as usize & 0x3f] as char);
+                } else {
+                    s.push('=');
+                }
+            }
+        }
+        s
+    }
+
+    pub fn decode(s: &str) -> Option<Vec<u8>> {
+        let s = s.trim_end_matches('=');
+        let mut bytes = Vec::with_capacity(s.len() * 3 / 4);
+        let mut n = 0u32;
+        let mut bits = 0;
+
+        for c in s.bytes() {
+            let v = BASE64_ALPHABET.iter().position(|a| *a == c)? as u32;
+
+            n = n << 6 | v;
+            bits += 6;
+
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((n >> bits) as u8);
+            }
+        }
+        Some(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::BufRead;
+    use std::thread;
+
+    /// A destination with a null certificate, followed by a private key.
+    fn private_key(seed: u8) -> Vec<u8> {
+        let mut bytes = vec![seed; DESTINATION_LENGTH];
+
+        bytes.extend([0, 0, 0]);
+        bytes.extend([0xff; 32]);
+        bytes
+    }
+
+    /// A fake SAM bridge, answering the given number of connections.
+    fn bridge(connections: usize, peer: Destination) -> net::SocketAddr {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming().take(connections) {
+                let mut stream = stream.unwrap();
+                let reader = io::BufReader::new(stream.try_clone().unwrap());
+                let peer = peer.clone();
+
+                thread::spawn(move || {
+                    for line in reader.lines() {
+                        let line = line.unwrap();
+                        let reply = match line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+                            ["HELLO", "VERSION"] => "HELLO REPLY RESULT=OK VERSION=3.1".to_owned(),
+                            ["SESSION", "CREATE"] => format!(
+                                "SESSION STATUS RESULT=OK DESTINATION={}",
+                                base64::encode(&private_key(7))
+                            ),
+                            ["NAMING", "LOOKUP"] if line.contains(&peer.address().to_string()) => {
+                                format!("NAMING REPLY RESULT=OK VALUE={}", peer.to_base64())
+                            }
+                            ["NAMING", "LOOKUP"] => {
+                                "NAMING REPLY RESULT=KEY_NOT_FOUND MESSAGE=\"not found\"".to_owned()
+                            }
+                            ["STREAM", "CONNECT"] => "STREAM STATUS RESULT=OK\nping".to_owned(),
+                            ["STREAM", "ACCEPT"] => format!(
+                                "STREAM STATUS RESULT=OK\n{} FROM_PORT=0 TO_PORT=0\nping",
+                                peer.to_base64()
+                            ),
+                            _ => "UNKNOWN REPLY RESULT=I2P_ERROR".to_owned(),
+                        };
+                        writeln!(stream, "{}", reply).unwrap();
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_encoding() {
+        for bytes in [&b""[..], b"f", b"fo", b"foo", b"foob", b"fooba", b"foobar"] {
+            assert_eq!(base64::decode(&base64::encode(bytes)).unwrap(), bytes);
+        }
+        assert_eq!(base64::encode(&[0xfb, 0xff, 0xbf]), "-~-~");
+        assert!(base64::decode("a+b/").is_none());
+    }
+
+    #[test]
+    fn test_address() {
+        let addr = Address::new([0x4f; 32]);
+        let s = addr.to_string();
+
+        assert_eq!(s.len(), 52 + Address::SUFFIX.len());
+        assert_eq!(s.parse::<Address>().unwrap(), addr);
+        assert_eq!(Address::from_addrv2(&addr.to_addrv2()), Some(addr));
+        assert_eq!(
+            Address::from_addrv2(&AddrV2::Ipv4(net::Ipv4Addr::LOCALHOST)),
+            None
+        );
+        assert!("4f4f.b32.i2p".parse::<Address>().is_err());
+        assert!(s.trim_end_matches(".i2p").parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn test_destination() {
+        let key = private_key(1);
+        let dest = Destination::from_base64(&base64::encode(&key)).unwrap();
+
+        assert_eq!(dest.0, key[..DESTINATION_LENGTH + 3]);
+        assert_eq!(
+            dest.address(),
+            Address(sha256::Hash::hash(&key[..DESTINATION_LENGTH + 3]).into_inner())
+        );
+        assert!(Destination::from_base64(&base64::encode(&key[..DESTINATION_LENGTH])).is_err());
+    }
+
+    #[test]
+    fn test_reply() {
+        let reply = "NAMING REPLY RESULT=KEY_NOT_FOUND MESSAGE=\"no such name\" NAME=x"
+            .parse::<Reply>()
+            .unwrap();
+
+        assert_eq!(reply.topic, "NAMING REPLY");
+        assert_eq!(reply.get("MESSAGE"), Some("no such name"));
+        assert_eq!(reply.get("NAME"), Some("x"));
+        assert_eq!(
+            reply.result().unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert!("HELLO".parse::<Reply>().is_err());
+        assert!("HELLO REPLY RESULT".parse::<Reply>().is_err());
+    }
+
+    #[test]
+    fn test_session() {
+        let peer = Destination::from_prefix(&private_key(9)).unwrap();
+        let sam = bridge(4, peer.clone());
+        let session = Session::create(sam, None).unwrap();
+
+        assert_eq!(
+            session.address(),
+            Destination::from_prefix(&private_key(7)).unwrap().address()
+        );
+        assert_eq!(session.private_key(), base64::encode(&private_key(7)));
+
+        let mut stream = session.connect(&peer.address()).unwrap();
+        assert_eq!(read_line(&mut stream).unwrap(), "ping");
+
+        let (mut stream, dest) = session.accept().unwrap();
+        assert_eq!(dest, peer);
+        assert_eq!(read_line(&mut stream).unwrap(), "ping");
+
+        let err = session.connect(&Address::new([0; 32])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_transport() {
+        let peer = Destination::from_prefix(&private_key(9)).unwrap();
+        // The session, dials to the peer and to an unknown address, and a few accepted
+        // streams, since the fake bridge accepts as many as it's asked for.
+        let sam = bridge(8, peer.clone());
+        let mut sources = popol::Sources::new();
+        let waker = Arc::new(popol::Waker::new(&mut sources, ()).unwrap());
+        let transport = Transport::spawn(sam, waker).unwrap();
+        let peer_addr = addresses::socket_addr(peer.address().0);
+        let unknown_addr = addresses::socket_addr([0; 32]);
+
+        transport.dial(peer_addr).unwrap();
+        transport.dial(unknown_addr).unwrap();
+        assert_eq!(
+            transport
+                .dial(([0xfd60, 0xdb4d, 0xddb5, 0, 0, 0, 0, 1], 0).into())
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::AddrNotAvailable
+        );
+
+        let (mut inbound, mut outbound, mut failed) = (0, 0, 0);
+        let mut events = popol::Events::new();
+
+        while inbound == 0 || outbound + failed < 2 {
+            sources
+                .wait_timeout(&mut events, time::Duration::from_secs(6))
+                .unwrap();
+            for (_, ev) in events.iter() {
+                popol::Waker::reset(ev.source).ok();
+            }
+            for conn in transport.connections() {
+                match conn {
+                    Connection::Established {
+                        addr,
+                        mut stream,
+                        link,
+                    } => {
+                        assert_eq!(addr, peer_addr);
+                        assert_eq!(read_line(&mut stream).unwrap(), "ping");
+
+                        match link {
+                            Link::Inbound => inbound += 1,
+                            Link::Outbound => outbound += 1,
+                        }
+                    }
+                    Connection::Failed { addr, error } => {
+                        assert_eq!(addr, unknown_addr);
+                        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+                        failed += 1;
+                    }
+                }
+            }
+        }
+        assert_eq!((outbound, failed), (1, 1));
+    }
+}
@@ -27,10 +27,13 @@
 #![allow(clippy::new_without_default)]
 #![allow(clippy::inconsistent_struct_constructor)]
 
+#[cfg(feature = "i2p")]
+pub mod i2p;
 #[cfg(unix)]
 pub mod reactor;
 pub mod socket;
 pub mod time;
+pub mod watchdog;
 
 pub use reactor::Reactor;
 
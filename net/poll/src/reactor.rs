@@ -2,13 +2,14 @@
 use crossbeam_channel as chan;
 
 use nakamoto_common::block::time::{LocalDuration, LocalTime};
+use nakamoto_common::p2p::Domain;
 
 use nakamoto_p2p::error::Error;
 use nakamoto_p2p::protocol;
 use nakamoto_p2p::protocol::{Command, DisconnectReason, Event, Io, Link};
 
 use log::*;
-use nakamoto_p2p::traits::Protocol;
+use nakamoto_p2p::traits::{Protocol, ReactorConfig, Step};
 
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
@@ -18,38 +19,49 @@ use std::net;
 use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
 use std::time;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
+
+use socket2::{SockRef, TcpKeepalive};
 
 use crate::fallible;
 use crate::socket::Socket;
 use crate::time::TimeoutManager;
+use crate::watchdog::Watchdog;
 
 /// Maximum time to wait when reading from a socket.
 const READ_TIMEOUT: time::Duration = time::Duration::from_secs(6);
 /// Maximum time to wait when writing to a socket.
 const WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(3);
-/// Maximum amount of time to wait for i/o.
-const WAIT_TIMEOUT: LocalDuration = LocalDuration::from_mins(60);
-/// Socket read buffer size.
-const READ_BUFFER_SIZE: usize = 1024 * 192;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 enum Source {
     Peer(net::SocketAddr),
-    Listener,
+    Listener(net::SocketAddr),
     Waker,
 }
 
 /// A single-threaded non-blocking reactor.
 pub struct Reactor<R: Write + Read, E> {
     peers: HashMap<net::SocketAddr, Socket<R>>,
-    connecting: HashSet<net::SocketAddr>,
+    /// Outbound connections being established, with their deadline.
+    connecting: HashMap<net::SocketAddr, LocalTime>,
+    listening: HashSet<net::SocketAddr>,
+    /// Peers that closed their side of the connection, while we still had data queued
+    /// for them.
+    closing: HashSet<net::SocketAddr>,
     commands: chan::Receiver<Command>,
     publisher: E,
     sources: popol::Sources<Source>,
     waker: Arc<popol::Waker>,
     timeouts: TimeoutManager<()>,
+    dials: TimeoutManager<net::SocketAddr>,
     shutdown: chan::Receiver<()>,
+    config: ReactorConfig,
+    buffer: Vec<u8>,
+    watchdog: Watchdog,
+    /// I2P transport, if configured.
+    #[cfg(feature = "i2p")]
+    i2p: Option<crate::i2p::Transport>,
 }
 
 /// The `R` parameter represents the underlying stream type, eg. `net::TcpStream`.
@@ -61,6 +73,34 @@ impl<R: Write + Read + AsRawFd, E> Reactor<R, E> {
         self.peers.insert(addr, Socket::from(stream, addr, link));
     }
 
+    /// Close outbound connection attempts that weren't established before their deadline.
+    fn expire_dials<P>(&mut self, protocol: &mut P, local_time: LocalTime)
+    where
+        P: Protocol,
+    {
+        let mut expired = Vec::new();
+        self.dials.wake(local_time, &mut expired);
+
+        for addr in expired {
+            // Nb. The deadline may be that of an earlier attempt, in which case the
+            // connection was established, or another attempt was made since.
+            if !matches!(self.connecting.get(&addr), Some(deadline) if *deadline <= local_time) {
+                continue;
+            }
+            debug!("{}: Connection attempt timed out", addr);
+
+            // Nb. The socket is closed when it's dropped.
+            self.unregister_peer(
+                addr,
+                DisconnectReason::ConnectionError(Arc::new(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "connection attempt timed out",
+                ))),
+                protocol,
+            );
+        }
+    }
+
     /// Unregister a peer from the reactor.
     fn unregister_peer<P>(
         &mut self,
@@ -71,6 +111,7 @@ impl<R: Write + Read + AsRawFd, E> Reactor<R, E> {
         P: Protocol,
     {
         self.connecting.remove(&addr);
+        self.closing.remove(&addr);
         self.sources.unregister(&Source::Peer(addr));
         self.peers.remove(&addr);
 
@@ -94,39 +135,78 @@ impl<E: protocol::event::Publisher> nakamoto_p2p::traits::Reactor<E>
         let mut sources = popol::Sources::new();
         let waker = Arc::new(popol::Waker::new(&mut sources, Source::Waker)?);
         let timeouts = TimeoutManager::new(LocalDuration::from_secs(1));
-        let connecting = HashSet::new();
+        let dials = TimeoutManager::new(LocalDuration::from_secs(0));
+        let connecting = HashMap::new();
+        let listening = HashSet::new();
+        let closing = HashSet::new();
+        let config = ReactorConfig::default();
+        let buffer = vec![0; config.read_buffer_size];
+        let watchdog = Watchdog::new(config.slow_step);
 
         Ok(Self {
             peers,
             connecting,
+            listening,
+            closing,
             sources,
             commands,
             publisher,
             waker,
             timeouts,
+            dials,
             shutdown,
+            config,
+            buffer,
+            watchdog,
+            #[cfg(feature = "i2p")]
+            i2p: None,
         })
     }
 
+    /// Configure the reactor.
+    fn configure(&mut self, config: ReactorConfig) {
+        self.buffer = vec![0; config.read_buffer_size];
+        self.watchdog = Watchdog::new(config.slow_step);
+        self.config = config;
+    }
+
     /// Run the given protocol with the reactor.
     fn run<P>(&mut self, listen_addrs: &[net::SocketAddr], mut protocol: P) -> Result<(), Error>
     where
         P: Protocol,
     {
-        let listener = if listen_addrs.is_empty() {
-            None
-        } else {
-            let listener = self::listen(listen_addrs)?;
+        let mut listeners = HashMap::new();
+
+        // Nb. Each address gets its own listener, so that IPv4 and IPv6 addresses
+        // can be listened on at the same time.
+        for addr in listen_addrs {
+            let listener = self::listen(addr)?;
             let local_addr = listener.local_addr()?;
 
-            self.sources
-                .register(Source::Listener, &listener, popol::interest::READ);
+            self.sources.register(
+                Source::Listener(local_addr),
+                &listener,
+                popol::interest::READ,
+            );
             self.publisher.publish(Event::Listening(local_addr));
 
             info!("Listening on {}", local_addr);
 
-            Some(listener)
-        };
+            self.listening.insert(local_addr);
+            listeners.insert(local_addr, listener);
+        }
+
+        if let Some(sam) = self.config.i2p {
+            #[cfg(feature = "i2p")]
+            {
+                self.i2p = Some(crate::i2p::Transport::spawn(sam, self.waker.clone())?);
+            }
+            #[cfg(not(feature = "i2p"))]
+            warn!(
+                "Ignoring I2P bridge {}: reactor was built without the `i2p` feature",
+                sam
+            );
+        }
 
         info!("Initializing protocol..");
 
@@ -141,11 +221,12 @@ impl<E: protocol::event::Publisher> nakamoto_p2p::traits::Reactor<E>
         let mut timeouts = Vec::with_capacity(32);
 
         loop {
-            let timeout = self
-                .timeouts
-                .next(SystemTime::now())
-                .unwrap_or(WAIT_TIMEOUT)
-                .into();
+            let now = SystemTime::now();
+            let timeout = match (self.timeouts.next(now), self.dials.next(now)) {
+                (Some(a), Some(b)) => a.min(b),
+                (a, b) => a.or(b).unwrap_or(self.config.wait_timeout),
+            }
+            .into();
 
             trace!(
                 "Polling {} source(s) and {} timeout(s), waking up in {:?}..",
@@ -181,14 +262,18 @@ impl<E: protocol::event::Publisher> nakamoto_p2p::traits::Reactor<E>
                                 }
 
                                 if ev.writable {
+                                    let started = Instant::now();
                                     self.handle_writable(addr, source, &mut protocol)?;
+                                    self.watchdog.step(Step::Write(*addr), started);
                                 }
                                 if ev.readable {
+                                    let started = Instant::now();
                                     self.handle_readable(addr, &mut protocol);
+                                    self.watchdog.step(Step::Received(*addr), started);
                                 }
                             }
-                            Source::Listener => loop {
-                                if let Some(ref listener) = listener {
+                            Source::Listener(local_addr) => loop {
+                                if let Some(listener) = listeners.get(local_addr) {
                                     let (conn, addr) = match listener.accept() {
                                         Ok((conn, addr)) => (conn, addr),
                                         Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
@@ -202,13 +287,16 @@ impl<E: protocol::event::Publisher> nakamoto_p2p::traits::Reactor<E>
                                     trace!("{}: Accepting peer connection", addr);
 
                                     conn.set_nonblocking(true)?;
+                                    self::configure(&SockRef::from(&conn), &self.config)?;
 
                                     let local_addr = conn.local_addr()?;
                                     let link = Link::Inbound;
 
                                     self.register_peer(addr, conn, link);
 
+                                    let started = Instant::now();
                                     protocol.connected(addr, &local_addr, link);
+                                    self.watchdog.step(Step::Accept(addr), started);
                                 }
                             },
                             Source::Waker => {
@@ -220,11 +308,15 @@ impl<E: protocol::event::Publisher> nakamoto_p2p::traits::Reactor<E>
                                 }
                                 popol::Waker::reset(ev.source).ok();
 
-                                debug_assert!(!self.commands.is_empty());
-
+                                // Nb. The command queue may be empty here, if commands were
+                                // drained by a previous wake-up, before this one was processed.
                                 for cmd in self.commands.try_iter() {
+                                    let started = Instant::now();
                                     protocol.command(cmd);
+                                    self.watchdog.step(Step::Command, started);
                                 }
+                                #[cfg(feature = "i2p")]
+                                self.handle_i2p(&mut protocol);
                             }
                         }
                     }
@@ -235,13 +327,48 @@ impl<E: protocol::event::Publisher> nakamoto_p2p::traits::Reactor<E>
                     self.timeouts.wake(local_time, &mut timeouts);
 
                     if !timeouts.is_empty() {
+                        let started = Instant::now();
                         timeouts.clear();
                         protocol.wake();
+                        self.watchdog.step(Step::Wake, started);
                     }
                 }
                 Err(err) => return Err(err.into()),
             }
+            self.expire_dials(&mut protocol, local_time);
+
+            let started = Instant::now();
             self.process(&mut protocol, local_time);
+            self.watchdog.step(Step::Process, started);
+
+            if let Some(event) = self.watchdog.finish() {
+                warn!("{}", event);
+                self.publisher.publish(event);
+            }
+        }
+    }
+
+    /// Tear down all sockets and timers.
+    fn reset(&mut self) {
+        let sources = self
+            .peers
+            .keys()
+            .map(|addr| Source::Peer(*addr))
+            .chain(self.listening.drain().map(Source::Listener))
+            .collect::<Vec<_>>();
+
+        for source in sources {
+            self.sources.unregister(&source);
+        }
+        self.peers.clear();
+        self.connecting.clear();
+        self.closing.clear();
+        self.timeouts = TimeoutManager::new(LocalDuration::from_secs(1));
+        self.dials = TimeoutManager::new(LocalDuration::from_secs(0));
+
+        #[cfg(feature = "i2p")]
+        {
+            self.i2p = None;
         }
     }
 
@@ -273,15 +400,50 @@ impl<E: protocol::event::Publisher> Reactor<net::TcpStream, E> {
                         source.set(popol::interest::WRITE);
                     }
                 }
+                Io::Connect(addr) if Domain::for_address(&addr) == Domain::I2P => {
+                    trace!("Connecting to {} over I2P...", &addr);
+
+                    // Nb. I2P connections are established in the background, and take
+                    // longer than TCP connections, so they are not subject to the dial
+                    // timeout.
+                    #[cfg(feature = "i2p")]
+                    let result = match &self.i2p {
+                        Some(i2p) => i2p.dial(addr),
+                        None => Err(io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            "I2P is not configured",
+                        )),
+                    };
+                    #[cfg(not(feature = "i2p"))]
+                    let result = Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "I2P is not supported",
+                    ));
+
+                    match result {
+                        Ok(()) => protocol.attempted(&addr),
+                        Err(err) => {
+                            error!("{}: Connection error: {}", addr, err);
+
+                            protocol.disconnected(
+                                &addr,
+                                DisconnectReason::ConnectionError(Arc::new(err)),
+                            );
+                        }
+                    }
+                }
                 Io::Connect(addr) => {
                     trace!("Connecting to {}...", &addr);
 
-                    match self::dial(&addr) {
+                    match self::dial(&addr, &self.config) {
                         Ok(stream) => {
                             trace!("{:#?}", stream);
 
+                            let deadline = local_time + self.config.dial_timeout;
+
                             self.register_peer(addr, stream, Link::Outbound);
-                            self.connecting.insert(addr);
+                            self.connecting.insert(addr, deadline);
+                            self.dials.register(addr, deadline);
 
                             protocol.attempted(&addr);
                         }
@@ -315,6 +477,9 @@ impl<E: protocol::event::Publisher> Reactor<net::TcpStream, E> {
                 Io::Wakeup(timeout) => {
                     self.timeouts.register((), local_time + timeout);
                 }
+                Io::CancelWakeups => {
+                    self.timeouts.clear();
+                }
                 Io::Event(event) => {
                     trace!("Event: {:?}", event);
 
@@ -324,6 +489,59 @@ impl<E: protocol::event::Publisher> Reactor<net::TcpStream, E> {
         }
     }
 
+    /// Register the I2P connections that were established, and report those that failed.
+    #[cfg(feature = "i2p")]
+    fn handle_i2p<P>(&mut self, protocol: &mut P)
+    where
+        P: Protocol,
+    {
+        use crate::i2p::Connection;
+
+        let connections = match &self.i2p {
+            Some(i2p) => i2p.connections().collect::<Vec<_>>(),
+            None => return,
+        };
+        for conn in connections {
+            match conn {
+                Connection::Established { addr, stream, link } => {
+                    if self.peers.contains_key(&addr) {
+                        debug!("{}: Dropping duplicate I2P connection", addr);
+                        continue;
+                    }
+                    let local_addr = match stream
+                        .set_nonblocking(true)
+                        .and_then(|()| self::configure(&SockRef::from(&stream), &self.config))
+                        .and_then(|()| stream.local_addr())
+                    {
+                        Ok(local_addr) => local_addr,
+                        Err(err) => {
+                            error!("{}: Connection error: {}", addr, err);
+
+                            if link.is_outbound() {
+                                protocol.disconnected(
+                                    &addr,
+                                    DisconnectReason::ConnectionError(Arc::new(err)),
+                                );
+                            }
+                            continue;
+                        }
+                    };
+                    self.register_peer(addr, stream, link);
+
+                    let started = Instant::now();
+                    protocol.connected(addr, &local_addr, link);
+                    self.watchdog.step(Step::Accept(addr), started);
+                }
+                Connection::Failed { addr, error } => {
+                    error!("{}: Connection error: {}", addr, error);
+
+                    protocol
+                        .disconnected(&addr, DisconnectReason::ConnectionError(Arc::new(error)));
+                }
+            }
+        }
+    }
+
     fn handle_readable<P>(&mut self, addr: &net::SocketAddr, protocol: &mut P)
     where
         P: Protocol,
@@ -332,7 +550,7 @@ impl<E: protocol::event::Publisher> Reactor<net::TcpStream, E> {
         // during an attempt to write, it will no longer be registered and hence available
         // for reads.
         if let Some(socket) = self.peers.get_mut(addr) {
-            let mut buffer = [0; READ_BUFFER_SIZE];
+            let buffer = &mut self.buffer;
 
             trace!("{}: Socket is readable", addr);
 
@@ -340,7 +558,7 @@ impl<E: protocol::event::Publisher> Reactor<net::TcpStream, E> {
             // we will be notified again if there is still data to be read on the socket.
             // Hence, there is no use in putting this socket read in a loop, as the second
             // invocation would likely block.
-            match socket.read(&mut buffer) {
+            match socket.read(buffer) {
                 Ok(count) => {
                     if count > 0 {
                         trace!("{}: Read {} bytes", addr, count);
@@ -349,9 +567,33 @@ impl<E: protocol::event::Publisher> Reactor<net::TcpStream, E> {
                     } else {
                         trace!("{}: Read 0 bytes", addr);
                         // If we get zero bytes read as a return value, it means the peer has
-                        // performed an orderly shutdown.
-                        socket.disconnect().ok();
-                        self.unregister_peer(*addr, DisconnectReason::PeerDisconnected, protocol);
+                        // performed an orderly shutdown, or at least closed its side of the
+                        // connection. Flush what is still queued for the peer before closing
+                        // ours.
+                        match protocol.write(addr, &mut *socket) {
+                            Err(err)
+                                if [io::ErrorKind::WouldBlock, io::ErrorKind::WriteZero]
+                                    .contains(&err.kind()) =>
+                            {
+                                debug!("{}: Peer closed the connection, flushing writes..", addr);
+
+                                if let Some(source) = self.sources.get_mut(&Source::Peer(*addr)) {
+                                    // Nb. The socket stays readable once the peer has closed
+                                    // its side, so we stop polling for reads.
+                                    source.unset(popol::interest::READ);
+                                    source.set(popol::interest::WRITE);
+                                }
+                                self.closing.insert(*addr);
+                            }
+                            _ => {
+                                socket.disconnect().ok();
+                                self.unregister_peer(
+                                    *addr,
+                                    DisconnectReason::PeerDisconnected,
+                                    protocol,
+                                );
+                            }
+                        }
                     }
                 }
                 Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
@@ -365,7 +607,7 @@ impl<E: protocol::event::Publisher> Reactor<net::TcpStream, E> {
                     socket.disconnect().ok();
                     self.unregister_peer(
                         *addr,
-                        DisconnectReason::ConnectionError(Arc::new(err)),
+                        self::disconnect_reason(err, &self.config),
                         protocol,
                     );
                 }
@@ -389,7 +631,7 @@ impl<E: protocol::event::Publisher> Reactor<net::TcpStream, E> {
         //
         // Since we perform a non-blocking connect, we're only really connected once the socket
         // is writable.
-        if self.connecting.remove(addr) {
+        if self.connecting.remove(addr).is_some() {
             let local_addr = socket.local_address()?;
 
             protocol.connected(socket.address, &local_addr, socket.link);
@@ -401,6 +643,13 @@ impl<E: protocol::event::Publisher> Reactor<net::TcpStream, E> {
             // socket.
             Ok(()) => {
                 source.unset(popol::interest::WRITE);
+
+                // If the peer closed its side of the connection, we can close ours now that
+                // all queued data was written.
+                if self.closing.contains(addr) {
+                    socket.disconnect().ok();
+                    self.unregister_peer(*addr, DisconnectReason::PeerDisconnected, protocol);
+                }
             }
             // In this case, the write couldn't complete. Set
             // our interest to `WRITE` to be notified when the
@@ -414,11 +663,7 @@ impl<E: protocol::event::Publisher> Reactor<net::TcpStream, E> {
                 error!("{}: Write error: {}", addr, err.to_string());
 
                 socket.disconnect().ok();
-                self.unregister_peer(
-                    *addr,
-                    DisconnectReason::ConnectionError(Arc::new(err)),
-                    protocol,
-                );
+                self.unregister_peer(*addr, self::disconnect_reason(err, &self.config), protocol);
             }
         }
         Ok(())
@@ -426,7 +671,7 @@ impl<E: protocol::event::Publisher> Reactor<net::TcpStream, E> {
 }
 
 /// Connect to a peer given a remote address.
-fn dial(addr: &net::SocketAddr) -> Result<net::TcpStream, io::Error> {
+fn dial(addr: &net::SocketAddr, config: &ReactorConfig) -> Result<net::TcpStream, io::Error> {
     use socket2::{Domain, Socket, Type};
     fallible! { io::Error::from(io::ErrorKind::Other) };
 
@@ -440,6 +685,7 @@ fn dial(addr: &net::SocketAddr) -> Result<net::TcpStream, io::Error> {
     sock.set_read_timeout(Some(READ_TIMEOUT))?;
     sock.set_write_timeout(Some(WRITE_TIMEOUT))?;
     sock.set_nonblocking(true)?;
+    self::configure(&sock, config)?;
 
     match sock.connect(&(*addr).into()) {
         Ok(()) => {}
@@ -453,11 +699,62 @@ fn dial(addr: &net::SocketAddr) -> Result<net::TcpStream, io::Error> {
     Ok(sock.into())
 }
 
+/// Set the configured socket options of a peer socket.
+fn configure(sock: &socket2::Socket, config: &ReactorConfig) -> io::Result<()> {
+    sock.set_nodelay(config.nodelay)?;
+
+    if let Some(keepalive) = config.keepalive {
+        let params = TcpKeepalive::new().with_time(keepalive.idle.into());
+
+        // Nb. The probe interval and count can only be set on some platforms.
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_vendor = "apple"
+        ))]
+        let params = params
+            .with_interval(keepalive.interval.into())
+            .with_retries(keepalive.count);
+
+        sock.set_tcp_keepalive(&params)?;
+    }
+    Ok(())
+}
+
+/// Get the reason for disconnecting a peer after a socket error.
+fn disconnect_reason(err: io::Error, config: &ReactorConfig) -> DisconnectReason {
+    // Sockets are non-blocking, so reads and writes only time out when keepalive probes
+    // go unanswered.
+    match err.kind() {
+        io::ErrorKind::TimedOut if config.keepalive.is_some() => DisconnectReason::KeepaliveTimeout,
+        io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe => DisconnectReason::ConnectionReset,
+        _ => DisconnectReason::ConnectionError(Arc::new(err)),
+    }
+}
+
 // Listen for connections on the given address.
-fn listen<A: net::ToSocketAddrs>(addr: A) -> Result<net::TcpListener, Error> {
-    let sock = net::TcpListener::bind(addr)?;
+fn listen(addr: &net::SocketAddr) -> Result<net::TcpListener, Error> {
+    use socket2::{Domain, Socket, Type};
+
+    let domain = if addr.is_ipv4() {
+        Domain::IPV4
+    } else {
+        // Only accept IPv6 connections, so that the IPv4 address can be listened on
+        // separately.
+        Domain::IPV6
+    };
+    let sock = Socket::new(domain, Type::STREAM, None)?;
 
+    if addr.is_ipv6() {
+        sock.set_only_v6(true)?;
+    }
+    sock.set_reuse_address(true)?;
+    sock.bind(&(*addr).into())?;
+    sock.listen(128)?;
     sock.set_nonblocking(true)?;
 
-    Ok(sock)
+    Ok(sock.into())
 }
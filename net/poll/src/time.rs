@@ -135,6 +135,11 @@ impl<K> TimeoutManager<K> {
         }
         woken.len() - before
     }
+
+    /// Remove all timeouts.
+    pub fn clear(&mut self) {
+        self.timeouts.clear();
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,98 @@
+//! Watchdog for slow reactor iterations.
+//!
+//! The reactor runs the protocol on a single thread, so a step that takes long, eg. because
+//! of a pathological message, delays every other peer and command. The watchdog times the
+//! steps of each iteration, and reports iterations that take longer than a threshold,
+//! along with their slowest step.
+use std::time::{Duration, Instant};
+
+use nakamoto_common::block::time::LocalDuration;
+use nakamoto_p2p::protocol::Event;
+use nakamoto_p2p::traits::Step;
+
+/// Times reactor iterations.
+#[derive(Debug)]
+pub struct Watchdog {
+    /// Time after which an iteration is considered slow.
+    threshold: Duration,
+    /// Time spent in the current iteration so far.
+    elapsed: Duration,
+    /// Slowest step of the current iteration.
+    slowest: Option<(Step, Duration)>,
+}
+
+impl Watchdog {
+    /// Create a new watchdog, reporting iterations slower than the given threshold.
+    pub fn new(threshold: LocalDuration) -> Self {
+        Self {
+            threshold: threshold.into(),
+            elapsed: Duration::ZERO,
+            slowest: None,
+        }
+    }
+
+    /// Record a step that started at the given instant, and just finished.
+    pub fn step(&mut self, step: Step, started: Instant) {
+        self.record(step, started.elapsed());
+    }
+
+    /// Finish the current iteration. Returns an event if the iteration was slow.
+    pub fn finish(&mut self) -> Option<Event> {
+        let elapsed = std::mem::take(&mut self.elapsed);
+        let (step, step_elapsed) = self.slowest.take()?;
+
+        if elapsed < self.threshold {
+            return None;
+        }
+        Some(Event::SlowStep {
+            elapsed: LocalDuration::from_millis(elapsed.as_millis()),
+            step,
+            step_elapsed: LocalDuration::from_millis(step_elapsed.as_millis()),
+        })
+    }
+
+    /// Record a step that took the given time.
+    fn record(&mut self, step: Step, elapsed: Duration) {
+        self.elapsed += elapsed;
+
+        if self.slowest.is_none_or(|(_, e)| elapsed > e) {
+            self.slowest = Some((step, elapsed));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog() {
+        let addr = ([88, 88, 88, 1], 8333).into();
+        let mut watchdog = Watchdog::new(LocalDuration::from_millis(100));
+
+        // Fast iterations aren't reported.
+        watchdog.record(Step::Received(addr), Duration::from_millis(40));
+        watchdog.record(Step::Process, Duration::from_millis(20));
+        assert!(watchdog.finish().is_none());
+        assert!(watchdog.finish().is_none());
+
+        // Slow iterations are reported, even if no single step is slow.
+        watchdog.record(Step::Wake, Duration::from_millis(30));
+        watchdog.record(Step::Received(addr), Duration::from_millis(60));
+        watchdog.record(Step::Process, Duration::from_millis(20));
+
+        match watchdog.finish() {
+            Some(Event::SlowStep {
+                elapsed,
+                step,
+                step_elapsed,
+            }) => {
+                assert_eq!(elapsed, LocalDuration::from_millis(110));
+                assert_eq!(step, Step::Received(addr));
+                assert_eq!(step_elapsed, LocalDuration::from_millis(60));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(watchdog.finish().is_none());
+    }
+}
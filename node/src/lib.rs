@@ -2,12 +2,14 @@
 #![deny(missing_docs, unsafe_code)]
 
 use std::net;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub use nakamoto_client::client::{self, Client, Config, Network};
 pub use nakamoto_client::error::Error;
-pub use nakamoto_client::Domain;
+pub use nakamoto_client::{Domain, PeerAddr};
 
+use nakamoto_client::config;
+use nakamoto_client::crawl;
 use nakamoto_client::protocol;
 
 pub mod logger;
@@ -16,19 +18,33 @@ pub mod logger;
 type Reactor = nakamoto_net_poll::Reactor<net::TcpStream, client::Publisher>;
 
 /// Run the light-client. Takes an initial list of peers to connect to, a list of listen addresses,
-/// the client root and the Bitcoin network to connect to.
+/// an optional address to publish notifications on, the client root, the communication
+/// domains, an optional I2P SAM bridge address, the Bitcoin network to connect to, and whether
+/// to only sync block headers.
+#[allow(clippy::too_many_arguments)]
 pub fn run(
-    connect: &[net::SocketAddr],
+    connect: &[PeerAddr],
     listen: &[net::SocketAddr],
+    notify: Option<net::SocketAddr>,
     root: Option<PathBuf>,
     domains: &[Domain],
+    i2p: Option<net::SocketAddr>,
     network: Network,
+    headers_only: bool,
 ) -> Result<(), Error> {
+    let connect = connect
+        .iter()
+        .map(|addr| {
+            addr.socket_addr()
+                .ok_or_else(|| config::Error::UnreachablePeer(addr.clone()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
     let mut cfg = Config {
         protocol: protocol::Config {
-            connect: connect.to_vec(),
+            connect: connect.clone(),
             domains: domains.to_vec(),
             network,
+            headers_only,
             ..protocol::Config::default()
         },
         listen: if listen.is_empty() {
@@ -36,14 +52,51 @@ pub fn run(
         } else {
             listen.to_vec()
         },
+        notify: notify.map(client::notify::Config::new),
         ..Config::default()
     };
     if let Some(path) = root {
         cfg.root = path;
     }
+    cfg.reactor.i2p = i2p;
+
+    if headers_only {
+        cfg.protocol.filter_cache_size = 0;
+    }
     if !connect.is_empty() {
         cfg.protocol.target_outbound_peers = connect.len();
+        cfg.protocol.connect_only = true;
     }
 
     Client::<Reactor>::new()?.run(cfg)
 }
+
+/// Get the local address of the host's cjdns interface, if it has one, ie. if the host
+/// routes [`Domain::CJDNS`] addresses through a local address in `fc00::/8`.
+///
+/// Nb. no packets are sent: this only asks the OS which source address it would use.
+pub fn cjdns_interface() -> Option<net::Ipv6Addr> {
+    let socket = net::UdpSocket::bind((net::Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    // Any address in the cjdns range will do.
+    socket
+        .connect((net::Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 1), 9))
+        .ok()?;
+
+    match socket.local_addr().ok()? {
+        net::SocketAddr::V6(addr) if addr.ip().octets()[0] == 0xfc => Some(*addr.ip()),
+        _ => None,
+    }
+}
+
+/// Crawl the network for reachable peers offering compact filters, and write their addresses
+/// to a seed list file. Returns the number of addresses written.
+pub fn crawl(path: &Path, domains: &[Domain], network: Network) -> Result<usize, Error> {
+    let config = crawl::Config {
+        domains: domains.to_vec(),
+        ..crawl::Config::new(network)
+    };
+    let peers = crawl::crawl(&config);
+    let count = crawl::export(&peers, client::Services::All.into(), path)?;
+
+    Ok(count)
+}
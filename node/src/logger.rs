@@ -5,17 +5,18 @@ use chrono::prelude::*;
 use colored::*;
 use log::{Level, Log, Metadata, Record, SetLoggerError};
 
-struct Logger {
-    level: Level,
-}
+use nakamoto_client::protocol::log_filter;
+
+struct Logger;
 
 impl Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= self.level
+        // Nb. We use the global filter, so that it can be changed at runtime.
+        log_filter::enabled(metadata)
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
+        if log_filter::matches(record) {
             let module = record.module_path().unwrap_or_default();
 
             if record.level() == Level::Error {
@@ -52,9 +53,7 @@ impl Log for Logger {
 
 /// Initialize a new logger.
 pub fn init(level: Level) -> Result<(), SetLoggerError> {
-    let logger = Logger { level };
-
-    log::set_boxed_logger(Box::new(logger))?;
+    log::set_boxed_logger(Box::new(Logger))?;
     log::set_max_level(level.to_level_filter());
 
     Ok(())
@@ -4,16 +4,17 @@ use std::path::PathBuf;
 use argh::FromArgs;
 
 use nakamoto_client::client::Network;
-use nakamoto_node::{logger, Domain};
+use nakamoto_node::{cjdns_interface, logger, Domain, PeerAddr};
 
 #[derive(FromArgs)]
 /// A Bitcoin light client.
 pub struct Options {
-    /// connect to the specified peers only
+    /// connect to the specified peers only, given as <host>:<port>, where the host is an
+    /// IP address, or an I2P address ending in .b32.i2p
     #[argh(option)]
-    pub connect: Vec<net::SocketAddr>,
+    pub connect: Vec<PeerAddr>,
 
-    /// listen on one of these addresses for peer connections.
+    /// listen on these addresses for peer connections.
     #[argh(option)]
     pub listen: Vec<net::SocketAddr>,
 
@@ -29,13 +30,36 @@ pub struct Options {
     #[argh(switch, short = '6')]
     pub ipv6: bool,
 
+    /// also connect to CJDNS addresses, if a cjdns interface is found on the host
+    /// (default: false)
+    #[argh(switch)]
+    pub cjdns: bool,
+
+    /// also connect to I2P addresses, through the SAM bridge of an I2P router at this
+    /// address, eg. 127.0.0.1:7656
+    #[argh(option)]
+    pub i2p: Option<net::SocketAddr>,
+
     /// log level (default: info)
     #[argh(option, default = "log::Level::Info")]
     pub log: log::Level,
 
+    /// publish chain event notifications to subscribers connecting on this address
+    #[argh(option)]
+    pub notify: Option<net::SocketAddr>,
+
     /// root directory for nakamoto files (default: ~)
     #[argh(option)]
     pub root: Option<PathBuf>,
+
+    /// only sync block headers, without compact filters (default: false)
+    #[argh(switch)]
+    pub headers_only: bool,
+
+    /// crawl the network for peers with compact filters, write their addresses to this
+    /// file, and exit
+    #[argh(option)]
+    pub crawl: Option<PathBuf>,
 }
 
 impl Options {
@@ -55,7 +79,7 @@ fn main() {
         Network::Mainnet
     };
 
-    let domains = if opts.ipv4 && opts.ipv6 {
+    let mut domains = if opts.ipv4 && opts.ipv6 {
         vec![Domain::IPV4, Domain::IPV6]
     } else if opts.ipv4 {
         vec![Domain::IPV4]
@@ -65,7 +89,39 @@ fn main() {
         vec![Domain::IPV4, Domain::IPV6]
     };
 
-    if let Err(e) = nakamoto_node::run(&opts.connect, &opts.listen, opts.root, &domains, network) {
+    if opts.cjdns {
+        if let Some(ip) = cjdns_interface() {
+            log::info!("Found cjdns interface with address {}", ip);
+            domains.push(Domain::CJDNS);
+        } else {
+            log::warn!("No cjdns interface found, CJDNS addresses will not be connected to");
+        }
+    }
+    if opts.i2p.is_some() {
+        domains.push(Domain::I2P);
+    }
+
+    if let Some(path) = opts.crawl {
+        match nakamoto_node::crawl(&path, &domains, network) {
+            Ok(count) => log::info!("Exported {} address(es) to {:?}", count, path),
+            Err(e) => {
+                log::error!("Exiting: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Err(e) = nakamoto_node::run(
+        &opts.connect,
+        &opts.listen,
+        opts.notify,
+        opts.root,
+        &domains,
+        opts.i2p,
+        network,
+        opts.headers_only,
+    ) {
         log::error!("Exiting: {}", e);
         std::process::exit(1);
     }
@@ -9,6 +9,8 @@ use crossbeam_channel as crossbeam;
 
 use thiserror::Error;
 
+use nakamoto_common::error::{self, Classify};
+
 /// An error occuring in peer-to-peer networking code.
 #[derive(Error, Debug)]
 pub enum Error {
@@ -25,6 +27,23 @@ pub enum Error {
     Channel(Box<dyn std::error::Error + Send + Sync + 'static>),
 }
 
+impl Classify for Error {
+    fn code(&self) -> u32 {
+        match self {
+            Self::Io(_) => 1000,
+            Self::Encode(_) => 1001,
+            Self::Channel(_) => 5000,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Io(err) => error::is_transient_io(err),
+            Self::Encode(_) | Self::Channel(_) => false,
+        }
+    }
+}
+
 impl<T: Debug + Send + Sync + 'static> From<crossbeam::SendError<T>> for Error {
     fn from(err: crossbeam::SendError<T>) -> Self {
         Self::Channel(Box::new(err))
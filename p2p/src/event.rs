@@ -1,14 +1,75 @@
 //! Events generated by the peer-to-peer system.
-use std::sync::{Arc, Mutex};
+//!
+//! Subscriptions created with [`Subscriber::subscribe`] are unbounded and receive every
+//! event. Subscriptions created with [`Subscriber::subscription`] are bounded: when a
+//! subscriber falls behind, the oldest queued event is dropped to make room for the new one.
+//! Events on these subscriptions are tagged with sequence numbers, so that dropped events
+//! show up as gaps, and consumers can reconcile their state, eg. with a rescan.
+use std::ops::Range;
+use std::sync::{Arc, Mutex, Weak};
 use std::time;
 
 use crossbeam_channel as chan;
 
 pub use chan::RecvTimeoutError;
 
+/// An event, tagged with its sequence number in a [`Subscription`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sequenced<T> {
+    /// Sequence number of the event. Starts at zero, and is incremented for every event
+    /// published to the subscription, including events that were dropped.
+    pub seq: u64,
+    /// The event.
+    pub event: T,
+}
+
+/// A bounded subscription, with a sender-side handle used to drop the oldest events.
+struct Sink<T> {
+    sender: chan::Sender<Sequenced<T>>,
+    /// Used to drop the oldest event when the subscription is full.
+    receiver: chan::Receiver<Sequenced<T>>,
+    /// Whether the [`Subscription`] is still around. Since we hold a receiver, the sender
+    /// can't tell when the subscription is dropped.
+    alive: Weak<()>,
+    /// Sequence number of the next event.
+    seq: u64,
+}
+
+impl<T> Sink<T> {
+    /// Send an event to the subscription, dropping the oldest event if it's full.
+    /// Returns `false` if the subscription was dropped.
+    fn send(&mut self, event: T) -> bool {
+        if self.alive.strong_count() == 0 {
+            return false;
+        }
+        let seq = self.seq;
+        self.seq += 1;
+
+        if let Err(chan::TrySendError::Full(event)) = self.sender.try_send(Sequenced { seq, event })
+        {
+            self.receiver.try_recv().ok();
+            self.sender.try_send(event).ok();
+        }
+        true
+    }
+}
+
+/// Subscriptions to an event channel.
+struct Subscriptions<T> {
+    unbounded: Vec<chan::Sender<T>>,
+    bounded: Vec<Sink<T>>,
+}
+
+impl<T: Clone> Subscriptions<T> {
+    fn emit(&mut self, event: T) {
+        self.unbounded.retain(|s| s.try_send(event.clone()).is_ok());
+        self.bounded.retain_mut(|s| s.send(event.clone()));
+    }
+}
+
 /// An event publish/subscribe channel.
 pub struct Broadcast<E, T> {
-    subscribers: Arc<Mutex<Vec<chan::Sender<T>>>>,
+    subscribers: Arc<Mutex<Subscriptions<T>>>,
     broadcast: Box<dyn FnMut(E, &Emitter<T>) + Send + Sync>,
 }
 
@@ -26,23 +87,27 @@ impl<E, T: Clone> Broadcast<E, T> {
 
 /// Publishes an event to all subscribers.
 pub struct Emitter<T> {
-    subscribers: Arc<Mutex<Vec<chan::Sender<T>>>>,
+    subscribers: Arc<Mutex<Subscriptions<T>>>,
 }
 
 impl<T: Clone> Emitter<T> {
     /// Publish an event to all subscribers.
     pub fn emit(&self, event: T) {
-        self.subscribers
-            .lock()
-            .unwrap()
-            .retain(|s| s.try_send(event.clone()).is_ok());
+        self.subscribers.lock().unwrap().emit(event);
     }
 }
 
 /// An event subscriber.
-#[derive(Clone)]
 pub struct Subscriber<T> {
-    subscribers: Arc<Mutex<Vec<chan::Sender<T>>>>,
+    subscribers: Arc<Mutex<Subscriptions<T>>>,
+}
+
+impl<T> Clone for Subscriber<T> {
+    fn clone(&self) -> Self {
+        Self {
+            subscribers: self.subscribers.clone(),
+        }
+    }
 }
 
 impl<T> Subscriber<T> {
@@ -50,17 +115,59 @@ impl<T> Subscriber<T> {
     pub fn subscribe(&self) -> chan::Receiver<T> {
         let (sender, receiver) = chan::unbounded();
         let mut subs = self.subscribers.lock().unwrap();
-        subs.push(sender);
+        subs.unbounded.push(sender);
 
         receiver
     }
+
+    /// Add a subscription which queues at most `capacity` events. When the subscription is
+    /// full, the oldest event is dropped, which is reported by [`Subscription::gap`].
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is zero.
+    pub fn subscription(&self, capacity: usize) -> Subscription<T> {
+        assert!(
+            capacity > 0,
+            "Subscriber::subscription: capacity must be greater than zero"
+        );
+        let (sender, receiver) = chan::bounded(capacity);
+        let alive = Arc::new(());
+        let mut subs = self.subscribers.lock().unwrap();
+
+        subs.bounded.push(Sink {
+            sender,
+            receiver: receiver.clone(),
+            alive: Arc::downgrade(&alive),
+            seq: 0,
+        });
+
+        Subscription {
+            receiver,
+            next: 0,
+            gap: None,
+            missed: 0,
+            _alive: alive,
+        }
+    }
+
+    /// Get an emitter that publishes events to this subscriber's subscriptions, eg. to
+    /// emit events that don't originate from the broadcast channel.
+    pub fn emitter(&self) -> Emitter<T> {
+        Emitter {
+            subscribers: self.subscribers.clone(),
+        }
+    }
 }
 
 /// Create a new broadcast channel.
 pub fn broadcast<E, T>(
     pipe: impl FnMut(E, &Emitter<T>) + Send + Sync + 'static,
 ) -> (Broadcast<E, T>, Subscriber<T>) {
-    let subscribers = Arc::new(Mutex::new(Vec::new()));
+    let subscribers = Arc::new(Mutex::new(Subscriptions {
+        unbounded: Vec::new(),
+        bounded: Vec::new(),
+    }));
     (
         Broadcast {
             subscribers: subscribers.clone(),
@@ -70,6 +177,78 @@ pub fn broadcast<E, T>(
     )
 }
 
+/// A bounded subscription to broadcast events. See [`Subscriber::subscription`].
+#[derive(Debug)]
+pub struct Subscription<T> {
+    receiver: chan::Receiver<Sequenced<T>>,
+    /// Sequence number of the next event we expect.
+    next: u64,
+    /// Events dropped before the last event received.
+    gap: Option<Range<u64>>,
+    /// Total number of events dropped.
+    missed: u64,
+    _alive: Arc<()>,
+}
+
+impl<T> Subscription<T> {
+    /// Receive the next event, blocking until one is available.
+    pub fn recv(&mut self) -> Result<Sequenced<T>, chan::RecvError> {
+        let event = self.receiver.recv()?;
+
+        Ok(self.received(event))
+    }
+
+    /// Receive the next event, if one is available.
+    pub fn try_recv(&mut self) -> Result<Sequenced<T>, chan::TryRecvError> {
+        let event = self.receiver.try_recv()?;
+
+        Ok(self.received(event))
+    }
+
+    /// Receive the next event, waiting at most for the given timeout.
+    pub fn recv_timeout(
+        &mut self,
+        timeout: time::Duration,
+    ) -> Result<Sequenced<T>, chan::RecvTimeoutError> {
+        let event = self.receiver.recv_timeout(timeout)?;
+
+        Ok(self.received(event))
+    }
+
+    /// Sequence numbers of the events that were dropped right before the last event
+    /// received, if any. When this is set, state derived from the events may be stale.
+    pub fn gap(&self) -> Option<Range<u64>> {
+        self.gap.clone()
+    }
+
+    /// Total number of events dropped since the subscription was created.
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+
+    /// Number of events queued.
+    pub fn len(&self) -> usize {
+        self.receiver.len()
+    }
+
+    /// Whether there are no events queued.
+    pub fn is_empty(&self) -> bool {
+        self.receiver.is_empty()
+    }
+
+    fn received(&mut self, event: Sequenced<T>) -> Sequenced<T> {
+        if event.seq > self.next {
+            self.gap = Some(self.next..event.seq);
+            self.missed += event.seq - self.next;
+        } else {
+            self.gap = None;
+        }
+        self.next = event.seq + 1;
+
+        event
+    }
+}
+
 /// Listen to an event feed, and wait for the given function to return something,
 /// or timeout if the specified amount of time has elapsed.
 pub fn wait<E, F, T>(
@@ -103,3 +282,51 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_subscription_gap() {
+        let (mut broadcast, subscriber) = broadcast(|e: u32, p: &Emitter<u32>| p.emit(e));
+        let mut subscription = subscriber.subscription(2);
+        let unbounded = subscriber.subscribe();
+
+        broadcast.broadcast(1);
+        assert_eq!(subscription.recv().unwrap(), Sequenced { seq: 0, event: 1 });
+        assert_eq!(subscription.gap(), None);
+
+        // The subscription is full after two events, so the oldest ones are dropped.
+        for e in 2..=5 {
+            broadcast.broadcast(e);
+        }
+        assert_eq!(subscription.len(), 2);
+        assert_eq!(subscription.recv().unwrap(), Sequenced { seq: 3, event: 4 });
+        assert_eq!(subscription.gap(), Some(1..3));
+        assert_eq!(subscription.missed(), 2);
+
+        assert_eq!(subscription.recv().unwrap(), Sequenced { seq: 4, event: 5 });
+        assert_eq!(subscription.gap(), None);
+        assert!(subscription.try_recv().is_err());
+
+        // Unbounded subscriptions don't drop events.
+        assert_eq!(
+            unbounded.try_iter().collect::<Vec<_>>(),
+            vec![1, 2, 3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn test_subscription_dropped() {
+        let (mut broadcast, subscriber) = broadcast(|e: u32, p: &Emitter<u32>| p.emit(e));
+        let subscription = subscriber.subscription(1);
+
+        broadcast.broadcast(1);
+        assert_eq!(subscriber.subscribers.lock().unwrap().bounded.len(), 1);
+
+        drop(subscription);
+        broadcast.broadcast(2);
+        assert!(subscriber.subscribers.lock().unwrap().bounded.is_empty());
+    }
+}
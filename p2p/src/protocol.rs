@@ -3,48 +3,73 @@
 use crossbeam_channel as chan;
 use log::*;
 
+pub mod asmap;
+pub mod bloom;
+pub mod capture;
 pub mod event;
+pub mod features;
 pub mod fees;
 pub mod filter_cache;
+pub mod identity;
+pub mod log_filter;
 pub mod output;
+pub mod ratelimit;
+pub mod replay;
+pub mod scheduler;
 
 // Sub-protocols.
 mod addrmgr;
 mod cbfmgr;
+mod dump;
 mod invmgr;
 mod peermgr;
 mod pingmgr;
+mod reindex;
+mod resync;
 mod syncmgr;
 
 #[cfg(test)]
 mod tests;
 
 use addrmgr::AddressManager;
+pub use addrmgr::{is_local, is_routable};
 use cbfmgr::FilterManager;
+pub use features::{Feature, Features};
+pub use identity::Fingerprint;
 use invmgr::InventoryManager;
-use output::{Disconnect as _, Outbox};
+use output::{Disconnect as _, Outbox, Wakeup as _};
 use peermgr::PeerManager;
+pub use peermgr::{Ban, ConnectionType};
 use pingmgr::PingManager;
+use ratelimit::RateLimiter;
+use reindex::Reindex;
+use resync::Resync;
+use scheduler::Schedule;
 use syncmgr::SyncManager;
 
 pub use addrmgr::Event as AddressEvent;
 pub use cbfmgr::Event as FilterEvent;
 pub use invmgr::Event as InventoryEvent;
+pub use invmgr::{PackageError, ReplaceError, TxStatus};
 pub use peermgr::Event as PeerEvent;
+pub use reindex::Event as ReindexEvent;
+pub use resync::Event as ResyncEvent;
 pub use syncmgr::Event as ChainEvent;
 
 use crate::stream;
 use crate::traits;
 
 pub use event::Event;
-pub use output::{DisconnectReason, Io};
+pub use output::{DisconnectReason, Io, QueueLimits};
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{self, Debug};
 use std::ops::{Bound, RangeInclusive};
 use std::sync::Arc;
 use std::{io, net};
 
+use microserde::json::Value;
+
 use nakamoto_common::bitcoin::blockdata::block::BlockHeader;
 use nakamoto_common::bitcoin::consensus::params::Params;
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
@@ -53,18 +78,20 @@ use nakamoto_common::bitcoin::network::message_blockdata::{GetHeadersMessage, In
 use nakamoto_common::bitcoin::network::message_filter::GetCFilters;
 use nakamoto_common::bitcoin::network::message_network::VersionMessage;
 use nakamoto_common::bitcoin::network::Address;
-use nakamoto_common::bitcoin::Script;
+use nakamoto_common::bitcoin::util::merkleblock::MerkleBlock;
+use nakamoto_common::bitcoin::{Script, Txid};
 use nakamoto_common::block::time::AdjustedClock;
+use nakamoto_common::error::Classify;
 
-use nakamoto_common::block::filter::Filters;
+use nakamoto_common::block::filter::{FilterHash, FilterHeader, Filters};
 use nakamoto_common::block::time::{LocalDuration, LocalTime};
 use nakamoto_common::block::tree::{self, BlockReader, BlockTree, ImportResult};
-use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::block::{BlockHash, Height, Work};
 use nakamoto_common::block::{BlockTime, Transaction};
 use nakamoto_common::network;
 use nakamoto_common::nonempty::NonEmpty;
 use nakamoto_common::p2p::peer::AddressSource;
-use nakamoto_common::p2p::{peer, Domain};
+use nakamoto_common::p2p::{peer, Domain, DomainPolicy};
 
 use thiserror::Error;
 
@@ -73,6 +100,9 @@ pub const PROTOCOL_VERSION: u32 = 70016;
 /// Minimum supported peer protocol version.
 /// This version includes support for the `sendheaders` feature.
 pub const MIN_PROTOCOL_VERSION: u32 = 70012;
+/// Maximum supported peer protocol version. Far above any version in use, peers announcing
+/// higher versions are not behaving sensibly.
+pub const MAX_PROTOCOL_VERSION: u32 = 1_000_000;
 /// User agent included in `version` messages.
 pub const USER_AGENT: &str = "/nakamoto:0.3.0/";
 
@@ -159,6 +189,139 @@ impl From<(&peermgr::PeerInfo, &peermgr::Connection)> for Peer {
     }
 }
 
+/// A snapshot of a connected peer's state, as returned by [`Command::QueryPeers`].
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    /// Peer address.
+    pub addr: net::SocketAddr,
+    /// Whether this is an inbound or outbound peer connection.
+    pub link: Link,
+    /// Connection class.
+    pub kind: ConnectionType,
+    /// The peer's services.
+    pub services: ServiceFlags,
+    /// Peer user agent string.
+    pub user_agent: String,
+    /// Connected since this time.
+    pub since: LocalTime,
+    /// Last time bytes were sent to the peer.
+    pub last_send: Option<LocalTime>,
+    /// Last time bytes were received from the peer.
+    pub last_recv: Option<LocalTime>,
+    /// Average ping round-trip time, if known.
+    pub latency: Option<LocalDuration>,
+    /// Misbehavior score. Increases every time the peer sends us invalid data.
+    pub banscore: u32,
+    /// Number of requests sent to the peer that are awaiting a response.
+    pub inflight: usize,
+    /// Number of bytes queued for sending to the peer.
+    pub queued: usize,
+    /// Optional protocol features negotiated with the peer.
+    pub features: Features,
+}
+
+/// Proof-of-work of the active chain and of known forks, as returned by
+/// [`Command::GetChainWork`].
+#[derive(Debug, Clone)]
+pub struct ChainWork {
+    /// Height of the active chain tip.
+    pub height: Height,
+    /// Hash of the active chain tip.
+    pub tip: BlockHash,
+    /// Total proof-of-work of the active chain.
+    pub work: Work,
+    /// Known forks off the active chain, with their best block.
+    pub forks: Vec<tree::Fork>,
+    /// Best height advertised by our peers, if any.
+    pub peer_height: Option<Height>,
+}
+
+impl ChainWork {
+    /// Number of blocks the active chain is behind the best height advertised by peers.
+    pub fn blocks_behind(&self) -> Height {
+        self.peer_height
+            .map_or(0, |h| h.saturating_sub(self.height))
+    }
+
+    /// Get the forks carrying more work than the active chain. Since the active chain is
+    /// always the valid chain with the most work, these are forks that are either invalid
+    /// or that we haven't been able to validate.
+    pub fn heavier_forks(&self) -> impl Iterator<Item = &tree::Fork> {
+        self.forks.iter().filter(move |f| f.work > self.work)
+    }
+}
+
+/// Time of the last exchange of bytes with a peer.
+#[derive(Debug, Default, Clone, Copy)]
+struct Traffic {
+    last_send: Option<LocalTime>,
+    last_recv: Option<LocalTime>,
+}
+
+/// Number of bytes exchanged with peers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    /// Bytes sent.
+    pub sent: u64,
+    /// Bytes received.
+    pub received: u64,
+}
+
+/// Bandwidth used since startup, in each network mode. See [`Command::SetLowData`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Bandwidth {
+    /// Bandwidth used in normal operation.
+    pub normal: Usage,
+    /// Bandwidth used in low-data mode.
+    pub low_data: Usage,
+}
+
+/// Number, size and time of the last of the messages of a single command, in one direction.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MessageUsage {
+    /// Number of messages.
+    pub count: u64,
+    /// Size of the messages in bytes, including message headers.
+    pub bytes: u64,
+    /// Local time of the last message, according to the protocol clock.
+    pub last: Option<LocalTime>,
+}
+
+impl MessageUsage {
+    /// Record a message of the given size, exchanged at the given time.
+    fn record(&mut self, bytes: usize, time: LocalTime) {
+        self.count += 1;
+        self.bytes += bytes as u64;
+        self.last = self.last.max(Some(time));
+    }
+}
+
+/// Messages exchanged with peers since startup, per command, eg. `headers` or `cfilter`.
+/// Useful to find out what dominates bandwidth during sync. Messages with unknown commands
+/// are counted under `unknown`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MessageStats {
+    /// Messages received.
+    pub inbound: BTreeMap<&'static str, MessageUsage>,
+    /// Messages sent.
+    pub outbound: BTreeMap<&'static str, MessageUsage>,
+}
+
+impl MessageStats {
+    /// Total usage of all commands in both directions.
+    pub fn total(&self) -> MessageUsage {
+        self.inbound.values().chain(self.outbound.values()).fold(
+            MessageUsage::default(),
+            |mut total, usage| {
+                total.count += usage.count;
+                total.bytes += usage.bytes;
+                total.last = total.last.max(usage.last);
+                total
+            },
+        )
+    }
+}
+
 /// Link direction of the peer connection.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Link {
@@ -187,8 +350,19 @@ pub enum Command {
     GetBlockByHeight(Height, chan::Sender<Option<BlockHeader>>),
     /// Get connected peers.
     GetPeers(ServiceFlags, chan::Sender<Vec<Peer>>),
+    /// Get a snapshot of all connected peers' state, including peers that haven't
+    /// completed the handshake yet.
+    QueryPeers(chan::Sender<Vec<PeerInfo>>),
     /// Get the tip of the active chain.
     GetTip(chan::Sender<(Height, BlockHeader)>),
+    /// Get the height of the filter header chain.
+    GetFilterTip(chan::Sender<Height>),
+    /// Get the block in which one of our transactions was recently confirmed, if any.
+    GetConfirmation(Txid, chan::Sender<Option<(Height, BlockHash)>>),
+    /// Get the merkle proof of one of our recently confirmed transactions, if any.
+    GetProof(Txid, chan::Sender<Option<(Height, MerkleBlock)>>),
+    /// Get the proof-of-work of the active chain and of known forks.
+    GetChainWork(chan::Sender<ChainWork>),
     /// Get a block from the active chain.
     GetBlock(BlockHash),
     /// Get block filters.
@@ -196,8 +370,10 @@ pub enum Command {
         RangeInclusive<Height>,
         chan::Sender<Result<(), GetFiltersError>>,
     ),
-    /// Rescan the chain for matching scripts and addresses.
+    /// Rescan the chain for matching scripts and addresses. Replaces any existing rescan.
     Rescan {
+        /// Rescan identifier, used in rescan events.
+        id: RescanId,
         /// Start scan from this height. If unbounded, start at the current height.
         from: Bound<Height>,
         /// Stop scanning at this height. If unbounded, don't stop scanning.
@@ -205,6 +381,12 @@ pub enum Command {
         /// Scripts to match on.
         watch: Vec<Script>,
     },
+    /// Pause the rescan with the given id.
+    PauseRescan(RescanId),
+    /// Resume the paused rescan with the given id.
+    ResumeRescan(RescanId),
+    /// Cancel the rescan with the given id.
+    CancelRescan(RescanId),
     /// Update the watchlist with the provided scripts.
     Watch {
         /// Scripts to watch.
@@ -216,10 +398,42 @@ pub enum Command {
     Query(NetworkMessage, chan::Sender<Option<net::SocketAddr>>),
     /// Query the block tree.
     QueryTree(Arc<dyn Fn(&dyn BlockReader) + Send + Sync>),
-    /// Connect to a peer.
-    Connect(net::SocketAddr),
-    /// Disconnect from a peer.
+    /// Start capturing the messages exchanged with peers, or stop capturing with `None`.
+    Capture(Option<capture::Capture>),
+    /// Connect to a peer. Persistent peers are reconnected to whenever they disconnect,
+    /// until they are disconnected with [`Command::Disconnect`].
+    Connect(net::SocketAddr, bool),
+    /// Connect to a peer reachable at any of the given addresses, keeping the first
+    /// connection established. Replies with the addresses that will be dialed.
+    ConnectAny(Vec<net::SocketAddr>, chan::Sender<Vec<net::SocketAddr>>),
+    /// Disconnect from a peer. If the peer is persistent, it isn't reconnected to anymore.
     Disconnect(net::SocketAddr),
+    /// Get our persistent peers, including those added with [`Command::Connect`].
+    GetAddedPeers(chan::Sender<Vec<net::SocketAddr>>),
+    /// Enable or disable network activity. When disabled, all peers are disconnected and
+    /// no connections are made or accepted; stores and protocol state are left intact.
+    SetNetworkActive(bool),
+    /// Sync for the given duration, then disconnect all peers and cancel pending timers
+    /// until the next burst, to minimize radio wakeups on mobile devices. Timer work that
+    /// came due in between is done as soon as the next burst starts. An
+    /// [`Event::Quiesced`] event is emitted when the burst ends. Enabling network activity
+    /// with [`Command::SetNetworkActive`] cancels the burst.
+    SyncBurst(LocalDuration),
+    /// Enable or disable low-data mode, eg. when connectivity is metered. In low-data mode,
+    /// compact filter and block downloads are deferred: only block headers are synced and
+    /// peers are kept alive. Full sync resumes when low-data mode is disabled.
+    SetLowData(bool),
+    /// Get the bandwidth used in each network mode.
+    GetBandwidth(chan::Sender<Bandwidth>),
+    /// Get statistics of the messages exchanged with peers, per command.
+    GetMessageStats(chan::Sender<MessageStats>),
+    /// Ban an address for the given duration, or permanently if no duration is given.
+    /// Existing connections to the address are dropped.
+    Ban(net::IpAddr, Option<LocalDuration>),
+    /// Lift the ban on an address.
+    Unban(net::IpAddr),
+    /// Get the list of banned addresses.
+    ListBans(chan::Sender<Vec<Ban>>),
     /// Import headers directly into the block store.
     ImportHeaders(
         Vec<BlockHeader>,
@@ -227,11 +441,46 @@ pub enum Command {
     ),
     /// Import addresses into the address book.
     ImportAddresses(Vec<Address>),
+    /// Add addresses resolved from DNS seeds to the address book, and connect to them if
+    /// we're short of peers.
+    ImportSeeds(Vec<net::SocketAddr>),
     /// Submit a transaction to the network.
     SubmitTransaction(
         Transaction,
         chan::Sender<Result<NonEmpty<PeerId>, CommandError>>,
     ),
+    /// Replace a submitted transaction with a conflicting transaction, eg. to bump its fee.
+    ReplaceTransaction(
+        Txid,
+        Transaction,
+        chan::Sender<Result<NonEmpty<PeerId>, CommandError>>,
+    ),
+    /// Submit a package of dependent transactions to the network. Replies with the
+    /// status of each transaction.
+    SubmitPackage(
+        Vec<Transaction>,
+        chan::Sender<Result<Vec<(Txid, TxStatus)>, CommandError>>,
+    ),
+    /// Update the protocol configuration at runtime.
+    SetConfig(ConfigUpdate),
+    /// Get all block headers of the active chain and all filter headers, starting
+    /// from the genesis.
+    ExportHeaders(chan::Sender<(Vec<BlockHeader>, Vec<(FilterHash, FilterHeader)>)>),
+    /// Import filter headers, starting from the genesis, directly into the filter store.
+    ImportFilterHeaders(
+        Vec<(FilterHash, FilterHeader)>,
+        chan::Sender<Result<Height, ImportFilterHeadersError>>,
+    ),
+    /// Revalidate the block header and filter header chains from the given height, in the
+    /// background. Replaces any ongoing reindex. Progress is reported with
+    /// [`ReindexEvent`]s.
+    Reindex(Height),
+    /// Get a JSON snapshot of the internal protocol state, for debugging. Peer addresses
+    /// are replaced with pseudonyms, and watched scripts and transactions are only counted.
+    DumpState(chan::Sender<String>),
+    /// Set the log levels per target, eg. `info,nakamoto_p2p::protocol::cbfmgr=trace`.
+    /// See [`log_filter`] for the syntax. Invalid filters are ignored.
+    SetLogFilter(String),
 }
 
 impl fmt::Debug for Command {
@@ -239,36 +488,118 @@ impl fmt::Debug for Command {
         match self {
             Self::GetBlockByHeight(height, _) => write!(f, "GetBlockByHeight({})", height),
             Self::GetPeers(flags, _) => write!(f, "GetPeers({})", flags),
+            Self::QueryPeers(_) => write!(f, "QueryPeers"),
+            Self::GetChainWork(_) => write!(f, "GetChainWork"),
             Self::GetTip(_) => write!(f, "GetTip"),
+            Self::GetFilterTip(_) => write!(f, "GetFilterTip"),
+            Self::GetConfirmation(txid, _) => write!(f, "GetConfirmation({})", txid),
+            Self::GetProof(txid, _) => write!(f, "GetProof({})", txid),
             Self::GetBlock(hash) => write!(f, "GetBlock({})", hash),
             Self::GetFilters(range, _) => write!(f, "GetFilters({:?})", range),
-            Self::Rescan { from, to, watch } => {
-                write!(f, "Rescan({:?}, {:?}, {:?})", from, to, watch)
+            Self::Rescan {
+                id,
+                from,
+                to,
+                watch,
+            } => {
+                write!(f, "Rescan({}, {:?}, {:?}, {:?})", id, from, to, watch)
             }
+            Self::PauseRescan(id) => write!(f, "PauseRescan({})", id),
+            Self::ResumeRescan(id) => write!(f, "ResumeRescan({})", id),
+            Self::CancelRescan(id) => write!(f, "CancelRescan({})", id),
             Self::Watch { watch } => {
                 write!(f, "Watch({:?})", watch)
             }
             Self::Broadcast(msg, _, _) => write!(f, "Broadcast({})", msg.cmd()),
             Self::Query(msg, _) => write!(f, "Query({})", msg.cmd()),
             Self::QueryTree(_) => write!(f, "QueryTree"),
-            Self::Connect(addr) => write!(f, "Connect({})", addr),
+            Self::Capture(capture) => write!(f, "Capture({})", capture.is_some()),
+            Self::Connect(addr, persistent) => write!(f, "Connect({}, {})", addr, persistent),
+            Self::ConnectAny(addrs, _) => write!(f, "ConnectAny({:?})", addrs),
             Self::Disconnect(addr) => write!(f, "Disconnect({})", addr),
+            Self::GetAddedPeers(_) => write!(f, "GetAddedPeers"),
+            Self::SetNetworkActive(active) => write!(f, "SetNetworkActive({})", active),
+            Self::SyncBurst(duration) => write!(f, "SyncBurst({})", duration),
+            Self::SetLowData(enabled) => write!(f, "SetLowData({})", enabled),
+            Self::GetBandwidth(_) => write!(f, "GetBandwidth"),
+            Self::GetMessageStats(_) => write!(f, "GetMessageStats"),
+            Self::Ban(addr, duration) => write!(f, "Ban({}, {:?})", addr, duration),
+            Self::Unban(addr) => write!(f, "Unban({})", addr),
+            Self::ListBans(_) => write!(f, "ListBans"),
             Self::ImportHeaders(_headers, _) => write!(f, "ImportHeaders(..)"),
             Self::ImportAddresses(addrs) => write!(f, "ImportAddresses({:?})", addrs),
+            Self::ImportSeeds(addrs) => write!(f, "ImportSeeds({:?})", addrs),
             Self::SubmitTransaction(tx, _) => write!(f, "SubmitTransaction({:?})", tx),
+            Self::SubmitPackage(txs, _) => write!(f, "SubmitPackage({:?})", txs),
+            Self::ReplaceTransaction(txid, tx, _) => {
+                write!(f, "ReplaceTransaction({}, {:?})", txid, tx)
+            }
+            Self::SetConfig(update) => write!(f, "SetConfig({:?})", update),
+            Self::ExportHeaders(_) => write!(f, "ExportHeaders"),
+            Self::ImportFilterHeaders(_headers, _) => write!(f, "ImportFilterHeaders(..)"),
+            Self::Reindex(height) => write!(f, "Reindex({})", height),
+            Self::DumpState(_) => write!(f, "DumpState"),
+            Self::SetLogFilter(filter) => write!(f, "SetLogFilter({:?})", filter),
         }
     }
 }
 
+/// A runtime configuration update. Only the settings that are set are applied,
+/// the others are left unchanged.
+///
+/// Watch-list gap limits are not part of the protocol configuration, since the
+/// protocol only knows about the scripts it is asked to watch: they are updated
+/// through the wallet, which derives the scripts.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigUpdate {
+    /// Target outbound peer connections.
+    pub target_outbound_peers: Option<usize>,
+    /// Maximum inbound peer connections. Existing connections are kept if
+    /// this is lowered.
+    pub max_inbound_peers: Option<usize>,
+    /// Ping timeout, after which remotes are disconnected.
+    pub ping_timeout: Option<LocalDuration>,
+    /// Maximum log level. Caps the levels set with [`Command::SetLogFilter`].
+    pub log_level: Option<LevelFilter>,
+    /// Limits on the data queued for sending to peers.
+    pub queue_limits: Option<QueueLimits>,
+    /// Limits on the rate of unsolicited messages received from peers.
+    pub rate_limits: Option<ratelimit::Config>,
+}
+
 /// A generic error resulting from processing a [`Command`].
 #[derive(Error, Debug)]
 pub enum CommandError {
     /// Not connected to any peer with the required services.
     #[error("not connected to any peer with the required services")]
     NotConnected,
+    /// None of the given peer addresses can be connected to.
+    #[error("none of the given addresses can be connected to")]
+    NoAddresses,
+    /// The transaction package is invalid.
+    #[error("invalid package: {0}")]
+    InvalidPackage(#[from] PackageError),
+    /// The transaction could not be replaced.
+    #[error("invalid replacement: {0}")]
+    InvalidReplacement(#[from] ReplaceError),
 }
 
-pub use cbfmgr::GetFiltersError;
+impl Classify for CommandError {
+    fn code(&self) -> u32 {
+        match self {
+            Self::NotConnected => 1002,
+            Self::NoAddresses => 1003,
+            Self::InvalidPackage(_) => 3010,
+            Self::InvalidReplacement(_) => 3011,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        matches!(self, Self::NotConnected)
+    }
+}
+
+pub use cbfmgr::{GetFiltersError, ImportFilterHeadersError, RescanId};
 
 /// Holds functions that are used to hook into or alter protocol behavior.
 #[derive(Clone)]
@@ -315,6 +646,25 @@ pub struct Protocol<T, F, P, C> {
     network: network::Network,
     /// Peer message inboxes.
     inbox: HashMap<PeerId, stream::Decoder>,
+    /// Peer traffic.
+    traffic: HashMap<PeerId, Traffic>,
+    /// Trusted peer, and the identity pinned for it, if any.
+    pinned: Option<(PeerId, Fingerprint)>,
+    /// Peer identities, as authenticated by the transport.
+    identities: HashMap<PeerId, Fingerprint>,
+    /// Whether we're in low-data mode.
+    low_data: bool,
+    /// Bandwidth used in each network mode.
+    bandwidth: Bandwidth,
+    /// Statistics of received messages, per command. Sent messages are counted by the
+    /// outbox.
+    inbound: BTreeMap<&'static str, MessageUsage>,
+    /// End of the ongoing sync burst, if any.
+    burst: Option<LocalTime>,
+    /// Whether timers are suspended, after a sync burst ended.
+    quiesced: bool,
+    /// Message rate limiter.
+    limiter: RateLimiter,
     /// Peer address manager.
     addrmgr: AddressManager<P, Outbox, C>,
     /// Blockchain synchronization manager.
@@ -327,6 +677,10 @@ pub struct Protocol<T, F, P, C> {
     peermgr: PeerManager<Outbox, C>,
     /// Inventory manager.
     invmgr: InventoryManager<Outbox, C>,
+    /// Ongoing chain reindex, if any.
+    reindex: Option<Reindex>,
+    /// Network partition detection.
+    resync: Resync,
     /// Network-adjusted clock.
     clock: C,
     /// Informational name of this protocol instance. Used for logging purposes only.
@@ -349,8 +703,50 @@ pub struct Config {
     pub network: network::Network,
     /// Peers to connect to.
     pub connect: Vec<net::SocketAddr>,
+    /// Only connect to the peers in [`Config::connect`]. All other connections are
+    /// refused, and address gossip is disabled.
+    pub connect_only: bool,
+    /// Peer to download full blocks from. Block headers and filters may still be fetched
+    /// from any peer, but blocks are only requested from this peer. If it isn't available,
+    /// block downloads are paused until it is.
+    pub trusted_peer: Option<net::SocketAddr>,
+    /// Identity pinned for the trusted peer. If set, the trusted peer is only negotiated
+    /// with once the transport authenticated it with this identity, and is disconnected
+    /// otherwise. Requires [`Config::trusted_peer`].
+    pub trusted_peer_identity: Option<Fingerprint>,
+    /// Number of random decoy blocks to download along with every block, to obscure
+    /// which blocks we're interested in.
+    pub decoy_blocks: usize,
+    /// Number of bytes of decoy blocks that can be downloaded per hour.
+    pub decoy_budget: usize,
+    /// Download new blocks as soon as their header is announced, if we're watching for
+    /// matches at the tip. Blocks are still only processed once their filter matches.
+    /// Reduces the latency of wallet updates, at the cost of downloading every new block.
+    pub direct_fetch: bool,
+    /// Ask the trusted peer for unconfirmed transactions paying to watched scripts, so that
+    /// incoming payments can be reported before they are confirmed. The watched scripts
+    /// are revealed to the trusted peer, which must support bloom filters, and unconfirmed
+    /// transactions may never be confirmed. Requires [`Config::trusted_peer`].
+    pub mempool_prefetch: bool,
+    /// Only sync block headers. Compact filters aren't synced, so filters can't be fetched
+    /// and rescans don't make progress, and blocks are only downloaded on request. Useful
+    /// when only a trustworthy tip is needed, eg. to verify merkle proofs.
+    pub headers_only: bool,
+    /// Port peers can reach us on from the outside, eg. the port forwarded to our listen
+    /// address. If set, our external IP address is detected from the addresses our outbound
+    /// peers see us as, and advertised to peers, so that other nodes can connect to us.
+    pub external_port: Option<u16>,
+    /// Map of IP prefixes to autonomous systems. If set, addresses are grouped by the
+    /// autonomous system they belong to instead of by prefix, and outbound peers are chosen
+    /// from distinct systems.
+    pub asmap: Option<asmap::Asmap>,
+    /// Secret key used to place peer addresses in buckets. Should be kept across restarts,
+    /// so that addresses stay in the same buckets. If not set, a random key is used.
+    pub addrmgr_key: Option<[u8; 32]>,
     /// Supported communication domains.
     pub domains: Vec<Domain>,
+    /// Policy for choosing the domain of outbound connections.
+    pub domain_policy: DomainPolicy,
     /// Services offered by our peer.
     pub services: ServiceFlags,
     /// Required peer services.
@@ -367,10 +763,35 @@ pub struct Config {
     pub target_outbound_peers: usize,
     /// Maximum inbound peer connections.
     pub max_inbound_peers: usize,
+    /// Target block-relay-only outbound peer connections.
+    pub target_block_relay_peers: usize,
+    /// Cadences of periodic tasks, eg. pings and feeler connections.
+    pub schedule: Schedule,
+    /// Time peers have to complete the handshake, after which they are disconnected.
+    pub handshake_timeout: LocalDuration,
     /// Ping timeout, after which remotes are disconnected.
     pub ping_timeout: LocalDuration,
+    /// Number of expected block intervals without a new block header, after which our
+    /// tip is considered stale.
+    pub stale_tip_factor: u32,
     /// Size in bytes of the compact filter cache.
     pub filter_cache_size: usize,
+    /// Serve compact filters and filter headers to peers, and advertise
+    /// [`ServiceFlags::COMPACT_FILTERS`]. Only filters in the filter cache can be served.
+    pub serve_filters: bool,
+    /// Known filter headers, by height, in addition to the network's built-in filter
+    /// checkpoints. Peers are asked for their filter headers at these heights before we
+    /// sync from them, and are banned if they don't match.
+    pub filter_checkpoints: BTreeMap<Height, FilterHeader>,
+    /// Per-peer message rate limits.
+    pub rate_limits: ratelimit::Config,
+    /// Banned addresses.
+    pub bans: Vec<Ban>,
+    /// Limits on the number of bytes queued for sending to peers.
+    pub queue_limits: QueueLimits,
+    /// Optional protocol features to signal to peers. Features are only used with peers
+    /// that signal them too.
+    pub features: Features,
     /// Log target.
     pub target: &'static str,
     /// Protocol event hooks.
@@ -381,17 +802,39 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             network: network::Network::default(),
-            params: Params::new(network::Network::default().into()),
+            params: network::Network::default().params(),
             connect: Vec::new(),
+            connect_only: false,
+            trusted_peer: None,
+            trusted_peer_identity: None,
+            decoy_blocks: 0,
+            decoy_budget: invmgr::DEFAULT_DECOY_BUDGET,
+            direct_fetch: true,
+            mempool_prefetch: false,
+            headers_only: false,
+            external_port: None,
+            asmap: None,
+            addrmgr_key: None,
             domains: Domain::all(),
+            domain_policy: DomainPolicy::default(),
             services: ServiceFlags::NONE,
             required_services: ServiceFlags::NETWORK,
             whitelist: Whitelist::default(),
             protocol_version: PROTOCOL_VERSION,
             target_outbound_peers: peermgr::TARGET_OUTBOUND_PEERS,
             max_inbound_peers: peermgr::MAX_INBOUND_PEERS,
+            target_block_relay_peers: peermgr::TARGET_BLOCK_RELAY_PEERS,
+            schedule: Schedule::default(),
+            handshake_timeout: peermgr::HANDSHAKE_TIMEOUT,
             ping_timeout: pingmgr::PING_TIMEOUT,
+            stale_tip_factor: syncmgr::STALE_TIP_FACTOR,
             filter_cache_size: cbfmgr::DEFAULT_FILTER_CACHE_SIZE,
+            serve_filters: false,
+            filter_checkpoints: BTreeMap::new(),
+            rate_limits: ratelimit::Config::default(),
+            bans: Vec::new(),
+            queue_limits: QueueLimits::default(),
+            features: [Feature::WtxidRelay, Feature::AddrV2].into_iter().collect(),
             user_agent: USER_AGENT,
             target: "self",
             hooks: Hooks::default(),
@@ -406,7 +849,7 @@ impl Config {
         network: network::Network,
         connect: Vec<net::SocketAddr>,
     ) -> Self {
-        let params = Params::new(network.into());
+        let params = network.params();
 
         Self {
             network,
@@ -450,15 +893,37 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Protoco
     ) -> Self {
         let Config {
             network,
-            connect,
+            mut connect,
+            connect_only,
+            trusted_peer,
+            trusted_peer_identity,
+            decoy_blocks,
+            decoy_budget,
+            direct_fetch,
+            mempool_prefetch,
+            headers_only,
+            external_port,
+            asmap,
+            addrmgr_key,
             domains,
-            services,
+            domain_policy,
+            mut services,
             whitelist,
             protocol_version,
             target_outbound_peers,
             max_inbound_peers,
+            target_block_relay_peers,
+            schedule,
+            handshake_timeout,
             ping_timeout,
+            stale_tip_factor,
             filter_cache_size,
+            serve_filters,
+            filter_checkpoints,
+            rate_limits,
+            bans,
+            queue_limits,
+            features,
             user_agent,
             required_services,
             target,
@@ -466,22 +931,46 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Protoco
             hooks,
         } = config;
 
-        let outbox = Outbox::new(network, protocol_version, target);
+        // Make sure we maintain a connection to the trusted peer.
+        if let Some(addr) = trusted_peer {
+            if !connect.contains(&addr) {
+                connect.insert(0, addr);
+            }
+        }
+        if serve_filters {
+            services |= ServiceFlags::COMPACT_FILTERS;
+        }
+        let outbox = Outbox::new(network.clone(), protocol_version, target).limits(queue_limits);
         let inbox = HashMap::new();
         let syncmgr = SyncManager::new(
             syncmgr::Config {
                 max_message_headers: syncmgr::MAX_MESSAGE_HEADERS,
                 request_timeout: syncmgr::REQUEST_TIMEOUT,
+                stale_tip_factor,
+                stale_tip_check: schedule.stale_tip_check,
                 params,
             },
             rng.clone(),
             outbox.clone(),
             clock.clone(),
         );
-        let pingmgr = PingManager::new(ping_timeout, rng.clone(), outbox.clone(), clock.clone());
+        let pingmgr = PingManager::new(
+            ping_timeout,
+            schedule.ping,
+            rng.clone(),
+            outbox.clone(),
+            clock.clone(),
+        );
         let cbfmgr = FilterManager::new(
             cbfmgr::Config {
-                filter_cache_size,
+                // Filters are never fetched when only syncing headers.
+                filter_cache_size: if headers_only { 0 } else { filter_cache_size },
+                serve: serve_filters,
+                sync: !headers_only,
+                checkpoints: network
+                    .filter_checkpoints()
+                    .chain(filter_checkpoints)
+                    .collect(),
                 ..cbfmgr::Config::default()
             },
             rng.clone(),
@@ -494,15 +983,26 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Protoco
                 protocol_version: PROTOCOL_VERSION,
                 whitelist,
                 persistent: connect,
+                connect_only,
                 domains: domains.clone(),
+                domain_policy,
                 target_outbound_peers,
                 max_inbound_peers,
+                target_block_relay_peers,
+                feeler: schedule.feeler,
+                handshake_timeout,
                 retry_max_wait: LocalDuration::from_mins(60),
                 retry_min_wait: LocalDuration::from_secs(1),
                 required_services,
-                preferred_services: syncmgr::REQUIRED_SERVICES | cbfmgr::REQUIRED_SERVICES,
+                preferred_services: if headers_only {
+                    syncmgr::REQUIRED_SERVICES
+                } else {
+                    syncmgr::REQUIRED_SERVICES | cbfmgr::REQUIRED_SERVICES
+                },
                 services,
                 user_agent,
+                bans,
+                features,
             },
             rng.clone(),
             hooks.clone(),
@@ -513,13 +1013,32 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Protoco
             addrmgr::Config {
                 required_services,
                 domains,
+                gossip: !connect_only,
+                request: schedule.addr_request,
+                services,
+                external_port,
+                advertise: schedule.advertise,
+                asmap,
+                key: addrmgr_key,
             },
             rng.clone(),
             peers,
             outbox.clone(),
             clock.clone(),
         );
-        let invmgr = InventoryManager::new(rng.clone(), outbox.clone(), clock.clone());
+        let invmgr = InventoryManager::new(
+            invmgr::Config {
+                trusted_peer,
+                decoys: decoy_blocks,
+                decoy_budget,
+                rebroadcast: schedule.rebroadcast,
+                direct_fetch: direct_fetch && !headers_only,
+                mempool_prefetch,
+            },
+            rng.clone(),
+            outbox.clone(),
+            clock.clone(),
+        );
 
         Self {
             tree,
@@ -527,12 +1046,23 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Protoco
             target,
             clock,
             inbox,
+            traffic: HashMap::new(),
+            pinned: trusted_peer.zip(trusted_peer_identity),
+            identities: HashMap::new(),
+            low_data: false,
+            bandwidth: Bandwidth::default(),
+            inbound: BTreeMap::new(),
+            burst: None,
+            quiesced: false,
+            limiter: RateLimiter::new(rate_limits, rng.clone()),
             addrmgr,
             syncmgr,
             pingmgr,
             cbfmgr,
             peermgr,
             invmgr,
+            reindex: None,
+            resync: Resync::default(),
             last_tick: LocalTime::default(),
             rng,
             outbox,
@@ -567,6 +1097,32 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Protoco
             return;
         }
 
+        // Responses to our own requests are not rate-limited.
+        let solicited = match &msg.payload {
+            NetworkMessage::Headers(_) => self.syncmgr.inflight(&addr) > 0,
+            payload => self.cbfmgr.is_solicited(&addr, payload, &self.tree),
+        };
+        match self.limiter.received(&addr, &msg.payload, solicited, now) {
+            ratelimit::Verdict::Allow => {}
+            ratelimit::Verdict::Throttle => {
+                debug!(target: self.target, "{}: Message {:?} dropped: rate limited", addr, cmd);
+                return;
+            }
+            ratelimit::Verdict::Misbehave => {
+                warn!(
+                    target: self.target,
+                    "{}: Message {:?} dropped: peer is flooding us (banscore = {})",
+                    addr,
+                    cmd,
+                    self.limiter.banscore(&addr)
+                );
+                return;
+            }
+            ratelimit::Verdict::Disconnect => {
+                return self.disconnect(addr, DisconnectReason::PeerMisbehaving("message flood"));
+            }
+        }
+
         match msg.payload {
             NetworkMessage::Version(msg) => {
                 let height = self.tree.height();
@@ -575,10 +1131,25 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Protoco
                     .received_version(&addr, msg, height, &mut self.addrmgr);
             }
             NetworkMessage::Verack => {
+                // Don't negotiate with an impersonator of our trusted peer.
+                if let Some((trusted, pinned)) = self.pinned {
+                    if addr == trusted && self.identities.get(&addr) != Some(&pinned) {
+                        return self.disconnect(addr, DisconnectReason::PeerIdentity);
+                    }
+                }
                 if let Some((peer, conn)) = self.peermgr.received_verack(&addr, now) {
                     self.clock.record_offset(conn.socket.addr, peer.time_offset);
-                    self.addrmgr
-                        .peer_negotiated(&addr, peer.services, conn.link);
+                    self.addrmgr.peer_negotiated(
+                        &addr,
+                        peer.services,
+                        conn.link,
+                        conn.kind == ConnectionType::FullRelay,
+                        peer.negotiated().has(Feature::AddrV2),
+                    );
+                    // Feeler connections are closed as soon as they are negotiated.
+                    if conn.kind == ConnectionType::Feeler {
+                        return;
+                    }
                     self.pingmgr.peer_negotiated(conn.socket.addr);
                     self.cbfmgr.peer_negotiated(
                         conn.socket.clone(),
@@ -598,9 +1169,15 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Protoco
                     self.invmgr.peer_negotiated(
                         conn.socket,
                         peer.services,
-                        peer.relay,
-                        peer.wtxidrelay,
+                        peer.relay && conn.kind == ConnectionType::FullRelay,
+                        peer.negotiated().has(Feature::WtxidRelay),
                     );
+                    // If we were partitioned from the network, check that our tip is still
+                    // in agreement with this peer's.
+                    if conn.link.is_outbound() && self.resync.negotiated(addr, now) {
+                        self.syncmgr.reconcile(addr, &self.tree);
+                        self.outbox.wakeup(resync::RECONCILE_TIMEOUT);
+                    }
                 }
             }
             NetworkMessage::Ping(nonce) => {
@@ -619,20 +1196,13 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Protoco
                     .received_headers(&addr, headers, &self.clock, &mut self.tree)
                 {
                     Err(e) => log::error!("Error receiving headers: {}", e),
-                    Ok(ImportResult::TipChanged(_, _, _, reverted, _)) => {
-                        // Nb. the reverted blocks are ordered from the tip down to
-                        // the oldest ancestor.
-                        if let Some((height, _)) = reverted.last() {
-                            // The height we need to rollback to, ie. the tip of our new chain
-                            // and the tallest block we are keeping.
-                            let fork_height = height - 1;
-                            self.cbfmgr.rollback(fork_height).unwrap();
-
-                            for (height, _) in reverted {
-                                for tx in self.invmgr.block_reverted(height) {
-                                    self.cbfmgr.watch_transaction(&tx);
-                                }
-                            }
+                    Ok(ImportResult::TipChanged(_, hash, height, reverted, connected)) => {
+                        self.resync.tip_changed(reverted.len(), connected.len());
+                        self.blocks_reverted(&reverted);
+                        // If the new tip could be relevant to us, fetch it right away from the
+                        // peer that announced it, instead of waiting for its filter.
+                        if self.cbfmgr.is_watching(height) {
+                            self.invmgr.prefetch_block(hash, addr);
                         }
                         // Trigger a filter sync, since we're going to have to catch up on the
                         // new block header(s). This is not required, but reduces latency.
@@ -643,6 +1213,7 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Protoco
                     }
                     _ => {}
                 }
+                self.reconcile();
             }
             NetworkMessage::GetHeaders(GetHeadersMessage {
                 locator_hashes,
@@ -652,22 +1223,33 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Protoco
                 self.syncmgr
                     .received_getheaders(&addr, (locator_hashes, stop_hash), &self.tree);
             }
+            NetworkMessage::SendHeaders => {
+                self.syncmgr.received_sendheaders(&addr);
+            }
             NetworkMessage::Block(block) => {
                 for confirmed in self.invmgr.received_block(&addr, block, &self.tree) {
                     self.cbfmgr.unwatch_transaction(&confirmed);
                 }
             }
             NetworkMessage::Inv(inventory) => {
+                self.invmgr.received_inv(addr, &inventory);
                 self.syncmgr.received_inv(addr, inventory, &self.tree);
                 // TODO: invmgr: Update block availability for this peer.
             }
+            NetworkMessage::Tx(tx) => {
+                self.invmgr.received_tx(addr, tx);
+            }
             NetworkMessage::CFHeaders(msg) => {
                 match self.cbfmgr.received_cfheaders(&addr, msg, &self.tree) {
                     Err(cbfmgr::Error::InvalidMessage { reason, .. }) => {
                         self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
                     }
+                    Err(cbfmgr::Error::InvalidCheckpoint { .. }) => self
+                        .peermgr
+                        .ban(addr.ip(), Some(cbfmgr::CHECKPOINT_BAN_DURATION)),
                     _ => {}
                 }
+                self.reconcile();
             }
             NetworkMessage::GetCFHeaders(msg) => {
                 match self.cbfmgr.received_getcfheaders(&addr, msg, &self.tree) {
@@ -681,22 +1263,60 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Protoco
                 match self.cbfmgr.received_cfilter(&addr, msg, &self.tree) {
                     Ok(matches) => {
                         for (_, hash) in matches {
-                            self.invmgr.get_block(hash);
+                            for confirmed in self.invmgr.get_filtered_block(hash, addr, &self.tree)
+                            {
+                                self.cbfmgr.unwatch_transaction(&confirmed);
+                            }
                         }
                     }
                     Err(cbfmgr::Error::InvalidMessage { reason, .. }) => {
                         self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
                     }
-                    Err(cbfmgr::Error::Ignored { .. } | cbfmgr::Error::Filters { .. }) => {}
+                    Err(
+                        cbfmgr::Error::Ignored { .. }
+                        | cbfmgr::Error::InvalidCheckpoint { .. }
+                        | cbfmgr::Error::Filters { .. },
+                    ) => {}
                 }
             }
             NetworkMessage::GetCFilters(msg) => {
+                match self
+                    .cbfmgr
+                    .received_getcfilters(&addr, msg.clone(), &self.tree)
+                {
+                    Err(cbfmgr::Error::InvalidMessage { reason, .. }) => {
+                        self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
+                    }
+                    _ => {}
+                }
                 (*self.hooks.on_getcfilters)(addr, msg, &self.outbox);
             }
+            NetworkMessage::CFCheckpt(msg) => {
+                match self.cbfmgr.received_cfcheckpt(&addr, msg, &self.tree) {
+                    Err(cbfmgr::Error::InvalidMessage { reason, .. }) => {
+                        self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
+                    }
+                    Err(cbfmgr::Error::InvalidCheckpoint { .. }) => self
+                        .peermgr
+                        .ban(addr.ip(), Some(cbfmgr::CHECKPOINT_BAN_DURATION)),
+                    _ => {}
+                }
+            }
+            NetworkMessage::GetCFCheckpt(msg) => {
+                match self.cbfmgr.received_getcfcheckpt(&addr, msg, &self.tree) {
+                    Err(cbfmgr::Error::InvalidMessage { reason, .. }) => {
+                        self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
+                    }
+                    _ => {}
+                }
+            }
             NetworkMessage::Addr(addrs) => {
                 self.addrmgr.received_addr(addr, addrs);
                 // TODO: Tick the peer manager, because we may have new addresses to connect to.
             }
+            NetworkMessage::AddrV2(addrs) => {
+                self.addrmgr.received_addrv2(addr, addrs);
+            }
             NetworkMessage::GetAddr => {
                 self.addrmgr.received_getaddr(&addr);
             }
@@ -705,12 +1325,20 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Protoco
                 (*self.hooks.on_getdata)(addr, invs, &self.outbox);
             }
             NetworkMessage::WtxidRelay => {
-                self.peermgr.received_wtxidrelay(&addr);
+                self.peermgr.received_feature(&addr, Feature::WtxidRelay);
+            }
+            NetworkMessage::SendAddrV2 => {
+                self.peermgr.received_feature(&addr, Feature::AddrV2);
             }
             NetworkMessage::Unknown {
                 command: ref cmd, ..
             } => {
-                debug!(target: self.target, "{}: Ignoring unknown message {:?}", addr, cmd)
+                // Some feature signalling messages are unknown to our message decoder.
+                if let Some(feature) = Feature::from_message(&msg.payload) {
+                    self.peermgr.received_feature(&addr, feature);
+                } else {
+                    debug!(target: self.target, "{}: Ignoring unknown message {:?}", addr, cmd)
+                }
             }
             _ => {
                 debug!(target: self.target, "{}: Ignoring {:?}", addr, cmd);
@@ -759,6 +1387,15 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Protoco
         }
     }
 
+    /// Bandwidth usage counters of the current network mode.
+    fn usage(&mut self) -> &mut Usage {
+        if self.low_data {
+            &mut self.bandwidth.low_data
+        } else {
+            &mut self.bandwidth.normal
+        }
+    }
+
     fn disconnect(&mut self, addr: PeerId, reason: DisconnectReason) {
         // TODO: Trigger disconnection everywhere, as if peer disconnected. This
         // avoids being in a state where we know a peer is about to get disconnected,
@@ -766,6 +1403,182 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> Protoco
 
         self.peermgr.disconnect(addr, reason);
     }
+
+    /// Called when blocks were removed from the active chain. Blocks are ordered from the tip
+    /// down to the oldest ancestor.
+    fn blocks_reverted(&mut self, reverted: &[(Height, BlockHeader)]) {
+        if let Some((height, _)) = reverted.last() {
+            // The height we need to rollback to, ie. the tip of our new chain
+            // and the tallest block we are keeping.
+            let fork_height = height - 1;
+            self.cbfmgr.rollback(fork_height).unwrap();
+
+            for (height, _) in reverted {
+                for tx in self.invmgr.block_reverted(*height) {
+                    self.cbfmgr.watch_transaction(&tx);
+                }
+            }
+        }
+    }
+
+    /// Resume after a sync burst ended. Timer work that came due while quiesced is done
+    /// right away, so that it's batched into the start of the next burst.
+    fn resume(&mut self) {
+        if self.quiesced {
+            self.quiesced = false;
+            self.outbox.suspend(false);
+
+            traits::Protocol::wake(self);
+        }
+    }
+
+    /// Revalidate the next batch of headers of the ongoing reindex, if any.
+    fn reindex(&mut self) {
+        let Some(mut reindex) = self.reindex.take() else {
+            return;
+        };
+        let tip = self.tree.height();
+        let end = Height::min(reindex.height + reindex::BATCH_SIZE, tip + 1);
+
+        while reindex.height < end {
+            let height = reindex.height;
+
+            match self.tree.revalidate(height, &self.clock) {
+                Ok(true) => {
+                    reindex.repaired += 1;
+                    self.outbox
+                        .event(Event::Reindex(ReindexEvent::Repaired { height }));
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    self.outbox
+                        .event(Event::Reindex(ReindexEvent::InvalidHeader {
+                            height,
+                            reason: err.to_string(),
+                        }));
+                    // Nb. The genesis block is never invalid.
+                    match self.tree.truncate(height - 1) {
+                        Ok(reverted) => {
+                            self.blocks_reverted(&reverted);
+                            self.syncmgr.blocks_truncated(&reverted, &self.tree);
+                            self.cbfmgr.sync(&self.tree);
+                        }
+                        Err(err) => {
+                            error!(target: self.target, "Error truncating block headers: {}", err)
+                        }
+                    }
+                    break;
+                }
+            }
+
+            if height <= self.cbfmgr.filters.height()
+                && !reindex::verify_filter_header(&self.cbfmgr.filters, height)
+            {
+                self.outbox
+                    .event(Event::Reindex(ReindexEvent::InvalidFilterHeader { height }));
+
+                if let Err(err) = self.cbfmgr.rollback(height - 1) {
+                    error!(target: self.target, "Error truncating filter headers: {}", err);
+                }
+                self.cbfmgr.sync(&self.tree);
+            }
+            reindex.height += 1;
+        }
+
+        let height = self.tree.height();
+
+        if reindex.height > height {
+            // Filter headers can't be ahead of block headers.
+            if let Err(err) = self.cbfmgr.rollback(height) {
+                error!(target: self.target, "Error truncating filter headers: {}", err);
+            }
+            self.outbox.event(Event::Reindex(ReindexEvent::Finished {
+                height,
+                filter_height: self.cbfmgr.filters.height(),
+                repaired: reindex.repaired,
+            }));
+        } else {
+            self.outbox.event(Event::Reindex(ReindexEvent::Progress {
+                height: reindex.height - 1,
+                tip,
+            }));
+            self.outbox.wakeup(reindex::BATCH_INTERVAL);
+            self.reindex = Some(reindex);
+        }
+    }
+
+    /// Detect network partitions, and reconnect to peers with backoff while partitioned.
+    fn resync(&mut self) {
+        let now = self.clock.local_time();
+
+        while let Some(event) = self.resync.wake(now) {
+            if let ResyncEvent::Reconnecting { next, .. } = event {
+                self.peermgr.reconnect(&mut self.addrmgr);
+                self.outbox.wakeup(next);
+            }
+            self.outbox.event(Event::Resync(event));
+        }
+        self.reconcile();
+    }
+
+    /// Finish reconciling our tip after a partition, once block and filter headers are
+    /// synced, or once reconciliation timed out.
+    fn reconcile(&mut self) {
+        let Some(deadline) = self.resync.reconcile_deadline() else {
+            return;
+        };
+        let synced = !self.syncmgr.is_syncing() && self.cbfmgr.is_synced(&self.tree);
+
+        if !synced && self.clock.local_time() < deadline {
+            return;
+        }
+        let (tip, _) = self.tree.tip();
+        let height = self.tree.height();
+        let filter_height = self.cbfmgr.filters.height();
+
+        if let Some(event) = self.resync.reconciled(height, tip, filter_height) {
+            self.outbox.event(Event::Resync(event));
+        }
+    }
+
+    /// Dump the internal protocol state, for debugging.
+    fn dump(&self) -> Value {
+        let mut redact = dump::Redact::default();
+        let (tip, _) = self.tree.tip();
+        let height = self.tree.height();
+
+        Value::Object(dump::object([
+            ("network", dump::string(self.network.as_str())),
+            ("time", dump::time(self.clock.local_time())),
+            (
+                "tip",
+                Value::Object(dump::object([
+                    ("height", dump::number(height)),
+                    ("hash", dump::string(tip)),
+                ])),
+            ),
+            (
+                "reindex",
+                dump::optional(self.reindex.as_ref(), |r| {
+                    Value::Object(dump::object([
+                        ("height", dump::number(r.height)),
+                        ("repaired", dump::number(r.repaired)),
+                    ]))
+                }),
+            ),
+            ("peers", self.peermgr.dump(&mut redact)),
+            ("sync", self.syncmgr.dump(&mut redact)),
+            ("filters", self.cbfmgr.dump(&mut redact)),
+            ("addresses", self.addrmgr.dump()),
+            (
+                "messages",
+                Value::Object(dump::object([
+                    ("inbound", dump::messages(&self.inbound)),
+                    ("outbound", dump::messages(&self.outbox.message_stats())),
+                ])),
+            ),
+        ]))
+    }
 }
 
 impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits::Protocol
@@ -775,6 +1588,7 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
 
     fn initialize(&mut self, time: LocalTime) {
         self.clock.set(time);
+        self.outbox.set_time(time);
         self.outbox.event(Event::Initializing);
         self.addrmgr.initialize();
         self.syncmgr.initialize(&self.tree);
@@ -800,6 +1614,19 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
         self.peermgr.peer_connected(addr, *local_addr, link, height);
         self.inbox
             .insert(addr, stream::Decoder::new(INBOX_BUFFER_SIZE));
+        self.traffic.insert(addr, Traffic::default());
+    }
+
+    fn authenticated(&mut self, addr: &net::SocketAddr, fingerprint: Fingerprint) {
+        info!(target: self.target, "[conn] {}: Authenticated as {}", addr, fingerprint);
+
+        self.identities.insert(*addr, fingerprint);
+
+        if let Some((trusted, pinned)) = self.pinned {
+            if *addr == trusted && fingerprint != pinned {
+                self.disconnect(*addr, DisconnectReason::PeerIdentity);
+            }
+        }
     }
 
     fn disconnected(&mut self, addr: &net::SocketAddr, reason: DisconnectReason) {
@@ -812,29 +1639,64 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
         self.peermgr
             .peer_disconnected(addr, &mut self.addrmgr, reason);
         self.invmgr.peer_disconnected(addr);
+        self.limiter.peer_disconnected(addr);
+        self.traffic.remove(addr);
+        self.identities.remove(addr);
+
+        // Disconnections while network activity is disabled are on purpose.
+        if self.peermgr.is_active() && self.peermgr.negotiated(Link::Outbound).next().is_none() {
+            let tip = resync::Tip {
+                height: self.tree.height(),
+                filter_height: self.cbfmgr.filters.height(),
+            };
+            self.resync.disconnected(tip, self.clock.local_time());
+            self.outbox.wakeup(resync::PARTITION_TIMEOUT);
+        }
 
         self.outbox.unregister(addr);
     }
 
     fn received_bytes(&mut self, addr: &net::SocketAddr, bytes: &[u8]) {
+        self.usage().received += bytes.len() as u64;
+
         if let Some(stream) = self.inbox.get_mut(addr) {
             stream.input(bytes);
 
+            if let Some(traffic) = self.traffic.get_mut(addr) {
+                traffic.last_recv = Some(self.clock.local_time());
+            }
+
             let mut msgs = Vec::with_capacity(1);
 
             loop {
-                match stream.decode_next() {
-                    Ok(Some(msg)) => msgs.push(msg),
+                let decoded = stream.decoded().len();
+
+                match stream.decode_next::<RawNetworkMessage>() {
+                    Ok(Some(msg)) => {
+                        let raw = &stream.decoded()[decoded..];
+
+                        self.outbox
+                            .captured(*addr, capture::Direction::Inbound, raw);
+                        msgs.push((msg, raw.len()));
+                    }
                     Ok(None) => break,
 
                     Err(err) => {
+                        // Capture the bytes that couldn't be decoded, starting with the
+                        // malformed message.
+                        self.outbox
+                            .captured(*addr, capture::Direction::Inbound, stream.unparsed());
                         self.outbox
                             .disconnect(*addr, DisconnectReason::DecodeError(Arc::new(err)));
                         return;
                     }
                 }
             }
-            for msg in msgs {
+            for (msg, size) in msgs {
+                self.inbound
+                    .entry(msg.cmd())
+                    .or_default()
+                    .record(size, self.clock.local_time());
                 self.received(addr, msg);
             }
         }
@@ -863,13 +1725,96 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
 
                 reply.send(peers).ok();
             }
-            Command::Connect(addr) => {
+            Command::QueryPeers(reply) => {
+                let peers = self
+                    .peermgr
+                    .peers()
+                    .map(|(peer, conn)| {
+                        let addr = conn.socket.addr;
+                        let traffic = self.traffic.get(&addr).copied().unwrap_or_default();
+
+                        PeerInfo {
+                            addr,
+                            link: conn.link,
+                            kind: conn.kind,
+                            services: peer.services,
+                            user_agent: peer.user_agent.clone(),
+                            since: conn.since,
+                            last_send: traffic.last_send,
+                            last_recv: traffic.last_recv,
+                            latency: self.pingmgr.latency(&addr),
+                            banscore: self.syncmgr.banscore(&addr) + self.limiter.banscore(&addr),
+                            inflight: self.syncmgr.inflight(&addr) + self.cbfmgr.inflight(&addr),
+                            queued: self.outbox.queued(&addr),
+                            features: peer.negotiated(),
+                        }
+                    })
+                    .collect();
+
+                reply.send(peers).ok();
+            }
+            Command::Capture(capture) => {
+                self.outbox.capture(capture);
+            }
+            Command::Connect(addr, persistent) => {
                 self.peermgr.whitelist(addr);
-                self.peermgr.connect(&addr);
+
+                if !persistent || !self.peermgr.add_persistent(addr) {
+                    self.peermgr.connect(&addr);
+                }
+            }
+            Command::ConnectAny(addrs, reply) => {
+                reply.send(self.peermgr.connect_any(&addrs)).ok();
             }
             Command::Disconnect(addr) => {
+                self.peermgr.remove_persistent(&addr);
                 self.disconnect(addr, DisconnectReason::Command);
             }
+            Command::GetAddedPeers(reply) => {
+                reply
+                    .send(self.peermgr.persistent().copied().collect())
+                    .ok();
+            }
+            Command::SetNetworkActive(active) => {
+                self.burst = None;
+                self.resync.reset();
+                self.peermgr.set_active(active, &mut self.addrmgr);
+                self.resume();
+            }
+            Command::SyncBurst(duration) => {
+                self.burst = Some(self.clock.local_time() + duration);
+                self.peermgr.set_active(true, &mut self.addrmgr);
+                self.resume();
+                self.outbox.wakeup(duration);
+            }
+            Command::SetLowData(enabled) => {
+                self.low_data = enabled;
+                self.invmgr.set_deferred(enabled);
+                self.cbfmgr.set_deferred(enabled, &self.tree);
+            }
+            Command::GetBandwidth(reply) => {
+                reply.send(self.bandwidth).ok();
+            }
+            Command::GetMessageStats(reply) => {
+                reply
+                    .send(MessageStats {
+                        inbound: self.inbound.clone(),
+                        outbound: self.outbox.message_stats(),
+                    })
+                    .ok();
+            }
+            Command::Ban(addr, duration) => {
+                self.peermgr.ban(addr, duration);
+            }
+            Command::Unban(addr) => {
+                self.peermgr.unban(&addr);
+            }
+            Command::ListBans(reply) => {
+                let now = self.clock.local_time();
+                let bans = self.peermgr.bans().filter(|b| !b.is_expired(now)).collect();
+
+                reply.send(bans).ok();
+            }
             Command::Query(msg, reply) => {
                 reply.send(self.query(msg, |_| true)).ok();
             }
@@ -898,12 +1843,48 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
                     peer::Source::Imported,
                 );
             }
+            Command::ImportSeeds(addrs) => {
+                self.addrmgr.seed(addrs);
+                self.peermgr.reconnect(&mut self.addrmgr);
+            }
             Command::GetTip(reply) => {
                 let (_, header) = self.tree.tip();
                 let height = self.tree.height();
 
                 reply.send((height, header)).ok();
             }
+            Command::GetFilterTip(reply) => {
+                reply.send(self.cbfmgr.filters.height()).ok();
+            }
+            Command::GetConfirmation(txid, reply) => {
+                let confirmation = self.invmgr.confirmation(&txid).and_then(|height| {
+                    self.tree
+                        .get_block_by_height(height)
+                        .map(|header| (height, header.block_hash()))
+                });
+                reply.send(confirmation).ok();
+            }
+            Command::GetProof(txid, reply) => {
+                let proof = self
+                    .invmgr
+                    .proof(&txid)
+                    .map(|(height, proof)| (height, proof.clone()));
+                reply.send(proof).ok();
+            }
+            Command::GetChainWork(reply) => {
+                let (tip, _) = self.tree.tip();
+                let height = self.tree.height();
+
+                reply
+                    .send(ChainWork {
+                        height,
+                        tip,
+                        work: self.tree.chain_work(height),
+                        forks: self.tree.forks(),
+                        peer_height: self.syncmgr.best_height(),
+                    })
+                    .ok();
+            }
             Command::GetFilters(range, reply) => {
                 let result = self.cbfmgr.get_cfilters(range, &self.tree);
                 reply.send(result).ok();
@@ -929,15 +1910,134 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
                     reply.send(Err(CommandError::NotConnected)).ok();
                 }
             }
-            Command::Rescan { from, to, watch } => {
+            Command::ReplaceTransaction(txid, tx, reply) => {
+                let replacement = tx.clone();
+                let result = match self.invmgr.replace(&txid, tx) {
+                    Ok(peers) => {
+                        // Watch the replacement too, since either version may be confirmed.
+                        self.cbfmgr.watch_transaction(&replacement);
+
+                        NonEmpty::from_vec(peers).ok_or(CommandError::NotConnected)
+                    }
+                    Err(err) => Err(err.into()),
+                };
+                reply.send(result).ok();
+            }
+            Command::SubmitPackage(txs, reply) => {
+                for tx in &txs {
+                    self.cbfmgr.watch_transaction(tx);
+                }
+                let result = match self.invmgr.announce_package(txs) {
+                    Ok((peers, statuses)) => {
+                        if peers.is_empty()
+                            && statuses.iter().any(|(_, s)| *s == TxStatus::Announced)
+                        {
+                            Err(CommandError::NotConnected)
+                        } else {
+                            Ok(statuses)
+                        }
+                    }
+                    Err(err) => Err(err.into()),
+                };
+                reply.send(result).ok();
+            }
+            Command::Rescan {
+                id,
+                from,
+                to,
+                watch,
+            } => {
                 // A rescan with a new watch list may return matches on cached filters.
-                for (_, hash) in self.cbfmgr.rescan(from, to, watch, &self.tree) {
+                for (_, hash) in self.cbfmgr.rescan(id, from, to, watch, &self.tree) {
                     self.invmgr.get_block(hash);
                 }
+                self.invmgr.watch_mempool(self.cbfmgr.watched().cloned());
+            }
+            Command::PauseRescan(id) => {
+                self.cbfmgr.pause_rescan(id);
+            }
+            Command::ResumeRescan(id) => {
+                for (_, hash) in self
+                    .cbfmgr
+                    .resume_rescan(id, &self.tree)
+                    .into_iter()
+                    .flatten()
+                {
+                    self.invmgr.get_block(hash);
+                }
+            }
+            Command::CancelRescan(id) => {
+                self.cbfmgr.cancel_rescan(id);
             }
             Command::Watch { watch } => {
                 self.cbfmgr.watch(watch);
+                self.invmgr.watch_mempool(self.cbfmgr.watched().cloned());
+            }
+            Command::SetConfig(update) => {
+                let ConfigUpdate {
+                    target_outbound_peers,
+                    max_inbound_peers,
+                    ping_timeout,
+                    log_level,
+                    queue_limits,
+                    rate_limits,
+                } = update.clone();
+
+                if target_outbound_peers.is_some() || max_inbound_peers.is_some() {
+                    self.peermgr.set_connection_targets(
+                        target_outbound_peers,
+                        max_inbound_peers,
+                        &mut self.addrmgr,
+                    );
+                }
+                if let Some(timeout) = ping_timeout {
+                    self.pingmgr.set_ping_timeout(timeout);
+                }
+                if let Some(level) = log_level {
+                    log::set_max_level(level);
+                }
+                if let Some(limits) = queue_limits {
+                    self.outbox.set_limits(limits);
+                }
+                if let Some(limits) = rate_limits {
+                    self.limiter.set_config(limits);
+                }
+                self.outbox.event(Event::ConfigUpdated(update));
+            }
+            Command::ExportHeaders(reply) => {
+                let headers = self.tree.iter().map(|(_, h)| h).collect();
+                let filter_headers = self
+                    .cbfmgr
+                    .filters
+                    .get_headers(0..=self.cbfmgr.filters.height());
+
+                reply.send((headers, filter_headers)).ok();
             }
+            Command::ImportFilterHeaders(headers, reply) => {
+                let result = self.cbfmgr.import_headers(headers, &self.tree);
+
+                reply.send(result).ok();
+            }
+            Command::Reindex(from) => {
+                let to = self.tree.height();
+
+                self.reindex = Some(Reindex::new(from));
+                self.outbox
+                    .event(Event::Reindex(ReindexEvent::Started { from, to }));
+                self.reindex();
+            }
+            Command::DumpState(reply) => {
+                reply.send(microserde::json::to_string(&self.dump())).ok();
+            }
+            Command::SetLogFilter(filter) => match filter.parse::<log_filter::LogFilter>() {
+                Ok(filter) => {
+                    info!(target: self.target, "Setting log filter to `{}`", filter);
+                    log_filter::set(filter);
+                }
+                Err(err) => {
+                    warn!(target: self.target, "Invalid log filter {:?}: {}", filter, err);
+                }
+            },
         }
     }
 
@@ -945,17 +2045,35 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
         trace!(target: self.target, "Received tick");
 
         self.clock.set(local_time);
+        self.outbox.set_time(local_time);
     }
 
     fn wake(&mut self) {
         trace!(target: self.target, "Received wake");
 
+        if self.quiesced {
+            return;
+        }
+        if let Some(end) = self.burst {
+            if self.clock.local_time() >= end {
+                self.burst = None;
+                self.quiesced = true;
+                self.resync.reset();
+                self.peermgr.set_active(false, &mut self.addrmgr);
+                self.outbox.suspend(true);
+                self.outbox.event(Event::Quiesced);
+
+                return;
+            }
+        }
         self.invmgr.received_wake(&self.tree);
         self.syncmgr.received_wake(&self.tree);
         self.pingmgr.received_wake();
         self.addrmgr.received_wake();
         self.peermgr.received_wake(&mut self.addrmgr);
         self.cbfmgr.received_wake(&self.tree);
+        self.reindex();
+        self.resync();
 
         #[cfg(not(test))]
         let local_time = self.clock.local_time();
@@ -1028,6 +2146,15 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
     }
 
     fn write<W: io::Write>(&mut self, addr: &net::SocketAddr, writer: W) -> io::Result<()> {
-        self.outbox.write(addr, writer)
+        let written = self.outbox.write(addr, writer)?;
+
+        if written > 0 {
+            self.usage().sent += written as u64;
+
+            if let Some(traffic) = self.traffic.get_mut(addr) {
+                traffic.last_send = Some(self.clock.local_time());
+            }
+        }
+        Ok(())
     }
 }
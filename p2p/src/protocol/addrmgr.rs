@@ -1,10 +1,17 @@
 //!
 //! The peer-to-peer address manager.
 //!
+//! Known addresses are kept in a table of "new" and "tried" buckets, see [`table`] for
+//! how addresses are bucketed and evicted.
+//!
 #![warn(missing_docs)]
+mod table;
+
 use std::net;
 
-use nakamoto_common::bitcoin::network::address::Address;
+use microserde::json::Value;
+
+use nakamoto_common::bitcoin::network::address::{AddrV2, AddrV2Message, Address};
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 
 use nakamoto_common::block::time::Clock;
@@ -12,10 +19,14 @@ use nakamoto_common::block::time::{LocalDuration, LocalTime};
 use nakamoto_common::block::BlockTime;
 use nakamoto_common::collections::{HashMap, HashSet};
 use nakamoto_common::p2p::peer::{AddressSource, KnownAddress, Source, Store};
-use nakamoto_common::p2p::Domain;
+use nakamoto_common::p2p::{i2p, Domain};
 
+use super::asmap::Asmap;
 use super::output::Wakeup;
-use super::{DisconnectReason, Link, PeerId};
+use super::scheduler::{Cadence, Scheduler};
+use super::{dump, DisconnectReason, Link, PeerId};
+
+use table::{AddressTable, Outcome};
 
 /// Time to wait until a request times out.
 pub const REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
@@ -26,10 +37,41 @@ pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
 /// Sample timeout. How long before a sampled address can be returned again.
 pub const SAMPLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(3);
 
+/// How long our response to `getaddr` messages is cached. All peers asking for addresses
+/// within this period get the same response, which prevents them from scraping our
+/// address book.
+pub const GETADDR_CACHE_TIMEOUT: LocalDuration = LocalDuration::from_mins(60 * 24);
+
+/// How often we advertise our external address to peers.
+pub const ADVERTISE_INTERVAL: LocalDuration = LocalDuration::from_mins(60 * 24);
+
 /// Maximum number of addresses expected in a `addr` message.
 const MAX_ADDR_ADDRESSES: usize = 1000;
-/// Maximum number of addresses we store for a given address range.
-const MAX_RANGE_SIZE: usize = 256;
+/// First byte of CJDNS addresses, which are all in `fc00::/8`.
+const CJDNS_PREFIX: u8 = 0xfc;
+/// Addresses not seen active for longer than this can be evicted from the address table.
+const ADDRESS_HORIZON: LocalDuration = LocalDuration::from_mins(60 * 24 * 30);
+/// Tried addresses that connected successfully within this period are not evicted from
+/// the address table to make room for other tried addresses.
+const TRIED_REPLACEMENT: LocalDuration = LocalDuration::from_mins(60 * 4);
+/// Maximum percentage of our address book sent in response to a `getaddr`.
+const MAX_GETADDR_PERCENT: usize = 23;
+/// Addresses not seen active for longer than this are not sent to peers.
+const MAX_GETADDR_AGE: LocalDuration = LocalDuration::from_mins(60 * 24 * 30);
+/// Only `addr` messages with at most this many addresses are relayed. Larger
+/// messages are usually responses to `getaddr`.
+const MAX_RELAY_ADDRESSES: usize = 10;
+/// Addresses older than this are not relayed.
+const MAX_RELAY_AGE: LocalDuration = LocalDuration::from_mins(10);
+/// Number of peers each address is relayed to.
+const RELAY_PEERS: usize = 2;
+/// Rate at which we accept addresses from a peer, in addresses per second.
+const ADDR_RATE: f64 = 0.1;
+/// Maximum number of addresses a peer can send us in a burst.
+const MAX_ADDR_TOKENS: f64 = MAX_ADDR_ADDRESSES as f64;
+/// Number of connected outbound peers that must agree on our external address before
+/// it's advertised.
+const MIN_EXTERNAL_REPORTS: usize = 2;
 
 /// Address manager event emission.
 pub trait Events {
@@ -43,11 +85,14 @@ pub trait SyncAddresses {
     fn get_addresses(&mut self, addr: PeerId);
     /// Send addresses to a peer.
     fn send_addresses(&mut self, addr: PeerId, addrs: Vec<(BlockTime, Address)>);
+    /// Send addresses to a peer that negotiated `addrv2` (BIP 155).
+    fn send_addresses_v2(&mut self, addr: PeerId, addrs: Vec<AddrV2Message>);
 }
 
 impl SyncAddresses for () {
     fn get_addresses(&mut self, _addr: PeerId) {}
     fn send_addresses(&mut self, _addr: PeerId, _addrs: Vec<(BlockTime, Address)>) {}
+    fn send_addresses_v2(&mut self, _addr: PeerId, _addrs: Vec<AddrV2Message>) {}
 }
 
 impl Events for () {
@@ -142,6 +187,22 @@ pub struct Config {
     pub required_services: ServiceFlags,
     /// Communication domains we're interested in.
     pub domains: Vec<Domain>,
+    /// Whether to participate in address gossip, ie. exchange addresses with peers.
+    pub gossip: bool,
+    /// How often to ask peers for addresses, while we're out of unused addresses.
+    pub request: Cadence,
+    /// Services we offer, advertised along with our external address.
+    pub services: ServiceFlags,
+    /// Port peers can reach us on. If set, our external address is detected and
+    /// advertised to peers.
+    pub external_port: Option<u16>,
+    /// How often to advertise our external address to peers.
+    pub advertise: Cadence,
+    /// Map used to group addresses by autonomous system, rather than by prefix.
+    pub asmap: Option<Asmap>,
+    /// Secret key used to place addresses in buckets. If not set, a random key is used,
+    /// and addresses are bucketed differently every time.
+    pub key: Option<[u8; 32]>,
 }
 
 impl Default for Config {
@@ -149,24 +210,65 @@ impl Default for Config {
         Self {
             required_services: ServiceFlags::NONE,
             domains: Domain::all(),
+            gossip: true,
+            request: Cadence::every(REQUEST_TIMEOUT),
+            services: ServiceFlags::NONE,
+            external_port: None,
+            advertise: Cadence::every(ADVERTISE_INTERVAL),
+            asmap: None,
+            key: None,
         }
     }
 }
 
+/// Address gossip state of a connected peer.
+#[derive(Debug)]
+struct GossipPeer {
+    /// Whether this is an inbound or outbound peer.
+    link: Link,
+    /// Number of addresses the peer is allowed to send us.
+    tokens: f64,
+    /// Last time tokens were added.
+    last_refill: LocalTime,
+    /// Whether we responded to a `getaddr` from this peer.
+    getaddr_answered: bool,
+    /// Whether the peer negotiated `addrv2`, and can be sent CJDNS and I2P addresses.
+    addrv2: bool,
+}
+
+impl GossipPeer {
+    /// Refill the token bucket, up to the maximum.
+    fn refill(&mut self, now: LocalTime) {
+        let elapsed = now - self.last_refill;
+
+        self.tokens = (self.tokens + elapsed.as_secs() as f64 * ADDR_RATE).min(MAX_ADDR_TOKENS);
+        self.last_refill = now;
+    }
+}
+
 /// Manages peer network addresses.
 #[derive(Debug)]
 pub struct AddressManager<P, U, C> {
     /// Peer address store.
     peers: P,
     bans: HashSet<net::IpAddr>,
-    address_ranges: HashMap<u8, HashSet<net::IpAddr>>,
+    /// Address buckets. Holds exactly the addresses of the store.
+    table: AddressTable,
     connected: HashSet<net::IpAddr>,
+    /// Outbound peers, connected or being connected to.
+    outbound: HashSet<net::SocketAddr>,
     sources: HashSet<net::SocketAddr>,
     local_addrs: HashSet<net::SocketAddr>,
-    /// The last time we asked our peers for new addresses.
-    last_request: Option<LocalTime>,
+    /// Our IP addresses, as reported by connected outbound peers.
+    external: HashMap<net::IpAddr, HashSet<net::SocketAddr>>,
+    /// Peers we exchange addresses with.
+    gossip: HashMap<PeerId, GossipPeer>,
+    /// Cached response to `getaddr` messages, and when it was created.
+    getaddr_cache: Option<(LocalTime, Vec<(BlockTime, Address)>)>,
     /// The last time we idled.
     last_idle: Option<LocalTime>,
+    /// Periodic tasks.
+    schedule: Scheduler,
     cfg: Config,
     upstream: U,
     rng: fastrand::Rng,
@@ -176,7 +278,10 @@ pub struct AddressManager<P, U, C> {
 impl<P: Store, U: SyncAddresses + Wakeup + Events, C: Clock> AddressManager<P, U, C> {
     /// Initialize the address manager.
     pub fn initialize(&mut self) {
-        self.idle();
+        if let Some(next) = self.schedule.due(&"idle", self.clock.local_time()) {
+            self.idle();
+            self.upstream.wakeup(next);
+        }
     }
 
     /// Return an iterator over randomly sampled addresses.
@@ -186,29 +291,193 @@ impl<P: Store, U: SyncAddresses + Wakeup + Events, C: Clock> AddressManager<P, U
 
     /// Get addresses from peers.
     pub fn get_addresses(&mut self) {
-        for peer in &self.sources {
-            self.upstream.get_addresses(*peer);
+        if !self.cfg.gossip {
+            return;
+        }
+        for peer in self.sources.iter().copied().collect::<Vec<_>>() {
+            self.request_addresses(peer);
         }
     }
 
     /// Called when we receive a `getaddr` message.
+    ///
+    /// To avoid leaking information about our address book, only inbound peers are answered,
+    /// at most once per connection, and with a random sample of our address book which
+    /// is only renewed every [`GETADDR_CACHE_TIMEOUT`].
     pub fn received_getaddr(&mut self, from: &net::SocketAddr) {
-        // TODO: We should only respond with peers who were last active within
-        // the last 3 hours.
-        let mut addrs = Vec::new();
-
-        // Include one random address per address range.
-        for range in self.address_ranges.values() {
-            let ix = self.rng.usize(..range.len());
-            let ip = range.iter().nth(ix).expect("index must be present");
-            let ka = self.peers.get(ip).expect("address must exist");
-
-            addrs.push((
-                ka.last_active.map(|t| t.block_time()).unwrap_or_default(),
-                ka.addr.clone(),
-            ));
+        if !self.cfg.gossip {
+            return;
+        }
+        match self.gossip.get_mut(from) {
+            Some(peer) if peer.link.is_inbound() && !peer.getaddr_answered => {
+                peer.getaddr_answered = true;
+            }
+            _ => return,
+        }
+        let now = self.clock.local_time();
+
+        if !matches!(self.getaddr_cache, Some((t, _)) if now - t < GETADDR_CACHE_TIMEOUT) {
+            let mut addrs = self
+                .peers
+                .iter()
+                .filter_map(|(_, ka)| ka.last_active.map(|t| (t, ka.addr.clone())))
+                .filter(|(t, _)| now - *t < MAX_GETADDR_AGE)
+                .map(|(t, addr)| (t.block_time(), addr))
+                .collect::<Vec<_>>();
+            let count = (addrs.len() * MAX_GETADDR_PERCENT)
+                .div_ceil(100)
+                .min(MAX_ADDR_ADDRESSES);
+
+            self.rng.shuffle(&mut addrs);
+            addrs.truncate(count);
+
+            self.getaddr_cache = Some((now, addrs));
+        }
+        if let Some((_, addrs)) = self.getaddr_cache.clone() {
+            self.send(*from, addrs);
+        }
+    }
+
+    /// Called when we received an `addr` message from a peer.
+    ///
+    /// Peers are rate-limited in the number of addresses they can send us, unless they are
+    /// responding to our `getaddr`. Fresh addresses from small announcements are relayed to
+    /// a few other peers.
+    pub fn received_addr(&mut self, peer: net::SocketAddr, mut addrs: Vec<(BlockTime, Address)>) {
+        if addrs.is_empty() || addrs.len() > MAX_ADDR_ADDRESSES {
+            // Peer misbehaving, got empty message or too many addresses.
+            return;
+        }
+        // Addresses in `fc00::/8` are private IPv6 addresses in `addr` messages: CJDNS
+        // addresses can only be announced with `addrv2`. The same goes for I2P.
+        addrs.retain(|(_, addr)| is_addrv1_compatible(addr));
+
+        self.received_addresses(peer, addrs);
+    }
+
+    /// Called when we received an `addrv2` message from a peer (BIP 155).
+    ///
+    /// Only addresses we can connect to are kept, ie. IPv4, IPv6, CJDNS and I2P addresses.
+    /// The others are handled like those of an `addr` message. I2P addresses are given a
+    /// synthetic IPv6 address, see [`i2p`].
+    pub fn received_addrv2(&mut self, peer: net::SocketAddr, addrs: Vec<AddrV2Message>) {
+        if addrs.is_empty() || addrs.len() > MAX_ADDR_ADDRESSES {
+            // Peer misbehaving, got empty message or too many addresses.
+            return;
+        }
+        let addrs = addrs
+            .into_iter()
+            .filter_map(|msg| {
+                let addr = match msg.addr {
+                    AddrV2::Ipv4(ip) => net::SocketAddr::from((ip, msg.port)),
+                    // Private IPv6 addresses must not be mistaken for CJDNS or I2P addresses.
+                    AddrV2::Ipv6(ip) if ip.octets()[0] != CJDNS_PREFIX && !i2p::is_i2p(&ip) => {
+                        net::SocketAddr::from((ip, msg.port))
+                    }
+                    AddrV2::Cjdns(ip) if ip.octets()[0] == CJDNS_PREFIX => {
+                        net::SocketAddr::from((ip, msg.port))
+                    }
+                    // Only keep track of I2P addresses if we can reach them.
+                    AddrV2::I2p(hash) if self.cfg.domains.contains(&Domain::I2P) => {
+                        i2p::socket_addr(hash)
+                    }
+                    _ => return None,
+                };
+                Some((msg.time, Address::new(&addr, msg.services)))
+            })
+            .collect::<Vec<_>>();
+
+        self.received_addresses(peer, addrs);
+    }
+
+    /// Rate-limit, relay and record addresses received from a peer.
+    fn received_addresses(&mut self, peer: net::SocketAddr, mut addrs: Vec<(BlockTime, Address)>) {
+        if addrs.is_empty() {
+            return;
+        }
+        if !self.cfg.gossip {
+            return;
+        }
+        let now = self.clock.local_time();
+        let relay = addrs.len() <= MAX_RELAY_ADDRESSES;
+
+        if let Some(p) = self.gossip.get_mut(&peer) {
+            p.refill(now);
+
+            let allowed = p.tokens as usize;
+            if addrs.len() > allowed {
+                addrs.truncate(allowed);
+            }
+            p.tokens -= addrs.len() as f64;
+        }
+        if addrs.is_empty() {
+            return;
+        }
+        let source = Source::Peer(peer);
+
+        self.upstream.event(Event::AddressesReceived {
+            count: addrs.len(),
+            source,
+        });
+        if relay {
+            self.relay(&peer, &addrs);
+        }
+        self.insert(addrs.into_iter(), source);
+    }
+
+    /// Relay fresh, routable addresses to a few random peers, other than the one we got
+    /// them from.
+    fn relay(&mut self, from: &PeerId, addrs: &[(BlockTime, Address)]) {
+        let now = self.clock.local_time();
+        let addrs = addrs
+            .iter()
+            .filter(|(time, _)| {
+                let time = LocalTime::from_block_time(*time);
+                time <= now && now - time <= MAX_RELAY_AGE
+            })
+            .filter(|(_, addr)| {
+                addr.socket_addr()
+                    .is_ok_and(|a| is_routable(&a.ip()) && !is_local(&a.ip()))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if addrs.is_empty() {
+            return;
+        }
+        let mut peers = self
+            .gossip
+            .keys()
+            .filter(|p| *p != from)
+            .copied()
+            .collect::<Vec<_>>();
+
+        self.rng.shuffle(&mut peers);
+
+        for peer in peers.into_iter().take(RELAY_PEERS) {
+            self.send(peer, addrs.clone());
+        }
+    }
+
+    /// Send addresses to a peer, with `addrv2` if the peer negotiated it. Otherwise, the
+    /// addresses that can't be sent with `addr` are left out.
+    fn send(&mut self, peer: PeerId, mut addrs: Vec<(BlockTime, Address)>) {
+        if self.gossip.get(&peer).is_some_and(|p| p.addrv2) {
+            let addrs = addrs
+                .iter()
+                .filter_map(|(time, addr)| to_addrv2(*time, addr))
+                .collect::<Vec<_>>();
+
+            if !addrs.is_empty() {
+                self.upstream.send_addresses_v2(peer, addrs);
+            }
+        } else {
+            addrs.retain(|(_, addr)| is_addrv1_compatible(addr));
+
+            if !addrs.is_empty() {
+                self.upstream.send_addresses(peer, addrs);
+            }
         }
-        self.upstream.send_addresses(*from, addrs);
     }
 
     /// Called when a tick is received.
@@ -216,18 +485,25 @@ impl<P: Store, U: SyncAddresses + Wakeup + Events, C: Clock> AddressManager<P, U
         let local_time = self.clock.local_time();
 
         // If we're already using all the addresses we have available, we should fetch more.
-        if local_time - self.last_request.unwrap_or_default() >= REQUEST_TIMEOUT
-            && self.is_exhausted()
-        {
-            Events::event(&self.upstream, Event::AddressBookExhausted);
-
-            self.get_addresses();
-            self.last_request = Some(local_time);
-            self.upstream.wakeup(REQUEST_TIMEOUT);
+        if self.is_exhausted() {
+            if let Some(next) = self.schedule.due(&"request", local_time) {
+                Events::event(&self.upstream, Event::AddressBookExhausted);
+
+                self.get_addresses();
+                self.upstream.wakeup(next);
+            }
         }
 
-        if local_time - self.last_idle.unwrap_or_default() >= IDLE_TIMEOUT {
+        if let Some(next) = self.schedule.due(&"idle", local_time) {
             self.idle();
+            self.upstream.wakeup(next);
+        }
+
+        if let Some(next) = self.schedule.due(&"advertise", local_time) {
+            for peer in self.gossip.keys().copied().collect::<Vec<_>>() {
+                self.advertise(peer);
+            }
+            self.upstream.wakeup(next);
         }
     }
 
@@ -242,6 +518,8 @@ impl<P: Store, U: SyncAddresses + Wakeup + Events, C: Clock> AddressManager<P, U
     /// Called when a peer connection is attempted.
     pub fn peer_attempted(&mut self, addr: &net::SocketAddr) {
         let time = self.clock.local_time();
+
+        self.outbound.insert(*addr);
         // We're only interested in connection attempts for addresses we keep track of.
         if let Some(ka) = self.peers.get_mut(&addr.ip()) {
             ka.last_attempt = Some(time);
@@ -256,37 +534,75 @@ impl<P: Store, U: SyncAddresses + Wakeup + Events, C: Clock> AddressManager<P, U
         self.connected.insert(addr.ip());
     }
 
-    /// Called when a peer has handshaked.
-    pub fn peer_negotiated(&mut self, addr: &net::SocketAddr, services: ServiceFlags, link: Link) {
+    /// Called when a peer has handshaked. Addresses are only exchanged with the peer
+    /// if `addr_relay` is set.
+    pub fn peer_negotiated(
+        &mut self,
+        addr: &net::SocketAddr,
+        services: ServiceFlags,
+        link: Link,
+        addr_relay: bool,
+        addrv2: bool,
+    ) {
         let time = self.clock.local_time();
 
         if !self.connected.contains(&addr.ip()) {
             return;
         }
-        if link.is_outbound() {
+        if link.is_outbound() && addr_relay {
             self.sources.insert(*addr);
         }
+        if self.cfg.gossip && addr_relay {
+            self.gossip.insert(
+                *addr,
+                GossipPeer {
+                    link,
+                    // Allow the peer to announce its own address.
+                    tokens: 1.,
+                    last_refill: time,
+                    getaddr_answered: false,
+                    addrv2,
+                },
+            );
+            // Let outbound peers know how to reach us right away. Other peers learn about
+            // it periodically.
+            if link.is_outbound() {
+                self.advertise(*addr);
+            }
+        }
 
         // We're only interested in peers we already know, eg. from DNS or peer
         // exchange. Peers should only be added to our address book if they are DNS seeds
         // or are discovered via a DNS seed.
         if let Some(ka) = self.peers.get_mut(&addr.ip()) {
             // Only ask for addresses when connecting for the first time.
-            if ka.last_success.is_none() {
-                self.upstream.get_addresses(*addr);
-            }
+            let first = ka.last_success.is_none();
+
             // Keep track of when the last successful handshake was.
             ka.last_success = Some(time);
             ka.last_active = Some(time);
             ka.addr.services = services;
+
+            if first && self.cfg.gossip && addr_relay {
+                self.request_addresses(*addr);
+            }
+            self.mark_tried(&addr.ip());
         }
     }
 
     /// Called when a peer disconnected.
     pub fn peer_disconnected(&mut self, addr: &net::SocketAddr, reason: DisconnectReason) {
+        self.outbound.remove(addr);
+
         if self.connected.remove(&addr.ip()) {
             // Disconnected peers cannot be used as a source for new addresses.
             self.sources.remove(addr);
+            self.gossip.remove(addr);
+            // Only connected peers vouch for our external address.
+            self.external.retain(|_, reporters| {
+                reporters.remove(addr);
+                !reporters.is_empty()
+            });
 
             // If the reason for disconnecting the peer suggests that we shouldn't try to
             // connect to this peer again, then remove the peer from the address book.
@@ -300,6 +616,24 @@ impl<P: Store, U: SyncAddresses + Wakeup + Events, C: Clock> AddressManager<P, U
 
     ////////////////////////////////////////////////////////////////////////////
 
+    /// Ask a peer for addresses. Since the response may contain up to [`MAX_ADDR_ADDRESSES`]
+    /// addresses, the peer's rate limit is lifted for it.
+    fn request_addresses(&mut self, addr: PeerId) {
+        if let Some(peer) = self.gossip.get_mut(&addr) {
+            peer.tokens += MAX_ADDR_ADDRESSES as f64;
+        }
+        self.upstream.get_addresses(addr);
+    }
+
+    /// Advertise our external address to a peer, if we know it.
+    fn advertise(&mut self, peer: PeerId) {
+        if let Some(addr) = self.external_address() {
+            let time = self.clock.local_time().block_time();
+
+            self.send(peer, vec![(time, Address::new(&addr, self.cfg.services))]);
+        }
+    }
+
     fn idle(&mut self) {
         // If it's been a while, save addresses to store.
         if let Err(err) = self.peers.flush() {
@@ -307,32 +641,45 @@ impl<P: Store, U: SyncAddresses + Wakeup + Events, C: Clock> AddressManager<P, U
                 .event(Event::Error(format!("flush to disk failed: {}", err)));
         }
         self.last_idle = Some(self.clock.local_time());
-        self.upstream.wakeup(IDLE_TIMEOUT);
     }
 }
 
 impl<P: Store, U: Events, C: Clock> AddressManager<P, U, C> {
     /// Create a new, empty address manager.
     pub fn new(cfg: Config, rng: fastrand::Rng, peers: P, upstream: U, clock: C) -> Self {
-        let ips = peers.iter().map(|(ip, _)| *ip).collect::<Vec<_>>();
+        let mut schedule = Scheduler::new(rng.clone());
+
+        schedule.schedule("request", cfg.request);
+        schedule.schedule("idle", Cadence::every(IDLE_TIMEOUT));
+
+        if cfg.gossip && cfg.external_port.is_some() {
+            schedule.schedule("advertise", cfg.advertise);
+        }
+
+        let table = match cfg.key {
+            Some(key) => AddressTable::with_key(key, rng.clone(), cfg.asmap.clone()),
+            None => AddressTable::new(rng.clone(), cfg.asmap.clone()),
+        };
         let mut addrmgr = Self {
             cfg,
             peers,
             bans: HashSet::with_hasher(rng.clone().into()),
-            address_ranges: HashMap::with_hasher(rng.clone().into()),
+            table,
             connected: HashSet::with_hasher(rng.clone().into()),
+            outbound: HashSet::with_hasher(rng.clone().into()),
             sources: HashSet::with_hasher(rng.clone().into()),
             local_addrs: HashSet::with_hasher(rng.clone().into()),
-            last_request: None,
+            external: HashMap::with_hasher(rng.clone().into()),
+            gossip: HashMap::with_hasher(rng.clone().into()),
+            getaddr_cache: None,
             last_idle: None,
+            schedule,
             upstream,
             rng,
             clock,
         };
 
-        for ip in ips.iter() {
-            addrmgr.populate_address_ranges(ip);
-        }
+        addrmgr.populate_table();
         addrmgr
     }
 
@@ -341,31 +688,87 @@ impl<P: Store, U: Events, C: Clock> AddressManager<P, U, C> {
         self.peers.len()
     }
 
+    /// Our externally reachable address, if we accept inbound connections and enough
+    /// outbound peers agree on our IP address.
+    pub fn external_address(&self) -> Option<net::SocketAddr> {
+        let port = self.cfg.external_port?;
+        let (ip, _) = self
+            .external
+            .iter()
+            .filter(|(_, reporters)| reporters.len() >= MIN_EXTERNAL_REPORTS)
+            .max_by_key(|(ip, reporters)| (reporters.len(), **ip))?;
+
+        Some(net::SocketAddr::new(*ip, port))
+    }
+
     /// Whether there are any peers known to the address manager.
     pub fn is_empty(&self) -> bool {
-        self.peers.is_empty() || self.address_ranges.is_empty()
+        self.peers.is_empty() || self.table.is_empty()
+    }
+
+    /// Dump a summary of the address manager state, for debugging. No addresses are
+    /// included.
+    pub fn dump(&self) -> Value {
+        let (mut peer, mut dns, mut imported) = (0, 0, 0);
+        let (mut ipv4, mut ipv6, mut cjdns, mut i2p) = (0, 0, 0, 0);
+
+        for (_, ka) in self.peers.iter() {
+            match ka.source {
+                Source::Peer(_) => peer += 1,
+                Source::Dns => dns += 1,
+                Source::Imported => imported += 1,
+            }
+            match ka.addr.socket_addr().map(|a| Domain::for_address(&a)) {
+                Ok(Domain::IPV4) => ipv4 += 1,
+                Ok(Domain::IPV6) => ipv6 += 1,
+                Ok(Domain::CJDNS) => cjdns += 1,
+                Ok(Domain::I2P) => i2p += 1,
+                Err(_) => {}
+            }
+        }
+
+        Value::Object(dump::object([
+            ("addresses", dump::number(self.peers.len())),
+            ("tried", dump::number(self.table.tried())),
+            (
+                "sources",
+                Value::Object(dump::object([
+                    ("peer", dump::number(peer)),
+                    ("dns", dump::number(dns)),
+                    ("imported", dump::number(imported)),
+                ])),
+            ),
+            (
+                "domains",
+                Value::Object(dump::object([
+                    ("ipv4", dump::number(ipv4)),
+                    ("ipv6", dump::number(ipv6)),
+                    ("cjdns", dump::number(cjdns)),
+                    ("i2p", dump::number(i2p)),
+                ])),
+            ),
+            (
+                "buckets",
+                Value::Object(dump::object([
+                    ("new", dump::number(self.table.new_buckets())),
+                    ("tried", dump::number(self.table.tried_buckets())),
+                ])),
+            ),
+            ("external", Value::Bool(self.external_address().is_some())),
+            ("connected", dump::number(self.connected.len())),
+            ("gossip_peers", dump::number(self.gossip.len())),
+            ("bans", dump::number(self.bans.len())),
+            ("asmap", Value::Bool(self.cfg.asmap.is_some())),
+            ("last_idle", dump::optional(self.last_idle, dump::time)),
+            ("schedule", self.schedule.dump()),
+        ]))
     }
 
     #[cfg(test)]
     /// Clear the address manager of all peers.
     pub fn clear(&mut self) {
         self.peers.clear();
-        self.address_ranges.clear();
-    }
-
-    /// Called when we received an `addr` message from a peer.
-    pub fn received_addr(&mut self, peer: net::SocketAddr, addrs: Vec<(BlockTime, Address)>) {
-        if addrs.is_empty() || addrs.len() > MAX_ADDR_ADDRESSES {
-            // Peer misbehaving, got empty message or too many addresses.
-            return;
-        }
-        let source = Source::Peer(peer);
-
-        self.upstream.event(Event::AddressesReceived {
-            count: addrs.len(),
-            source,
-        });
-        self.insert(addrs.into_iter(), source);
+        self.table.clear();
     }
 
     /// Add addresses to the address manager. The input matches that of the `addr` message
@@ -405,7 +808,10 @@ impl<P: Store, U: Events, C: Clock> AddressManager<P, U, C> {
             let ip = net_addr.ip();
 
             // Ensure no self-connections.
-            if self.local_addrs.contains(&net_addr) {
+            if self.local_addrs.contains(&net_addr)
+                || (self.external.contains_key(&ip)
+                    && self.cfg.external_port == Some(net_addr.port()))
+            {
                 continue;
             }
             // No banned addresses.
@@ -430,17 +836,42 @@ impl<P: Store, U: Events, C: Clock> AddressManager<P, U, C> {
                 Some(LocalTime::from_block_time(last_active))
             };
 
-            // Record the address, and ignore addresses we already know.
-            // Note that this should never overwrite an existing address.
-            if !self
-                .peers
-                .insert(ip, KnownAddress::new(addr.clone(), source, last_active))
+            // Ignore addresses we already know.
+            if self.table.contains(&ip) {
+                continue;
+            }
+            // Find the address a slot in the table, and record it.
+            if !self.populate_table_with(ip, &source) {
+                continue;
+            }
+            self.peers
+                .insert(ip, KnownAddress::new(addr.clone(), source, last_active));
+            self.upstream.event(Event::AddressDiscovered(addr, source));
+        }
+    }
+
+    /// Add addresses resolved from DNS seeds. Unlike with [`AddressManager::insert`], these
+    /// don't come with service information or a last active time.
+    pub fn seed(&mut self, addrs: impl IntoIterator<Item = net::SocketAddr>) {
+        for addr in addrs {
+            let ip = addr.ip();
+
+            if !self.cfg.domains.contains(&Domain::for_address(&addr))
+                || self.bans.contains(&ip)
+                || self.table.contains(&ip)
+                || self.local_addrs.contains(&addr)
             {
                 continue;
             }
+            if !self.populate_table_with(ip, &Source::Dns) {
+                continue;
+            }
+            let addr = Address::new(&addr, ServiceFlags::NONE);
 
-            self.populate_address_ranges(&net_addr.ip());
-            self.upstream.event(Event::AddressDiscovered(addr, source));
+            self.peers
+                .insert(ip, KnownAddress::new(addr.clone(), Source::Dns, None));
+            self.upstream
+                .event(Event::AddressDiscovered(addr, Source::Dns));
         }
     }
 
@@ -448,32 +879,26 @@ impl<P: Store, U: Events, C: Clock> AddressManager<P, U, C> {
     ///
     /// This function tries to ensure a good geo-diversity of addresses, such that an adversary
     /// controlling a disproportionately large number of addresses in the same address range does
-    /// not have an advantage over other peers.
+    /// not have an advantage over other peers: a bucket is picked at random before an address
+    /// in it, and addresses of a given range all share a handful of buckets.
     ///
     /// This works under the assumption that adversaries are *localized*.
     pub fn sample(&mut self, services: ServiceFlags) -> Option<(Address, Source)> {
-        self.sample_with(|ka: &KnownAddress| {
-            if !ka.addr.services.has(services) {
-                match ka.source {
-                    Source::Dns => {
-                        // If we've negotiated with this peer and it hasn't signaled the
-                        // required services, we know not to return it.
-                        // DNS-sourced addresses don't include service information,
-                        // so we won't be including these until we know the services.
-                    }
-                    Source::Imported => {
-                        // We expect that imported addresses will always include the correct
-                        // service information. Hence, if this one doesn't have the necessary
-                        // services, it's safe to skip.
-                    }
-                    Source::Peer(_) => {
-                        // Peer-sourced addresses come with service information. It's safe to
-                        // skip this address if it doesn't have the required services.
-                    }
-                }
-                return false;
-            }
-            true
+        self.sample_with(|ka| has_services(ka, services))
+    }
+
+    /// Pick an address of the given domain at random from the set of known addresses.
+    pub fn sample_in(
+        &mut self,
+        services: ServiceFlags,
+        domain: Domain,
+    ) -> Option<(Address, Source)> {
+        self.sample_with(|ka| {
+            has_services(ka, services)
+                && ka
+                    .addr
+                    .socket_addr()
+                    .is_ok_and(|a| Domain::for_address(&a) == domain)
         })
     }
 
@@ -490,52 +915,52 @@ impl<P: Store, U: Events, C: Clock> AddressManager<P, U, C> {
             .last_idle
             .expect("AddressManager::sample: manager must be initialized before sampling");
         let domains = &self.cfg.domains;
+        // Network groups of our outbound peers. We don't make more than one outbound
+        // connection per group, so that a single entity can't control all of them.
+        let outbound = self
+            .outbound
+            .iter()
+            .map(|addr| self.table.group(&addr.ip()))
+            .collect::<std::collections::HashSet<_>>();
+
+        // Addresses come bucket by bucket, with buckets in random order.
+        for ip in self.table.candidates(&self.rng) {
+            let ka = self.peers.get_mut(&ip).expect("address must exist");
+
+            // If the address domain is unsupported, skip it.
+            // Nb. this currently skips Tor addresses too.
+            if !ka
+                .addr
+                .socket_addr()
+                .map_or(false, |a| domains.contains(&Domain::for_address(&a)))
+            {
+                continue;
+            }
 
-        let mut ranges: Vec<_> = self.address_ranges.values().collect();
-        self.rng.shuffle(&mut ranges);
-
-        // First select a random address range.
-        for range in ranges.drain(..) {
-            assert!(!range.is_empty());
-
-            let mut ips: Vec<_> = range.iter().collect();
-            self.rng.shuffle(&mut ips);
-
-            // Then select a random address in that range.
-            for ip in ips.drain(..) {
-                let ka = self.peers.get_mut(ip).expect("address must exist");
-
-                // If the address domain is unsupported, skip it.
-                // Nb. this currently skips Tor addresses too.
-                if !ka
-                    .addr
-                    .socket_addr()
-                    .map_or(false, |a| domains.contains(&Domain::for_address(&a)))
-                {
-                    continue;
-                }
-
-                // If the address was already attempted unsuccessfully, skip it.
-                if ka.last_attempt.is_some() && ka.last_success.is_none() {
-                    continue;
-                }
-                // If we recently sampled this address, don't return it again.
-                if time - ka.last_sampled.unwrap_or_default() < SAMPLE_TIMEOUT {
-                    continue;
-                }
-                // If we're already connected to this address, skip it.
-                if self.connected.contains(ip) {
-                    continue;
-                }
-                // If the provided filter doesn't pass, keep looking.
-                if !predicate(ka) {
-                    continue;
-                }
-                // Ok, we've found a worthy address!
-                ka.last_sampled = Some(time);
-
-                return Some((ka.addr.clone(), ka.source));
+            // If the address was already attempted unsuccessfully, skip it.
+            if ka.last_attempt.is_some() && ka.last_success.is_none() {
+                continue;
             }
+            // If we recently sampled this address, don't return it again.
+            if time - ka.last_sampled.unwrap_or_default() < SAMPLE_TIMEOUT {
+                continue;
+            }
+            // If we're already connected to this address, skip it.
+            if self.connected.contains(&ip) {
+                continue;
+            }
+            // If we're connected to another address in its group, skip it.
+            if outbound.contains(&self.table.group(&ip)) {
+                continue;
+            }
+            // If the provided filter doesn't pass, keep looking.
+            if !predicate(ka) {
+                continue;
+            }
+            // Ok, we've found a worthy address!
+            ka.last_sampled = Some(time);
+
+            return Some((ka.addr.clone(), ka.source));
         }
 
         None
@@ -543,50 +968,76 @@ impl<P: Store, U: Events, C: Clock> AddressManager<P, U, C> {
 
     ////////////////////////////////////////////////////////////////////////////
 
-    /// Populate address ranges with an IP. This may remove an existing IP if
-    /// its range is full. Returns the range key that was used.
-    fn populate_address_ranges(&mut self, ip: &net::IpAddr) -> u8 {
-        let key = self::addr_key(ip);
-        let range = self.address_ranges.entry(key).or_insert_with({
-            let rng = self.rng.clone();
+    /// Populate the address table with the addresses of the store. Addresses that don't
+    /// fit in the table are removed from the store.
+    fn populate_table(&mut self) {
+        let mut addrs = self
+            .peers
+            .iter()
+            .map(|(ip, ka)| (*ip, ka.source, ka.last_success.is_some()))
+            .collect::<Vec<_>>();
+        // Load tried addresses first, so that they get first pick of the slots.
+        addrs.sort_by_key(|(_, _, tried)| !tried);
+
+        for (ip, source, tried) in addrs {
+            if !self.populate_table_with(ip, &source) {
+                self.peers.remove(&ip);
+            } else if tried {
+                self.mark_tried(&ip);
+            }
+        }
+    }
 
-            || HashSet::with_hasher(rng.into())
+    /// Find a new address a slot in the address table. Addresses of poor quality are
+    /// evicted from the table and the store to make room for it. Returns `false` if
+    /// there was no room for the address.
+    fn populate_table_with(&mut self, ip: net::IpAddr, source: &Source) -> bool {
+        let time = self.clock.local_time();
+        let peers = &self.peers;
+        let outcome = self.table.insert(ip, source, |ip| {
+            peers.get(ip).is_none_or(|ka| is_terrible(ka, time))
         });
 
-        // If the address range is already full, remove a random address
-        // before inserting this new one.
-        if range.len() == MAX_RANGE_SIZE {
-            let ix = self.rng.usize(..range.len());
-            let addr = range
-                .iter()
-                .cloned()
-                .nth(ix)
-                .expect("the range is not empty");
-
-            range.remove(&addr);
-            self.peers.remove(&addr);
+        match outcome {
+            Outcome::Added { evicted } => {
+                for ip in evicted {
+                    self.peers.remove(&ip);
+                }
+                true
+            }
+            Outcome::Collision(_) | Outcome::Exists => false,
         }
-        range.insert(*ip);
+    }
+
+    /// Move an address to the tried buckets. The address in its slot, if any, is kept
+    /// if we're connected to it or connected to it recently.
+    fn mark_tried(&mut self, ip: &net::IpAddr) {
+        let time = self.clock.local_time();
+        let (peers, connected) = (&self.peers, &self.connected);
+        let outcome = self.table.mark_tried(ip, |ip| {
+            connected.contains(ip)
+                || peers
+                    .get(ip)
+                    .and_then(|ka| ka.last_success)
+                    .is_some_and(|t| time - t < TRIED_REPLACEMENT)
+        });
 
-        key
+        if let Outcome::Added { evicted } = outcome {
+            for ip in evicted {
+                self.peers.remove(&ip);
+            }
+        }
     }
 
     /// Remove an address from the address book and prevent it from being sampled again.
     fn ban(&mut self, addr: &net::IpAddr) -> bool {
         debug_assert!(!self.connected.contains(addr));
 
-        let key = self::addr_key(addr);
-
-        if let Some(range) = self.address_ranges.get_mut(&key) {
-            range.remove(addr);
-
+        if self.table.remove(addr) {
             // TODO: Persist bans.
             self.peers.remove(addr);
             self.bans.insert(*addr);
 
-            if range.is_empty() {
-                self.address_ranges.remove(&key);
-            }
             return true;
         }
         false
@@ -600,15 +1051,59 @@ impl<P: Store, U: Events + SyncAddresses + Wakeup, C: Clock> AddressSource
         AddressManager::sample(self, services)
     }
 
+    fn sample_in(&mut self, services: ServiceFlags, domain: Domain) -> Option<(Address, Source)> {
+        AddressManager::sample_in(self, services, domain)
+    }
+
     fn record_local_address(&mut self, addr: net::SocketAddr) {
         self.local_addrs.insert(addr);
     }
 
+    fn record_external_address(&mut self, addr: net::SocketAddr, from: net::SocketAddr) {
+        let ip = addr.ip();
+
+        if !self::is_routable(&ip) || self::is_local(&ip) || !self.connected.contains(&from.ip()) {
+            return;
+        }
+        self.external
+            .entry(ip)
+            .or_insert_with({
+                let rng = self.rng.clone();
+                || HashSet::with_hasher(rng.into())
+            })
+            .insert(from);
+    }
+
     fn iter(&mut self, services: ServiceFlags) -> Box<dyn Iterator<Item = (Address, Source)> + '_> {
         Box::new(AddressManager::iter(self, services))
     }
 }
 
+/// Check whether a known address signals the given services.
+fn has_services(ka: &KnownAddress, services: ServiceFlags) -> bool {
+    if !ka.addr.services.has(services) {
+        match ka.source {
+            Source::Dns => {
+                // If we've negotiated with this peer and it hasn't signaled the
+                // required services, we know not to return it.
+                // DNS-sourced addresses don't include service information,
+                // so we won't be including these until we know the services.
+            }
+            Source::Imported => {
+                // We expect that imported addresses will always include the correct
+                // service information. Hence, if this one doesn't have the necessary
+                // services, it's safe to skip.
+            }
+            Source::Peer(_) => {
+                // Peer-sourced addresses come with service information. It's safe to
+                // skip this address if it doesn't have the required services.
+            }
+        }
+        return false;
+    }
+    true
+}
+
 /// Check whether an IP address is globally routable.
 pub fn is_routable(addr: &net::IpAddr) -> bool {
     match addr {
@@ -627,30 +1122,46 @@ pub fn is_local(addr: &net::IpAddr) -> bool {
     }
 }
 
-/// Get the 8-bit key of an IP address. This key is based on the IP address's
-/// range, and is used as a key to group IP addresses by range.
-fn addr_key(ip: &net::IpAddr) -> u8 {
-    match ip {
-        net::IpAddr::V4(ip) => {
-            // Use the /16 range (first two components) of the IP address to key into the
-            // range buckets.
-            //
-            // Eg. 124.99.123.1 and 124.54.123.1 would be placed in
-            // different buckets, but 100.99.43.12 and 100.99.12.8
-            // would be placed in the same bucket.
-            let octets: [u8; 4] = ip.octets();
-            let bits: u16 = (octets[0] as u16) << 8 | octets[1] as u16;
-
-            (bits % u8::MAX as u16) as u8
-        }
-        net::IpAddr::V6(ip) => {
-            // Use the first 32 bits of an IPv6 address to as a key.
-            let segments: [u16; 8] = ip.segments();
-            let bits: u32 = (segments[0] as u32) << 16 | segments[1] as u32;
+/// Check whether an address can be sent in an `addr` message. CJDNS and I2P addresses can
+/// only be sent with `addrv2`, since they would be taken for private IPv6 addresses.
+fn is_addrv1_compatible(addr: &Address) -> bool {
+    addr.socket_addr().map_or(true, |a| {
+        !matches!(Domain::for_address(&a), Domain::CJDNS | Domain::I2P)
+    })
+}
 
-            (bits % u8::MAX as u32) as u8
-        }
+/// Convert an address to its `addrv2` form. Returns `None` for I2P addresses that can't be
+/// resolved, or invalid addresses.
+fn to_addrv2(time: BlockTime, addr: &Address) -> Option<AddrV2Message> {
+    let socket_addr = addr.socket_addr().ok()?;
+    let addr_v2 = match socket_addr {
+        net::SocketAddr::V4(a) => AddrV2::Ipv4(*a.ip()),
+        net::SocketAddr::V6(a) => match Domain::for_address(&socket_addr) {
+            Domain::CJDNS => AddrV2::Cjdns(*a.ip()),
+            Domain::I2P => AddrV2::I2p(i2p::resolve(&socket_addr)?),
+            _ => AddrV2::Ipv6(*a.ip()),
+        },
+    };
+    Some(AddrV2Message {
+        time,
+        services: addr.services,
+        addr: addr_v2,
+        port: socket_addr.port(),
+    })
+}
+
+/// Check whether a known address is of poor quality, and can be evicted from the address
+/// table to make room for another.
+fn is_terrible(ka: &KnownAddress, time: LocalTime) -> bool {
+    // Addresses that haven't been active in a long time.
+    if ka.last_active.is_none_or(|t| time - t > ADDRESS_HORIZON) {
+        return true;
+    }
+    // Addresses we couldn't connect to. Recent attempts may still be ongoing.
+    if ka.last_success.is_none() && ka.last_attempt.is_some_and(|t| time - t > REQUEST_TIMEOUT) {
+        return true;
     }
+    false
 }
 
 /// Check whether an IPv4 address is globally routable.
@@ -762,7 +1273,7 @@ mod tests {
         assert!(ka.last_sampled.is_none());
 
         // Only when it is negotiated is it a "success".
-        addrmgr.peer_negotiated(addr, services, Link::Outbound);
+        addrmgr.peer_negotiated(addr, services, Link::Outbound, true, false);
 
         let ka = addrmgr.peers.get(&addr.ip()).unwrap();
         assert!(ka.last_success.is_some());
@@ -827,7 +1338,13 @@ mod tests {
         // If a peer has been connected to successfully, and then disconnected for a transient
         // reason, its address should be once again available.
         addrmgr.peer_connected(&([44, 44, 44, 44], 8333).into());
-        addrmgr.peer_negotiated(&([44, 44, 44, 44], 8333).into(), services, Link::Outbound);
+        addrmgr.peer_negotiated(
+            &([44, 44, 44, 44], 8333).into(),
+            services,
+            Link::Outbound,
+            true,
+            false,
+        );
         addrmgr.peer_disconnected(
             &([44, 44, 44, 44], 8333).into(),
             DisconnectReason::PeerTimeout("timeout"),
@@ -881,7 +1398,7 @@ mod tests {
 
         addrmgr.peer_attempted(addr);
         addrmgr.peer_connected(addr);
-        addrmgr.peer_negotiated(addr, services, Link::Outbound);
+        addrmgr.peer_negotiated(addr, services, Link::Outbound, true, false);
         addrmgr.peer_disconnected(addr, DisconnectReason::PeerMisbehaving("misbehaving"));
 
         // Peer is now disconnected for non-transient reasons.
@@ -932,52 +1449,60 @@ mod tests {
             Source::Dns,
         );
 
+        // Addresses that collide with others in the table are not added.
+        let stored = addrmgr.peers.keys().copied().collect::<HashSet<_>>();
+        assert!(!stored.is_empty() || addrs.is_empty());
+
         let mut sampled = HashSet::with_hasher(fastrand::Rng::with_seed(seed).into());
-        for _ in 0..addrs.len() {
+        for _ in 0..stored.len() {
             let (addr, _) = addrmgr
                 .sample(services)
                 .expect("an address should be returned");
             sampled.insert(addr.socket_addr().unwrap().ip());
         }
 
-        assert_eq!(
-            sampled,
-            addrs.iter().map(|a| (*a).into()).collect::<HashSet<_>>()
-        );
+        assert_eq!(sampled, stored);
+        assert!(addrmgr.sample(services).is_none());
+
         TestResult::passed()
     }
 
     #[test]
-    fn test_max_range_size() {
+    fn test_max_bucket_size() {
         let services = ServiceFlags::NONE;
         let time = LocalTime::now();
 
+        // Pin the bucketing key, so that the two ranges never share a bucket.
         let mut addrmgr = AddressManager::new(
-            Config::default(),
-            fastrand::Rng::new(),
+            Config {
+                key: Some([1; 32]),
+                ..Config::default()
+            },
+            fastrand::Rng::with_seed(1),
             HashMap::new(),
             (),
             time,
         );
         addrmgr.initialize();
 
-        for i in 0..MAX_RANGE_SIZE + 1 {
+        for i in 0..table::BUCKET_SIZE as u16 * 16 {
             addrmgr.insert(
                 iter::once((
                     time.block_time(),
                     Address::new(
-                        &([111, 111, (i / u8::MAX as usize) as u8, i as u8], 8333).into(),
+                        &([111, 111, (i >> 8) as u8, i as u8], 8333).into(),
                         services,
                     ),
                 )),
                 Source::Dns,
             );
         }
-        assert_eq!(
-            addrmgr.len(),
-            MAX_RANGE_SIZE,
-            "we can't insert more than a certain amount of addresses in the same range"
+        let len = addrmgr.len();
+        assert!(
+            len <= table::BUCKET_SIZE,
+            "we can't insert more than a bucket's worth of addresses in the same range"
         );
+        assert_eq!(addrmgr.table.len(), len);
 
         addrmgr.insert(
             iter::once((
@@ -988,25 +1513,212 @@ mod tests {
         );
         assert_eq!(
             addrmgr.len(),
-            MAX_RANGE_SIZE + 1,
+            len + 1,
             "inserting in another range is perfectly fine"
         );
     }
 
     #[test]
-    fn test_addr_key() {
-        assert_eq!(
-            addr_key(&net::IpAddr::V4(net::Ipv4Addr::new(255, 0, 3, 4))),
-            0
+    fn test_evict_terrible() {
+        let services = ServiceFlags::NONE;
+        let clock = RefClock::from(LocalTime::now());
+        let mut addrmgr = AddressManager::new(
+            Config::default(),
+            fastrand::Rng::new(),
+            HashMap::new(),
+            (),
+            clock.clone(),
         );
-        assert_eq!(
-            addr_key(&net::IpAddr::V4(net::Ipv4Addr::new(255, 1, 3, 4))),
-            1
+        addrmgr.initialize();
+
+        // Fill the bucket of a range with addresses that we then fail to connect to.
+        for i in 0..table::BUCKET_SIZE as u16 * 16 {
+            addrmgr.insert(
+                iter::once((
+                    clock.block_time(),
+                    Address::new(
+                        &([111, 111, (i >> 8) as u8, i as u8], 8333).into(),
+                        services,
+                    ),
+                )),
+                Source::Dns,
+            );
+        }
+        let failed = addrmgr.peers.keys().copied().collect::<Vec<_>>();
+        for ip in &failed {
+            addrmgr.peer_attempted(&(*ip, 8333).into());
+        }
+        clock.elapse(REQUEST_TIMEOUT * 2);
+
+        // New addresses of the range now replace the failed ones.
+        for i in 0..table::BUCKET_SIZE as u16 * 16 {
+            addrmgr.insert(
+                iter::once((
+                    clock.block_time(),
+                    Address::new(
+                        &([111, 111, (i >> 8) as u8, i as u8], 8334).into(),
+                        services,
+                    ),
+                )),
+                Source::Dns,
+            );
+        }
+        assert!(!addrmgr.is_empty());
+        assert!(addrmgr.peers.values().all(|ka| ka.last_attempt.is_none()));
+        assert_eq!(addrmgr.table.len(), addrmgr.len());
+    }
+
+    #[test]
+    fn test_advertise_external_address() {
+        use crate::protocol::output::{self, Outbox};
+        use nakamoto_common::bitcoin::network::message::NetworkMessage;
+
+        let clock = RefClock::from(LocalTime::now());
+        let mut upstream = Outbox::new(Network::Mainnet, 0, "test");
+        let cfg = Config {
+            services: ServiceFlags::NETWORK,
+            external_port: Some(8333),
+            ..Config::default()
+        };
+        let mut addrmgr = AddressManager::new(
+            cfg,
+            fastrand::Rng::new(),
+            HashMap::new(),
+            upstream.clone(),
+            clock.clone(),
         );
-        assert_eq!(
-            addr_key(&net::IpAddr::V4(net::Ipv4Addr::new(1, 255, 3, 4))),
-            1
+        let external: net::SocketAddr = ([77, 77, 77, 77], 8333).into();
+        let seen: net::SocketAddr = ([77, 77, 77, 77], 54321).into();
+        let (alice, bob, carol, dave): (PeerId, PeerId, PeerId, PeerId) = (
+            ([88, 88, 88, 88], 8333).into(),
+            ([99, 99, 99, 99], 8333).into(),
+            ([111, 111, 111, 111], 8333).into(),
+            ([122, 122, 122, 122], 8333).into(),
+        );
+        let advertised = |upstream: &mut Outbox, peer: &PeerId| {
+            output::test::messages(upstream, peer).any(|m| match m {
+                NetworkMessage::Addr(addrs) => addrs
+                    .iter()
+                    .any(|(_, a)| a.socket_addr().ok() == Some(external)),
+                _ => false,
+            })
+        };
+        addrmgr.initialize();
+
+        for peer in [alice, bob, carol, dave] {
+            addrmgr.peer_connected(&peer);
+        }
+        // A single peer isn't trusted with our external address.
+        addrmgr.record_external_address(seen, alice);
+        addrmgr.record_external_address(([66, 66, 66, 66], 1).into(), dave);
+        assert_eq!(addrmgr.external_address(), None);
+
+        addrmgr.record_external_address(seen, bob);
+        assert_eq!(addrmgr.external_address(), Some(external));
+
+        // Our address is advertised to outbound peers once connected.
+        addrmgr.peer_negotiated(&carol, ServiceFlags::NETWORK, Link::Outbound, true, false);
+        assert!(advertised(&mut upstream, &carol));
+
+        // Inbound peers learn about it periodically.
+        addrmgr.peer_negotiated(&dave, ServiceFlags::NETWORK, Link::Inbound, true, false);
+        assert!(!advertised(&mut upstream, &dave));
+
+        clock.elapse(ADVERTISE_INTERVAL);
+        addrmgr.received_wake();
+        assert!(advertised(&mut upstream, &carol));
+        assert!(advertised(&mut upstream, &dave));
+
+        // Once a reporting peer disconnects, our address isn't known anymore.
+        addrmgr.peer_disconnected(&alice, DisconnectReason::PeerTimeout("timeout"));
+        assert_eq!(addrmgr.external_address(), None);
+
+        clock.elapse(ADVERTISE_INTERVAL);
+        addrmgr.received_wake();
+        assert!(!advertised(&mut upstream, &carol));
+
+        // We don't add our own address to the address book.
+        addrmgr.record_external_address(seen, carol);
+        addrmgr.insert(
+            [(
+                clock.block_time(),
+                Address::new(&external, ServiceFlags::NETWORK),
+            )],
+            Source::Dns,
+        );
+        assert!(addrmgr.is_empty());
+    }
+
+    #[test]
+    fn test_outbound_diversity() {
+        let services = ServiceFlags::NETWORK;
+        let time = LocalTime::now();
+        let mut addrmgr = AddressManager::new(
+            Config {
+                asmap: Some(crate::protocol::asmap::test::asmap(1, 2)),
+                ..Config::default()
+            },
+            fastrand::Rng::new(),
+            HashMap::new(),
+            (),
+            time,
         );
+        addrmgr.initialize();
+
+        // Addresses in many different prefixes, but only two autonomous systems.
+        for i in (11..21).chain(131..141) {
+            addrmgr.insert(
+                iter::once((
+                    time.block_time(),
+                    Address::new(&([i, 1, 1, 1], 8333).into(), services),
+                )),
+                Source::Dns,
+            );
+        }
+
+        let (first, _) = addrmgr.sample(services).unwrap();
+        let first = first.socket_addr().unwrap();
+        addrmgr.peer_attempted(&first);
+
+        let (second, _) = addrmgr.sample(services).unwrap();
+        let second = second.socket_addr().unwrap();
+        addrmgr.peer_attempted(&second);
+
+        let is_low = |addr: &net::SocketAddr| matches!(addr.ip(), net::IpAddr::V4(ip) if ip.octets()[0] < 128);
+
+        assert_ne!(
+            is_low(&first),
+            is_low(&second),
+            "outbound peers are chosen from distinct autonomous systems"
+        );
+        assert!(addrmgr.sample(services).is_none());
+
+        // Once a peer disconnects, its autonomous system is available again.
+        addrmgr.peer_disconnected(&first, DisconnectReason::PeerTimeout("timeout"));
+        assert!(addrmgr.sample(services).is_some());
+    }
+
+    #[test]
+    fn test_tried_from_store() {
+        let time = LocalTime::now();
+        let mut peers = HashMap::new();
+
+        for (i, ip) in [[44, 44, 44, 44], [55, 55, 55, 55]].into_iter().enumerate() {
+            let mut ka = KnownAddress::new(
+                Address::new(&(ip, 8333).into(), ServiceFlags::NETWORK),
+                Source::Dns,
+                Some(time),
+            );
+            if i == 0 {
+                ka.last_success = Some(time);
+            }
+            peers.insert(ip.into(), ka);
+        }
+        let addrmgr = AddressManager::new(Config::default(), fastrand::Rng::new(), peers, (), time);
+
+        assert_eq!(addrmgr.table.len(), 2);
+        assert!(addrmgr.table.is_tried(&[44, 44, 44, 44].into()));
+        assert!(!addrmgr.table.is_tried(&[55, 55, 55, 55].into()));
     }
 
     #[test]
@@ -1063,6 +1775,77 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_received_addrv2() {
+        let time = LocalTime::now();
+        let peer: net::SocketAddr = ([99, 99, 99, 99], 8333).into();
+        let cjdns: net::Ipv6Addr = "fc32:17ea:e415:c3bf:9808:149d:b5a2:c9aa".parse().unwrap();
+        let private: net::Ipv6Addr = "fc00::1".parse().unwrap();
+        let i2p = i2p::socket_addr([0x3e; 32]);
+        let msg = |addr| AddrV2Message {
+            time: time.block_time(),
+            services: ServiceFlags::NETWORK,
+            addr,
+            port: 8333,
+        };
+        let addrs = vec![
+            msg(AddrV2::Ipv4([183, 8, 55, 2].into())),
+            msg(AddrV2::Cjdns(cjdns)),
+            // Not a CJDNS address.
+            msg(AddrV2::Cjdns("2001:db8::1".parse().unwrap())),
+            // A private address, mislabeled as IPv6.
+            msg(AddrV2::Ipv6(private)),
+            msg(AddrV2::TorV3([1; 32])),
+            msg(AddrV2::I2p([0x3e; 32])),
+            // A synthetic I2P address, mislabeled as IPv6.
+            msg(AddrV2::Ipv6("fd60:db4d:ddb5:1::1".parse().unwrap())),
+        ];
+
+        for (domains, expected) in [
+            (Domain::all(), vec![net::IpAddr::from([183, 8, 55, 2])]),
+            (
+                vec![Domain::IPV4, Domain::CJDNS],
+                vec![[183, 8, 55, 2].into(), cjdns.into()],
+            ),
+            (
+                vec![Domain::IPV4, Domain::I2P],
+                vec![[183, 8, 55, 2].into(), i2p.ip()],
+            ),
+        ] {
+            let cfg = Config {
+                domains,
+                ..Config::default()
+            };
+            let mut addrmgr =
+                AddressManager::new(cfg, fastrand::Rng::new(), HashMap::new(), (), time);
+
+            addrmgr.initialize();
+            addrmgr.received_addrv2(peer, addrs.clone());
+
+            let mut ips = addrmgr.peers.keys().copied().collect::<Vec<_>>();
+            ips.sort();
+
+            assert_eq!(ips, expected);
+
+            if let Some(ka) = addrmgr.peers.get(&i2p.ip()) {
+                assert_eq!(ka.addr.socket_addr().ok(), Some(i2p));
+                assert_eq!(i2p::resolve(&i2p), Some([0x3e; 32]));
+                // I2P addresses can't be announced with `addr` either.
+                assert!(!is_addrv1_compatible(&ka.addr));
+            }
+
+            // CJDNS addresses can't be announced with `addr`.
+            addrmgr.received_addr(
+                peer,
+                vec![(
+                    time.block_time(),
+                    Address::new(&(private, 8333).into(), ServiceFlags::NETWORK),
+                )],
+            );
+            assert_eq!(addrmgr.len(), expected.len());
+        }
+    }
+
     #[test]
     fn test_sample() {
         use std::collections::HashMap;
@@ -1074,8 +1857,9 @@ mod tests {
 
         let cfg = Config::default();
         let clock = RefClock::from(LocalTime::now());
-        let mut addrmgr =
-            AddressManager::new(cfg, fastrand::Rng::new(), HashMap::new(), (), clock.clone());
+        // Use a fixed seed, so that bucket placement is deterministic.
+        let rng = fastrand::Rng::with_seed(1);
+        let mut addrmgr = AddressManager::new(cfg, rng, HashMap::new(), (), clock.clone());
 
         addrmgr.initialize();
 
@@ -0,0 +1,649 @@
+//! Address table, modeled after Bitcoin Core's address manager.
+//!
+//! Addresses we haven't connected to yet are placed in "new" buckets, and addresses we
+//! successfully connected to are moved to "tried" buckets. The bucket of an address is
+//! derived from a secret key, the network group of the address, and for new addresses,
+//! the network group of the peer that told us about it. This limits how much of the table
+//! an adversary can fill:
+//!
+//! * All addresses of a network group, sent by peers of a given network group, share a
+//!   single new bucket.
+//! * A given source group can only ever place addresses in [`NEW_BUCKETS_PER_SOURCE_GROUP`]
+//!   new buckets.
+//! * A given network group can only ever be placed in [`TRIED_BUCKETS_PER_GROUP`] tried
+//!   buckets.
+//!
+//! Each bucket has [`BUCKET_SIZE`] slots. When an address maps to a slot that is already
+//! taken, the existing address is only evicted if it's of poor quality, and sampling
+//! selects a bucket at random before selecting an address in it, so that an adversary with
+//! many addresses doesn't get picked more often than a handful of honest peers.
+//!
+//! Network groups are /16 prefixes for IPv4 and /32 prefixes for IPv6, unless an
+//! [`Asmap`] is used, in which case addresses are grouped by autonomous system.
+use std::collections::BTreeMap;
+use std::net;
+
+use nakamoto_common::bitcoin_hashes::{sha256d, Hash};
+use nakamoto_common::collections::HashMap;
+use nakamoto_common::p2p::i2p;
+use nakamoto_common::p2p::peer::Source;
+
+use crate::protocol::asmap::Asmap;
+
+/// Number of new buckets.
+pub const NEW_BUCKET_COUNT: usize = 1024;
+/// Number of tried buckets.
+pub const TRIED_BUCKET_COUNT: usize = 256;
+/// Number of addresses in a bucket.
+pub const BUCKET_SIZE: usize = 64;
+/// Number of tried buckets the addresses of a network group are spread over.
+pub const TRIED_BUCKETS_PER_GROUP: u64 = 8;
+/// Number of new buckets the addresses sent by a source group are spread over.
+pub const NEW_BUCKETS_PER_SOURCE_GROUP: u64 = 64;
+
+/// Network group of an address. Addresses in the same group are likely to be controlled
+/// by the same entity.
+pub type NetGroup = Vec<u8>;
+
+/// Get the network group of an IP address: its autonomous system if it's mapped by the
+/// given asmap, and otherwise its /16 for IPv4, and its /32 for IPv6. Non-routable
+/// addresses all share a group.
+///
+/// CJDNS addresses are derived from public keys, so they are cheap to come by in any
+/// range: they're grouped by their first four bits after the `fc` prefix, like in
+/// Bitcoin Core. The same goes for I2P addresses, which are hashes.
+pub fn netgroup(ip: &net::IpAddr, asmap: Option<&Asmap>) -> NetGroup {
+    match ip {
+        net::IpAddr::V6(v6) if v6.octets()[0] == 0xfc => vec![4, v6.octets()[1] & 0xf0],
+        net::IpAddr::V6(v6) if i2p::is_i2p(v6) => vec![5, v6.octets()[6] & 0xf0],
+        net::IpAddr::V4(v4) if super::is_routable(ip) && !super::is_local(ip) => {
+            if let Some(asn) = asmap.and_then(|m| m.asn(ip)) {
+                return [&[3], &asn.to_le_bytes()[..]].concat();
+            }
+            let [a, b, _, _] = v4.octets();
+            vec![1, a, b]
+        }
+        net::IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => netgroup(&v4.into(), asmap),
+            None => {
+                if let Some(asn) = asmap.and_then(|m| m.asn(ip)) {
+                    return [&[3], &asn.to_le_bytes()[..]].concat();
+                }
+                let [a, b, c, d, ..] = v6.octets();
+                vec![2, a, b, c, d]
+            }
+        },
+        net::IpAddr::V4(_) => vec![0],
+    }
+}
+
+/// Get the network group of an address source. Addresses that don't come from peers are
+/// grouped by how we learned about them.
+pub fn source_group(source: &Source, asmap: Option<&Asmap>) -> NetGroup {
+    match source {
+        Source::Peer(addr) => netgroup(&addr.ip(), asmap),
+        Source::Dns => vec![0xff, 0],
+        Source::Imported => vec![0xff, 1],
+    }
+}
+
+/// Outcome of adding an address to the table, or moving it to a tried bucket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The address was added. Addresses that were removed from the table to make room
+    /// are included.
+    Added {
+        /// Addresses removed from the table.
+        evicted: Vec<net::IpAddr>,
+    },
+    /// The slot of the address is taken by another address, which was kept.
+    Collision(net::IpAddr),
+    /// The address was already in the table.
+    Exists,
+}
+
+/// Location of an address in the table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    New(usize, usize),
+    Tried(usize, usize),
+}
+
+/// A table entry.
+#[derive(Debug, Clone)]
+struct Entry {
+    /// Network group of the source of the address.
+    source: NetGroup,
+    slot: Slot,
+}
+
+/// Buckets of a table, by index. Buckets are removed when empty.
+type Buckets = BTreeMap<usize, BTreeMap<usize, net::IpAddr>>;
+
+/// Table of known addresses, split into new and tried buckets.
+#[derive(Debug)]
+pub struct AddressTable {
+    /// Secret key used to place addresses in buckets.
+    key: [u8; 32],
+    /// Map used to group addresses by autonomous system.
+    asmap: Option<Asmap>,
+    new: Buckets,
+    tried: Buckets,
+    entries: HashMap<net::IpAddr, Entry>,
+}
+
+impl AddressTable {
+    /// Create a new, empty table. The bucketing key is drawn from the given RNG.
+    pub fn new(rng: fastrand::Rng, asmap: Option<Asmap>) -> Self {
+        let mut key = [0; 32];
+        key.iter_mut().for_each(|b| *b = rng.u8(..));
+
+        Self::with_key(key, rng, asmap)
+    }
+
+    /// Create a new, empty table with the given bucketing key. Using the same key across
+    /// restarts keeps addresses in the same buckets.
+    pub fn with_key(key: [u8; 32], rng: fastrand::Rng, asmap: Option<Asmap>) -> Self {
+        Self {
+            key,
+            asmap,
+            new: BTreeMap::new(),
+            tried: BTreeMap::new(),
+            entries: HashMap::with_hasher(rng.into()),
+        }
+    }
+
+    /// Get the network group of an address.
+    pub fn group(&self, ip: &net::IpAddr) -> NetGroup {
+        self::netgroup(ip, self.asmap.as_ref())
+    }
+
+    /// Number of addresses in the table.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the table is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Number of addresses in tried buckets.
+    pub fn tried(&self) -> usize {
+        self.tried.values().map(|b| b.len()).sum()
+    }
+
+    /// Number of non-empty new buckets.
+    pub fn new_buckets(&self) -> usize {
+        self.new.len()
+    }
+
+    /// Number of non-empty tried buckets.
+    pub fn tried_buckets(&self) -> usize {
+        self.tried.len()
+    }
+
+    /// Whether the address is in the table.
+    pub fn contains(&self, ip: &net::IpAddr) -> bool {
+        self.entries.contains_key(ip)
+    }
+
+    #[cfg(test)]
+    /// Whether the address is in a tried bucket.
+    pub fn is_tried(&self, ip: &net::IpAddr) -> bool {
+        matches!(
+            self.entries.get(ip),
+            Some(Entry {
+                slot: Slot::Tried(..),
+                ..
+            })
+        )
+    }
+
+    /// Add an address to a new bucket. If its slot is taken by an address for which
+    /// `is_terrible` returns `true`, that address is evicted, otherwise the new address
+    /// isn't added.
+    pub fn insert(
+        &mut self,
+        ip: net::IpAddr,
+        source: &Source,
+        is_terrible: impl Fn(&net::IpAddr) -> bool,
+    ) -> Outcome {
+        if self.entries.contains_key(&ip) {
+            return Outcome::Exists;
+        }
+        let source = source_group(source, self.asmap.as_ref());
+        let slot = self.new_slot(&ip, &source);
+        let mut evicted = Vec::new();
+
+        if let Some(other) = self.get(slot) {
+            if !is_terrible(&other) {
+                return Outcome::Collision(other);
+            }
+            self.remove(&other);
+            evicted.push(other);
+        }
+        self.put(ip, Entry { source, slot });
+
+        Outcome::Added { evicted }
+    }
+
+    /// Move an address to a tried bucket. If its slot is taken by an address for which
+    /// `keep` returns `true`, the address stays where it is. Otherwise, the address in
+    /// the slot is moved back to a new bucket, evicting the address in that slot if any.
+    pub fn mark_tried(&mut self, ip: &net::IpAddr, keep: impl Fn(&net::IpAddr) -> bool) -> Outcome {
+        let Some(entry) = self.entries.get(ip).cloned() else {
+            return Outcome::Exists;
+        };
+        if let Slot::Tried(..) = entry.slot {
+            return Outcome::Exists;
+        }
+        let slot = self.tried_slot(ip);
+        let mut evicted = Vec::new();
+
+        if let Some(other) = self.get(slot) {
+            if keep(&other) {
+                return Outcome::Collision(other);
+            }
+            let source = self.entries[&other].source.clone();
+            let new = self.new_slot(&other, &source);
+
+            self.remove(&other);
+            self.remove(ip);
+            self.put(*ip, Entry { slot, ..entry });
+
+            if let Some(occupant) = self.get(new) {
+                self.remove(&occupant);
+                evicted.push(occupant);
+            }
+            self.put(other, Entry { source, slot: new });
+        } else {
+            self.remove(ip);
+            self.put(*ip, Entry { slot, ..entry });
+        }
+        Outcome::Added { evicted }
+    }
+
+    /// Remove an address from the table. Returns `false` if it wasn't in the table.
+    pub fn remove(&mut self, ip: &net::IpAddr) -> bool {
+        let Some(entry) = self.entries.remove(ip) else {
+            return false;
+        };
+        let (buckets, bucket, pos) = match entry.slot {
+            Slot::New(b, p) => (&mut self.new, b, p),
+            Slot::Tried(b, p) => (&mut self.tried, b, p),
+        };
+        if let Some(slots) = buckets.get_mut(&bucket) {
+            slots.remove(&pos);
+
+            if slots.is_empty() {
+                buckets.remove(&bucket);
+            }
+        }
+        true
+    }
+
+    #[cfg(test)]
+    /// Remove all addresses.
+    pub fn clear(&mut self) {
+        self.new.clear();
+        self.tried.clear();
+        self.entries.clear();
+    }
+
+    /// All addresses, in the order they should be considered when sampling.
+    ///
+    /// With even odds, tried or new addresses come first. Within each, buckets are ordered
+    /// at random, and so are addresses within a bucket, so that every bucket is equally
+    /// likely to be picked from first, no matter how many addresses it holds.
+    pub fn candidates(&self, rng: &fastrand::Rng) -> Vec<net::IpAddr> {
+        let mut tables = [&self.tried, &self.new];
+        if rng.bool() {
+            tables.reverse();
+        }
+        let mut candidates = Vec::with_capacity(self.len());
+
+        for buckets in tables {
+            let mut buckets = buckets.values().collect::<Vec<_>>();
+            rng.shuffle(&mut buckets);
+
+            for bucket in buckets {
+                let mut addrs = bucket.values().copied().collect::<Vec<_>>();
+                rng.shuffle(&mut addrs);
+
+                candidates.extend(addrs);
+            }
+        }
+        candidates
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+
+    fn get(&self, slot: Slot) -> Option<net::IpAddr> {
+        let (buckets, bucket, pos) = match slot {
+            Slot::New(b, p) => (&self.new, b, p),
+            Slot::Tried(b, p) => (&self.tried, b, p),
+        };
+        buckets.get(&bucket).and_then(|b| b.get(&pos)).copied()
+    }
+
+    fn put(&mut self, ip: net::IpAddr, entry: Entry) {
+        let (buckets, bucket, pos) = match entry.slot {
+            Slot::New(b, p) => (&mut self.new, b, p),
+            Slot::Tried(b, p) => (&mut self.tried, b, p),
+        };
+        buckets.entry(bucket).or_default().insert(pos, ip);
+        self.entries.insert(ip, entry);
+    }
+
+    fn new_slot(&self, ip: &net::IpAddr, source: &[u8]) -> Slot {
+        let group = self.group(ip);
+        let n = self.hash(&[&group, source]) % NEW_BUCKETS_PER_SOURCE_GROUP;
+        let bucket = self.hash(&[source, &n.to_le_bytes()]) as usize % NEW_BUCKET_COUNT;
+
+        Slot::New(bucket, self.position(b'N', bucket, ip))
+    }
+
+    fn tried_slot(&self, ip: &net::IpAddr) -> Slot {
+        let group = self.group(ip);
+        let n = self.hash(&[&octets(ip)]) % TRIED_BUCKETS_PER_GROUP;
+        let bucket = self.hash(&[&group, &n.to_le_bytes()]) as usize % TRIED_BUCKET_COUNT;
+
+        Slot::Tried(bucket, self.position(b'K', bucket, ip))
+    }
+
+    fn position(&self, table: u8, bucket: usize, ip: &net::IpAddr) -> usize {
+        self.hash(&[&[table], &(bucket as u64).to_le_bytes(), &octets(ip)]) as usize % BUCKET_SIZE
+    }
+
+    /// Keyed hash of the given data.
+    fn hash(&self, data: &[&[u8]]) -> u64 {
+        let mut engine = sha256d::Hash::engine();
+
+        std::io::Write::write_all(&mut engine, &self.key).ok();
+        for d in data {
+            std::io::Write::write_all(&mut engine, d).ok();
+        }
+        let hash = sha256d::Hash::from_engine(engine);
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&hash[..8]);
+
+        u64::from_le_bytes(bytes)
+    }
+}
+
+/// Bytes of an IP address.
+fn octets(ip: &net::IpAddr) -> Vec<u8> {
+    match ip {
+        net::IpAddr::V4(ip) => ip.octets().to_vec(),
+        net::IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use nakamoto_common::collections::HashSet;
+    use nakamoto_test::assert_matches;
+
+    fn ip(a: u8, b: u8, c: u8, d: u8) -> net::IpAddr {
+        net::IpAddr::V4(net::Ipv4Addr::new(a, b, c, d))
+    }
+
+    fn peer(a: u8, b: u8) -> Source {
+        Source::Peer(([a, b, 1, 1], 8333).into())
+    }
+
+    /// Find an address in the given /16 that maps to the same slot as `ip`.
+    fn collide(table: &AddressTable, ip: &net::IpAddr, source: &Source) -> net::IpAddr {
+        let [a, b, ..] = octets(ip)[..] else {
+            unreachable!()
+        };
+        let slot = table.new_slot(ip, &source_group(source, None));
+
+        (0..=u16::MAX)
+            .map(|i| self::ip(a, b, (i >> 8) as u8, i as u8))
+            .find(|other| other != ip && table.new_slot(other, &source_group(source, None)) == slot)
+            .expect("a colliding address exists")
+    }
+
+    #[test]
+    fn test_netgroup() {
+        assert_eq!(netgroup(&ip(124, 99, 123, 1), None), vec![1, 124, 99]);
+        assert_eq!(
+            netgroup(&ip(124, 99, 43, 12), None),
+            netgroup(&ip(124, 99, 8, 8), None)
+        );
+        assert_ne!(
+            netgroup(&ip(124, 99, 43, 12), None),
+            netgroup(&ip(124, 54, 43, 12), None)
+        );
+        assert_eq!(
+            netgroup(&ip(192, 168, 1, 1), None),
+            netgroup(&ip(10, 0, 0, 1), None)
+        );
+        assert_eq!(
+            netgroup(&"::ffff:124.99.123.1".parse().unwrap(), None),
+            netgroup(&ip(124, 99, 1, 1), None)
+        );
+        assert_eq!(
+            netgroup(&"2001:db8:1::1".parse().unwrap(), None),
+            netgroup(&"2001:db8:2::1".parse().unwrap(), None)
+        );
+        assert_eq!(
+            netgroup(&"fc12:3456::1".parse().unwrap(), None),
+            netgroup(&"fc1f:ffff::1".parse().unwrap(), None)
+        );
+        assert_ne!(
+            netgroup(&"fc12:3456::1".parse().unwrap(), None),
+            netgroup(&"fc22:3456::1".parse().unwrap(), None)
+        );
+        assert_eq!(
+            netgroup(&"fd60:db4d:ddb5:1200::1".parse().unwrap(), None),
+            netgroup(&"fd60:db4d:ddb5:1fff::1".parse().unwrap(), None)
+        );
+        assert_ne!(
+            netgroup(&"fd60:db4d:ddb5:1200::1".parse().unwrap(), None),
+            netgroup(&"fd60:db4d:ddb5:2200::1".parse().unwrap(), None)
+        );
+        assert_ne!(
+            source_group(&Source::Dns, None),
+            source_group(&Source::Imported, None)
+        );
+    }
+
+    #[test]
+    fn test_netgroup_asmap() {
+        let asmap = crate::protocol::asmap::test::asmap(64496, 64511);
+        let asmap = Some(&asmap);
+
+        // Addresses are grouped by AS, regardless of their prefix.
+        assert_eq!(
+            netgroup(&ip(1, 1, 1, 1), asmap),
+            netgroup(&ip(100, 200, 1, 1), asmap)
+        );
+        assert_ne!(
+            netgroup(&ip(1, 1, 1, 1), asmap),
+            netgroup(&ip(200, 1, 1, 1), asmap)
+        );
+        // Unmapped addresses fall back to prefixes.
+        assert_eq!(
+            netgroup(&"2001:db8:1::1".parse().unwrap(), asmap),
+            netgroup(&"2001:db8:1::1".parse().unwrap(), None)
+        );
+        // Non-routable addresses aren't mapped.
+        assert_eq!(netgroup(&ip(10, 0, 0, 1), asmap), vec![0]);
+
+        // Addresses of an AS sent by a single source share a bucket.
+        let mut table = AddressTable::new(fastrand::Rng::with_seed(1), asmap.cloned());
+        for a in (1..100).filter(|a| *a != 10) {
+            table.insert(ip(a, a, 1, 1), &peer(222, 1), |_| false);
+        }
+        assert_eq!(table.new_buckets(), 1);
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let a = AddressTable::new(fastrand::Rng::with_seed(7), None);
+        let b = AddressTable::new(fastrand::Rng::with_seed(7), None);
+        let c = AddressTable::new(fastrand::Rng::with_seed(8), None);
+        let addr = ip(124, 99, 123, 1);
+        let source = source_group(&Source::Dns, None);
+
+        assert_eq!(a.new_slot(&addr, &source), b.new_slot(&addr, &source));
+        assert_eq!(a.tried_slot(&addr), b.tried_slot(&addr));
+        assert_ne!(
+            (a.new_slot(&addr, &source), a.tried_slot(&addr)),
+            (c.new_slot(&addr, &source), c.tried_slot(&addr)),
+            "the key is secret"
+        );
+    }
+
+    #[test]
+    fn test_new_buckets() {
+        let mut table = AddressTable::new(fastrand::Rng::with_seed(1), None);
+
+        // All the addresses of a group sent by a single source share a bucket.
+        for i in 0..=u8::MAX {
+            table.insert(ip(111, 111, 1, i), &peer(88, 88), |_| false);
+        }
+        assert_eq!(table.new.len(), 1);
+        assert!(table.len() <= BUCKET_SIZE);
+
+        // A single source group can only fill a limited number of buckets.
+        let mut table = AddressTable::new(fastrand::Rng::with_seed(1), None);
+        for a in 1..=200 {
+            for b in 0..8 {
+                table.insert(ip(a, b, 1, 1), &peer(88, 88), |_| false);
+            }
+        }
+        assert!(table.new.len() as u64 <= NEW_BUCKETS_PER_SOURCE_GROUP);
+
+        // Addresses of a group sent by many sources are spread over several buckets.
+        let mut table = AddressTable::new(fastrand::Rng::with_seed(1), None);
+        for a in 1..=200 {
+            table.insert(ip(111, 111, 1, a), &peer(a, 88), |_| false);
+        }
+        assert!(table.new.len() > 1);
+    }
+
+    #[test]
+    fn test_tried_buckets() {
+        let mut table = AddressTable::new(fastrand::Rng::with_seed(1), None);
+        let mut buckets = HashSet::with_hasher(fastrand::Rng::with_seed(1).into());
+
+        for i in 0..=u8::MAX {
+            let addr = ip(111, 111, i, 1);
+            table.insert(addr, &peer(i, 1), |_| false);
+            table.mark_tried(&addr, |_| true);
+
+            if let Some(Entry {
+                slot: Slot::Tried(bucket, _),
+                ..
+            }) = table.entries.get(&addr)
+            {
+                buckets.insert(*bucket);
+            }
+        }
+        assert!(buckets.len() as u64 <= TRIED_BUCKETS_PER_GROUP);
+        assert_eq!(table.tried.len(), buckets.len());
+    }
+
+    #[test]
+    fn test_new_collision_eviction() {
+        let mut table = AddressTable::new(fastrand::Rng::with_seed(1), None);
+        let source = Source::Dns;
+        let first = ip(111, 111, 1, 1);
+        let second = collide(&table, &first, &source);
+
+        assert_eq!(
+            table.insert(first, &source, |_| false),
+            Outcome::Added { evicted: vec![] }
+        );
+        assert_eq!(table.insert(first, &source, |_| false), Outcome::Exists);
+
+        // Good addresses are kept.
+        assert_eq!(
+            table.insert(second, &source, |_| false),
+            Outcome::Collision(first)
+        );
+        assert!(table.contains(&first));
+        assert!(!table.contains(&second));
+
+        // Terrible addresses are evicted.
+        assert_eq!(
+            table.insert(second, &source, |a| a == &first),
+            Outcome::Added {
+                evicted: vec![first]
+            }
+        );
+        assert!(!table.contains(&first));
+        assert!(table.contains(&second));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn test_tried_collision_eviction() {
+        let mut table = AddressTable::new(fastrand::Rng::with_seed(1), None);
+        let first = ip(111, 111, 1, 1);
+        let second = (0..=u16::MAX)
+            .map(|i| ip(111, 111, (i >> 8) as u8, i as u8))
+            .find(|a| a != &first && table.tried_slot(a) == table.tried_slot(&first))
+            .unwrap();
+
+        for addr in [first, second] {
+            table.insert(addr, &Source::Dns, |_| false);
+        }
+        table.mark_tried(&first, |_| true);
+        assert!(table.is_tried(&first));
+        assert_eq!(table.tried(), 1);
+
+        // The tried address is kept if it's still good.
+        assert_eq!(
+            table.mark_tried(&second, |_| true),
+            Outcome::Collision(first)
+        );
+        assert!(table.is_tried(&first));
+        assert!(!table.is_tried(&second));
+
+        // Otherwise it's moved back to a new bucket.
+        assert_matches!(table.mark_tried(&second, |_| false), Outcome::Added { .. });
+        assert!(table.is_tried(&second));
+        assert!(!table.is_tried(&first));
+        assert!(table.contains(&first));
+        assert_eq!(table.tried(), 1);
+        assert_eq!(table.len(), 2);
+
+        // Removed addresses leave no trace.
+        assert!(table.remove(&second));
+        assert!(!table.remove(&second));
+        assert_eq!(table.tried(), 0);
+        assert!(table.tried.is_empty());
+    }
+
+    #[test]
+    fn test_candidates() {
+        let rng = fastrand::Rng::with_seed(1);
+        let mut table = AddressTable::new(rng.clone(), None);
+        let tried = ip(44, 44, 44, 44);
+
+        table.insert(tried, &Source::Dns, |_| false);
+        table.mark_tried(&tried, |_| true);
+
+        for a in 1..=32 {
+            table.insert(ip(a, a, a, a), &Source::Dns, |_| false);
+        }
+        let mut first = 0;
+        for _ in 0..256 {
+            let candidates = table.candidates(&rng);
+
+            assert_eq!(candidates.len(), table.len());
+            if candidates[0] == tried {
+                first += 1;
+            }
+        }
+        // A single tried address is returned first about half the time.
+        assert!((96..=160).contains(&first), "{}", first);
+    }
+}
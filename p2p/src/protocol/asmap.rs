@@ -0,0 +1,472 @@
+//! IP to AS number maps, in Bitcoin Core's `asmap` format.
+//!
+//! An asmap is a compressed binary trie, encoded as a small bytecode program which is run
+//! against the bits of an IP address to find the autonomous system (AS) it belongs to.
+//! Grouping addresses by AS rather than by prefix makes it harder for an adversary renting
+//! servers from a single hosting provider to fill our address table and connections, since
+//! hosting providers commonly announce many unrelated prefixes.
+//!
+//! Asmap files can be generated with Bitcoin Core's `contrib/asmap` tooling.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net;
+use std::path::Path;
+use std::sync::Arc;
+
+use thiserror::Error;
+
+/// Name of the asmap file, by convention.
+pub const ASMAP_FILE: &str = "ip_asn.map";
+
+/// Number of bits of an IP address, as interpreted by an asmap. IPv4 addresses are
+/// mapped to IPv6.
+const ADDR_BITS: usize = 128;
+
+/// Bit sizes of the encoded instruction types.
+const TYPE_BIT_SIZES: &[u8] = &[0, 0, 1];
+/// Bit sizes of the encoded AS numbers.
+const ASN_BIT_SIZES: &[u8] = &[15, 16, 17, 18, 19, 20, 21, 22, 23, 24];
+/// Bit sizes of the encoded match values.
+const MATCH_BIT_SIZES: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+/// Bit sizes of the encoded jump offsets.
+const JUMP_BIT_SIZES: &[u8] = &[
+    5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29,
+    30,
+];
+
+/// An asmap error.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// An I/O error.
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    /// The asmap is malformed.
+    #[error("malformed asmap")]
+    Malformed,
+}
+
+/// An asmap instruction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Instruction {
+    /// Return the given AS number.
+    Return,
+    /// Skip ahead if the next bit of the address is set.
+    Jump,
+    /// Return the default AS number, unless the next bits of the address match.
+    Match,
+    /// Set the default AS number.
+    Default,
+}
+
+/// A bit reader over the asmap program.
+#[derive(Debug, Clone, Copy)]
+struct Cursor<'a> {
+    bits: &'a [u8],
+    pos: usize,
+    end: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(bits: &'a [u8]) -> Self {
+        Self {
+            bits,
+            pos: 0,
+            end: bits.len() * 8,
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.end - self.pos
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.end
+    }
+
+    /// Read the next bit. Bits are read from the least significant bit of each byte.
+    fn bit(&mut self) -> Option<bool> {
+        if self.is_empty() {
+            return None;
+        }
+        let bit = self.bits[self.pos / 8] >> (self.pos % 8) & 1 == 1;
+        self.pos += 1;
+
+        Some(bit)
+    }
+
+    /// Decode a variable-length integer: a unary-encoded size class, followed by the
+    /// value within that class.
+    fn decode(&mut self, min: u32, sizes: &[u8]) -> Option<u32> {
+        let mut val = min;
+
+        for (i, size) in sizes.iter().enumerate() {
+            let last = i + 1 == sizes.len();
+            let bit = if last { false } else { self.bit()? };
+
+            if bit {
+                val += 1 << size;
+            } else {
+                for b in 0..*size {
+                    let bit = self.bit()? as u32;
+                    val += bit << (size - 1 - b);
+                }
+                return Some(val);
+            }
+        }
+        None
+    }
+
+    fn instruction(&mut self) -> Option<Instruction> {
+        match self.decode(0, TYPE_BIT_SIZES)? {
+            0 => Some(Instruction::Return),
+            1 => Some(Instruction::Jump),
+            2 => Some(Instruction::Match),
+            3 => Some(Instruction::Default),
+            _ => None,
+        }
+    }
+
+    fn asn(&mut self) -> Option<u32> {
+        self.decode(1, ASN_BIT_SIZES)
+    }
+
+    fn matches(&mut self) -> Option<u32> {
+        self.decode(2, MATCH_BIT_SIZES)
+    }
+
+    fn jump(&mut self) -> Option<u32> {
+        self.decode(17, JUMP_BIT_SIZES)
+    }
+}
+
+/// An IP to AS number map. Cheap to clone.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Asmap {
+    bits: Arc<[u8]>,
+}
+
+impl fmt::Debug for Asmap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Asmap({} bytes)", self.bits.len())
+    }
+}
+
+impl Asmap {
+    /// Load an asmap from a file.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let bytes = fs::read(path)?;
+
+        Self::from_bytes(&bytes)
+    }
+
+    /// Decode an asmap. The asmap is checked to be well-formed, such that every address
+    /// maps to an AS number.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if !self::is_sane(bytes, ADDR_BITS) {
+            return Err(Error::Malformed);
+        }
+        Ok(Self {
+            bits: Arc::from(bytes),
+        })
+    }
+
+    /// Size of the asmap, in bytes.
+    pub fn len(&self) -> usize {
+        self.bits.len()
+    }
+
+    /// Whether the asmap is empty. Empty asmaps are never well-formed.
+    pub fn is_empty(&self) -> bool {
+        self.bits.is_empty()
+    }
+
+    /// Get the AS number of an IP address. Returns `None` if the address isn't mapped.
+    pub fn asn(&self, ip: &net::IpAddr) -> Option<u32> {
+        let octets = match ip {
+            net::IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+            net::IpAddr::V6(ip) => ip.octets(),
+        };
+        let bit = |i: usize| octets[i / 8] >> (7 - i % 8) & 1 == 1;
+        let mut cursor = Cursor::new(&self.bits);
+        let mut consumed = 0;
+        let mut default = 0;
+
+        // Nb. Since the asmap was checked on construction, running it can't fail.
+        while !cursor.is_empty() {
+            match cursor.instruction()? {
+                Instruction::Return => {
+                    default = cursor.asn()?;
+                    break;
+                }
+                Instruction::Jump => {
+                    let jump = cursor.jump()? as usize;
+
+                    if consumed == ADDR_BITS || jump >= cursor.remaining() {
+                        return None;
+                    }
+                    if bit(consumed) {
+                        cursor.pos += jump;
+                    }
+                    consumed += 1;
+                }
+                Instruction::Match => {
+                    let value = cursor.matches()?;
+                    let len = (u32::BITS - value.leading_zeros() - 1) as usize;
+
+                    if ADDR_BITS - consumed < len {
+                        return None;
+                    }
+                    for i in 0..len {
+                        if bit(consumed) != (value >> (len - 1 - i) & 1 == 1) {
+                            return Some(default).filter(|asn| *asn != 0);
+                        }
+                        consumed += 1;
+                    }
+                }
+                Instruction::Default => {
+                    default = cursor.asn()?;
+                }
+            }
+        }
+        Some(default).filter(|asn| *asn != 0)
+    }
+}
+
+/// Check that an asmap is well-formed for addresses of the given number of bits: that
+/// running it always ends on a `Return` instruction, without reading past its end, and
+/// that it has no unreachable code or excessive padding.
+fn is_sane(bytes: &[u8], mut bits: usize) -> bool {
+    let mut cursor = Cursor::new(bytes);
+    // Positions we may jump to, and the number of address bits left after jumping.
+    let mut jumps: Vec<(usize, usize)> = Vec::with_capacity(bits);
+    let mut prev = Instruction::Jump;
+    let mut had_incomplete_match = false;
+
+    while !cursor.is_empty() {
+        if jumps.last().is_some_and(|(to, _)| cursor.pos >= *to) {
+            // Jumping into the middle of the previous instruction.
+            return false;
+        }
+        let Some(instruction) = cursor.instruction() else {
+            return false;
+        };
+        match instruction {
+            Instruction::Return => {
+                if prev == Instruction::Default || cursor.asn().is_none() {
+                    return false;
+                }
+                match jumps.pop() {
+                    None => {
+                        // Nothing left to run: only zero padding may follow.
+                        return cursor.remaining() <= 7
+                            && std::iter::from_fn(|| cursor.bit()).all(|b| !b);
+                    }
+                    Some((to, left)) => {
+                        // Continue as if we jumped to the next instruction.
+                        if cursor.pos != to {
+                            return false;
+                        }
+                        bits = left;
+                        prev = Instruction::Jump;
+                    }
+                }
+            }
+            Instruction::Jump => {
+                let Some(jump) = cursor.jump() else {
+                    return false;
+                };
+                let jump = jump as usize;
+
+                if jump > cursor.remaining() || bits == 0 {
+                    return false;
+                }
+                bits -= 1;
+
+                let to = cursor.pos + jump;
+                if jumps.last().is_some_and(|(last, _)| to >= *last) {
+                    // Intersecting jumps.
+                    return false;
+                }
+                jumps.push((to, bits));
+                prev = Instruction::Jump;
+            }
+            Instruction::Match => {
+                let Some(value) = cursor.matches() else {
+                    return false;
+                };
+                let len = (u32::BITS - value.leading_zeros() - 1) as usize;
+
+                if prev != Instruction::Match {
+                    had_incomplete_match = false;
+                }
+                // Within a sequence of matches, at most one may be incomplete.
+                if len < 8 && had_incomplete_match {
+                    return false;
+                }
+                had_incomplete_match = len < 8;
+
+                if bits < len {
+                    return false;
+                }
+                bits -= len;
+                prev = Instruction::Match;
+            }
+            Instruction::Default => {
+                if prev == Instruction::Default || cursor.asn().is_none() {
+                    return false;
+                }
+                prev = Instruction::Default;
+            }
+        }
+    }
+    // Reached the end without a `Return`.
+    false
+}
+
+/// Asmap test utilities.
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// Encode an asmap program, for testing.
+    #[derive(Debug, Default)]
+    pub struct Encoder {
+        bits: Vec<bool>,
+    }
+
+    impl Encoder {
+        fn encode(&mut self, val: u32, min: u32, sizes: &[u8]) {
+            let mut val = val - min;
+
+            for (i, size) in sizes.iter().enumerate() {
+                let last = i + 1 == sizes.len();
+
+                if val >= 1 << size {
+                    assert!(!last, "value out of range");
+                    self.bits.push(true);
+                    val -= 1 << size;
+                } else {
+                    if !last {
+                        self.bits.push(false);
+                    }
+                    for b in 0..*size {
+                        self.bits.push(val >> (size - 1 - b) & 1 == 1);
+                    }
+                    return;
+                }
+            }
+        }
+
+        /// Number of bits encoded so far.
+        pub fn size(&self) -> usize {
+            self.bits.len()
+        }
+
+        /// Return the given AS number.
+        pub fn ret(mut self, asn: u32) -> Self {
+            self.encode(0, 0, TYPE_BIT_SIZES);
+            self.encode(asn, 1, ASN_BIT_SIZES);
+            self
+        }
+
+        /// Skip the given number of bits if the next address bit is set.
+        pub fn jump(mut self, offset: u32) -> Self {
+            self.encode(1, 0, TYPE_BIT_SIZES);
+            self.encode(offset, 17, JUMP_BIT_SIZES);
+            self
+        }
+
+        /// Match the next address bits against the given byte.
+        pub fn byte(mut self, byte: u8) -> Self {
+            self.encode(2, 0, TYPE_BIT_SIZES);
+            self.encode(0x100 | byte as u32, 2, MATCH_BIT_SIZES);
+            self
+        }
+
+        /// Set the default AS number.
+        pub fn fallback(mut self, asn: u32) -> Self {
+            self.encode(3, 0, TYPE_BIT_SIZES);
+            self.encode(asn, 1, ASN_BIT_SIZES);
+            self
+        }
+
+        /// Match the IPv4-mapped IPv6 prefix, ie. `::ffff:0:0/96`.
+        pub fn ipv4(self) -> Self {
+            (0..10).fold(self, |e, _| e.byte(0)).byte(0xff).byte(0xff)
+        }
+
+        /// Get the encoded bytes.
+        pub fn finish(self) -> Vec<u8> {
+            let mut bytes = vec![0; self.bits.len().div_ceil(8)];
+
+            for (i, bit) in self.bits.into_iter().enumerate() {
+                bytes[i / 8] |= (bit as u8) << (i % 8);
+            }
+            bytes
+        }
+    }
+
+    /// An asmap mapping IPv4 addresses below `128.0.0.0` to `low`, and the others to
+    /// `high`. IPv6 addresses aren't mapped.
+    pub fn asmap(low: u32, high: u32) -> Asmap {
+        let ret = Encoder::default().ret(low);
+        let bytes = Encoder::default()
+            .ipv4()
+            .jump(ret.size() as u32)
+            .ret(low)
+            .ret(high)
+            .finish();
+
+        Asmap::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_asn() {
+        let asmap = asmap(64496, 64511);
+
+        assert_eq!(asmap.asn(&[1, 1, 1, 1].into()), Some(64496));
+        assert_eq!(asmap.asn(&[127, 255, 255, 255].into()), Some(64496));
+        assert_eq!(asmap.asn(&[128, 0, 0, 0].into()), Some(64511));
+        assert_eq!(asmap.asn(&[222, 1, 1, 1].into()), Some(64511));
+        assert_eq!(asmap.asn(&"2001:db8::1".parse().unwrap()), None);
+        assert_eq!(
+            asmap.asn(&"::ffff:222.1.1.1".parse().unwrap()),
+            Some(64511),
+            "mapped addresses are treated as IPv4"
+        );
+    }
+
+    #[test]
+    fn test_default() {
+        let bytes = Encoder::default().fallback(7).byte(0x20).ret(9).finish();
+        let asmap = Asmap::from_bytes(&bytes).unwrap();
+
+        assert_eq!(asmap.asn(&"2001:db8::1".parse().unwrap()), Some(9));
+        assert_eq!(asmap.asn(&"3001:db8::1".parse().unwrap()), Some(7));
+        assert_eq!(asmap.asn(&[1, 1, 1, 1].into()), Some(7));
+    }
+
+    #[test]
+    fn test_malformed() {
+        // Empty.
+        assert!(Asmap::from_bytes(&[]).is_err());
+        // No `Return` instruction.
+        assert!(Asmap::from_bytes(&Encoder::default().ipv4().finish()).is_err());
+        // Successive `Default` instructions.
+        assert!(
+            Asmap::from_bytes(&Encoder::default().fallback(1).fallback(2).ret(3).finish()).is_err()
+        );
+        // Jump past the end.
+        assert!(Asmap::from_bytes(&Encoder::default().jump(1024).ret(1).finish()).is_err());
+        // Unreachable code.
+        assert!(Asmap::from_bytes(&Encoder::default().ret(1).ret(2).finish()).is_err());
+        // Trailing garbage.
+        let mut bytes = Encoder::default().ret(1).finish();
+        bytes.push(0xff);
+        assert!(Asmap::from_bytes(&bytes).is_err());
+        // Truncated.
+        let bytes = test::asmap(1, 2).bits.to_vec();
+        assert!(Asmap::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+//! BIP 37 bloom filters.
+//!
+//! Bloom filters are loaded on a peer with `filterload`, after which the peer only announces
+//! transactions matching the filter, eg. when responding to a `mempool` request. Since the
+//! filter reveals the scripts we're interested in, it should only be loaded on a trusted peer.
+use std::f64::consts::LN_2;
+
+use nakamoto_common::bitcoin::blockdata::script::{Instruction, Script};
+use nakamoto_common::bitcoin::network::message_bloom::{BloomFlags, FilterLoad};
+
+/// Maximum size of a filter, in bytes.
+pub const MAX_FILTER_SIZE: usize = 36_000;
+/// Maximum number of hash functions used by a filter.
+pub const MAX_HASH_FUNCS: u32 = 50;
+
+/// Multiplier used to derive the seed of each hash function.
+const SEED_MULTIPLIER: u32 = 0xfba4c795;
+
+/// A BIP 37 bloom filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    data: Vec<u8>,
+    hash_funcs: u32,
+    tweak: u32,
+}
+
+impl BloomFilter {
+    /// Create a filter sized for the given number of elements and false positive rate.
+    pub fn new(elements: usize, fp_rate: f64, tweak: u32) -> Self {
+        let elements = elements.max(1);
+        let size = (-1. / LN_2.powi(2) * elements as f64 * fp_rate.ln() / 8.) as usize;
+        let size = size.clamp(1, MAX_FILTER_SIZE);
+        let hash_funcs = ((size * 8 / elements) as f64 * LN_2) as u32;
+
+        Self {
+            data: vec![0; size],
+            hash_funcs: hash_funcs.clamp(1, MAX_HASH_FUNCS),
+            tweak,
+        }
+    }
+
+    /// Create a filter matching transactions with outputs paying to any of the given scripts.
+    ///
+    /// Peers match the data pushes of output scripts against the filter, so the pushes of
+    /// each script are inserted, eg. the public key hash of a P2WPKH script.
+    pub fn for_scripts<'a>(
+        scripts: impl IntoIterator<Item = &'a Script>,
+        fp_rate: f64,
+        tweak: u32,
+    ) -> Self {
+        let pushes = scripts
+            .into_iter()
+            .flat_map(|s| s.instructions().flatten())
+            .filter_map(|i| match i {
+                Instruction::PushBytes(bytes) if !bytes.is_empty() => Some(bytes),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let mut filter = Self::new(pushes.len(), fp_rate, tweak);
+
+        for push in pushes {
+            filter.insert(push);
+        }
+        filter
+    }
+
+    /// Insert an element into the filter.
+    pub fn insert(&mut self, element: &[u8]) {
+        for n in 0..self.hash_funcs {
+            let bit = self.bit(n, element);
+            self.data[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Check whether the filter may contain the element.
+    pub fn contains(&self, element: &[u8]) -> bool {
+        (0..self.hash_funcs).all(|n| {
+            let bit = self.bit(n, element);
+            self.data[bit / 8] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    /// Index of the bit set by the given hash function for an element.
+    fn bit(&self, n: u32, element: &[u8]) -> usize {
+        let seed = n.wrapping_mul(SEED_MULTIPLIER).wrapping_add(self.tweak);
+        murmur3(seed, element) as usize % (self.data.len() * 8)
+    }
+}
+
+impl From<BloomFilter> for FilterLoad {
+    fn from(filter: BloomFilter) -> Self {
+        FilterLoad {
+            filter: filter.data,
+            hash_funcs: filter.hash_funcs,
+            tweak: filter.tweak,
+            // We're only interested in unconfirmed payments, so the filter needn't be
+            // updated with the outpoints it matches.
+            flags: BloomFlags::None,
+        }
+    }
+}
+
+/// 32-bit MurmurHash3, as used by BIP 37.
+fn murmur3(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+
+    let mut h = seed;
+    let mut chunks = data.chunks_exact(4);
+
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h ^= k;
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
+    }
+
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        let mut k = 0u32;
+        for (i, byte) in tail.iter().enumerate() {
+            k ^= (*byte as u32) << (8 * i);
+        }
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2ae35);
+    h ^= h >> 16;
+    h
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use nakamoto_common::bitcoin::blockdata::script::Builder;
+    use nakamoto_common::bitcoin::consensus::serialize;
+    use nakamoto_common::bitcoin_hashes::hex::{FromHex, ToHex};
+
+    #[test]
+    fn test_murmur3() {
+        // Test vectors from Bitcoin Core.
+        assert_eq!(murmur3(0x00000000, &[]), 0x00000000);
+        assert_eq!(murmur3(0xfba4c795, &[]), 0x6a396f08);
+        assert_eq!(murmur3(0xffffffff, &[]), 0x81f16f39);
+        assert_eq!(murmur3(0x00000000, &[0x00]), 0x514e28b7);
+        assert_eq!(murmur3(0xfba4c795, &[0x00]), 0xea3f0b17);
+        assert_eq!(murmur3(0x00000000, &[0xff]), 0xfd6cf10d);
+        assert_eq!(murmur3(0x00000000, &[0x00, 0x11]), 0x16c6b7ab);
+        assert_eq!(murmur3(0x00000000, &[0x00, 0x11, 0x22]), 0x8eb51c3d);
+        assert_eq!(murmur3(0x00000000, &[0x00, 0x11, 0x22, 0x33]), 0xb4471bf8);
+        assert_eq!(
+            murmur3(0x00000000, &[0x00, 0x11, 0x22, 0x33, 0x44]),
+            0xe2301fa8
+        );
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        // Test vector from Bitcoin Core.
+        let mut filter = BloomFilter::new(3, 0.01, 0);
+        let elements = [
+            "99108ad8ed9bb6274d3980bab5a85c048f0950c8",
+            "b5a2c786d9ef4658287ced5914b37a1b4aa32eee",
+            "b9300670b4c5366e95b2699e8b18bc75e5f729c5",
+        ]
+        .map(|e| Vec::<u8>::from_hex(e).unwrap());
+
+        for element in &elements {
+            assert!(!filter.contains(element));
+            filter.insert(element);
+            assert!(filter.contains(element));
+        }
+        assert!(
+            !filter.contains(&Vec::from_hex("19108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap())
+        );
+
+        let mut load = FilterLoad::from(filter);
+        load.flags = BloomFlags::All;
+        assert_eq!(serialize(&load).to_hex(), "03614e9b050000000000000001");
+    }
+
+    #[test]
+    fn test_for_scripts() {
+        let pkh = Vec::<u8>::from_hex("99108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap();
+        let script = Builder::new().push_int(0).push_slice(&pkh).into_script();
+        let filter = BloomFilter::for_scripts([&script], 0.0001, 7);
+
+        assert!(filter.contains(&pkh));
+        assert!(!filter.contains(&[0; 20]));
+    }
+}
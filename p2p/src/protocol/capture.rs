@@ -0,0 +1,274 @@
+//! Capture of raw peer-to-peer messages, for debugging.
+//!
+//! When capture is enabled, every message sent to or received from a peer is recorded,
+//! one JSON object per line, with the time it was captured, the peer address, the
+//! direction, and the hex-encoded message, including its header. Captures can be read
+//! back with [`read`], so that protocol bugs can be reproduced from them.
+//!
+//! Messages that can't be decoded are captured too, since they are often the ones worth
+//! looking at: the record holds the bytes that couldn't be decoded, and the peer is
+//! disconnected right after.
+//!
+//! Records are written out on a background thread, so that a slow disk doesn't stall the
+//! protocol. If the writer falls more than [`QUEUE_SIZE`] records behind, further records
+//! are dropped until it catches up.
+use std::fmt;
+use std::io::{self, BufRead};
+use std::thread;
+
+use crossbeam_channel as chan;
+
+use microserde as serde;
+use microserde::json::{Number, Object, Value};
+
+use nakamoto_common::bitcoin::consensus::encode;
+use nakamoto_common::bitcoin::network::message::RawNetworkMessage;
+use nakamoto_common::bitcoin_hashes::hex::{FromHex, ToHex};
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+
+use super::PeerId;
+
+/// Maximum number of records waiting to be written.
+pub const QUEUE_SIZE: usize = 1024;
+
+/// Direction of a captured message.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    /// Received from the peer.
+    Inbound,
+    /// Sent to the peer.
+    Outbound,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Inbound => write!(f, "in"),
+            Self::Outbound => write!(f, "out"),
+        }
+    }
+}
+
+/// A captured message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Time at which the message was captured.
+    pub time: LocalTime,
+    /// Peer the message was exchanged with.
+    pub peer: PeerId,
+    /// Message direction.
+    pub direction: Direction,
+    /// Raw message, including its header.
+    pub message: Vec<u8>,
+}
+
+impl Record {
+    /// Decode the captured message.
+    pub fn decode(&self) -> Result<RawNetworkMessage, encode::Error> {
+        encode::deserialize(&self.message)
+    }
+
+    /// Message command, as found in the message header.
+    pub fn command(&self) -> String {
+        self.message
+            .get(4..16)
+            .unwrap_or_default()
+            .iter()
+            .take_while(|b| **b != 0)
+            .map(|b| *b as char)
+            .collect()
+    }
+
+    /// Convert the record to JSON.
+    pub fn to_json(&self) -> Value {
+        let millis = (self.time - LocalTime::default()).as_millis() as u64;
+        let mut obj = Object::new();
+
+        obj.insert("time".to_owned(), Value::Number(Number::U64(millis)));
+        obj.insert("peer".to_owned(), Value::String(self.peer.to_string()));
+        obj.insert(
+            "direction".to_owned(),
+            Value::String(self.direction.to_string()),
+        );
+        obj.insert("command".to_owned(), Value::String(self.command()));
+        obj.insert("message".to_owned(), Value::String(self.message.to_hex()));
+
+        Value::Object(obj)
+    }
+
+    /// Convert JSON to a record.
+    pub fn from_json(v: Value) -> Result<Self, serde::Error> {
+        let obj = match v {
+            Value::Object(obj) => obj,
+            _ => return Err(serde::Error),
+        };
+        let time = match obj.get("time") {
+            Some(Value::Number(Number::U64(n))) => {
+                LocalTime::default() + LocalDuration::from_millis(*n as u128)
+            }
+            _ => return Err(serde::Error),
+        };
+        let peer = match obj.get("peer") {
+            Some(Value::String(s)) => s.parse().map_err(|_| serde::Error)?,
+            _ => return Err(serde::Error),
+        };
+        let direction = match obj.get("direction").and_then(|v| match v {
+            Value::String(s) => Some(s.as_str()),
+            _ => None,
+        }) {
+            Some("in") => Direction::Inbound,
+            Some("out") => Direction::Outbound,
+            _ => return Err(serde::Error),
+        };
+        let message = match obj.get("message") {
+            Some(Value::String(s)) => Vec::from_hex(s).map_err(|_| serde::Error)?,
+            _ => return Err(serde::Error),
+        };
+
+        Ok(Self {
+            time,
+            peer,
+            direction,
+            message,
+        })
+    }
+}
+
+/// Input of the capture writer thread.
+enum Op {
+    /// Write a record.
+    Write(String),
+    /// Reply once the records before it are written.
+    Flush(chan::Sender<()>),
+}
+
+/// Records messages to a writer, on a background thread. Clones share the same writer.
+/// The thread exits once all clones are dropped and the pending records are written.
+#[derive(Clone)]
+pub struct Capture {
+    ops: chan::Sender<Op>,
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Capture").finish_non_exhaustive()
+    }
+}
+
+impl Capture {
+    /// Create a new capture, recording messages to the given writer.
+    pub fn new(mut writer: impl io::Write + Send + 'static) -> Self {
+        let (ops, receiver) = chan::bounded::<Op>(QUEUE_SIZE);
+
+        thread::spawn(move || {
+            for op in receiver {
+                let result = match op {
+                    Op::Write(line) => writer.write_all(line.as_bytes()),
+                    Op::Flush(reply) => writer.flush().map(|()| {
+                        reply.send(()).ok();
+                    }),
+                };
+                if let Err(err) = result {
+                    log::error!("Failed to write captured message: {}", err);
+                    return;
+                }
+            }
+            writer.flush().ok();
+        });
+
+        Self { ops }
+    }
+
+    /// Record a message. Fails if the writer failed, in which case nothing more can be
+    /// recorded.
+    pub fn record(&mut self, record: &Record) -> io::Result<()> {
+        let line = serde::json::to_string(&record.to_json()) + "\n";
+
+        match self.ops.try_send(Op::Write(line)) {
+            Ok(()) => Ok(()),
+            Err(chan::TrySendError::Full(_)) => {
+                log::warn!(
+                    "Capture queue is full, dropping {} message",
+                    record.command()
+                );
+                Ok(())
+            }
+            Err(chan::TrySendError::Disconnected(_)) => {
+                Err(io::Error::other("capture writer has stopped"))
+            }
+        }
+    }
+
+    /// Wait for the messages recorded so far to be written out, and flush the writer.
+    pub fn flush(&self) -> io::Result<()> {
+        let (reply, done) = chan::bounded(1);
+        let stopped = || io::Error::other("capture writer has stopped");
+
+        self.ops.send(Op::Flush(reply)).map_err(|_| stopped())?;
+        done.recv().map_err(|_| stopped())
+    }
+}
+
+/// Read captured messages.
+pub fn read<R: BufRead>(reader: R) -> impl Iterator<Item = io::Result<Record>> {
+    reader.lines().map(|line| {
+        let value = serde::json::from_str(&line?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Record::from_json(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use nakamoto_common::bitcoin::network::message::NetworkMessage;
+
+    /// A writer that can be inspected after being moved into a capture.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_capture() {
+        let msg = RawNetworkMessage {
+            magic: 0xd9b4bef9,
+            payload: NetworkMessage::Ping(42),
+        };
+        let record = Record {
+            time: LocalTime::from_secs(1_600_000_000) + LocalDuration::from_millis(7),
+            peer: ([88, 88, 88, 1], 8333).into(),
+            direction: Direction::Inbound,
+            message: encode::serialize(&msg),
+        };
+        assert_eq!(record.command(), "ping");
+
+        let output = Shared::default();
+        let mut capture = Capture::new(output.clone());
+
+        capture.record(&record).unwrap();
+        capture.record(&record).unwrap();
+        capture.flush().unwrap();
+
+        let bytes = output.0.lock().unwrap().clone();
+        let records = read(bytes.as_slice())
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(records, vec![record.clone(), record]);
+        assert_eq!(records[0].decode().unwrap(), msg);
+        assert!(read("{}".as_bytes()).next().unwrap().is_err());
+    }
+}
@@ -4,25 +4,32 @@
 //!
 mod rescan;
 
+use std::collections::BTreeMap;
 use std::ops::{Bound, RangeInclusive};
 
 use thiserror::Error;
 
+use microserde::json::Value;
+
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
-use nakamoto_common::bitcoin::network::message_filter::{CFHeaders, CFilter, GetCFHeaders};
+use nakamoto_common::bitcoin::network::message::NetworkMessage;
+use nakamoto_common::bitcoin::network::message_filter::{
+    CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, GetCFHeaders, GetCFilters,
+};
 
 use nakamoto_common::bitcoin::{Script, Transaction, Txid};
 
-use nakamoto_common::block::filter::{self, BlockFilter, Filters};
+use nakamoto_common::block::filter::{self, BlockFilter, FilterHash, FilterHeader, Filters};
 use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
 use nakamoto_common::block::tree::BlockReader;
-use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::block::{Anchor, BlockHash, Height};
 use nakamoto_common::collections::{AddressBook, HashMap};
+use nakamoto_common::error::Classify;
 use nakamoto_common::source;
 
 use super::filter_cache::FilterCache;
 use super::output::{Disconnect, Wakeup};
-use super::{DisconnectReason, Link, PeerId, Socket};
+use super::{dump, DisconnectReason, Link, PeerId, Socket};
 
 use rescan::Rescan;
 
@@ -38,12 +45,21 @@ pub const MAX_MESSAGE_CFHEADERS: usize = 2000;
 /// Maximum filters to be expected in a message.
 pub const MAX_MESSAGE_CFILTERS: usize = 1000;
 
+/// Interval between filter headers in a `cfcheckpt` message.
+pub const CFCHECKPT_INTERVAL: Height = 1000;
+
+/// How long peers serving filter headers that don't match our checkpoints are banned for.
+pub const CHECKPOINT_BAN_DURATION: LocalDuration = LocalDuration::from_mins(60 * 24);
+
 /// Filter cache capacity in bytes.
 pub const DEFAULT_FILTER_CACHE_SIZE: usize = 1024 * 1024; // 1 MB.
 
 /// How long to wait to receive a reply from a peer.
 pub const DEFAULT_REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(6);
 
+/// Identifies a rescan. Chosen by the caller when starting a rescan.
+pub type RescanId = u64;
+
 /// An error originating in the CBF manager.
 #[derive(Error, Debug)]
 pub enum Error {
@@ -63,6 +79,14 @@ pub enum Error {
         /// Reason why the message is invalid.
         reason: &'static str,
     },
+    /// A filter header received from a peer doesn't match our checkpoint.
+    #[error("filter header at height {height} from {from} doesn't match our checkpoint")]
+    InvalidCheckpoint {
+        /// Message sender.
+        from: PeerId,
+        /// Height of the mismatching filter header.
+        height: Height,
+    },
     /// Error with the underlying filters datastore.
     #[error("filters error: {0}")]
     Filters(#[from] filter::Error),
@@ -84,10 +108,8 @@ pub enum Event {
     },
     /// Filter was processed.
     FilterProcessed {
-        /// The corresponding block hash.
-        block: BlockHash,
-        /// The filter height.
-        height: Height,
+        /// The corresponding block. The filter height is the block height.
+        block: Anchor,
         /// Whether or not this filter matched something in the watchlist.
         matched: bool,
         /// Whether or not this filter was valid.
@@ -120,13 +142,38 @@ pub enum Event {
     },
     /// A rescan has started.
     RescanStarted {
+        /// Rescan identifier.
+        id: RescanId,
         /// Start height.
         start: Height,
         /// End height.
         end: Option<Height>,
     },
+    /// An active rescan was paused.
+    RescanPaused {
+        /// Rescan identifier.
+        id: RescanId,
+        /// Height from which the rescan will resume.
+        height: Height,
+    },
+    /// A paused rescan was resumed.
+    RescanResumed {
+        /// Rescan identifier.
+        id: RescanId,
+        /// Height from which the rescan resumed.
+        height: Height,
+    },
+    /// A rescan was canceled before completing.
+    RescanCanceled {
+        /// Rescan identifier.
+        id: RescanId,
+        /// Height up to which filters were processed.
+        height: Height,
+    },
     /// An active rescan has completed.
     RescanCompleted {
+        /// Rescan identifier.
+        id: RescanId,
         /// Last height processed by rescan.
         height: Height,
     },
@@ -157,7 +204,7 @@ impl std::fmt::Display for Event {
                 )
             }
             Event::FilterProcessed {
-                height,
+                block,
                 matched,
                 valid,
                 ..
@@ -165,7 +212,7 @@ impl std::fmt::Display for Event {
                 write!(
                     fmt,
                     "Filter processed at height {} (match = {}, valid = {})",
-                    height, matched, valid
+                    block.height, matched, valid
                 )
             }
             Event::FilterHeadersImported { count, height, .. } => {
@@ -188,16 +235,34 @@ impl std::fmt::Display for Event {
                 peer, start_height, stop_hash
             ),
             Event::RescanStarted {
+                id,
                 start,
                 end: Some(end),
             } => {
-                write!(fmt, "Rescan started from height {} to {}", start, end)
+                write!(
+                    fmt,
+                    "Rescan {} started from height {} to {}",
+                    id, start, end
+                )
+            }
+            Event::RescanStarted {
+                id,
+                start,
+                end: None,
+            } => {
+                write!(fmt, "Rescan {} started from height {}", id, start)
+            }
+            Event::RescanPaused { id, height } => {
+                write!(fmt, "Rescan {} paused at height {}", id, height)
             }
-            Event::RescanStarted { start, end: None } => {
-                write!(fmt, "Rescan started from height {}", start)
+            Event::RescanResumed { id, height } => {
+                write!(fmt, "Rescan {} resumed from height {}", id, height)
             }
-            Event::RescanCompleted { height } => {
-                write!(fmt, "Rescan completed at height {}", height)
+            Event::RescanCanceled { id, height } => {
+                write!(fmt, "Rescan {} canceled at height {}", id, height)
+            }
+            Event::RescanCompleted { id, height } => {
+                write!(fmt, "Rescan {} completed at height {}", id, height)
             }
             Event::RequestCanceled { reason } => {
                 write!(fmt, "Request canceled: {}", reason)
@@ -236,6 +301,10 @@ pub trait SyncFilters {
     fn send_cfheaders(&mut self, addr: PeerId, headers: CFHeaders);
     /// Send a compact filter to a peer.
     fn send_cfilter(&mut self, addr: PeerId, filter: CFilter);
+    /// Get compact filter header checkpoints from a peer, up to the stop hash.
+    fn get_cfcheckpt(&mut self, addr: PeerId, stop_hash: BlockHash, timeout: LocalDuration);
+    /// Send compact filter header checkpoints to a peer.
+    fn send_cfcheckpt(&mut self, addr: PeerId, checkpt: CFCheckpt);
 }
 
 /// The ability to emit CBF related events.
@@ -253,6 +322,54 @@ pub enum GetFiltersError {
     /// Not connected to any compact filter peer.
     #[error("not connected to any peer with compact filters support")]
     NotConnected,
+    /// Filter sync is disabled.
+    #[error("compact filters are disabled")]
+    Disabled,
+    /// Filter downloads are deferred, eg. because we're on a metered network.
+    #[error("compact filter downloads are deferred")]
+    Deferred,
+}
+
+impl Classify for GetFiltersError {
+    fn code(&self) -> u32 {
+        match self {
+            Self::InvalidRange => 3012,
+            Self::NotConnected => 1006,
+            Self::Deferred => 1004,
+            Self::Disabled => 4013,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        matches!(self, Self::NotConnected | Self::Deferred)
+    }
+}
+
+/// An error from attempting to import filter headers.
+#[derive(Error, Debug)]
+pub enum ImportFilterHeadersError {
+    /// The filter header doesn't connect to, or conflicts with our filter header chain.
+    #[error("invalid filter header at height {0}")]
+    InvalidHeader(Height),
+    /// Error with the underlying filters datastore.
+    #[error("filters error: {0}")]
+    Filters(#[from] filter::Error),
+}
+
+impl Classify for ImportFilterHeadersError {
+    fn code(&self) -> u32 {
+        match self {
+            Self::InvalidHeader(_) => 3013,
+            Self::Filters(err) => err.code(),
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::InvalidHeader(_) => false,
+            Self::Filters(err) => err.is_transient(),
+        }
+    }
 }
 
 /// CBF manager configuration.
@@ -262,6 +379,13 @@ pub struct Config {
     pub request_timeout: LocalDuration,
     /// Filter cache size, in bytes.
     pub filter_cache_size: usize,
+    /// Serve filter headers and filters to peers.
+    pub serve: bool,
+    /// Sync filter headers and filters from peers. When disabled, filters can't be
+    /// requested, and rescans don't make progress.
+    pub sync: bool,
+    /// Known filter headers, by height. Peers whose filter headers don't match are banned.
+    pub checkpoints: BTreeMap<Height, FilterHeader>,
 }
 
 impl Default for Config {
@@ -269,6 +393,9 @@ impl Default for Config {
         Self {
             request_timeout: DEFAULT_REQUEST_TIMEOUT,
             filter_cache_size: DEFAULT_FILTER_CACHE_SIZE,
+            serve: false,
+            sync: true,
+            checkpoints: BTreeMap::new(),
         }
     }
 }
@@ -276,10 +403,13 @@ impl Default for Config {
 /// A CBF peer.
 #[derive(Debug)]
 struct Peer {
-    #[allow(dead_code)]
     height: Height,
-    #[allow(dead_code)]
     last_active: LocalTime,
+    /// Height up to which the peer's filter headers were verified against our checkpoints.
+    verified: Height,
+    /// Stop hash and expiry of our pending `getcfcheckpt` request. We don't sync filter
+    /// headers from a peer while its checkpoints are being verified.
+    verifying: Option<(BlockHash, LocalTime)>,
     #[allow(dead_code)]
     socket: Socket,
 }
@@ -303,6 +433,8 @@ pub struct FilterManager<F, U, C> {
     last_processed: Option<LocalTime>,
     /// Inflight requests.
     inflight: HashMap<BlockHash, (Height, PeerId, LocalTime)>,
+    /// Whether filter header and filter downloads are deferred.
+    deferred: bool,
 }
 
 impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> FilterManager<F, U, C> {
@@ -321,6 +453,7 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
             inflight: HashMap::with_hasher(rng.into()),
             last_idle: None,
             last_processed: None,
+            deferred: false,
         }
     }
 
@@ -342,7 +475,10 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
             if now >= *expiry {
                 let (start_height, stop_hash) = (*start_height, *stop_hash);
 
-                if let Some((peer, _)) = self.peers.sample_with(|p, _| p != addr) {
+                if let Some((peer, _)) = self
+                    .peers
+                    .sample_with(|p, peer| p != addr && peer.verifying.is_none())
+                {
                     let peer = *peer;
 
                     self.peers.remove(addr);
@@ -357,6 +493,20 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
             }
         }
 
+        // Disconnect peers that didn't send us the checkpoints we asked for.
+        let expired = self
+            .peers
+            .iter()
+            .filter(|(_, p)| p.verifying.is_some_and(|(_, expiry)| now >= expiry))
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+
+        for addr in expired {
+            self.peers.remove(&addr);
+            self.upstream
+                .disconnect(addr, DisconnectReason::PeerTimeout("getcfcheckpt"));
+        }
+
         // If we've waited too long since the last processed filter, re-issue requests
         // for missing filters.
         if now - self.last_processed.unwrap_or_default() >= DEFAULT_REQUEST_TIMEOUT {
@@ -426,6 +576,21 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
         self.rescan.watch.extend(scripts);
     }
 
+    /// Scripts being watched.
+    pub fn watched(&self) -> impl Iterator<Item = &Script> {
+        self.rescan.watch.iter()
+    }
+
+    /// Check whether we're watching new blocks for matches, and the filter of the block at
+    /// the given height is the next one to be processed.
+    pub fn is_watching(&self, height: Height) -> bool {
+        let rescan = &self.rescan;
+
+        rescan.active
+            && rescan.current == height
+            && !(rescan.watch.is_empty() && rescan.transactions.is_empty())
+    }
+
     /// Add transaction outputs to list of transactions to watch.
     pub fn watch_transaction(&mut self, tx: &Transaction) {
         self.rescan.transactions.insert(
@@ -439,15 +604,17 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
         self.rescan.transactions.remove(txid).is_some()
     }
 
-    /// Rescan compact block filters.
+    /// Rescan compact block filters. Replaces any existing rescan.
     pub fn rescan<T: BlockReader>(
         &mut self,
+        id: RescanId,
         start: Bound<Height>,
         end: Bound<Height>,
         watch: Vec<Script>,
         tree: &T,
     ) -> Vec<(Height, BlockHash)> {
         self.rescan.restart(
+            id,
             match start {
                 Bound::Unbounded => tree.height() + 1,
                 Bound::Included(h) => h,
@@ -462,6 +629,7 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
         );
 
         self.upstream.event(Event::RescanStarted {
+            id,
             start: self.rescan.start,
             end: self.rescan.end,
         });
@@ -469,9 +637,66 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
         if self.rescan.watch.is_empty() {
             return vec![];
         }
+        self.continue_rescan(tree)
+    }
+
+    /// Pause the rescan with the given id. Filters that were requested but not yet
+    /// processed are discarded, and re-requested when the rescan is resumed.
+    pub fn pause_rescan(&mut self, id: RescanId) -> bool {
+        if !self.rescan.active || self.rescan.id != id {
+            return false;
+        }
+        self.rescan.active = false;
+        self.rescan.paused = true;
+        self.rescan.reset();
+
+        self.upstream.event(Event::RescanPaused {
+            id,
+            height: self.rescan.current,
+        });
+        true
+    }
+
+    /// Resume the paused rescan with the given id.
+    pub fn resume_rescan<T: BlockReader>(
+        &mut self,
+        id: RescanId,
+        tree: &T,
+    ) -> Option<Vec<(Height, BlockHash)>> {
+        if !self.rescan.paused || self.rescan.id != id {
+            return None;
+        }
+        self.rescan.active = true;
+        self.rescan.paused = false;
+
+        self.upstream.event(Event::RescanResumed {
+            id,
+            height: self.rescan.current,
+        });
+        Some(self.continue_rescan(tree))
+    }
+
+    /// Cancel the active or paused rescan with the given id.
+    pub fn cancel_rescan(&mut self, id: RescanId) -> bool {
+        if !(self.rescan.active || self.rescan.paused) || self.rescan.id != id {
+            return false;
+        }
+        self.rescan.active = false;
+        self.rescan.paused = false;
+        self.rescan.reset();
 
+        self.upstream.event(Event::RescanCanceled {
+            id,
+            height: self.rescan.current,
+        });
+        true
+    }
+
+    /// Fetch the filters needed to continue the active rescan, and process the ones we
+    /// already have.
+    fn continue_rescan<T: BlockReader>(&mut self, tree: &T) -> Vec<(Height, BlockHash)> {
         let height = self.filters.height();
-        let start = self.rescan.start;
+        let start = self.rescan.current;
         let stop = self
             .rescan
             .end
@@ -487,7 +712,11 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
         // Start fetching the filters we can.
         match self.get_cfilters(range, tree) {
             Ok(()) => {}
-            Err(GetFiltersError::NotConnected) => {}
+            Err(
+                GetFiltersError::NotConnected
+                | GetFiltersError::Disabled
+                | GetFiltersError::Deferred,
+            ) => {}
             Err(err) => panic!("{}: Error fetching filters: {}", source!(), err),
         }
         // When we reset the rescan range, there is the possibility of getting immediate cache
@@ -508,6 +737,12 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
         range: RangeInclusive<Height>,
         tree: &T,
     ) -> Result<(), GetFiltersError> {
+        if !self.config.sync {
+            return Err(GetFiltersError::Disabled);
+        }
+        if self.deferred {
+            return Err(GetFiltersError::Deferred);
+        }
         if self.peers.is_empty() {
             return Err(GetFiltersError::NotConnected);
         }
@@ -618,9 +853,17 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
         let mut last_header = prev_header;
         let mut headers = Vec::with_capacity(count);
 
-        // Create headers out of the hashes.
-        for filter_hash in hashes {
+        // Create headers out of the hashes, and make sure they match our checkpoints.
+        for (i, filter_hash) in hashes.into_iter().enumerate() {
+            let height = start_height + 1 + i as Height;
+
             last_header = filter_hash.filter_header(&last_header);
+
+            if let Some(checkpoint) = self.config.checkpoints.get(&height) {
+                if checkpoint != &last_header {
+                    return Err(Error::InvalidCheckpoint { from, height });
+                }
+            }
             headers.push((filter_hash, last_header));
         }
         self.filters
@@ -645,6 +888,72 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
             .map_err(Error::from)
     }
 
+    /// Import filter headers from a trusted source, eg. a snapshot. Headers are expected
+    /// to start at the genesis, and must match the headers we already have. Headers above
+    /// the block tree height are ignored. Importing no headers is a no-op.
+    ///
+    /// Returns the new filter header height, or an error.
+    pub fn import_headers<T: BlockReader>(
+        &mut self,
+        mut headers: Vec<(FilterHash, FilterHeader)>,
+        tree: &T,
+    ) -> Result<Height, ImportFilterHeadersError> {
+        let start_height = self.filters.height();
+
+        if headers.is_empty() {
+            return Ok(start_height);
+        }
+        headers.truncate(tree.height() as usize + 1);
+
+        if headers.len() <= start_height as usize {
+            // We already have all these headers. Just make sure they match ours.
+            let height = headers.len().saturating_sub(1) as Height;
+
+            if headers.last() != self.filters.get_header(height).as_ref() {
+                return Err(ImportFilterHeadersError::InvalidHeader(height));
+            }
+            return Ok(start_height);
+        }
+        let headers = headers.split_off(start_height as usize);
+        let (_, mut prev_header) = self.filters.tip();
+
+        if headers[0] != (*self.filters.tip().0, *prev_header) {
+            return Err(ImportFilterHeadersError::InvalidHeader(start_height));
+        }
+        let headers = headers.into_iter().skip(1).collect::<Vec<_>>();
+
+        for (i, (hash, header)) in headers.iter().enumerate() {
+            if &hash.filter_header(prev_header) != header {
+                return Err(ImportFilterHeadersError::InvalidHeader(
+                    start_height + i as Height + 1,
+                ));
+            }
+            prev_header = header;
+        }
+        let count = headers.len();
+        let height = self.filters.import_headers(headers)?;
+        let block_hash = tree
+            .get_block_by_height(height)
+            .map(|h| h.block_hash())
+            .expect("FilterManager::import_headers: headers are within the block tree height");
+
+        self.upstream.event(Event::FilterHeadersImported {
+            count,
+            height,
+            block_hash,
+        });
+        // Nb. We may not be connected to any peer yet, in which case filters are
+        // fetched later.
+        self.headers_imported(start_height, height, tree).ok();
+
+        if height == tree.height() {
+            self.upstream.event(Event::Synced(height));
+        } else {
+            self.sync(tree);
+        }
+        Ok(height)
+    }
+
     /// Handle a `getcfheaders` message from a peer.
     pub fn received_getcfheaders<T: BlockReader>(
         &mut self,
@@ -654,10 +963,18 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
     ) -> Result<(), Error> {
         let from = *from;
 
+        if !self.config.serve {
+            return Err(Error::Ignored {
+                msg: "getcfheaders",
+                from,
+            });
+        }
         if msg.filter_type != 0x0 {
-            return Err(Error::InvalidMessage {
+            // Peers may support filter types we don't, that's not a reason to
+            // disconnect them.
+            return Err(Error::Ignored {
+                msg: "getcfheaders: unsupported filter type",
                 from,
-                reason: "getcfheaders: invalid filter type",
             });
         }
 
@@ -698,6 +1015,189 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
         })
     }
 
+    /// Handle a `getcfilters` message from a peer.
+    ///
+    /// Filters are served from the filter cache, since they aren't otherwise stored.
+    /// Filters that are not in the cache are skipped.
+    pub fn received_getcfilters<T: BlockReader>(
+        &mut self,
+        from: &PeerId,
+        msg: GetCFilters,
+        tree: &T,
+    ) -> Result<(), Error> {
+        let from = *from;
+
+        if !self.config.serve {
+            return Err(Error::Ignored {
+                msg: "getcfilters",
+                from,
+            });
+        }
+        if msg.filter_type != 0x0 {
+            // Peers may support filter types we don't, that's not a reason to
+            // disconnect them.
+            return Err(Error::Ignored {
+                msg: "getcfilters: unsupported filter type",
+                from,
+            });
+        }
+
+        let start_height = msg.start_height as Height;
+        let stop_height = match tree.get_block(&msg.stop_hash) {
+            Some((height, _)) if height >= start_height => height,
+            Some(_) => {
+                return Err(Error::InvalidMessage {
+                    from,
+                    reason: "getcfilters: start height is greater than stop height",
+                })
+            }
+            None => {
+                return Err(Error::Ignored {
+                    msg: "getcfilters",
+                    from,
+                })
+            }
+        };
+        if (stop_height - start_height) as usize >= MAX_MESSAGE_CFILTERS {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "getcfilters: too many filters requested",
+            });
+        }
+
+        for height in start_height..=stop_height {
+            let (filter, block_hash) = match (
+                self.rescan.cache.get(&height),
+                tree.get_block_by_height(height),
+            ) {
+                (Some(filter), Some(header)) => (filter, header.block_hash()),
+                _ => continue,
+            };
+            self.upstream.send_cfilter(
+                from,
+                CFilter {
+                    filter_type: msg.filter_type,
+                    block_hash,
+                    filter: filter.content.clone(),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Handle a `getcfcheckpt` message from a peer.
+    pub fn received_getcfcheckpt<T: BlockReader>(
+        &mut self,
+        from: &PeerId,
+        msg: GetCFCheckpt,
+        tree: &T,
+    ) -> Result<(), Error> {
+        let from = *from;
+
+        if !self.config.serve {
+            return Err(Error::Ignored {
+                msg: "getcfcheckpt",
+                from,
+            });
+        }
+        if msg.filter_type != 0x0 {
+            // Peers may support filter types we don't, that's not a reason to
+            // disconnect them.
+            return Err(Error::Ignored {
+                msg: "getcfcheckpt: unsupported filter type",
+                from,
+            });
+        }
+
+        let stop_height = match tree.get_block(&msg.stop_hash) {
+            Some((height, _)) if height <= self.filters.height() => height,
+            // Either we don't have the stop block, or we're still syncing its filter headers.
+            _ => {
+                return Err(Error::Ignored {
+                    msg: "getcfcheckpt",
+                    from,
+                })
+            }
+        };
+        let filter_headers = (1..=stop_height / CFCHECKPT_INTERVAL)
+            .filter_map(|i| self.filters.get_header(i * CFCHECKPT_INTERVAL))
+            .map(|(_, header)| header)
+            .collect();
+
+        self.upstream.send_cfcheckpt(
+            from,
+            CFCheckpt {
+                filter_type: msg.filter_type,
+                stop_hash: msg.stop_hash,
+                filter_headers,
+            },
+        );
+        Ok(())
+    }
+
+    /// Handle a `cfcheckpt` message from a peer, in response to our request. The
+    /// checkpoints are compared with ours: if they match, we start syncing from the peer.
+    pub fn received_cfcheckpt<T: BlockReader>(
+        &mut self,
+        from: &PeerId,
+        msg: CFCheckpt,
+        tree: &T,
+    ) -> Result<(), Error> {
+        let from = *from;
+        let Some(peer) = self.peers.get_mut(&from) else {
+            return Err(Error::Ignored {
+                msg: "cfcheckpt: unknown peer",
+                from,
+            });
+        };
+        match peer.verifying {
+            Some((stop_hash, _)) if stop_hash == msg.stop_hash => {}
+            _ => {
+                return Err(Error::Ignored {
+                    msg: "cfcheckpt: unsolicited message",
+                    from,
+                })
+            }
+        }
+        if msg.filter_type != 0x0 {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "cfcheckpt: invalid filter type",
+            });
+        }
+        let Some((stop_height, _)) = tree.get_block(&msg.stop_hash) else {
+            // The stop block was re-orged out since our request. Try again later.
+            peer.verifying = None;
+
+            return Err(Error::Ignored {
+                msg: "cfcheckpt: unknown stop hash",
+                from,
+            });
+        };
+        if msg.filter_headers.len() as Height != stop_height / CFCHECKPT_INTERVAL {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "cfcheckpt: header count does not match stop height",
+            });
+        }
+
+        for (i, header) in msg.filter_headers.iter().enumerate() {
+            let height = (i as Height + 1) * CFCHECKPT_INTERVAL;
+
+            if let Some(checkpoint) = self.config.checkpoints.get(&height) {
+                if checkpoint != header {
+                    return Err(Error::InvalidCheckpoint { from, height });
+                }
+            }
+        }
+        peer.verified = stop_height;
+        peer.verifying = None;
+
+        self.sync(tree);
+
+        Ok(())
+    }
+
     /// Handle a `cfilter` message.
     ///
     /// Returns a list of blocks that need to be fetched from the network.
@@ -716,8 +1216,8 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
             });
         }
 
-        let height = if let Some((height, _)) = tree.get_block(&msg.block_hash) {
-            height
+        let (height, block) = if let Some((height, header)) = tree.get_block(&msg.block_hash) {
+            (height, *header)
         } else {
             // Can't handle this message, we don't have the block.
             return Err(Error::Ignored {
@@ -760,7 +1260,7 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
             filter: filter.clone(),
         });
 
-        if self.rescan.received(height, filter, block_hash) {
+        if self.rescan.received(height, filter, block) {
             let (matches, events, processed) = self.rescan.process();
             for event in events {
                 self.upstream.event(event);
@@ -777,6 +1277,88 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
         Ok(Vec::default())
     }
 
+    /// Check whether a message is a response to one of our requests to the given peer.
+    pub fn is_solicited<T: BlockReader>(
+        &self,
+        from: &PeerId,
+        msg: &NetworkMessage,
+        tree: &T,
+    ) -> bool {
+        match msg {
+            NetworkMessage::CFHeaders(msg) => self
+                .inflight
+                .get(&msg.stop_hash)
+                .is_some_and(|(_, peer, _)| peer == from),
+            NetworkMessage::CFCheckpt(msg) => self
+                .peers
+                .get(from)
+                .and_then(|peer| peer.verifying)
+                .is_some_and(|(stop_hash, _)| stop_hash == msg.stop_hash),
+            // Nb. Filters are requested in ranges, from any peer.
+            NetworkMessage::CFilter(msg) => tree
+                .get_block(&msg.block_hash)
+                .is_some_and(|(height, _)| self.rescan.is_requested(height)),
+            _ => false,
+        }
+    }
+
+    /// Get the number of `getcfheaders` requests awaiting a response from a peer.
+    pub fn inflight(&self, addr: &PeerId) -> usize {
+        self.inflight
+            .values()
+            .filter(|(_, peer, _)| peer == addr)
+            .count()
+    }
+
+    /// Dump the filter manager state, for debugging.
+    pub fn dump(&self, redact: &mut dump::Redact) -> Value {
+        let mut peers = self.peers.iter().collect::<Vec<_>>();
+        peers.sort_by_key(|(addr, _)| **addr);
+
+        let peers = peers
+            .into_iter()
+            .map(|(addr, peer)| {
+                Value::Object(dump::object([
+                    ("addr", redact.peer(addr)),
+                    ("height", dump::number(peer.height)),
+                    ("last_active", dump::time(peer.last_active)),
+                    ("verified", dump::number(peer.verified)),
+                    (
+                        "verifying",
+                        dump::optional(peer.verifying, |(stop_hash, _)| dump::string(stop_hash)),
+                    ),
+                ]))
+            })
+            .collect();
+
+        let mut inflight = self.inflight.iter().collect::<Vec<_>>();
+        inflight.sort_by_key(|(_, (height, _, _))| *height);
+
+        let inflight = inflight
+            .into_iter()
+            .map(|(stop_hash, (start_height, addr, expiry))| {
+                Value::Object(dump::object([
+                    ("start_height", dump::number(*start_height)),
+                    ("stop_hash", dump::string(stop_hash)),
+                    ("addr", redact.peer(addr)),
+                    ("expiry", dump::time(*expiry)),
+                ]))
+            })
+            .collect();
+
+        Value::Object(dump::object([
+            ("height", dump::number(self.filters.height())),
+            ("rescan", self.rescan.dump()),
+            ("peers", Value::Array(peers)),
+            ("inflight", Value::Array(inflight)),
+            ("last_idle", dump::optional(self.last_idle, dump::time)),
+            (
+                "last_processed",
+                dump::optional(self.last_processed, dump::time),
+            ),
+        ]))
+    }
+
     /// Called when a peer disconnected.
     pub fn peer_disconnected(&mut self, id: &PeerId) {
         self.peers.remove(id);
@@ -791,7 +1373,7 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
         link: Link,
         tree: &T,
     ) {
-        if !link.is_outbound() {
+        if !link.is_outbound() || !self.config.sync {
             return;
         }
         if !services.has(REQUIRED_SERVICES) {
@@ -804,14 +1386,35 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
             Peer {
                 last_active: time,
                 height,
+                verified: 0,
+                verifying: None,
                 socket,
             },
         );
         self.sync(tree);
     }
 
+    /// Defer filter header and filter downloads, or resume them. While deferred, peers
+    /// are still tracked, but nothing is requested from them.
+    pub fn set_deferred<T: BlockReader>(&mut self, deferred: bool, tree: &T) {
+        self.deferred = deferred;
+
+        if !deferred {
+            self.sync(tree);
+        }
+    }
+
+    /// Check whether the filter header chain is synced up to the block header chain. Always
+    /// `true` if filter headers aren't being synced.
+    pub fn is_synced<T: BlockReader>(&self, tree: &T) -> bool {
+        !self.config.sync || self.deferred || self.filters.height() >= tree.height()
+    }
+
     /// Attempt to sync the filter header chain.
     pub fn sync<T: BlockReader>(&mut self, tree: &T) {
+        if self.deferred {
+            return;
+        }
         let filter_height = self.filters.height();
         let block_height = tree.height();
 
@@ -826,6 +1429,8 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
             }
         }
 
+        self.verify_checkpoints(tree);
+
         if filter_height < block_height {
             // We need to sync the filter header chain.
             let start_height = self.filters.height() + 1;
@@ -861,14 +1466,47 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
         }
     }
 
-    /// Send a `getcfheaders` message to a random peer.
-    ///
-    /// # Panics
-    ///
-    /// Panics if the range is not within the bounds of the active chain.
-    ///
-    fn send_getcfheaders<T: BlockReader>(
-        &mut self,
+    /// Ask peers for the filter headers at our checkpoint heights, if they weren't
+    /// already verified.
+    fn verify_checkpoints<T: BlockReader>(&mut self, tree: &T) {
+        let now = self.clock.local_time();
+        let timeout = self.config.request_timeout;
+
+        for (addr, peer) in self.peers.iter_mut() {
+            if peer.verifying.is_some() {
+                continue;
+            }
+            let max = Height::min(peer.height, tree.height());
+            let Some(height) = self
+                .config
+                .checkpoints
+                .range(..=max)
+                .map(|(h, _)| *h)
+                .rfind(|h| h % CFCHECKPT_INTERVAL == 0)
+            else {
+                continue;
+            };
+            if height <= peer.verified {
+                continue;
+            }
+            let Some(stop) = tree.get_block_by_height(height) else {
+                continue;
+            };
+            let stop_hash = stop.block_hash();
+
+            self.upstream.get_cfcheckpt(*addr, stop_hash, timeout);
+            peer.verifying = Some((stop_hash, now + timeout));
+        }
+    }
+
+    /// Send a `getcfheaders` message to a random peer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is not within the bounds of the active chain.
+    ///
+    fn send_getcfheaders<T: BlockReader>(
+        &mut self,
         range: RangeInclusive<Height>,
         tree: &T,
     ) -> Option<(PeerId, Height, BlockHash)> {
@@ -898,7 +1536,7 @@ impl<F: Filters, U: SyncFilters + Events + Wakeup + Disconnect, C: Clock> Filter
         }
 
         // TODO: We should select peers that are caught up to the requested height.
-        if let Some((peer, _)) = self.peers.sample() {
+        if let Some((peer, _)) = self.peers.sample_with(|_, p| p.verifying.is_none()) {
             let time = self.clock.local_time();
             let timeout = self.config.request_timeout;
 
@@ -974,6 +1612,7 @@ impl Iterator for HeightIterator {
 mod tests {
     use std::iter;
     use std::ops::RangeBounds;
+    use std::rc::Rc;
 
     use nakamoto_common::bitcoin;
     use nakamoto_common::bitcoin_hashes;
@@ -1019,6 +1658,27 @@ mod tests {
             FilterManager<FilterCache<store::Memory<StoredHeader>>, Outbox, C>,
             BlockCache<store::Memory<BlockHeader>>,
             NonEmpty<bitcoin::Block>,
+        ) {
+            let config = Config {
+                filter_cache_size,
+                ..Config::default()
+            };
+            setup_with(network, height, config, true, clock)
+        }
+
+        /// Setup a filter manager with the given configuration, and a block tree of the
+        /// given height. If `synced` is set, the filter headers of the whole tree are
+        /// imported.
+        pub fn setup_with<C: Clock>(
+            network: Network,
+            height: Height,
+            config: Config,
+            synced: bool,
+            clock: C,
+        ) -> (
+            FilterManager<FilterCache<store::Memory<StoredHeader>>, Outbox, C>,
+            BlockCache<store::Memory<BlockHeader>>,
+            NonEmpty<bitcoin::Block>,
         ) {
             let mut rng = fastrand::Rng::new();
             let genesis = network.genesis_block();
@@ -1026,28 +1686,33 @@ mod tests {
             let tree = {
                 let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
                 let store = store::Memory::new(headers);
-                let params = Params::new(network.into());
+                let params = Params::new(network.clone().into());
 
                 BlockCache::from(store, params, &[]).unwrap()
             };
-            let cfheaders =
-                gen::cfheaders_from_blocks(FilterHeader::genesis(network), chain.tail.iter());
-
-            let mut cache = FilterCache::from(store::memory::Memory::genesis(network)).unwrap();
-            cache.import_headers(cfheaders).unwrap();
-            cache.verify(network).unwrap();
+            let mut cbfmgr = manager(network.clone(), config, clock);
+
+            if synced {
+                let cfheaders = gen::cfheaders_from_blocks(
+                    FilterHeader::genesis(network.clone()),
+                    chain.tail.iter(),
+                );
+                cbfmgr.filters.import_headers(cfheaders).unwrap();
+                cbfmgr.filters.verify(network).unwrap();
+            }
+            (cbfmgr, tree, chain)
+        }
 
+        /// Create a filter manager with only the genesis filter header.
+        pub fn manager<C: Clock>(
+            network: Network,
+            config: Config,
+            clock: C,
+        ) -> FilterManager<FilterCache<store::Memory<StoredHeader>>, Outbox, C> {
+            let cache = FilterCache::from(store::memory::Memory::genesis(network.clone())).unwrap();
             let upstream = Outbox::new(network, PROTOCOL_VERSION, "channel");
-            let config = Config {
-                filter_cache_size,
-                ..Config::default()
-            };
 
-            (
-                FilterManager::new(config, rng, cache, upstream, clock),
-                tree,
-                chain,
-            )
+            FilterManager::new(config, fastrand::Rng::new(), cache, upstream, clock)
         }
 
         pub fn cfilters<'a>(
@@ -1170,8 +1835,8 @@ mod tests {
         };
         let mut cbfmgr = {
             let rng = fastrand::Rng::new();
-            let cache = FilterCache::from(store::memory::Memory::genesis(network)).unwrap();
-            let upstream = Outbox::new(network, PROTOCOL_VERSION, "test");
+            let cache = FilterCache::from(store::memory::Memory::genesis(network.clone())).unwrap();
+            let upstream = Outbox::new(network.clone(), PROTOCOL_VERSION, "test");
 
             FilterManager::new(Config::default(), rng, cache, upstream, clock)
         };
@@ -1241,6 +1906,7 @@ mod tests {
 
         // Start rescan with no peers.
         cbfmgr.rescan(
+            0,
             Bound::Included(0),
             Bound::Unbounded,
             vec![gen::script(&mut rng)],
@@ -1282,9 +1948,54 @@ mod tests {
 
     /// Test that a bounded rescan will eventually complete.
     #[test]
-    #[ignore]
     fn test_rescan_completed() {
-        todo!()
+        let birth = 11;
+        let stop = birth + 2;
+        let best = 42;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let network = Network::Regtest;
+        let (mut cbfmgr, tree, chain) = util::setup(network.clone(), best, 0, RefClock::from(time));
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let cfheaders = util::cfheaders(FilterHeader::genesis(network), &chain.tail);
+        let cfilters = util::cfilters(chain.iter()).collect::<Vec<_>>();
+
+        cbfmgr.filters.clear().unwrap();
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            Socket::new(remote),
+            best,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            &tree,
+        );
+        cbfmgr
+            .received_cfheaders(&remote, cfheaders, &tree)
+            .unwrap();
+        cbfmgr.rescan(
+            0,
+            Bound::Included(birth),
+            Bound::Included(stop),
+            vec![gen::script(&mut rng)],
+            &tree,
+        );
+
+        for height in birth..stop {
+            cbfmgr
+                .received_cfilter(&remote, cfilters[height as usize].clone(), &tree)
+                .unwrap();
+        }
+        assert!(cbfmgr.rescan.active);
+        assert!(!util::events(cbfmgr.upstream.drain())
+            .any(|e| matches!(e, Event::RescanCompleted { .. })));
+
+        // The rescan completes once the filter at the stop height is processed.
+        cbfmgr
+            .received_cfilter(&remote, cfilters[stop as usize].clone(), &tree)
+            .unwrap();
+        assert!(!cbfmgr.rescan.active);
+        assert!(util::events(cbfmgr.upstream.drain())
+            .any(|e| matches!(e, Event::RescanCompleted { height, .. } if height == stop)));
     }
 
     /// Test that rescanning triggers filter syncing immediately.
@@ -1295,7 +2006,7 @@ mod tests {
         let mut rng = fastrand::Rng::new();
         let time = LocalTime::now();
         let network = Network::Regtest;
-        let (mut cbfmgr, tree, chain) = util::setup(network, best, 0, RefClock::from(time));
+        let (mut cbfmgr, tree, chain) = util::setup(network.clone(), best, 0, RefClock::from(time));
         let remote: PeerId = ([88, 88, 88, 88], 8333).into();
         let tip = tree.get_block_by_height(best).unwrap().block_hash();
         let filter_type = 0x0;
@@ -1334,6 +2045,7 @@ mod tests {
 
         // Start rescan.
         cbfmgr.rescan(
+            0,
             Bound::Included(birth),
             Bound::Unbounded,
             vec![gen::script(&mut rng)],
@@ -1359,7 +2071,7 @@ mod tests {
         let time = LocalTime::now();
         let network = Network::Regtest;
         let filter_type = 0x0;
-        let (mut cbfmgr, tree, chain) = util::setup(network, best, 0, RefClock::from(time));
+        let (mut cbfmgr, tree, chain) = util::setup(network.clone(), best, 0, RefClock::from(time));
         let remote: PeerId = ([88, 88, 88, 88], 8333).into();
         let previous_filter_header = FilterHeader::genesis(network);
         let cfheaders = util::cfheaders(previous_filter_header, &chain.tail);
@@ -1381,6 +2093,7 @@ mod tests {
 
         // Start rescan.
         cbfmgr.rescan(
+            0,
             Bound::Included(birth),
             Bound::Unbounded,
             vec![gen::script(&mut rng)],
@@ -1433,6 +2146,81 @@ mod tests {
         assert_eq!(cbfmgr.rescan.current, current + 1);
     }
 
+    /// Test that a rescan can be paused, resumed and canceled.
+    #[test]
+    fn test_rescan_pause_resume_cancel() {
+        let id = 7;
+        let birth = 11;
+        let best = 42;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let network = Network::Regtest;
+        let (mut cbfmgr, tree, chain) = util::setup(network.clone(), best, 0, RefClock::from(time));
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let cfheaders = util::cfheaders(FilterHeader::genesis(network), &chain.tail);
+        let cfilters = util::cfilters(chain.iter()).collect::<Vec<_>>();
+
+        cbfmgr.filters.clear().unwrap();
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            Socket::new(remote),
+            best,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            &tree,
+        );
+        cbfmgr
+            .received_cfheaders(&remote, cfheaders, &tree)
+            .unwrap();
+        cbfmgr.rescan(
+            id,
+            Bound::Included(birth),
+            Bound::Unbounded,
+            vec![gen::script(&mut rng)],
+            &tree,
+        );
+        cbfmgr
+            .received_cfilter(&remote, cfilters[birth as usize].clone(), &tree)
+            .unwrap();
+        assert_eq!(cbfmgr.rescan.current, birth + 1);
+
+        // Pausing a different rescan has no effect.
+        assert!(!cbfmgr.pause_rescan(id + 1));
+        assert!(cbfmgr.pause_rescan(id));
+        assert!(!cbfmgr.rescan.active);
+        assert!(util::events(cbfmgr.upstream.drain()).any(|e| matches!(
+            e,
+            Event::RescanPaused { id: i, height } if i == id && height == birth + 1
+        )));
+
+        // Filters requested before pausing are ignored.
+        cbfmgr
+            .received_cfilter(&remote, cfilters[birth as usize + 1].clone(), &tree)
+            .unwrap();
+        assert_eq!(cbfmgr.rescan.current, birth + 1);
+
+        // Resuming re-requests the missing filters.
+        assert!(cbfmgr.resume_rescan(id, &tree).is_some());
+        assert!(cbfmgr.resume_rescan(id, &tree).is_none());
+        assert!(cbfmgr.rescan.active);
+
+        let (stop_hash, _) = tree.tip();
+        let expected = GetCFilters {
+            filter_type: 0x0,
+            start_height: birth as u32 + 1,
+            stop_hash,
+        };
+        output::test::messages(&mut cbfmgr.upstream, &remote)
+            .find(|m| matches!(m, NetworkMessage::GetCFilters(msg) if msg == &expected))
+            .expect("`getcfilters` sent");
+
+        assert!(cbfmgr.cancel_rescan(id));
+        assert!(!cbfmgr.cancel_rescan(id));
+        assert!(!cbfmgr.rescan.active);
+        assert!(util::events(cbfmgr.upstream.drain())
+            .any(|e| matches!(e, Event::RescanCanceled { id: i, .. } if i == id)));
+    }
+
     /// Test that if we start with our cfheader chain behind our header
     /// chain, we immediately try to catch up.
     #[test]
@@ -1446,15 +2234,15 @@ mod tests {
         let time = LocalTime::now();
 
         let mut cbfmgr = {
-            let cache = FilterCache::from(store::memory::Memory::genesis(network)).unwrap();
+            let cache = FilterCache::from(store::memory::Memory::genesis(network.clone())).unwrap();
             let rng = fastrand::Rng::new();
-            let upstream = Outbox::new(network, PROTOCOL_VERSION, "test");
+            let upstream = Outbox::new(network.clone(), PROTOCOL_VERSION, "test");
             FilterManager::new(Config::default(), rng, cache, upstream, time)
         };
 
         let chain = gen::blockchain(network.genesis_block(), header_height, &mut rng);
         let cfheaders = gen::cfheaders_from_blocks(
-            FilterHeader::genesis(network),
+            FilterHeader::genesis(network.clone()),
             chain.tail.iter().take(cfheader_height),
         );
         cbfmgr.filters.import_headers(cfheaders).unwrap();
@@ -1495,6 +2283,370 @@ mod tests {
         }).expect("GetCFHeaders request");
     }
 
+    #[test]
+    fn test_sync_disabled() {
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let time = LocalTime::now();
+        let config = Config {
+            sync: false,
+            ..Config::default()
+        };
+        let (mut cbfmgr, tree, _) = util::setup_with(network, 15, config, false, time);
+
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            Socket::new(remote),
+            15,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            &tree,
+        );
+
+        assert_eq!(
+            output::test::messages(&mut cbfmgr.upstream, &remote).count(),
+            0,
+            "filter headers aren't synced"
+        );
+        assert_matches!(
+            cbfmgr.get_cfilters(1..=15, &tree),
+            Err(GetFiltersError::Disabled)
+        );
+    }
+
+    #[test]
+    fn test_filter_type() {
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let time = LocalTime::now();
+        let config = Config {
+            serve: true,
+            ..Config::default()
+        };
+        let (mut cbfmgr, tree, chain) = util::setup_with(network, 8, config, false, time);
+        let stop_hash = chain.last().block_hash();
+        cbfmgr.initialize(&tree);
+
+        // Requests for filter types we don't support are ignored, since peers may
+        // support more filter types than we do.
+        assert_matches!(
+            cbfmgr.received_getcfheaders(
+                &remote,
+                GetCFHeaders {
+                    filter_type: 0x1,
+                    start_height: 1,
+                    stop_hash,
+                },
+                &tree
+            ),
+            Err(Error::Ignored { .. })
+        );
+        assert_matches!(
+            cbfmgr.received_getcfilters(
+                &remote,
+                GetCFilters {
+                    filter_type: 0x1,
+                    start_height: 1,
+                    stop_hash,
+                },
+                &tree
+            ),
+            Err(Error::Ignored { .. })
+        );
+        assert_matches!(
+            cbfmgr.received_getcfcheckpt(
+                &remote,
+                GetCFCheckpt {
+                    filter_type: 0x1,
+                    stop_hash,
+                },
+                &tree
+            ),
+            Err(Error::Ignored { .. })
+        );
+        assert_eq!(
+            output::test::messages(&mut cbfmgr.upstream, &remote).count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_import_headers() {
+        let network = Network::Regtest;
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, chain) =
+            util::setup_with(network.clone(), 16, Config::default(), false, time);
+        let cfheaders =
+            gen::cfheaders_from_blocks(FilterHeader::genesis(network.clone()), chain.tail.iter());
+        let genesis = cbfmgr.filters.get_header(0).unwrap();
+        let headers = std::iter::once(genesis)
+            .chain(cfheaders.iter().cloned())
+            .collect::<Vec<_>>();
+
+        cbfmgr
+            .filters
+            .import_headers(cfheaders[..4].to_vec())
+            .unwrap();
+
+        // Corrupt a header above our filter tip.
+        let mut corrupt = headers.clone();
+        corrupt[10].1 = FilterHeader::genesis(network);
+        assert_matches!(
+            cbfmgr.import_headers(corrupt, &tree),
+            Err(ImportFilterHeadersError::InvalidHeader(10))
+        );
+        assert_eq!(cbfmgr.filters.height(), 4);
+
+        // Importing nothing leaves our filter headers untouched.
+        assert_eq!(cbfmgr.import_headers(vec![], &tree).unwrap(), 4);
+        assert_eq!(cbfmgr.filters.height(), 4);
+
+        assert_eq!(cbfmgr.import_headers(headers.clone(), &tree).unwrap(), 16);
+        assert_eq!(cbfmgr.filters.get_headers(0..=16), headers);
+        assert!(util::events(cbfmgr.upstream.drain()).any(|e| matches!(
+            e,
+            Event::FilterHeadersImported {
+                count: 12,
+                height: 16,
+                ..
+            }
+        )));
+
+        // Importing the same headers again is a no-op.
+        assert_eq!(
+            cbfmgr.import_headers(headers[..8].to_vec(), &tree).unwrap(),
+            16
+        );
+    }
+
+    #[test]
+    fn test_serve_filters() {
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, chain) = util::setup(network, 2048, DEFAULT_FILTER_CACHE_SIZE, time);
+        let (tip, _) = tree.tip();
+
+        // Filters are only served when enabled.
+        assert_matches!(
+            cbfmgr.received_getcfcheckpt(
+                &remote,
+                GetCFCheckpt {
+                    filter_type: 0x0,
+                    stop_hash: tip,
+                },
+                &tree,
+            ),
+            Err(Error::Ignored { .. })
+        );
+        cbfmgr.config.serve = true;
+
+        // Cache the filters of a few blocks. Filters that aren't cached can't be served.
+        for height in 8..=12 {
+            let filter = gen::cfilter(&chain[height as usize]);
+            cbfmgr.rescan.cache.push(height, Rc::new(filter));
+        }
+        cbfmgr
+            .received_getcfilters(
+                &remote,
+                GetCFilters {
+                    filter_type: 0x0,
+                    start_height: 4,
+                    stop_hash: chain[10].block_hash(),
+                },
+                &tree,
+            )
+            .unwrap();
+
+        let cfilters = output::test::messages(&mut cbfmgr.upstream, &remote)
+            .filter_map(|m| match m {
+                NetworkMessage::CFilter(cfilter) => Some(cfilter),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cfilters,
+            util::cfilters(&chain.tail[7..10]).collect::<Vec<_>>()
+        );
+
+        // Requesting too many filters is not allowed.
+        assert_matches!(
+            cbfmgr.received_getcfilters(
+                &remote,
+                GetCFilters {
+                    filter_type: 0x0,
+                    start_height: 0,
+                    stop_hash: chain[MAX_MESSAGE_CFILTERS].block_hash(),
+                },
+                &tree,
+            ),
+            Err(Error::InvalidMessage { .. })
+        );
+
+        cbfmgr
+            .received_getcfcheckpt(
+                &remote,
+                GetCFCheckpt {
+                    filter_type: 0x0,
+                    stop_hash: tip,
+                },
+                &tree,
+            )
+            .unwrap();
+
+        let checkpt = output::test::messages(&mut cbfmgr.upstream, &remote)
+            .find_map(|m| match m {
+                NetworkMessage::CFCheckpt(checkpt) => Some(checkpt),
+                _ => None,
+            })
+            .expect("`cfcheckpt` is sent");
+        assert_eq!(
+            checkpt.filter_headers,
+            vec![
+                cbfmgr.filters.get_header(1000).unwrap().1,
+                cbfmgr.filters.get_header(2000).unwrap().1,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_filter_checkpoints() {
+        let network = Network::Regtest;
+        let honest: PeerId = ([88, 88, 88, 88], 8333).into();
+        let dishonest: PeerId = ([99, 99, 99, 99], 8333).into();
+        let time = LocalTime::now();
+        let (mut server, tree, _) =
+            util::setup(network.clone(), 2048, DEFAULT_FILTER_CACHE_SIZE, time);
+        let height = tree.height();
+        let stop_hash = tree.get_block_by_height(2000).unwrap().block_hash();
+
+        server.config.serve = true;
+
+        let mut cbfmgr = util::manager(
+            network.clone(),
+            Config {
+                checkpoints: [1000, 2000]
+                    .into_iter()
+                    .map(|h| (h, server.filters.get_header(h).unwrap().1))
+                    .collect(),
+                ..Config::default()
+            },
+            time,
+        );
+        cbfmgr.initialize(&tree);
+
+        for peer in [honest, dishonest] {
+            cbfmgr.peer_negotiated(
+                Socket::new(peer),
+                height,
+                REQUIRED_SERVICES,
+                Link::Outbound,
+                &tree,
+            );
+            let msgs = output::test::messages(&mut cbfmgr.upstream, &peer).collect::<Vec<_>>();
+
+            // Peers are asked for the filter headers at our last checkpoint, and aren't
+            // synced from until they are verified.
+            assert!(msgs.iter().any(|m| matches!(
+                m,
+                NetworkMessage::GetCFCheckpt(GetCFCheckpt { stop_hash: h, .. }) if *h == stop_hash
+            )));
+            assert!(!msgs
+                .iter()
+                .any(|m| matches!(m, NetworkMessage::GetCFHeaders(_))));
+        }
+
+        server
+            .received_getcfcheckpt(
+                &honest,
+                GetCFCheckpt {
+                    filter_type: 0x0,
+                    stop_hash,
+                },
+                &tree,
+            )
+            .unwrap();
+        let checkpt = output::test::messages(&mut server.upstream, &honest)
+            .find_map(|m| match m {
+                NetworkMessage::CFCheckpt(checkpt) => Some(checkpt),
+                _ => None,
+            })
+            .expect("`cfcheckpt` is sent");
+
+        // A peer serving a different filter header chain is rejected.
+        let mut corrupt = checkpt.clone();
+        corrupt.filter_headers[1] = FilterHeader::genesis(network);
+        assert_matches!(
+            cbfmgr.received_cfcheckpt(&dishonest, corrupt, &tree),
+            Err(Error::InvalidCheckpoint { height: 2000, .. })
+        );
+        assert!(output::test::messages(&mut cbfmgr.upstream, &dishonest)
+            .all(|m| !matches!(m, NetworkMessage::GetCFHeaders(_))));
+
+        // Checkpoints are only accepted in response to our request.
+        assert_matches!(
+            cbfmgr.received_cfcheckpt(&([77, 77, 77, 77], 8333).into(), checkpt.clone(), &tree),
+            Err(Error::Ignored { .. })
+        );
+
+        // Once verified, the peer is synced from.
+        cbfmgr.received_cfcheckpt(&honest, checkpt, &tree).unwrap();
+        assert!(output::test::messages(&mut cbfmgr.upstream, &honest)
+            .any(|m| matches!(m, NetworkMessage::GetCFHeaders(_))));
+    }
+
+    #[test]
+    fn test_cfheaders_checkpoint_mismatch() {
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let time = LocalTime::now();
+        let (server, tree, chain) =
+            util::setup(network.clone(), 16, DEFAULT_FILTER_CACHE_SIZE, time);
+        let height = tree.height();
+        let manager = |checkpoints: BTreeMap<Height, FilterHeader>| {
+            let config = Config {
+                checkpoints,
+                ..Config::default()
+            };
+            let mut cbfmgr = util::manager(network.clone(), config, time);
+
+            cbfmgr.initialize(&tree);
+            cbfmgr.peer_negotiated(
+                Socket::new(remote),
+                height,
+                REQUIRED_SERVICES,
+                Link::Outbound,
+                &tree,
+            );
+            output::test::messages(&mut cbfmgr.upstream, &remote)
+                .find(|m| matches!(m, NetworkMessage::GetCFHeaders(_)))
+                .expect("`getcfheaders` is sent");
+            cbfmgr
+        };
+        let msg = util::cfheaders(FilterHeader::genesis(network.clone()), &chain.tail);
+
+        // Filter headers that don't match a checkpoint are rejected, and not imported.
+        let mut cbfmgr = manager(BTreeMap::from([(
+            5,
+            FilterHeader::genesis(network.clone()),
+        )]));
+        assert_matches!(
+            cbfmgr.received_cfheaders(&remote, msg.clone(), &tree),
+            Err(Error::InvalidCheckpoint { height: 5, .. })
+        );
+        assert_eq!(cbfmgr.filters.height(), 0);
+
+        // Filter headers that match are imported.
+        let mut cbfmgr = manager(BTreeMap::from([(
+            5,
+            server.filters.get_header(5).unwrap().1,
+        )]));
+        assert_eq!(
+            cbfmgr.received_cfheaders(&remote, msg, &tree).unwrap(),
+            height
+        );
+    }
+
     #[test]
     fn test_partial_cache_hit_overlap_max() {
         // Head              8
@@ -1530,6 +2682,7 @@ mod tests {
 
         // 1. Populate the cache from heights 5 to 8.
         cbfmgr.rescan(
+            0,
             Bound::Included(birth),
             Bound::Included(best),
             watch.clone(),
@@ -1564,6 +2717,7 @@ mod tests {
 
         // 5. Trigger a rescan for the new range 7 to 9
         let matched = cbfmgr.rescan(
+            0,
             rescan_range.start_bound().cloned(),
             rescan_range.end_bound().cloned(),
             watch,
@@ -1622,6 +2776,7 @@ mod tests {
 
         // 1. Populate the cache from heights 7 to 9.
         cbfmgr.rescan(
+            0,
             Bound::Included(birth),
             Bound::Included(best),
             watch.clone(),
@@ -1643,6 +2798,7 @@ mod tests {
         // 5. Trigger a rescan for the new range 6 to 8.
         // Nothing should be matched yet, since we don't have filter #6.
         let matched = cbfmgr.rescan(
+            0,
             rescan_range.start_bound().cloned(),
             rescan_range.end_bound().cloned(),
             watch,
@@ -1675,7 +2831,7 @@ mod tests {
         assert_matches!(
             events.next(),
             Some(Event::FilterProcessed {
-                height: 6,
+                block: Anchor { height: 6, .. },
                 matched: false,
                 cached: false,
                 ..
@@ -1684,7 +2840,7 @@ mod tests {
         assert_matches!(
             events.next(),
             Some(Event::FilterProcessed {
-                height: 7,
+                block: Anchor { height: 7, .. },
                 matched: true,
                 cached: true,
                 ..
@@ -1693,7 +2849,7 @@ mod tests {
         assert_matches!(
             events.next(),
             Some(Event::FilterProcessed {
-                height: 8,
+                block: Anchor { height: 8, .. },
                 matched: true,
                 cached: true,
                 ..
@@ -1736,6 +2892,7 @@ mod tests {
 
         // 1. Populate the cache from heights 5 to 8.
         cbfmgr.rescan(
+            0,
             Bound::Included(birth),
             Bound::Included(best),
             watch.clone(),
@@ -1770,6 +2927,7 @@ mod tests {
 
         // 5. Trigger a rescan for the new range 7 to 9
         let matched = cbfmgr.rescan(
+            0,
             rescan_range.start_bound().cloned(),
             rescan_range.end_bound().cloned(),
             watch,
@@ -1827,6 +2985,7 @@ mod tests {
         // 1. Populate the cache with height 6 and 8.
         for height in [6, 8] {
             cbfmgr.rescan(
+                0,
                 Bound::Included(height),
                 Bound::Included(height),
                 watch.clone(),
@@ -1842,7 +3001,7 @@ mod tests {
         cbfmgr.upstream.unregister(&remote);
 
         // 2. Request range 5 to 9.
-        let matched = cbfmgr.rescan(Bound::Included(5), Bound::Included(9), watch, &tree);
+        let matched = cbfmgr.rescan(0, Bound::Included(5), Bound::Included(9), watch, &tree);
         assert!(matched.is_empty());
 
         let mut events = util::events(cbfmgr.upstream.drain())
@@ -1870,10 +3029,10 @@ mod tests {
             assert_matches!(
                 events.next(),
                 Some(Event::FilterProcessed {
-                    height,
+                    block,
                     cached,
                     ..
-                }) if height == h && cached == c
+                }) if block.height == h && cached == c
             );
         }
     }
@@ -1904,6 +3063,7 @@ mod tests {
             &tree,
         );
         let matched = cbfmgr.rescan(
+            0,
             Bound::Included(birth),
             Bound::Unbounded,
             watch.clone(),
@@ -1922,6 +3082,7 @@ mod tests {
         // After a new rescan with a non-empty watchlist, the scripts are checked against the
         // cached filters.
         let matched = cbfmgr.rescan(
+            0,
             Bound::Included(birth),
             Bound::Unbounded,
             watch.clone(),
@@ -1998,7 +3159,7 @@ mod tests {
                 Link::Outbound,
                 &tree,
             );
-            cbfmgr.rescan(Bound::Included(birth), Bound::Unbounded, watch, &tree);
+            cbfmgr.rescan(0, Bound::Included(birth), Bound::Unbounded, watch, &tree);
 
             log::debug!(target: "test",
                 "Chain {:?}",
@@ -2104,7 +3265,7 @@ mod tests {
         let remote: PeerId = ([88, 88, 88, 88], 8333).into();
 
         let time = LocalTime::now();
-        let (mut cbfmgr, tree, chain) = util::setup(network, best, cache, time);
+        let (mut cbfmgr, tree, chain) = util::setup(network.clone(), best, cache, time);
         let tip = chain.last().block_hash();
 
         // Generate a watchlist and keep track of the matching block heights.
@@ -2122,7 +3283,7 @@ mod tests {
             Link::Outbound,
             &tree,
         );
-        cbfmgr.rescan(Bound::Included(birth), Bound::Unbounded, watch, &tree);
+        cbfmgr.rescan(0, Bound::Included(birth), Bound::Unbounded, watch, &tree);
 
         let mut msgs = output::test::messages(&mut cbfmgr.upstream, &remote);
         let mut events = util::events(cbfmgr.upstream.drain());
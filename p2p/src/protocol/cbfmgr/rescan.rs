@@ -3,20 +3,27 @@ use std::collections::BTreeSet;
 use std::ops::RangeInclusive;
 use std::rc::Rc;
 
+use microserde::json::Value;
+
 use nakamoto_common::bitcoin::util::bip158;
 use nakamoto_common::bitcoin::{Script, Txid};
 use nakamoto_common::block::filter::BlockFilter;
 use nakamoto_common::block::tree::BlockReader;
-use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::block::{Anchor, BlockHash, BlockHeader, Height};
 use nakamoto_common::collections::{HashMap, HashSet};
 
-use super::{Event, FilterCache, HeightIterator, MAX_MESSAGE_CFILTERS};
+use super::super::dump;
+use super::{Event, FilterCache, HeightIterator, RescanId, MAX_MESSAGE_CFILTERS};
 
 /// Filter (re)scan state.
 #[derive(Debug, Default)]
 pub struct Rescan {
+    /// Identifier of the current rescan.
+    pub id: RescanId,
     /// Whether a rescan is currently in progress.
     pub active: bool,
+    /// Whether the current rescan is paused. Paused rescans are not active.
+    pub paused: bool,
     /// Current height from which we're synced filters.
     /// Must be between `start` and `end`.
     pub current: Height,
@@ -34,7 +41,7 @@ pub struct Rescan {
     /// Filters requested and remaining to download.
     requested: BTreeSet<Height>,
     /// Received filters waiting to be matched.
-    received: HashMap<Height, (Rc<BlockFilter>, BlockHash, bool)>,
+    received: HashMap<Height, (Rc<BlockFilter>, BlockHeader, bool)>,
 }
 
 impl Rescan {
@@ -51,11 +58,14 @@ impl Rescan {
     /// Start or restart a rescan. Resets the request state.
     pub fn restart(
         &mut self,
+        id: RescanId,
         start: Height,
         end: Option<Height>,
         watch: impl IntoIterator<Item = Script>,
     ) {
+        self.id = id;
         self.active = true;
+        self.paused = false;
         self.start = start;
         self.current = start;
         self.end = end;
@@ -76,6 +86,29 @@ impl Rescan {
         )
     }
 
+    /// Dump the rescan state, for debugging. Watched scripts and transactions are only
+    /// counted.
+    pub fn dump(&self) -> Value {
+        Value::Object(dump::object([
+            ("id", dump::number(self.id)),
+            ("active", Value::Bool(self.active)),
+            ("paused", Value::Bool(self.paused)),
+            ("start", dump::number(self.start)),
+            ("current", dump::number(self.current)),
+            ("end", dump::optional(self.end, dump::number)),
+            ("watch", dump::number(self.watch.len())),
+            ("transactions", dump::number(self.transactions.len())),
+            ("cached", dump::number(self.cache.len())),
+            ("requested", dump::number(self.requested.len())),
+            ("received", dump::number(self.received.len())),
+        ]))
+    }
+
+    /// Check whether the filter at the given height was requested and not yet received.
+    pub fn is_requested(&self, height: Height) -> bool {
+        self.requested.contains(&height)
+    }
+
     /// Reset requested heights. This allows for requests to be re-issued.
     pub fn reset(&mut self) {
         self.requested.clear();
@@ -86,8 +119,8 @@ impl Rescan {
         self.cache.rollback(to)
     }
 
-    /// A filter was received.
-    pub fn received(&mut self, height: Height, filter: BlockFilter, block_hash: BlockHash) -> bool {
+    /// A filter was received for the given block.
+    pub fn received(&mut self, height: Height, filter: BlockFilter, block: BlockHeader) -> bool {
         let requested = self.requested.remove(&height);
         if requested {
             // We use a reference counted pointer here because it's possible for a filter to be
@@ -95,7 +128,7 @@ impl Rescan {
             let filter = Rc::new(filter);
 
             self.cache.push(height, filter.clone());
-            self.received.insert(height, (filter, block_hash, false));
+            self.received.insert(height, (filter, block, false));
         }
         requested
     }
@@ -110,7 +143,8 @@ impl Rescan {
         let mut current = self.current;
         let old = current;
 
-        while let Some((filter, block_hash, cached)) = self.received.remove(&current) {
+        while let Some((filter, block, cached)) = self.received.remove(&current) {
+            let block_hash = block.block_hash();
             let (matched, valid) = if let Ok(matched) = self.match_filter(&filter, &block_hash) {
                 (matched, true)
             } else {
@@ -121,8 +155,7 @@ impl Rescan {
                 matches.push((current, block_hash));
             }
             events.push(Event::FilterProcessed {
-                block: block_hash,
-                height: current,
+                block: Anchor::new(current, &block),
                 valid,
                 matched,
                 cached,
@@ -132,9 +165,14 @@ impl Rescan {
         self.current = current;
 
         if let Some(stop) = self.end {
-            if self.current == stop {
+            // Nb. `current` is the next height to process, so we're done once it's past
+            // the stop height.
+            if self.active && self.current > stop {
                 self.active = false;
-                events.push(Event::RescanCompleted { height: stop });
+                events.push(Event::RescanCompleted {
+                    id: self.id,
+                    height: stop,
+                });
             }
         }
 
@@ -178,10 +216,9 @@ impl Rescan {
         for height in range.clone() {
             if let Some(filter) = self.cache.get(&height) {
                 if let Some(header) = tree.get_block_by_height(height) {
-                    let block_hash = header.block_hash();
                     // Insert the cached filters into the processing queue.
                     self.received
-                        .insert(height, (filter.clone(), block_hash, true));
+                        .insert(height, (filter.clone(), *header, true));
                 }
             }
         }
@@ -0,0 +1,95 @@
+//! Protocol state dumps, for debugging.
+//!
+//! A dump is a JSON snapshot of the internal state of the protocol's sub-protocols, to be
+//! attached to bug reports, eg. when sync is stuck. Information that could identify the
+//! user or their wallet is redacted: peer addresses are replaced with pseudonyms that are
+//! consistent within a dump, and watched scripts and transactions are only counted.
+use std::collections::{BTreeMap, HashMap};
+
+use microserde::json::{Number, Object, Value};
+
+use nakamoto_common::block::time::LocalTime;
+
+use super::{Link, MessageUsage, PeerId};
+
+/// Replaces peer addresses with pseudonyms.
+#[derive(Debug, Default)]
+pub struct Redact {
+    peers: HashMap<PeerId, usize>,
+}
+
+impl Redact {
+    /// Pseudonym of a peer. The same peer always gets the same pseudonym.
+    pub fn peer(&mut self, addr: &PeerId) -> Value {
+        let next = self.peers.len();
+        let ix = *self.peers.entry(*addr).or_insert(next);
+
+        Value::String(format!("peer-{}", ix))
+    }
+}
+
+/// Build a JSON object from its fields.
+pub fn object<const N: usize>(fields: [(&str, Value); N]) -> Object {
+    fields.into_iter().map(|(k, v)| (k.to_owned(), v)).collect()
+}
+
+/// A JSON number.
+pub fn number(n: impl TryInto<u64>) -> Value {
+    Value::Number(Number::U64(n.try_into().unwrap_or(u64::MAX)))
+}
+
+/// A JSON string.
+pub fn string(s: impl ToString) -> Value {
+    Value::String(s.to_string())
+}
+
+/// A local time, as a number of milliseconds since the epoch.
+pub fn time(t: LocalTime) -> Value {
+    number((t - LocalTime::default()).as_millis())
+}
+
+/// A link direction.
+pub fn link(link: Link) -> Value {
+    match link {
+        Link::Inbound => string("inbound"),
+        Link::Outbound => string("outbound"),
+    }
+}
+
+/// Message statistics, per command.
+pub fn messages(stats: &BTreeMap<&'static str, MessageUsage>) -> Value {
+    Value::Object(
+        stats
+            .iter()
+            .map(|(cmd, usage)| {
+                let usage = object([
+                    ("count", number(usage.count)),
+                    ("bytes", number(usage.bytes)),
+                    ("last", optional(usage.last, time)),
+                ]);
+                (cmd.to_string(), Value::Object(usage))
+            })
+            .collect(),
+    )
+}
+
+/// An optional value, or `null`.
+pub fn optional<T>(v: Option<T>, f: impl FnOnce(T) -> Value) -> Value {
+    v.map_or(Value::Null, f)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let mut redact = Redact::default();
+        let a = ([88, 88, 88, 1], 8333).into();
+        let b = ([88, 88, 88, 2], 8333).into();
+
+        assert!(matches!(redact.peer(&a), Value::String(s) if s == "peer-0"));
+        assert!(matches!(redact.peer(&b), Value::String(s) if s == "peer-1"));
+        assert!(matches!(redact.peer(&a), Value::String(s) if s == "peer-0"));
+    }
+}
@@ -1,10 +1,21 @@
 //! Protocol events.
+//!
+//! Events are delivered to a [`Publisher`]. Besides broadcast channels, publishers are
+//! provided for channel senders, callbacks, eg. to forward events over FFI, and writers of
+//! JSON lines, eg. files or unix sockets. Any publisher can be restricted to a subset of
+//! events with [`Publisher::filter`].
+use std::fmt;
+use std::io::{self, Write};
 use std::net;
 
+use crossbeam_channel as chan;
+use microserde::json::{Object, Value};
+
 use nakamoto_common::bitcoin::network::message::NetworkMessage;
 
 use crate::event::Broadcast;
-use crate::protocol::{self, Height, LocalTime, PeerId};
+use crate::protocol::{self, Height, LocalDuration, LocalTime, PeerId};
+use crate::traits::Step;
 
 /// A peer-to-peer event.
 #[derive(Debug, Clone)]
@@ -34,12 +45,102 @@ pub enum Event {
     Filter(protocol::FilterEvent),
     /// An inventory manager event.
     Inventory(protocol::InventoryEvent),
+    /// The protocol configuration was updated at runtime.
+    ConfigUpdated(protocol::ConfigUpdate),
+    /// A chain reindex event.
+    Reindex(protocol::ReindexEvent),
+    /// A network partition event.
+    Resync(protocol::ResyncEvent),
+    /// A reactor iteration took longer than the configured threshold, during which no
+    /// other input was processed.
+    SlowStep {
+        /// Time spent in the iteration, excluding time waiting for I/O.
+        elapsed: LocalDuration,
+        /// The slowest step of the iteration.
+        step: Step,
+        /// Time spent in the slowest step.
+        step_elapsed: LocalDuration,
+    },
+    /// A sync burst ended. Peers were disconnected and timers are suspended until the
+    /// next burst, or until network activity is enabled.
+    Quiesced,
+}
+
+impl Event {
+    /// The kind of event, eg. `chain` for sync manager events.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Initializing => "initializing",
+            Self::Ready { .. } => "ready",
+            Self::Listening(_) => "listening",
+            Self::Received(_, _) => "received",
+            Self::Address(_) => "address",
+            Self::Chain(_) => "chain",
+            Self::Peer(_) => "peer",
+            Self::Filter(_) => "filter",
+            Self::Inventory(_) => "inventory",
+            Self::ConfigUpdated(_) => "config",
+            Self::Reindex(_) => "reindex",
+            Self::Resync(_) => "resync",
+            Self::SlowStep { .. } => "slow-step",
+            Self::Quiesced => "quiesced",
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Initializing => write!(fmt, "Initializing.."),
+            Self::Ready {
+                height,
+                filter_height,
+                ..
+            } => write!(
+                fmt,
+                "Ready with height = {}, filter height = {}",
+                height, filter_height
+            ),
+            Self::Listening(addr) => write!(fmt, "Listening on {}", addr),
+            Self::Received(addr, msg) => write!(fmt, "{}: Received `{}`", addr, msg.cmd()),
+            Self::Address(e) => write!(fmt, "{}", e),
+            Self::Chain(e) => write!(fmt, "{}", e),
+            Self::Peer(e) => write!(fmt, "{}", e),
+            Self::Filter(e) => write!(fmt, "{}", e),
+            Self::Inventory(e) => write!(fmt, "{}", e),
+            Self::ConfigUpdated(update) => write!(fmt, "Configuration updated: {:?}", update),
+            Self::Reindex(e) => write!(fmt, "{}", e),
+            Self::Resync(e) => write!(fmt, "{}", e),
+            Self::SlowStep {
+                elapsed,
+                step,
+                step_elapsed,
+            } => write!(
+                fmt,
+                "Reactor iteration took {} (slowest step `{}` took {})",
+                elapsed, step, step_elapsed
+            ),
+            Self::Quiesced => write!(fmt, "Sync burst ended, network activity suspended"),
+        }
+    }
 }
 
 /// Any type that is able to publish events.
 pub trait Publisher: Send + Sync {
     /// Publish an event.
     fn publish(&mut self, event: Event);
+
+    /// Only publish the events for which the given predicate returns `true`.
+    fn filter<F>(self, predicate: F) -> Filter<Self, F>
+    where
+        F: FnMut(&Event) -> bool + Send + Sync,
+        Self: Sized,
+    {
+        Filter {
+            publisher: self,
+            predicate,
+        }
+    }
 }
 
 impl<T: Clone + Send + Sync> Publisher for Broadcast<Event, T> {
@@ -48,3 +149,145 @@ impl<T: Clone + Send + Sync> Publisher for Broadcast<Event, T> {
         self.broadcast(event)
     }
 }
+
+impl Publisher for chan::Sender<Event> {
+    /// Send the event on the channel. Events are dropped if the channel is full or
+    /// disconnected.
+    fn publish(&mut self, event: Event) {
+        self.try_send(event).ok();
+    }
+}
+
+impl Publisher for Box<dyn Publisher> {
+    fn publish(&mut self, event: Event) {
+        self.as_mut().publish(event)
+    }
+}
+
+/// A publisher that only publishes the events matching a predicate.
+/// See [`Publisher::filter`].
+#[derive(Debug)]
+pub struct Filter<P, F> {
+    publisher: P,
+    predicate: F,
+}
+
+impl<P, F> Publisher for Filter<P, F>
+where
+    P: Publisher,
+    F: FnMut(&Event) -> bool + Send + Sync,
+{
+    fn publish(&mut self, event: Event) {
+        if (self.predicate)(&event) {
+            self.publisher.publish(event);
+        }
+    }
+}
+
+/// A publisher that calls a function with every event.
+#[derive(Debug)]
+pub struct Callback<F>(pub F);
+
+impl<F: FnMut(&Event) + Send + Sync> Publisher for Callback<F> {
+    fn publish(&mut self, event: Event) {
+        (self.0)(&event)
+    }
+}
+
+/// A publisher writing events as JSON objects, one per line, with the event's `type`,
+/// and a human-readable `message`.
+///
+/// Writing stops after the first error.
+#[derive(Debug)]
+pub struct JsonLines<W> {
+    writer: Option<W>,
+}
+
+impl<W: Write + Send + Sync> JsonLines<W> {
+    /// Create a new publisher writing to the given writer.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Some(writer),
+        }
+    }
+
+    /// Get the underlying writer, unless writing failed.
+    pub fn get_ref(&self) -> Option<&W> {
+        self.writer.as_ref()
+    }
+}
+
+impl JsonLines<std::fs::File> {
+    /// Append events to the file at the given path, creating it if necessary.
+    pub fn append(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map(Self::new)
+    }
+}
+
+#[cfg(unix)]
+impl JsonLines<std::os::unix::net::UnixStream> {
+    /// Write events to the unix socket at the given path.
+    pub fn connect(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        std::os::unix::net::UnixStream::connect(path).map(Self::new)
+    }
+}
+
+impl<W: Write + Send + Sync> Publisher for JsonLines<W> {
+    fn publish(&mut self, event: Event) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+        let mut object = Object::new();
+
+        object.insert("type".to_owned(), Value::String(event.kind().to_owned()));
+        object.insert("message".to_owned(), Value::String(event.to_string()));
+
+        let line = microserde::json::to_string(&Value::Object(object)) + "\n";
+
+        if let Err(err) = writer.write_all(line.as_bytes()) {
+            log::error!("Failed to publish event, stopping: {}", err);
+            self.writer = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_publishers() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (sender, receiver) = chan::unbounded();
+        let mut publishers: Vec<Box<dyn Publisher>> = vec![
+            Box::new(sender.filter(|e| matches!(e, Event::Listening(_)))),
+            Box::new(Callback({
+                let events = events.clone();
+                move |e: &Event| events.lock().unwrap().push(e.kind())
+            })),
+            Box::new(JsonLines::new(Vec::new())),
+        ];
+
+        for publisher in publishers.iter_mut() {
+            publisher.publish(Event::Initializing);
+            publisher.publish(Event::Listening(([127, 0, 0, 1], 8333).into()));
+        }
+
+        assert!(matches!(receiver.try_recv(), Ok(Event::Listening(_))));
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(*events.lock().unwrap(), vec!["initializing", "listening"]);
+
+        let mut lines = JsonLines::new(Vec::new());
+        lines.publish(Event::Listening(([127, 0, 0, 1], 8333).into()));
+        assert_eq!(
+            String::from_utf8(lines.get_ref().unwrap().clone()).unwrap(),
+            "{\"message\":\"Listening on 127.0.0.1:8333\",\"type\":\"listening\"}\n"
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! Negotiation of optional protocol features.
+//!
+//! Some protocol features are enabled per connection, by sending a signalling message,
+//! eg. `wtxidrelay` (BIP 339) or `sendaddrv2` (BIP 155). Such features are only signalled
+//! to peers whose protocol version supports them, and are only used with a peer if both
+//! sides signalled them. Handshake features must be signalled between `version` and
+//! `verack`, while other features may be signalled any time after the handshake.
+use std::fmt;
+
+use nakamoto_common::bitcoin::network::message::{CommandString, NetworkMessage};
+
+/// Compact block relay version we signal. Version 2 includes witness data.
+const COMPACT_BLOCKS_VERSION: u64 = 2;
+
+/// An optional protocol feature.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Feature {
+    /// Transaction announcements by witness transaction id. See BIP 339.
+    WtxidRelay,
+    /// Version 2 address messages. See BIP 155.
+    AddrV2,
+    /// Compact block relay. See BIP 152.
+    CompactBlocks,
+}
+
+impl Feature {
+    /// All known features.
+    pub const ALL: [Feature; 3] = [Self::WtxidRelay, Self::AddrV2, Self::CompactBlocks];
+
+    /// Minimum protocol version a peer must advertise for the feature to be signalled.
+    pub fn min_version(&self) -> u32 {
+        match self {
+            Self::WtxidRelay | Self::AddrV2 => 70016,
+            Self::CompactBlocks => 70014,
+        }
+    }
+
+    /// Whether the feature must be signalled during the handshake, before `verack`.
+    pub fn is_handshake(&self) -> bool {
+        match self {
+            Self::WtxidRelay | Self::AddrV2 => true,
+            Self::CompactBlocks => false,
+        }
+    }
+
+    /// The message used to signal this feature.
+    pub fn message(&self) -> NetworkMessage {
+        match self {
+            Self::WtxidRelay => NetworkMessage::WtxidRelay,
+            Self::AddrV2 => NetworkMessage::SendAddrV2,
+            Self::CompactBlocks => {
+                // We never ask peers to announce blocks with compact blocks.
+                let mut payload = vec![0];
+                payload.extend_from_slice(&COMPACT_BLOCKS_VERSION.to_le_bytes());
+
+                NetworkMessage::Unknown {
+                    command: CommandString::try_from("sendcmpct").unwrap(),
+                    payload,
+                }
+            }
+        }
+    }
+
+    /// Get the feature signalled by a message, if any.
+    pub fn from_message(msg: &NetworkMessage) -> Option<Self> {
+        match msg {
+            NetworkMessage::WtxidRelay => Some(Self::WtxidRelay),
+            NetworkMessage::SendAddrV2 => Some(Self::AddrV2),
+            NetworkMessage::Unknown { command, payload }
+                if command.as_ref() == "sendcmpct" && payload.len() == 9 =>
+            {
+                Some(Self::CompactBlocks)
+            }
+            _ => None,
+        }
+    }
+
+    fn bit(&self) -> u8 {
+        match self {
+            Self::WtxidRelay => 1 << 0,
+            Self::AddrV2 => 1 << 1,
+            Self::CompactBlocks => 1 << 2,
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WtxidRelay => write!(f, "wtxidrelay"),
+            Self::AddrV2 => write!(f, "addrv2"),
+            Self::CompactBlocks => write!(f, "cmpctblock"),
+        }
+    }
+}
+
+/// A set of protocol features.
+#[derive(Default, Copy, Clone, PartialEq, Eq)]
+pub struct Features(u8);
+
+impl Features {
+    /// The empty set.
+    pub const NONE: Features = Features(0);
+
+    /// Check whether a feature is in the set.
+    pub fn has(&self, feature: Feature) -> bool {
+        self.0 & feature.bit() != 0
+    }
+
+    /// Add a feature to the set.
+    pub fn insert(&mut self, feature: Feature) {
+        self.0 |= feature.bit();
+    }
+
+    /// Get the features that are in both sets.
+    pub fn intersection(&self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+
+    /// Iterate over the features in the set.
+    pub fn iter(&self) -> impl Iterator<Item = Feature> + '_ {
+        Feature::ALL.into_iter().filter(|f| self.has(*f))
+    }
+}
+
+impl From<Feature> for Features {
+    fn from(feature: Feature) -> Self {
+        Self(feature.bit())
+    }
+}
+
+impl FromIterator<Feature> for Features {
+    fn from_iter<I: IntoIterator<Item = Feature>>(iter: I) -> Self {
+        let mut features = Features::NONE;
+        for feature in iter {
+            features.insert(feature);
+        }
+        features
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == Features::NONE {
+            return write!(f, "none");
+        }
+        let names = self.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+
+        write!(f, "{}", names.join(","))
+    }
+}
+
+impl fmt::Debug for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Features({})", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signalling_messages() {
+        for feature in Feature::ALL {
+            assert_eq!(Feature::from_message(&feature.message()), Some(feature));
+        }
+        assert_eq!(Feature::from_message(&NetworkMessage::Verack), None);
+    }
+
+    #[test]
+    fn test_features() {
+        let ours = Features::from(Feature::WtxidRelay);
+        let theirs = [Feature::WtxidRelay, Feature::CompactBlocks]
+            .into_iter()
+            .collect::<Features>();
+
+        assert!(theirs.has(Feature::CompactBlocks));
+        assert!(!theirs.has(Feature::AddrV2));
+        assert_eq!(ours.intersection(theirs), ours);
+        assert_eq!(theirs.to_string(), "wtxidrelay,cmpctblock");
+        assert_eq!(Features::NONE.to_string(), "none");
+    }
+}
@@ -0,0 +1,94 @@
+//! Peer identities.
+//!
+//! Plain P2P connections are unauthenticated: anyone in a position to intercept our
+//! connection to a peer can impersonate it. When connecting to our own full node, eg. the
+//! [trusted peer](super::Config::trusted_peer), the connection can be authenticated by the
+//! transport, for example by an encrypted tunnel such as SSH or TLS, or by a BIP 324 session.
+//! The transport then reports the fingerprint of the identity it authenticated the peer
+//! with, which is checked against the fingerprint pinned for the peer. See
+//! [`Protocol::authenticated`](crate::traits::Protocol::authenticated).
+use std::fmt;
+use std::str::FromStr;
+
+use nakamoto_common::bitcoin_hashes::hex::{FromHex, ToHex};
+use thiserror::Error;
+
+/// Error parsing a fingerprint.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseFingerprintError {
+    /// The fingerprint isn't valid hex.
+    #[error("fingerprint is not a valid hex string")]
+    Hex,
+    /// The fingerprint doesn't have the right length.
+    #[error("fingerprint must be {} bytes, got {0}", Fingerprint::LENGTH)]
+    Length(usize),
+}
+
+/// Fingerprint of a peer identity, eg. the SHA-256 hash of a tunnel's public key or
+/// certificate, or of a BIP 324 session id.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Fingerprint([u8; Self::LENGTH]);
+
+impl Fingerprint {
+    /// Length of a fingerprint, in bytes.
+    pub const LENGTH: usize = 32;
+
+    /// Create a fingerprint from its bytes.
+    pub fn new(bytes: [u8; Self::LENGTH]) -> Self {
+        Self(bytes)
+    }
+
+    /// Get the bytes of the fingerprint.
+    pub fn as_bytes(&self) -> &[u8; Self::LENGTH] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.to_hex())
+    }
+}
+
+impl FromStr for Fingerprint {
+    type Err = ParseFingerprintError;
+
+    /// Parse a hex-encoded fingerprint. Bytes may be separated by colons, as is common for
+    /// certificate fingerprints, eg. `4f:a2:...`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.replace(':', "");
+        let bytes = Vec::<u8>::from_hex(&hex).map_err(|_| ParseFingerprintError::Hex)?;
+        let bytes = <[u8; Self::LENGTH]>::try_from(bytes.as_slice())
+            .map_err(|_| ParseFingerprintError::Length(bytes.len()))?;
+
+        Ok(Self(bytes))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint_parse() {
+        let fingerprint = Fingerprint::new([0xab; 32]);
+        let hex = fingerprint.to_string();
+        let colons = hex
+            .as_bytes()
+            .chunks(2)
+            .map(|c| std::str::from_utf8(c).unwrap())
+            .collect::<Vec<_>>()
+            .join(":");
+
+        assert_eq!(hex.parse(), Ok(fingerprint));
+        assert_eq!(colons.to_uppercase().parse(), Ok(fingerprint));
+        assert_eq!(
+            "abab".parse::<Fingerprint>(),
+            Err(ParseFingerprintError::Length(2))
+        );
+        assert_eq!(
+            "xyz".parse::<Fingerprint>(),
+            Err(ParseFingerprintError::Hex)
+        );
+    }
+}
@@ -19,20 +19,63 @@
 //! the [`InventoryManager::received_wake`] function is called. Confirmed transactions are removed
 //! after they are burried at a certain depth.
 //!
-use std::collections::BTreeMap;
+//! ## Witness transaction ids
+//!
+//! Transactions are tracked by [`Wtxid`], and announced by `wtxid` to peers that negotiated
+//! BIP 339 (`wtxidrelay`), and by [`Txid`] to all other peers. Since the witness of a transaction
+//! can be malleated without changing its `txid`, transactions are matched by `txid` when they
+//! are confirmed: a malleated version of one of our transactions confirms it all the same.
+//! Likewise, announcing a transaction with the same `txid` as a transaction already in the
+//! mempool replaces it.
+//!
+//! ## Packages
+//!
+//! Transactions that depend on each other, eg. a parent and a child paying for it (CPFP), can
+//! be submitted together as a package with [`InventoryManager::announce_package`]. Parents are
+//! always announced and served before their children, and all transactions of a package are
+//! re-announced together when a broadcast is retried, so that peers never end up with an
+//! orphaned child.
+//!
+//! ## Replacements
+//!
+//! A transaction in the mempool can be replaced by a conflicting transaction, eg. to bump its
+//! fee (RBF), with [`InventoryManager::replace`]. The original transaction is then no longer
+//! announced, but since it may still be confirmed instead of its replacement, it is kept
+//! track of until either version is confirmed. At that point, the other versions are
+//! reported as stale.
+//!
+//! ## Mempool prefetch
+//!
+//! When [`Config::mempool_prefetch`] is set, a bloom filter matching the watched scripts is
+//! loaded on the trusted peer, and the peer is asked for the matching transactions in its
+//! mempool, with `mempool`. The peer then also announces matching transactions as they enter
+//! its mempool. Matches are reported with [`Event::MempoolMatched`], so that incoming
+//! payments can be shown before they are confirmed.
+//!
+//! This reveals the watched scripts to the trusted peer, and unconfirmed transactions
+//! may never be confirmed: they can be double-spent, or replaced by the sender.
+//!
+use std::collections::{BTreeMap, HashSet};
 
+use thiserror::Error;
+
+use nakamoto_common::bitcoin::network::message_bloom::FilterLoad;
 use nakamoto_common::bitcoin::network::{constants::ServiceFlags, message_blockdata::Inventory};
-use nakamoto_common::bitcoin::{Block, BlockHash, Transaction, Txid, Wtxid};
+use nakamoto_common::bitcoin::util::merkleblock::MerkleBlock;
+use nakamoto_common::bitcoin::{Block, BlockHash, OutPoint, Script, Transaction, Txid, Wtxid};
 
 // TODO: Timeout should be configurable
 // TODO: Add exponential back-off
 
 use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
 use nakamoto_common::block::tree::BlockReader;
+use nakamoto_common::block::Anchor;
 use nakamoto_common::collections::{AddressBook, HashMap};
 
+use super::bloom::BloomFilter;
 use super::fees::{FeeEstimate, FeeEstimator};
 use super::output::Wakeup;
+use super::scheduler::{Cadence, Scheduler};
 use super::{Height, PeerId, Socket};
 
 /// Time between re-broadcasts of inventories.
@@ -50,6 +93,28 @@ pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
 /// Block depth at which confirmed transactions are pruned and no longer reverted after a re-org.
 pub const TRANSACTION_PRUNE_DEPTH: Height = 12;
 
+/// Period over which the decoy block download budget is measured.
+pub const DECOY_BUDGET_PERIOD: LocalDuration = LocalDuration::from_mins(60);
+
+/// Default number of bytes of decoy blocks that can be downloaded per budget period.
+pub const DEFAULT_DECOY_BUDGET: usize = 1024 * 1024 * 64;
+
+/// Time after which we stop waiting for a decoy block.
+pub const DECOY_TIMEOUT: LocalDuration = LocalDuration::from_mins(2);
+
+/// Time after which we stop waiting for the filter of a block fetched ahead of it.
+pub const PREFETCH_TIMEOUT: LocalDuration = LocalDuration::from_mins(2);
+
+/// False positive rate of the bloom filter loaded on the trusted peer for mempool prefetch.
+pub const MEMPOOL_FILTER_FP_RATE: f64 = 0.0001;
+
+/// Maximum number of mempool transactions announced by the trusted peer that are remembered,
+/// so that they aren't requested twice.
+pub const MAX_MEMPOOL_SEEN: usize = 4096;
+
+/// Maximum number of transactions in a package.
+pub const MAX_PACKAGE_COUNT: usize = 25;
+
 /// The ability to send and receive inventory data.
 pub trait Inventories {
     /// Sends an `inv` message to a peer.
@@ -58,6 +123,10 @@ pub trait Inventories {
     fn getdata(&mut self, addr: PeerId, inventories: Vec<Inventory>);
     /// Sends a `tx` message to a peer.
     fn tx(&mut self, addr: PeerId, tx: Transaction);
+    /// Sends a `filterload` message to a peer.
+    fn filterload(&mut self, addr: PeerId, filter: FilterLoad);
+    /// Sends a `mempool` message to a peer.
+    fn mempool(&mut self, addr: PeerId);
     /// Fire an event.
     fn event(&self, event: Event);
 }
@@ -92,10 +161,26 @@ pub enum Event {
     Confirmed {
         /// The confirmed transaction.
         transaction: Transaction, // TODO: Just the txid?
-        /// The height at which it was confirmed.
-        height: Height,
         /// The block in which it was confirmed.
-        block: BlockHash,
+        block: Anchor,
+    },
+    /// A transaction was replaced by a conflicting transaction, and is no longer announced.
+    /// Either transaction may still be confirmed.
+    Replaced {
+        /// The replaced transaction ID.
+        txid: Txid,
+        /// The replacement transaction ID.
+        replaced_by: Txid,
+    },
+    /// A replaced transaction, or a replacement, can no longer be confirmed, because a
+    /// conflicting version was confirmed.
+    Stale {
+        /// The stale transaction ID.
+        txid: Txid,
+        /// The confirmed transaction ID.
+        replaced_by: Txid,
+        /// The block in which the confirmed transaction was included.
+        block: Anchor,
     },
     /// A transaction was reverted.
     Reverted {
@@ -107,6 +192,21 @@ pub enum Event {
         /// Peer who timed out.
         peer: PeerId,
     },
+    /// An unconfirmed transaction paying to a watched script was found in the trusted
+    /// peer's mempool. It may never be confirmed.
+    MempoolMatched {
+        /// The unconfirmed transaction.
+        transaction: Transaction,
+        /// The peer it was received from.
+        peer: PeerId,
+    },
+    /// Block downloads are paused, because the trusted peer is not connected.
+    TrustedPeerUnavailable {
+        /// The trusted peer.
+        peer: PeerId,
+        /// Number of blocks waiting to be downloaded.
+        remaining: usize,
+    },
 }
 
 impl std::fmt::Display for Event {
@@ -125,21 +225,114 @@ impl std::fmt::Display for Event {
                     txid, peer
                 )
             }
-            Event::Confirmed {
-                transaction,
-                height,
-                block,
-            } => write!(
+            Event::Confirmed { transaction, block } => write!(
                 fmt,
                 "Transaction {} was included in block #{} ({})",
                 transaction.txid(),
-                height,
+                block.height,
+                block.hash,
+            ),
+            Event::Replaced { txid, replaced_by } => {
+                write!(fmt, "Transaction {} was replaced by {}", txid, replaced_by)
+            }
+            Event::Stale {
+                txid,
+                replaced_by,
                 block,
+            } => write!(
+                fmt,
+                "Transaction {} is stale, {} was included in block {}",
+                txid, replaced_by, block.hash
             ),
             Event::Reverted { transaction, .. } => {
                 write!(fmt, "Transaction {} was reverted", transaction.txid(),)
             }
             Event::TimedOut { peer } => write!(fmt, "Peer {} timed out", peer),
+            Event::MempoolMatched { transaction, peer } => write!(
+                fmt,
+                "Unconfirmed transaction {} matched in the mempool of {}",
+                transaction.txid(),
+                peer
+            ),
+            Event::TrustedPeerUnavailable { peer, remaining } => write!(
+                fmt,
+                "Trusted peer {} is unavailable, pausing download of {} block(s)",
+                peer, remaining
+            ),
+        }
+    }
+}
+
+/// An error submitting a transaction package.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum PackageError {
+    /// The package has no transactions.
+    #[error("package is empty")]
+    Empty,
+    /// The package has too many transactions.
+    #[error("package has more than {MAX_PACKAGE_COUNT} transactions")]
+    TooLarge,
+    /// The package contains the same transaction more than once.
+    #[error("transaction {0} is included more than once")]
+    Duplicate(Txid),
+    /// Two transactions of the package spend the same output.
+    #[error("output {0} is spent by more than one transaction")]
+    Conflict(OutPoint),
+}
+
+/// An error replacing a transaction.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ReplaceError {
+    /// The transaction to replace is not in the mempool.
+    #[error("transaction {0} is not in the mempool")]
+    UnknownTransaction(Txid),
+    /// The replacement doesn't spend any of the outputs spent by the original transaction.
+    #[error("replacement doesn't conflict with transaction {0}")]
+    NotConflicting(Txid),
+}
+
+/// Status of a transaction submitted as part of a package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxStatus {
+    /// The transaction was added to the mempool and is announced to peers.
+    Announced,
+    /// The transaction was recently confirmed at the given height, and is not announced.
+    Confirmed(Height),
+}
+
+/// Inventory manager configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Peer to download blocks from. When set, blocks are never requested from any
+    /// other peer, since block requests reveal which transactions we're interested in.
+    pub trusted_peer: Option<PeerId>,
+    /// Number of random decoy blocks to download for every block requested, to obscure
+    /// which blocks we're interested in. Decoys are preferably requested from other peers
+    /// than the one the real block was requested from.
+    pub decoys: usize,
+    /// Number of bytes of decoy blocks that can be downloaded per [`DECOY_BUDGET_PERIOD`].
+    /// Since block sizes aren't known in advance, this budget may be exceeded by up to
+    /// one round of decoy requests.
+    pub decoy_budget: usize,
+    /// How often to rebroadcast transactions and retry block requests.
+    pub rebroadcast: Cadence,
+    /// Fetch new blocks at the tip from the peer that announced them, before their filter
+    /// is matched. See [`InventoryManager::prefetch_block`].
+    pub direct_fetch: bool,
+    /// Ask the trusted peer for unconfirmed transactions paying to watched scripts. Requires
+    /// a trusted peer that supports bloom filters. See the module documentation for caveats.
+    pub mempool_prefetch: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            trusted_peer: None,
+            decoys: 0,
+            decoy_budget: DEFAULT_DECOY_BUDGET,
+            rebroadcast: Cadence::every(IDLE_TIMEOUT),
+            direct_fetch: true,
+            mempool_prefetch: false,
         }
     }
 }
@@ -151,7 +344,7 @@ pub struct Peer {
     pub relay: bool,
     /// Peer announced services.
     pub services: ServiceFlags,
-    /// Does this peer use BIP-339?
+    /// Does this peer use BIP-339? If not, transactions are announced and requested by `txid`.
     pub wtxidrelay: bool,
 
     /// Inventories we are attempting to send to this peer.
@@ -189,6 +382,8 @@ impl Peer {
 /// Inventory manager state.
 #[derive(Debug)]
 pub struct InventoryManager<U, C> {
+    /// Manager configuration.
+    config: Config,
     /// Peer map.
     peers: AddressBook<PeerId, Peer>,
     /// Timeout used for retrying broadcasts.
@@ -196,18 +391,51 @@ pub struct InventoryManager<U, C> {
     /// Confirmed transactions by block height.
     /// Pruned after a certain depth.
     confirmed: HashMap<Height, Vec<Transaction>>,
+    /// Merkle proofs of confirmed transactions, with the height of their block.
+    /// Pruned along with confirmed transactions.
+    proofs: HashMap<Txid, (Height, MerkleBlock)>,
 
     /// Transaction fee estimator.
     estimator: FeeEstimator,
 
     /// Transaction mempool. Stores unconfirmed transactions sent to the network.
     pub mempool: BTreeMap<Wtxid, Transaction>,
+    /// Mempool transactions indexed by `txid`.
+    txids: HashMap<Txid, Wtxid>,
+    /// Transactions submitted as part of a package, and all the transactions of their package.
+    packages: HashMap<Wtxid, Vec<Wtxid>>,
+    /// Transactions we replaced, with the `txid` of their replacement. These are kept until
+    /// either version is confirmed.
+    replaced: HashMap<Txid, (Transaction, Txid)>,
     /// Blocks requested and the time at which they were last requested.
     pub remaining: HashMap<BlockHash, Option<LocalTime>>,
     /// Blocks received, waiting to be processed.
     pub received: HashMap<Height, Block>,
-
-    last_tick: Option<LocalTime>,
+    /// Whether block downloads are paused, waiting for the trusted peer.
+    paused: bool,
+    /// Whether speculative downloads, ie. block prefetches, decoys and mempool
+    /// transactions, are deferred.
+    deferred: bool,
+    /// Peers from which we received the filter that matched a requested block. To avoid a
+    /// single peer linking a filter match to a block download, we try not to request the
+    /// block from these.
+    filter_peers: HashMap<BlockHash, PeerId>,
+    /// Decoy blocks requested and the time at which they were requested.
+    decoys: HashMap<BlockHash, LocalTime>,
+    /// Start of the current decoy budget period, and bytes downloaded during it.
+    decoy_usage: (LocalTime, usize),
+    /// Blocks fetched ahead of their filter, and the time at which they were requested.
+    prefetching: HashMap<BlockHash, LocalTime>,
+    /// Blocks fetched ahead of their filter, waiting for it to match. Includes the time at
+    /// which they were received, and the peer they were received from.
+    prefetched: HashMap<BlockHash, (LocalTime, PeerId, Block)>,
+    /// Scripts matched by the bloom filter loaded on the trusted peer.
+    mempool_watch: HashSet<Script>,
+    /// Transactions announced by the trusted peer, that were requested.
+    mempool_seen: HashSet<Inventory>,
+
+    /// Periodic tasks.
+    schedule: Scheduler,
     rng: fastrand::Rng,
     upstream: U,
     clock: C,
@@ -215,16 +443,33 @@ pub struct InventoryManager<U, C> {
 
 impl<U: Inventories + Wakeup, C: Clock> InventoryManager<U, C> {
     /// Create a new inventory manager.
-    pub fn new(rng: fastrand::Rng, upstream: U, clock: C) -> Self {
+    pub fn new(config: Config, rng: fastrand::Rng, upstream: U, clock: C) -> Self {
+        let mut schedule = Scheduler::new(rng.clone());
+        schedule.schedule("rebroadcast", config.rebroadcast);
+
         Self {
+            config,
             peers: AddressBook::new(rng.clone()),
             mempool: BTreeMap::new(),
+            txids: HashMap::with_hasher(rng.clone().into()),
+            packages: HashMap::with_hasher(rng.clone().into()),
+            replaced: HashMap::with_hasher(rng.clone().into()),
             estimator: FeeEstimator::default(),
             confirmed: HashMap::with_hasher(rng.clone().into()),
+            proofs: HashMap::with_hasher(rng.clone().into()),
             remaining: HashMap::with_hasher(rng.clone().into()),
             received: HashMap::with_hasher(rng.clone().into()),
             timeout: REBROADCAST_TIMEOUT,
-            last_tick: None,
+            paused: false,
+            deferred: false,
+            filter_peers: HashMap::with_hasher(rng.clone().into()),
+            decoys: HashMap::with_hasher(rng.clone().into()),
+            decoy_usage: (LocalTime::default(), 0),
+            prefetching: HashMap::with_hasher(rng.clone().into()),
+            prefetched: HashMap::with_hasher(rng.clone().into()),
+            mempool_watch: HashSet::new(),
+            mempool_seen: HashSet::new(),
+            schedule,
             rng,
             upstream,
             clock,
@@ -243,6 +488,23 @@ impl<U: Inventories + Wakeup, C: Clock> InventoryManager<U, C> {
         self.mempool.contains_key(wtxid)
     }
 
+    /// Get the height at which a transaction was confirmed, if it was recently confirmed.
+    /// Transactions confirmed deeper than [`TRANSACTION_PRUNE_DEPTH`] are forgotten.
+    pub fn confirmation(&self, txid: &Txid) -> Option<Height> {
+        self.confirmed
+            .iter()
+            .find(|(_, txs)| txs.iter().any(|t| t.txid() == *txid))
+            .map(|(height, _)| *height)
+    }
+
+    /// Get the merkle proof of a recently confirmed transaction, along with the height of
+    /// the block it was confirmed in. See [`InventoryManager::confirmation`].
+    pub fn proof(&self, txid: &Txid) -> Option<(Height, &MerkleBlock)> {
+        self.proofs
+            .get(txid)
+            .map(|(height, proof)| (*height, proof))
+    }
+
     /// Called when a peer is negotiated.
     pub fn peer_negotiated(
         &mut self,
@@ -256,9 +518,11 @@ impl<U: Inventories + Wakeup, C: Clock> InventoryManager<U, C> {
         for (wtxid, tx) in self.mempool.iter() {
             outbox.insert(*wtxid, tx.txid());
         }
+        let addr = socket.addr;
+
         self.schedule_tick();
         self.peers.insert(
-            socket.addr,
+            addr,
             Peer {
                 services,
                 attempts: 0,
@@ -270,6 +534,9 @@ impl<U: Inventories + Wakeup, C: Clock> InventoryManager<U, C> {
                 _socket: socket,
             },
         );
+        if self.config.trusted_peer == Some(addr) {
+            self.load_mempool_filter(addr);
+        }
     }
 
     /// Called when a peer disconnected.
@@ -280,6 +547,7 @@ impl<U: Inventories + Wakeup, C: Clock> InventoryManager<U, C> {
     /// Called when a block is reverted.
     pub fn block_reverted(&mut self, height: Height) -> Vec<Transaction> {
         self.estimator.rollback(height - 1);
+        self.proofs.retain(|_, (h, _)| *h != height);
 
         if let Some(transactions) = self.confirmed.remove(&height) {
             for tx in transactions.iter().cloned() {
@@ -298,9 +566,8 @@ impl<U: Inventories + Wakeup, C: Clock> InventoryManager<U, C> {
     pub fn received_wake<T: BlockReader>(&mut self, tree: &T) {
         let now = self.clock.local_time();
         // Rate-limit how much we run this function.
-        if now - self.last_tick.unwrap_or_default() >= IDLE_TIMEOUT {
-            self.last_tick = Some(now);
-            self.upstream.wakeup(IDLE_TIMEOUT);
+        if let Some(next) = self.schedule.due(&"rebroadcast", now) {
+            self.upstream.wakeup(next);
         } else {
             return;
         }
@@ -310,6 +577,8 @@ impl<U: Inventories + Wakeup, C: Clock> InventoryManager<U, C> {
             let height = tree.height();
             self.confirmed
                 .retain(|h, _| height - h <= TRANSACTION_PRUNE_DEPTH);
+            self.proofs
+                .retain(|_, (h, _)| height - *h <= TRANSACTION_PRUNE_DEPTH);
         }
 
         // Handle retries annd disconnects.
@@ -337,17 +606,29 @@ impl<U: Inventories + Wakeup, C: Clock> InventoryManager<U, C> {
 
                 peer.attempted(now);
 
-                let mut invs = Vec::with_capacity(peer.outbox.len());
-                if peer.wtxidrelay {
-                    for wtxid in peer.outbox.keys() {
-                        invs.push(Inventory::WTx(self.mempool[wtxid].wtxid()));
-                    }
-                } else {
-                    // TODO: Should we send a WitnessTransaction?
-                    for wtxid in peer.outbox.keys() {
-                        invs.push(Inventory::Transaction(self.mempool[wtxid].txid()));
+                // Packages are re-announced as a unit, even if some of their transactions
+                // were already requested by the peer.
+                let mut wtxids = peer.outbox.keys().copied().collect::<HashSet<_>>();
+                for wtxid in peer.outbox.keys() {
+                    if let Some(package) = self.packages.get(wtxid) {
+                        wtxids.extend(package.iter().filter(|w| self.mempool.contains_key(*w)));
                     }
                 }
+                let mut wtxids = wtxids.into_iter().collect::<Vec<_>>();
+                wtxids.sort_by_key(|w| depth(w, &self.mempool, &self.txids));
+
+                // Nb. Peers that don't use BIP-339 request the witness with `getdata`,
+                // witness inventories are never announced.
+                let invs = wtxids
+                    .into_iter()
+                    .map(|wtxid| {
+                        if peer.wtxidrelay {
+                            Inventory::WTx(wtxid)
+                        } else {
+                            Inventory::Transaction(self.mempool[&wtxid].txid())
+                        }
+                    })
+                    .collect();
                 self.upstream.inv(*addr, invs);
                 self.upstream.wakeup(self.timeout);
             }
@@ -364,18 +645,38 @@ impl<U: Inventories + Wakeup, C: Clock> InventoryManager<U, C> {
             .iter_mut()
             .filter(|(_, t)| now - t.unwrap_or_default() >= REQUEST_TIMEOUT);
 
+        let trusted = self.config.trusted_peer;
+        let filter_peers = &self.filter_peers;
+        let mut unavailable = false;
+        let mut requested = Vec::new();
+
         for (block_hash, last_request) in queue {
-            if let Some((addr, _)) = self
+            let eligible = |addr: &PeerId, p: &Peer| {
+                p.services.has(ServiceFlags::NETWORK) && trusted.is_none_or(|t| &t == addr)
+            };
+            // Prefer peers other than the one that sent us the matching filter, but fall
+            // back to it if there is no other choice.
+            let peer = self
                 .peers
-                .sample_with(|_, p| p.services.has(ServiceFlags::NETWORK))
-            {
+                .sample_with(|addr, p| {
+                    eligible(addr, p) && filter_peers.get(block_hash) != Some(addr)
+                })
+                .or_else(|| self.peers.sample_with(eligible));
+
+            if let Some((addr, _)) = peer {
                 log::debug!("Requesting block {} from {}", block_hash, addr);
 
                 self.upstream
                     .getdata(*addr, vec![Inventory::Block(*block_hash)]);
                 self.upstream.wakeup(REQUEST_TIMEOUT);
 
+                // Only request decoys the first time a block is requested.
+                if last_request.is_none() {
+                    requested.push(*addr);
+                }
                 *last_request = Some(now);
+            } else if trusted.is_some() {
+                unavailable = true;
             } else {
                 log::debug!(
                     "No peers with required services to request block {} from",
@@ -383,20 +684,56 @@ impl<U: Inventories + Wakeup, C: Clock> InventoryManager<U, C> {
                 );
             }
         }
+
+        // Stop waiting for decoys that were never delivered.
+        self.decoys.retain(|_, t| now - *t < DECOY_TIMEOUT);
+        // Drop blocks fetched ahead of their filter, if it didn't match in time.
+        self.prefetching.retain(|_, t| now - *t < PREFETCH_TIMEOUT);
+        self.prefetched
+            .retain(|_, (t, _, _)| now - *t < PREFETCH_TIMEOUT);
+
+        for addr in requested {
+            self.request_decoys(&addr, tree);
+        }
+
+        // Only notify the user once, when downloads are first paused.
+        if let Some(peer) = trusted {
+            if unavailable && !self.paused {
+                log::warn!(
+                    "Trusted peer {} is unavailable, pausing block downloads",
+                    peer
+                );
+
+                self.upstream.event(Event::TrustedPeerUnavailable {
+                    peer,
+                    remaining: self.remaining.len(),
+                });
+            }
+            self.paused = unavailable;
+        }
     }
 
     /// Called when a `getdata` is received from a peer.
     pub fn received_getdata(&mut self, addr: PeerId, invs: &[Inventory]) {
-        for inv in invs {
+        // Serve parents before their children.
+        let mut invs = invs.to_vec();
+        invs.sort_by_key(|inv| match inv {
+            Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) => self
+                .txids
+                .get(txid)
+                .map_or(0, |w| depth(w, &self.mempool, &self.txids)),
+            Inventory::WTx(wtxid) => depth(wtxid, &self.mempool, &self.txids),
+            _ => 0,
+        });
+
+        for inv in &invs {
             match inv {
                 // NOTE: Normally, we would handle non-witness inventory requests differently
                 // than witness inventories, but the `bitcoin` crate doesn't allow us to
                 // omit the witness data, hence we treat them equally here.
                 Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) => {
-                    if let Some(tx) = self.mempool.values().find(|tx| tx.txid() == *txid) {
-                        let wtxid = tx.wtxid();
-                        debug_assert!(self.mempool.contains_key(&wtxid));
-                        self.upstream.tx(addr, tx.clone());
+                    if let Some(wtxid) = self.txids.get(txid).copied() {
+                        self.upstream.tx(addr, self.mempool[&wtxid].clone());
 
                         // Since we received a `getdata` from the peer, it means it received our
                         // inventory broadcast and we no longer need to send it.
@@ -450,6 +787,20 @@ impl<U: Inventories + Wakeup, C: Clock> InventoryManager<U, C> {
         let hash = block.block_hash();
         let from = *from;
 
+        if self.decoys.remove(&hash).is_some() {
+            log::debug!("Received decoy block {} from {}", hash, from);
+
+            self.decoy_usage.1 += block.size();
+        }
+        if self.prefetching.remove(&hash).is_some() && !self.remaining.contains_key(&hash) {
+            log::debug!("Received block {} ahead of its filter from {}", hash, from);
+
+            // Hold on to the block until we know whether its filter matches.
+            self.prefetched
+                .insert(hash, (self.clock.local_time(), from, block));
+
+            return vec![];
+        }
         if self.remaining.remove(&hash).is_none() {
             // Nb. The remote isn't necessarily sending an unsolicited block here.
             // We often have to ask multiple peers to get a response, so we may
@@ -458,6 +809,8 @@ impl<U: Inventories + Wakeup, C: Clock> InventoryManager<U, C> {
         }
 
         // We're done requesting this block.
+        self.filter_peers.remove(&hash);
+
         for peer in self.peers.values_mut() {
             peer.requests.remove(&hash);
         }
@@ -492,29 +845,41 @@ impl<U: Inventories + Wakeup, C: Clock> InventoryManager<U, C> {
             .cloned()
             .and_then(|h| self.received.remove(&h).map(|b| (h, b)))
         {
-            let hash = block.block_hash();
+            let anchor = Anchor::new(height, &block.header);
 
             for tx in &block.txdata {
-                let wtxid = tx.wtxid();
-
-                // Attempt to remove confirmed transaction from mempool.
-                if let Some(transaction) = self.mempool.remove(&wtxid) {
-                    confirmed.push(tx.txid());
-
-                    // Transactions that have been confirmed no longer need to be announced.
-                    for peer in self.peers.values_mut() {
-                        peer.outbox.remove(&wtxid);
+                let txid = tx.txid();
+
+                // Attempt to remove confirmed transaction from mempool. Transactions are
+                // matched by `txid`, since the confirmed transaction may have a malleated
+                // witness. Transactions we replaced may also be confirmed.
+                if self.remove(&txid).is_some() || self.replaced.contains_key(&txid) {
+                    let transaction = tx.clone();
+                    confirmed.push(txid);
+
+                    for stale in self.settle(&txid) {
+                        self.upstream.event(Event::Stale {
+                            txid: stale,
+                            replaced_by: txid,
+                            block: anchor,
+                        });
                     }
 
                     self.confirmed
                         .entry(height)
                         .or_default()
                         .push(transaction.clone());
+                    self.proofs.insert(
+                        txid,
+                        (
+                            height,
+                            MerkleBlock::from_block_with_predicate(&block, |t| *t == txid),
+                        ),
+                    );
 
                     self.upstream.event(Event::Confirmed {
                         transaction,
-                        block: hash,
-                        height,
+                        block: anchor,
                     });
                 }
             }
@@ -538,8 +903,14 @@ impl<U: Inventories + Wakeup, C: Clock> InventoryManager<U, C> {
         let txid = tx.txid();
         let wtxid = tx.wtxid();
 
+        // A transaction with a different witness replaces the existing one.
+        if self.txids.get(&txid).is_some_and(|w| *w != wtxid) {
+            log::debug!("Replacing transaction {} with wtxid {}", txid, wtxid);
+            self.remove(&txid);
+        }
         // Insert transaction into the peer outboxes and keep a local copy for re-broadcasting later.
         self.mempool.insert(wtxid, tx);
+        self.txids.insert(txid, wtxid);
 
         for (addr, peer) in self.peers.iter_mut().filter(|(_, p)| p.relay) {
             peer.outbox.insert(wtxid, txid);
@@ -550,6 +921,111 @@ impl<U: Inventories + Wakeup, C: Clock> InventoryManager<U, C> {
         addrs
     }
 
+    /// Announce a package of transactions to all matching peers. Transactions may be given
+    /// in any order: parents are always announced before their children. Retries if necessary.
+    ///
+    /// Returns the peers the package is announced to, and the status of each transaction,
+    /// in announcement order.
+    pub fn announce_package(
+        &mut self,
+        txs: Vec<Transaction>,
+    ) -> Result<(Vec<PeerId>, Vec<(Txid, TxStatus)>), PackageError> {
+        if txs.is_empty() {
+            return Err(PackageError::Empty);
+        }
+        if txs.len() > MAX_PACKAGE_COUNT {
+            return Err(PackageError::TooLarge);
+        }
+        let mut txids = HashSet::new();
+        let mut spent = HashSet::new();
+
+        for tx in &txs {
+            if !txids.insert(tx.txid()) {
+                return Err(PackageError::Duplicate(tx.txid()));
+            }
+            for input in &tx.input {
+                if !spent.insert(input.previous_output) {
+                    return Err(PackageError::Conflict(input.previous_output));
+                }
+            }
+        }
+
+        // Sort the package topologically, keeping the given order otherwise.
+        let mut remaining = txs;
+        let mut sorted = Vec::with_capacity(remaining.len());
+
+        while !remaining.is_empty() {
+            let ready = remaining
+                .iter()
+                .position(|tx| {
+                    tx.input
+                        .iter()
+                        .all(|i| !remaining.iter().any(|p| p.txid() == i.previous_output.txid))
+                })
+                // Nb. Transaction graphs can't have cycles.
+                .unwrap_or_default();
+
+            sorted.push(remaining.remove(ready));
+        }
+
+        let mut peers = Vec::new();
+        let mut statuses = Vec::with_capacity(sorted.len());
+        let mut package = Vec::with_capacity(sorted.len());
+
+        for tx in sorted {
+            let txid = tx.txid();
+
+            if let Some(height) = self.confirmation(&txid) {
+                statuses.push((txid, TxStatus::Confirmed(height)));
+            } else {
+                package.push(tx.wtxid());
+                peers = self.announce(tx);
+                statuses.push((txid, TxStatus::Announced));
+            }
+        }
+        for wtxid in &package {
+            self.packages.insert(*wtxid, package.clone());
+        }
+        Ok((peers, statuses))
+    }
+
+    /// Replace a transaction in the mempool with a conflicting transaction, eg. to bump its fee.
+    /// The replacement is announced to all matching peers, and the original transaction is no
+    /// longer announced. Retries if necessary.
+    pub fn replace(
+        &mut self,
+        txid: &Txid,
+        replacement: Transaction,
+    ) -> Result<Vec<PeerId>, ReplaceError> {
+        let original = self
+            .txids
+            .get(txid)
+            .and_then(|wtxid| self.mempool.get(wtxid))
+            .ok_or(ReplaceError::UnknownTransaction(*txid))?;
+
+        let conflicting = replacement.txid() != *txid
+            && replacement.input.iter().any(|i| {
+                original
+                    .input
+                    .iter()
+                    .any(|o| o.previous_output == i.previous_output)
+            });
+        if !conflicting {
+            return Err(ReplaceError::NotConflicting(*txid));
+        }
+        let replaced_by = replacement.txid();
+
+        if let Some(original) = self.remove(txid) {
+            self.replaced.insert(*txid, (original, replaced_by));
+        }
+        self.upstream.event(Event::Replaced {
+            txid: *txid,
+            replaced_by,
+        });
+
+        Ok(self.announce(replacement))
+    }
+
     /// Attempt to get a block from the network. Retries if necessary.
     pub fn get_block(&mut self, hash: BlockHash) {
         log::debug!("Queueing block {hash} to be requested");
@@ -558,14 +1034,269 @@ impl<U: Inventories + Wakeup, C: Clock> InventoryManager<U, C> {
         self.schedule_tick();
     }
 
+    /// Attempt to get a block whose filter was received from the given peer. The block is
+    /// preferably requested from a different peer. Retries if necessary.
+    ///
+    /// If the block was fetched ahead of its filter, it's processed right away, and the
+    /// transactions it confirmed are returned, as with [`InventoryManager::received_block`].
+    pub fn get_filtered_block<T: BlockReader>(
+        &mut self,
+        hash: BlockHash,
+        filter_peer: PeerId,
+        tree: &T,
+    ) -> Vec<Txid> {
+        self.filter_peers.insert(hash, filter_peer);
+
+        if let Some((_, from, block)) = self.prefetched.remove(&hash) {
+            self.remaining.insert(hash, None);
+
+            return self.received_block(&from, block, tree);
+        }
+        if let Some(requested) = self.prefetching.get(&hash) {
+            // The block is already on its way. Only request it again if it doesn't arrive.
+            self.remaining.entry(hash).or_insert(Some(*requested));
+            self.upstream.wakeup(REQUEST_TIMEOUT);
+
+            return vec![];
+        }
+        self.get_block(hash);
+
+        vec![]
+    }
+
+    /// Fetch a new block at the tip from the peer that announced it, without waiting for
+    /// its filter. Compact filters are still used to decide whether the block is relevant:
+    /// the block is only processed once its filter matches, but doesn't need to be
+    /// requested then, which saves a round-trip. Since every new block is fetched, this
+    /// doesn't reveal which blocks we're interested in.
+    pub fn prefetch_block(&mut self, hash: BlockHash, from: PeerId) {
+        if !self.config.direct_fetch || self.deferred {
+            return;
+        }
+        // Blocks are only ever downloaded from the trusted peer, if there is one.
+        if self.config.trusted_peer.is_some_and(|t| t != from) {
+            return;
+        }
+        if self.remaining.contains_key(&hash)
+            || self.prefetching.contains_key(&hash)
+            || self.prefetched.contains_key(&hash)
+        {
+            return;
+        }
+        log::debug!("Fetching block {} ahead of its filter from {}", hash, from);
+
+        self.upstream.getdata(from, vec![Inventory::Block(hash)]);
+        self.upstream.wakeup(PREFETCH_TIMEOUT);
+        self.prefetching.insert(hash, self.clock.local_time());
+    }
+
+    /// Defer speculative downloads, or resume them. Blocks we need are still requested.
+    pub fn set_deferred(&mut self, deferred: bool) {
+        self.deferred = deferred;
+    }
+
+    /// Set the scripts to look for in the trusted peer's mempool. If they changed, a new
+    /// bloom filter is loaded on the trusted peer. Only used with mempool prefetch.
+    pub fn watch_mempool(&mut self, scripts: impl IntoIterator<Item = Script>) {
+        let scripts = scripts.into_iter().collect::<HashSet<_>>();
+
+        if scripts == self.mempool_watch {
+            return;
+        }
+        self.mempool_watch = scripts;
+
+        if let Some(peer) = self.config.trusted_peer {
+            self.load_mempool_filter(peer);
+        }
+    }
+
+    /// Called when an `inv` is received from a peer. Unconfirmed transactions announced by
+    /// the trusted peer are requested, if mempool prefetch is enabled.
+    pub fn received_inv(&mut self, addr: PeerId, invs: &[Inventory]) {
+        if !self.config.mempool_prefetch || self.deferred || self.config.trusted_peer != Some(addr)
+        {
+            return;
+        }
+        let mut request = Vec::new();
+
+        for inv in invs {
+            let ours = match inv {
+                Inventory::Transaction(txid) => self.txids.contains_key(txid),
+                Inventory::WTx(wtxid) => self.mempool.contains_key(wtxid),
+                _ => continue,
+            };
+            if ours || self.mempool_seen.contains(inv) {
+                continue;
+            }
+            if self.mempool_seen.len() >= MAX_MEMPOOL_SEEN {
+                self.mempool_seen.clear();
+            }
+            self.mempool_seen.insert(*inv);
+            request.push(*inv);
+        }
+        if !request.is_empty() {
+            self.upstream.getdata(addr, request);
+        }
+    }
+
+    /// Called when a `tx` is received from a peer. Transactions requested from the trusted
+    /// peer's mempool are reported if they pay to a watched script.
+    pub fn received_tx(&mut self, addr: PeerId, tx: Transaction) {
+        if !self.config.mempool_prefetch || self.config.trusted_peer != Some(addr) {
+            return;
+        }
+        if !self
+            .mempool_seen
+            .contains(&Inventory::Transaction(tx.txid()))
+            && !self.mempool_seen.contains(&Inventory::WTx(tx.wtxid()))
+        {
+            return;
+        }
+        // The bloom filter has false positives, so we check for matches ourselves.
+        if tx
+            .output
+            .iter()
+            .any(|o| self.mempool_watch.contains(&o.script_pubkey))
+        {
+            self.upstream.event(Event::MempoolMatched {
+                transaction: tx,
+                peer: addr,
+            });
+        }
+    }
+
     ////////////////////////////////////////////////////////////////////////////
 
+    /// Load a bloom filter matching the watched scripts on the trusted peer, and ask for
+    /// the matching transactions in its mempool.
+    fn load_mempool_filter(&mut self, peer: PeerId) {
+        if !self.config.mempool_prefetch || self.mempool_watch.is_empty() {
+            return;
+        }
+        let Some(p) = self.peers.get(&peer) else {
+            return;
+        };
+        if !p.services.has(ServiceFlags::BLOOM) {
+            log::warn!(
+                "Trusted peer {} doesn't support bloom filters, mempool prefetch is disabled",
+                peer
+            );
+            return;
+        }
+        let filter = BloomFilter::for_scripts(
+            &self.mempool_watch,
+            MEMPOOL_FILTER_FP_RATE,
+            self.rng.u32(..),
+        );
+        self.upstream.filterload(peer, filter.into());
+        self.upstream.mempool(peer);
+    }
+
+    /// Remove a transaction from the mempool and from all peer outboxes, by `txid`.
+    fn remove(&mut self, txid: &Txid) -> Option<Transaction> {
+        let wtxid = self.txids.remove(txid)?;
+
+        self.packages.remove(&wtxid);
+        for peer in self.peers.values_mut() {
+            peer.outbox.remove(&wtxid);
+        }
+        self.mempool.remove(&wtxid)
+    }
+
+    /// Settle a chain of replacements, given the `txid` of the version that was confirmed.
+    /// Returns the other versions, which can no longer be confirmed.
+    fn settle(&mut self, confirmed: &Txid) -> Vec<Txid> {
+        let mut txid = *confirmed;
+        let mut stale = Vec::new();
+
+        // Find the first version.
+        while let Some((prev, _)) = self.replaced.iter().find(|(_, (_, r))| *r == txid) {
+            txid = *prev;
+        }
+        while let Some((_, next)) = self.replaced.remove(&txid) {
+            if txid != *confirmed {
+                stale.push(txid);
+            }
+            txid = next;
+        }
+        // The last version is the one in the mempool.
+        if txid != *confirmed {
+            self.remove(&txid);
+            stale.push(txid);
+        }
+        stale
+    }
+
+    /// Request random decoy blocks, preferably from peers other than the one given.
+    fn request_decoys<T: BlockReader>(&mut self, exclude: &PeerId, tree: &T) {
+        if self.deferred {
+            return;
+        }
+        let now = self.clock.local_time();
+        let height = tree.height();
+
+        if now - self.decoy_usage.0 >= DECOY_BUDGET_PERIOD {
+            self.decoy_usage = (now, 0);
+        }
+
+        for _ in 0..self.config.decoys {
+            if self.decoy_usage.1 >= self.config.decoy_budget {
+                log::debug!("Decoy block budget exhausted, not requesting decoys");
+                break;
+            }
+            if height == 0 {
+                break;
+            }
+            let hash = match tree.get_block_by_height(self.rng.u64(1..=height)) {
+                Some(header) => header.block_hash(),
+                None => continue,
+            };
+            if self.remaining.contains_key(&hash) || self.decoys.contains_key(&hash) {
+                continue;
+            }
+            let peer = self
+                .peers
+                .sample_with(|a, p| a != exclude && p.services.has(ServiceFlags::NETWORK))
+                .or_else(|| {
+                    self.peers
+                        .sample_with(|_, p| p.services.has(ServiceFlags::NETWORK))
+                })
+                .map(|(addr, _)| *addr);
+
+            if let Some(addr) = peer {
+                log::debug!("Requesting decoy block {} from {}", hash, addr);
+
+                self.upstream.getdata(addr, vec![Inventory::Block(hash)]);
+                self.decoys.insert(hash, now);
+            }
+        }
+    }
+
     fn schedule_tick(&mut self) {
-        self.last_tick = None; // Disable rate-limiting for the next tick.
+        self.schedule.reset(&"rebroadcast"); // Disable rate-limiting for the next tick.
         self.upstream.wakeup(LocalDuration::from_secs(1));
     }
 }
 
+/// Number of transactions in the longest chain of mempool ancestors of a transaction,
+/// including itself.
+fn depth(
+    wtxid: &Wtxid,
+    mempool: &BTreeMap<Wtxid, Transaction>,
+    txids: &HashMap<Txid, Wtxid>,
+) -> usize {
+    let Some(tx) = mempool.get(wtxid) else {
+        return 0;
+    };
+    1 + tx
+        .input
+        .iter()
+        .filter_map(|i| txids.get(&i.previous_output.txid))
+        .map(|parent| depth(parent, mempool, txids))
+        .max()
+        .unwrap_or_default()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -578,6 +1309,7 @@ mod tests {
     use crate::protocol::{Io, PROTOCOL_VERSION};
 
     use nakamoto_common::bitcoin::network::message::NetworkMessage;
+    use nakamoto_common::bitcoin::Witness;
     use nakamoto_common::block::time::RefClock;
     use nakamoto_common::block::tree::BlockTree as _;
     use nakamoto_common::collections::HashSet;
@@ -599,7 +1331,7 @@ mod tests {
 
         let network = Network::Regtest;
 
-        let mut upstream = Outbox::new(network, PROTOCOL_VERSION, "test");
+        let mut upstream = Outbox::new(network.clone(), PROTOCOL_VERSION, "test");
         let mut rng = fastrand::Rng::new();
         let clock = RefClock::from(LocalTime::now());
 
@@ -612,7 +1344,12 @@ mod tests {
         let inv = vec![Inventory::Block(hash)];
         let block = chain.iter().find(|b| b.block_hash() == hash).unwrap();
 
-        let mut invmgr = InventoryManager::new(rng.clone(), upstream.clone(), clock.clone());
+        let mut invmgr = InventoryManager::new(
+            Config::default(),
+            rng.clone(),
+            upstream.clone(),
+            clock.clone(),
+        );
 
         invmgr.peer_negotiated(
             Socket::new(([66, 66, 66, 66], 8333)),
@@ -697,10 +1434,240 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_trusted_peer() {
+        let network = Network::Regtest;
+
+        let mut upstream = Outbox::new(network.clone(), PROTOCOL_VERSION, "test");
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+
+        let genesis = network.genesis_block();
+        let chain = gen::blockchain(genesis, 16, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let hash = tree.get_block_by_height(6).unwrap().block_hash();
+        let inv = vec![Inventory::Block(hash)];
+
+        let trusted: PeerId = ([99, 99, 99, 99], 8333).into();
+        let config = Config {
+            trusted_peer: Some(trusted),
+            ..Config::default()
+        };
+        let mut invmgr = InventoryManager::new(config, rng, upstream.clone(), clock.clone());
+
+        invmgr.peer_negotiated(
+            Socket::new(([66, 66, 66, 66], 8333)),
+            ServiceFlags::NETWORK,
+            true,
+            true,
+        );
+        invmgr.get_block(hash);
+        invmgr.received_wake(&tree);
+
+        let outputs = upstream.drain().collect::<Vec<_>>();
+        assert!(
+            !outputs.iter().any(|o| matches!(o, Io::Write(_))),
+            "Blocks are not requested from untrusted peers"
+        );
+        assert_matches!(
+            events(outputs.into_iter()).next(),
+            Some(Event::TrustedPeerUnavailable { peer, remaining: 1 }) if peer == trusted
+        );
+
+        // We're only notified once.
+        clock.elapse(REQUEST_TIMEOUT);
+        invmgr.received_wake(&tree);
+        assert_eq!(events(upstream.drain()).count(), 0);
+
+        // Once the trusted peer is available, downloads resume.
+        invmgr.peer_negotiated(Socket::new(trusted), ServiceFlags::NETWORK, true, true);
+        invmgr.received_wake(&tree);
+        upstream.drain().for_each(drop);
+
+        assert!(output::test::messages(&mut upstream, &trusted)
+            .any(|m| matches!(m, NetworkMessage::GetData(i) if i == inv)));
+    }
+
+    #[test]
+    fn test_decoy_blocks() {
+        let network = Network::Regtest;
+
+        let mut upstream = Outbox::new(network.clone(), PROTOCOL_VERSION, "test");
+        let mut rng = fastrand::Rng::with_seed(1);
+        let clock = RefClock::from(LocalTime::now());
+
+        let genesis = network.genesis_block();
+        let chain = gen::blockchain(genesis, 64, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let config = Config {
+            decoys: 4,
+            decoy_budget: 1,
+            ..Config::default()
+        };
+        let mut invmgr = InventoryManager::new(config, rng, upstream.clone(), clock.clone());
+        let peers: [PeerId; 2] = [
+            ([66, 66, 66, 66], 8333).into(),
+            ([77, 77, 77, 77], 8333).into(),
+        ];
+
+        for peer in peers {
+            invmgr.peer_negotiated(Socket::new(peer), ServiceFlags::NETWORK, true, true);
+        }
+        let hash = chain[6].block_hash();
+        invmgr.get_block(hash);
+        invmgr.received_wake(&tree);
+        upstream.drain().for_each(drop);
+
+        let mut requests = HashMap::with_hasher(fastrand::Rng::new().into());
+        for peer in peers {
+            for msg in output::test::messages(&mut upstream, &peer) {
+                if let NetworkMessage::GetData(invs) = msg {
+                    for inv in invs {
+                        if let Inventory::Block(h) = inv {
+                            requests.insert(h, peer);
+                        }
+                    }
+                }
+            }
+        }
+        let real = requests[&hash];
+
+        assert!(!invmgr.decoys.is_empty());
+        assert_eq!(requests.len(), invmgr.decoys.len() + 1);
+        assert!(
+            invmgr.decoys.keys().all(|h| requests[h] != real),
+            "Decoys are requested from other peers"
+        );
+
+        // Decoys are not processed, but count towards the budget.
+        let decoy = *invmgr.decoys.keys().next().unwrap();
+        let block = chain.iter().find(|b| b.block_hash() == decoy).unwrap();
+        let confirmed = invmgr.received_block(&requests[&decoy], block.clone(), &tree);
+
+        assert!(confirmed.is_empty());
+        assert_eq!(events(upstream.drain()).count(), 0);
+        assert!(invmgr.remaining.contains_key(&hash));
+        assert_eq!(invmgr.decoy_usage.1, block.size());
+
+        // Once the budget is used up, no more decoys are requested.
+        let decoys = invmgr.decoys.len();
+        invmgr.get_block(chain[12].block_hash());
+        invmgr.received_wake(&tree);
+
+        assert_eq!(invmgr.decoys.len(), decoys);
+    }
+
+    #[test]
+    fn test_filtered_block_partitioning() {
+        let network = Network::Regtest;
+
+        let mut upstream = Outbox::new(network.clone(), PROTOCOL_VERSION, "test");
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+
+        let genesis = network.genesis_block();
+        let chain = gen::blockchain(genesis, 16, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let hash = chain[6].block_hash();
+        let inv = vec![Inventory::Block(hash)];
+
+        let alice: PeerId = ([66, 66, 66, 66], 8333).into();
+        let bob: PeerId = ([77, 77, 77, 77], 8333).into();
+        let mut invmgr =
+            InventoryManager::new(Config::default(), rng, upstream.clone(), clock.clone());
+
+        for peer in [alice, bob] {
+            invmgr.peer_negotiated(Socket::new(peer), ServiceFlags::NETWORK, true, true);
+        }
+        // Alice sent us the matching filter.
+        invmgr.get_filtered_block(hash, alice, &tree);
+
+        for _ in 0..8 {
+            invmgr.received_wake(&tree);
+            upstream.drain().for_each(drop);
+
+            assert!(output::test::messages(&mut upstream, &bob)
+                .any(|m| matches!(m, NetworkMessage::GetData(ref i) if i == &inv)));
+            assert_eq!(output::test::messages(&mut upstream, &alice).count(), 0);
+
+            clock.elapse(IDLE_TIMEOUT);
+        }
+
+        // Without any other peer, we fall back to alice.
+        invmgr.peer_disconnected(&bob);
+        clock.elapse(IDLE_TIMEOUT);
+        invmgr.received_wake(&tree);
+        upstream.drain().for_each(drop);
+
+        assert!(output::test::messages(&mut upstream, &alice)
+            .any(|m| matches!(m, NetworkMessage::GetData(ref i) if i == &inv)));
+    }
+
+    #[test]
+    fn test_prefetch_block() {
+        let network = Network::Regtest;
+
+        let mut upstream = Outbox::new(network.clone(), PROTOCOL_VERSION, "test");
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+
+        let genesis = network.genesis_block();
+        let chain = gen::blockchain(genesis, 16, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let (matched, unmatched) = (&chain[15], &chain[14]);
+
+        let alice: PeerId = ([66, 66, 66, 66], 8333).into();
+        let bob: PeerId = ([77, 77, 77, 77], 8333).into();
+        let mut invmgr =
+            InventoryManager::new(Config::default(), rng, upstream.clone(), clock.clone());
+
+        for peer in [alice, bob] {
+            invmgr.peer_negotiated(Socket::new(peer), ServiceFlags::NETWORK, true, true);
+        }
+
+        // Bob announced the blocks, so we fetch them from him, ahead of their filter.
+        for block in [matched, unmatched] {
+            let inv = vec![Inventory::Block(block.block_hash())];
+
+            invmgr.prefetch_block(block.block_hash(), bob);
+            assert!(output::test::messages(&mut upstream, &bob)
+                .any(|m| matches!(m, NetworkMessage::GetData(ref i) if i == &inv)));
+
+            invmgr.received_block(&bob, block.clone(), &tree);
+        }
+        assert_eq!(
+            events(upstream.drain()).count(),
+            0,
+            "Blocks aren't processed yet"
+        );
+
+        // Once the filter matches, the block is processed without requesting it again.
+        let confirmed = invmgr.get_filtered_block(matched.block_hash(), alice, &tree);
+        assert!(confirmed.is_empty());
+        assert_matches!(
+            events(upstream.drain()).last(),
+            Some(Event::BlockProcessed { block, .. }) if block.block_hash() == matched.block_hash()
+        );
+        assert!(invmgr.remaining.is_empty());
+
+        // Blocks whose filter doesn't match are eventually dropped.
+        clock.elapse(PREFETCH_TIMEOUT);
+        invmgr.received_wake(&tree);
+        assert!(invmgr.prefetched.is_empty());
+
+        upstream.drain().for_each(drop);
+        assert_eq!(output::test::messages(&mut upstream, &alice).count(), 0);
+        assert_eq!(output::test::messages(&mut upstream, &bob).count(), 0);
+    }
+
     #[test]
     fn test_rebroadcast_timeout() {
         let network = Network::Mainnet;
-        let mut upstream = Outbox::new(network, PROTOCOL_VERSION, "test");
+        let mut upstream = Outbox::new(network.clone(), PROTOCOL_VERSION, "test");
         let tree = model::Cache::from(NonEmpty::new(network.genesis()));
         let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
         let mut rng = fastrand::Rng::with_seed(1);
@@ -708,7 +1675,8 @@ mod tests {
         let clock = RefClock::from(LocalTime::now());
         let tx = gen::transaction(&mut rng);
 
-        let mut invmgr = InventoryManager::new(rng, upstream.clone(), clock.clone());
+        let mut invmgr =
+            InventoryManager::new(Config::default(), rng, upstream.clone(), clock.clone());
 
         invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, false);
         invmgr.announce(tx);
@@ -741,7 +1709,7 @@ mod tests {
     #[test]
     fn test_max_attemps() {
         let network = Network::Mainnet;
-        let mut upstream = Outbox::new(network, PROTOCOL_VERSION, "test");
+        let mut upstream = Outbox::new(network.clone(), PROTOCOL_VERSION, "test");
         let tree = model::Cache::from(NonEmpty::new(network.genesis()));
 
         let mut rng = fastrand::Rng::with_seed(1);
@@ -750,7 +1718,8 @@ mod tests {
         let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
         let tx = gen::transaction(&mut rng);
 
-        let mut invmgr = InventoryManager::new(rng, upstream.clone(), clock.clone());
+        let mut invmgr =
+            InventoryManager::new(Config::default(), rng, upstream.clone(), clock.clone());
 
         invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, false);
         invmgr.announce(tx.clone());
@@ -798,7 +1767,7 @@ mod tests {
         let time = LocalTime::now();
 
         let mut tree = model::Cache::from(headers);
-        let mut invmgr = InventoryManager::new(rng, upstream.clone(), time);
+        let mut invmgr = InventoryManager::new(Config::default(), rng, upstream.clone(), time);
 
         invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, false);
         invmgr.announce(tx.clone());
@@ -817,6 +1786,7 @@ mod tests {
                 }
             })
             .unwrap();
+        assert_eq!(invmgr.confirmation(&tx.txid()), Some(height));
 
         tree.import_blocks(
             vec![fork_block1.header, fork_block2.header].into_iter(),
@@ -826,6 +1796,7 @@ mod tests {
 
         invmgr.block_reverted(height);
         assert!(invmgr.contains(&tx.wtxid()));
+        assert_eq!(invmgr.confirmation(&tx.txid()), None);
 
         events
             .find(|e| {
@@ -843,7 +1814,7 @@ mod tests {
             .find(|e| {
                 matches! {
                     e, Event::Confirmed { transaction, block: b, .. }
-                    if transaction.txid() == tx.txid() && b == &fork_block1.block_hash()
+                    if transaction.txid() == tx.txid() && b.hash == fork_block1.block_hash()
                 }
             })
             .unwrap();
@@ -852,7 +1823,7 @@ mod tests {
     #[test]
     fn test_wtx_inv() {
         let network = Network::Mainnet;
-        let mut upstream = Outbox::new(network, PROTOCOL_VERSION, "test");
+        let mut upstream = Outbox::new(network.clone(), PROTOCOL_VERSION, "test");
         let tree = model::Cache::from(NonEmpty::new(network.genesis()));
 
         let mut rng = fastrand::Rng::with_seed(1);
@@ -862,7 +1833,7 @@ mod tests {
         let remote2: net::SocketAddr = ([88, 88, 88, 89], 8333).into();
         let tx = gen::transaction(&mut rng);
 
-        let mut invmgr = InventoryManager::new(rng, upstream.clone(), time);
+        let mut invmgr = InventoryManager::new(Config::default(), rng, upstream.clone(), time);
 
         invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, true);
         invmgr.announce(tx);
@@ -892,6 +1863,226 @@ mod tests {
         assert_matches!(invs.first(), Some(Inventory::Transaction(_)));
     }
 
+    #[test]
+    fn test_malleated_transaction() {
+        let network = Network::Regtest;
+        let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::new();
+
+        let mut main = gen::blockchain(network.genesis_block(), 16, &mut rng);
+        let tip = main.last().header;
+        let tx = gen::transaction(&mut rng);
+        let mut malleated = tx.clone();
+        malleated.input[0].witness = Witness::from_vec(vec![vec![0xff]]);
+
+        assert_eq!(tx.txid(), malleated.txid());
+        assert_ne!(tx.wtxid(), malleated.wtxid());
+
+        let block = gen::block_with(&tip, vec![malleated.clone()], &mut rng);
+        let tip_block = block.clone();
+        main.push(block.clone());
+
+        let headers = NonEmpty::from_vec(main.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION, "test");
+        let mut invmgr =
+            InventoryManager::new(Config::default(), rng, upstream.clone(), LocalTime::now());
+
+        invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, false);
+        invmgr.announce(tx.clone());
+
+        // Txid-only peers can request the transaction by txid.
+        invmgr.received_getdata(remote, &[Inventory::WitnessTransaction(tx.txid())]);
+        assert_matches!(
+            output::test::messages(&mut upstream, &remote).next(),
+            Some(NetworkMessage::Tx(t)) if t.wtxid() == tx.wtxid()
+        );
+
+        // Announcing a malleated version replaces the original.
+        invmgr.announce(malleated.clone());
+        assert!(!invmgr.contains(&tx.wtxid()));
+        assert!(invmgr.contains(&malleated.wtxid()));
+        invmgr.announce(tx.clone());
+        assert_eq!(invmgr.mempool.len(), 1);
+
+        // A malleated version of our transaction confirms it.
+        invmgr.get_block(block.block_hash());
+        let confirmed = invmgr.received_block(&remote, block, &tree);
+
+        assert_eq!(confirmed, vec![tx.txid()]);
+        assert!(invmgr.is_empty());
+        assert!(invmgr.txids.is_empty());
+
+        // A proof of inclusion is kept for the confirmed transaction.
+        let (height, proof) = invmgr.proof(&tx.txid()).unwrap();
+        let (mut matches, mut indexes) = (Vec::new(), Vec::new());
+
+        assert_eq!(height, tree.height());
+        assert_eq!(proof.header, tip_block.header);
+        assert!(proof.extract_matches(&mut matches, &mut indexes).is_ok());
+        assert_eq!(matches, vec![tx.txid()]);
+
+        invmgr.block_reverted(height);
+        assert!(invmgr.proof(&tx.txid()).is_none());
+    }
+
+    #[test]
+    fn test_package() {
+        let network = Network::Mainnet;
+        let tree = model::Cache::from(NonEmpty::new(network.genesis()));
+        let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::with_seed(1);
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION, "test");
+        let clock = RefClock::from(LocalTime::now());
+
+        let parent = gen::transaction(&mut rng);
+        let child = gen::transaction_with(OutPoint::new(parent.txid(), 0), 1000, &mut rng);
+        let grandchild = gen::transaction_with(OutPoint::new(child.txid(), 0), 500, &mut rng);
+        let invs = |upstream: &mut Outbox| {
+            output::test::messages(upstream, &remote)
+                .find_map(|m| match m {
+                    NetworkMessage::Inv(invs) => Some(invs),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        let expected = [&parent, &child, &grandchild]
+            .map(|tx| Inventory::WTx(tx.wtxid()))
+            .to_vec();
+
+        let mut invmgr =
+            InventoryManager::new(Config::default(), rng, upstream.clone(), clock.clone());
+
+        assert_eq!(invmgr.announce_package(vec![]), Err(PackageError::Empty));
+        assert_eq!(
+            invmgr.announce_package(vec![parent.clone(), parent.clone()]),
+            Err(PackageError::Duplicate(parent.txid()))
+        );
+        assert!(invmgr.is_empty());
+
+        invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, true);
+
+        // Parents are announced first, whatever the order they were given in.
+        let (peers, statuses) = invmgr
+            .announce_package(vec![grandchild.clone(), child.clone(), parent.clone()])
+            .unwrap();
+        assert_eq!(peers, vec![remote]);
+        assert_eq!(
+            statuses,
+            vec![
+                (parent.txid(), TxStatus::Announced),
+                (child.txid(), TxStatus::Announced),
+                (grandchild.txid(), TxStatus::Announced),
+            ]
+        );
+        invmgr.received_wake(&tree);
+        assert_eq!(invs(&mut upstream), expected);
+
+        // Parents are served first.
+        invmgr.received_getdata(
+            remote,
+            &[
+                Inventory::WTx(child.wtxid()),
+                Inventory::WTx(parent.wtxid()),
+            ],
+        );
+        let served = output::test::messages(&mut upstream, &remote)
+            .filter_map(|m| match m {
+                NetworkMessage::Tx(tx) => Some(tx.txid()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(served, vec![parent.txid(), child.txid()]);
+
+        // The package is re-announced as a unit.
+        clock.elapse(REBROADCAST_TIMEOUT);
+        invmgr.received_wake(&tree);
+        assert_eq!(invs(&mut upstream), expected);
+    }
+
+    #[test]
+    fn test_replace() {
+        let network = Network::Regtest;
+        let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::new();
+
+        let chain = gen::blockchain(network.genesis_block(), 16, &mut rng);
+        let tip = chain.last().header;
+        let tx = gen::transaction(&mut rng);
+        let mut bump1 = tx.clone();
+        bump1.output[0].value -= 1;
+        let mut bump2 = bump1.clone();
+        bump2.output[0].value -= 1;
+
+        // Either the original or the last replacement may be confirmed.
+        for (confirmed, stale) in [
+            (&tx, vec![bump1.txid(), bump2.txid()]),
+            (&bump2, vec![tx.txid(), bump1.txid()]),
+        ] {
+            let block = gen::block_with(&tip, vec![confirmed.clone()], &mut rng);
+            let mut chain = chain.clone();
+            chain.push(block.clone());
+
+            let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+            let tree = model::Cache::from(headers);
+            let mut upstream = Outbox::new(network.clone(), PROTOCOL_VERSION, "test");
+            let mut invmgr = InventoryManager::new(
+                Config::default(),
+                rng.clone(),
+                upstream.clone(),
+                LocalTime::now(),
+            );
+            invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, false);
+            invmgr.announce(tx.clone());
+
+            assert_eq!(
+                invmgr.replace(&bump1.txid(), bump2.clone()),
+                Err(ReplaceError::UnknownTransaction(bump1.txid()))
+            );
+            assert_eq!(
+                invmgr.replace(&tx.txid(), gen::transaction(&mut rng)),
+                Err(ReplaceError::NotConflicting(tx.txid()))
+            );
+            assert_eq!(invmgr.replace(&tx.txid(), bump1.clone()), Ok(vec![remote]));
+            assert_eq!(
+                invmgr.replace(&bump1.txid(), bump2.clone()),
+                Ok(vec![remote])
+            );
+            assert!(!invmgr.contains(&tx.wtxid()));
+            assert!(invmgr.contains(&bump2.wtxid()));
+
+            invmgr.get_block(block.block_hash());
+            invmgr.received_block(&remote, block.clone(), &tree);
+
+            assert!(invmgr.is_empty());
+            assert!(invmgr.replaced.is_empty());
+
+            let events = events(upstream.drain()).collect::<Vec<_>>();
+            let mut reported = events
+                .iter()
+                .filter_map(|e| match e {
+                    Event::Stale {
+                        txid,
+                        replaced_by,
+                        block: b,
+                    } if replaced_by == &confirmed.txid() && b.hash == block.block_hash() => {
+                        Some(*txid)
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            reported.sort();
+
+            let mut stale = stale;
+            stale.sort();
+            assert_eq!(reported, stale);
+            assert!(events.iter().any(|e| matches!(
+                e,
+                Event::Confirmed { transaction, .. } if transaction.txid() == confirmed.txid()
+            )));
+        }
+    }
+
     #[test]
     fn test_wtx_getdata() {
         let network = Network::Mainnet;
@@ -902,7 +2093,8 @@ mod tests {
         let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
         let tx = gen::transaction(&mut rng);
 
-        let mut invmgr = InventoryManager::new(rng, upstream.clone(), LocalTime::now());
+        let mut invmgr =
+            InventoryManager::new(Config::default(), rng, upstream.clone(), LocalTime::now());
 
         invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, true);
         invmgr.announce(tx.clone());
@@ -933,4 +2125,77 @@ mod tests {
             .unwrap();
         assert_eq!(tr.wtxid(), tx.wtxid());
     }
+
+    #[test]
+    fn test_mempool_prefetch() {
+        let network = Network::Regtest;
+
+        let mut upstream = Outbox::new(network.clone(), PROTOCOL_VERSION, "test");
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+
+        let chain = gen::blockchain(network.genesis_block(), 8, &mut rng);
+        let (matching, other) = (chain[4].txdata[0].clone(), chain[5].txdata[0].clone());
+        let script = matching.output[0].script_pubkey.clone();
+        assert!(other.output.iter().all(|o| o.script_pubkey != script));
+
+        let trusted: PeerId = ([99, 99, 99, 99], 8333).into();
+        let untrusted: PeerId = ([66, 66, 66, 66], 8333).into();
+        let config = Config {
+            trusted_peer: Some(trusted),
+            mempool_prefetch: true,
+            ..Config::default()
+        };
+        let mut invmgr = InventoryManager::new(config, rng, upstream.clone(), clock);
+
+        invmgr.watch_mempool([script]);
+        invmgr.peer_negotiated(Socket::new(untrusted), ServiceFlags::NETWORK, true, true);
+        invmgr.peer_negotiated(
+            Socket::new(trusted),
+            ServiceFlags::NETWORK | ServiceFlags::BLOOM,
+            true,
+            true,
+        );
+        assert_eq!(
+            output::test::messages(&mut upstream, &untrusted).count(),
+            0,
+            "The filter is only loaded on the trusted peer"
+        );
+
+        let msgs = output::test::messages(&mut upstream, &trusted).collect::<Vec<_>>();
+        assert_matches!(
+            msgs.as_slice(),
+            [NetworkMessage::FilterLoad(_), NetworkMessage::MemPool]
+        );
+
+        // Transactions are only requested from the trusted peer, and only once.
+        let invs = vec![
+            Inventory::WTx(matching.wtxid()),
+            Inventory::WTx(other.wtxid()),
+        ];
+        invmgr.received_inv(untrusted, &invs);
+        assert_eq!(output::test::messages(&mut upstream, &untrusted).count(), 0);
+
+        invmgr.received_inv(trusted, &invs);
+        invmgr.received_inv(trusted, &invs);
+        let requests = output::test::messages(&mut upstream, &trusted).collect::<Vec<_>>();
+        assert_matches!(requests.as_slice(), [NetworkMessage::GetData(i)] if i == &invs);
+
+        // Only transactions paying to watched scripts are reported.
+        invmgr.received_tx(untrusted, matching.clone());
+        invmgr.received_tx(trusted, other);
+        assert_eq!(events(upstream.drain()).count(), 0);
+
+        invmgr.received_tx(trusted, matching.clone());
+        assert_matches!(
+            events(upstream.drain()).next(),
+            Some(Event::MempoolMatched { transaction, peer })
+                if transaction == matching && peer == trusted
+        );
+
+        // Changing the watch list reloads the filter.
+        invmgr.watch_mempool([Script::new()]);
+        assert!(output::test::messages(&mut upstream, &trusted)
+            .any(|m| matches!(m, NetworkMessage::FilterLoad(_))));
+    }
 }
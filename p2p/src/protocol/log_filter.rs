@@ -0,0 +1,251 @@
+//! Runtime log filtering.
+//!
+//! The `log` facade only has a global maximum level. A [`LogFilter`] also sets levels per
+//! log target, eg. to turn on trace logging for a single module while diagnosing a sync
+//! issue. The active filter is global, and can be changed at runtime, eg. with
+//! [`Command::SetLogFilter`](super::Command::SetLogFilter). Loggers honor it by checking
+//! [`enabled`] and [`matches`].
+//!
+//! Directives name modules, and records are matched by the module they were logged from.
+//! The record target isn't used unless the module is unknown, since the protocol logs
+//! under a per-peer target, eg. `self`, which wouldn't be matched by module directives.
+//!
+//! Filters are written as comma-separated directives, each either a level, which applies
+//! to targets not matched by any other directive, a `target=level` pair, or a target on
+//! its own, which enables all levels for it:
+//!
+//! ```text
+//! info,nakamoto_p2p::protocol::cbfmgr=trace
+//! ```
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use log::{LevelFilter, Metadata, Record};
+use thiserror::Error;
+
+/// The active filter, if any.
+static FILTER: RwLock<Option<LogFilter>> = RwLock::new(None);
+
+/// An error parsing a log filter.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// A log level is invalid.
+    #[error("invalid log level `{0}`")]
+    InvalidLevel(String),
+    /// A directive has no target.
+    #[error("invalid log directive `{0}`")]
+    InvalidDirective(String),
+}
+
+/// Log levels per target.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogFilter {
+    /// Level of targets not matched by any directive. If not set, the level in effect
+    /// when the filter is applied is kept.
+    pub default: Option<LevelFilter>,
+    /// Targets and their level. Matching a target also matches its sub-modules.
+    pub directives: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    /// Level of the given target.
+    pub fn level(&self, target: &str) -> Option<LevelFilter> {
+        self.directives
+            .iter()
+            .filter(|(t, _)| {
+                target
+                    .strip_prefix(t.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(t, _)| t.len())
+            .map(|(_, level)| *level)
+            .or(self.default)
+    }
+
+    /// Highest level enabled by the filter.
+    fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|(_, level)| *level)
+            .chain(self.default)
+            .max()
+            .unwrap_or(LevelFilter::Off)
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = Self::default();
+
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    let target = target.trim();
+                    let level = level.trim();
+
+                    if target.is_empty() {
+                        return Err(Error::InvalidDirective(directive.to_owned()));
+                    }
+                    let level = LevelFilter::from_str(level)
+                        .map_err(|_| Error::InvalidLevel(level.to_owned()))?;
+
+                    filter.directives.push((target.to_owned(), level));
+                }
+                None => match LevelFilter::from_str(directive) {
+                    Ok(level) => filter.default = Some(level),
+                    Err(_) => filter
+                        .directives
+                        .push((directive.to_owned(), LevelFilter::Trace)),
+                },
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let directives = self
+            .default
+            .iter()
+            .map(|level| level.to_string().to_lowercase())
+            .chain(
+                self.directives
+                    .iter()
+                    .map(|(t, level)| format!("{}={}", t, level.to_string().to_lowercase())),
+            )
+            .collect::<Vec<_>>();
+
+        write!(f, "{}", directives.join(","))
+    }
+}
+
+/// Apply a filter, replacing the active filter. Raises the global maximum level if
+/// needed for the filter to take effect.
+pub fn set(mut filter: LogFilter) {
+    let mut active = FILTER.write().unwrap_or_else(|e| e.into_inner());
+
+    if filter.default.is_none() {
+        filter.default = Some(
+            active
+                .as_ref()
+                .and_then(|f| f.default)
+                .unwrap_or_else(log::max_level),
+        );
+    }
+    log::set_max_level(filter.max_level());
+    *active = Some(filter);
+}
+
+/// Check whether records of the given level may be logged, given the global maximum
+/// level. Records that pass should still be checked with [`matches`].
+pub fn enabled(metadata: &Metadata) -> bool {
+    metadata.level() <= log::max_level()
+}
+
+/// Check whether a record should be logged, given the active filter and the global
+/// maximum level.
+pub fn matches(record: &Record) -> bool {
+    if !enabled(record.metadata()) {
+        return false;
+    }
+    match &*FILTER.read().unwrap_or_else(|e| e.into_inner()) {
+        Some(filter) => filter
+            .level(module(record))
+            .is_none_or(|level| record.level() <= level),
+        None => true,
+    }
+}
+
+/// The module a record was logged from, or its target if unknown.
+fn module<'a>(record: &Record<'a>) -> &'a str {
+    record
+        .module_path_static()
+        .or_else(|| record.module_path())
+        .unwrap_or_else(|| record.target())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let filter = LogFilter::from_str("info, nakamoto_p2p::protocol::cbfmgr=trace,sim").unwrap();
+
+        assert_eq!(filter.default, Some(LevelFilter::Info));
+        assert_eq!(
+            filter.directives,
+            vec![
+                (
+                    String::from("nakamoto_p2p::protocol::cbfmgr"),
+                    LevelFilter::Trace
+                ),
+                (String::from("sim"), LevelFilter::Trace),
+            ]
+        );
+        assert_eq!(
+            filter.to_string(),
+            "info,nakamoto_p2p::protocol::cbfmgr=trace,sim=trace"
+        );
+        assert_eq!(filter.to_string().parse::<LogFilter>().unwrap(), filter);
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+
+        assert_eq!(
+            LogFilter::from_str("p2p=loud"),
+            Err(Error::InvalidLevel(String::from("loud")))
+        );
+        assert_eq!(
+            LogFilter::from_str("=debug"),
+            Err(Error::InvalidDirective(String::from("=debug")))
+        );
+        assert_eq!(LogFilter::from_str("").unwrap(), LogFilter::default());
+    }
+
+    #[test]
+    fn test_level() {
+        let filter =
+            LogFilter::from_str("warn,nakamoto_p2p=info,nakamoto_p2p::protocol::cbfmgr=trace")
+                .unwrap();
+
+        assert_eq!(
+            filter.level("nakamoto_p2p::protocol::cbfmgr"),
+            Some(LevelFilter::Trace)
+        );
+        assert_eq!(
+            filter.level("nakamoto_p2p::protocol::cbfmgr::rescan"),
+            Some(LevelFilter::Trace)
+        );
+        assert_eq!(
+            filter.level("nakamoto_p2p::protocol::syncmgr"),
+            Some(LevelFilter::Info)
+        );
+        assert_eq!(filter.level("nakamoto_p2pool"), Some(LevelFilter::Warn));
+        assert_eq!(filter.level("nakamoto_client"), Some(LevelFilter::Warn));
+        assert_eq!(LogFilter::default().level("nakamoto_client"), None);
+    }
+
+    #[test]
+    fn test_module() {
+        let filter = LogFilter::from_str("warn,nakamoto_p2p::protocol=debug").unwrap();
+        let args = format_args!("Sending");
+        let record = Record::builder()
+            .level(log::Level::Debug)
+            .target("self")
+            .module_path_static(Some("nakamoto_p2p::protocol::output"))
+            .args(args)
+            .build();
+
+        assert_eq!(module(&record), "nakamoto_p2p::protocol::output");
+        assert_eq!(filter.level(module(&record)), Some(LevelFilter::Debug));
+
+        let args = format_args!("Sending");
+        let record = Record::builder().target("self").args(args).build();
+
+        assert_eq!(module(&record), "self");
+        assert_eq!(filter.level(module(&record)), Some(LevelFilter::Warn));
+    }
+}
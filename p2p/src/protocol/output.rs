@@ -6,8 +6,8 @@
 //! with specific capabilities, eg. peer disconnection, message sending etc. to
 //! communicate with the network.
 use log::*;
-use std::cell::RefCell;
-use std::collections::{HashMap, VecDeque};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::rc::Rc;
 use std::sync::Arc;
 use std::{fmt, io, net};
@@ -15,24 +15,36 @@ use std::{fmt, io, net};
 pub use crossbeam_channel as chan;
 
 use nakamoto_common::bitcoin::consensus::encode;
-use nakamoto_common::bitcoin::network::address::Address;
+use nakamoto_common::bitcoin::network::address::{AddrV2Message, Address};
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::message::NetworkMessage;
 use nakamoto_common::bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
+use nakamoto_common::bitcoin::network::message_bloom::FilterLoad;
 use nakamoto_common::bitcoin::network::message_filter::{
-    CFHeaders, CFilter, GetCFHeaders, GetCFilters,
+    CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, GetCFHeaders, GetCFilters,
 };
 use nakamoto_common::bitcoin::network::message_network::VersionMessage;
 use nakamoto_common::bitcoin::Transaction;
 
-use nakamoto_common::block::time::LocalDuration;
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
 use nakamoto_common::block::{BlockHash, BlockHeader, BlockTime, Height};
 
-use crate::protocol::{Event, PeerId};
+use crate::protocol::{Event, MessageUsage, PeerId};
 
+use super::capture::{Capture, Direction, Record};
+use super::features::Feature;
 use super::network::Network;
 use super::{addrmgr, cbfmgr, invmgr, peermgr, pingmgr, syncmgr, Locators};
 
+/// Maximum number of bytes queued for sending to a single peer.
+pub const MAX_PEER_QUEUE_SIZE: usize = 4 * 1024 * 1024;
+/// Maximum number of bytes queued for sending to all peers combined.
+pub const MAX_QUEUE_SIZE: usize = 32 * 1024 * 1024;
+
+/// Capacity retained by a peer's send buffer once it is flushed. Larger buffers are
+/// shrunk, so that a burst of messages doesn't hold on to memory.
+const SEND_BUFFER_CAPACITY: usize = 64 * 1024;
+
 /// Output of a state transition of the `Protocol` state machine.
 #[derive(Debug)]
 pub enum Io {
@@ -44,6 +56,8 @@ pub enum Io {
     Disconnect(PeerId, DisconnectReason),
     /// Ask for a wakeup in a specified amount of time.
     Wakeup(LocalDuration),
+    /// Cancel all pending wakeups.
+    CancelWakeups,
     /// Emit an event.
     Event(Event),
 }
@@ -63,26 +77,50 @@ pub enum DisconnectReason {
     PeerProtocolVersion(u32),
     /// Peer doesn't have the required services.
     PeerServices(ServiceFlags),
+    /// Peer wasn't authenticated with the identity pinned for it.
+    PeerIdentity,
     /// Peer chain is too far behind.
     PeerHeight(Height),
     /// Peer magic is invalid.
     PeerMagic(u32),
     /// Peer timed out.
     PeerTimeout(&'static str),
-    /// Peer disconnected us.
+    /// Peer didn't answer TCP keepalive probes, ie. the connection is dead.
+    KeepaliveTimeout,
+    /// Peer closed the connection in an orderly way.
     PeerDisconnected,
+    /// Peer reset the connection, or it was aborted.
+    ConnectionReset,
     /// Peer was dropped by all sub-protocols.
     PeerDropped,
+    /// Feeler connection completed its handshake.
+    Feeler,
+    /// Peer was rotated out because our tip is stale.
+    StaleTip,
     /// Connection to self was detected.
     SelfConnection,
+    /// Peer is already connected to us, in the other direction.
+    DuplicateConnection,
     /// Inbound connection limit reached.
     ConnectionLimit,
+    /// Peer is not in the list of allowed peers.
+    PeerNotAllowed,
+    /// Too many bytes are queued for sending to the peer. This usually means the peer
+    /// is not reading from its socket.
+    QueueFull,
+    /// Peer address is banned.
+    PeerBanned,
+    /// Connection attempt was cancelled, because another address of the same peer
+    /// connected first.
+    DialCancelled,
     /// Error with the underlying connection.
     ConnectionError(Arc<std::io::Error>),
     /// Error trying to decode incoming message.
     DecodeError(Arc<encode::Error>),
     /// Peer was forced to disconnect by external command.
     Command,
+    /// Network activity was disabled.
+    NetworkInactive,
     /// Peer was disconnected for another reason.
     Other(&'static str),
 }
@@ -95,8 +133,16 @@ impl DisconnectReason {
             self,
             Self::ConnectionLimit
                 | Self::PeerTimeout(_)
+                | Self::KeepaliveTimeout
+                | Self::ConnectionReset
                 | Self::PeerHeight(_)
                 | Self::ConnectionError(_)
+                | Self::Feeler
+                | Self::StaleTip
+                | Self::QueueFull
+                | Self::DialCancelled
+                | Self::DuplicateConnection
+                | Self::NetworkInactive
         )
     }
 }
@@ -107,16 +153,27 @@ impl fmt::Display for DisconnectReason {
             Self::PeerMisbehaving(reason) => write!(f, "peer misbehaving: {}", reason),
             Self::PeerProtocolVersion(_) => write!(f, "peer protocol version mismatch"),
             Self::PeerServices(_) => write!(f, "peer doesn't have the required services"),
+            Self::PeerIdentity => write!(f, "peer identity doesn't match the pinned identity"),
             Self::PeerHeight(_) => write!(f, "peer is too far behind"),
             Self::PeerMagic(magic) => write!(f, "received message with invalid magic: {}", magic),
             Self::PeerTimeout(s) => write!(f, "peer timed out: {:?}", s),
+            Self::KeepaliveTimeout => write!(f, "peer connection is dead (keepalive timeout)"),
             Self::PeerDropped => write!(f, "peer dropped"),
+            Self::Feeler => write!(f, "feeler connection completed"),
+            Self::StaleTip => write!(f, "peer rotated out due to stale tip"),
             Self::PeerDisconnected => write!(f, "peer disconnected"),
+            Self::ConnectionReset => write!(f, "connection reset by peer"),
             Self::SelfConnection => write!(f, "detected self-connection"),
+            Self::DuplicateConnection => write!(f, "peer is already connected"),
             Self::ConnectionLimit => write!(f, "inbound connection limit reached"),
+            Self::PeerNotAllowed => write!(f, "peer is not in the list of allowed peers"),
+            Self::PeerBanned => write!(f, "peer address is banned"),
+            Self::QueueFull => write!(f, "peer send queue is full"),
+            Self::DialCancelled => write!(f, "another address of the peer connected first"),
             Self::ConnectionError(err) => write!(f, "connection error: {}", err),
             Self::DecodeError(err) => write!(f, "message decode error: {}", err),
             Self::Command => write!(f, "received external command"),
+            Self::NetworkInactive => write!(f, "network activity disabled"),
             Self::Other(reason) => write!(f, "{}", reason),
         }
     }
@@ -124,7 +181,8 @@ impl fmt::Display for DisconnectReason {
 
 pub(crate) mod message {
     use nakamoto_common::bitcoin::consensus::Encodable;
-    use nakamoto_common::bitcoin::network::message::RawNetworkMessage;
+    use nakamoto_common::bitcoin::hashes::{sha256d, Hash as _};
+    use nakamoto_common::bitcoin::network::message::{CommandString, RawNetworkMessage};
 
     use super::*;
     use std::io;
@@ -146,11 +204,55 @@ pub(crate) mod message {
             payload: NetworkMessage,
             writer: W,
         ) -> Result<usize, io::Error> {
-            RawNetworkMessage {
-                payload,
-                magic: self.magic,
+            match payload {
+                // Unknown message payloads are length-prefixed by the encoder, so we
+                // encode these messages ourselves.
+                NetworkMessage::Unknown { command, payload } => {
+                    self.write_raw(command, &payload, writer)
+                }
+                payload => RawNetworkMessage {
+                    payload,
+                    magic: self.magic,
+                }
+                .consensus_encode(writer),
             }
-            .consensus_encode(writer)
+        }
+
+        fn write_raw<W: io::Write>(
+            &self,
+            command: CommandString,
+            payload: &[u8],
+            mut writer: W,
+        ) -> Result<usize, io::Error> {
+            let checksum = sha256d::Hash::hash(payload);
+            let mut len = self.magic.consensus_encode(&mut writer)?;
+
+            len += command.consensus_encode(&mut writer)?;
+            len += (payload.len() as u32).consensus_encode(&mut writer)?;
+            writer.write_all(&checksum[..4])?;
+            writer.write_all(payload)?;
+
+            Ok(len + 4 + payload.len())
+        }
+    }
+}
+
+/// Limits on the number of bytes queued for sending to peers. Peers are disconnected
+/// when a limit is exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueLimits {
+    /// Maximum number of bytes queued for a single peer.
+    pub peer: usize,
+    /// Maximum number of bytes queued for all peers combined. When exceeded, the peer
+    /// with the largest queue is disconnected.
+    pub total: usize,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        Self {
+            peer: MAX_PEER_QUEUE_SIZE,
+            total: MAX_QUEUE_SIZE,
         }
     }
 }
@@ -162,8 +264,25 @@ pub struct Outbox {
     version: u32,
     /// Output queue.
     outbound: Rc<RefCell<VecDeque<Io>>>,
-    /// Message outbox.
-    outbox: Rc<RefCell<HashMap<PeerId, Vec<u8>>>>,
+    /// Message outbox. Messages are encoded once into a per-peer ring buffer, and written
+    /// out from the front of it as the peer's socket becomes writable.
+    outbox: Rc<RefCell<HashMap<PeerId, VecDeque<u8>>>>,
+    /// Peers whose queue overflowed, and that are being disconnected. Messages to these
+    /// peers are dropped.
+    overflowed: Rc<RefCell<HashSet<PeerId>>>,
+    /// Peers with a write output in the output queue. Messages queued for these peers
+    /// before the queue is drained are written out together, with a single write.
+    writing: Rc<RefCell<HashSet<PeerId>>>,
+    /// Message capture, if enabled.
+    capture: Rc<RefCell<Option<Capture>>>,
+    /// Statistics of sent messages, per command.
+    stats: Rc<RefCell<BTreeMap<&'static str, MessageUsage>>>,
+    /// Local time, as last set by the protocol.
+    time: Rc<Cell<LocalTime>>,
+    /// Queue limits. Shared, so that updates apply to every clone.
+    limits: Rc<Cell<QueueLimits>>,
+    /// Whether wakeups are suspended.
+    suspended: Rc<Cell<bool>>,
     /// Network message builder.
     builder: message::Builder,
     /// Log target.
@@ -177,11 +296,87 @@ impl Outbox {
             version,
             outbound: Rc::new(RefCell::new(VecDeque::new())),
             outbox: Rc::new(RefCell::new(HashMap::new())),
+            overflowed: Rc::new(RefCell::new(HashSet::new())),
+            writing: Rc::new(RefCell::new(HashSet::new())),
+            capture: Rc::new(RefCell::new(None)),
+            stats: Rc::new(RefCell::new(BTreeMap::new())),
+            time: Rc::new(Cell::new(LocalTime::default())),
+            limits: Rc::new(Cell::new(QueueLimits::default())),
+            suspended: Rc::new(Cell::new(false)),
             builder: message::Builder::new(network),
             target,
         }
     }
 
+    /// Set the queue limits.
+    pub fn limits(self, limits: QueueLimits) -> Self {
+        self.limits.set(limits);
+        self
+    }
+
+    /// Update the queue limits. Queues that are already over the new limits are
+    /// only trimmed when more data is queued for them.
+    pub fn set_limits(&self, limits: QueueLimits) {
+        self.limits.set(limits);
+    }
+
+    /// Suspend or resume wakeups. Suspending cancels pending wakeups, including those not
+    /// yet drained, and wakeups asked for while suspended are dropped.
+    pub fn suspend(&self, suspended: bool) {
+        if suspended && !self.suspended.get() {
+            self.outbound
+                .borrow_mut()
+                .retain(|o| !matches!(o, Io::Wakeup(_)));
+            self.push(Io::CancelWakeups);
+        }
+        self.suspended.set(suspended);
+    }
+
+    /// Start capturing messages, or stop capturing with `None`.
+    pub fn capture(&self, capture: Option<Capture>) {
+        *self.capture.borrow_mut() = capture;
+    }
+
+    /// Record a raw message exchanged with a peer, if capture is enabled. Capture is
+    /// stopped if the message can't be recorded.
+    pub fn captured(&self, peer: PeerId, direction: Direction, message: &[u8]) {
+        let mut capture = self.capture.borrow_mut();
+
+        if let Some(c) = capture.as_mut() {
+            let record = Record {
+                time: LocalTime::now(),
+                peer,
+                direction,
+                message: message.to_vec(),
+            };
+            if let Err(err) = c.record(&record) {
+                error!(target: self.target, "Failed to capture message, stopping: {}", err);
+                *capture = None;
+            }
+        }
+    }
+
+    /// Set the local time, used to timestamp message statistics.
+    pub fn set_time(&self, time: LocalTime) {
+        self.time.set(time);
+    }
+
+    /// Statistics of the messages sent so far, per command. Messages dropped because
+    /// of a send queue overflow aren't counted.
+    pub fn message_stats(&self) -> BTreeMap<&'static str, MessageUsage> {
+        self.stats.borrow().clone()
+    }
+
+    /// Number of bytes queued for sending to the given peer.
+    pub fn queued(&self, peer: &PeerId) -> usize {
+        self.outbox.borrow().get(peer).map_or(0, |b| b.len())
+    }
+
+    /// Number of bytes queued for sending to all peers.
+    pub fn total_queued(&self) -> usize {
+        self.outbox.borrow().values().map(|b| b.len()).sum()
+    }
+
     /// Push an output to the channel.
     pub fn push(&self, output: Io) {
         self.outbound.borrow_mut().push_back(output);
@@ -189,6 +384,8 @@ impl Outbox {
 
     /// Unregister peer. Clears the outbox.
     pub fn unregister(&mut self, peer: &PeerId) {
+        self.overflowed.borrow_mut().remove(peer);
+
         if let Some(outbox) = self.outbox.borrow_mut().remove(peer) {
             if !outbox.is_empty() {
                 debug!(target: self.target, "{}: Dropping outbox with {} bytes", peer, outbox.len());
@@ -200,36 +397,92 @@ impl Outbox {
     pub fn drain(&mut self) -> Drain {
         Drain {
             items: self.outbound.clone(),
+            writing: self.writing.clone(),
         }
     }
 
     /// Write the peer's output buffer to the given writer.
-    pub fn write<W: io::Write>(&mut self, peer: &PeerId, mut writer: W) -> io::Result<()> {
+    /// Returns the number of bytes written.
+    pub fn write<W: io::Write>(&mut self, peer: &PeerId, mut writer: W) -> io::Result<usize> {
+        let mut written = 0;
+
         if let Some(buf) = self.outbox.borrow_mut().get_mut(peer) {
             while !buf.is_empty() {
-                match writer.write(buf) {
+                // The buffer may wrap around, in which case its contents are split in two.
+                let (head, tail) = buf.as_slices();
+                let bufs = [io::IoSlice::new(head), io::IoSlice::new(tail)];
+
+                match writer.write_vectored(&bufs) {
                     Err(e) => return Err(e),
 
                     Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
                     Ok(n) => {
+                        // Nb. Removing bytes from the front of the buffer doesn't move the
+                        // remaining bytes, so partial writes are cheap to resume from.
                         buf.drain(..n);
+                        written += n;
                     }
                 }
             }
+            buf.shrink_to(SEND_BUFFER_CAPACITY);
         }
-        Ok(())
+        Ok(written)
     }
 
     /// Push a message to the channel.
     pub fn message(&mut self, addr: PeerId, message: NetworkMessage) -> &Self {
+        if self.overflowed.borrow().contains(&addr) {
+            trace!(target: self.target, "{}: Dropping {:?}", addr, message.cmd());
+            return self;
+        }
         debug!(target: self.target, "{}: Sending {:?}", addr, message.cmd());
 
         let mut outbox = self.outbox.borrow_mut();
-        let buffer = outbox.entry(addr).or_insert_with(Vec::new);
+        let buffer = outbox.entry(addr).or_default();
+
+        let start = buffer.len();
+        let cmd = message.cmd();
 
         // Nb. writing to a vector cannot result in an error.
-        self.builder.write(message, buffer).ok();
-        self.push(Io::Write(addr));
+        self.builder.write(message, &mut *buffer).ok();
+        self.stats
+            .borrow_mut()
+            .entry(cmd)
+            .or_default()
+            .record(buffer.len() - start, self.time.get());
+
+        if self.capture.borrow().is_some() {
+            let bytes = buffer.range(start..).copied().collect::<Vec<_>>();
+            self.captured(addr, Direction::Outbound, &bytes);
+        }
+
+        let limits = self.limits.get();
+        let overflowed = if buffer.len() > limits.peer {
+            Some(addr)
+        } else if outbox.values().map(|b| b.len()).sum::<usize>() > limits.total {
+            // Disconnect the peer we have the most data queued for, since it's
+            // likely the one that stalled.
+            outbox
+                .iter()
+                .max_by_key(|(_, b)| b.len())
+                .map(|(peer, _)| *peer)
+        } else {
+            None
+        };
+
+        if let Some(peer) = overflowed {
+            let buffer = outbox.remove(&peer).unwrap_or_default();
+
+            warn!(
+                target: self.target,
+                "{}: Send queue overflow with {} byte(s) queued", peer, buffer.len()
+            );
+            self.overflowed.borrow_mut().insert(peer);
+            self.disconnect(peer, DisconnectReason::QueueFull);
+        }
+        if !self.overflowed.borrow().contains(&addr) && self.writing.borrow_mut().insert(addr) {
+            self.push(Io::Write(addr));
+        }
         self
     }
 
@@ -242,13 +495,19 @@ impl Outbox {
 /// Draining iterator over outbound channel queue.
 pub struct Drain {
     items: Rc<RefCell<VecDeque<Io>>>,
+    writing: Rc<RefCell<HashSet<PeerId>>>,
 }
 
 impl Iterator for Drain {
     type Item = Io;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.items.borrow_mut().pop_front()
+        let item = self.items.borrow_mut().pop_front();
+
+        if let Some(Io::Write(addr)) = &item {
+            self.writing.borrow_mut().remove(addr);
+        }
+        item
     }
 }
 
@@ -277,7 +536,9 @@ pub trait Wakeup {
 
 impl Wakeup for Outbox {
     fn wakeup(&self, duration: LocalDuration) -> &Self {
-        self.push(Io::Wakeup(duration));
+        if !self.suspended.get() {
+            self.push(Io::Wakeup(duration));
+        }
         self
     }
 }
@@ -296,13 +557,17 @@ impl addrmgr::SyncAddresses for Outbox {
     fn send_addresses(&mut self, addr: PeerId, addrs: Vec<(BlockTime, Address)>) {
         self.message(addr, NetworkMessage::Addr(addrs));
     }
+
+    fn send_addresses_v2(&mut self, addr: PeerId, addrs: Vec<AddrV2Message>) {
+        self.message(addr, NetworkMessage::AddrV2(addrs));
+    }
 }
 
 impl peermgr::Connect for Outbox {
     fn connect(&self, addr: net::SocketAddr, timeout: LocalDuration) {
         info!(target: self.target, "[conn] {}: Connecting..", addr);
         self.push(Io::Connect(addr));
-        self.push(Io::Wakeup(timeout));
+        self.wakeup(timeout);
     }
 }
 
@@ -365,6 +630,10 @@ impl syncmgr::SyncHeaders for Outbox {
         self.message(addr, NetworkMessage::Headers(headers));
     }
 
+    fn send_inv(&mut self, addr: PeerId, inv: Vec<Inventory>) {
+        self.message(addr, NetworkMessage::Inv(inv));
+    }
+
     fn negotiate(&mut self, addr: PeerId) {
         self.message(addr, NetworkMessage::SendHeaders);
     }
@@ -393,8 +662,8 @@ impl peermgr::Handshake for Outbox {
         self
     }
 
-    fn wtxidrelay(&mut self, addr: PeerId) -> &mut Self {
-        self.message(addr, NetworkMessage::WtxidRelay);
+    fn feature(&mut self, addr: PeerId, feature: Feature) -> &mut Self {
+        self.message(addr, feature.message());
         self
     }
 }
@@ -408,7 +677,7 @@ impl peermgr::Handshake for () {
         self
     }
 
-    fn wtxidrelay(&mut self, _addr: PeerId) -> &mut Self {
+    fn feature(&mut self, _addr: PeerId, _feature: Feature) -> &mut Self {
         self
     }
 }
@@ -457,6 +726,21 @@ impl cbfmgr::SyncFilters for Outbox {
     fn send_cfilter(&mut self, addr: PeerId, cfilter: CFilter) {
         self.message(addr, NetworkMessage::CFilter(cfilter));
     }
+
+    fn get_cfcheckpt(&mut self, addr: PeerId, stop_hash: BlockHash, timeout: LocalDuration) {
+        self.message(
+            addr,
+            NetworkMessage::GetCFCheckpt(GetCFCheckpt {
+                filter_type: 0x0,
+                stop_hash,
+            }),
+        );
+        self.wakeup(timeout);
+    }
+
+    fn send_cfcheckpt(&mut self, addr: PeerId, checkpt: CFCheckpt) {
+        self.message(addr, NetworkMessage::CFCheckpt(checkpt));
+    }
 }
 
 impl invmgr::Inventories for Outbox {
@@ -472,6 +756,14 @@ impl invmgr::Inventories for Outbox {
         self.message(addr, NetworkMessage::Tx(tx));
     }
 
+    fn filterload(&mut self, addr: PeerId, filter: FilterLoad) {
+        self.message(addr, NetworkMessage::FilterLoad(filter));
+    }
+
+    fn mempool(&mut self, addr: PeerId) {
+        self.message(addr, NetworkMessage::MemPool);
+    }
+
     fn event(&self, event: invmgr::Event) {
         debug!(target: self.target, "[invmgr] {}", &event);
         self.event(Event::Inventory(event));
@@ -496,6 +788,7 @@ impl cbfmgr::Events for Outbox {
 #[cfg(test)]
 pub mod test {
     use super::*;
+    use crate::protocol::capture;
     use nakamoto_common::bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
 
     pub fn messages(
@@ -514,4 +807,186 @@ pub mod test {
         }
         msgs.into_iter()
     }
+
+    /// A writer that accepts a limited number of bytes before it would block.
+    struct Choked {
+        bytes: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl io::Write for Choked {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.capacity == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.capacity).min(7);
+
+            self.bytes.extend_from_slice(&buf[..n]);
+            self.capacity -= n;
+
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_partial_writes() {
+        let network = Network::Mainnet;
+        let addr: PeerId = ([88, 88, 88, 88], 8333).into();
+        let mut outbox = Outbox::new(network.clone(), 0, "test");
+        let mut writer = Choked {
+            bytes: Vec::new(),
+            capacity: 50,
+        };
+        let msgs = (0..8).map(NetworkMessage::Ping).collect::<Vec<_>>();
+        let mut expected = Vec::new();
+
+        for msg in msgs.iter().take(4) {
+            outbox.message(addr, msg.clone());
+        }
+        // The write blocks half-way through a message.
+        let err = outbox.write(&addr, &mut writer).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(outbox.queued(&addr), 4 * 32 - 50);
+
+        // More messages are queued while the socket is blocked.
+        for msg in msgs.iter().skip(4) {
+            outbox.message(addr, msg.clone());
+        }
+        writer.capacity = usize::MAX;
+        assert_eq!(outbox.write(&addr, &mut writer).unwrap(), 8 * 32 - 50);
+        assert_eq!(outbox.queued(&addr), 0);
+
+        // The peer receives all messages, in order, as if they were written at once.
+        for msg in msgs {
+            message::Builder::new(network.clone())
+                .write(msg, &mut expected)
+                .unwrap();
+        }
+        assert_eq!(writer.bytes, expected);
+    }
+
+    #[test]
+    fn test_write_batching() {
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let mut outbox = Outbox::new(Network::Mainnet, 0, "test");
+        let writes = |outbox: &mut Outbox| {
+            outbox
+                .drain()
+                .filter_map(|o| match o {
+                    Io::Write(addr) => Some(addr),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        outbox.message(alice, NetworkMessage::Ping(0));
+        outbox.message(bob, NetworkMessage::Inv(vec![]));
+        outbox.message(alice, NetworkMessage::Addr(vec![]));
+        outbox.message(alice, NetworkMessage::Pong(0));
+
+        // Messages queued before the outputs are drained are written with a single write.
+        assert_eq!(writes(&mut outbox), vec![alice, bob]);
+        assert_eq!(messages(&mut outbox, &alice).count(), 3);
+
+        // Once drained, new messages are written again.
+        outbox.message(alice, NetworkMessage::Ping(1));
+        assert_eq!(writes(&mut outbox), vec![alice]);
+        assert!(writes(&mut outbox).is_empty());
+    }
+
+    #[test]
+    fn test_capture() {
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut outbox = Outbox::new(Network::Mainnet, 0, "test");
+
+        let capture = Capture::new(file.reopen().unwrap());
+
+        outbox.capture(Some(capture.clone()));
+        outbox.message(alice, NetworkMessage::Ping(42));
+        outbox.capture(None);
+        outbox.message(alice, NetworkMessage::Pong(42));
+        capture.flush().unwrap();
+
+        let records = capture::read(io::BufReader::new(file))
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].peer, alice);
+        assert_eq!(records[0].direction, Direction::Outbound);
+        assert_eq!(
+            records[0].decode().unwrap().payload,
+            NetworkMessage::Ping(42)
+        );
+    }
+
+    #[test]
+    fn test_queue_limits() {
+        let alice: PeerId = ([88, 88, 88, 88], 8333).into();
+        let bob: PeerId = ([99, 99, 99, 99], 8333).into();
+        let mut outbox = Outbox::new(Network::Mainnet, 0, "test").limits(QueueLimits {
+            peer: 256,
+            total: 384,
+        });
+        let disconnected = |outbox: &mut Outbox| {
+            outbox
+                .drain()
+                .filter_map(|o| match o {
+                    Io::Disconnect(addr, DisconnectReason::QueueFull) => Some(addr),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        // A 32-byte ping message.
+        let ping = NetworkMessage::Ping(0);
+
+        for _ in 0..8 {
+            outbox.message(alice, ping.clone());
+        }
+        assert_eq!(outbox.queued(&alice), 256);
+        assert!(disconnected(&mut outbox).is_empty());
+
+        // Writing to the socket makes room in the queue.
+        messages(&mut outbox, &alice).for_each(drop);
+        assert_eq!(outbox.queued(&alice), 0);
+
+        for _ in 0..8 {
+            outbox.message(alice, ping.clone());
+        }
+        // Exceeding the per-peer limit disconnects the peer, and drops its queue.
+        outbox.message(alice, ping.clone());
+        assert_eq!(disconnected(&mut outbox), vec![alice]);
+        assert_eq!(outbox.queued(&alice), 0);
+
+        // Further messages to the peer are dropped, until it's unregistered.
+        outbox.message(alice, ping.clone());
+        assert_eq!(outbox.queued(&alice), 0);
+        outbox.unregister(&alice);
+
+        // Exceeding the total limit disconnects the peer with the largest queue.
+        for _ in 0..4 {
+            outbox.message(alice, ping.clone());
+        }
+        for _ in 0..8 {
+            outbox.message(bob, ping.clone());
+        }
+        assert!(disconnected(&mut outbox).is_empty());
+        outbox.message(alice, ping);
+        assert_eq!(disconnected(&mut outbox), vec![bob]);
+        assert_eq!(outbox.total_queued(), 160);
+
+        // Limits updated through a clone apply to the original.
+        outbox.clone().set_limits(QueueLimits {
+            peer: 64,
+            total: 1024,
+        });
+        outbox.message(alice, NetworkMessage::Ping(0));
+        assert_eq!(disconnected(&mut outbox), vec![alice]);
+    }
 }
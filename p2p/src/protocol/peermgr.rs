@@ -14,15 +14,18 @@
 //!   3. Send `verack` message.
 //!   4. Expect `verack` message from remote.
 //!
+use std::collections::VecDeque;
 use std::net;
 use std::sync::Arc;
 
+use microserde::json::{Number, Value};
+
 use nakamoto_common::bitcoin::network::address::Address;
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::message_network::VersionMessage;
 
 use nakamoto_common::p2p::peer::{AddressSource, Source};
-use nakamoto_common::p2p::Domain;
+use nakamoto_common::p2p::{Domain, DomainPolicy};
 
 use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
 use nakamoto_common::block::Height;
@@ -31,14 +34,16 @@ use nakamoto_common::source;
 
 use crate::protocol::addrmgr;
 
+use super::{dump, Feature, Features, Hooks, Link, PeerId, Socket, Whitelist};
 use super::{
     output::{Disconnect, Wakeup},
+    scheduler::{Cadence, Scheduler},
     DisconnectReason,
 };
-use super::{Hooks, Link, PeerId, Socket, Whitelist};
 
-/// Time to wait for response during peer handshake before disconnecting the peer.
-pub const HANDSHAKE_TIMEOUT: LocalDuration = LocalDuration::from_secs(10);
+/// Time peers have to complete the handshake, counting from connection, before they are
+/// disconnected.
+pub const HANDSHAKE_TIMEOUT: LocalDuration = LocalDuration::from_secs(20);
 /// Time to wait for a new connection.
 /// TODO: Should be in config.
 pub const CONNECTION_TIMEOUT: LocalDuration = LocalDuration::from_secs(6);
@@ -48,6 +53,13 @@ pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
 pub const TARGET_OUTBOUND_PEERS: usize = 8;
 /// Maximum number of inbound peer connections.
 pub const MAX_INBOUND_PEERS: usize = 16;
+/// Target number of block-relay-only outbound peer connections.
+pub const TARGET_BLOCK_RELAY_PEERS: usize = 2;
+/// Time between feeler connections.
+pub const FEELER_INTERVAL: LocalDuration = LocalDuration::from_mins(2);
+/// Time to wait for a connection attempt before dialing the next address of a peer with
+/// multiple addresses, as recommended by RFC 8305.
+pub const DIAL_STAGGER: LocalDuration = LocalDuration::from_millis(250);
 
 /// Maximum height difference for a stale peer, to maintain the connection (2 weeks).
 const MAX_STALE_HEIGHT_DIFFERENCE: Height = 2016;
@@ -79,6 +91,10 @@ pub enum Event {
         height: Height,
         /// Protocol version.
         version: u32,
+        /// Whether the peer relays transactions.
+        relay: bool,
+        /// The address the peer advertised for itself, if any.
+        advertised_addr: Option<net::SocketAddr>,
     },
     /// Connecting to a peer found from the specified source.
     Connecting(PeerId, Source, ServiceFlags),
@@ -90,6 +106,10 @@ pub enum Event {
     Connected(PeerId, Link),
     /// A peer has been disconnected.
     Disconnected(PeerId, DisconnectReason),
+    /// An address was banned.
+    Banned(Ban),
+    /// An address was unbanned, or its ban expired.
+    Unbanned(net::IpAddr),
 }
 
 impl std::fmt::Display for Event {
@@ -104,11 +124,12 @@ impl std::fmt::Display for Event {
                 addr,
                 height,
                 services,
+                user_agent,
                 ..
             } => write!(
                 fmt,
-                "{}: Peer negotiated with services {} and height {}..",
-                addr, services, height
+                "{}: Peer negotiated with services {} and height {} ({})..",
+                addr, services, height, user_agent
             ),
             Self::Connecting(addr, source, services) => {
                 write!(
@@ -124,18 +145,44 @@ impl std::fmt::Display for Event {
             Self::Disconnected(addr, reason) => {
                 write!(fmt, "Disconnected from {} ({})", &addr, reason)
             }
+            Self::Banned(Ban { addr, until: None }) => {
+                write!(fmt, "{}: Address banned permanently", addr)
+            }
+            Self::Banned(Ban {
+                addr,
+                until: Some(until),
+            }) => {
+                write!(fmt, "{}: Address banned until {}", addr, until)
+            }
+            Self::Unbanned(addr) => write!(fmt, "{}: Address unbanned", addr),
         }
     }
 }
 
+/// A banned address.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Ban {
+    /// Banned IP address. All ports are banned.
+    pub addr: net::IpAddr,
+    /// Time at which the ban expires, or `None` if the ban is permanent.
+    pub until: Option<LocalTime>,
+}
+
+impl Ban {
+    /// Check whether the ban has expired.
+    pub fn is_expired(&self, now: LocalTime) -> bool {
+        self.until.is_some_and(|t| now >= t)
+    }
+}
+
 /// The ability to negotiate protocols between peers.
 pub trait Handshake {
     /// Send a `version` message.
     fn version(&mut self, addr: PeerId, msg: VersionMessage) -> &mut Self;
     /// Send a `verack` message.
     fn verack(&mut self, addr: PeerId) -> &mut Self;
-    /// Send a BIP-339 `wtxidrelay` message.
-    fn wtxidrelay(&mut self, addr: PeerId) -> &mut Self;
+    /// Signal support for an optional protocol feature.
+    fn feature(&mut self, addr: PeerId, feature: Feature) -> &mut Self;
 }
 
 /// Ability to connect to peers.
@@ -161,6 +208,8 @@ pub struct Config {
     pub services: ServiceFlags,
     /// Peer addresses to persist connections with.
     pub persistent: Vec<net::SocketAddr>,
+    /// Only connect to persistent peers, and refuse all other connections.
+    pub connect_only: bool,
     /// Services required by peers.
     pub required_services: ServiceFlags,
     /// Peer services preferred. We try to maintain as many
@@ -170,6 +219,12 @@ pub struct Config {
     pub target_outbound_peers: usize,
     /// Maximum number of inbound peer connections.
     pub max_inbound_peers: usize,
+    /// Target number of block-relay-only outbound peer connections.
+    pub target_block_relay_peers: usize,
+    /// How often feeler connections are made, or `None` to disable feelers.
+    pub feeler: Option<Cadence>,
+    /// Time peers have to complete the handshake, counting from connection.
+    pub handshake_timeout: LocalDuration,
     /// Maximum time to wait between reconnection attempts.
     pub retry_max_wait: LocalDuration,
     /// Minimum time to wait between reconnection attempts.
@@ -178,6 +233,12 @@ pub struct Config {
     pub user_agent: &'static str,
     /// Supported communication domains.
     pub domains: Vec<Domain>,
+    /// Policy for choosing the domain of outbound connections.
+    pub domain_policy: DomainPolicy,
+    /// Banned addresses. We never connect to, or accept connections from these.
+    pub bans: Vec<Ban>,
+    /// Optional protocol features we signal to peers.
+    pub features: Features,
 }
 
 /// Peer negotiation (handshake) state.
@@ -201,15 +262,52 @@ pub struct Connection {
     pub link: Link,
     /// Connected since this time.
     pub since: LocalTime,
+    /// Connection class.
+    pub kind: ConnectionType,
+    /// Nonce of the `version` message we send on this connection.
+    pub nonce: u64,
+}
+
+impl Connection {
+    /// Communication domain of the connection.
+    pub fn domain(&self) -> Domain {
+        Domain::for_address(&self.socket.addr)
+    }
+}
+
+/// Class of an outbound connection. Each class has its own connection budget.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum ConnectionType {
+    /// Full relay of blocks, transactions and addresses.
+    #[default]
+    FullRelay,
+    /// Block relay only. No transactions or addresses are relayed to or from the peer.
+    BlockRelay,
+    /// Short-lived connection, used to check that an address is reachable.
+    /// Disconnected as soon as the handshake completes.
+    Feeler,
+}
+
+impl std::fmt::Display for ConnectionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::FullRelay => write!(f, "full-relay"),
+            Self::BlockRelay => write!(f, "block-relay"),
+            Self::Feeler => write!(f, "feeler"),
+        }
+    }
 }
 
 /// Peer state.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum Peer {
     /// A connection is being attempted.
     Connecting {
         /// Time the connection was attempted.
         time: LocalTime,
+        /// Connection class.
+        kind: ConnectionType,
     },
     /// A connection is established.
     Connected {
@@ -236,24 +334,47 @@ pub struct PeerInfo {
     pub time_offset: TimeOffset,
     /// Whether this peer relays transactions.
     pub relay: bool,
-    /// Whether this peer supports BIP-339.
-    pub wtxidrelay: bool,
+    /// Optional protocol features signalled by the peer.
+    pub features: Features,
+    /// Optional protocol features we signalled to the peer.
+    pub signalled: Features,
     /// The max protocol version supported by both the peer and nakamoto.
     pub version: u32,
+    /// The address the peer advertised for itself, if any.
+    pub advertised_addr: Option<net::SocketAddr>,
 
-    /// Peer nonce. Used to detect self-connections.
+    /// Nonce of the peer's `version` message. Used to detect duplicate connections.
     nonce: u64,
     /// Peer handshake state.
     state: HandshakeState,
 }
 
 impl PeerInfo {
+    /// Features signalled by both the peer and us. Only these can be used with the peer.
+    pub fn negotiated(&self) -> Features {
+        self.features.intersection(self.signalled)
+    }
+
     /// Check whether the peer has finished negotiating and received our `version`.
     pub fn is_negotiated(&self) -> bool {
         matches!(self.state, HandshakeState::ReceivedVerack { .. })
     }
 }
 
+/// Staggered connection attempts to the addresses of a single peer. The first address
+/// to connect wins, and the other attempts are cancelled.
+#[derive(Debug, Clone)]
+struct Race {
+    /// Addresses that weren't dialed yet, in dialing order.
+    pending: VecDeque<PeerId>,
+    /// Addresses being dialed.
+    dialing: Vec<PeerId>,
+    /// Time at which the next pending address is dialed.
+    next: LocalTime,
+    /// IPs added to the whitelist for this race. Only the winner's stays whitelisted.
+    whitelisted: Vec<net::IpAddr>,
+}
+
 /// Manages peer connections and handshake.
 #[derive(Debug)]
 pub struct PeerManager<U, C> {
@@ -263,10 +384,17 @@ pub struct PeerManager<U, C> {
     retry_at: HashMap<net::SocketAddr, LocalTime>,
     retry_attempts: HashMap<net::SocketAddr, u32>,
 
-    /// Last time we were idle.
-    last_idle: Option<LocalTime>,
+    /// Periodic tasks.
+    schedule: Scheduler,
     /// Connection states.
     peers: HashMap<net::SocketAddr, Peer>,
+    /// Ongoing connection races to peers with multiple addresses.
+    races: Vec<Race>,
+    /// Banned addresses, with their ban expiry time.
+    bans: HashMap<net::IpAddr, Option<LocalTime>>,
+    /// Whether network activity is enabled. When disabled, we neither dial nor
+    /// accept connections.
+    active: bool,
     upstream: U,
     rng: fastrand::Rng,
     hooks: Hooks,
@@ -275,15 +403,31 @@ pub struct PeerManager<U, C> {
 
 impl<U: Handshake + Wakeup + Connect + Disconnect + Events, C: Clock> PeerManager<U, C> {
     /// Create a new peer manager.
-    pub fn new(config: Config, rng: fastrand::Rng, hooks: Hooks, upstream: U, clock: C) -> Self {
+    pub fn new(
+        mut config: Config,
+        rng: fastrand::Rng,
+        hooks: Hooks,
+        upstream: U,
+        clock: C,
+    ) -> Self {
         let peers = HashMap::with_hasher(rng.clone().into());
+        let mut bans = HashMap::with_hasher(rng.clone().into());
+
+        for ban in config.bans.drain(..) {
+            bans.insert(ban.addr, ban.until);
+        }
+        let mut schedule = Scheduler::new(rng.clone());
+        schedule.schedule("idle", Cadence::every(IDLE_TIMEOUT));
 
         Self {
             config,
             retry_at: HashMap::with_hasher(rng.clone().into()),
             retry_attempts: HashMap::with_hasher(rng.clone().into()),
-            last_idle: None,
+            schedule,
             peers,
+            races: Vec::new(),
+            bans,
+            active: true,
             upstream,
             rng,
             hooks,
@@ -297,6 +441,7 @@ impl<U: Handshake + Wakeup + Connect + Disconnect + Events, C: Clock> PeerManage
             .config
             .persistent
             .iter()
+            .filter(|addr| !self.is_banned(&addr.ip()))
             .take(self.config.target_outbound_peers)
             .cloned()
             .collect::<Vec<_>>();
@@ -330,6 +475,9 @@ impl<U: Handshake + Wakeup + Connect + Disconnect + Events, C: Clock> PeerManage
     }
 
     fn retrier_reconnect(&mut self) {
+        if !self.active {
+            return;
+        }
         let local_time = self.clock.local_time();
         let peers: Vec<_> = self
             .retry_at
@@ -361,10 +509,15 @@ impl<U: Handshake + Wakeup + Connect + Disconnect + Events, C: Clock> PeerManage
         }
         debug_assert!(!self.is_connected(&addr));
 
-        // TODO: There is a chance that we simultaneously connect to a peer that is connecting
-        // to us. This would create two connections to the same peer, one outbound and one
-        // inbound. To prevent this, we could look at IPs when receiving inbound connections,
-        // to check whether we are already connected to the peer.
+        // Inbound connections are always treated as full relay.
+        let kind = match self.peers.get(&addr) {
+            Some(Peer::Connecting { kind, .. }) if link.is_outbound() => *kind,
+            _ => ConnectionType::FullRelay,
+        };
+
+        // Nb. We may simultaneously connect to a peer that is connecting to us. One of the
+        // two connections is dropped once the `version` messages are exchanged.
+        let nonce = self.rng.u64(..);
 
         self.peers.insert(
             addr,
@@ -374,15 +527,29 @@ impl<U: Handshake + Wakeup + Connect + Disconnect + Events, C: Clock> PeerManage
                     local_addr,
                     link,
                     since: local_time,
+                    kind,
+                    nonce,
                 },
                 peer: None,
             },
         );
         self.retrier_remove_peer(&addr);
 
+        if link.is_outbound() {
+            self.race_won(&addr);
+        }
+
         match link {
             Link::Inbound => {
-                if self.connected().filter(|c| c.link.is_inbound()).count()
+                if !self.active {
+                    self._disconnect(addr, DisconnectReason::NetworkInactive);
+                } else if self.is_banned(&addr.ip()) {
+                    self._disconnect(addr, DisconnectReason::PeerBanned);
+                } else if self.config.connect_only && !self.is_persistent(&addr) {
+                    // In connect-only mode, we don't allow connections from peers outside
+                    // of our persistent set.
+                    self._disconnect(addr, DisconnectReason::PeerNotAllowed);
+                } else if self.connected().filter(|c| c.link.is_inbound()).count()
                     >= self.config.max_inbound_peers
                 {
                     // TODO: Test this branch.
@@ -393,15 +560,14 @@ impl<U: Handshake + Wakeup + Connect + Disconnect + Events, C: Clock> PeerManage
                 }
             }
             Link::Outbound => {
-                let nonce = self.rng.u64(..);
                 self.upstream.version(
                     addr,
                     self.version(addr, local_addr, nonce, height, local_time),
                 );
             }
         }
-        // Set a timeout for receiving the `version` message.
-        self.upstream.wakeup(HANDSHAKE_TIMEOUT);
+        // Set a deadline for completing the handshake.
+        self.upstream.wakeup(self.config.handshake_timeout);
         self.upstream.event(Event::Connected(addr, link));
     }
 
@@ -428,8 +594,11 @@ impl<U: Handshake + Wakeup + Connect + Disconnect + Events, C: Clock> PeerManage
         }
 
         self.peers.remove(addr);
+        self.race_lost(addr);
 
-        if self.config.persistent.contains(addr) {
+        if !self.active {
+            // Don't reconnect while network activity is disabled.
+        } else if self.config.persistent.contains(addr) && !self.is_banned(&addr.ip()) {
             self.retrier_add_peer(addr, local_time);
         } else {
             // If an outbound peer disconnected, we should make sure to maintain
@@ -438,21 +607,27 @@ impl<U: Handshake + Wakeup + Connect + Disconnect + Events, C: Clock> PeerManage
         }
     }
 
-    /// Called when a `wtxidrelay` message was received.
-    pub fn received_wtxidrelay(&mut self, addr: &PeerId) {
+    /// Called when a message signalling an optional protocol feature was received.
+    pub fn received_feature(&mut self, addr: &PeerId, feature: Feature) {
         if let Some(Peer::Connected {
             peer: Some(peer),
             conn: _,
         }) = self.peers.get_mut(addr)
         {
-            match peer.state {
-                HandshakeState::ReceivedVersion { .. } => peer.wtxidrelay = true,
-                _ => self.disconnect(
+            match (peer.state, feature) {
+                (HandshakeState::ReceivedVerack { .. }, Feature::WtxidRelay) => self.disconnect(
                     *addr,
                     DisconnectReason::PeerMisbehaving(
                         "`wtxidrelay` must be received before `verack`",
                     ),
                 ),
+                (HandshakeState::ReceivedVerack { .. }, Feature::AddrV2) => self.disconnect(
+                    *addr,
+                    DisconnectReason::PeerMisbehaving(
+                        "`sendaddrv2` must be received before `verack`",
+                    ),
+                ),
+                _ => peer.features.insert(feature),
             }
         }
     }
@@ -479,7 +654,12 @@ impl<U: Handshake + Wakeup + Connect + Disconnect + Events, C: Clock> PeerManage
     ) -> Result<(), DisconnectReason> {
         let now = self.clock.local_time();
 
-        if let Some(Peer::Connected { conn, .. }) = self.peers.get(addr) {
+        if let Some(Peer::Connected { conn, peer }) = self.peers.get(addr) {
+            if peer.is_some() {
+                return Err(DisconnectReason::PeerMisbehaving(
+                    "duplicate `version` message received",
+                ));
+            }
             self.upstream.event(Event::VersionReceived {
                 addr: *addr,
                 msg: msg.clone(),
@@ -500,6 +680,8 @@ impl<U: Handshake + Wakeup + Connect + Disconnect + Events, C: Clock> PeerManage
                 nonce,
                 // Our address, as seen by the remote peer.
                 receiver,
+                // The peer's address, as seen by itself.
+                sender,
                 // Relay node.
                 relay,
                 ..
@@ -507,18 +689,28 @@ impl<U: Handshake + Wakeup + Connect + Disconnect + Events, C: Clock> PeerManage
 
             let target = self.config.target_outbound_peers;
             let preferred = self.config.preferred_services;
+            // Peers often don't know their own address, and leave it empty.
+            let advertised_addr = sender
+                .socket_addr()
+                .ok()
+                .filter(|a| !a.ip().is_unspecified() && a.port() != 0);
             let trusted = self.config.whitelist.contains(&addr.ip(), &user_agent)
                 || addrmgr::is_local(&addr.ip());
 
-            // Don't support peers with too old of a protocol version.
-            if version < super::MIN_PROTOCOL_VERSION {
+            // Don't support peers with too old, or nonsensical protocol versions.
+            if !(super::MIN_PROTOCOL_VERSION..=super::MAX_PROTOCOL_VERSION).contains(&version) {
                 return Err(DisconnectReason::PeerProtocolVersion(version));
             }
+            if start_height < 0 {
+                return Err(DisconnectReason::PeerMisbehaving(
+                    "negative start height in `version` message",
+                ));
+            }
 
-            // Peers that don't advertise the `NETWORK` service are not full nodes.
-            // It's not so useful for us to connect to them, because they're likely
+            // Peers that don't advertise the services we need from them given their role,
+            // eg. that aren't full nodes, aren't so useful to connect to, since they're likely
             // to be less secure.
-            if conn.link.is_outbound() && !services.has(self.config.required_services) && !trusted {
+            if !services.has(self.required_services(conn)) && !trusted {
                 return Err(DisconnectReason::PeerServices(services));
             }
             // If the peer is too far behind, there's no use connecting to it, we'll
@@ -529,19 +721,41 @@ impl<U: Handshake + Wakeup + Connect + Disconnect + Events, C: Clock> PeerManage
             {
                 return Err(DisconnectReason::PeerHeight(start_height as Height));
             }
-            // Check for self-connections. We only need to check one link direction,
-            // since in the case of a self-connection, we will see both link directions.
-            for (peer, conn) in self.peers() {
-                if conn.link.is_outbound() && peer.nonce == nonce {
+            // Check for self-connections, ie. whether we're receiving the nonce we sent on
+            // an outbound connection. Both ends of the connection are dropped.
+            if conn.link.is_inbound() {
+                let outbound = self
+                    .connected()
+                    .find(|c| c.link.is_outbound() && c.nonce == nonce)
+                    .map(|c| c.socket.addr);
+
+                if let Some(outbound) = outbound {
+                    self._disconnect(outbound, DisconnectReason::SelfConnection);
+
                     return Err(DisconnectReason::SelfConnection);
                 }
             }
+            // Check for a connection to the same peer in the other direction. Connections
+            // to peers we trust or chose to stay connected with are left alone.
+            let duplicate = if trusted || self.is_persistent(addr) {
+                None
+            } else {
+                self.duplicate(conn, nonce, advertised_addr)
+            };
+            if duplicate == Some(conn.socket.addr) {
+                return Err(DisconnectReason::DuplicateConnection);
+            }
 
             // If this peer doesn't have the preferred services, and we already have enough peers,
-            // disconnect this peer.
+            // disconnect this peer. Only full-relay peers count towards the target.
             if conn.link.is_outbound()
+                && conn.kind == ConnectionType::FullRelay
                 && !services.has(preferred)
-                && self.negotiated(Link::Outbound).count() >= target
+                && self
+                    .negotiated(Link::Outbound)
+                    .filter(|(_, c)| c.kind == ConnectionType::FullRelay)
+                    .count()
+                    >= target
             {
                 return Err(DisconnectReason::ConnectionLimit);
             }
@@ -551,29 +765,33 @@ impl<U: Handshake + Wakeup + Connect + Disconnect + Events, C: Clock> PeerManage
                 return Err(DisconnectReason::Other(reason));
             }
 
-            // Record the address this peer has of us.
-            if let Ok(addr) = receiver.socket_addr() {
-                addrs.record_local_address(addr);
-            }
+            // Record the address this peer has of us. Only outbound peers are relied on
+            // to tell us how we're reachable, since inbound peers are chosen by others.
+            if let Ok(local) = receiver.socket_addr() {
+                addrs.record_local_address(local);
 
-            match conn.link {
-                Link::Inbound => {
-                    self.upstream
-                        .version(
-                            conn.socket.addr,
-                            self.version(conn.socket.addr, conn.local_addr, nonce, height, now),
-                        )
-                        .wtxidrelay(conn.socket.addr)
-                        .verack(conn.socket.addr)
-                        .wakeup(HANDSHAKE_TIMEOUT);
-                }
-                Link::Outbound => {
-                    self.upstream
-                        .wtxidrelay(conn.socket.addr)
-                        .verack(conn.socket.addr)
-                        .wakeup(HANDSHAKE_TIMEOUT);
+                if conn.link.is_outbound() {
+                    addrs.record_external_address(local, *addr);
                 }
             }
+
+            if conn.link.is_inbound() {
+                self.upstream.version(
+                    conn.socket.addr,
+                    self.version(conn.socket.addr, conn.local_addr, conn.nonce, height, now),
+                );
+            }
+            // Signal the handshake features supported by the peer's protocol version.
+            let signalled = self
+                .config
+                .features
+                .iter()
+                .filter(|f| f.is_handshake() && version >= f.min_version())
+                .collect::<Features>();
+            for feature in signalled.iter() {
+                self.upstream.feature(conn.socket.addr, feature);
+            }
+            self.upstream.verack(conn.socket.addr);
             let conn = conn.clone();
 
             self.peers.insert(
@@ -588,11 +806,17 @@ impl<U: Handshake + Wakeup + Connect + Disconnect + Events, C: Clock> PeerManage
                         user_agent,
                         state: HandshakeState::ReceivedVersion { since: now },
                         relay,
-                        wtxidrelay: false,
+                        features: Features::NONE,
+                        signalled,
                         version: u32::min(self.config.protocol_version, version),
+                        advertised_addr,
                     }),
                 },
             );
+
+            if let Some(other) = duplicate {
+                self._disconnect(other, DisconnectReason::DuplicateConnection);
+            }
         }
 
         Ok(())
@@ -617,17 +841,37 @@ impl<U: Handshake + Wakeup + Connect + Disconnect + Events, C: Clock> PeerManage
                     user_agent: peer.user_agent.clone(),
                     height: peer.height,
                     version: peer.version,
+                    relay: peer.relay,
+                    advertised_addr: peer.advertised_addr,
                 });
 
                 peer.state = HandshakeState::ReceivedVerack { since: local_time };
 
-                return Some((peer.clone(), conn.clone()));
+                // Signal the remaining features supported by the peer's protocol version.
+                for feature in self.config.features.iter() {
+                    if !feature.is_handshake() && peer.version >= feature.min_version() {
+                        self.upstream.feature(*addr, feature);
+                        peer.signalled.insert(feature);
+                    }
+                }
+
+                let negotiated = (peer.clone(), conn.clone());
+                // Feelers have served their purpose once the handshake completes.
+                if conn.kind == ConnectionType::Feeler {
+                    self._disconnect(*addr, DisconnectReason::Feeler);
+                }
+                return Some(negotiated);
             } else {
                 self._disconnect(
                     *addr,
                     DisconnectReason::PeerMisbehaving("unexpected `verack` message received"),
                 );
             }
+        } else if let Some(Peer::Connected { peer: None, .. }) = self.peers.get(addr) {
+            self._disconnect(
+                *addr,
+                DisconnectReason::PeerMisbehaving("`verack` received before `version`"),
+            );
         }
         None
     }
@@ -641,24 +885,19 @@ impl<U: Handshake + Wakeup + Connect + Disconnect + Events, C: Clock> PeerManage
         for addr in self.idle_peers(local_time).collect::<Vec<_>>() {
             timed_out.push((addr, "connection"));
         }
-        // Time out peers that haven't sent a `verack` quickly enough.
-        for (peer, conn) in self.peers() {
-            match peer.state {
-                HandshakeState::ReceivedVersion { since } => {
-                    if local_time - since >= HANDSHAKE_TIMEOUT {
-                        timed_out.push((conn.socket.addr, "handshake"));
-                    }
-                }
-                HandshakeState::ReceivedVerack { .. } => {}
+        // Time out peers that haven't completed the handshake quickly enough. The reason
+        // is the message we're still waiting for.
+        for peer in self.peers.values() {
+            let Peer::Connected { conn, peer } = peer else {
+                continue;
+            };
+            if local_time - conn.since < self.config.handshake_timeout {
+                continue;
             }
-        }
-        // Time out peers that haven't sent a `version` quickly enough.
-        for connected in self.peers.values().filter_map(|c| match c {
-            Peer::Connected { conn, peer: None } => Some(conn),
-            _ => None,
-        }) {
-            if local_time - connected.since >= HANDSHAKE_TIMEOUT {
-                timed_out.push((connected.socket.addr, "handshake"));
+            match peer {
+                None => timed_out.push((conn.socket.addr, "version")),
+                Some(p) if !p.is_negotiated() => timed_out.push((conn.socket.addr, "verack")),
+                Some(_) => {}
             }
         }
         // Disconnect all timed out peers.
@@ -679,13 +918,245 @@ impl<U: Handshake + Wakeup + Connect + Disconnect + Events, C: Clock> PeerManage
             self._disconnect(addr, DisconnectReason::PeerDropped);
         }
 
-        if local_time - self.last_idle.unwrap_or_default() >= IDLE_TIMEOUT {
+        if let Some(next) = self.schedule.due(&"idle", local_time) {
             self.maintain_connections(addrs);
-            self.upstream.wakeup(IDLE_TIMEOUT);
-            self.last_idle = Some(local_time);
+            self.upstream.wakeup(next);
         }
-
+        self.maintain_feeler_connection(addrs);
+        self.maintain_races();
         self.retrier_reconnect();
+
+        // Lift expired bans.
+        let expired = self
+            .bans()
+            .filter(|b| b.is_expired(local_time))
+            .map(|b| b.addr)
+            .collect::<Vec<_>>();
+        for addr in expired {
+            self.unban(&addr);
+        }
+    }
+
+    /// Ban an address for the given duration, or permanently if no duration is given.
+    /// Connections to and from this address are dropped.
+    pub fn ban(&mut self, addr: net::IpAddr, duration: Option<LocalDuration>) {
+        let ban = Ban {
+            addr,
+            until: duration.map(|d| self.clock.local_time() + d),
+        };
+        let peers = self
+            .peers
+            .iter()
+            .filter(|(a, p)| a.ip() == addr && !matches!(p, Peer::Disconnecting))
+            .map(|(a, _)| *a)
+            .collect::<Vec<_>>();
+
+        for peer in peers {
+            self._disconnect(peer, DisconnectReason::PeerBanned);
+        }
+        self.retry_at.retain(|a, _| a.ip() != addr);
+        self.bans.insert(addr, ban.until);
+        self.upstream.event(Event::Banned(ban));
+
+        if let Some(duration) = duration {
+            self.upstream.wakeup(duration);
+        }
+    }
+
+    /// Lift the ban on an address. Returns `false` if the address wasn't banned.
+    pub fn unban(&mut self, addr: &net::IpAddr) -> bool {
+        if self.bans.remove(addr).is_none() {
+            return false;
+        }
+        self.upstream.event(Event::Unbanned(*addr));
+
+        // Reconnect to persistent peers that were banned.
+        let persistent = self
+            .config
+            .persistent
+            .iter()
+            .filter(|p| p.ip() == *addr)
+            .cloned()
+            .collect::<Vec<_>>();
+        for peer in persistent {
+            self.connect(&peer);
+        }
+        true
+    }
+
+    /// Find a connection to the same peer as the given connection, in the other direction,
+    /// given the nonce and address the peer sent us on the latter. Of the two connections,
+    /// we keep the one whose initiator sent the highest nonce: since both ends know both
+    /// nonces, they drop the same connection. Returns the connection to drop, if any.
+    ///
+    /// Peers are identified by the address we connected to on outbound connections, and by
+    /// the address they advertised on inbound connections. Many nodes can share an IP, eg.
+    /// behind a NAT, so inbound peers that don't advertise their address are never taken
+    /// for duplicates.
+    fn duplicate(
+        &self,
+        conn: &Connection,
+        nonce: u64,
+        advertised_addr: Option<net::SocketAddr>,
+    ) -> Option<PeerId> {
+        let initiator = |c: &Connection, remote: Option<u64>| {
+            if c.link.is_outbound() {
+                Some(c.nonce)
+            } else {
+                remote
+            }
+        };
+        let identity = |c: &Connection, advertised: Option<net::SocketAddr>| {
+            if c.link.is_outbound() {
+                Some(c.socket.addr)
+            } else {
+                advertised
+            }
+        };
+        let ours = initiator(conn, Some(nonce))?;
+        let remote = identity(conn, advertised_addr)?;
+
+        // Nb. Different nodes on the same host share the loopback address.
+        if remote.ip().is_loopback() {
+            return None;
+        }
+
+        self.peers.iter().find_map(|(addr, peer)| {
+            let Peer::Connected { conn: other, peer } = peer else {
+                return None;
+            };
+            if other.link == conn.link
+                || identity(other, peer.as_ref().and_then(|p| p.advertised_addr)) != Some(remote)
+            {
+                return None;
+            }
+            // Feelers are short-lived, and don't take the place of a regular connection.
+            if other.kind == ConnectionType::Feeler || conn.kind == ConnectionType::Feeler {
+                return None;
+            }
+            let theirs = initiator(other, peer.as_ref().map(|p| p.nonce))?;
+
+            if theirs > ours {
+                Some(conn.socket.addr)
+            } else {
+                Some(*addr)
+            }
+        })
+    }
+
+    /// Get the services a peer must offer given its connection. Inbound peers chose to
+    /// connect to us, and feelers only check that an address is reachable, so nothing is
+    /// required of them. Block-relay peers are only useful if they serve blocks.
+    fn required_services(&self, conn: &Connection) -> ServiceFlags {
+        match (conn.link, conn.kind) {
+            (Link::Inbound, _) | (_, ConnectionType::Feeler) => ServiceFlags::NONE,
+            (Link::Outbound, ConnectionType::FullRelay) => self.config.required_services,
+            (Link::Outbound, ConnectionType::BlockRelay) => {
+                self.config.required_services | ServiceFlags::NETWORK
+            }
+        }
+    }
+
+    /// Update the connection targets, and connect to new peers if needed.
+    pub fn set_connection_targets<A: AddressSource>(
+        &mut self,
+        target_outbound_peers: Option<usize>,
+        max_inbound_peers: Option<usize>,
+        addrs: &mut A,
+    ) {
+        if let Some(target) = target_outbound_peers {
+            self.config.target_outbound_peers = target;
+        }
+        if let Some(max) = max_inbound_peers {
+            self.config.max_inbound_peers = max;
+        }
+        self.maintain_connections(addrs);
+    }
+
+    /// Enable or disable network activity. When disabled, all peers are disconnected
+    /// and no new connections are made or accepted, until network activity is enabled
+    /// again. Returns `false` if the setting was unchanged.
+    pub fn set_active<A: AddressSource>(&mut self, active: bool, addrs: &mut A) -> bool {
+        if self.active == active {
+            return false;
+        }
+        self.active = active;
+
+        if active {
+            self.reconnect(addrs);
+        } else {
+            let peers = self
+                .peers
+                .iter()
+                .filter(|(_, p)| !matches!(p, Peer::Disconnecting))
+                .map(|(a, _)| *a)
+                .collect::<Vec<_>>();
+
+            for addr in peers {
+                self._disconnect(addr, DisconnectReason::NetworkInactive);
+            }
+            self.races.clear();
+            self.retry_at.clear();
+            self.retry_attempts.clear();
+        }
+        true
+    }
+
+    /// Check whether network activity is enabled.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Reconnect to our persistent peers right away, without waiting for their retry
+    /// timers, and connect to more peers if we're below our targets.
+    pub fn reconnect<A: AddressSource>(&mut self, addrs: &mut A) {
+        let persistent = self
+            .config
+            .persistent
+            .iter()
+            .filter(|addr| !self.is_banned(&addr.ip()))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for addr in persistent {
+            self.connect(&addr);
+        }
+        self.maintain_connections(addrs);
+    }
+
+    /// Check whether a peer is one of our persistent peers. Inbound connections
+    /// are matched by IP address only, since the remote port is ephemeral.
+    pub fn is_persistent(&self, addr: &PeerId) -> bool {
+        self.config.persistent.iter().any(|p| p.ip() == addr.ip())
+    }
+
+    /// Get our persistent peers, including those added at runtime.
+    pub fn persistent(&self) -> impl Iterator<Item = &net::SocketAddr> {
+        self.config.persistent.iter()
+    }
+
+    /// Add a persistent peer, which is reconnected to whenever it disconnects, and connect
+    /// to it. Returns `false` if the peer was already persistent.
+    pub fn add_persistent(&mut self, addr: net::SocketAddr) -> bool {
+        if self.config.persistent.contains(&addr) {
+            return false;
+        }
+        self.config.persistent.push(addr);
+        self.connect(&addr);
+
+        true
+    }
+
+    /// Remove a persistent peer, so that it isn't reconnected to anymore. Doesn't
+    /// disconnect the peer. Returns `false` if the peer wasn't persistent.
+    pub fn remove_persistent(&mut self, addr: &net::SocketAddr) -> bool {
+        let len = self.config.persistent.len();
+
+        self.config.persistent.retain(|p| p != addr);
+        self.retry_at.remove(addr);
+        self.retry_attempts.remove(addr);
+
+        self.config.persistent.len() != len
     }
 
     /// Whitelist a peer.
@@ -792,6 +1263,32 @@ impl<U: Connect + Wakeup + Disconnect + Events, C: Clock> PeerManager<U, C> {
             .map(|(addr, _)| addr)
     }
 
+    /// Number of connecting and connected peers of the given connection class.
+    pub fn count(&self, class: ConnectionType) -> usize {
+        self.peers
+            .values()
+            .filter(|p| match p {
+                Peer::Connecting { kind, .. } => *kind == class,
+                Peer::Connected { conn, .. } => conn.link.is_outbound() && conn.kind == class,
+                Peer::Disconnecting => false,
+            })
+            .count()
+    }
+
+    /// Number of outbound connections, established or being established, in the given
+    /// domain.
+    pub fn count_in(&self, domain: Domain) -> usize {
+        self.peers
+            .iter()
+            .filter(|(addr, _)| Domain::for_address(addr) == domain)
+            .filter(|(_, p)| match p {
+                Peer::Connecting { .. } => true,
+                Peer::Connected { conn, .. } => conn.link.is_outbound(),
+                Peer::Disconnecting => false,
+            })
+            .count()
+    }
+
     /// Iterator over peers in a *connected* state..
     pub fn connected(&self) -> impl Iterator<Item = &Connection> + Clone {
         self.peers.values().filter_map(|c| match c {
@@ -808,8 +1305,53 @@ impl<U: Connect + Wakeup + Disconnect + Events, C: Clock> PeerManager<U, C> {
 
     /// Connect to a peer.
     pub fn connect(&mut self, addr: &PeerId) -> bool {
+        self.connect_as(addr, ConnectionType::FullRelay)
+    }
+
+    /// Connect to a peer reachable at any of the given addresses. Addresses are dialed
+    /// one after the other, [`DIAL_STAGGER`] apart, alternating between domains, without
+    /// waiting for earlier attempts to fail. The first connection established is kept, and
+    /// the other attempts are cancelled. Returns the addresses that will be dialed.
+    ///
+    /// Like peers connected to on request, the addresses are whitelisted, but only the
+    /// address that wins the race stays whitelisted.
+    pub fn connect_any(&mut self, addrs: &[PeerId]) -> Vec<PeerId> {
+        let mut pending = self::interleave(
+            addrs
+                .iter()
+                .filter(|a| self.is_disconnected(a))
+                .filter(|a| self.config.domains.contains(&Domain::for_address(a)))
+                .filter(|a| !self.is_banned(&a.ip())),
+        );
+        let candidates = pending.iter().copied().collect::<Vec<_>>();
+
+        if let Some(first) = pending.pop_front() {
+            let next = self.clock.local_time() + DIAL_STAGGER;
+            let whitelisted = candidates
+                .iter()
+                .filter(|a| self.config.whitelist.addr.insert(a.ip()))
+                .map(|a| a.ip())
+                .collect();
+
+            self.connect(&first);
+            self.races.push(Race {
+                pending,
+                dialing: vec![first],
+                next,
+                whitelisted,
+            });
+            self.upstream.wakeup(DIAL_STAGGER);
+        }
+        candidates
+    }
+
+    /// Connect to a peer, with the given connection class.
+    fn connect_as(&mut self, addr: &PeerId, kind: ConnectionType) -> bool {
         let time = self.clock.local_time();
 
+        if !self.active {
+            return false;
+        }
         if !self.is_disconnected(addr) && !self.is_disconnecting(addr) {
             return false;
         }
@@ -817,12 +1359,96 @@ impl<U: Connect + Wakeup + Disconnect + Events, C: Clock> PeerManager<U, C> {
         if !self.config.domains.contains(&Domain::for_address(addr)) {
             return false;
         }
-        self.peers.insert(*addr, Peer::Connecting { time });
+        if self.is_banned(&addr.ip()) {
+            return false;
+        }
+        self.peers.insert(*addr, Peer::Connecting { time, kind });
         self.upstream.connect(*addr, CONNECTION_TIMEOUT);
 
         true
     }
 
+    /// Check whether an address is banned.
+    pub fn is_banned(&self, addr: &net::IpAddr) -> bool {
+        let now = self.clock.local_time();
+
+        self.bans
+            .get(addr)
+            .is_some_and(|until| until.is_none_or(|t| now < t))
+    }
+
+    /// Iterator over banned addresses, including expired bans that weren't lifted yet.
+    pub fn bans(&self) -> impl Iterator<Item = Ban> + '_ {
+        self.bans.iter().map(|(addr, until)| Ban {
+            addr: *addr,
+            until: *until,
+        })
+    }
+
+    /// Dump the peer manager state, for debugging. Banned addresses are only counted.
+    pub fn dump(&self, redact: &mut dump::Redact) -> Value {
+        let mut peers = self.peers.iter().collect::<Vec<_>>();
+        peers.sort_by_key(|(addr, _)| **addr);
+
+        let peers = peers
+            .into_iter()
+            .map(|(addr, peer)| {
+                let mut obj = dump::object([("addr", redact.peer(addr))]);
+
+                match peer {
+                    Peer::Connecting { time, kind } => {
+                        obj.insert("state".to_owned(), dump::string("connecting"));
+                        obj.insert("kind".to_owned(), dump::string(kind));
+                        obj.insert("since".to_owned(), dump::time(*time));
+                    }
+                    Peer::Connected { conn, peer } => {
+                        let state = match peer {
+                            Some(p) if p.is_negotiated() => "negotiated",
+                            Some(_) => "handshake",
+                            None => "connected",
+                        };
+                        obj.insert("state".to_owned(), dump::string(state));
+                        obj.insert("kind".to_owned(), dump::string(conn.kind));
+                        obj.insert("link".to_owned(), dump::link(conn.link));
+                        obj.insert("since".to_owned(), dump::time(conn.since));
+
+                        if let Some(peer) = peer {
+                            obj.extend(dump::object([
+                                ("height", dump::number(peer.height)),
+                                ("services", dump::string(peer.services)),
+                                ("user_agent", dump::string(&peer.user_agent)),
+                                ("version", dump::number(peer.version)),
+                                ("relay", Value::Bool(peer.relay)),
+                                ("features", dump::string(peer.negotiated())),
+                                ("time_offset", Value::Number(Number::I64(peer.time_offset))),
+                            ]));
+                        }
+                    }
+                    Peer::Disconnecting => {
+                        obj.insert("state".to_owned(), dump::string("disconnecting"));
+                    }
+                }
+                Value::Object(obj)
+            })
+            .collect();
+
+        Value::Object(dump::object([
+            ("peers", Value::Array(peers)),
+            (
+                "outbound",
+                dump::number(self.negotiated(Link::Outbound).count()),
+            ),
+            (
+                "inbound",
+                dump::number(self.negotiated(Link::Inbound).count()),
+            ),
+            ("races", dump::number(self.races.len())),
+            ("retries", dump::number(self.retry_at.len())),
+            ("bans", dump::number(self.bans.len())),
+            ("schedule", self.schedule.dump()),
+        ]))
+    }
+
     /// Disconnect from a peer.
     pub fn disconnect(&mut self, addr: PeerId, reason: DisconnectReason) {
         if self.is_connected(&addr) {
@@ -842,15 +1468,39 @@ impl<U: Connect + Wakeup + Disconnect + Events, C: Clock> PeerManager<U, C> {
         // Peers with our preferred services.
         let primary = self
             .negotiated(Link::Outbound)
-            .filter(|(p, _)| p.services.has(self.config.preferred_services))
+            .filter(|(p, c)| {
+                c.kind == ConnectionType::FullRelay
+                    && p.services.has(self.config.preferred_services)
+            })
             .count();
         // Peers only with required services, which we'd eventually want to drop in favor of peers
         // that have all services.
-        let secondary = self.negotiated(Link::Outbound).count() - primary;
+        let secondary = self
+            .negotiated(Link::Outbound)
+            .filter(|(_, c)| c.kind == ConnectionType::FullRelay)
+            .count()
+            - primary;
         // Connected peers that have not yet completed handshake.
-        let connected = self.connected().count() - primary - secondary;
+        let connected = self
+            .connected()
+            .filter(|c| c.kind == ConnectionType::FullRelay)
+            .count()
+            - primary
+            - secondary;
         // Connecting peers.
-        let connecting = self.connecting().count();
+        let connecting = self
+            .peers
+            .values()
+            .filter(|p| {
+                matches!(
+                    p,
+                    Peer::Connecting {
+                        kind: ConnectionType::FullRelay,
+                        ..
+                    }
+                )
+            })
+            .count();
 
         // We connect up to the target number of peers plus an extra margin equal to the number of
         // target divided by two. This ensures we have *some* connections to
@@ -878,6 +1528,10 @@ impl<U: Connect + Wakeup + Disconnect + Events, C: Clock> PeerManager<U, C> {
 
     /// Attempt to maintain a certain number of outbound peers.
     fn maintain_connections<A: AddressSource>(&mut self, addrs: &mut A) {
+        // In connect-only mode, we never connect to peers from the address book.
+        if self.config.connect_only || !self.active {
+            return;
+        }
         let delta = self.delta();
         let negotiated = self.negotiated(Link::Outbound).count();
         let target = self.config.target_outbound_peers;
@@ -887,18 +1541,18 @@ impl<U: Connect + Wakeup + Disconnect + Events, C: Clock> PeerManager<U, C> {
         let mut connecting = HashSet::with_hasher(self.rng.clone().into());
 
         while connecting.len() < delta {
-            if let Some((addr, source)) =
-                addrs.sample(self.config.preferred_services).or_else(|| {
+            if let Some((addr, source)) = self
+                .sample(addrs, self.config.preferred_services)
+                .or_else(|| {
                     // Only try to connect to non-preferred peers if we are below our target.
                     if negotiated < target {
-                        addrs
-                            .sample(self.config.required_services)
+                        self.sample(addrs, self.config.required_services)
                             // If we can't find peers with any kind of useful services, then
                             // perhaps we should connect to peers that may know of such peers. This
                             // is especially important when doing an initial DNS sync, since DNS
                             // addresses don't come with service information. This will draw from
                             // that pool.
-                            .or_else(|| addrs.sample(ServiceFlags::NONE))
+                            .or_else(|| self.sample(addrs, ServiceFlags::NONE))
                     } else {
                         None
                     }
@@ -923,12 +1577,160 @@ impl<U: Connect + Wakeup + Disconnect + Events, C: Clock> PeerManager<U, C> {
                 break;
             }
         }
+        self.maintain_block_relay_connections(addrs);
+    }
+
+    /// Attempt to maintain the target number of block-relay-only peers.
+    fn maintain_block_relay_connections<A: AddressSource>(&mut self, addrs: &mut A) {
+        let target = self.config.target_block_relay_peers;
+
+        while self.count(ConnectionType::BlockRelay) < target {
+            let Some((addr, source)) = self
+                .sample(addrs, self.config.preferred_services)
+                .or_else(|| self.sample(addrs, self.config.required_services))
+            else {
+                break;
+            };
+            if let Ok(sockaddr) = addr.socket_addr() {
+                if self.connect_as(&sockaddr, ConnectionType::BlockRelay) {
+                    self.upstream
+                        .event(Event::Connecting(sockaddr, source, addr.services));
+                }
+            }
+        }
+    }
+
+    /// Make a feeler connection to a random address, if it's time to do so. Feelers
+    /// are used to check that addresses in our address book are reachable.
+    fn maintain_feeler_connection<A: AddressSource>(&mut self, addrs: &mut A) {
+        // In connect-only mode, we never connect to peers from the address book.
+        if self.config.connect_only || !self.active {
+            return;
+        }
+        let Some(cadence) = self.config.feeler else {
+            self.schedule.unschedule(&"feeler");
+            return;
+        };
+        // Only one feeler connection at a time.
+        if self.count(ConnectionType::Feeler) > 0 {
+            return;
+        }
+        self.schedule.schedule("feeler", cadence);
+
+        let Some(next) = self.schedule.due(&"feeler", self.clock.local_time()) else {
+            return;
+        };
+        self.upstream.wakeup(next);
+
+        if let Some((addr, source)) = self.sample(addrs, ServiceFlags::NONE) {
+            if let Ok(sockaddr) = addr.socket_addr() {
+                if self.connect_as(&sockaddr, ConnectionType::Feeler) {
+                    self.upstream
+                        .event(Event::Connecting(sockaddr, source, addr.services));
+                }
+            }
+        }
+    }
+
+    /// Sample an address to connect to, following the configured domain policy.
+    fn sample<A: AddressSource>(
+        &self,
+        addrs: &mut A,
+        services: ServiceFlags,
+    ) -> Option<(Address, Source)> {
+        match self.config.domain_policy {
+            DomainPolicy::Any => addrs.sample(services),
+            DomainPolicy::Prefer(domain) => addrs
+                .sample_in(services, domain)
+                .or_else(|| addrs.sample(services)),
+            DomainPolicy::Diverse => {
+                let mut domains = self.config.domains.clone();
+                domains.sort_by_key(|d| self.count_in(*d));
+                domains
+                    .into_iter()
+                    .find_map(|d| addrs.sample_in(services, d))
+            }
+        }
+    }
+
+    /// Dial the next address of connection races that are due.
+    fn maintain_races(&mut self) {
+        let local_time = self.clock.local_time();
+
+        for i in 0..self.races.len() {
+            if self.races[i].next <= local_time {
+                self.race_next(i);
+            }
+        }
+        let (done, races) = self
+            .races
+            .drain(..)
+            .partition::<Vec<_>, _>(|r| r.pending.is_empty() && r.dialing.is_empty());
+
+        self.races = races;
+        for race in done {
+            self.unwhitelist(&race.whitelisted);
+        }
+    }
+
+    /// Dial the next pending address of a connection race.
+    fn race_next(&mut self, ix: usize) {
+        while let Some(addr) = self.races[ix].pending.pop_front() {
+            if self.connect(&addr) {
+                let race = &mut self.races[ix];
+
+                race.dialing.push(addr);
+                race.next = self.clock.local_time() + DIAL_STAGGER;
+                self.upstream.wakeup(DIAL_STAGGER);
+
+                break;
+            }
+        }
+    }
+
+    /// Called when an outbound connection was established. Cancels the other attempts
+    /// of the connection race the address is part of, if any.
+    fn race_won(&mut self, addr: &PeerId) {
+        let Some(ix) = self.races.iter().position(|r| r.dialing.contains(addr)) else {
+            return;
+        };
+        let race = self.races.swap_remove(ix);
+
+        for other in race.dialing.iter().filter(|a| *a != addr) {
+            if self.is_connecting(other) {
+                self._disconnect(*other, DisconnectReason::DialCancelled);
+            }
+        }
+        self.unwhitelist(race.whitelisted.iter().filter(|ip| **ip != addr.ip()));
+    }
+
+    /// Remove IPs whitelisted for a connection race from the whitelist.
+    fn unwhitelist<'a>(&mut self, ips: impl IntoIterator<Item = &'a net::IpAddr>) {
+        for ip in ips {
+            self.config.whitelist.addr.remove(ip);
+        }
+    }
+
+    /// Called when a peer disconnected. If the address was being dialed as part of a
+    /// connection race, the next address is dialed right away.
+    fn race_lost(&mut self, addr: &PeerId) {
+        let Some(ix) = self.races.iter().position(|r| r.dialing.contains(addr)) else {
+            return;
+        };
+        self.races[ix].dialing.retain(|a| a != addr);
+        self.race_next(ix);
+
+        let race = &self.races[ix];
+        if race.pending.is_empty() && race.dialing.is_empty() {
+            let race = self.races.swap_remove(ix);
+            self.unwhitelist(&race.whitelisted);
+        }
     }
 
     /// Peers that have been idle longer than [`CONNECTION_TIMEOUT`].
     fn idle_peers(&self, now: LocalTime) -> impl Iterator<Item = PeerId> + '_ {
         self.peers.iter().filter_map(move |(addr, c)| {
-            if let Peer::Connecting { time } = c {
+            if let Peer::Connecting { time, .. } = c {
                 if now - *time >= CONNECTION_TIMEOUT {
                     return Some(*addr);
                 }
@@ -938,10 +1740,36 @@ impl<U: Connect + Wakeup + Disconnect + Events, C: Clock> PeerManager<U, C> {
     }
 }
 
+/// Order addresses so that domains alternate, starting with the domain of the first
+/// address, as recommended by RFC 8305. The relative order of addresses within a domain
+/// is preserved.
+fn interleave<'a>(addrs: impl IntoIterator<Item = &'a PeerId>) -> VecDeque<PeerId> {
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) = (VecDeque::new(), VecDeque::new());
+    let mut domain = None;
+
+    for addr in addrs {
+        let d = Domain::for_address(addr);
+
+        if *domain.get_or_insert(d) == d {
+            first.push_back(*addr);
+        } else {
+            second.push_back(*addr);
+        }
+    }
+    let mut interleaved = VecDeque::with_capacity(first.len() + second.len());
+
+    loop {
+        match (first.pop_front(), second.pop_front()) {
+            (None, None) => break,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+    interleaved
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::VecDeque;
 
     use nakamoto_common::bitcoin::network::address::Address;
     use nakamoto_common::block::time::RefClock;
@@ -955,15 +1783,22 @@ mod tests {
                 protocol_version: crate::protocol::PROTOCOL_VERSION,
                 target_outbound_peers: TARGET_OUTBOUND_PEERS,
                 max_inbound_peers: MAX_INBOUND_PEERS,
+                target_block_relay_peers: 0,
+                feeler: None,
                 domains: Domain::all(),
+                domain_policy: DomainPolicy::default(),
                 user_agent: crate::protocol::USER_AGENT,
                 persistent: vec![],
+                connect_only: false,
+                handshake_timeout: HANDSHAKE_TIMEOUT,
                 retry_max_wait: LocalDuration::from_mins(60),
                 retry_min_wait: LocalDuration::from_secs(1),
                 services: ServiceFlags::NONE,
                 preferred_services: ServiceFlags::COMPACT_FILTERS | ServiceFlags::NETWORK,
                 required_services: ServiceFlags::NETWORK,
                 whitelist: Whitelist::default(),
+                bans: vec![],
+                features: Feature::WtxidRelay.into(),
             }
         }
     }
@@ -1016,6 +1851,78 @@ mod tests {
         assert_eq!(peermgr.connecting().next(), Some(&remote));
     }
 
+    #[test]
+    fn test_add_remove_persistent() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(LocalTime::now());
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(util::config(), rng, Hooks::default(), (), time.clone());
+
+        peermgr.initialize(&mut addrs);
+        assert!(peermgr.add_persistent(remote));
+        assert!(!peermgr.add_persistent(remote));
+        assert_eq!(peermgr.connecting().next(), Some(&remote));
+        assert_eq!(peermgr.persistent().collect::<Vec<_>>(), vec![&remote]);
+
+        // Persistent peers are reconnected to.
+        peermgr.peer_connected(remote, local, Link::Outbound, 144);
+        peermgr.peer_disconnected(&remote, &mut addrs, DisconnectReason::PeerTimeout(""));
+
+        time.elapse(LocalDuration::from_secs(1));
+        peermgr.received_wake(&mut addrs);
+        assert_eq!(peermgr.connecting().next(), Some(&remote));
+
+        // Once removed, they aren't.
+        peermgr.peer_disconnected(&remote, &mut addrs, DisconnectReason::PeerTimeout(""));
+        assert!(peermgr.remove_persistent(&remote));
+        assert!(!peermgr.remove_persistent(&remote));
+        assert_eq!(peermgr.persistent().next(), None);
+
+        time.elapse(LocalDuration::from_mins(1));
+        peermgr.received_wake(&mut addrs);
+        assert_eq!(peermgr.connecting().next(), None);
+    }
+
+    #[test]
+    fn test_set_active() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(LocalTime::now());
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+        let inbound = ([124, 43, 110, 2], 39001).into();
+
+        let mut addrs = VecDeque::new();
+        let cfg = Config {
+            persistent: vec![remote],
+            ..util::config()
+        };
+        let mut peermgr = PeerManager::new(cfg, rng, Hooks::default(), (), time.clone());
+
+        peermgr.initialize(&mut addrs);
+        peermgr.peer_connected(remote, local, Link::Outbound, 144);
+
+        assert!(peermgr.set_active(false, &mut addrs));
+        assert!(!peermgr.set_active(false, &mut addrs));
+        assert!(peermgr.is_disconnecting(&remote));
+
+        // Persistent peers aren't reconnected to, and inbound peers are rejected.
+        peermgr.peer_disconnected(&remote, &mut addrs, DisconnectReason::NetworkInactive);
+        peermgr.peer_connected(inbound, local, Link::Inbound, 144);
+        assert!(peermgr.is_disconnecting(&inbound));
+        assert!(!peermgr.connect(&remote));
+
+        time.elapse(LocalDuration::from_mins(1));
+        peermgr.received_wake(&mut addrs);
+        assert_eq!(peermgr.connecting().next(), None);
+
+        // Once re-enabled, we reconnect to our persistent peers.
+        assert!(peermgr.set_active(true, &mut addrs));
+        assert_eq!(peermgr.connecting().next(), Some(&remote));
+    }
+
     #[test]
     fn test_wtxidrelay_outbound() {
         let rng = fastrand::Rng::with_seed(1);
@@ -1039,15 +1946,15 @@ mod tests {
 
         assert_matches!(
             peermgr.peers.get(&remote),
-            Some(Peer::Connected{peer: Some(p), ..}) if !p.wtxidrelay
+            Some(Peer::Connected{peer: Some(p), ..}) if !p.negotiated().has(Feature::WtxidRelay)
         );
 
-        peermgr.received_wtxidrelay(&remote);
+        peermgr.received_feature(&remote, Feature::WtxidRelay);
         peermgr.received_verack(&remote, time);
 
         assert_matches!(
             peermgr.peers.get(&remote),
-            Some(Peer::Connected{peer: Some(p), ..}) if p.wtxidrelay
+            Some(Peer::Connected{peer: Some(p), ..}) if p.negotiated().has(Feature::WtxidRelay)
         );
     }
 
@@ -1072,7 +1979,7 @@ mod tests {
         peermgr.peer_connected(remote, local, Link::Outbound, height);
         peermgr.received_version(&remote, version, height, &mut addrs);
         peermgr.received_verack(&remote, time);
-        peermgr.received_wtxidrelay(&remote);
+        peermgr.received_feature(&remote, Feature::WtxidRelay);
 
         assert_matches!(peermgr.peers.get(&remote), Some(Peer::Disconnecting));
     }
@@ -1329,4 +2236,429 @@ mod tests {
             assert_eq!(peermgr.delta(), delta, "{:?}", case);
         }
     }
+
+    #[test]
+    fn test_block_relay_connections() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = LocalTime::now();
+        let height = 144;
+        let local = ([99, 99, 99, 99], 9999).into();
+        let cfg = Config {
+            target_outbound_peers: 1,
+            target_block_relay_peers: 2,
+            ..util::config()
+        };
+        let mut addrs = (1..=8)
+            .map(|i| {
+                let addr = ([88, 88, 88, i], 8333).into();
+                (Address::new(&addr, cfg.required_services), Source::Dns)
+            })
+            .collect::<VecDeque<_>>();
+        let mut peermgr = PeerManager::new(cfg.clone(), rng.clone(), Hooks::default(), (), time);
+
+        peermgr.initialize(&mut addrs);
+
+        assert_eq!(peermgr.connecting().count(), 3);
+        assert_eq!(peermgr.count(ConnectionType::FullRelay), 1);
+        assert_eq!(peermgr.count(ConnectionType::BlockRelay), 2);
+
+        let remote = *peermgr
+            .peers
+            .iter()
+            .find(|(_, p)| {
+                matches!(
+                    p,
+                    Peer::Connecting {
+                        kind: ConnectionType::BlockRelay,
+                        ..
+                    }
+                )
+            })
+            .unwrap()
+            .0;
+        let version = VersionMessage {
+            services: cfg.required_services,
+            ..peermgr.version(local, remote, rng.u64(..), height, time)
+        };
+        peermgr.peer_connected(remote, local, Link::Outbound, height);
+        peermgr.received_version(&remote, version, height, &mut addrs);
+
+        let (_, conn) = peermgr.received_verack(&remote, time).unwrap();
+        assert_eq!(conn.kind, ConnectionType::BlockRelay);
+
+        // Block-relay peers don't count towards the full-relay budget.
+        assert_eq!(peermgr.delta(), 0);
+        assert_eq!(peermgr.count(ConnectionType::BlockRelay), 2);
+
+        // A disconnected block-relay peer is replaced by another one.
+        peermgr.disconnect(remote, DisconnectReason::Command);
+        peermgr.peer_disconnected(&remote, &mut addrs, DisconnectReason::Command);
+
+        assert_eq!(peermgr.count(ConnectionType::FullRelay), 1);
+        assert_eq!(peermgr.count(ConnectionType::BlockRelay), 2);
+    }
+
+    #[test]
+    fn test_domain_policy() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = LocalTime::now();
+        let services = ServiceFlags::NETWORK;
+        let addrs = (1..=4)
+            .map(|i| net::SocketAddr::from(([88, 88, 88, i], 8333)))
+            .chain(
+                (1..=4).map(|i| net::SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, i], 8333))),
+            )
+            .map(|addr| (Address::new(&addr, services), Source::Dns))
+            .collect::<VecDeque<_>>();
+
+        for (policy, ipv4, ipv6) in [
+            (DomainPolicy::Any, 4, 0),
+            (DomainPolicy::Prefer(Domain::IPV6), 0, 4),
+            (DomainPolicy::Diverse, 2, 2),
+        ] {
+            let cfg = Config {
+                target_outbound_peers: 4,
+                domain_policy: policy,
+                ..util::config()
+            };
+            let mut addrs = addrs.clone();
+            let mut peermgr = PeerManager::new(cfg, rng.clone(), Hooks::default(), (), time);
+
+            peermgr.initialize(&mut addrs);
+
+            assert_eq!(peermgr.count_in(Domain::IPV4), ipv4, "{:?}", policy);
+            assert_eq!(peermgr.count_in(Domain::IPV6), ipv6, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn test_connect_any() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(LocalTime::now());
+        let height = 144;
+        let local = ([99, 99, 99, 99], 9999).into();
+        let ipv6a = ([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 8333).into();
+        let ipv6b = ([0x2001, 0xdb8, 0, 0, 0, 0, 0, 2], 8333).into();
+        let ipv4 = ([88, 88, 88, 1], 8333).into();
+        let cfg = Config {
+            target_outbound_peers: 0,
+            ..util::config()
+        };
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(cfg, rng, Hooks::default(), (), time.clone());
+
+        peermgr.initialize(&mut addrs);
+
+        // Domains are alternated.
+        assert_eq!(
+            peermgr.connect_any(&[ipv6a, ipv6b, ipv4]),
+            vec![ipv6a, ipv4, ipv6b]
+        );
+        assert_eq!(peermgr.connecting().collect::<Vec<_>>(), vec![&ipv6a]);
+        assert_eq!(peermgr.config.whitelist.addr.len(), 3);
+
+        // The next address is dialed without waiting for the first attempt to fail.
+        time.elapse(DIAL_STAGGER);
+        peermgr.received_wake(&mut addrs);
+        assert!(peermgr.is_connecting(&ipv6a));
+        assert!(peermgr.is_connecting(&ipv4));
+
+        // A failed attempt causes the next address to be dialed right away.
+        let err = Arc::new(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+        peermgr.peer_disconnected(&ipv6a, &mut addrs, DisconnectReason::ConnectionError(err));
+        assert!(peermgr.is_connecting(&ipv6b));
+
+        // The first connection established wins, the other attempts are cancelled.
+        peermgr.peer_connected(ipv4, local, Link::Outbound, height);
+        assert!(peermgr.is_connected(&ipv4));
+        assert!(peermgr.is_disconnecting(&ipv6b));
+        assert!(peermgr.races.is_empty());
+
+        // Only the winner stays whitelisted.
+        assert_eq!(
+            peermgr.config.whitelist.addr.iter().collect::<Vec<_>>(),
+            vec![&ipv4.ip()]
+        );
+
+        // Addresses we're already connected to aren't dialed again.
+        assert_eq!(peermgr.connect_any(&[ipv4]), vec![]);
+
+        // If all attempts fail, none of the addresses stay whitelisted, but addresses that
+        // were already whitelisted are left alone.
+        peermgr.peer_disconnected(&ipv6b, &mut addrs, DisconnectReason::DialCancelled);
+        peermgr.whitelist(ipv6b);
+        assert_eq!(peermgr.connect_any(&[ipv6a, ipv6b]), vec![ipv6a, ipv6b]);
+
+        let err = Arc::new(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+        peermgr.peer_disconnected(&ipv6a, &mut addrs, DisconnectReason::ConnectionError(err));
+        peermgr.peer_disconnected(&ipv6b, &mut addrs, DisconnectReason::DialCancelled);
+        assert!(peermgr.races.is_empty());
+
+        let mut whitelisted = peermgr.config.whitelist.addr.iter().collect::<Vec<_>>();
+        whitelisted.sort();
+        assert_eq!(whitelisted, vec![&ipv4.ip(), &ipv6b.ip()]);
+    }
+
+    #[test]
+    fn test_self_connection() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(LocalTime::now());
+        let height = 144;
+        let local: PeerId = ([99, 99, 99, 99], 8333).into();
+        let ephemeral: PeerId = ([99, 99, 99, 99], 50000).into();
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(util::config(), rng, Hooks::default(), (), time);
+
+        peermgr.initialize(&mut addrs);
+
+        // We connect to our own address, and receive our own connection.
+        assert!(peermgr.connect(&local));
+        peermgr.peer_connected(local, ephemeral, Link::Outbound, height);
+        peermgr.peer_connected(ephemeral, local, Link::Inbound, height);
+
+        let outbound = peermgr
+            .connected()
+            .find(|c| c.link.is_outbound())
+            .unwrap()
+            .clone();
+        let version = peermgr.version(local, ephemeral, outbound.nonce, height, LocalTime::now());
+        peermgr.received_version(&ephemeral, version, height, &mut addrs);
+
+        assert!(peermgr.is_disconnecting(&ephemeral));
+        assert!(peermgr.is_disconnecting(&local));
+    }
+
+    #[test]
+    fn test_duplicate_connection() {
+        let height = 144;
+        let time = LocalTime::now();
+        let (a, b): (PeerId, PeerId) = (
+            ([88, 88, 88, 1], 8333).into(),
+            ([88, 88, 88, 2], 8333).into(),
+        );
+        let (a_ephemeral, b_ephemeral): (PeerId, PeerId) = (
+            ([88, 88, 88, 1], 50000).into(),
+            ([88, 88, 88, 2], 50000).into(),
+        );
+
+        for seed in 0..8 {
+            let rng = fastrand::Rng::with_seed(seed);
+            let mut addrs = VecDeque::new();
+            let [mut alice, mut bob] = [(), ()].map(|_| {
+                let mut peermgr = PeerManager::new(
+                    util::config(),
+                    fastrand::Rng::with_seed(rng.u64(..)),
+                    Hooks::default(),
+                    (),
+                    RefClock::from(time),
+                );
+                peermgr.initialize(&mut addrs);
+                peermgr
+            });
+
+            // Alice and Bob connect to each other at the same time.
+            alice.connect(&b);
+            bob.connect(&a);
+            alice.peer_connected(b, a_ephemeral, Link::Outbound, height);
+            bob.peer_connected(a_ephemeral, b, Link::Inbound, height);
+            bob.peer_connected(a, b_ephemeral, Link::Outbound, height);
+            alice.peer_connected(b_ephemeral, a, Link::Inbound, height);
+
+            let nonce = |peermgr: &PeerManager<(), _>, addr: &PeerId| {
+                peermgr
+                    .connected()
+                    .find(|c| &c.socket.addr == addr)
+                    .unwrap()
+                    .nonce
+            };
+            let template = alice.version(a, b, 0, height, time);
+            let version = |nonce, sender: PeerId| VersionMessage {
+                services: ServiceFlags::NETWORK,
+                nonce,
+                sender: Address::new(&sender, ServiceFlags::NETWORK),
+                ..template.clone()
+            };
+            let (alice_out, alice_in) = (nonce(&alice, &b), nonce(&alice, &b_ephemeral));
+            let (bob_out, bob_in) = (nonce(&bob, &a), nonce(&bob, &a_ephemeral));
+
+            // Both advertise the address they listen on.
+            alice.received_version(&b, version(bob_in, b), height, &mut addrs);
+            alice.received_version(&b_ephemeral, version(bob_out, b), height, &mut addrs);
+            bob.received_version(&a, version(alice_in, a), height, &mut addrs);
+            bob.received_version(&a_ephemeral, version(alice_out, a), height, &mut addrs);
+
+            // Exactly one of the two connections is dropped, at both ends.
+            let alice_kept_outbound = !alice.is_disconnecting(&b);
+            assert_ne!(alice_kept_outbound, !alice.is_disconnecting(&b_ephemeral));
+            assert_eq!(alice_kept_outbound, !bob.is_disconnecting(&a_ephemeral));
+            assert_eq!(alice_kept_outbound, bob.is_disconnecting(&a));
+            assert_eq!(alice_kept_outbound, alice_out > bob_out);
+        }
+    }
+
+    #[test]
+    fn test_duplicate_connection_same_ip() {
+        let height = 144;
+        let time = LocalTime::now();
+        let remote: PeerId = ([88, 88, 88, 1], 8333).into();
+        let ephemeral: PeerId = ([88, 88, 88, 1], 50000).into();
+        let persistent: PeerId = ([88, 88, 88, 2], 8333).into();
+        let loopback: PeerId = ([127, 0, 0, 1], 18444).into();
+        let mut addrs = VecDeque::new();
+        let mut peermgr = PeerManager::new(
+            Config {
+                persistent: vec![persistent],
+                ..util::config()
+            },
+            fastrand::Rng::with_seed(1),
+            Hooks::default(),
+            (),
+            RefClock::from(time),
+        );
+        peermgr.initialize(&mut addrs);
+
+        let template = peermgr.version(remote, ephemeral, 0, height, time);
+        let version = |nonce, sender: Option<PeerId>| VersionMessage {
+            services: ServiceFlags::NETWORK,
+            nonce,
+            sender: Address::new(
+                &sender.unwrap_or(([0, 0, 0, 0], 0).into()),
+                ServiceFlags::NETWORK,
+            ),
+            ..template.clone()
+        };
+        let mut connect = |peermgr: &mut PeerManager<(), _>, addr: PeerId, sender, nonce| {
+            let inbound = PeerId::new(addr.ip(), addr.port() + 1000);
+
+            peermgr.connect(&addr);
+            peermgr.peer_connected(addr, ephemeral, Link::Outbound, height);
+            peermgr.peer_connected(inbound, addr, Link::Inbound, height);
+            peermgr.received_version(&addr, version(nonce, None), height, &mut addrs);
+            peermgr.received_version(&inbound, version(nonce + 1, sender), height, &mut addrs);
+
+            (addr, inbound)
+        };
+
+        // Inbound peers sharing the IP of an outbound peer, but which listen on another port
+        // or don't say where they listen, are different peers.
+        for (addr, sender) in [
+            (remote, Some(PeerId::new(remote.ip(), 8334))),
+            (([88, 88, 88, 3], 8333).into(), None),
+        ] {
+            let (outbound, inbound) = connect(&mut peermgr, addr, sender, 1);
+            assert!(!peermgr.is_disconnecting(&outbound));
+            assert!(!peermgr.is_disconnecting(&inbound));
+        }
+
+        // Duplicate connections to persistent or loopback peers are kept.
+        for addr in [persistent, loopback] {
+            let (outbound, inbound) = connect(&mut peermgr, addr, Some(addr), 1);
+            assert!(!peermgr.is_disconnecting(&outbound));
+            assert!(!peermgr.is_disconnecting(&inbound));
+        }
+
+        // An inbound peer advertising the address of an outbound peer is a duplicate.
+        let addr = ([88, 88, 88, 4], 8333).into();
+        let (outbound, inbound) = connect(&mut peermgr, addr, Some(addr), 1);
+        assert!(peermgr.is_disconnecting(&outbound) ^ peermgr.is_disconnecting(&inbound));
+    }
+
+    #[test]
+    fn test_required_services_by_role() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(LocalTime::now());
+        let height = 144;
+        let local = ([99, 99, 99, 99], 9999).into();
+        let cfg = Config {
+            required_services: ServiceFlags::NONE,
+            ..util::config()
+        };
+        let mut addrs = VecDeque::new();
+        let mut peermgr =
+            PeerManager::new(cfg.clone(), rng.clone(), Hooks::default(), (), time.clone());
+
+        peermgr.initialize(&mut addrs);
+
+        // Peers without any services, connected in different roles.
+        let roles = [
+            (Link::Inbound, ConnectionType::FullRelay, true),
+            (Link::Outbound, ConnectionType::FullRelay, true),
+            (Link::Outbound, ConnectionType::Feeler, true),
+            // Block-relay peers must at least serve blocks.
+            (Link::Outbound, ConnectionType::BlockRelay, false),
+        ];
+        for (i, (link, kind, accepted)) in roles.into_iter().enumerate() {
+            let remote = ([88, 88, 88, i as u8], 8333).into();
+            let version = VersionMessage {
+                services: ServiceFlags::NONE,
+                ..peermgr.version(local, remote, rng.u64(..), height, time.local_time())
+            };
+            if link.is_outbound() {
+                assert!(peermgr.connect_as(&remote, kind));
+            }
+            peermgr.peer_connected(remote, local, link, height);
+            peermgr.received_version(&remote, version, height, &mut addrs);
+
+            assert_eq!(
+                !peermgr.is_disconnecting(&remote),
+                accepted,
+                "{:?} {} peer",
+                link,
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn test_feeler_connection() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(LocalTime::now());
+        let height = 144;
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote1 = ([88, 88, 88, 1], 8333).into();
+        let remote2 = ([88, 88, 88, 2], 8333).into();
+        let cfg = Config {
+            target_outbound_peers: 0,
+            feeler: Some(Cadence::every(FEELER_INTERVAL)),
+            ..util::config()
+        };
+        let mut addrs = VecDeque::new();
+        let mut peermgr =
+            PeerManager::new(cfg.clone(), rng.clone(), Hooks::default(), (), time.clone());
+
+        peermgr.initialize(&mut addrs);
+        assert_eq!(peermgr.connecting().count(), 0);
+
+        addrs.push_back((Address::new(&remote1, ServiceFlags::NONE), Source::Dns));
+        addrs.push_back((Address::new(&remote2, ServiceFlags::NONE), Source::Dns));
+        peermgr.received_wake(&mut addrs);
+
+        assert_eq!(peermgr.connecting().collect::<Vec<_>>(), vec![&remote1]);
+        assert_eq!(peermgr.count(ConnectionType::Feeler), 1);
+
+        let version = VersionMessage {
+            services: cfg.required_services,
+            ..peermgr.version(local, remote1, rng.u64(..), height, time.local_time())
+        };
+        peermgr.peer_connected(remote1, local, Link::Outbound, height);
+        peermgr.received_version(&remote1, version, height, &mut addrs);
+
+        let (_, conn) = peermgr
+            .received_verack(&remote1, time.local_time())
+            .unwrap();
+        assert_eq!(conn.kind, ConnectionType::Feeler);
+        assert!(
+            peermgr.is_disconnecting(&remote1),
+            "Feelers are disconnected after the handshake"
+        );
+        peermgr.peer_disconnected(&remote1, &mut addrs, DisconnectReason::Feeler);
+
+        // No new feeler until the interval has elapsed.
+        peermgr.received_wake(&mut addrs);
+        assert_eq!(peermgr.connecting().count(), 0);
+
+        time.elapse(FEELER_INTERVAL);
+        peermgr.received_wake(&mut addrs);
+        assert_eq!(peermgr.connecting().collect::<Vec<_>>(), vec![&remote2]);
+    }
 }
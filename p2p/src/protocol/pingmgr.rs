@@ -14,6 +14,7 @@ use crate::protocol::PeerId;
 
 use super::{
     output::{Disconnect, Wakeup},
+    scheduler::{Cadence, Scheduler},
     DisconnectReason,
 };
 
@@ -36,7 +37,7 @@ pub trait Ping {
 #[derive(Debug)]
 enum State {
     AwaitingPong { nonce: u64, since: LocalTime },
-    Idle { since: LocalTime },
+    Idle,
 }
 
 #[derive(Debug)]
@@ -49,11 +50,13 @@ struct Peer {
 
 impl Peer {
     /// Calculate the average latency of this peer.
-    #[allow(dead_code)]
-    fn latency(&self) -> LocalDuration {
+    fn latency(&self) -> Option<LocalDuration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
         let sum: LocalDuration = self.latencies.iter().sum();
 
-        sum / self.latencies.len() as u32
+        Some(sum / self.latencies.len() as u32)
     }
 
     fn record_latency(&mut self, sample: LocalDuration) {
@@ -67,6 +70,10 @@ impl Peer {
 pub struct PingManager<U, C> {
     peers: HashMap<PeerId, Peer>,
     ping_timeout: LocalDuration,
+    /// How often peers are pinged.
+    ping_interval: Cadence,
+    /// Next ping of each peer.
+    schedule: Scheduler<PeerId>,
     /// Random number generator.
     rng: fastrand::Rng,
     upstream: U,
@@ -75,24 +82,46 @@ pub struct PingManager<U, C> {
 
 impl<U: Ping + Wakeup + Disconnect, C: Clock> PingManager<U, C> {
     /// Create a new ping manager.
-    pub fn new(ping_timeout: LocalDuration, rng: fastrand::Rng, upstream: U, clock: C) -> Self {
+    pub fn new(
+        ping_timeout: LocalDuration,
+        ping_interval: Cadence,
+        rng: fastrand::Rng,
+        upstream: U,
+        clock: C,
+    ) -> Self {
         let peers = HashMap::with_hasher(rng.clone().into());
+        let schedule = Scheduler::new(rng.clone());
 
         Self {
             peers,
             ping_timeout,
+            ping_interval,
+            schedule,
             rng,
             upstream,
             clock,
         }
     }
 
+    /// Set the ping timeout. Applies to pings already awaiting a reply.
+    pub fn set_ping_timeout(&mut self, timeout: LocalDuration) {
+        self.ping_timeout = timeout;
+    }
+
     /// Called when a peer is negotiated.
     pub fn peer_negotiated(&mut self, address: PeerId) {
         let nonce = self.rng.u64(..);
         let now = self.clock.local_time();
 
-        self.upstream.ping(address, nonce);
+        // The first ping is sent right away.
+        self.schedule.schedule(address, self.ping_interval);
+
+        if let Some(next) = self.schedule.due(&address, now) {
+            self.upstream
+                .ping(address, nonce)
+                .wakeup(self.ping_timeout)
+                .wakeup(next);
+        }
         self.peers.insert(
             address,
             Peer {
@@ -103,9 +132,15 @@ impl<U: Ping + Wakeup + Disconnect, C: Clock> PingManager<U, C> {
         );
     }
 
+    /// Get the average ping round-trip time of a peer, if any ping was answered.
+    pub fn latency(&self, addr: &PeerId) -> Option<LocalDuration> {
+        self.peers.get(addr).and_then(|p| p.latency())
+    }
+
     /// Called when a peer is disconnected.
     pub fn peer_disconnected(&mut self, addr: &PeerId) {
         self.peers.remove(addr);
+        self.schedule.unschedule(addr);
     }
 
     /// Called when a tick is received.
@@ -127,16 +162,16 @@ impl<U: Ping + Wakeup + Disconnect, C: Clock> PingManager<U, C> {
                             .disconnect(peer.address, DisconnectReason::PeerTimeout("ping"));
                     }
                 }
-                State::Idle { since } => {
-                    // We aren't waiting for any `pong`. Check whether enough time has passed since we
-                    // received the last `pong`, and if so, send a new `ping`.
-                    if now - since >= PING_INTERVAL {
+                State::Idle => {
+                    // We aren't waiting for any `pong`. Check whether it's time for the next
+                    // `ping`, and if so, send it.
+                    if let Some(next) = self.schedule.due(&peer.address, now) {
                         let nonce = self.rng.u64(..);
 
                         self.upstream
                             .ping(peer.address, nonce)
                             .wakeup(self.ping_timeout)
-                            .wakeup(PING_INTERVAL);
+                            .wakeup(next);
 
                         peer.state = State::AwaitingPong { nonce, since: now };
                     }
@@ -165,13 +200,13 @@ impl<U: Ping + Wakeup + Disconnect, C: Clock> PingManager<U, C> {
                 } => {
                     if nonce == last_nonce {
                         peer.record_latency(now - since);
-                        peer.state = State::Idle { since: now };
+                        peer.state = State::Idle;
 
                         return true;
                     }
                 }
                 // Unsolicited or redundant `pong`. Ignore.
-                State::Idle { .. } => {}
+                State::Idle => {}
             }
         }
         false
@@ -0,0 +1,290 @@
+//! Per-peer rate limiting of expensive messages.
+//!
+//! Every peer is given a token bucket for each class of message that is expensive to
+//! process. Responses to our own requests are not limited, since their rate is under our
+//! control. Other messages received while a bucket is empty are dropped. Peers that keep
+//! exceeding their limits accumulate a ban score, and are disconnected once it gets
+//! too high. This protects the single-threaded reactor from peers flooding us.
+use nakamoto_common::bitcoin::network::message::NetworkMessage;
+use nakamoto_common::block::time::LocalTime;
+use nakamoto_common::collections::HashMap;
+
+use super::PeerId;
+
+/// Number of dropped messages after which a peer's ban score starts increasing.
+pub const MAX_THROTTLED: u32 = 16;
+/// Ban score at which a peer is disconnected.
+pub const MAX_BANSCORE: u32 = 32;
+
+/// A message rate limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    /// Number of messages allowed per second, on average.
+    pub rate: f64,
+    /// Number of messages allowed in a burst.
+    pub burst: f64,
+}
+
+impl Limit {
+    /// Create a new rate limit.
+    pub const fn new(rate: f64, burst: f64) -> Self {
+        Self { rate, burst }
+    }
+}
+
+/// Rate limiter configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Limit on `headers` messages.
+    pub headers: Limit,
+    /// Limit on `inv` messages.
+    pub inv: Limit,
+    /// Limit on `addr` and `addrv2` messages.
+    pub addr: Limit,
+    /// Limit on `cfilter`, `cfheaders` and `cfcheckpt` messages.
+    pub cfilters: Limit,
+    /// Number of dropped messages after which a peer's ban score starts increasing.
+    pub max_throttled: u32,
+    /// Ban score at which a peer is disconnected.
+    pub max_banscore: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            headers: Limit::new(10., 500.),
+            inv: Limit::new(20., 500.),
+            addr: Limit::new(1., 20.),
+            // Filters are requested in large batches.
+            cfilters: Limit::new(200., 2000.),
+            max_throttled: MAX_THROTTLED,
+            max_banscore: MAX_BANSCORE,
+        }
+    }
+}
+
+/// Outcome of rate limiting a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// The message is allowed through.
+    Allow,
+    /// The message should be dropped.
+    Throttle,
+    /// The message should be dropped, and the peer's ban score was increased.
+    Misbehave,
+    /// The peer should be disconnected.
+    Disconnect,
+}
+
+/// Class of rate-limited message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Class {
+    Headers,
+    Inv,
+    Addr,
+    Filters,
+}
+
+impl Class {
+    fn of(msg: &NetworkMessage) -> Option<Self> {
+        match msg {
+            NetworkMessage::Headers(_) => Some(Self::Headers),
+            NetworkMessage::Inv(_) => Some(Self::Inv),
+            NetworkMessage::Addr(_) | NetworkMessage::AddrV2(_) => Some(Self::Addr),
+            NetworkMessage::CFilter(_)
+            | NetworkMessage::CFHeaders(_)
+            | NetworkMessage::CFCheckpt(_) => Some(Self::Filters),
+            _ => None,
+        }
+    }
+}
+
+/// A token bucket.
+#[derive(Debug)]
+struct Bucket {
+    /// Number of messages that can be received right now.
+    tokens: f64,
+    /// Last time tokens were added.
+    last_refill: LocalTime,
+}
+
+impl Bucket {
+    /// Refill the bucket according to the time elapsed, and try to take a token.
+    fn take(&mut self, limit: &Limit, now: LocalTime) -> bool {
+        let elapsed = now - self.last_refill;
+
+        self.tokens =
+            (self.tokens + elapsed.as_millis() as f64 / 1000. * limit.rate).min(limit.burst);
+        self.last_refill = now;
+
+        if self.tokens >= 1. {
+            self.tokens -= 1.;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Rate limiting state of a peer.
+#[derive(Debug, Default)]
+struct Peer {
+    buckets: HashMap<Class, Bucket>,
+    /// Number of dropped messages. Decreases as messages are allowed through.
+    throttled: u32,
+    /// Ban score. Never decreases.
+    banscore: u32,
+}
+
+/// Per-peer message rate limiter.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: Config,
+    peers: HashMap<PeerId, Peer>,
+}
+
+impl RateLimiter {
+    /// Create a new rate limiter.
+    pub fn new(config: Config, rng: fastrand::Rng) -> Self {
+        Self {
+            config,
+            peers: HashMap::with_hasher(rng.into()),
+        }
+    }
+
+    /// Update the limits. Takes effect from the next received message.
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    /// Called when a message is received from a peer. Returns what should be done
+    /// with the message. Messages that were `solicited` by us are always allowed.
+    pub fn received(
+        &mut self,
+        addr: &PeerId,
+        msg: &NetworkMessage,
+        solicited: bool,
+        now: LocalTime,
+    ) -> Verdict {
+        if solicited {
+            return Verdict::Allow;
+        }
+        let Some(class) = Class::of(msg) else {
+            return Verdict::Allow;
+        };
+        let limit = match class {
+            Class::Headers => &self.config.headers,
+            Class::Inv => &self.config.inv,
+            Class::Addr => &self.config.addr,
+            Class::Filters => &self.config.cfilters,
+        };
+        let peer = self.peers.entry(*addr).or_default();
+        let bucket = peer.buckets.entry(class).or_insert_with(|| Bucket {
+            tokens: limit.burst,
+            last_refill: now,
+        });
+
+        if bucket.take(limit, now) {
+            peer.throttled = peer.throttled.saturating_sub(1);
+
+            return Verdict::Allow;
+        }
+        peer.throttled += 1;
+
+        if peer.throttled <= self.config.max_throttled {
+            return Verdict::Throttle;
+        }
+        peer.banscore += 1;
+
+        if peer.banscore >= self.config.max_banscore {
+            Verdict::Disconnect
+        } else {
+            Verdict::Misbehave
+        }
+    }
+
+    /// Get a peer's ban score.
+    pub fn banscore(&self, addr: &PeerId) -> u32 {
+        self.peers.get(addr).map_or(0, |p| p.banscore)
+    }
+
+    /// Called when a peer disconnected.
+    pub fn peer_disconnected(&mut self, addr: &PeerId) {
+        self.peers.remove(addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nakamoto_common::block::time::LocalDuration;
+
+    #[test]
+    fn test_rate_limit_escalation() {
+        let config = Config {
+            headers: Limit::new(1., 4.),
+            max_throttled: 2,
+            max_banscore: 2,
+            ..Config::default()
+        };
+        let addr = ([88, 88, 88, 88], 8333).into();
+        let mut now = LocalTime::now();
+        let mut limiter = RateLimiter::new(config, fastrand::Rng::with_seed(1));
+        let msg = NetworkMessage::Headers(vec![]);
+
+        // Messages that are not rate-limited are always allowed.
+        for _ in 0..100 {
+            assert_eq!(
+                limiter.received(&addr, &NetworkMessage::Verack, false, now),
+                Verdict::Allow
+            );
+        }
+        // The burst is allowed through.
+        for _ in 0..4 {
+            assert_eq!(limiter.received(&addr, &msg, false, now), Verdict::Allow);
+        }
+        // Responses to our requests are never limited.
+        for _ in 0..100 {
+            assert_eq!(limiter.received(&addr, &msg, true, now), Verdict::Allow);
+        }
+        // Over the limit, messages are throttled.
+        assert_eq!(limiter.received(&addr, &msg, false, now), Verdict::Throttle);
+        assert_eq!(limiter.received(&addr, &msg, false, now), Verdict::Throttle);
+        assert_eq!(limiter.banscore(&addr), 0);
+
+        // Tokens are added back over time.
+        now.elapse(LocalDuration::from_secs(1));
+        assert_eq!(limiter.received(&addr, &msg, false, now), Verdict::Allow);
+
+        // Peers that keep exceeding their limit are penalized, then disconnected.
+        assert_eq!(limiter.received(&addr, &msg, false, now), Verdict::Throttle);
+        assert_eq!(
+            limiter.received(&addr, &msg, false, now),
+            Verdict::Misbehave
+        );
+        assert_eq!(limiter.banscore(&addr), 1);
+        assert_eq!(
+            limiter.received(&addr, &msg, false, now),
+            Verdict::Disconnect
+        );
+
+        // The state is reset on disconnect.
+        limiter.peer_disconnected(&addr);
+        assert_eq!(limiter.banscore(&addr), 0);
+        assert_eq!(limiter.received(&addr, &msg, false, now), Verdict::Allow);
+
+        // Updated limits apply to the following messages.
+        let other = ([99, 99, 99, 99], 8333).into();
+        limiter.set_config(Config {
+            headers: Limit::new(1., 1.),
+            max_throttled: 0,
+            max_banscore: 1,
+            ..Config::default()
+        });
+        assert_eq!(limiter.received(&other, &msg, false, now), Verdict::Allow);
+        assert_eq!(
+            limiter.received(&other, &msg, false, now),
+            Verdict::Disconnect
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Chain reindexing.
+//!
+//! Revalidates the active block header chain and the filter header chain from a given
+//! height, one batch of headers at a time, so that the protocol stays responsive while the
+//! stores are checked. Headers whose index or stored copy doesn't match the chain are
+//! repaired, and the chains are truncated below the first invalid header, to be synced
+//! again from peers.
+use std::fmt;
+
+use nakamoto_common::block::filter::Filters;
+use nakamoto_common::block::time::LocalDuration;
+use nakamoto_common::block::Height;
+
+/// Number of headers revalidated per batch.
+pub const BATCH_SIZE: Height = 2000;
+/// Time to wait between batches.
+pub const BATCH_INTERVAL: LocalDuration = LocalDuration::from_millis(10);
+
+/// An event emitted while reindexing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Reindexing started.
+    Started {
+        /// Height reindexing started from.
+        from: Height,
+        /// Block header height.
+        to: Height,
+    },
+    /// Headers were revalidated up to the given height.
+    Progress {
+        /// Height revalidated up to.
+        height: Height,
+        /// Block header height.
+        tip: Height,
+    },
+    /// The block header at the given height was repaired.
+    Repaired {
+        /// Block height.
+        height: Height,
+    },
+    /// The block header at the given height is invalid. The block header chain was
+    /// truncated below it.
+    InvalidHeader {
+        /// Block height.
+        height: Height,
+        /// Reason the header is invalid.
+        reason: String,
+    },
+    /// The filter header at the given height is invalid. The filter header chain was
+    /// truncated below it.
+    InvalidFilterHeader {
+        /// Block height.
+        height: Height,
+    },
+    /// Reindexing finished.
+    Finished {
+        /// Block header height.
+        height: Height,
+        /// Filter header height.
+        filter_height: Height,
+        /// Number of block headers repaired.
+        repaired: usize,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Started { from, to } => write!(fmt, "Reindexing chain from {} to {}", from, to),
+            Self::Progress { height, tip } => write!(fmt, "Reindexing chain {}/{}", height, tip),
+            Self::Repaired { height } => write!(fmt, "Repaired block header at height {}", height),
+            Self::InvalidHeader { height, reason } => write!(
+                fmt,
+                "Invalid block header at height {}: {} (chain truncated)",
+                height, reason
+            ),
+            Self::InvalidFilterHeader { height } => write!(
+                fmt,
+                "Invalid filter header at height {} (filter chain truncated)",
+                height
+            ),
+            Self::Finished {
+                height,
+                filter_height,
+                repaired,
+            } => write!(
+                fmt,
+                "Reindexed chain up to height = {}, filter height = {} ({} header(s) repaired)",
+                height, filter_height, repaired
+            ),
+        }
+    }
+}
+
+/// An ongoing reindex.
+#[derive(Debug)]
+pub struct Reindex {
+    /// Next height to revalidate.
+    pub height: Height,
+    /// Number of block headers repaired so far.
+    pub repaired: usize,
+}
+
+impl Reindex {
+    /// Start reindexing from the given height.
+    pub fn new(from: Height) -> Self {
+        Self {
+            height: from,
+            repaired: 0,
+        }
+    }
+}
+
+/// Check that the filter header at the given height commits to its filter and parent.
+/// The genesis filter header has no parent, and is always valid.
+pub fn verify_filter_header<F: Filters>(filters: &F, height: Height) -> bool {
+    if height == 0 {
+        return true;
+    }
+    match (filters.get_header(height), filters.get_prev_header(height)) {
+        (Some((hash, header)), Some(prev)) => hash.filter_header(&prev) == header,
+        _ => false,
+    }
+}
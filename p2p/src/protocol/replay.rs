@@ -0,0 +1,196 @@
+//! Replay of captured message traces.
+//!
+//! A [`Replay`] feeds the inbound messages of a trace recorded with
+//! [`capture`](super::capture) into a fresh protocol instance, driving the protocol's clock
+//! from the trace timestamps, and collects everything the protocol outputs in response.
+//! This turns captures attached to bug reports into regression tests.
+//!
+//! Connections are inferred from the trace: a peer whose first message is outbound is
+//! assumed to have been connected to by us, and one whose first message is inbound to
+//! have connected to us. Outbound messages of the trace are otherwise ignored, since the
+//! replayed protocol sends its own.
+use std::collections::{HashSet, VecDeque};
+use std::net;
+
+use nakamoto_common::bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use nakamoto_common::block::time::LocalTime;
+
+use crate::stream::Decoder;
+use crate::traits::Protocol;
+
+use super::capture::{Direction, Record};
+use super::{Command, DisconnectReason, Event, Io, Link, PeerId};
+
+/// Replays captured messages into a protocol.
+#[derive(Debug)]
+pub struct Replay<P> {
+    protocol: P,
+    /// Local address used for connections.
+    local_addr: net::SocketAddr,
+    /// Current time, if the protocol was initialized.
+    time: Option<LocalTime>,
+    /// Peers connected to the protocol.
+    connected: HashSet<PeerId>,
+    /// Peers disconnected by the protocol. Further messages from these are skipped.
+    disconnected: HashSet<PeerId>,
+    /// Pending wakeups.
+    wakeups: Vec<LocalTime>,
+    /// Messages sent by the protocol.
+    sent: Vec<(PeerId, NetworkMessage)>,
+    /// Events emitted by the protocol.
+    events: Vec<Event>,
+    /// Peers disconnected by the protocol, with the reason.
+    disconnects: Vec<(PeerId, DisconnectReason)>,
+}
+
+impl<P: Protocol> Replay<P> {
+    /// Create a new replay, for the given protocol instance. The protocol mustn't be
+    /// initialized yet.
+    pub fn new(protocol: P, local_addr: net::SocketAddr) -> Self {
+        Self {
+            protocol,
+            local_addr,
+            time: None,
+            connected: HashSet::new(),
+            disconnected: HashSet::new(),
+            wakeups: Vec::new(),
+            sent: Vec::new(),
+            events: Vec::new(),
+            disconnects: Vec::new(),
+        }
+    }
+
+    /// Replay all the given records.
+    pub fn run(&mut self, records: impl IntoIterator<Item = Record>) {
+        for record in records {
+            self.step(&record);
+        }
+    }
+
+    /// Replay a single record.
+    pub fn step(&mut self, record: &Record) {
+        let peer = record.peer;
+
+        self.elapse(record.time);
+
+        if self.disconnected.contains(&peer) {
+            return;
+        }
+        if self.connected.insert(peer) {
+            let link = match record.direction {
+                Direction::Outbound => Link::Outbound,
+                Direction::Inbound => Link::Inbound,
+            };
+            if link.is_outbound() {
+                self.protocol.command(Command::Connect(peer, false));
+                self.protocol.attempted(&peer);
+            }
+            self.protocol.connected(peer, &self.local_addr, link);
+            self.process();
+        }
+        if record.direction == Direction::Inbound {
+            self.protocol.received_bytes(&peer, &record.message);
+            self.process();
+        }
+    }
+
+    /// Advance the protocol clock to the given time, waking the protocol up for all the
+    /// wakeups that are due on the way.
+    pub fn elapse(&mut self, time: LocalTime) {
+        if self.time.is_none() {
+            self.time = Some(time);
+            self.protocol.initialize(time);
+            self.process();
+        }
+        while let Some((ix, wakeup)) = self
+            .wakeups
+            .iter()
+            .copied()
+            .enumerate()
+            .min_by_key(|(_, t)| *t)
+            .filter(|(_, t)| *t <= time)
+        {
+            self.wakeups.swap_remove(ix);
+            self.tick(wakeup);
+            self.protocol.wake();
+            self.process();
+        }
+        self.tick(time);
+    }
+
+    /// Messages sent by the protocol, in order.
+    pub fn sent(&self) -> impl Iterator<Item = &(PeerId, NetworkMessage)> {
+        self.sent.iter()
+    }
+
+    /// Events emitted by the protocol, in order.
+    pub fn events(&self) -> impl Iterator<Item = &Event> {
+        self.events.iter()
+    }
+
+    /// Peers disconnected by the protocol, in order.
+    pub fn disconnects(&self) -> impl Iterator<Item = &(PeerId, DisconnectReason)> {
+        self.disconnects.iter()
+    }
+
+    /// The replayed protocol.
+    pub fn protocol(&self) -> &P {
+        &self.protocol
+    }
+
+    /// Consume the replay, returning the protocol.
+    pub fn into_inner(self) -> P {
+        self.protocol
+    }
+
+    /// Set the protocol clock, without going back in time.
+    fn tick(&mut self, time: LocalTime) {
+        let time = self.time.map_or(time, |t| t.max(time));
+
+        self.time = Some(time);
+        self.protocol.tick(time);
+    }
+
+    /// Process protocol outputs, like a reactor would.
+    fn process(&mut self) {
+        let time = self.time.unwrap_or_default();
+        let mut outputs = self.protocol.drain().collect::<VecDeque<_>>();
+
+        while let Some(out) = outputs.pop_front() {
+            match out {
+                Io::Write(addr) => {
+                    let mut bytes = Vec::new();
+                    let mut decoder = Decoder::new(0);
+
+                    // Nb. writing to a vector cannot fail.
+                    self.protocol.write(&addr, &mut bytes).ok();
+                    decoder.input(&bytes);
+
+                    while let Ok(Some(msg)) = decoder.decode_next::<RawNetworkMessage>() {
+                        self.sent.push((addr, msg.payload));
+                    }
+                }
+                Io::Connect(_) => {
+                    // Connections are only established when a peer shows up in the trace.
+                }
+                Io::Disconnect(addr, reason) => {
+                    if self.connected.remove(&addr) {
+                        self.disconnected.insert(addr);
+                    }
+                    self.disconnects.push((addr, reason.clone()));
+                    self.protocol.disconnected(&addr, reason);
+                    outputs.extend(self.protocol.drain());
+                }
+                Io::Wakeup(timeout) => {
+                    self.wakeups.push(time + timeout);
+                }
+                Io::CancelWakeups => {
+                    self.wakeups.clear();
+                }
+                Io::Event(event) => {
+                    self.events.push(event);
+                }
+            }
+        }
+    }
+}
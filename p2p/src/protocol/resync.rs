@@ -0,0 +1,391 @@
+//! Automatic resync after network partitions.
+//!
+//! When no outbound peer has been connected for [`PARTITION_TIMEOUT`], we consider
+//! ourselves partitioned from the network. Peers are then reconnected to with exponential
+//! backoff, and the address book is re-seeded from DNS on every attempt, since the
+//! addresses we know may all have gone stale.
+//!
+//! Once an outbound peer is negotiated again, our tip is reconciled with the network:
+//! block headers are synced from a locator exchange with the peer, and filter headers are
+//! synced on top of our filter header chain, which checks that they still connect. A single
+//! [`Event::Resynced`] then summarizes what changed while we were partitioned.
+use std::fmt;
+
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+use nakamoto_common::block::{BlockHash, Height};
+
+use super::PeerId;
+
+/// Time without any outbound peer after which we consider ourselves partitioned.
+pub const PARTITION_TIMEOUT: LocalDuration = LocalDuration::from_mins(2);
+/// Time to wait before the second reconnection attempt.
+pub const MIN_BACKOFF: LocalDuration = LocalDuration::from_secs(5);
+/// Maximum time to wait between reconnection attempts.
+pub const MAX_BACKOFF: LocalDuration = LocalDuration::from_mins(30);
+/// Time after which reconciliation is considered done, even if headers are still syncing.
+pub const RECONCILE_TIMEOUT: LocalDuration = LocalDuration::from_mins(2);
+
+/// An event related to network partitions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// No outbound peer was connected for the given duration. Peers are reconnected to
+    /// with exponential backoff until the partition ends.
+    Partitioned {
+        /// Time since the last outbound peer disconnected.
+        offline: LocalDuration,
+    },
+    /// Attempting to reconnect to peers. The address book should be re-seeded from DNS.
+    Reconnecting {
+        /// Reconnection attempt, starting at one.
+        attempt: u32,
+        /// Time until the next attempt.
+        next: LocalDuration,
+    },
+    /// Reconnected after a partition, and reconciled our tip with the network.
+    Resynced {
+        /// Peer our tip was reconciled with.
+        peer: PeerId,
+        /// Time spent without any outbound peer.
+        offline: LocalDuration,
+        /// Number of reconnection attempts.
+        attempts: u32,
+        /// Block header height before the partition.
+        from: Height,
+        /// Block header height after reconciliation.
+        height: Height,
+        /// Block hash of our new tip.
+        tip: BlockHash,
+        /// Number of blocks removed from the active chain.
+        reverted: usize,
+        /// Number of blocks added to the active chain.
+        connected: usize,
+        /// Filter header height before the partition.
+        from_filter_height: Height,
+        /// Filter header height after reconciliation.
+        filter_height: Height,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Partitioned { offline } => write!(
+                fmt,
+                "No outbound peer for {}, reconnecting with backoff",
+                offline
+            ),
+            Self::Reconnecting { attempt, next } => write!(
+                fmt,
+                "Reconnecting to peers (attempt {}, next in {})",
+                attempt, next
+            ),
+            Self::Resynced {
+                peer,
+                offline,
+                attempts,
+                from,
+                height,
+                reverted,
+                connected,
+                filter_height,
+                ..
+            } => write!(
+                fmt,
+                "Resynced with {} after {} offline ({} attempt(s)): height {} -> {}, \
+                 {} block(s) connected, {} reverted, filter height = {}",
+                peer, offline, attempts, from, height, connected, reverted, filter_height
+            ),
+        }
+    }
+}
+
+/// Our chain tips, as they were when we lost our last outbound peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tip {
+    /// Block header height.
+    pub height: Height,
+    /// Filter header height.
+    pub filter_height: Height,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum State {
+    /// Connected to at least one outbound peer.
+    Online,
+    /// Not connected to any outbound peer since the given time.
+    Offline { since: LocalTime, tip: Tip },
+    /// Partitioned from the network, and reconnecting with backoff.
+    Partitioned {
+        since: LocalTime,
+        tip: Tip,
+        attempts: u32,
+        retry_at: LocalTime,
+    },
+    /// Reconnected after a partition, and reconciling our tip with the given peer.
+    Reconciling {
+        since: LocalTime,
+        tip: Tip,
+        attempts: u32,
+        peer: PeerId,
+        started: LocalTime,
+        reverted: usize,
+        connected: usize,
+    },
+}
+
+/// Detects network partitions, and tracks reconnection and reconciliation.
+#[derive(Debug)]
+pub struct Resync {
+    state: State,
+}
+
+impl Default for Resync {
+    fn default() -> Self {
+        Self {
+            state: State::Online,
+        }
+    }
+}
+
+impl Resync {
+    /// Forget about any partition, eg. when network activity is disabled on purpose.
+    pub fn reset(&mut self) {
+        self.state = State::Online;
+    }
+
+    /// Called when we're left without outbound peers.
+    pub fn disconnected(&mut self, tip: Tip, now: LocalTime) {
+        match &self.state {
+            State::Online => {
+                self.state = State::Offline { since: now, tip };
+            }
+            State::Reconciling {
+                since,
+                tip,
+                attempts,
+                ..
+            } => {
+                self.state = State::Partitioned {
+                    since: *since,
+                    tip: *tip,
+                    attempts: *attempts,
+                    retry_at: now + backoff(*attempts),
+                };
+            }
+            State::Offline { .. } | State::Partitioned { .. } => {}
+        }
+    }
+
+    /// Called when an outbound peer was negotiated. Returns `true` if our tip should be
+    /// reconciled with the peer.
+    pub fn negotiated(&mut self, peer: PeerId, now: LocalTime) -> bool {
+        match &self.state {
+            State::Offline { .. } => {
+                self.state = State::Online;
+            }
+            State::Partitioned {
+                since,
+                tip,
+                attempts,
+                ..
+            } => {
+                self.state = State::Reconciling {
+                    since: *since,
+                    tip: *tip,
+                    attempts: *attempts,
+                    peer,
+                    started: now,
+                    reverted: 0,
+                    connected: 0,
+                };
+                return true;
+            }
+            State::Online | State::Reconciling { .. } => {}
+        }
+        false
+    }
+
+    /// Called when our tip changed. Only recorded while reconciling.
+    pub fn tip_changed(&mut self, blocks_reverted: usize, blocks_connected: usize) {
+        if let State::Reconciling {
+            reverted,
+            connected,
+            ..
+        } = &mut self.state
+        {
+            *reverted += blocks_reverted;
+            *connected += blocks_connected;
+        }
+    }
+
+    /// Called periodically. Returns an event if the partition state changed, or if it's
+    /// time to attempt reconnecting.
+    pub fn wake(&mut self, now: LocalTime) -> Option<Event> {
+        match self.state {
+            State::Offline { since, tip } if now - since >= PARTITION_TIMEOUT => {
+                self.state = State::Partitioned {
+                    since,
+                    tip,
+                    attempts: 0,
+                    retry_at: now,
+                };
+                Some(Event::Partitioned {
+                    offline: now - since,
+                })
+            }
+            State::Partitioned {
+                ref mut attempts,
+                ref mut retry_at,
+                ..
+            } if now >= *retry_at => {
+                *attempts += 1;
+
+                let next = backoff(*attempts);
+                *retry_at = now + next;
+
+                Some(Event::Reconnecting {
+                    attempt: *attempts,
+                    next,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Time at which reconciliation times out, if we're reconciling.
+    pub fn reconcile_deadline(&self) -> Option<LocalTime> {
+        match self.state {
+            State::Reconciling { started, .. } => Some(started + RECONCILE_TIMEOUT),
+            _ => None,
+        }
+    }
+
+    /// Finish reconciling our tip, given our tip after reconciliation. Returns the event
+    /// summarizing the resync, if we were reconciling.
+    pub fn reconciled(
+        &mut self,
+        height: Height,
+        tip: BlockHash,
+        filter_height: Height,
+    ) -> Option<Event> {
+        let State::Reconciling {
+            since,
+            tip: previous,
+            attempts,
+            peer,
+            started,
+            reverted,
+            connected,
+        } = self.state
+        else {
+            return None;
+        };
+        self.state = State::Online;
+
+        Some(Event::Resynced {
+            peer,
+            offline: started - since,
+            attempts,
+            from: previous.height,
+            height,
+            tip,
+            reverted,
+            connected,
+            from_filter_height: previous.filter_height,
+            filter_height,
+        })
+    }
+}
+
+/// Time to wait after the given number of reconnection attempts, before the next one.
+fn backoff(attempts: u32) -> LocalDuration {
+    if attempts == 0 {
+        return LocalDuration::from_secs(0);
+    }
+    let factor = 1u128.checked_shl(attempts - 1).unwrap_or(u128::MAX);
+    let wait = MIN_BACKOFF.as_millis().saturating_mul(factor);
+
+    LocalDuration::from_millis(wait.min(MAX_BACKOFF.as_millis()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0), LocalDuration::from_secs(0));
+        assert_eq!(backoff(1), MIN_BACKOFF);
+        assert_eq!(backoff(2), MIN_BACKOFF * 2);
+        assert_eq!(backoff(3), MIN_BACKOFF * 4);
+        assert_eq!(backoff(64), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_partition() {
+        let peer = ([8, 8, 8, 8], 8333).into();
+        let tip = Tip {
+            height: 100,
+            filter_height: 99,
+        };
+        let mut time = LocalTime::from_secs(1_000_000);
+        let mut resync = Resync::default();
+
+        // A short disconnection isn't a partition.
+        resync.disconnected(tip, time);
+        assert_eq!(resync.wake(time), None);
+        assert!(!resync.negotiated(peer, time));
+
+        resync.disconnected(tip, time);
+        time = time + PARTITION_TIMEOUT;
+        assert_eq!(
+            resync.wake(time),
+            Some(Event::Partitioned {
+                offline: PARTITION_TIMEOUT
+            })
+        );
+
+        // Reconnection attempts back off exponentially.
+        assert_eq!(
+            resync.wake(time),
+            Some(Event::Reconnecting {
+                attempt: 1,
+                next: MIN_BACKOFF
+            })
+        );
+        assert_eq!(resync.wake(time), None);
+
+        time = time + MIN_BACKOFF;
+        assert_eq!(
+            resync.wake(time),
+            Some(Event::Reconnecting {
+                attempt: 2,
+                next: MIN_BACKOFF * 2
+            })
+        );
+
+        // Reconnecting starts reconciliation.
+        time = time + LocalDuration::from_secs(1);
+        assert!(resync.negotiated(peer, time));
+        assert_eq!(resync.reconcile_deadline(), Some(time + RECONCILE_TIMEOUT));
+
+        resync.tip_changed(1, 3);
+        assert_eq!(
+            resync.reconciled(102, BlockHash::default(), 102),
+            Some(Event::Resynced {
+                peer,
+                offline: PARTITION_TIMEOUT + MIN_BACKOFF + LocalDuration::from_secs(1),
+                attempts: 2,
+                from: 100,
+                height: 102,
+                tip: BlockHash::default(),
+                reverted: 1,
+                connected: 3,
+                from_filter_height: 99,
+                filter_height: 102,
+            })
+        );
+        assert_eq!(resync.reconciled(102, BlockHash::default(), 102), None);
+        assert_eq!(resync.reconcile_deadline(), None);
+    }
+}
@@ -0,0 +1,242 @@
+//! Scheduling of periodic protocol tasks.
+//!
+//! Sub-protocols run recurring tasks, eg. pinging peers or checking whether our tip is
+//! stale, from their wake-up handlers. Each sub-protocol keeps its tasks in a
+//! [`Scheduler`], which tracks when every task is next due, adds the configured jitter,
+//! and tells the sub-protocol when it should be woken up next.
+use std::hash::Hash;
+
+use microserde::json::Value;
+
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+use nakamoto_common::collections::HashMap;
+
+use super::{addrmgr, dump, invmgr, peermgr, pingmgr};
+
+/// How often a recurring task runs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Cadence {
+    /// Time between runs.
+    pub interval: LocalDuration,
+    /// Maximum random delay added to each interval, so that runs aren't predictable.
+    pub jitter: LocalDuration,
+}
+
+impl Cadence {
+    /// Run every given interval, without jitter.
+    pub const fn every(interval: LocalDuration) -> Self {
+        Self {
+            interval,
+            jitter: LocalDuration::from_millis(0),
+        }
+    }
+
+    /// Add up to the given random delay to each interval.
+    pub const fn with_jitter(self, jitter: LocalDuration) -> Self {
+        Self { jitter, ..self }
+    }
+}
+
+/// Cadences of the configurable protocol tasks. No jitter is added by default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    /// Pinging each peer.
+    pub ping: Cadence,
+    /// Asking peers for addresses, while the address book is exhausted.
+    pub addr_request: Cadence,
+    /// Connecting to a random known address, to check that it's reachable.
+    /// `None` disables feeler connections.
+    pub feeler: Option<Cadence>,
+    /// Re-announcing unconfirmed transactions and retrying block requests. Transactions
+    /// are still re-announced to a given peer at most once a minute.
+    pub rebroadcast: Cadence,
+    /// Checking whether our tip is stale.
+    pub stale_tip_check: Cadence,
+    /// Advertising our external address to peers, if we accept inbound connections.
+    pub advertise: Cadence,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            ping: Cadence::every(pingmgr::PING_INTERVAL),
+            addr_request: Cadence::every(addrmgr::REQUEST_TIMEOUT),
+            feeler: Some(Cadence::every(peermgr::FEELER_INTERVAL)),
+            rebroadcast: Cadence::every(invmgr::IDLE_TIMEOUT),
+            stale_tip_check: Cadence::every(LocalDuration::BLOCK_INTERVAL),
+            advertise: Cadence::every(addrmgr::ADVERTISE_INTERVAL),
+        }
+    }
+}
+
+/// A scheduled task.
+#[derive(Debug, Clone)]
+struct Task {
+    cadence: Cadence,
+    /// When the task is next due, or `None` if it never ran.
+    next: Option<LocalTime>,
+}
+
+/// Keeps track of recurring tasks, identified by keys, eg. task names.
+#[derive(Debug)]
+pub struct Scheduler<K = &'static str> {
+    tasks: HashMap<K, Task>,
+    rng: fastrand::Rng,
+}
+
+impl<K: Hash + Eq> Scheduler<K> {
+    /// Create a new, empty scheduler.
+    pub fn new(rng: fastrand::Rng) -> Self {
+        Self {
+            tasks: HashMap::with_hasher(rng.clone().into()),
+            rng,
+        }
+    }
+
+    /// Schedule a recurring task. New tasks are due immediately. If the task is already
+    /// scheduled, only its cadence is updated, starting from its next run.
+    pub fn schedule(&mut self, key: K, cadence: Cadence) {
+        self.tasks
+            .entry(key)
+            .and_modify(|t| t.cadence = cadence)
+            .or_insert(Task {
+                cadence,
+                next: None,
+            });
+    }
+
+    /// Make a task due immediately, eg. to run it ahead of its schedule.
+    pub fn reset(&mut self, key: &K) {
+        if let Some(task) = self.tasks.get_mut(key) {
+            task.next = None;
+        }
+    }
+
+    /// Remove a task.
+    pub fn unschedule(&mut self, key: &K) {
+        self.tasks.remove(key);
+    }
+
+    /// Check whether a task is due at the given time. If it is, its next run is scheduled,
+    /// and the time until then is returned: the caller is expected to run the task, and
+    /// to wake up again after the returned delay.
+    pub fn due(&mut self, key: &K, now: LocalTime) -> Option<LocalDuration> {
+        let task = self.tasks.get_mut(key)?;
+
+        if task.next.is_some_and(|next| now < next) {
+            return None;
+        }
+        let Cadence { interval, jitter } = task.cadence;
+        let jitter = if jitter.as_millis() > 0 {
+            LocalDuration::from_millis(self.rng.u128(..=jitter.as_millis()))
+        } else {
+            jitter
+        };
+        task.next = Some(now + interval + jitter);
+
+        Some(interval + jitter)
+    }
+
+    /// Time until the next task is due, if any task is scheduled.
+    pub fn next(&self, now: LocalTime) -> Option<LocalDuration> {
+        self.tasks
+            .values()
+            .map(|t| {
+                t.next
+                    .map_or(LocalDuration::from_millis(0), |next| next - now)
+            })
+            .min()
+    }
+}
+
+impl Scheduler<&'static str> {
+    /// Dump the schedule, for debugging.
+    pub fn dump(&self) -> Value {
+        let mut tasks = self.tasks.iter().collect::<Vec<_>>();
+        tasks.sort_by_key(|(name, _)| **name);
+
+        Value::Object(
+            tasks
+                .into_iter()
+                .map(|(name, task)| (name.to_string(), dump::optional(task.next, dump::time)))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scheduler() {
+        let mut scheduler = Scheduler::new(fastrand::Rng::with_seed(1));
+        let mut now = LocalTime::from_secs(1_600_000_000);
+        let minute = LocalDuration::from_mins(1);
+
+        assert_eq!(scheduler.due(&"ping", now), None);
+        assert_eq!(scheduler.next(now), None);
+
+        scheduler.schedule("ping", Cadence::every(minute));
+        scheduler.schedule("idle", Cadence::every(minute * 3));
+        assert_eq!(scheduler.next(now), Some(LocalDuration::from_millis(0)));
+
+        // New tasks are due immediately, and only once per interval.
+        assert_eq!(scheduler.due(&"ping", now), Some(minute));
+        assert_eq!(scheduler.due(&"ping", now), None);
+        assert_eq!(scheduler.due(&"idle", now), Some(minute * 3));
+        assert_eq!(scheduler.next(now), Some(minute));
+
+        now.elapse(LocalDuration::from_secs(59));
+        assert_eq!(scheduler.due(&"ping", now), None);
+        now.elapse(LocalDuration::from_secs(1));
+        assert_eq!(scheduler.due(&"ping", now), Some(minute));
+        assert_eq!(scheduler.next(now), Some(minute));
+
+        // Updating the cadence of a task doesn't change its next run.
+        scheduler.schedule("ping", Cadence::every(minute * 5));
+        assert_eq!(scheduler.next(now), Some(minute));
+        now.elapse(minute);
+        assert_eq!(scheduler.due(&"ping", now), Some(minute * 5));
+        assert_eq!(scheduler.next(now), Some(minute));
+        now.elapse(minute);
+        assert_eq!(scheduler.due(&"idle", now), Some(minute * 3));
+        assert_eq!(scheduler.next(now), Some(minute * 3));
+
+        // Reset tasks are due immediately.
+        scheduler.reset(&"idle");
+        assert_eq!(scheduler.next(now), Some(LocalDuration::from_millis(0)));
+        assert_eq!(scheduler.due(&"idle", now), Some(minute * 3));
+
+        scheduler.unschedule(&"idle");
+        assert_eq!(scheduler.next(now), Some(minute * 4));
+        scheduler.unschedule(&"ping");
+        assert_eq!(scheduler.next(now), None);
+    }
+
+    #[test]
+    fn test_jitter() {
+        let mut scheduler = Scheduler::new(fastrand::Rng::with_seed(1));
+        let mut now = LocalTime::from_secs(1_600_000_000);
+        let cadence =
+            Cadence::every(LocalDuration::from_mins(1)).with_jitter(LocalDuration::from_secs(30));
+        let mut delays = Vec::new();
+
+        scheduler.schedule("gossip", cadence);
+
+        for _ in 0..32 {
+            let delay = scheduler.due(&"gossip", now).unwrap();
+
+            assert_eq!(scheduler.next(now), Some(delay));
+            assert!(delay >= cadence.interval);
+            assert!(delay <= cadence.interval + cadence.jitter);
+
+            delays.push(delay);
+            now.elapse(delay);
+        }
+        delays.sort();
+        delays.dedup();
+
+        assert!(delays.len() > 1, "runs are spread out");
+    }
+}
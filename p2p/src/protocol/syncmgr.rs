@@ -1,6 +1,8 @@
 //!
 //! Manages header synchronization with peers.
 //!
+use microserde::json::Value;
+
 use nakamoto_common::bitcoin::consensus::params::Params;
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::message_blockdata::Inventory;
@@ -13,13 +15,17 @@ use nakamoto_common::collections::{AddressBook, HashMap};
 use nakamoto_common::nonempty::NonEmpty;
 
 use super::output::{Disconnect, Wakeup};
-use super::{DisconnectReason, Link, Locators, PeerId, Socket};
+use super::scheduler::{Cadence, Scheduler};
+use super::{dump, DisconnectReason, Link, Locators, PeerId, Socket};
 
 /// How long to wait for a request, eg. `getheaders` to be fulfilled.
 pub const REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
 /// How long before the tip of the chain is considered stale. This takes into account
 /// that the block timestamp may have been set sometime in the future.
 pub const TIP_STALE_DURATION: LocalDuration = LocalDuration::from_mins(60 * 2);
+/// Number of expected block intervals without a new block header, after which our tip
+/// is considered stale.
+pub const STALE_TIP_FACTOR: u32 = 3;
 /// Maximum number of headers sent in a `headers` message.
 pub const MAX_MESSAGE_HEADERS: usize = 2000;
 /// Maximum number of inventories sent in an `inv` message.
@@ -31,8 +37,16 @@ pub const REQUIRED_SERVICES: ServiceFlags = ServiceFlags::NETWORK;
 
 /// Maximum headers announced in a `headers` message, when unsolicited.
 const MAX_UNSOLICITED_HEADERS: usize = 24;
+/// Maximum headers we announce to a peer. If a peer is further behind, only our
+/// tip is announced, and the peer is expected to request the headers in between.
+const MAX_ANNOUNCED_HEADERS: usize = 8;
 /// How long to wait between checks for longer chains from peers.
 const PEER_SAMPLE_INTERVAL: LocalDuration = LocalDuration::from_mins(60);
+/// How long to hold on to headers received before their parent, before requesting the
+/// missing headers.
+pub const ORPHAN_TIMEOUT: LocalDuration = LocalDuration::from_secs(10);
+/// Maximum number of orphan header chains held at a time.
+const MAX_ORPHANS: usize = 32;
 
 /// The ability to get and send headers.
 pub trait SyncHeaders {
@@ -40,6 +54,8 @@ pub trait SyncHeaders {
     fn get_headers(&mut self, addr: PeerId, locators: Locators);
     /// Send headers to a peer.
     fn send_headers(&mut self, addr: PeerId, headers: Vec<BlockHeader>);
+    /// Send an inventory announcement to a peer.
+    fn send_inv(&mut self, addr: PeerId, inv: Vec<Inventory>);
     /// Send initial post-negotiation messages, eg. `sendheaders`.
     fn negotiate(&mut self, addr: PeerId);
     /// Emit a sync-related event.
@@ -66,10 +82,27 @@ struct Peer {
     link: Link,
     last_active: Option<LocalTime>,
     last_asked: Option<Locators>,
+    /// Time at which the peer was registered.
+    since: LocalTime,
+    /// Whether the peer prefers block announcements via `headers`, as per BIP 130.
+    sendheaders: bool,
+    /// Number of times the peer sent us invalid headers.
+    banscore: u32,
 
     _socket: Socket,
 }
 
+/// Headers received before their parent.
+#[derive(Debug)]
+struct Orphan {
+    /// Peer we received the headers from.
+    from: PeerId,
+    /// The headers, in chain order.
+    headers: NonEmpty<BlockHeader>,
+    /// When the headers were received.
+    received_at: LocalTime,
+}
+
 /// Sync manager configuration.
 #[derive(Debug)]
 pub struct Config {
@@ -77,6 +110,11 @@ pub struct Config {
     pub max_message_headers: usize,
     /// How long to wait for a response from a peer.
     pub request_timeout: LocalDuration,
+    /// Number of expected block intervals without a new block header, after which our tip
+    /// is considered stale.
+    pub stale_tip_factor: u32,
+    /// How often to check whether our tip is stale.
+    pub stale_tip_check: Cadence,
     /// Consensus parameters.
     pub params: Params,
 }
@@ -91,12 +129,12 @@ pub struct SyncManager<U, C> {
     peers: AddressBook<PeerId, Peer>,
     /// Last time our tip was updated.
     last_tip_update: Option<LocalTime>,
-    /// Last time we sampled our peers for their active chain.
-    last_peer_sample: Option<LocalTime>,
-    /// Last time we idled.
-    last_idle: Option<LocalTime>,
+    /// Periodic tasks.
+    schedule: Scheduler,
     /// In-flight requests to peers.
     inflight: HashMap<PeerId, GetHeaders>,
+    /// Headers received before their parent, keyed by the hash of the missing parent.
+    orphans: HashMap<BlockHash, Orphan>,
     /// Upstream protocol channel.
     upstream: U,
     /// Clock.
@@ -131,8 +169,12 @@ pub enum Event {
     },
     /// Synced up to the specified hash and height.
     Synced(BlockHash, Height),
-    /// Potential stale tip detected on the active chain.
-    StaleTip(LocalTime),
+    /// Potential stale tip detected on the active chain. No new block header was
+    /// accepted for the given duration.
+    StaleTip {
+        /// Time since our tip was last updated.
+        age: LocalDuration,
+    },
     /// Peer misbehaved.
     PeerMisbehaved(PeerId),
     /// Peer height updated.
@@ -174,11 +216,11 @@ impl std::fmt::Display for Event {
             Event::BlockDiscovered(from, hash) => {
                 write!(fmt, "{}: Discovered new block: {}", from, &hash)
             }
-            Event::StaleTip(last_update) => {
+            Event::StaleTip { age } => {
                 write!(
                     fmt,
-                    "Potential stale tip detected (last update was {})",
-                    last_update
+                    "Potential stale tip detected (last update was {} minute(s) ago)",
+                    age.as_mins()
                 )
             }
         }
@@ -201,17 +243,21 @@ impl<U: Wakeup + Disconnect + SyncHeaders, C: Clock> SyncManager<U, C> {
     pub fn new(config: Config, rng: fastrand::Rng, upstream: U, clock: C) -> Self {
         let peers = AddressBook::new(rng.clone());
         let last_tip_update = None;
-        let last_peer_sample = None;
-        let last_idle = None;
-        let inflight = HashMap::with_hasher(rng.into());
+        let inflight = HashMap::with_hasher(rng.clone().into());
+        let orphans = HashMap::with_hasher(rng.clone().into());
+        let mut schedule = Scheduler::new(rng);
+
+        schedule.schedule("idle", Cadence::every(IDLE_TIMEOUT));
+        schedule.schedule("stale-tip", config.stale_tip_check);
+        schedule.schedule("peer-sample", Cadence::every(PEER_SAMPLE_INTERVAL));
 
         Self {
             peers,
             config,
             last_tip_update,
-            last_peer_sample,
-            last_idle,
+            schedule,
             inflight,
+            orphans,
             upstream,
             clock,
         }
@@ -232,12 +278,11 @@ impl<U: Wakeup + Disconnect + SyncHeaders, C: Clock> SyncManager<U, C> {
         let now = self.clock.local_time();
         // Nb. The idle timeout is very long: as long as the block interval.
         // This shouldn't be a problem, as the sync manager can make progress without it.
-        if now - self.last_idle.unwrap_or_default() >= IDLE_TIMEOUT {
+        if let Some(next) = self.schedule.due(&"idle", now) {
             if !self.sync(tree) {
                 self.sample_peers(tree);
             }
-            self.last_idle = Some(now);
-            self.upstream.wakeup(IDLE_TIMEOUT);
+            self.upstream.wakeup(next);
         }
     }
 
@@ -264,6 +309,15 @@ impl<U: Wakeup + Disconnect + SyncHeaders, C: Clock> SyncManager<U, C> {
         self.sync(tree);
     }
 
+    /// Exchange locators with a peer, to find out whether its chain diverged from ours, even
+    /// if it doesn't claim to be ahead of us.
+    pub fn reconcile<T: BlockReader>(&mut self, addr: PeerId, tree: &T) {
+        let locators = (tree.locator_hashes(tree.height()), BlockHash::default());
+        let timeout = self.config.request_timeout;
+
+        self.request(addr, locators, timeout, OnTimeout::Ignore);
+    }
+
     /// Called when a peer disconnected.
     pub fn peer_disconnected(&mut self, id: &PeerId) {
         self.unregister(id);
@@ -286,9 +340,29 @@ impl<U: Wakeup + Disconnect + SyncHeaders, C: Clock> SyncManager<U, C> {
         if headers.is_empty() {
             return;
         }
+        // Remember the last header sent, so that we don't announce it again.
+        if let Some((height, hash)) = headers
+            .last()
+            .map(|h| h.block_hash())
+            .and_then(|h| tree.get_block(&h).map(|(height, _)| (height, h)))
+        {
+            if let Some(peer) = self.peers.get_mut(addr) {
+                if height > peer.height {
+                    peer.height = height;
+                    peer.tip = hash;
+                }
+            }
+        }
         self.upstream.send_headers(*addr, headers);
     }
 
+    /// Called when we received a `sendheaders` message from a peer.
+    pub fn received_sendheaders(&mut self, addr: &PeerId) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.sendheaders = true;
+        }
+    }
+
     /// Import blocks into our block tree.
     pub fn import_blocks<T: BlockTree, I: Iterator<Item = BlockHeader>>(
         &mut self,
@@ -324,6 +398,19 @@ impl<U: Wakeup + Disconnect + SyncHeaders, C: Clock> SyncManager<U, C> {
         }
     }
 
+    /// Called when blocks were removed from the active chain outside of a header import,
+    /// eg. when reindexing. Blocks are ordered from the tip down. Tries to sync the
+    /// removed blocks again.
+    pub fn blocks_truncated<T: BlockReader>(&mut self, blocks: &[(Height, BlockHeader)], tree: &T) {
+        for (height, header) in blocks {
+            self.upstream.event(Event::BlockDisconnected {
+                height: *height,
+                header: *header,
+            });
+        }
+        self.sync(tree);
+    }
+
     /// Called when we receive headers from a peer.
     pub fn received_headers<T: BlockTree>(
         &mut self,
@@ -370,6 +457,18 @@ impl<U: Wakeup + Disconnect + SyncHeaders, C: Clock> SyncManager<U, C> {
         if tree.contains(&best) {
             return Ok(ImportResult::TipUnchanged);
         }
+        // Announced headers may arrive before their parent, if the parent is announced
+        // by a different peer. Hold on to them for a little while, instead of asking
+        // the peer for the missing headers right away.
+        if request.is_none()
+            && !tree.is_known(&headers.first().prev_blockhash)
+            && self.is_near_tip(headers.first(), tree)
+        {
+            self.add_orphan(*from, headers);
+
+            return Ok(ImportResult::TipUnchanged);
+        }
+        let headers = self.adopt_orphans(headers);
 
         match self.import_blocks(headers.into_iter(), tree) {
             Ok(ImportResult::TipUnchanged) => {
@@ -507,6 +606,28 @@ impl<U: Wakeup + Disconnect + SyncHeaders, C: Clock> SyncManager<U, C> {
             })
             .collect::<Vec<_>>();
 
+        // Request the missing headers of orphans whose parent didn't arrive in time.
+        let expired = self
+            .orphans
+            .iter()
+            .filter(|(_, o)| local_time - o.received_at >= ORPHAN_TIMEOUT)
+            .map(|(parent, _)| *parent)
+            .collect::<Vec<_>>();
+
+        for parent in expired {
+            if let Some(orphan) = self.orphans.remove(&parent) {
+                if self.peers.contains_key(&orphan.from)
+                    && !self.inflight.contains_key(&orphan.from)
+                {
+                    let locators = (
+                        tree.locator_hashes(tree.height()),
+                        orphan.headers.last().block_hash(),
+                    );
+                    self.request(orphan.from, locators, timeout, OnTimeout::Ignore);
+                }
+            }
+        }
+
         let mut sync = false;
         for (peer, on_timeout, req) in timed_out {
             self.inflight.remove(&peer);
@@ -531,6 +652,8 @@ impl<U: Wakeup + Disconnect + SyncHeaders, C: Clock> SyncManager<U, C> {
             }
         }
 
+        self.stale_tip_watchdog(tree);
+
         // If some of the requests timed out, force a sync, otherwise just idle.
         if sync {
             self.sync(tree);
@@ -549,6 +672,75 @@ impl<U: Wakeup + Disconnect + SyncHeaders, C: Clock> SyncManager<U, C> {
         !self.inflight.is_empty()
     }
 
+    /// Dump the sync manager state, for debugging.
+    pub fn dump(&self, redact: &mut dump::Redact) -> Value {
+        let mut peers = self.peers.iter().collect::<Vec<_>>();
+        peers.sort_by_key(|(addr, _)| **addr);
+
+        let peers = peers
+            .into_iter()
+            .map(|(addr, peer)| {
+                Value::Object(dump::object([
+                    ("addr", redact.peer(addr)),
+                    ("height", dump::number(peer.height)),
+                    ("tip", dump::string(peer.tip)),
+                    ("link", dump::link(peer.link)),
+                    ("preferred", Value::Bool(peer.preferred)),
+                    ("sendheaders", Value::Bool(peer.sendheaders)),
+                    ("banscore", dump::number(peer.banscore)),
+                    ("since", dump::time(peer.since)),
+                    ("last_active", dump::optional(peer.last_active, dump::time)),
+                ]))
+            })
+            .collect();
+
+        let mut inflight = self.inflight.iter().collect::<Vec<_>>();
+        inflight.sort_by_key(|(addr, _)| **addr);
+
+        let inflight = inflight
+            .into_iter()
+            .map(|(addr, req)| {
+                let (locators, stop) = &req.locators;
+
+                Value::Object(dump::object([
+                    ("addr", redact.peer(addr)),
+                    ("locator", dump::optional(locators.first(), dump::string)),
+                    ("stop", dump::string(stop)),
+                    ("sent_at", dump::time(req.sent_at)),
+                ]))
+            })
+            .collect();
+
+        let orphans = self
+            .orphans
+            .iter()
+            .map(|(parent, orphan)| {
+                Value::Object(dump::object([
+                    ("parent", dump::string(parent)),
+                    ("from", redact.peer(&orphan.from)),
+                    ("headers", dump::number(orphan.headers.len())),
+                    ("received_at", dump::time(orphan.received_at)),
+                ]))
+            })
+            .collect();
+
+        Value::Object(dump::object([
+            ("syncing", Value::Bool(self.is_syncing())),
+            (
+                "best_height",
+                dump::optional(self.best_height(), dump::number),
+            ),
+            ("peers", Value::Array(peers)),
+            ("inflight", Value::Array(inflight)),
+            ("orphans", Value::Array(orphans)),
+            (
+                "last_tip_update",
+                dump::optional(self.last_tip_update, dump::time),
+            ),
+            ("schedule", self.schedule.dump()),
+        ]))
+    }
+
     ///////////////////////////////////////////////////////////////////////////
 
     fn handle_error(&mut self, from: &PeerId, err: Error) -> Result<(), store::Error> {
@@ -580,10 +772,68 @@ impl<U: Wakeup + Disconnect + SyncHeaders, C: Clock> SyncManager<U, C> {
         }
     }
 
+    /// Check whether a header is close enough to our tip to be worth holding on to
+    /// as an orphan.
+    fn is_near_tip<T: BlockReader>(&self, header: &BlockHeader, tree: &T) -> bool {
+        let (_, tip) = tree.tip();
+        let delta = (header.time as i64 - tip.time as i64).unsigned_abs();
+
+        delta <= TIP_STALE_DURATION.as_secs()
+    }
+
+    /// Add headers to the orphan pool. If the pool is full, the oldest orphan is evicted.
+    fn add_orphan(&mut self, from: PeerId, headers: NonEmpty<BlockHeader>) {
+        let parent = headers.first().prev_blockhash;
+
+        if self.orphans.contains_key(&parent) {
+            return;
+        }
+        if self.orphans.len() >= MAX_ORPHANS {
+            if let Some(oldest) = self
+                .orphans
+                .iter()
+                .min_by_key(|(_, o)| o.received_at)
+                .map(|(h, _)| *h)
+            {
+                self.orphans.remove(&oldest);
+            }
+        }
+        self.orphans.insert(
+            parent,
+            Orphan {
+                from,
+                headers,
+                received_at: self.clock.local_time(),
+            },
+        );
+        self.upstream.wakeup(ORPHAN_TIMEOUT);
+    }
+
+    /// Extend the given headers with any orphans that descend from them.
+    fn adopt_orphans(&mut self, mut headers: NonEmpty<BlockHeader>) -> NonEmpty<BlockHeader> {
+        while let Some(orphan) = self.orphans.remove(&headers.last().block_hash()) {
+            headers.append(&mut Vec::from(orphan.headers));
+        }
+        headers
+    }
+
     fn record_misbehavior(&mut self, peer: &PeerId) {
+        if let Some(peer) = self.peers.get_mut(peer) {
+            peer.banscore += 1;
+        }
         self.upstream.event(Event::PeerMisbehaved(*peer));
     }
 
+    /// Get the misbehavior score of a peer.
+    pub fn banscore(&self, addr: &PeerId) -> u32 {
+        self.peers.get(addr).map_or(0, |p| p.banscore)
+    }
+
+    /// Get the number of `getheaders` requests awaiting a response from a peer.
+    pub fn inflight(&self, addr: &PeerId) -> usize {
+        self.inflight.contains_key(addr) as usize
+    }
+
     /// Check whether our current tip is stale.
     ///
     /// *Nb. This doesn't check whether we've already requested new blocks.*
@@ -591,9 +841,7 @@ impl<U: Wakeup + Disconnect + SyncHeaders, C: Clock> SyncManager<U, C> {
         let now = self.clock.local_time();
 
         if let Some(last_update) = self.last_tip_update {
-            if last_update
-                < now - LocalDuration::from_secs(self.config.params.pow_target_spacing * 3)
-            {
+            if last_update < now - self.stale_tip_duration() {
                 return Some(last_update);
             }
         }
@@ -610,10 +858,55 @@ impl<U: Wakeup + Disconnect + SyncHeaders, C: Clock> SyncManager<U, C> {
         None
     }
 
+    /// Time without a new block header after which our tip is considered stale.
+    fn stale_tip_duration(&self) -> LocalDuration {
+        LocalDuration::from_secs(
+            self.config.params.pow_target_spacing * self.config.stale_tip_factor as u64,
+        )
+    }
+
+    /// Check whether our tip is stale, when it's time to. If it is, an event is
+    /// emitted, all outbound peers are asked for new headers, and the outbound peer that
+    /// has been inactive for the longest is disconnected, to make room for a new one.
+    /// This helps in case we're being eclipsed.
+    fn stale_tip_watchdog<T: BlockReader>(&mut self, tree: &T) {
+        let now = self.clock.local_time();
+        let Some(next) = self.schedule.due(&"stale-tip", now) else {
+            return;
+        };
+        self.upstream.wakeup(next);
+
+        let Some(last_update) = self.stale_tip(tree) else {
+            return;
+        };
+        self.upstream.event(Event::StaleTip {
+            age: now - last_update,
+        });
+
+        // Probe our peers for headers we may have missed.
+        self.probe_peers(tree);
+
+        // Rotate out the outbound peer that has been inactive the longest, if it's been
+        // inactive for long enough.
+        let threshold = self.stale_tip_duration();
+        let inactive = self
+            .peers
+            .iter()
+            .filter(|(_, p)| p.link.is_outbound())
+            .map(|(a, p)| (*a, p.last_active.unwrap_or(p.since)))
+            .filter(|(_, t)| now - *t >= threshold)
+            .min_by_key(|(_, t)| *t);
+
+        if let Some((addr, _)) = inactive {
+            self.upstream.disconnect(addr, DisconnectReason::StaleTip);
+        }
+    }
+
     /// Register a new peer.
     fn register(&mut self, socket: Socket, height: Height, preferred: bool, link: Link) {
         let last_active = None;
         let last_asked = None;
+        let since = self.clock.local_time();
         let tip = BlockHash::default();
 
         self.peers.insert(
@@ -625,6 +918,9 @@ impl<U: Wakeup + Disconnect + SyncHeaders, C: Clock> SyncManager<U, C> {
                 preferred,
                 last_active,
                 last_asked,
+                since,
+                sendheaders: false,
+                banscore: 0,
                 _socket: socket,
             },
         );
@@ -670,9 +966,7 @@ impl<U: Wakeup + Disconnect + SyncHeaders, C: Clock> SyncManager<U, C> {
 
     /// Check whether or not we are in sync with the network.
     fn is_synced<T: BlockReader>(&self, tree: &T) -> bool {
-        if let Some(last_update) = self.stale_tip(tree) {
-            self.upstream.event(Event::StaleTip(last_update));
-
+        if self.stale_tip(tree).is_some() {
             return false;
         }
         let height = tree.height();
@@ -731,15 +1025,33 @@ impl<U: Wakeup + Disconnect + SyncHeaders, C: Clock> SyncManager<U, C> {
         }
     }
 
-    /// Broadcast our best block header to connected peers who don't have it.
+    /// Announce our best block to inbound peers who don't have it. Peers who asked for
+    /// `headers` announcements get the headers they are missing, or only our tip if they
+    /// are missing too many. Other peers get an `inv`.
     fn broadcast_tip<T: BlockReader>(&mut self, hash: &BlockHash, tree: &T) {
-        if let Some((height, best)) = tree.get_block(hash) {
-            for (addr, peer) in &*self.peers {
-                // TODO: Don't broadcast to peer that is currently syncing?
-                if peer.link == Link::Inbound && height > peer.height {
-                    self.upstream.send_headers(*addr, vec![*best]);
-                }
+        let (height, best) = match tree.get_block(hash) {
+            Some((height, best)) => (height, *best),
+            None => return,
+        };
+        for (addr, peer) in self.peers.iter_mut() {
+            // TODO: Don't broadcast to peer that is currently syncing?
+            if peer.link != Link::Inbound || height <= peer.height {
+                continue;
             }
+            if !peer.sendheaders {
+                self.upstream.send_inv(*addr, vec![Inventory::Block(*hash)]);
+            } else if height - peer.height <= MAX_ANNOUNCED_HEADERS as Height {
+                let headers = (peer.height + 1..=height)
+                    .filter_map(|h| tree.get_block_by_height(h))
+                    .copied()
+                    .collect();
+
+                self.upstream.send_headers(*addr, headers);
+            } else {
+                self.upstream.send_headers(*addr, vec![best]);
+            }
+            peer.height = height;
+            peer.tip = *hash;
         }
     }
 
@@ -747,16 +1059,20 @@ impl<U: Wakeup + Disconnect + SyncHeaders, C: Clock> SyncManager<U, C> {
     fn sample_peers<T: BlockReader>(&mut self, tree: &T) {
         let now = self.clock.local_time();
 
-        if now - self.last_peer_sample.unwrap_or_default() < PEER_SAMPLE_INTERVAL {
+        if self.stale_tip(tree).is_none() {
             return;
         }
-        if self.stale_tip(tree).is_none() {
+        if self.schedule.due(&"peer-sample", now).is_none() {
             return;
         }
-        self.last_peer_sample = Some(now);
 
         // If we think we're in sync and we haven't asked other peers in a while, then
         // sample their headers just to make sure we're on the right chain.
+        self.probe_peers(tree);
+    }
+
+    /// Ask all our peers that we can send a request to, for headers following our tip.
+    fn probe_peers<T: BlockReader>(&mut self, tree: &T) {
         let locators = tree.locator_hashes(tree.height());
         let addrs = self
             .peers
@@ -13,21 +13,24 @@ use std::sync::Arc;
 use log::*;
 use nakamoto_common::bitcoin::network::message_blockdata::GetHeadersMessage;
 
-use super::{addrmgr, cbfmgr, invmgr, peermgr, pingmgr, syncmgr};
+use super::scheduler::Cadence;
+use super::{addrmgr, cbfmgr, invmgr, peermgr, pingmgr, ratelimit, resync, syncmgr};
 use super::{
     chan, network::Network, output::message, BlockHash, BlockHeader, Command, Config,
     DisconnectReason, Event, HashSet, Height, Io, Link, LocalDuration, LocalTime, NetworkMessage,
-    PeerId, RawNetworkMessage, ServiceFlags, VersionMessage,
+    PeerId, QueueLimits, RawNetworkMessage, ServiceFlags, VersionMessage, Work,
 };
-use super::{PROTOCOL_VERSION, USER_AGENT};
+use super::{ChainEvent, ReindexEvent, ResyncEvent};
+use super::{CommandError, Feature, Features, Fingerprint, TxStatus, PROTOCOL_VERSION, USER_AGENT};
 
 use peer::{Peer, PeerDummy};
-use simulator::{Options, Simulation};
+use simulator::{Adversary, Options, Simulation};
 
 use nakamoto_common::bitcoin::network::message_blockdata::Inventory;
 use nakamoto_common::bitcoin::network::message_filter::CFilter;
 use nakamoto_common::bitcoin::network::message_filter::{CFHeaders, GetCFHeaders, GetCFilters};
 use nakamoto_common::bitcoin::network::Address;
+use nakamoto_common::bitcoin::OutPoint;
 use nakamoto_common::bitcoin_hashes::hex::FromHex;
 
 use quickcheck_macros::quickcheck;
@@ -36,7 +39,7 @@ use nakamoto_chain::block::cache::BlockCache;
 use nakamoto_chain::block::store;
 use nakamoto_chain::store::Genesis;
 
-use nakamoto_common::block::filter::FilterHeader;
+use nakamoto_common::block::filter::{FilterHeader, Filters as _};
 use nakamoto_common::block::time::{AdjustedTime, RefClock};
 use nakamoto_common::block::tree::BlockReader as _;
 use nakamoto_common::collections::HashMap;
@@ -111,7 +114,13 @@ fn test_initial_sync() {
 
     assert!(headers.len() >= height);
 
-    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng.clone());
+    let mut alice = Peer::genesis(
+        "alice",
+        [48, 48, 48, 48],
+        network.clone(),
+        vec![],
+        rng.clone(),
+    );
     let mut bob = Peer::new(
         "bob",
         [97, 97, 97, 97],
@@ -126,7 +135,7 @@ fn test_initial_sync() {
 
     assert_eq!(bob.protocol.tree.height(), height as Height);
 
-    alice.command(Command::Connect(bob.addr));
+    alice.command(Command::Connect(bob.addr, false));
 
     let mut simulation = Simulation::new(time, rng, Options::default());
     simulation.initialize([&mut alice, &mut bob]);
@@ -167,6 +176,47 @@ fn test_idle_disconnect() {
         .expect("peer disconnects remote");
 }
 
+#[test]
+fn test_ping_cadence() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let interval = LocalDuration::from_secs(45);
+    let remote = PeerDummy::new(
+        [241, 19, 44, 18],
+        network.clone(),
+        144,
+        ServiceFlags::NETWORK,
+    );
+
+    let mut cfg = Config::from("alice", network, vec![]);
+    cfg.schedule.ping = Cadence::every(interval);
+
+    let mut peer = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+
+    peer.connect(&remote, Link::Outbound);
+    peer.elapse(LocalDuration::from_secs(1));
+
+    let nonce = peer
+        .messages(&remote.addr)
+        .find_map(|m| match m {
+            NetworkMessage::Ping(nonce) => Some(nonce),
+            _ => None,
+        })
+        .expect("`ping` is sent after the handshake");
+    peer.received(remote.addr, NetworkMessage::Pong(nonce));
+
+    // Pings are sent at the configured interval, counting from the last ping.
+    peer.elapse(LocalDuration::from_secs(43));
+    assert!(!peer
+        .messages(&remote.addr)
+        .any(|m| matches!(m, NetworkMessage::Ping(_))));
+
+    peer.elapse(LocalDuration::from_secs(1));
+    peer.messages(&remote.addr)
+        .find(|m| matches!(m, NetworkMessage::Ping(_)))
+        .expect("`ping` is sent");
+}
+
 #[test]
 fn test_inv_getheaders() {
     let rng = fastrand::Rng::new();
@@ -277,7 +327,7 @@ fn test_getheaders_retry() {
         BlockHash::from_hex("0000000000b7b2c71f2a345e3a4fc328bf5bbb436012afca590b1a11466e2206")
             .unwrap();
 
-    let mut alice = Peer::genesis("alice", [49, 40, 43, 40], network, vec![], rng);
+    let mut alice = Peer::genesis("alice", [49, 40, 43, 40], network.clone(), vec![], rng);
     let peers = [
         ([55, 55, 55, 55], network.port()).into(),
         ([66, 66, 66, 66], network.port()).into(),
@@ -381,7 +431,7 @@ fn test_handshake_version_timeout() {
 fn test_handshake_verack_timeout() {
     let network = Network::Mainnet;
     let rng = fastrand::Rng::new();
-    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network.clone(), vec![], rng);
     let remote = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
 
     peer.initialize();
@@ -408,6 +458,228 @@ fn test_handshake_verack_timeout() {
     }
 }
 
+#[test]
+fn test_handshake_deadline() {
+    let network = Network::Mainnet;
+    let rng = fastrand::Rng::new();
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network.clone(), vec![], rng);
+    let remote = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
+
+    peer.initialize();
+    peer.protocol
+        .connected(remote.addr, &peer.addr, Link::Inbound);
+
+    // The `version` arrives late, but within the deadline.
+    peer.elapse(LocalDuration::from_secs(
+        peermgr::HANDSHAKE_TIMEOUT.as_secs() - 1,
+    ));
+    peer.received(
+        remote.addr,
+        NetworkMessage::Version(remote.version(peer.addr, 0)),
+    );
+    peer.messages(&remote.addr)
+        .find(|m| matches!(m, NetworkMessage::Verack))
+        .expect("peer should send a 'verack' message back");
+
+    // The deadline applies to the whole handshake, not to each of its messages.
+    peer.elapse(LocalDuration::from_secs(1));
+    peer.outputs()
+        .find(|o| {
+            matches!(
+                o,
+                Io::Disconnect(a, DisconnectReason::PeerTimeout("verack")) if a == &remote.addr
+            )
+        })
+        .expect("peer should disconnect when the handshake isn't completed in time");
+}
+
+#[test]
+fn test_handshake_misbehavior() {
+    let network = Network::Mainnet;
+    let rng = fastrand::Rng::new();
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network.clone(), vec![], rng);
+    let remote = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
+    let cases: [(Vec<NetworkMessage>, fn(&DisconnectReason) -> bool); 4] = [
+        (vec![NetworkMessage::Verack], |r| {
+            matches!(
+                r,
+                DisconnectReason::PeerMisbehaving("`verack` received before `version`")
+            )
+        }),
+        (
+            vec![
+                NetworkMessage::Version(remote.version(peer.addr, 0)),
+                NetworkMessage::Version(remote.version(peer.addr, 0)),
+            ],
+            |r| {
+                matches!(
+                    r,
+                    DisconnectReason::PeerMisbehaving("duplicate `version` message received")
+                )
+            },
+        ),
+        (
+            vec![NetworkMessage::Version(VersionMessage {
+                version: u32::MAX,
+                ..remote.version(peer.addr, 0)
+            })],
+            |r| matches!(r, DisconnectReason::PeerProtocolVersion(u32::MAX)),
+        ),
+        (
+            vec![NetworkMessage::Version(VersionMessage {
+                start_height: -1,
+                ..remote.version(peer.addr, 0)
+            })],
+            |r| {
+                matches!(
+                    r,
+                    DisconnectReason::PeerMisbehaving("negative start height in `version` message")
+                )
+            },
+        ),
+    ];
+
+    peer.initialize();
+
+    for (msgs, expected) in cases {
+        peer.protocol
+            .connected(remote.addr, &peer.addr, Link::Inbound);
+
+        for msg in msgs {
+            peer.received(remote.addr, msg);
+        }
+        let reason = peer
+            .outputs()
+            .find_map(|o| match o {
+                Io::Disconnect(a, reason) if a == remote.addr => Some(reason),
+                _ => None,
+            })
+            .expect("peer should be disconnected");
+
+        assert!(
+            expected(&reason),
+            "unexpected disconnect reason: {}",
+            reason
+        );
+        peer.disconnected(&remote.addr, reason);
+    }
+}
+
+#[test]
+fn test_trusted_peer_identity() {
+    let network = Network::Mainnet;
+    let rng = fastrand::Rng::new();
+    let remote = PeerDummy::new(
+        [131, 31, 11, 33],
+        network.clone(),
+        144,
+        ServiceFlags::NETWORK,
+    );
+    let pinned = Fingerprint::new([1; 32]);
+
+    let mut cfg = Config::from("alice", network, vec![]);
+    cfg.trusted_peer = Some(remote.addr);
+    cfg.trusted_peer_identity = Some(pinned);
+
+    let mut peer = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+    peer.initialize();
+
+    for (identity, negotiated) in [
+        (None, false),
+        (Some(Fingerprint::new([2; 32])), false),
+        (Some(pinned), true),
+    ] {
+        let local = peer.addr;
+
+        peer.protocol.peermgr.connect(&remote.addr);
+        peer.protocol.connected(remote.addr, &local, Link::Outbound);
+
+        if let Some(fingerprint) = identity {
+            peer.protocol.authenticated(&remote.addr, fingerprint);
+        }
+        peer.received(
+            remote.addr,
+            NetworkMessage::Version(remote.version(local, 0)),
+        );
+        peer.received(remote.addr, NetworkMessage::Verack);
+
+        let outputs = peer.outputs().collect::<Vec<_>>();
+        let disconnected = outputs.iter().find_map(|o| match o {
+            Io::Disconnect(a, reason) if a == &remote.addr => Some(reason.clone()),
+            _ => None,
+        });
+
+        if negotiated {
+            assert!(disconnected.is_none(), "{:?}", disconnected);
+            assert!(outputs.iter().any(|o| matches!(
+                o,
+                Io::Event(Event::Peer(peermgr::Event::Negotiated { addr, .. })) if addr == &remote.addr
+            )));
+        } else {
+            let reason = disconnected.expect("peer should be disconnected");
+
+            assert!(
+                matches!(reason, DisconnectReason::PeerIdentity),
+                "{}",
+                reason
+            );
+            peer.disconnected(&remote.addr, reason);
+        }
+    }
+}
+
+#[test]
+fn test_trusted_peer_identity_stays_connected() {
+    let network = Network::Mainnet;
+    let rng = fastrand::Rng::new();
+    let remote = PeerDummy::new(
+        [131, 31, 11, 33],
+        network.clone(),
+        144,
+        ServiceFlags::NETWORK,
+    );
+    let pinned = Fingerprint::new([1; 32]);
+
+    let mut cfg = Config::from("alice", network, vec![]);
+    cfg.trusted_peer = Some(remote.addr);
+    cfg.trusted_peer_identity = Some(pinned);
+
+    let mut peer = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+    let local = peer.addr;
+
+    peer.initialize();
+    peer.protocol.peermgr.connect(&remote.addr);
+    peer.protocol.connected(remote.addr, &local, Link::Outbound);
+    peer.protocol.authenticated(&remote.addr, pinned);
+    peer.received(
+        remote.addr,
+        NetworkMessage::Version(remote.version(local, 0)),
+    );
+    peer.received(remote.addr, NetworkMessage::Verack);
+
+    // Keep the peer alive for half an hour, answering pings.
+    for _ in 0..60 {
+        let pings = peer
+            .messages(&remote.addr)
+            .filter_map(|m| match m {
+                NetworkMessage::Ping(nonce) => Some(nonce),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for nonce in pings {
+            peer.received(remote.addr, NetworkMessage::Pong(nonce));
+        }
+        let disconnect = peer.outputs().find_map(|o| match o {
+            Io::Disconnect(a, reason) if a == remote.addr => Some(reason),
+            _ => None,
+        });
+        assert!(disconnect.is_none(), "{:?}", disconnect);
+
+        peer.elapse(LocalDuration::from_secs(30));
+    }
+    assert!(peer.protocol.peermgr.is_connected(&remote.addr));
+}
+
 #[test]
 fn test_handshake_version_hook() {
     let network = Network::Mainnet;
@@ -422,7 +694,12 @@ fn test_handshake_version_hook() {
     });
 
     let mut peer = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
-    let craig = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
+    let craig = PeerDummy::new(
+        [131, 31, 11, 33],
+        network.clone(),
+        144,
+        ServiceFlags::NETWORK,
+    );
     let satoshi = PeerDummy::new([131, 31, 11, 66], network, 144, ServiceFlags::NETWORK);
 
     peer.protocol
@@ -456,7 +733,7 @@ fn test_handshake_version_hook() {
 fn test_handshake_initial_messages() {
     let rng = fastrand::Rng::new();
     let network = Network::Mainnet;
-    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network.clone(), vec![], rng);
 
     let remote = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
     let local = ([0, 0, 0, 0], 0).into();
@@ -493,10 +770,10 @@ fn test_handshake_initial_messages() {
 fn test_connection_error() {
     let network = Network::Mainnet;
     let rng = fastrand::Rng::new();
-    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network.clone(), vec![], rng);
     let remote = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
 
-    peer.command(Command::Connect(remote.addr));
+    peer.command(Command::Connect(remote.addr, false));
     peer.outputs()
         .find(|o| matches!(o, Io::Connect(addr) if addr == &remote.addr))
         .expect("Alice should try to connect to remote");
@@ -561,7 +838,7 @@ fn test_getaddr() {
 fn test_stale_tip() {
     let rng = fastrand::Rng::new();
     let network = Network::Mainnet;
-    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network.clone(), vec![], rng);
     let remote: PeerId = ([33, 33, 33, 33], network.port()).into();
     let headers = &BITCOIN_HEADERS;
 
@@ -585,7 +862,7 @@ fn test_stale_tip() {
     alice.elapse(syncmgr::TIP_STALE_DURATION);
     alice
         .events()
-        .find(|e| matches!(e, Event::Chain(syncmgr::Event::StaleTip(_))))
+        .find(|e| matches!(e, Event::Chain(syncmgr::Event::StaleTip { .. })))
         .expect("Alice emits a `StaleTip` event");
 
     // Timeout the `getheaders` request.
@@ -604,8 +881,56 @@ fn test_stale_tip() {
     // Chain update should be stale this time.
     alice
         .events()
-        .find(|e| matches!(e, Event::Chain(syncmgr::Event::StaleTip(_))))
+        .find(|e| matches!(e, Event::Chain(syncmgr::Event::StaleTip { .. })))
+        .expect("Alice emits a `StaleTip` event");
+}
+
+#[test]
+fn test_stale_tip_rotation() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network.clone(), vec![], rng);
+    let bob: PeerId = ([241, 19, 44, 18], network.port()).into();
+    let eve: PeerId = ([241, 19, 44, 19], network.port()).into();
+    let stale = LocalDuration::from_secs(
+        network.params().pow_target_spacing * syncmgr::STALE_TIP_FACTOR as u64,
+    );
+
+    // Some time has passed. Our tip is stale, but there's no one to rotate out.
+    alice.initialize();
+    alice.elapse(syncmgr::TIP_STALE_DURATION);
+    alice
+        .events()
+        .find(|e| matches!(e, Event::Chain(syncmgr::Event::StaleTip { .. })))
         .expect("Alice emits a `StaleTip` event");
+
+    // Our peers were only just connected, so they aren't rotated out.
+    alice.connect_addr(&bob, Link::Outbound);
+    alice.connect_addr(&eve, Link::Outbound);
+    alice.elapse(LocalDuration::BLOCK_INTERVAL);
+
+    assert!(!alice
+        .outputs()
+        .any(|o| matches!(o, Io::Disconnect(_, DisconnectReason::StaleTip))));
+
+    // Our peers haven't sent us any headers for too long. One of them is rotated out,
+    // and the other is asked for headers.
+    alice.elapse(stale);
+
+    let disconnected = alice
+        .outputs()
+        .filter_map(|o| match o {
+            Io::Disconnect(addr, DisconnectReason::StaleTip) => Some(addr),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(disconnected.len(), 1);
+
+    let remaining = if disconnected[0] == bob { eve } else { bob };
+    alice
+        .messages(&remaining)
+        .find(|msg| matches!(msg, NetworkMessage::GetHeaders(_)))
+        .expect("Alice probes the remaining peer for headers");
 }
 
 #[quickcheck]
@@ -614,8 +939,10 @@ fn prop_addrs(seed: u64) {
     let network = Network::Mainnet;
     let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
     let bob: PeerId = ([241, 19, 44, 18], 8333).into();
+    let eve: PeerId = ([241, 19, 44, 19], 8333).into();
 
     alice.connect_addr(&bob, Link::Outbound);
+    alice.connect_addr(&eve, Link::Inbound);
 
     let jak: PeerId = ([88, 13, 16, 59], 8333).into();
     let jim: PeerId = ([99, 45, 180, 58], 8333).into();
@@ -623,7 +950,8 @@ fn prop_addrs(seed: u64) {
 
     let time = alice.local_time().block_time();
 
-    // Let alice know about these amazing peers.
+    // Let alice know about these amazing peers, in response to her `getaddr`.
+    alice.protocol.addrmgr.get_addresses();
     alice.received(
         bob,
         NetworkMessage::Addr(vec![
@@ -633,10 +961,15 @@ fn prop_addrs(seed: u64) {
         ]),
     );
 
+    // Since these addresses are fresh, they are relayed to Eve.
+    assert!(alice
+        .messages(&eve)
+        .any(|o| matches!(o, NetworkMessage::Addr(addrs) if addrs.len() == 3)));
+
     // Let's query Alice to see if she has these addresses.
-    alice.received(bob, NetworkMessage::GetAddr);
+    alice.received(eve, NetworkMessage::GetAddr);
     let msg = alice
-        .messages(&bob)
+        .messages(&eve)
         .find(|o| matches!(o, NetworkMessage::Addr(_)))
         .expect("peer should respond with `addr`");
 
@@ -644,18 +977,11 @@ fn prop_addrs(seed: u64) {
         NetworkMessage::Addr(addrs) => addrs,
         _ => unreachable!(),
     };
-    assert_eq!(addrs.len(), 3);
-
-    let addrs: HashSet<net::SocketAddr> = addrs
-        .iter()
-        .map(|(_, a)| a.socket_addr().unwrap())
-        .collect();
-
-    assert!(addrs.contains(&jak));
-    assert!(addrs.contains(&jim));
-    assert!(addrs.contains(&jon));
+    // Only a sample of the address book is returned.
+    assert_eq!(addrs.len(), 1);
 
-    assert_eq!(addrs.len(), 3);
+    let addr = addrs[0].1.socket_addr().unwrap();
+    assert!([jak, jim, jon].contains(&addr));
 }
 
 #[quickcheck]
@@ -727,6 +1053,45 @@ fn test_connect_to_peers() {
         );
 }
 
+#[test]
+fn test_adversary_lying_filters() {
+    let (alice, evil, simulator) = simulations::sync_with_adversary(Adversary::LyingFilters, 1);
+
+    // The liar's filter headers don't match our checkpoint: it is banned.
+    assert!(alice.protocol.peermgr.is_banned(&evil.ip()));
+    assert!(simulator
+        .disconnects(alice.addr.ip())
+        .any(|(addr, reason)| addr == &evil && matches!(reason, DisconnectReason::PeerBanned)));
+}
+
+#[test]
+fn test_adversary_stalling() {
+    let (alice, evil, _) = simulations::sync_with_adversary(Adversary::Stalling, 1);
+
+    // Headers were synced from the honest peer. Since an unresponsive peer may just be
+    // slow, the stalling peer isn't banned.
+    assert!(!alice.protocol.peermgr.is_banned(&evil.ip()));
+}
+
+#[test]
+fn test_adversary_oversized_message() {
+    let (alice, evil, simulator) = simulations::sync_with_adversary(Adversary::Oversized, 1);
+
+    assert!(simulator
+        .disconnects(alice.addr.ip())
+        .any(|(addr, reason)| addr == &evil && matches!(reason, DisconnectReason::DecodeError(_))));
+}
+
+#[test]
+fn test_adversary_addr_flood() {
+    let (alice, evil, simulator) = simulations::sync_with_adversary(Adversary::AddrFlood, 1);
+
+    assert!(simulator
+        .disconnects(alice.addr.ip())
+        .any(|(addr, reason)| addr == &evil
+            && matches!(reason, DisconnectReason::PeerMisbehaving("message flood"))));
+}
+
 #[test]
 fn test_connect_to_peers_1() {
     assert!(simulations::connect_to_peers(
@@ -838,6 +1203,92 @@ fn test_submit_transactions() {
         .expect("Alice responds to `getdata` with a `tx` message");
 }
 
+#[test]
+fn test_replace_unknown_transaction() {
+    let network = Network::Mainnet;
+    let mut rng = fastrand::Rng::new();
+    let mut alice = Peer::genesis(
+        "alice",
+        [48, 48, 48, 48],
+        network.clone(),
+        vec![],
+        rng.clone(),
+    );
+    let remote = PeerDummy {
+        relay: true,
+        ..PeerDummy::new([88, 88, 88, 88], network, 144, ServiceFlags::NETWORK)
+    };
+    let original = gen::transaction(&mut rng);
+    let replacement = gen::transaction(&mut rng);
+    let (transmit, receive) = chan::bounded(1);
+
+    alice.connect(&remote, Link::Outbound);
+    alice.command(Command::ReplaceTransaction(
+        original.txid(),
+        replacement.clone(),
+        transmit,
+    ));
+    assert!(receive.recv().unwrap().is_err());
+
+    // A failed replacement isn't watched.
+    assert!(!alice
+        .protocol
+        .cbfmgr
+        .rescan
+        .transactions
+        .contains_key(&replacement.txid()));
+}
+
+#[test]
+fn test_submit_package() {
+    let network = Network::Mainnet;
+    let mut rng = fastrand::Rng::new();
+    let mut alice = Peer::genesis(
+        "alice",
+        [48, 48, 48, 48],
+        network.clone(),
+        vec![],
+        rng.clone(),
+    );
+    let remote = PeerDummy {
+        relay: true,
+        ..PeerDummy::new([88, 88, 88, 88], network, 144, ServiceFlags::NETWORK)
+    };
+    let parent = gen::transaction(&mut rng);
+    let child = gen::transaction_with(OutPoint::new(parent.txid(), 0), 1000, &mut rng);
+    let (transmit, receive) = chan::bounded(1);
+
+    alice.command(Command::SubmitPackage(
+        vec![child.clone(), parent.clone()],
+        transmit.clone(),
+    ));
+    assert_matches!(receive.recv().unwrap(), Err(CommandError::NotConnected));
+
+    alice.connect(&remote, Link::Outbound);
+    alice.command(Command::SubmitPackage(
+        vec![child.clone(), parent.clone()],
+        transmit,
+    ));
+    assert_eq!(
+        receive.recv().unwrap().unwrap(),
+        vec![
+            (parent.txid(), TxStatus::Announced),
+            (child.txid(), TxStatus::Announced)
+        ]
+    );
+
+    alice.tock();
+    alice
+        .messages(&remote.addr)
+        .find(|msg| {
+            msg == &NetworkMessage::Inv(vec![
+                Inventory::Transaction(parent.txid()),
+                Inventory::Transaction(child.txid()),
+            ])
+        })
+        .expect("Alice announces the parent before the child");
+}
+
 /// Should rebroadcast `inv` when no `getdata` is received.
 /// Should rebroadcast when a new peer connects.
 #[test]
@@ -1073,7 +1524,7 @@ fn test_confirmed_transaction() {
     assert!(
         matches! {
             events.next().unwrap(), invmgr::Event::Confirmed { block, transaction, .. }
-            if block == blk1.block_hash() && transaction.txid() == tx1.txid()
+            if block.hash == blk1.block_hash() && transaction.txid() == tx1.txid()
         },
         "Alice emits the first 'Confirmed' event"
     );
@@ -1088,7 +1539,7 @@ fn test_confirmed_transaction() {
     assert!(
         matches! {
             events.next().unwrap(), invmgr::Event::Confirmed { block, transaction, .. }
-            if block == blk2.block_hash() && transaction.txid() == tx2.txid()
+            if block.hash == blk2.block_hash() && transaction.txid() == tx2.txid()
         },
         "Alice emits the second 'Confirmed' event"
     );
@@ -1119,19 +1570,24 @@ fn test_submitted_transaction_filtering() {
     let genesis = network.genesis_block();
     let chain = gen::blockchain(genesis, height, &mut rng);
     let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
-    let cfheader_genesis = FilterHeader::genesis(network);
+    let cfheader_genesis = FilterHeader::genesis(network.clone());
     let cfheaders = gen::cfheaders_from_blocks(cfheader_genesis, chain.iter())
         .into_iter()
         .skip(1) // Skip genesis
         .collect::<Vec<_>>();
     let filter_type = 0x0;
-    let mut alice = Peer::new(
-        "alice",
+    let cfg = Config {
+        // Blocks are only requested once their filter matches.
+        direct_fetch: false,
+        services: syncmgr::REQUIRED_SERVICES | cbfmgr::REQUIRED_SERVICES,
+        ..Config::from("alice", network, vec![])
+    };
+    let mut alice = Peer::config(
         [48, 48, 48, 48],
-        network,
         headers.tail,
         cfheaders.clone(),
         vec![],
+        cfg,
         rng.clone(),
     );
     let tx = gen::transaction(&mut rng);
@@ -1154,6 +1610,7 @@ fn test_submitted_transaction_filtering() {
 
     // Start a rescan, to make sure we catch the transaction when it's confirmed.
     alice.command(Command::Rescan {
+        id: 0,
         from: Bound::Unbounded, // Start scanning from the current height.
         to: Bound::Unbounded,   // Keep scanning forever.
         watch: vec![],          // Submitted transactions are tracked automatically.
@@ -1221,26 +1678,22 @@ fn test_submitted_transaction_filtering() {
 }
 
 #[test]
-fn test_transaction_reverted_reconfirm() {
+fn test_direct_fetch() {
     let height = 16;
     let mut rng = fastrand::Rng::new();
 
-    logger::init(log::Level::Debug);
-
     let network = Network::Regtest;
     let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+    let (transmit, _) = chan::unbounded();
     let genesis = network.genesis_block();
     let chain = gen::blockchain(genesis, height, &mut rng);
-    let chain_tip = chain.last();
     let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
-    let cfheader_genesis = FilterHeader::genesis(network);
+    let cfheader_genesis = FilterHeader::genesis(network.clone());
     let cfheaders = gen::cfheaders_from_blocks(cfheader_genesis, chain.iter())
         .into_iter()
         .skip(1) // Skip genesis
         .collect::<Vec<_>>();
-    let (_, cfheaders_tip) = cfheaders.last().unwrap();
     let filter_type = 0x0;
-
     let mut alice = Peer::new(
         "alice",
         [48, 48, 48, 48],
@@ -1252,7 +1705,7 @@ fn test_transaction_reverted_reconfirm() {
     );
     let tx = gen::transaction(&mut rng);
 
-    // Connect to a peer and submit the transaction.
+    alice.tick(LocalTime::from_block_time(chain.last().header.time));
     alice.connect(
         &PeerDummy {
             addr: remote,
@@ -1264,31 +1717,144 @@ fn test_transaction_reverted_reconfirm() {
         },
         Link::Outbound,
     );
-
-    let (submit_reply, _) = chan::bounded(1);
-
-    // Start a rescan, to make sure we catch the transaction when it's confirmed.
     alice.command(Command::Rescan {
-        from: Bound::Unbounded, // Start scanning from the current height.
-        to: Bound::Unbounded,   // Keep scanning forever.
-        watch: vec![],          // Submitted transactions are tracked automatically.
+        id: 0,
+        from: Bound::Unbounded,
+        to: Bound::Unbounded,
+        watch: vec![],
     });
-    alice.command(Command::SubmitTransaction(tx.clone(), submit_reply));
+    alice.command(Command::SubmitTransaction(tx.clone(), transmit));
     alice.tock();
+    alice.drain();
 
-    // Alice receives the initial shorter chain.
-    {
-        // The next block will have the matching transaction.
-        let matching = gen::block_with(&chain_tip.header, vec![tx.clone()], &mut rng);
-        let cfilter = gen::cfilter(&matching);
-        let (_, parent) = cfheaders.last().unwrap();
-        let (cfhash, _) = gen::cfheader(parent, &cfilter);
-
-        // Set Alice's clock to the last block time.
-        alice.tick(LocalTime::from_block_time(chain_tip.header.time));
+    let matching = gen::block_with(&chain.last().header, vec![tx.clone()], &mut rng);
+    let cfilter = gen::cfilter(&matching);
+    let (_, parent) = cfheaders.last().unwrap();
+    let (cfhash, _) = gen::cfheader(parent, &cfilter);
+    let expected = vec![Inventory::Block(matching.block_hash())];
 
-        // Alice receives a header announcement.
-        alice.received(remote, NetworkMessage::Headers(vec![matching.header]));
+    // Alice receives a header announcement, and fetches the block right away.
+    alice.received(remote, NetworkMessage::Headers(vec![matching.header]));
+    alice
+        .messages(&remote)
+        .find(|m| matches!(m, NetworkMessage::GetData(data) if data == &expected))
+        .expect("Alice asks for the announced block");
+
+    // The block isn't processed until its filter matches.
+    alice.received(remote, NetworkMessage::Block(matching.clone()));
+    assert!(!alice
+        .events()
+        .any(|e| matches!(e, Event::Inventory(invmgr::Event::BlockProcessed { .. }))));
+
+    alice.received(
+        remote,
+        NetworkMessage::CFHeaders(CFHeaders {
+            filter_type,
+            stop_hash: matching.block_hash(),
+            previous_filter_header: *parent,
+            filter_hashes: vec![cfhash],
+        }),
+    );
+    alice.received(
+        remote,
+        NetworkMessage::CFilter(CFilter {
+            filter_type,
+            block_hash: matching.block_hash(),
+            filter: cfilter.content,
+        }),
+    );
+    alice
+        .events()
+        .find(|e| {
+            matches!(
+                e, Event::Inventory(invmgr::Event::BlockProcessed { block, .. })
+                if block.block_hash() == matching.block_hash()
+            )
+        })
+        .expect("Alice processes the block once its filter matches");
+
+    // The block isn't requested again.
+    alice.tock();
+    assert!(!alice
+        .messages(&remote)
+        .any(|m| matches!(m, NetworkMessage::GetData(_))));
+    assert!(alice.protocol.invmgr.is_empty(), "The mempool is empty");
+    assert!(
+        !alice.protocol.cbfmgr.unwatch_transaction(&tx.txid()),
+        "The transaction is no longer watched"
+    );
+}
+
+#[test]
+fn test_transaction_reverted_reconfirm() {
+    let height = 16;
+    let mut rng = fastrand::Rng::new();
+
+    logger::init(log::Level::Debug);
+
+    let network = Network::Regtest;
+    let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+    let genesis = network.genesis_block();
+    let chain = gen::blockchain(genesis, height, &mut rng);
+    let chain_tip = chain.last();
+    let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+    let cfheader_genesis = FilterHeader::genesis(network.clone());
+    let cfheaders = gen::cfheaders_from_blocks(cfheader_genesis, chain.iter())
+        .into_iter()
+        .skip(1) // Skip genesis
+        .collect::<Vec<_>>();
+    let (_, cfheaders_tip) = cfheaders.last().unwrap();
+    let filter_type = 0x0;
+
+    let mut alice = Peer::new(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        headers.tail,
+        cfheaders.clone(),
+        vec![],
+        rng.clone(),
+    );
+    let tx = gen::transaction(&mut rng);
+
+    // Connect to a peer and submit the transaction.
+    alice.connect(
+        &PeerDummy {
+            addr: remote,
+            height,
+            protocol_version: PROTOCOL_VERSION,
+            services: cbfmgr::REQUIRED_SERVICES | syncmgr::REQUIRED_SERVICES,
+            relay: true,
+            time: alice.local_time(),
+        },
+        Link::Outbound,
+    );
+
+    let (submit_reply, _) = chan::bounded(1);
+
+    // Start a rescan, to make sure we catch the transaction when it's confirmed.
+    alice.command(Command::Rescan {
+        id: 0,
+        from: Bound::Unbounded, // Start scanning from the current height.
+        to: Bound::Unbounded,   // Keep scanning forever.
+        watch: vec![],          // Submitted transactions are tracked automatically.
+    });
+    alice.command(Command::SubmitTransaction(tx.clone(), submit_reply));
+    alice.tock();
+
+    // Alice receives the initial shorter chain.
+    {
+        // The next block will have the matching transaction.
+        let matching = gen::block_with(&chain_tip.header, vec![tx.clone()], &mut rng);
+        let cfilter = gen::cfilter(&matching);
+        let (_, parent) = cfheaders.last().unwrap();
+        let (cfhash, _) = gen::cfheader(parent, &cfilter);
+
+        // Set Alice's clock to the last block time.
+        alice.tick(LocalTime::from_block_time(chain_tip.header.time));
+
+        // Alice receives a header announcement.
+        alice.received(remote, NetworkMessage::Headers(vec![matching.header]));
 
         // Alice receives the cfheaders.
         alice.received(
@@ -1404,7 +1970,7 @@ fn test_transaction_reverted_reconfirm() {
                 matches!(
                     e,
                     Event::Filter(cbfmgr::Event::FilterProcessed { block, matched: true, .. })
-                    if block == &fork_matching.block_hash()
+                    if block.hash == fork_matching.block_hash()
                 )
             })
             .expect("The new block is matched");
@@ -1416,7 +1982,7 @@ fn test_transaction_reverted_reconfirm() {
                 matches!(
                     e,
                     Event::Inventory(invmgr::Event::Confirmed { transaction, block, .. })
-                    if transaction.txid() == tx.txid() && block == &fork_matching.block_hash()
+                    if transaction.txid() == tx.txid() && block.hash == fork_matching.block_hash()
                 )
             })
             .expect("The transaction is re-confirmed");
@@ -1554,3 +2120,1226 @@ fn test_transaction_mempool_rebroadcast() {
 fn test_getdata_retry() {
     // TODO: Should retry getting blocks
 }
+
+#[test]
+fn test_set_config() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let port = network.port();
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let time = alice.local_time().block_time();
+    let timeout = LocalDuration::from_secs(7);
+
+    // Only full-relay connections are under test here.
+    alice.protocol.peermgr.config.target_block_relay_peers = 0;
+    alice.protocol.peermgr.config.feeler = None;
+    alice.initialize();
+    alice.command(Command::SetConfig(super::ConfigUpdate {
+        target_outbound_peers: Some(0),
+        ..super::ConfigUpdate::default()
+    }));
+    alice.drain();
+
+    // Give alice an address for her address book. Since her outbound target is zero,
+    // she shouldn't connect to it.
+    let addr = Address::new(
+        &([77, 77, 77, 77], port).into(),
+        alice.protocol.peermgr.config.required_services,
+    );
+    alice.protocol.addrmgr.insert([(time, addr)], Source::Dns);
+    alice.elapse(peermgr::IDLE_TIMEOUT);
+
+    assert!(!alice.outputs().any(|o| matches!(o, Io::Connect(_))));
+
+    // Raising the target triggers a connection straight away.
+    let update = super::ConfigUpdate {
+        target_outbound_peers: Some(1),
+        ping_timeout: Some(timeout),
+        queue_limits: Some(QueueLimits {
+            peer: 1024,
+            total: 4096,
+        }),
+        rate_limits: Some(ratelimit::Config {
+            max_banscore: 1,
+            ..ratelimit::Config::default()
+        }),
+        ..super::ConfigUpdate::default()
+    };
+    alice.command(Command::SetConfig(update.clone()));
+
+    let outputs = alice.outputs().collect::<Vec<_>>();
+    assert!(outputs.iter().any(|o| matches!(o, Io::Connect(_))));
+    assert!(outputs
+        .iter()
+        .any(|o| matches!(o, Io::Event(Event::ConfigUpdated(u)) if u == &update)));
+    assert_eq!(alice.protocol.peermgr.config.target_outbound_peers, 1);
+}
+
+#[test]
+fn test_sync_burst() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+    let duration = LocalDuration::from_secs(30);
+    let mut alice = Peer::config(
+        [48, 48, 48, 48],
+        vec![],
+        vec![],
+        vec![],
+        Config {
+            connect: vec![remote],
+            ..Config::from("alice", network, vec![])
+        },
+        rng,
+    );
+    alice.initialize();
+    alice.command(Command::SyncBurst(duration));
+    alice.connect_addr(&remote, Link::Outbound);
+    alice.drain();
+
+    // Once the burst is over, we disconnect and stop waking up.
+    alice.elapse(duration);
+
+    let outputs = alice.outputs().collect::<Vec<_>>();
+    assert!(outputs.iter().any(
+        |o| matches!(o, Io::Disconnect(a, DisconnectReason::NetworkInactive) if a == &remote)
+    ));
+    assert!(outputs
+        .iter()
+        .any(|o| matches!(o, Io::Event(Event::Quiesced))));
+
+    // Pending timers are cancelled, and no new ones are set.
+    let cancelled = outputs
+        .iter()
+        .position(|o| matches!(o, Io::CancelWakeups))
+        .expect("wakeups are cancelled");
+    assert!(!outputs[cancelled..]
+        .iter()
+        .any(|o| matches!(o, Io::Wakeup(_))));
+
+    alice.elapse(peermgr::IDLE_TIMEOUT);
+    assert_eq!(alice.outputs().count(), 0);
+
+    // The next burst reconnects to our peers, and re-arms the timers.
+    alice.command(Command::SyncBurst(duration));
+    let outputs = alice.outputs().collect::<Vec<_>>();
+    assert!(outputs
+        .iter()
+        .any(|o| matches!(o, Io::Connect(a) if a == &remote)));
+    assert!(outputs
+        .iter()
+        .any(|o| matches!(o, Io::Wakeup(d) if d == &duration)));
+    assert!(!outputs.iter().any(|o| matches!(o, Io::CancelWakeups)));
+}
+
+#[test]
+fn test_partition_resync() {
+    let height = 16;
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+    let chain = gen::blockchain(network.genesis_block(), height, &mut rng);
+    let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+    let peer = |time| PeerDummy {
+        addr: remote,
+        height,
+        protocol_version: PROTOCOL_VERSION,
+        services: syncmgr::REQUIRED_SERVICES,
+        relay: true,
+        time,
+    };
+    let cfg = Config {
+        connect: vec![remote],
+        headers_only: true,
+        ..Config::from("alice", network, vec![])
+    };
+    let mut alice = Peer::config(
+        [48, 48, 48, 48],
+        headers.tail,
+        vec![],
+        vec![],
+        cfg,
+        rng.clone(),
+    );
+    alice.tick(LocalTime::from_block_time(chain.last().header.time));
+    alice.connect(&peer(alice.local_time()), Link::Outbound);
+    alice
+        .protocol
+        .disconnected(&remote, DisconnectReason::PeerTimeout("test"));
+    alice.drain();
+
+    // After a while without peers, we start reconnecting with backoff.
+    alice.elapse(resync::PARTITION_TIMEOUT);
+    let events = alice.events().collect::<Vec<_>>();
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, Event::Resync(ResyncEvent::Partitioned { .. }))));
+    assert!(events.iter().any(|e| matches!(
+        e,
+        Event::Resync(ResyncEvent::Reconnecting { attempt: 1, next }) if *next == resync::MIN_BACKOFF
+    )));
+
+    alice.elapse(resync::MIN_BACKOFF);
+    assert!(alice.events().any(|e| matches!(
+        e,
+        Event::Resync(ResyncEvent::Reconnecting { attempt: 2, .. })
+    )));
+
+    // Once reconnected, we exchange locators with the peer, even though it doesn't claim
+    // to be ahead of us.
+    alice.connect(&peer(alice.local_time()), Link::Outbound);
+    alice
+        .messages(&remote)
+        .find(|m| matches!(m, NetworkMessage::GetHeaders(_)))
+        .expect("Alice asks for headers");
+
+    let block = gen::block(&chain.last().header, &mut rng);
+    alice.received(remote, NetworkMessage::Headers(vec![block.header]));
+
+    let resynced = alice
+        .events()
+        .filter(|e| matches!(e, Event::Resync(ResyncEvent::Resynced { .. })))
+        .collect::<Vec<_>>();
+    assert_matches!(
+        resynced.as_slice(),
+        [Event::Resync(ResyncEvent::Resynced {
+            attempts: 2,
+            from,
+            height: to,
+            reverted: 0,
+            connected: 1,
+            ..
+        })] if *from == height && *to == height + 1
+    );
+}
+
+#[test]
+fn test_low_data() {
+    let height = 16;
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+    let chain = gen::blockchain(network.genesis_block(), height, &mut rng);
+    let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+    let cfg = Config {
+        services: syncmgr::REQUIRED_SERVICES | cbfmgr::REQUIRED_SERVICES,
+        ..Config::from("alice", network, vec![])
+    };
+    let mut alice = Peer::config(
+        [48, 48, 48, 48],
+        headers.tail,
+        vec![],
+        vec![],
+        cfg,
+        rng.clone(),
+    );
+    alice.tick(LocalTime::from_block_time(chain.last().header.time));
+    alice.command(Command::SetLowData(true));
+    alice.connect(
+        &PeerDummy {
+            addr: remote,
+            height,
+            protocol_version: PROTOCOL_VERSION,
+            services: cbfmgr::REQUIRED_SERVICES | syncmgr::REQUIRED_SERVICES,
+            relay: true,
+            time: alice.local_time(),
+        },
+        Link::Outbound,
+    );
+
+    // In low-data mode, we follow the tip, but don't fetch filters or blocks.
+    let block = gen::block(&chain.last().header, &mut rng);
+    alice.received(remote, NetworkMessage::Headers(vec![block.header]));
+
+    assert_eq!(alice.protocol.tree.height(), height + 1);
+    assert!(!alice.messages(&remote).any(|m| matches!(
+        m,
+        NetworkMessage::GetCFHeaders(_) | NetworkMessage::GetData(_)
+    )));
+
+    let (transmit, receive) = chan::bounded(1);
+    alice.command(Command::GetBandwidth(transmit));
+    let bandwidth = receive.recv().unwrap();
+
+    assert!(bandwidth.low_data.received > 0);
+    assert_eq!(bandwidth.normal, super::Usage::default());
+
+    // Full sync resumes once we leave low-data mode.
+    alice.command(Command::SetLowData(false));
+    alice
+        .messages(&remote)
+        .find(|m| matches!(m, NetworkMessage::GetCFHeaders(_)))
+        .expect("Alice asks for cfheaders");
+}
+
+#[test]
+fn test_capture_malformed() {
+    use super::capture::{self, Capture, Direction};
+
+    let rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let bob = PeerDummy::new([88, 88, 88, 88], network.clone(), 2, ServiceFlags::NETWORK);
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network.clone(), vec![], rng);
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let capture = Capture::new(file.reopen().unwrap());
+
+    alice.connect(&bob, Link::Outbound);
+    alice.command(Command::Capture(Some(capture.clone())));
+
+    // A message with an invalid checksum.
+    let mut bytes = Vec::new();
+    message::Builder::new(network)
+        .write(NetworkMessage::Ping(42), &mut bytes)
+        .unwrap();
+    bytes[20] ^= 0xff;
+    alice.protocol.received_bytes(&bob.addr, &bytes);
+
+    alice.command(Command::Capture(None));
+    capture.flush().unwrap();
+
+    let records = capture::read(io::BufReader::new(file))
+        .collect::<io::Result<Vec<_>>>()
+        .unwrap();
+    let record = records.last().unwrap();
+
+    assert_eq!(record.direction, Direction::Inbound);
+    assert_eq!(record.message, bytes);
+    assert!(record.decode().is_err());
+    assert!(alice.protocol.outbox.drain().any(|o| matches!(
+        o,
+        Io::Disconnect(addr, DisconnectReason::DecodeError(_)) if addr == bob.addr
+    )));
+}
+
+#[test]
+fn test_message_stats() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let bob = PeerDummy::new([88, 88, 88, 88], network.clone(), 2, ServiceFlags::NETWORK);
+    let remote = bob.addr;
+    let chain = gen::blockchain(network.genesis_block(), 2, &mut rng);
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+
+    alice.connect(&bob, Link::Outbound);
+    alice.received(remote, NetworkMessage::Headers(vec![chain[1].header]));
+    alice.elapse(LocalDuration::from_secs(60));
+    alice.received(remote, NetworkMessage::Headers(vec![chain[2].header]));
+
+    let (transmit, receive) = chan::bounded(1);
+    alice.command(Command::GetMessageStats(transmit));
+    let stats = receive.recv().unwrap();
+
+    // Message header, header count, and header with its transaction count.
+    let headers = &stats.inbound["headers"];
+    assert_eq!(headers.count, 2);
+    assert_eq!(headers.bytes, 2 * (24 + 1 + 81));
+    // Times come from the protocol clock.
+    assert_eq!(headers.last, Some(alice.local_time()));
+    assert!(stats.inbound["version"].last < headers.last);
+
+    assert_eq!(stats.inbound["version"].count, 1);
+    assert_eq!(stats.outbound["version"].count, 1);
+    assert!(stats.outbound["getheaders"].count >= 1);
+    assert!(!stats.outbound.contains_key("headers"));
+
+    let total = stats.total();
+    assert_eq!(
+        total.count,
+        stats
+            .inbound
+            .values()
+            .chain(stats.outbound.values())
+            .map(|u| u.count)
+            .sum::<u64>()
+    );
+    assert!(total.bytes > headers.bytes);
+}
+
+#[test]
+fn test_connect_only() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let bob = PeerDummy::new(
+        [88, 88, 88, 88],
+        network.clone(),
+        144,
+        ServiceFlags::NETWORK,
+    );
+    let eve: PeerId = ([99, 99, 99, 99], 38812).into();
+    let cfg = Config {
+        network,
+        connect: vec![bob.addr],
+        connect_only: true,
+        ..Config::default()
+    };
+    let mut alice = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+    let local = alice.addr;
+
+    alice.initialize();
+    alice.protocol.addrmgr.insert(
+        [(
+            alice.local_time().block_time(),
+            Address::new(&bob.addr, ServiceFlags::NETWORK),
+        )],
+        Source::Dns,
+    );
+    assert!(alice
+        .outputs()
+        .any(|o| matches!(o, Io::Connect(a) if a == bob.addr)));
+
+    // Connecting to an allowed peer works, but we don't ask it for addresses.
+    alice.connected(bob.addr, &local, Link::Outbound);
+    alice.received(bob.addr, NetworkMessage::Version(bob.version(local, 0)));
+    alice.received(bob.addr, NetworkMessage::Verack);
+
+    let msgs = alice.messages(&bob.addr).collect::<Vec<_>>();
+    assert!(msgs.contains(&NetworkMessage::SendHeaders));
+    assert!(!msgs.contains(&NetworkMessage::GetAddr));
+
+    // Inbound connections from other peers are refused.
+    alice.connected(eve, &local, Link::Inbound);
+    assert!(alice
+        .outputs()
+        .any(|o| matches!(o, Io::Disconnect(a, DisconnectReason::PeerNotAllowed) if a == eve)));
+}
+
+#[test]
+fn test_header_relay() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let headers = &BITCOIN_HEADERS.tail;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network.clone(), vec![], rng);
+    let bob = PeerDummy::new([88, 88, 88, 88], network.clone(), 0, ServiceFlags::NETWORK);
+    let carol = PeerDummy::new([99, 99, 99, 99], network, 0, ServiceFlags::NETWORK);
+
+    // Make sure the headers we import aren't in the future.
+    alice.initialize();
+    alice.elapse(LocalDuration::from_mins(60 * 24 * 7));
+    alice.connect(&bob, Link::Inbound);
+    alice.connect(&carol, Link::Inbound);
+    alice.received(bob.addr, NetworkMessage::SendHeaders);
+    alice.drain();
+
+    let (tx, _) = chan::bounded(1);
+    alice.command(Command::ImportHeaders(headers[..3].to_vec(), tx));
+
+    // Bob asked for headers announcements, while Carol didn't.
+    assert_eq!(
+        alice
+            .messages(&bob.addr)
+            .filter(|m| matches!(m, NetworkMessage::Headers(_)))
+            .collect::<Vec<_>>(),
+        vec![NetworkMessage::Headers(headers[..3].to_vec())]
+    );
+    assert!(alice
+        .messages(&carol.addr)
+        .any(|m| m == NetworkMessage::Inv(vec![Inventory::Block(headers[2].block_hash())])));
+
+    // Too many headers to announce: only the tip is announced.
+    let (tx, _) = chan::bounded(1);
+    alice.command(Command::ImportHeaders(headers[3..12].to_vec(), tx));
+
+    assert!(alice
+        .messages(&bob.addr)
+        .any(|m| m == NetworkMessage::Headers(vec![headers[11]])));
+
+    // Bob then asks for the headers he's missing.
+    alice.received(
+        bob.addr,
+        NetworkMessage::GetHeaders(GetHeadersMessage {
+            version: PROTOCOL_VERSION,
+            locator_hashes: vec![headers[2].block_hash()],
+            stop_hash: BlockHash::default(),
+        }),
+    );
+    assert!(alice
+        .messages(&bob.addr)
+        .any(|m| m == NetworkMessage::Headers(headers[3..12].to_vec())));
+
+    // Nothing is announced to peers who already have our tip.
+    let (tx, _) = chan::bounded(1);
+    alice.command(Command::ImportHeaders(headers[..12].to_vec(), tx));
+    assert_eq!(alice.messages(&bob.addr).count(), 0);
+}
+
+#[test]
+fn test_addr_relay() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let bob: PeerId = ([88, 88, 88, 88], 8333).into();
+    let carol: PeerId = ([99, 99, 99, 99], 8333).into();
+    let dave: PeerId = ([77, 77, 77, 77], 8333).into();
+    let time = alice.local_time().block_time();
+
+    alice.initialize();
+    alice.protocol.addrmgr.insert(
+        (1..=8).map(|i| {
+            let addr: PeerId = ([14, 45, 16, i], 8333).into();
+            (time, Address::new(&addr, ServiceFlags::NETWORK))
+        }),
+        Source::Dns,
+    );
+    alice.connect_addr(&bob, Link::Outbound);
+    alice.connect_addr(&carol, Link::Inbound);
+    alice.connect_addr(&dave, Link::Inbound);
+    alice.drain();
+
+    // Only inbound peers get a response to `getaddr`, and only once.
+    alice.received(carol, NetworkMessage::GetAddr);
+    let addrs = alice
+        .messages(&carol)
+        .find_map(|m| match m {
+            NetworkMessage::Addr(addrs) => Some(addrs),
+            _ => None,
+        })
+        .expect("Alice responds to `getaddr`");
+    assert!(!addrs.is_empty() && addrs.len() < 8);
+
+    alice.received(carol, NetworkMessage::GetAddr);
+    alice.received(bob, NetworkMessage::GetAddr);
+    for peer in [carol, bob] {
+        assert!(!alice
+            .messages(&peer)
+            .any(|m| matches!(m, NetworkMessage::Addr(_))));
+    }
+
+    // Fresh addresses are relayed to other peers.
+    let toto: PeerId = ([14, 45, 16, 57], 8333).into();
+    let announcement = vec![(time, Address::new(&toto, ServiceFlags::NETWORK))];
+
+    alice.received(bob, NetworkMessage::Addr(announcement.clone()));
+    for peer in [carol, dave] {
+        assert!(alice
+            .messages(&peer)
+            .any(|m| m == NetworkMessage::Addr(announcement.clone())));
+    }
+
+    // Peers that send too many addresses are rate-limited.
+    alice.drain();
+    let titi: PeerId = ([14, 45, 16, 58], 8333).into();
+    alice.received(
+        bob,
+        NetworkMessage::Addr(vec![(time, Address::new(&titi, ServiceFlags::NETWORK))]),
+    );
+    assert!(!alice
+        .messages(&carol)
+        .any(|m| matches!(m, NetworkMessage::Addr(_))));
+    assert!(!alice
+        .events()
+        .any(|e| matches!(e, Event::Address(addrmgr::Event::AddressDiscovered(..)))));
+}
+
+#[test]
+fn test_addrv2_relay() {
+    use nakamoto_common::bitcoin::network::address::{AddrV2, AddrV2Message};
+    use nakamoto_common::p2p::Domain;
+
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let cfg = Config {
+        domains: vec![Domain::IPV4, Domain::CJDNS],
+        ..Config::default()
+    };
+    let mut alice = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+    let bob = PeerDummy::new([88, 88, 88, 88], network, 144, ServiceFlags::NETWORK);
+    let carol: PeerId = ([99, 99, 99, 99], 8333).into();
+    let dave: PeerId = ([77, 77, 77, 77], 8333).into();
+    let local = alice.addr;
+    let time = alice.local_time().block_time();
+    let announcement = |addr| {
+        vec![AddrV2Message {
+            time,
+            services: ServiceFlags::NETWORK,
+            addr,
+            port: 8333,
+        }]
+    };
+
+    // Bob negotiates `addrv2`, Carol doesn't.
+    alice.initialize();
+    alice.connected(bob.addr, &local, Link::Inbound);
+    alice.received(bob.addr, NetworkMessage::Version(bob.version(local, 0)));
+    alice.received(bob.addr, NetworkMessage::SendAddrV2);
+    alice.received(bob.addr, NetworkMessage::Verack);
+    alice.connect_addr(&carol, Link::Inbound);
+    alice.connect_addr(&dave, Link::Outbound);
+    alice.drain();
+
+    // CJDNS addresses are only relayed with `addrv2`.
+    let cjdns = AddrV2::Cjdns("fc32:17ea:e415:c3bf:9808:149d:b5a2:c9aa".parse().unwrap());
+    alice.received(dave, NetworkMessage::AddrV2(announcement(cjdns.clone())));
+
+    assert!(alice
+        .messages(&bob.addr)
+        .any(|m| m == NetworkMessage::AddrV2(announcement(cjdns.clone()))));
+    assert!(!alice
+        .messages(&carol)
+        .any(|m| matches!(m, NetworkMessage::Addr(_) | NetworkMessage::AddrV2(_))));
+
+    // Other addresses are relayed to both, in the format each peer understands.
+    let ipv4 = AddrV2::Ipv4([14, 45, 16, 57].into());
+    let toto: PeerId = ([14, 45, 16, 57], 8333).into();
+
+    alice.elapse(LocalDuration::from_mins(1));
+    alice.received(dave, NetworkMessage::AddrV2(announcement(ipv4.clone())));
+
+    assert!(alice
+        .messages(&bob.addr)
+        .any(|m| m == NetworkMessage::AddrV2(announcement(ipv4.clone()))));
+    assert!(alice.messages(&carol).any(
+        |m| m == NetworkMessage::Addr(vec![(time, Address::new(&toto, ServiceFlags::NETWORK))])
+    ));
+}
+
+#[test]
+fn test_orphan_headers() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let headers = &BITCOIN_HEADERS.tail;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network.clone(), vec![], rng);
+    let bob = PeerDummy::new([88, 88, 88, 88], network.clone(), 0, ServiceFlags::NETWORK);
+    let carol = PeerDummy::new([99, 99, 99, 99], network, 0, ServiceFlags::NETWORK);
+
+    // Make sure the headers we import aren't in the future.
+    alice.initialize();
+    alice.elapse(LocalDuration::from_mins(60 * 24 * 7));
+    alice.connect(&bob, Link::Inbound);
+    alice.connect(&carol, Link::Inbound);
+
+    let (tx, _) = chan::bounded(1);
+    alice.command(Command::ImportHeaders(headers[..5].to_vec(), tx));
+    alice.drain();
+
+    // Carol announces a block before Bob announces its parent.
+    alice.received(carol.addr, NetworkMessage::Headers(vec![headers[6]]));
+    assert!(!alice
+        .messages(&carol.addr)
+        .any(|m| matches!(m, NetworkMessage::GetHeaders(_))));
+
+    // When the parent arrives, the orphan is connected.
+    alice.received(bob.addr, NetworkMessage::Headers(vec![headers[5]]));
+    assert_eq!(alice.protocol.tree.height(), 7);
+    assert_eq!(alice.protocol.tree.tip().0, headers[6].block_hash());
+
+    // If the parent doesn't arrive in time, the missing headers are requested.
+    alice.received(carol.addr, NetworkMessage::Headers(vec![headers[8]]));
+    alice.elapse(syncmgr::ORPHAN_TIMEOUT);
+
+    assert!(alice.messages(&carol.addr).any(|m| matches!(
+        m,
+        NetworkMessage::GetHeaders(GetHeadersMessage { stop_hash, .. })
+            if stop_hash == headers[8].block_hash()
+    )));
+}
+
+#[test]
+fn test_query_peers() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network.clone(), vec![], rng);
+    let bob = PeerDummy::new(
+        [241, 19, 44, 19],
+        network,
+        144,
+        cbfmgr::REQUIRED_SERVICES | syncmgr::REQUIRED_SERVICES,
+    );
+    let query = |alice: &mut Peer<Protocol>| {
+        let (transmit, receive) = chan::bounded(1);
+        alice.command(Command::QueryPeers(transmit));
+        receive.recv().unwrap()
+    };
+
+    alice.connect(&bob, Link::Outbound);
+
+    let peers = query(&mut alice);
+    assert_eq!(peers.len(), 1);
+
+    let info = &peers[0];
+    assert_eq!(info.addr, bob.addr);
+    assert_eq!(info.link, Link::Outbound);
+    assert_eq!(info.services, bob.services);
+    assert_eq!(info.user_agent, USER_AGENT);
+    assert_eq!(info.last_recv, Some(alice.local_time()));
+    assert_eq!(info.latency, None);
+    assert_eq!(info.banscore, 0);
+    // Bob is ahead of us, so we're waiting for headers from him.
+    assert_eq!(info.inflight, 1);
+
+    // Bytes are only sent once the reactor writes them out.
+    alice.elapse(pingmgr::PING_INTERVAL);
+    alice.protocol.write(&bob.addr, io::sink()).unwrap();
+    assert_eq!(query(&mut alice)[0].last_send, Some(alice.local_time()));
+
+    alice
+        .protocol
+        .disconnected(&bob.addr, DisconnectReason::Command);
+    assert!(query(&mut alice).is_empty());
+}
+
+#[test]
+fn test_dump_state() {
+    use microserde::json::{Number, Object, Value};
+    use nakamoto_common::bitcoin::Script;
+    use nakamoto_common::bitcoin_hashes::hex::ToHex;
+
+    fn get<'a>(obj: &'a Object, key: &str) -> &'a Value {
+        obj.get(key)
+            .unwrap_or_else(|| panic!("missing key {:?}", key))
+    }
+    fn object(v: &Value) -> &Object {
+        match v {
+            Value::Object(obj) => obj,
+            _ => panic!("expected an object"),
+        }
+    }
+    fn array(v: &Value) -> &[Value] {
+        match v {
+            Value::Array(a) => a,
+            _ => panic!("expected an array"),
+        }
+    }
+
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network.clone(), vec![], rng);
+    let bob = PeerDummy::new(
+        [241, 19, 44, 19],
+        network,
+        144,
+        cbfmgr::REQUIRED_SERVICES | syncmgr::REQUIRED_SERVICES,
+    );
+    let script = Script::from(vec![0xde, 0xad, 0xbe, 0xef]);
+
+    alice.connect(&bob, Link::Outbound);
+    alice.command(Command::Watch {
+        watch: vec![script.clone()],
+    });
+
+    let (transmit, receive) = chan::bounded(1);
+    alice.command(Command::DumpState(transmit));
+
+    let json = receive.recv().unwrap();
+    let state: Value = microserde::json::from_str(&json).unwrap();
+    let state = object(&state);
+
+    // Peer addresses and watched scripts are redacted.
+    assert!(!json.contains(&bob.addr.ip().to_string()));
+    assert!(!json.contains(&script.to_hex()));
+
+    let peer = object(&array(get(object(get(state, "peers")), "peers"))[0]);
+    assert_matches!(get(peer, "addr"), Value::String(s) if s == "peer-0");
+    assert_matches!(get(peer, "state"), Value::String(s) if s == "negotiated");
+
+    // The same pseudonym is used across sub-protocols.
+    let request = object(&array(get(object(get(state, "sync")), "inflight"))[0]);
+    assert_matches!(get(request, "addr"), Value::String(s) if s == "peer-0");
+
+    let rescan = object(get(object(get(state, "filters")), "rescan"));
+    assert_matches!(get(rescan, "watch"), Value::Number(Number::U64(1)));
+
+    let messages = object(get(state, "messages"));
+    let version = object(get(object(get(messages, "inbound")), "version"));
+    assert_matches!(get(version, "count"), Value::Number(Number::U64(1)));
+    assert!(object(get(messages, "outbound")).contains_key("verack"));
+}
+
+#[test]
+fn test_feature_negotiation() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let cfg = Config {
+        network: network.clone(),
+        features: Feature::ALL.into_iter().collect(),
+        ..Config::default()
+    };
+    let mut alice = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+    let bob = PeerDummy::new(
+        [88, 88, 88, 88],
+        network.clone(),
+        144,
+        ServiceFlags::NETWORK,
+    );
+    let mut carol = PeerDummy::new([99, 99, 99, 99], network, 144, ServiceFlags::NETWORK);
+    let local = alice.addr;
+    let sendcmpct = Feature::CompactBlocks.message();
+    let query = |alice: &mut Peer<Protocol>, addr: &PeerId| {
+        let (transmit, receive) = chan::bounded(1);
+        alice.command(Command::QueryPeers(transmit));
+        receive
+            .recv()
+            .unwrap()
+            .into_iter()
+            .find(|p| &p.addr == addr)
+            .unwrap()
+            .features
+    };
+    carol.protocol_version = 70015;
+
+    alice.initialize();
+    alice.connected(bob.addr, &local, Link::Inbound);
+    alice.received(bob.addr, NetworkMessage::Version(bob.version(local, 0)));
+
+    // Handshake features are signalled before `verack`, other features after.
+    let msgs = alice.messages(&bob.addr).collect::<Vec<_>>();
+    assert!(msgs.contains(&NetworkMessage::WtxidRelay));
+    assert!(msgs.contains(&NetworkMessage::SendAddrV2));
+    assert!(!msgs.contains(&sendcmpct));
+
+    alice.received(bob.addr, NetworkMessage::WtxidRelay);
+    alice.received(bob.addr, NetworkMessage::Verack);
+    assert!(alice.messages(&bob.addr).any(|m| m == sendcmpct));
+    assert_eq!(query(&mut alice, &bob.addr), Feature::WtxidRelay.into());
+
+    alice.received(bob.addr, sendcmpct.clone());
+    assert_eq!(
+        query(&mut alice, &bob.addr),
+        [Feature::WtxidRelay, Feature::CompactBlocks]
+            .into_iter()
+            .collect::<Features>()
+    );
+
+    // Features aren't signalled to peers with protocol versions that don't support them,
+    // and aren't used with these peers either.
+    alice.connected(carol.addr, &local, Link::Inbound);
+    alice.received(carol.addr, NetworkMessage::Version(carol.version(local, 0)));
+    alice.received(carol.addr, NetworkMessage::WtxidRelay);
+    alice.received(carol.addr, NetworkMessage::Verack);
+
+    let msgs = alice.messages(&carol.addr).collect::<Vec<_>>();
+    assert!(!msgs.contains(&NetworkMessage::WtxidRelay));
+    assert!(!msgs.contains(&NetworkMessage::SendAddrV2));
+    assert!(msgs.contains(&sendcmpct));
+    assert_eq!(query(&mut alice, &carol.addr), Features::NONE);
+
+    // Handshake features can't be signalled after `verack`.
+    alice.received(carol.addr, NetworkMessage::SendAddrV2);
+    assert!(alice.outputs().any(|o| matches!(
+        o,
+        Io::Disconnect(a, DisconnectReason::PeerMisbehaving(_)) if a == carol.addr
+    )));
+}
+
+#[test]
+fn test_message_flood() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let cfg = Config {
+        rate_limits: ratelimit::Config {
+            addr: ratelimit::Limit::new(1., 2.),
+            max_throttled: 2,
+            max_banscore: 2,
+            ..ratelimit::Config::default()
+        },
+        ..Config::default()
+    };
+    let mut alice = Peer::config([48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+    let bob = PeerDummy::new(
+        [241, 19, 44, 19],
+        network,
+        144,
+        cbfmgr::REQUIRED_SERVICES | syncmgr::REQUIRED_SERVICES,
+    );
+    let disconnected = |alice: &mut Peer<Protocol>| {
+        alice
+            .outputs()
+            .any(|o| matches!(o, Io::Disconnect(addr, _) if addr == bob.addr))
+    };
+    let query = |alice: &mut Peer<Protocol>| {
+        let (transmit, receive) = chan::bounded(1);
+        alice.command(Command::QueryPeers(transmit));
+        receive.recv().unwrap().remove(0)
+    };
+
+    alice.initialize();
+    alice.connect(&bob, Link::Outbound);
+
+    // Messages within the limit, followed by throttled messages.
+    for _ in 0..4 {
+        alice.received(bob.addr, NetworkMessage::Addr(vec![]));
+    }
+    assert!(!disconnected(&mut alice));
+    assert_eq!(query(&mut alice).banscore, 0);
+
+    // Bob keeps flooding us: his ban score increases, until he is disconnected.
+    alice.received(bob.addr, NetworkMessage::Addr(vec![]));
+    assert!(!disconnected(&mut alice));
+    assert_eq!(query(&mut alice).banscore, 1);
+
+    alice.received(bob.addr, NetworkMessage::Addr(vec![]));
+    assert!(disconnected(&mut alice));
+}
+
+#[test]
+fn test_solicited_messages_not_rate_limited() {
+    let height = 16;
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+    let genesis = network.genesis_block();
+    let chain = gen::blockchain(genesis, height, &mut rng);
+    let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+    let cfheaders =
+        gen::cfheaders_from_blocks(FilterHeader::genesis(network.clone()), chain.tail.iter());
+    let cfilters = gen::cfilters(chain.iter()).collect::<Vec<_>>();
+    let cfg = Config {
+        services: syncmgr::REQUIRED_SERVICES | cbfmgr::REQUIRED_SERVICES,
+        rate_limits: ratelimit::Config {
+            cfilters: ratelimit::Limit::new(0., 2.),
+            max_throttled: 2,
+            max_banscore: 2,
+            ..ratelimit::Config::default()
+        },
+        ..Config::from("alice", network, vec![])
+    };
+    let mut alice = Peer::config(
+        [48, 48, 48, 48],
+        headers.tail,
+        cfheaders,
+        vec![],
+        cfg,
+        rng.clone(),
+    );
+    let cfilter = |i: usize| {
+        NetworkMessage::CFilter(CFilter {
+            filter_type: 0x0,
+            block_hash: chain[i].block_hash(),
+            filter: cfilters[i].content.clone(),
+        })
+    };
+
+    alice.tick(LocalTime::from_block_time(chain.last().header.time));
+    alice.connect(
+        &PeerDummy {
+            addr: remote,
+            height,
+            protocol_version: PROTOCOL_VERSION,
+            services: cbfmgr::REQUIRED_SERVICES | syncmgr::REQUIRED_SERVICES,
+            relay: true,
+            time: alice.local_time(),
+        },
+        Link::Outbound,
+    );
+    alice.command(Command::Rescan {
+        id: 0,
+        from: Bound::Included(1),
+        to: Bound::Included(height),
+        watch: vec![gen::script(&mut rng)],
+    });
+    alice
+        .messages(&remote)
+        .find(|m| matches!(m, NetworkMessage::GetCFilters(_)))
+        .expect("Alice asks for the cfilters");
+
+    // All the requested filters are processed, even though there are more of them than
+    // the rate limit allows.
+    for i in 1..=height as usize {
+        alice.received(remote, cfilter(i));
+    }
+    let processed = alice
+        .events()
+        .filter(|e| matches!(e, Event::Filter(cbfmgr::Event::FilterProcessed { .. })))
+        .count();
+    assert_eq!(processed, height as usize);
+    assert_eq!(alice.protocol.limiter.banscore(&remote), 0);
+
+    // Filters we didn't ask for are limited.
+    for _ in 0..4 {
+        alice.received(remote, cfilter(1));
+    }
+    assert!(!alice
+        .outputs()
+        .any(|o| matches!(o, Io::Disconnect(addr, _) if addr == remote)));
+    assert_eq!(alice.protocol.limiter.banscore(&remote), 0);
+
+    alice.received(remote, cfilter(1));
+    assert_eq!(alice.protocol.limiter.banscore(&remote), 1);
+}
+
+#[test]
+fn test_ban() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::config(
+        [48, 48, 48, 48],
+        vec![],
+        vec![],
+        vec![],
+        Config::default(),
+        rng,
+    );
+    let bob = PeerDummy::new(
+        [241, 19, 44, 19],
+        network,
+        144,
+        cbfmgr::REQUIRED_SERVICES | syncmgr::REQUIRED_SERVICES,
+    );
+    let local = alice.addr;
+    let ip = bob.addr.ip();
+    let bans = |alice: &mut Peer<Protocol>| {
+        let (transmit, receive) = chan::bounded(1);
+        alice.command(Command::ListBans(transmit));
+        receive.recv().unwrap()
+    };
+
+    alice.connect(&bob, Link::Outbound);
+    alice.drain();
+
+    // Banning a connected peer disconnects it.
+    alice.command(Command::Ban(ip, Some(LocalDuration::from_mins(60))));
+    let outputs = alice.outputs().collect::<Vec<_>>();
+    assert!(outputs
+        .iter()
+        .any(|o| matches!(o, Io::Disconnect(a, DisconnectReason::PeerBanned) if *a == bob.addr)));
+    assert!(outputs.iter().any(|o| matches!(
+        o,
+        Io::Event(Event::Peer(peermgr::Event::Banned(peermgr::Ban { addr, until: Some(_) })))
+        if *addr == ip
+    )));
+    alice.disconnected(&bob.addr, DisconnectReason::PeerBanned);
+    assert_eq!(bans(&mut alice).len(), 1);
+
+    // We don't connect to banned peers..
+    assert!(!alice.protocol.peermgr.connect(&bob.addr));
+    // ..nor accept connections from them, on any port.
+    let inbound = net::SocketAddr::new(ip, 48112);
+    alice.connected(inbound, &local, Link::Inbound);
+    assert!(alice
+        .outputs()
+        .any(|o| matches!(o, Io::Disconnect(a, DisconnectReason::PeerBanned) if a == inbound)));
+    alice.disconnected(&inbound, DisconnectReason::PeerBanned);
+
+    // Bans expire.
+    alice.elapse(LocalDuration::from_mins(60));
+    assert!(alice
+        .events()
+        .any(|e| matches!(e, Event::Peer(peermgr::Event::Unbanned(addr)) if addr == ip)));
+    assert!(bans(&mut alice).is_empty());
+    assert!(alice.protocol.peermgr.connect(&bob.addr));
+
+    // Permanent bans can be lifted.
+    let eve = net::IpAddr::from([99, 99, 99, 99]);
+    alice.command(Command::Ban(eve, None));
+    assert_eq!(
+        bans(&mut alice),
+        vec![peermgr::Ban {
+            addr: eve,
+            until: None
+        }]
+    );
+    alice.elapse(LocalDuration::from_mins(60 * 24 * 365));
+    assert_eq!(bans(&mut alice).len(), 1);
+
+    alice.command(Command::Unban(eve));
+    assert!(bans(&mut alice).is_empty());
+}
+
+#[test]
+fn test_get_chain_work() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail.to_vec();
+    let height = headers.len() as Height;
+    let cfg = Config {
+        network: network.clone(),
+        ..Config::default()
+    };
+    let mut alice = Peer::config([48, 48, 48, 48], headers.clone(), vec![], vec![], cfg, rng);
+    let bob = PeerDummy::new(
+        [241, 19, 44, 19],
+        network,
+        height + 100,
+        cbfmgr::REQUIRED_SERVICES | syncmgr::REQUIRED_SERVICES,
+    );
+    let query = |alice: &mut Peer<Protocol>| {
+        let (transmit, receive) = chan::bounded(1);
+        alice.command(Command::GetChainWork(transmit));
+        receive.recv().unwrap()
+    };
+
+    let work = query(&mut alice);
+    assert_eq!(work.height, height);
+    assert_eq!(work.tip, headers.last().unwrap().block_hash());
+    assert_eq!(
+        work.work,
+        BITCOIN_HEADERS
+            .iter()
+            .fold(Work::default(), |w, h| w + h.work())
+    );
+    assert!(work.forks.is_empty());
+    assert_eq!(work.heavier_forks().count(), 0);
+    assert_eq!(work.peer_height, None);
+    assert_eq!(work.blocks_behind(), 0);
+
+    alice.connect(&bob, Link::Outbound);
+
+    let work = query(&mut alice);
+    assert_eq!(work.peer_height, Some(height + 100));
+    assert_eq!(work.blocks_behind(), 100);
+}
+
+#[test]
+fn test_replay() {
+    use super::capture::{Direction, Record};
+    use super::pingmgr::PING_TIMEOUT;
+    use super::replay::Replay;
+
+    let network = Network::Mainnet;
+    let rng = fastrand::Rng::with_seed(1);
+    let alice = Peer::genesis(
+        "alice",
+        [48, 48, 48, 48],
+        network.clone(),
+        vec![],
+        rng.clone(),
+    );
+    let local = alice.addr;
+    let remote = PeerDummy::new(
+        [131, 31, 11, 33],
+        network.clone(),
+        144,
+        syncmgr::REQUIRED_SERVICES | cbfmgr::REQUIRED_SERVICES,
+    );
+    let builder = message::Builder::new(network);
+    let record = |time: LocalTime, payload: NetworkMessage| {
+        let mut message = Vec::new();
+        builder.write(payload, &mut message).unwrap();
+
+        Record {
+            time,
+            peer: remote.addr,
+            direction: Direction::Inbound,
+            message,
+        }
+    };
+    let t0 = remote.time;
+    let mut replay = Replay::new(alice.protocol, local);
+
+    replay.run([
+        record(
+            t0,
+            NetworkMessage::Version(remote.version(local, rng.u64(..))),
+        ),
+        record(t0 + LocalDuration::from_secs(1), NetworkMessage::Verack),
+    ]);
+
+    let sent = replay
+        .sent()
+        .filter(|(addr, _)| *addr == remote.addr)
+        .map(|(_, msg)| msg.cmd())
+        .collect::<Vec<_>>();
+
+    assert_eq!(sent[0], "version");
+    assert!(sent.contains(&"verack"));
+    assert!(replay.events().any(|e| matches!(
+        e,
+        Event::Peer(peermgr::Event::Negotiated { addr, .. }) if addr == &remote.addr
+    )));
+
+    // A ping is sent once the peer is negotiated. Answering it keeps the peer connected.
+    let nonce = replay
+        .sent()
+        .find_map(|(_, msg)| match msg {
+            NetworkMessage::Ping(nonce) => Some(*nonce),
+            _ => None,
+        })
+        .expect("a ping is sent");
+    let t1 = t0 + PING_TIMEOUT * 2;
+
+    replay.step(&record(
+        t0 + LocalDuration::from_secs(2),
+        NetworkMessage::Pong(nonce),
+    ));
+    replay.elapse(t1);
+    assert_eq!(replay.disconnects().count(), 0);
+
+    // Messages with the wrong magic get the peer disconnected, and subsequent messages
+    // from the peer are skipped.
+    let mut message = Vec::new();
+    message::Builder::new(Network::Testnet)
+        .write(NetworkMessage::Verack, &mut message)
+        .unwrap();
+
+    replay.step(&Record {
+        message,
+        ..record(t1, NetworkMessage::Verack)
+    });
+    replay.step(&record(t1, NetworkMessage::Verack));
+
+    assert_matches!(
+        replay.disconnects().collect::<Vec<_>>()[..],
+        [(addr, DisconnectReason::PeerMagic(_))] if addr == &remote.addr
+    );
+}
+
+#[test]
+fn test_reindex() {
+    let height = 16;
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let genesis = network.genesis_block();
+    let chain = gen::blockchain(genesis.clone(), height, &mut rng);
+    let mut headers = chain.iter().skip(1).map(|b| b.header).collect::<Vec<_>>();
+    let mut cfheaders = gen::cfheaders_from_blocks(FilterHeader::default(), chain.iter())
+        .into_iter()
+        .skip(1) // Skip genesis
+        .collect::<Vec<_>>();
+    let time = LocalTime::from_block_time(chain.last().header.time);
+
+    // Corrupt the filter header at height 10, and the block header at the tip.
+    cfheaders[9].1 = FilterHeader::default();
+    headers.last_mut().unwrap().time = genesis.header.time;
+
+    let mut alice = Peer::new(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        headers,
+        cfheaders,
+        vec![],
+        rng.clone(),
+    );
+    alice.tick(time);
+    alice.initialize();
+    alice.drain();
+    alice.command(Command::Reindex(0));
+
+    let events = alice
+        .events()
+        .filter_map(|e| match e {
+            Event::Reindex(e) => Some(e),
+            Event::Chain(ChainEvent::BlockDisconnected { height, .. }) => {
+                assert_eq!(height, 16);
+                None
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    assert_matches!(
+        &events[..],
+        [
+            ReindexEvent::Started { from: 0, to: 16 },
+            ReindexEvent::InvalidFilterHeader { height: 10 },
+            ReindexEvent::InvalidHeader { height: 16, .. },
+            ReindexEvent::Finished {
+                height: 15,
+                filter_height: 9,
+                repaired: 0,
+            },
+        ]
+    );
+    assert_eq!(alice.protocol.tree.height(), 15);
+    assert_eq!(alice.protocol.cbfmgr.filters.height(), 9);
+
+    // Reindexing a valid chain leaves it unchanged.
+    alice.command(Command::Reindex(4));
+    assert!(alice.events().any(|e| matches!(
+        e,
+        Event::Reindex(ReindexEvent::Finished {
+            height: 15,
+            filter_height: 9,
+            repaired: 0,
+        })
+    )));
+}
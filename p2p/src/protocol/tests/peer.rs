@@ -18,6 +18,8 @@ use nakamoto_common::p2p::peer::KnownAddress;
 
 use nakamoto_test::block::cache::model;
 
+use crate::protocol::scheduler::Schedule;
+
 pub struct PeerDummy {
     pub addr: PeerId,
     pub height: Height,
@@ -98,7 +100,7 @@ impl Peer<Protocol> {
         rng: fastrand::Rng,
     ) -> Self {
         let cfg = Config {
-            network,
+            network: network.clone(),
             params: Params::new(network.into()),
             target: name,
             // We don't actually have the required services, but we pretend to
@@ -127,13 +129,16 @@ impl Peer<Protocol> {
         cfg: Config,
         rng: fastrand::Rng,
     ) -> Self {
-        let network = cfg.network;
+        let network = cfg.network.clone();
         let genesis = network.genesis();
         let time = LocalTime::from_secs(genesis.time as u64);
         let clock = RefClock::from(AdjustedTime::new(time));
         let headers = NonEmpty::from((network.genesis(), headers));
         let cfheaders = NonEmpty::from((
-            (FilterHash::genesis(network), FilterHeader::genesis(network)),
+            (
+                FilterHash::genesis(network.clone()),
+                FilterHeader::genesis(network.clone()),
+            ),
             cfheaders,
         ));
         let peers = peers
@@ -222,7 +227,7 @@ impl Peer<Protocol> {
     }
 
     pub fn received(&mut self, remote: net::SocketAddr, payload: NetworkMessage) {
-        let msg = message::Builder::new(self.protocol.network);
+        let msg = message::Builder::new(self.protocol.network.clone());
 
         let mut buf = Vec::new();
         msg.write(payload, &mut buf).unwrap();
@@ -276,9 +281,16 @@ impl Peer<Protocol> {
             .find(|o| {
                 matches!(
                     o,
-                    Io::Event(
-                        Event::Peer(peermgr::Event::Negotiated { addr, services, .. })
-                    ) if addr == &remote.addr && services.has(ServiceFlags::NETWORK)
+                    Io::Event(Event::Peer(peermgr::Event::Negotiated {
+                        addr,
+                        services,
+                        relay,
+                        advertised_addr,
+                        ..
+                    })) if addr == &remote.addr
+                        && services.has(ServiceFlags::NETWORK)
+                        && relay == &remote.relay
+                        && advertised_addr == &Some(remote.addr)
                 )
             })
             .expect("peer handshake is successful");
@@ -339,10 +351,15 @@ pub fn network(network: Network, size: usize, rng: fastrand::Rng) -> Vec<Peer<Pr
         .map(|(i, (addr, _, _))| {
             let peers = address_books.get(addr).unwrap_or(&Vec::new()).clone();
             let cfg = Config {
-                network,
+                network: network.clone(),
                 target: names[i],
                 // These nodes don't need to try connecting to other nodes.
                 target_outbound_peers: 0,
+                target_block_relay_peers: 0,
+                schedule: Schedule {
+                    feeler: None,
+                    ..Schedule::default()
+                },
                 // These are full nodes.
                 services: syncmgr::REQUIRED_SERVICES | cbfmgr::REQUIRED_SERVICES,
                 ..Config::default()
@@ -20,13 +20,15 @@ pub fn connect_to_peers(
     let time = LocalTime::from_block_time(headers.last().unwrap().time);
 
     // Alice will try to connect to enough outbound peers.
-    let mut peers = peer::network(network, target * 2, rng.clone());
+    let mut peers = peer::network(network.clone(), target * 2, rng.clone());
     let addrs = peers
         .iter()
         .map(|p| (p.addr, Source::Dns, p.cfg.services))
         .collect::<Vec<_>>();
     let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, addrs, rng.clone());
     alice.protocol.peermgr.config.target_outbound_peers = target;
+    alice.protocol.peermgr.config.target_block_relay_peers = 0;
+    alice.protocol.peermgr.config.feeler = None;
 
     let mut simulator = Simulation::new(time, rng, options);
 
@@ -61,3 +63,87 @@ pub fn connect_to_peers(
     }
     true
 }
+
+/// Test that we sync the header and filter header chains from an honest peer, while
+/// connected to an adversary. The adversary is connected to first, so that it gets a chance
+/// to misbehave before the honest peer can help us along.
+///
+/// Returns the victim node, the adversary's address, and the simulation, once synced.
+pub fn sync_with_adversary(
+    adversary: Adversary,
+    seed: u64,
+) -> (Peer<Protocol>, PeerId, Simulation) {
+    logger::init(log::Level::Debug);
+
+    let rng = fastrand::Rng::with_seed(seed);
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail.to_vec();
+    let height = headers.len() as Height;
+    let cfheaders = gen::cfheaders(FilterHeader::genesis(network.clone()), &mut rng.clone())
+        .take(headers.len())
+        .collect::<Vec<_>>();
+    let time = LocalTime::from_block_time(headers.last().unwrap().time);
+    let checkpoint = cbfmgr::CFCHECKPT_INTERVAL;
+
+    assert!(height >= checkpoint);
+
+    let mut alice = Peer::config(
+        [48, 48, 48, 48],
+        vec![],
+        vec![],
+        vec![],
+        Config {
+            filter_checkpoints: [(checkpoint, cfheaders[checkpoint as usize - 1].1)]
+                .into_iter()
+                .collect(),
+            ..Config::from("alice", network.clone(), vec![])
+        },
+        rng.clone(),
+    );
+    let [mut honest, mut evil] =
+        [("honest", [97, 97, 97, 97]), ("evil", [66, 66, 66, 66])].map(|(name, ip)| {
+            let cfg = Config {
+                serve_filters: true,
+                services: syncmgr::REQUIRED_SERVICES | cbfmgr::REQUIRED_SERVICES,
+                ..Config::from(name, network.clone(), vec![])
+            };
+            Peer::config(
+                ip,
+                headers.clone(),
+                cfheaders.clone(),
+                vec![],
+                cfg,
+                rng.clone(),
+            )
+        });
+    let mut simulator = Simulation::new(time, rng, Options::default());
+
+    simulator.adversary(evil.addr.ip(), adversary);
+    simulator.initialize([&mut alice, &mut honest, &mut evil]);
+
+    alice.command(Command::Connect(evil.addr, false));
+    while simulator.step([&mut alice, &mut honest, &mut evil]) {
+        if alice.protocol.peermgr.negotiated(Link::Outbound).count() > 0
+            || simulator.disconnects(alice.addr.ip()).count() > 0
+        {
+            break;
+        }
+    }
+    alice.command(Command::Connect(honest.addr, false));
+
+    while simulator.step([&mut alice, &mut honest, &mut evil]) {
+        if alice.protocol.tree.height() == height
+            && alice.protocol.cbfmgr.filters.height() == height
+        {
+            break;
+        }
+        assert!(
+            simulator.elapsed() < LocalDuration::from_mins(30),
+            "alice should sync in the presence of {:?} adversary",
+            adversary
+        );
+    }
+    let addr = evil.addr;
+
+    (alice, addr, simulator)
+}
@@ -2,8 +2,12 @@
 #![allow(clippy::collapsible_if)]
 use super::*;
 
+use nakamoto_common::bitcoin_hashes::Hash as _;
+use nakamoto_common::block::filter::FilterHash;
 use nakamoto_common::collections::{HashMap, HashSet};
 
+use crate::stream;
+
 use std::collections::BTreeMap;
 use std::collections::VecDeque;
 use std::fmt;
@@ -122,6 +126,89 @@ impl Inbox {
     }
 }
 
+/// Number of `addr` messages sent by a flooding adversary, per message it would
+/// otherwise send. Enough to exhaust the rate limit of a peer in one go.
+pub const ADDR_FLOOD: usize = 128;
+
+/// Misbehavior of an adversarial node. Adversaries run the regular protocol, but their
+/// outbound messages are tampered with by the simulator before being delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Adversary {
+    /// Serves filter headers that don't commit to the actual block filters, in `cfheaders`
+    /// and `cfcheckpt` messages.
+    LyingFilters,
+    /// Withholds `headers` and `block` messages, stalling peers that request them.
+    Stalling,
+    /// Sends messages announcing a payload larger than the protocol allows, once the
+    /// handshake is done.
+    Oversized,
+    /// Follows every message with a flood of `addr` messages, once the handshake is done.
+    AddrFlood,
+}
+
+impl Adversary {
+    /// Tamper with the bytes written by an adversarial node.
+    fn tamper(&self, bytes: &[u8], network: Network) -> Vec<u8> {
+        let builder = message::Builder::new(network);
+        let mut decoder = stream::Decoder::new(bytes.len());
+        let mut tampered = Vec::new();
+
+        decoder.input(bytes);
+
+        while let Some(msg) = decoder.decode_next::<RawNetworkMessage>().unwrap() {
+            let handshake = matches!(
+                msg.payload,
+                NetworkMessage::Version(_) | NetworkMessage::Verack
+            );
+
+            match (self, msg.payload) {
+                (Self::LyingFilters, NetworkMessage::CFHeaders(mut msg)) => {
+                    for hash in msg.filter_hashes.iter_mut() {
+                        *hash = FilterHash::hash(&hash.into_inner());
+                    }
+                    builder.write(NetworkMessage::CFHeaders(msg), &mut tampered)
+                }
+                (Self::LyingFilters, NetworkMessage::CFCheckpt(mut msg)) => {
+                    for header in msg.filter_headers.iter_mut() {
+                        *header = FilterHeader::hash(&header.into_inner());
+                    }
+                    builder.write(NetworkMessage::CFCheckpt(msg), &mut tampered)
+                }
+                (Self::Stalling, NetworkMessage::Headers(_) | NetworkMessage::Block(_)) => {
+                    continue;
+                }
+                (Self::Oversized, payload) if !handshake => {
+                    let start = tampered.len();
+
+                    builder.write(payload, &mut tampered).unwrap();
+                    // Only keep the message header, with a bogus payload length.
+                    tampered.truncate(start + stream::HEADER_SIZE);
+                    tampered[start + 16..start + 20]
+                        .copy_from_slice(&(stream::MAX_PAYLOAD_SIZE as u32 + 1).to_le_bytes());
+
+                    continue;
+                }
+                (Self::AddrFlood, payload) if !handshake => {
+                    builder.write(payload, &mut tampered).unwrap();
+
+                    for i in 0..ADDR_FLOOD {
+                        let addr: net::SocketAddr = ([8, 8, (i >> 8) as u8, i as u8], PORT).into();
+                        let addr = Address::new(&addr, ServiceFlags::NETWORK);
+
+                        builder
+                            .write(NetworkMessage::Addr(vec![(0, addr)]), &mut tampered)
+                            .unwrap();
+                    }
+                    continue;
+                }
+                (_, payload) => builder.write(payload, &mut tampered),
+            }
+            .unwrap();
+        }
+        tampered
+    }
+}
+
 /// Simulation options.
 #[derive(Debug, Clone)]
 pub struct Options {
@@ -183,6 +270,10 @@ pub struct Simulation {
     connections: HashSet<(NodeId, NodeId)>,
     /// Set of connection attempts.
     attempts: HashSet<(NodeId, NodeId)>,
+    /// Adversarial nodes.
+    adversaries: HashMap<NodeId, Adversary>,
+    /// Disconnections initiated by nodes, with the remote peer and reason.
+    disconnects: Vec<(NodeId, PeerId, DisconnectReason)>,
     /// Simulation options.
     opts: Options,
     /// Start time of simulation.
@@ -205,6 +296,8 @@ impl Simulation {
             latencies: HashMap::with_hasher(rng.clone().into()),
             connections: HashSet::with_hasher(rng.clone().into()),
             attempts: HashSet::with_hasher(rng.clone().into()),
+            adversaries: HashMap::with_hasher(rng.clone().into()),
+            disconnects: Vec::new(),
             opts,
             start_time: time,
             time,
@@ -233,6 +326,22 @@ impl Simulation {
             .all(|(_, s)| matches!(s.input, Input::Tock))
     }
 
+    /// Turn a node into an adversary. Its outbound messages are tampered with from now on.
+    pub fn adversary(&mut self, node: NodeId, adversary: Adversary) {
+        self.adversaries.insert(node, adversary);
+    }
+
+    /// Get the disconnections initiated by a node, along with their reason.
+    pub fn disconnects(
+        &self,
+        node: NodeId,
+    ) -> impl Iterator<Item = (&PeerId, &DisconnectReason)> + '_ {
+        self.disconnects
+            .iter()
+            .filter(move |(n, _, _)| *n == node)
+            .map(|(_, remote, reason)| (remote, reason))
+    }
+
     /// Get the latency between two nodes. The minimum latency between nodes is 1 millisecond.
     pub fn latency(&self, from: NodeId, to: NodeId) -> LocalDuration {
         self.latencies
@@ -396,6 +505,9 @@ impl Simulation {
                 // Always drain the protocol output buffer.
                 protocol.write(&receiver, &mut msg).unwrap();
 
+                if let Some(adversary) = self.adversaries.get(&node) {
+                    msg = adversary.tamper(&msg, protocol.network.clone());
+                }
                 if msg.is_empty() {
                     return;
                 }
@@ -515,6 +627,8 @@ impl Simulation {
                 let local_addr: net::SocketAddr = (node, PORT).into();
                 let latency = self.latency(node, remote.ip());
 
+                self.disconnects.push((node, remote, reason.clone()));
+
                 // The local node is immediately disconnected.
                 self.priority.push_back(Scheduled {
                     remote,
@@ -557,6 +671,11 @@ impl Simulation {
                     );
                 }
             }
+            Io::CancelWakeups => {
+                self.inbox
+                    .messages
+                    .retain(|_, s| !(s.node == node && matches!(s.input, Input::Tock)));
+            }
             Io::Event(_) => {
                 // Ignored.
             }
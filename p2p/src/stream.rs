@@ -1,43 +1,107 @@
 //! Message stream utilities.
-use std::io;
-
 use nakamoto_common::bitcoin::consensus::{encode, Decodable};
+use nakamoto_common::bitcoin::network::message::RawNetworkMessage;
+
+/// Size of a network message header: magic, command, payload length and checksum.
+pub const HEADER_SIZE: usize = 24;
+/// Maximum size of a network message payload. Matches the limit used by Bitcoin Core.
+pub const MAX_PAYLOAD_SIZE: usize = 4_000_000;
+
+/// A message that can be decoded from a stream. Messages start with a fixed-size header,
+/// which announces the size of the payload that follows.
+pub trait Message: Decodable {
+    /// Size of the message header.
+    const HEADER_SIZE: usize;
+    /// Maximum size of the message payload.
+    const MAX_PAYLOAD_SIZE: usize;
+
+    /// Get the payload size announced in a message header, given as exactly
+    /// [`Message::HEADER_SIZE`] bytes.
+    fn payload_size(header: &[u8]) -> usize;
+}
+
+impl Message for RawNetworkMessage {
+    const HEADER_SIZE: usize = HEADER_SIZE;
+    const MAX_PAYLOAD_SIZE: usize = MAX_PAYLOAD_SIZE;
+
+    fn payload_size(header: &[u8]) -> usize {
+        // The payload length follows the magic and the command.
+        let mut len = [0; 4];
+        len.copy_from_slice(&header[16..20]);
+
+        u32::from_le_bytes(len) as usize
+    }
+}
 
 /// Message stream decoder.
 ///
-/// Used to for example turn a byte stream into network messages.
+/// Turns a byte stream into network messages. Bytes are accumulated in a reassembly
+/// buffer until a full message is available, so messages may be split across any number
+/// of inputs. The payload size announced in a message header is checked before the
+/// payload is buffered, so that peers can't make us allocate arbitrary amounts of memory.
 #[derive(Debug)]
 pub struct Decoder {
-    unparsed: Vec<u8>,
+    /// Reassembly buffer.
+    buffer: Vec<u8>,
+    /// Offset into the buffer of the first byte not yet decoded.
+    offset: usize,
 }
 
 impl Decoder {
     /// Create a new stream decoder.
     pub fn new(capacity: usize) -> Self {
         Self {
-            unparsed: Vec::with_capacity(capacity),
+            buffer: Vec::with_capacity(capacity),
+            offset: 0,
         }
     }
 
     /// Input bytes into the decoder.
     pub fn input(&mut self, bytes: &[u8]) {
-        self.unparsed.extend_from_slice(bytes);
+        // Discard decoded bytes. This is done here rather than after every decoded
+        // message, so that the buffer is shifted at most once per input.
+        self.buffer.drain(..self.offset);
+        self.offset = 0;
+        self.buffer.extend_from_slice(bytes);
     }
 
-    /// Decode and return the next message. Returns [`None`] if nothing was decoded.
-    pub fn decode_next<D: Decodable>(&mut self) -> Result<Option<D>, encode::Error> {
-        match encode::deserialize_partial::<D>(&self.unparsed) {
-            Ok((msg, index)) => {
-                // Drain deserialized bytes only.
-                self.unparsed.drain(..index);
-                Ok(Some(msg))
-            }
+    /// Decode and return the next message. Returns [`None`] if there isn't a full
+    /// message in the buffer yet.
+    pub fn decode_next<D: Message>(&mut self) -> Result<Option<D>, encode::Error> {
+        let unparsed = self.unparsed();
 
-            Err(encode::Error::Io(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
-                Ok(None)
-            }
-            Err(err) => Err(err),
+        if unparsed.len() < D::HEADER_SIZE {
+            return Ok(None);
+        }
+        let len = D::payload_size(&unparsed[..D::HEADER_SIZE]);
+
+        if len > D::MAX_PAYLOAD_SIZE {
+            return Err(encode::Error::OversizedVectorAllocation {
+                requested: len,
+                max: D::MAX_PAYLOAD_SIZE,
+            });
+        }
+        let size = D::HEADER_SIZE + len;
+
+        if unparsed.len() < size {
+            // Make room for the rest of the message.
+            self.buffer.reserve(size - unparsed.len());
+            return Ok(None);
         }
+        let msg = encode::deserialize::<D>(&unparsed[..size])?;
+        self.offset += size;
+
+        Ok(Some(msg))
+    }
+
+    /// Bytes that were decoded since the last input.
+    pub fn decoded(&self) -> &[u8] {
+        &self.buffer[..self.offset]
+    }
+
+    /// Bytes that were input but not yet decoded.
+    pub fn unparsed(&self) -> &[u8] {
+        &self.buffer[self.offset..]
     }
 }
 
@@ -64,7 +128,7 @@ mod test {
         let mut msgs = vec![];
         let mut decoder = Decoder::new(1024);
 
-        let chunk_size = 1 + chunk_size % decoder.unparsed.capacity();
+        let chunk_size = 1 + chunk_size % 1024;
 
         bytes.extend_from_slice(&MSG_VERACK);
         bytes.extend_from_slice(&MSG_PING);
@@ -77,7 +141,7 @@ mod test {
             }
         }
 
-        assert_eq!(decoder.unparsed.len(), 0);
+        assert!(decoder.unparsed().is_empty());
         assert_eq!(msgs.len(), 2);
         assert_eq!(
             msgs[0],
@@ -94,4 +158,144 @@ mod test {
             }
         );
     }
+
+    fn decode_all(decoder: &mut Decoder) -> Vec<RawNetworkMessage> {
+        let mut msgs = Vec::new();
+
+        while let Some(msg) = decoder.decode_next::<RawNetworkMessage>().unwrap() {
+            msgs.push(msg);
+        }
+        msgs
+    }
+
+    #[test]
+    fn test_decode_fragmented() {
+        let headers = RawNetworkMessage {
+            magic: 3652501241,
+            payload: NetworkMessage::Headers(nakamoto_test::BITCOIN_HEADERS.tail[..16].to_vec()),
+        };
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(&MSG_VERACK);
+        bytes.extend_from_slice(&encode::serialize(&headers));
+        bytes.extend_from_slice(&MSG_PING);
+
+        let expected = decode_all(&mut {
+            let mut decoder = Decoder::new(0);
+            decoder.input(&bytes);
+            decoder
+        });
+        assert_eq!(expected.len(), 3);
+        assert_eq!(expected[1], headers);
+
+        // Split the stream in two at every byte boundary.
+        for i in 0..=bytes.len() {
+            let mut decoder = Decoder::new(64);
+            let (a, b) = bytes.split_at(i);
+
+            decoder.input(a);
+            let mut msgs = decode_all(&mut decoder);
+            decoder.input(b);
+            msgs.extend(decode_all(&mut decoder));
+
+            assert_eq!(msgs, expected, "split at byte {}", i);
+            assert!(decoder.unparsed().is_empty());
+        }
+
+        // Feed the stream one byte at a time.
+        let mut decoder = Decoder::new(0);
+        let mut msgs = Vec::new();
+
+        for byte in &bytes {
+            decoder.input(&[*byte]);
+            msgs.extend(decode_all(&mut decoder));
+        }
+        assert_eq!(msgs, expected);
+    }
+
+    #[test]
+    fn test_decode_oversized() {
+        let mut header = MSG_VERACK;
+        header[16..20].copy_from_slice(&(MAX_PAYLOAD_SIZE as u32 + 1).to_le_bytes());
+
+        // The error is returned as soon as the header is received.
+        let mut decoder = Decoder::new(0);
+        decoder.input(&header);
+
+        assert!(matches!(
+            decoder.decode_next::<RawNetworkMessage>(),
+            Err(encode::Error::OversizedVectorAllocation { requested, .. })
+                if requested == MAX_PAYLOAD_SIZE + 1
+        ));
+        assert!(decoder.buffer.capacity() < MAX_PAYLOAD_SIZE);
+    }
+
+    #[test]
+    fn test_decode_invalid_checksum() {
+        let mut msg = MSG_PING;
+        msg[20] ^= 0xff;
+
+        let mut decoder = Decoder::new(0);
+        decoder.input(&msg);
+
+        assert!(decoder.decode_next::<RawNetworkMessage>().is_err());
+    }
+
+    /// A toy message with a one-byte length prefix and a small payload limit.
+    #[derive(Debug, PartialEq, Eq)]
+    struct Short(Vec<u8>);
+
+    impl Decodable for Short {
+        fn consensus_decode<R: std::io::Read>(mut r: R) -> Result<Self, encode::Error> {
+            let mut len = [0; 1];
+            r.read_exact(&mut len)?;
+
+            let mut payload = vec![0; len[0] as usize];
+            r.read_exact(&mut payload)?;
+
+            Ok(Self(payload))
+        }
+    }
+
+    impl Message for Short {
+        const HEADER_SIZE: usize = 1;
+        const MAX_PAYLOAD_SIZE: usize = 8;
+
+        fn payload_size(header: &[u8]) -> usize {
+            header[0] as usize
+        }
+    }
+
+    #[test]
+    fn test_decode_custom_message() {
+        let mut decoder = Decoder::new(0);
+
+        // Shorter than a network message header, but complete for this message type.
+        decoder.input(&[3, 1, 2, 3, 0, 2, 9]);
+
+        assert_eq!(
+            decoder.decode_next::<Short>().unwrap(),
+            Some(Short(vec![1, 2, 3]))
+        );
+        assert_eq!(decoder.decode_next::<Short>().unwrap(), Some(Short(vec![])));
+        assert_eq!(decoder.decode_next::<Short>().unwrap(), None);
+
+        decoder.input(&[8]);
+        assert_eq!(
+            decoder.decode_next::<Short>().unwrap(),
+            Some(Short(vec![9, 8]))
+        );
+        assert!(decoder.unparsed().is_empty());
+
+        let mut decoder = Decoder::new(0);
+        decoder.input(&[9]);
+
+        assert!(matches!(
+            decoder.decode_next::<Short>(),
+            Err(encode::Error::OversizedVectorAllocation {
+                requested: 9,
+                max: 8
+            })
+        ));
+    }
 }
@@ -1,12 +1,12 @@
 //! P2P related traits.
-use std::{io, net};
+use std::{fmt, io, net};
 
 use crossbeam_channel as chan;
-use nakamoto_common::block::time::LocalTime;
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
 
 use crate::error::Error;
 use crate::protocol::event::Publisher;
-use crate::protocol::{Command, DisconnectReason, Io, Link};
+use crate::protocol::{Command, DisconnectReason, Fingerprint, Io, Link};
 
 /// A protocol state-machine.
 ///
@@ -33,6 +33,12 @@ pub trait Protocol {
     fn attempted(&mut self, addr: &net::SocketAddr);
     /// New connection with a peer.
     fn connected(&mut self, addr: net::SocketAddr, local_addr: &net::SocketAddr, link: Link);
+    /// The transport authenticated a peer, eg. through an encrypted tunnel. Called after
+    /// [`Protocol::connected`], before any bytes are received from the peer.
+    ///
+    /// Plain connections are unauthenticated, in which case this is never called. Only
+    /// reactors with [`Reactor::AUTHENTICATES_PEERS`] set call it.
+    fn authenticated(&mut self, _addr: &net::SocketAddr, _fingerprint: Fingerprint) {}
     /// Disconnected from peer.
     fn disconnected(&mut self, addr: &net::SocketAddr, reason: DisconnectReason);
     /// An external command has been received.
@@ -52,10 +58,108 @@ pub trait Protocol {
     fn write<W: io::Write>(&mut self, addr: &net::SocketAddr, writer: W) -> io::Result<()>;
 }
 
+/// Reactor configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReactorConfig {
+    /// Maximum amount of time to wait for i/o, when no timers are pending.
+    pub wait_timeout: LocalDuration,
+    /// Socket read buffer size, in bytes.
+    pub read_buffer_size: usize,
+    /// Whether to disable Nagle's algorithm on peer sockets (`TCP_NODELAY`). Outbound
+    /// messages are already written in batches, once per reactor iteration, so delaying
+    /// small writes only adds latency.
+    pub nodelay: bool,
+    /// TCP keepalive options of peer sockets, or `None` to disable keepalive probes.
+    pub keepalive: Option<Keepalive>,
+    /// Time to wait for an outbound connection to be established, after which the
+    /// connection attempt is abandoned.
+    pub dial_timeout: LocalDuration,
+    /// Time after which a reactor iteration is considered slow. Slow iterations are logged
+    /// and reported with [`Event::SlowStep`](crate::protocol::Event::SlowStep), since they
+    /// delay the processing of all other peers.
+    pub slow_step: LocalDuration,
+    /// Address of the SAM bridge of an I2P router, to connect to I2P peers through, or
+    /// `None` to not use I2P. Requires reactor support, eg. the `i2p` feature of the
+    /// poll reactor.
+    pub i2p: Option<net::SocketAddr>,
+}
+
+/// A unit of work done by a reactor, for which the protocol is called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Bytes received from a peer were processed.
+    Received(net::SocketAddr),
+    /// The peer's output buffer was written to its socket.
+    Write(net::SocketAddr),
+    /// An inbound connection was accepted.
+    Accept(net::SocketAddr),
+    /// A command was processed.
+    Command,
+    /// The protocol was woken up by a timer.
+    Wake,
+    /// Protocol outputs were processed.
+    Process,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Received(addr) => write!(f, "{}: received bytes", addr),
+            Self::Write(addr) => write!(f, "{}: write", addr),
+            Self::Accept(addr) => write!(f, "{}: accept", addr),
+            Self::Command => write!(f, "command"),
+            Self::Wake => write!(f, "wake"),
+            Self::Process => write!(f, "process outputs"),
+        }
+    }
+}
+
+/// TCP keepalive options. Keepalive probes detect dead connections, eg. connections
+/// silently dropped by a NAT, without waiting for protocol pings to time out. A
+/// connection is considered dead after `idle + interval * count` without a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Time a connection must be idle before probes are sent.
+    pub idle: LocalDuration,
+    /// Time between probes.
+    pub interval: LocalDuration,
+    /// Number of unanswered probes after which the connection is dropped.
+    pub count: u32,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            idle: LocalDuration::from_secs(60),
+            interval: LocalDuration::from_secs(15),
+            count: 4,
+        }
+    }
+}
+
+impl Default for ReactorConfig {
+    fn default() -> Self {
+        Self {
+            wait_timeout: LocalDuration::from_mins(60),
+            read_buffer_size: 1024 * 192,
+            nodelay: true,
+            keepalive: Some(Keepalive::default()),
+            dial_timeout: LocalDuration::from_secs(5),
+            slow_step: LocalDuration::from_millis(250),
+            i2p: None,
+        }
+    }
+}
+
 /// Any network reactor that can drive the light-client protocol.
 pub trait Reactor<E: Publisher> {
-    /// The type of waker this reactor uses.
-    type Waker: Send + Clone;
+    /// The type of waker this reactor uses. Wakers are shared between client handles,
+    /// which may be used from multiple threads at once.
+    type Waker: Send + Sync + Clone;
+
+    /// Whether the reactor's transport authenticates peers, and reports their identity
+    /// with [`Protocol::authenticated`]. Peer identities can only be pinned if it does.
+    const AUTHENTICATES_PEERS: bool = false;
 
     /// Create a new reactor, initializing it with a publisher for protocol events,
     /// a channel to receive commands, and a channel to shut it down.
@@ -68,6 +172,9 @@ pub trait Reactor<E: Publisher> {
         E: Publisher,
         Self: Sized;
 
+    /// Configure the reactor. Should be called before [`Reactor::run`].
+    fn configure(&mut self, config: ReactorConfig);
+
     /// Run the given protocol state machine with the reactor.
     fn run<P: Protocol>(
         &mut self,
@@ -75,6 +182,11 @@ pub trait Reactor<E: Publisher> {
         protocol: P,
     ) -> Result<(), Error>;
 
+    /// Tear down all sockets and timers left over by a failed [`Reactor::run`], so that
+    /// the reactor can be run again. The waker, as well as the command and shutdown
+    /// channels, are kept.
+    fn reset(&mut self);
+
     /// Used to wake certain types of reactors.
     fn wake(waker: &Self::Waker) -> io::Result<()>;
 
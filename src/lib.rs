@@ -41,12 +41,18 @@
 //! }
 //! ```
 
+#[cfg(feature = "nakamoto-bdk")]
+pub use nakamoto_bdk as bdk;
 #[cfg(feature = "nakamoto-chain")]
 pub use nakamoto_chain as chain;
 #[cfg(feature = "nakamoto-client")]
 pub use nakamoto_client as client;
 #[cfg(feature = "nakamoto-common")]
 pub use nakamoto_common as common;
+#[cfg(feature = "nakamoto-esplora")]
+pub use nakamoto_esplora as esplora;
+#[cfg(feature = "nakamoto-ldk")]
+pub use nakamoto_ldk as ldk;
 #[cfg(feature = "nakamoto-node")]
 pub use nakamoto_node as node;
 #[cfg(feature = "nakamoto-p2p")]
@@ -5,14 +5,16 @@ use std::ops::RangeInclusive;
 
 use nakamoto_common::block::filter::{self, BlockFilter, FilterHash, FilterHeader, Filters};
 use nakamoto_common::block::iter::Iter;
-use nakamoto_common::block::tree::{BlockReader, BlockTree, Branch, Error, ImportResult};
+use nakamoto_common::block::tree::{BlockReader, BlockTree, Branch, Error, Fork, ImportResult};
 use nakamoto_common::block::Height;
 use nakamoto_common::nonempty::NonEmpty;
 
 use std::collections::{BTreeMap, HashMap, VecDeque};
 
 use nakamoto_common::bitcoin::blockdata::block::BlockHeader;
+use nakamoto_common::bitcoin::consensus::params::Params;
 use nakamoto_common::bitcoin::hash_types::BlockHash;
+use nakamoto_common::network::Network;
 
 #[derive(Debug, Clone)]
 pub struct Cache {
@@ -20,6 +22,17 @@ pub struct Cache {
     pub chain: NonEmpty<BlockHeader>,
     pub tip: BlockHash,
     pub genesis: BlockHash,
+    /// Consensus parameters, of the network with the same genesis block, or regtest.
+    pub params: Params,
+}
+
+/// Get the consensus parameters of the network with the given genesis block, or regtest.
+fn params(genesis: &BlockHash) -> Params {
+    [Network::Mainnet, Network::Testnet, Network::Signet]
+        .into_iter()
+        .find(|n| n.genesis_hash() == *genesis)
+        .unwrap_or(Network::Regtest)
+        .params()
 }
 
 impl Cache {
@@ -35,6 +48,7 @@ impl Cache {
             chain,
             tip: hash,
             genesis: hash,
+            params: self::params(&hash),
         }
     }
 
@@ -52,6 +66,7 @@ impl Cache {
             chain,
             tip,
             genesis,
+            params: self::params(&genesis),
         }
     }
 
@@ -169,6 +184,40 @@ impl BlockTree for Cache {
             Ok(ImportResult::TipUnchanged)
         }
     }
+
+    fn revalidate<C>(&mut self, height: Height, _context: &C) -> Result<bool, Error> {
+        if height == 0 {
+            return Ok(false);
+        }
+        match (
+            self.chain.get(height as usize - 1),
+            self.chain.get(height as usize),
+        ) {
+            (Some(parent), Some(header)) if header.prev_blockhash == parent.block_hash() => {
+                Ok(false)
+            }
+            (Some(_), Some(header)) => Err(Error::BlockMissing(header.prev_blockhash)),
+            _ => Err(Error::InvalidBlockHeight(height)),
+        }
+    }
+
+    fn truncate(&mut self, height: Height) -> Result<Vec<(Height, BlockHeader)>, Error> {
+        let mut discarded = self
+            .chain
+            .tail
+            .drain(height as usize..)
+            .zip(height + 1..)
+            .map(|(header, height)| (height, header))
+            .collect::<Vec<_>>();
+        discarded.reverse();
+
+        for (_, header) in &discarded {
+            self.headers.remove(&header.block_hash());
+        }
+        self.tip = self.chain.last().block_hash();
+
+        Ok(discarded)
+    }
 }
 
 impl BlockReader for Cache {
@@ -194,6 +243,15 @@ impl BlockReader for Cache {
         unimplemented!()
     }
 
+    fn forks(&self) -> Vec<Fork> {
+        // Stale blocks are not kept.
+        vec![]
+    }
+
+    fn params(&self) -> &Params {
+        &self.params
+    }
+
     fn last_checkpoint(&self) -> Height {
         0
     }
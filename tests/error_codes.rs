@@ -0,0 +1,181 @@
+//! Error codes are assigned by hand in every crate. Check that they are unique across
+//! the workspace, so that a code always identifies a single error.
+//!
+//! When adding an error variant with its own code, add it here too.
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io;
+
+use nakamoto_chain as chain;
+use nakamoto_client as client;
+use nakamoto_common as common;
+use nakamoto_p2p as p2p;
+
+use common::bitcoin::consensus::encode;
+use common::bitcoin::util::merkleblock::MerkleBlockError;
+use common::bitcoin::util::{address, bip32, psbt};
+use common::block::{filter, store, tree, BlockHash, Target};
+use common::error::Classify;
+use common::network::Network;
+use common::p2p::PeerAddr;
+use p2p::protocol::{
+    CommandError, GetFiltersError, ImportFilterHeadersError, PackageError, ReplaceError,
+};
+
+use client::{config, handle, journal, ots, rescan, snapshot};
+use nakamoto_wallet::{keychain, labels};
+
+#[test]
+fn test_codes_unique() {
+    let io = || io::Error::from(io::ErrorKind::Other);
+    let encode = || encode::Error::ParseFailed("");
+    let hash = BlockHash::default();
+    let errors: Vec<Box<dyn Classify>> = vec![
+        // Common.
+        Box::new(store::Error::Io(io())),
+        Box::new(store::Error::Decoding(encode())),
+        Box::new(store::Error::Corruption),
+        Box::new(store::Error::Locked),
+        Box::new(tree::Error::InvalidBlockPoW),
+        Box::new(tree::Error::InvalidBlockTarget(
+            Target::default(),
+            Target::default(),
+        )),
+        Box::new(tree::Error::InvalidBlockHash(hash, 0)),
+        Box::new(tree::Error::InvalidBlockHeight(0)),
+        Box::new(tree::Error::InvalidBlockTime(0, Ordering::Less)),
+        Box::new(tree::Error::DuplicateBlock(hash)),
+        Box::new(tree::Error::BlockMissing(hash)),
+        Box::new(filter::Error::NotFound(0)),
+        // Chain.
+        Box::new(chain::filter::store::Error::Integrity),
+        // P2P.
+        Box::new(p2p::error::Error::Io(io())),
+        Box::new(p2p::error::Error::Encode(encode())),
+        Box::new(p2p::error::Error::Channel(Box::new(io()))),
+        Box::new(CommandError::NotConnected),
+        Box::new(CommandError::NoAddresses),
+        Box::new(CommandError::InvalidPackage(PackageError::Empty)),
+        Box::new(CommandError::InvalidReplacement(
+            ReplaceError::NotConflicting(Default::default()),
+        )),
+        Box::new(GetFiltersError::InvalidRange),
+        Box::new(GetFiltersError::NotConnected),
+        Box::new(GetFiltersError::Disabled),
+        Box::new(GetFiltersError::Deferred),
+        Box::new(ImportFilterHeadersError::InvalidHeader(0)),
+        // Client.
+        Box::new(journal::Error::Io(io())),
+        Box::new(journal::Error::Decode(encode())),
+        Box::new(journal::Error::Disabled),
+        Box::new(journal::Error::Compacted(0)),
+        Box::new(rescan::Error::Io(io())),
+        Box::new(rescan::Error::Decode(encode())),
+        Box::new(rescan::Error::Disabled),
+        Box::new(rescan::Error::Ended(1)),
+        Box::new(snapshot::Error::Io(io())),
+        Box::new(snapshot::Error::Decode(encode())),
+        Box::new(snapshot::Error::Magic),
+        Box::new(snapshot::Error::Version(0)),
+        Box::new(snapshot::Error::Checksum),
+        Box::new(snapshot::Error::Network {
+            expected: hash,
+            found: hash,
+        }),
+        Box::new(snapshot::Error::InvalidHeader(0)),
+        Box::new(snapshot::Error::InvalidFilterHeader(0)),
+        Box::new(snapshot::Error::Checkpoint(0)),
+        Box::new(config::Error::NoPeers),
+        Box::new(config::Error::TooManyPersistentPeers {
+            connect: 0,
+            target: 0,
+        }),
+        Box::new(config::Error::NoDomains),
+        Box::new(config::Error::ListenWithoutInbound),
+        Box::new(config::Error::PingTimeout),
+        Box::new(config::Error::ConnectOnlyWithoutPeers),
+        Box::new(config::Error::ReadBufferSize(0)),
+        Box::new(config::Error::MempoolPrefetchWithoutTrustedPeer),
+        Box::new(config::Error::ExternalPortWithoutInbound),
+        Box::new(config::Error::ServeFiltersHeadersOnly),
+        Box::new(config::Error::MempoolPrefetchHeadersOnly),
+        Box::new(config::Error::TrustedPeerIdentityWithoutTrustedPeer),
+        Box::new(config::Error::TrustedPeerIdentityUnsupported),
+        Box::new(config::Error::UnreachablePeer(PeerAddr::from(
+            std::net::SocketAddr::from(([127, 0, 0, 1], 8333)),
+        ))),
+        Box::new(handle::Error::Disconnected),
+        Box::new(handle::Error::Timeout),
+        Box::new(handle::Error::ShuttingDown),
+        Box::new(handle::Error::Io(io())),
+        Box::new(handle::Error::LogFilter(
+            p2p::protocol::log_filter::Error::InvalidLevel(String::new()),
+        )),
+        Box::new(handle::Error::BlockNotFound(hash)),
+        Box::new(handle::Error::InvalidMerkleProof(
+            MerkleBlockError::NoTransactions,
+        )),
+        Box::new(handle::Error::Ots(ots::Error::Magic)),
+        Box::new(handle::Error::InvalidArgument("")),
+        Box::new(client::error::Error::PeerStore(io())),
+        Box::new(client::error::Error::BanList(io())),
+        Box::new(client::error::Error::Asmap(
+            p2p::protocol::asmap::Error::Malformed,
+        )),
+        Box::new(client::error::Error::AlreadyRunning(Network::Mainnet)),
+        Box::new(client::error::Error::Panic(String::new())),
+        Box::new(client::error::Error::Channel),
+        Box::new(client::error::Error::Io(io())),
+        // Wallet.
+        Box::new(nakamoto_wallet::store::Error::Io(io())),
+        Box::new(nakamoto_wallet::store::Error::Decode(encode())),
+        Box::new(nakamoto_wallet::store::Error::Format("")),
+        Box::new(nakamoto_wallet::store::Error::Decryption),
+        Box::new(nakamoto_wallet::store::Error::PassphraseRequired),
+        Box::new(nakamoto_wallet::store::Error::Kdf(
+            argon2::Error::PwdTooLong,
+        )),
+        Box::new(labels::Error::Io(io())),
+        Box::new(labels::Error::Invalid {
+            line: 1,
+            reason: "",
+        }),
+        Box::new(keychain::Error::Descriptor("")),
+        Box::new(keychain::Error::Bip32(
+            bip32::Error::CannotDeriveFromHardenedKey,
+        )),
+        Box::new(keychain::Error::Address(address::Error::EmptyBech32Payload)),
+        Box::new(keychain::Error::Miniscript(miniscript::Error::Unexpected(
+            String::new(),
+        ))),
+        Box::new(keychain::Error::Conversion(
+            miniscript::descriptor::ConversionError::Wildcard,
+        )),
+        Box::new(nakamoto_wallet::Error::Io(io())),
+        Box::new(nakamoto_wallet::Error::NoDescriptor),
+        Box::new(nakamoto_wallet::Error::FeeBump("")),
+        Box::new(nakamoto_wallet::Error::Psbt(psbt::Error::InvalidMagic)),
+        Box::new(nakamoto_wallet::Error::Finalize(
+            miniscript::psbt::Error::WrongInputCount {
+                in_tx: 0,
+                in_map: 0,
+            },
+        )),
+        Box::new(nakamoto_wallet::Error::Locked(Default::default())),
+        Box::new(nakamoto_wallet::Error::InsufficientFunds {
+            available: 0,
+            needed: 0,
+        }),
+        // Integrations.
+        Box::new(nakamoto_ldk::Error::NotFound(hash)),
+        Box::new(nakamoto_ldk::Error::Timeout(hash)),
+        Box::new(nakamoto_bdk::Error::NotFound(0)),
+    ];
+    let mut codes = HashMap::new();
+
+    for err in &errors {
+        if let Some(other) = codes.insert(err.code(), err) {
+            panic!("{:?} and {:?} share code {}", other, err, err.code());
+        }
+    }
+}
@@ -0,0 +1,400 @@
+//! Wallet transaction history.
+//!
+//! Keeps track of the transactions relevant to the wallet, ie. transactions paying to one
+//! of the wallet's addresses or spending one of its outputs, along with what they mean for
+//! the wallet: direction, amount and fee.
+//!
+//! Entries are either confirmed at a given height, or pending. Pending entries are
+//! transactions submitted by the wallet that haven't been seen in a block yet, and
+//! transactions that were confirmed in a block that was since reverted.
+use std::io;
+use std::ops::{Bound, RangeBounds};
+
+use nakamoto_client::spv::utxos::Utxos;
+use nakamoto_common::bitcoin::consensus::encode::{self, Decodable, Encodable, VarInt};
+use nakamoto_common::bitcoin::{Script, Transaction, Txid};
+use nakamoto_common::block::Height;
+
+/// Direction of a transaction, from the point of view of the wallet.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    /// The transaction pays to the wallet, and doesn't spend any of its outputs.
+    Incoming,
+    /// The transaction spends outputs belonging to the wallet.
+    Outgoing,
+}
+
+/// Confirmation status of a history entry.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// The transaction isn't part of the main chain.
+    Pending,
+    /// The transaction was confirmed at the given height.
+    Confirmed {
+        /// Height of the block including the transaction.
+        height: Height,
+    },
+}
+
+/// A transaction history entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The transaction.
+    pub transaction: Transaction,
+    /// Confirmation status.
+    pub status: Status,
+    /// Direction of the transaction.
+    pub direction: Direction,
+    /// Amount received by the wallet, for incoming transactions, or amount sent out of the
+    /// wallet, excluding change and fees, for outgoing transactions.
+    pub amount: u64,
+    /// Fee paid by the transaction, if the value of all its inputs is known.
+    pub fee: Option<u64>,
+}
+
+impl Entry {
+    /// Transaction ID of the entry.
+    pub fn txid(&self) -> Txid {
+        self.transaction.txid()
+    }
+
+    /// Height at which the transaction was confirmed, if any.
+    pub fn height(&self) -> Option<Height> {
+        match self.status {
+            Status::Confirmed { height } => Some(height),
+            Status::Pending => None,
+        }
+    }
+}
+
+/// Transaction history of a wallet.
+///
+/// Entries are kept in the order in which they were confirmed, so that the wallet's UTXO
+/// set can be rebuilt from them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct History {
+    entries: Vec<Entry>,
+}
+
+impl History {
+    /// Create an empty history.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of entries in the history.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the history is empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterate over all entries.
+    pub fn iter(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter()
+    }
+
+    /// Get the entry for the given transaction.
+    pub fn get(&self, txid: &Txid) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.txid() == *txid)
+    }
+
+    /// Record a transaction with the given status, given the wallet's UTXOs and scripts
+    /// *before* the transaction is applied to them. If the transaction is already in the
+    /// history, its status is updated.
+    ///
+    /// Returns `false` if the transaction isn't relevant to the wallet.
+    pub fn record(
+        &mut self,
+        tx: &Transaction,
+        status: Status,
+        utxos: &Utxos,
+        scripts: &[Script],
+    ) -> bool {
+        let txid = tx.txid();
+
+        if let Some(ix) = self.entries.iter().position(|e| e.txid() == txid) {
+            let mut entry = self.entries.remove(ix);
+            entry.status = status;
+
+            // Keep entries in confirmation order.
+            self.entries.push(entry);
+
+            return true;
+        }
+
+        let received = tx
+            .output
+            .iter()
+            .filter(|o| scripts.contains(&o.script_pubkey))
+            .map(|o| o.value)
+            .sum::<u64>();
+        let inputs = tx
+            .input
+            .iter()
+            .map(|i| {
+                utxos.get(&i.previous_output).map(|o| o.value).or_else(|| {
+                    self.get(&i.previous_output.txid).and_then(|e| {
+                        e.transaction
+                            .output
+                            .get(i.previous_output.vout as usize)
+                            .filter(|o| scripts.contains(&o.script_pubkey))
+                            .map(|o| o.value)
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        let sent = inputs.iter().flatten().sum::<u64>();
+
+        if received == 0 && sent == 0 {
+            return false;
+        }
+        let fee = inputs
+            .into_iter()
+            .sum::<Option<u64>>()
+            .and_then(|total| total.checked_sub(tx.output.iter().map(|o| o.value).sum()));
+        let (direction, amount) = if sent > 0 {
+            let amount = sent
+                .saturating_sub(received)
+                .saturating_sub(fee.unwrap_or(0));
+            (Direction::Outgoing, amount)
+        } else {
+            (Direction::Incoming, received)
+        };
+
+        self.entries.push(Entry {
+            transaction: tx.clone(),
+            status,
+            direction,
+            amount,
+            fee,
+        });
+
+        true
+    }
+
+    /// Mark all entries confirmed at or above the given height as pending. Called when a
+    /// block is disconnected from the main chain. Returns the number of entries affected.
+    pub fn revert(&mut self, height: Height) -> usize {
+        let mut reverted = 0;
+
+        for entry in &mut self.entries {
+            if matches!(entry.status, Status::Confirmed { height: h } if h >= height) {
+                entry.status = Status::Pending;
+                reverted += 1;
+            }
+        }
+        reverted
+    }
+
+    /// Remove an entry from the history, eg. a pending transaction that will never
+    /// be confirmed.
+    pub fn remove(&mut self, txid: &Txid) -> Option<Entry> {
+        let ix = self.entries.iter().position(|e| e.txid() == *txid)?;
+
+        Some(self.entries.remove(ix))
+    }
+
+    /// Get the entries confirmed within the given height range, in height order. If the
+    /// range is unbounded on the right, pending entries are also returned, last.
+    pub fn range(&self, range: impl RangeBounds<Height>) -> Vec<&Entry> {
+        let mut entries = self
+            .entries
+            .iter()
+            .filter(|e| match e.height() {
+                Some(height) => range.contains(&height),
+                None => range.end_bound() == Bound::Unbounded,
+            })
+            .collect::<Vec<_>>();
+
+        entries.sort_by_key(|e| e.height().unwrap_or(Height::MAX));
+        entries
+    }
+
+    /// Rebuild the wallet's UTXO set from the confirmed entries.
+    pub fn utxos(&self, scripts: &[Script]) -> Utxos {
+        let mut utxos = Utxos::new();
+
+        for entry in self.range(..=Height::MAX) {
+            utxos.apply(&entry.transaction, scripts);
+        }
+        utxos
+    }
+}
+
+impl Encodable for History {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = VarInt(self.entries.len() as u64).consensus_encode(&mut w)?;
+
+        for entry in &self.entries {
+            len += entry.transaction.consensus_encode(&mut w)?;
+            len += match entry.status {
+                Status::Pending => 0u8.consensus_encode(&mut w)?,
+                Status::Confirmed { height } => {
+                    1u8.consensus_encode(&mut w)? + height.consensus_encode(&mut w)?
+                }
+            };
+            len += match entry.direction {
+                Direction::Incoming => 0u8,
+                Direction::Outgoing => 1u8,
+            }
+            .consensus_encode(&mut w)?;
+            len += entry.amount.consensus_encode(&mut w)?;
+            len += match entry.fee {
+                None => 0u8.consensus_encode(&mut w)?,
+                Some(fee) => 1u8.consensus_encode(&mut w)? + fee.consensus_encode(&mut w)?,
+            };
+        }
+        Ok(len)
+    }
+}
+
+impl History {
+    /// Decode a history saved with version 2 of the wallet state format, in which entries
+    /// carried their own label. The labels are returned along with the history.
+    pub(crate) fn decode_labeled<D: io::Read>(
+        d: D,
+    ) -> Result<(Self, Vec<(Txid, String)>), encode::Error> {
+        Self::decode_entries(d, true)
+    }
+
+    fn decode_entries<D: io::Read>(
+        mut d: D,
+        labeled: bool,
+    ) -> Result<(Self, Vec<(Txid, String)>), encode::Error> {
+        let mut history = History::new();
+        let mut labels = Vec::new();
+
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            let transaction = Transaction::consensus_decode(&mut d)?;
+            let status = match u8::consensus_decode(&mut d)? {
+                0 => Status::Pending,
+                1 => Status::Confirmed {
+                    height: Height::consensus_decode(&mut d)?,
+                },
+                _ => return Err(encode::Error::ParseFailed("invalid history entry status")),
+            };
+            let direction = match u8::consensus_decode(&mut d)? {
+                0 => Direction::Incoming,
+                1 => Direction::Outgoing,
+                _ => {
+                    return Err(encode::Error::ParseFailed(
+                        "invalid history entry direction",
+                    ))
+                }
+            };
+            let amount = u64::consensus_decode(&mut d)?;
+            let fee = match u8::consensus_decode(&mut d)? {
+                0 => None,
+                _ => Some(u64::consensus_decode(&mut d)?),
+            };
+            if labeled && u8::consensus_decode(&mut d)? != 0 {
+                labels.push((transaction.txid(), String::consensus_decode(&mut d)?));
+            }
+
+            history.entries.push(Entry {
+                transaction,
+                status,
+                direction,
+                amount,
+                fee,
+            });
+        }
+        Ok((history, labels))
+    }
+}
+
+impl Decodable for History {
+    fn consensus_decode<D: io::Read>(d: D) -> Result<Self, encode::Error> {
+        Self::decode_entries(d, false).map(|(history, _)| history)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use nakamoto_common::bitcoin::{OutPoint, TxIn, TxOut, Witness};
+
+    fn tx(inputs: &[OutPoint], outputs: &[(u64, &Script)]) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: inputs
+                .iter()
+                .map(|o| TxIn {
+                    previous_output: *o,
+                    script_sig: Script::new(),
+                    sequence: 0xffffffff,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|(value, script)| TxOut {
+                    value: *value,
+                    script_pubkey: (*script).clone(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_record_revert() {
+        let ours = Script::from(vec![0x51]);
+        let theirs = Script::from(vec![0x52]);
+        let scripts = [ours.clone()];
+        let mut history = History::new();
+        let mut utxos = Utxos::new();
+
+        let foreign = OutPoint {
+            txid: Txid::default(),
+            vout: 0,
+        };
+        let funding = tx(&[foreign], &[(10_000, &ours), (5_000, &theirs)]);
+        let spend = tx(
+            &[OutPoint {
+                txid: funding.txid(),
+                vout: 0,
+            }],
+            &[(6_000, &theirs), (3_000, &ours)],
+        );
+        let unrelated = tx(&[foreign], &[(1_000, &theirs)]);
+
+        for (height, tx) in [(1, &funding), (2, &spend), (2, &unrelated)] {
+            history.record(tx, Status::Confirmed { height }, &utxos, &scripts);
+            utxos.apply(tx, &scripts);
+        }
+        assert_eq!(history.len(), 2);
+
+        let entry = history.get(&funding.txid()).unwrap();
+        assert_eq!(entry.direction, Direction::Incoming);
+        assert_eq!(entry.amount, 10_000);
+        assert_eq!(entry.fee, None);
+
+        let entry = history.get(&spend.txid()).unwrap();
+        assert_eq!(entry.direction, Direction::Outgoing);
+        assert_eq!(entry.amount, 6_000);
+        assert_eq!(entry.fee, Some(1_000));
+
+        assert_eq!(*history.utxos(&scripts), *utxos);
+
+        // The block including the spend is reverted.
+        assert_eq!(history.revert(2), 1);
+        assert_eq!(history.range(..).len(), 2);
+        assert_eq!(history.range(..=2).len(), 1);
+        assert_eq!(history.utxos(&scripts).balance(), 10_000);
+
+        // The spend is confirmed again, at a different height.
+        history.record(&spend, Status::Confirmed { height: 3 }, &utxos, &scripts);
+        let entry = history.get(&spend.txid()).unwrap();
+        assert_eq!(entry.height(), Some(3));
+        assert_eq!(*history.utxos(&scripts), *utxos);
+
+        let decoded: History = encode::deserialize(&encode::serialize(&history)).unwrap();
+        assert_eq!(decoded, history);
+    }
+}
@@ -0,0 +1,1088 @@
+//! Address derivation.
+//!
+//! Addresses can be derived from extended public keys, described by a simple output
+//! descriptor of one of the following forms:
+//!
+//! * `pkh(KEY)`: pay to public key hash.
+//! * `wpkh(KEY)`: pay to witness public key hash.
+//! * `tr(KEY)`: pay to taproot, spendable with the key path only.
+//! * `tr(KEY,TREE)`: pay to taproot, with a script tree, where `TREE` is either a leaf of
+//!   the form `pk(KEY)`, or a branch of the form `{TREE,TREE}`.
+//!
+//! Keys are of the form `<xpub>/<path>/*`, where `<path>` is a list of non-hardened
+//! derivation steps. All keys of a descriptor are derived at the same index.
+//!
+//! More complex scripts, eg. for multisig or timelocked wallets, are described by
+//! `wsh(MINISCRIPT)` and `sh(..)` descriptors, which are handled by the `miniscript` crate.
+//! Such descriptors can also be compiled from a spending policy with
+//! [`Descriptor::compile`].
+//!
+//! Since we can't know which derived addresses have received funds without scanning for
+//! them, a window of [`Keychain::gap_limit`] addresses past the last used or handed out
+//! address is derived and watched. When a watched address is used, the window slides
+//! forward, and the newly derived addresses must be added to the watch list.
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use miniscript::descriptor::{ConversionError, DescriptorPublicKey, DescriptorTrait, ShInner};
+use miniscript::policy::Concrete;
+use miniscript::ForEachKey as _;
+use thiserror::Error;
+
+use nakamoto_common::bitcoin::blockdata::opcodes::all::OP_CHECKSIG;
+use nakamoto_common::bitcoin::blockdata::script::Builder;
+use nakamoto_common::bitcoin::consensus::encode::{self, Decodable, Encodable};
+use nakamoto_common::bitcoin::secp256k1::{self, Secp256k1, Verification, XOnlyPublicKey};
+use nakamoto_common::bitcoin::util::bip32::{self, ChildNumber, ExtendedPubKey, KeySource};
+use nakamoto_common::bitcoin::util::psbt::{self, TapTree};
+use nakamoto_common::bitcoin::util::taproot::{
+    LeafVersion, TapLeafHash, TaprootBuilder, TaprootSpendInfo,
+};
+use nakamoto_common::bitcoin::{Address, Network, PublicKey, Script};
+use nakamoto_common::error::Classify;
+
+/// Default number of unused addresses watched past the last used one.
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+/// A derivation error.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The descriptor could not be parsed.
+    #[error("invalid descriptor: {0}")]
+    Descriptor(&'static str),
+    /// A key could not be derived.
+    #[error("key derivation error: {0}")]
+    Bip32(#[from] bip32::Error),
+    /// An address could not be derived.
+    #[error("address derivation error: {0}")]
+    Address(#[from] nakamoto_common::bitcoin::util::address::Error),
+    /// A miniscript descriptor or policy is invalid.
+    #[error("miniscript error: {0}")]
+    Miniscript(#[from] miniscript::Error),
+    /// A miniscript key could not be derived.
+    #[error("key conversion error: {0}")]
+    Conversion(#[from] ConversionError),
+}
+
+impl Classify for Error {
+    fn code(&self) -> u32 {
+        match self {
+            Self::Descriptor(_) => 4020,
+            Self::Miniscript(_) => 4021,
+            Self::Bip32(_) => 3022,
+            Self::Address(_) => 3023,
+            Self::Conversion(_) => 3024,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        false
+    }
+}
+
+/// Type of script derived by a descriptor.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ScriptKind {
+    /// Pay to public key hash.
+    Pkh,
+    /// Pay to witness public key hash.
+    Wpkh,
+    /// Pay to taproot.
+    Tr,
+}
+
+impl ScriptKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pkh => "pkh",
+            Self::Wpkh => "wpkh",
+            Self::Tr => "tr",
+        }
+    }
+}
+
+/// A derivable key, ie. an extended public key and a derivation path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+    /// Extended public key.
+    pub xpub: ExtendedPubKey,
+    /// Derivation path, from the extended key to the parent of derived keys.
+    pub path: Vec<ChildNumber>,
+}
+
+impl Key {
+    /// Derive the public key at the given index, along with its origin.
+    fn derive<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        index: u32,
+    ) -> Result<(secp256k1::PublicKey, KeySource), Error> {
+        let mut path = self.path.clone();
+        path.push(ChildNumber::from_normal_idx(index)?);
+
+        let key = self.xpub.derive_pub(secp, &path)?.public_key;
+
+        Ok((key, (self.xpub.fingerprint(), path.into())))
+    }
+}
+
+impl FromStr for Key {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s
+            .strip_suffix("/*")
+            .ok_or(Error::Descriptor("expected a wildcard derivation step"))?
+            .split('/');
+        let xpub = parts
+            .next()
+            .and_then(|s| ExtendedPubKey::from_str(s).ok())
+            .ok_or(Error::Descriptor("invalid extended public key"))?;
+        let path = parts
+            .map(|step| {
+                step.parse::<u32>()
+                    .map_err(|_| Error::Descriptor("invalid derivation step"))
+                    .and_then(|i| ChildNumber::from_normal_idx(i).map_err(Error::from))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { xpub, path })
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.xpub)?;
+        for step in &self.path {
+            write!(f, "/{}", step)?;
+        }
+        write!(f, "/*")
+    }
+}
+
+/// A taproot script tree, with single-key leaves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Tree {
+    /// A leaf with a `<key> OP_CHECKSIG` script.
+    Leaf(Key),
+    /// A branch.
+    Branch(Box<Tree>, Box<Tree>),
+}
+
+impl Tree {
+    /// Visit the leaves in depth-first order, with their depth.
+    fn leaves(&self, depth: u8, visit: &mut impl FnMut(u8, &Key)) {
+        match self {
+            Self::Leaf(key) => visit(depth, key),
+            Self::Branch(left, right) => {
+                left.leaves(depth + 1, visit);
+                right.leaves(depth + 1, visit);
+            }
+        }
+    }
+}
+
+impl FromStr for Tree {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(inner) = s.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+            let (left, right) =
+                split(inner).ok_or(Error::Descriptor("expected `{<tree>,<tree>}`"))?;
+
+            Ok(Self::Branch(
+                Box::new(left.parse()?),
+                Box::new(right.parse()?),
+            ))
+        } else if let Some(key) = s.strip_prefix("pk(").and_then(|s| s.strip_suffix(')')) {
+            Ok(Self::Leaf(key.parse()?))
+        } else {
+            Err(Error::Descriptor("unsupported script tree leaf"))
+        }
+    }
+}
+
+impl fmt::Display for Tree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Leaf(key) => write!(f, "pk({})", key),
+            Self::Branch(left, right) => write!(f, "{{{},{}}}", left, right),
+        }
+    }
+}
+
+/// Split a string at the first top-level comma.
+fn split(s: &str) -> Option<(&str, &str)> {
+    let mut depth = 0;
+
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' | '{' => depth += 1,
+            ')' | '}' => depth -= 1,
+            ',' if depth == 0 => return Some((&s[..i], &s[i + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// An output descriptor, deriving addresses from extended public keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Descriptor {
+    /// A single-key descriptor, with an optional taproot script tree.
+    Single {
+        /// Type of script derived.
+        kind: ScriptKind,
+        /// Key from which addresses are derived. This is the internal key of taproot outputs.
+        key: Key,
+        /// Script tree of taproot outputs.
+        tree: Option<Tree>,
+    },
+    /// A miniscript descriptor, eg. compiled from a spending policy.
+    Miniscript(miniscript::Descriptor<DescriptorPublicKey>),
+}
+
+impl Descriptor {
+    /// Compile a spending policy, eg. `or(pk(A),and(pk(B),older(1000)))`, to a pay to
+    /// witness script hash descriptor.
+    pub fn compile(policy: &str) -> Result<Self, Error> {
+        let policy = Concrete::<DescriptorPublicKey>::from_str(policy)?;
+        let descriptor =
+            miniscript::Descriptor::new_wsh(policy.compile().map_err(miniscript::Error::from)?)?;
+
+        Self::miniscript(descriptor)
+    }
+
+    /// Derive the address at the given index.
+    pub fn derive(&self, index: u32) -> Result<Address, Error> {
+        self.derive_spend_info(index).map(|d| d.address)
+    }
+
+    /// Derive the address at the given index, along with the information needed to
+    /// spend from it.
+    pub fn derive_spend_info(&self, index: u32) -> Result<Derived, Error> {
+        let secp = Secp256k1::verification_only();
+
+        let (kind, key, tree) = match self {
+            Self::Single { kind, key, tree } => (kind, key, tree),
+            Self::Miniscript(descriptor) => {
+                let mut origins = Vec::new();
+                let mut network = Network::Bitcoin;
+                let derived = descriptor.derive(index);
+                let mut keys = Vec::new();
+
+                derived.for_each_key(|key| {
+                    keys.push(key.as_key().clone());
+                    true
+                });
+                for key in keys {
+                    if let DescriptorPublicKey::XPub(xpub) = &key {
+                        network = xpub.xkey.network;
+                    }
+                    origins.push((
+                        key.derive_public_key(&secp)?.inner,
+                        (key.master_fingerprint(), key.full_derivation_path()),
+                    ));
+                }
+                let derived = derived.derived_descriptor(&secp, 0)?;
+                let script = derived.explicit_script()?;
+                // Nb. The redeem script of a nested witness output is its witness program.
+                let (redeem_script, witness_script) = match &derived {
+                    miniscript::Descriptor::Sh(sh) => match sh.as_inner() {
+                        ShInner::Wsh(_) => (Some(script.to_v0_p2wsh()), Some(script)),
+                        _ => (Some(script), None),
+                    },
+                    miniscript::Descriptor::Wsh(_) => (None, Some(script)),
+                    _ => (None, None),
+                };
+
+                return Ok(Derived {
+                    address: derived.address(network)?,
+                    origins,
+                    redeem_script,
+                    witness_script,
+                    taproot: None,
+                });
+            }
+        };
+        let network = key.xpub.network;
+        let (key, origin) = key.derive(&secp, index)?;
+
+        match kind {
+            ScriptKind::Pkh => Ok(Derived {
+                address: Address::p2pkh(&PublicKey::new(key), network),
+                origins: vec![(key, origin)],
+                redeem_script: None,
+                witness_script: None,
+                taproot: None,
+            }),
+            ScriptKind::Wpkh => Ok(Derived {
+                address: Address::p2wpkh(&PublicKey::new(key), network)?,
+                origins: vec![(key, origin)],
+                redeem_script: None,
+                witness_script: None,
+                taproot: None,
+            }),
+            ScriptKind::Tr => {
+                let internal = XOnlyPublicKey::from(key);
+                let mut origins = vec![(key, origin)];
+                let mut leaves = Vec::new();
+
+                if let Some(tree) = tree {
+                    let mut result = Ok(());
+
+                    tree.leaves(0, &mut |depth, leaf| match leaf.derive(&secp, index) {
+                        Ok((key, origin)) => {
+                            let script = Builder::new()
+                                .push_slice(&XOnlyPublicKey::from(key).serialize())
+                                .push_opcode(OP_CHECKSIG)
+                                .into_script();
+
+                            leaves.push((depth, script));
+                            origins.push((key, origin));
+                        }
+                        Err(err) => result = Err(err),
+                    });
+                    result?;
+                }
+                let builder = leaves
+                    .iter()
+                    .try_fold(TaprootBuilder::new(), |builder, (depth, script)| {
+                        builder.add_leaf(*depth, script.clone())
+                    })
+                    .map_err(|_| Error::Descriptor("invalid script tree"))?;
+                let spend_info = builder
+                    .clone()
+                    .finalize(&secp, internal)
+                    .map_err(|_| Error::Descriptor("invalid script tree"))?;
+                let address = Address::p2tr(&secp, internal, spend_info.merkle_root(), network);
+                let tree = if leaves.is_empty() {
+                    None
+                } else {
+                    Some(
+                        TapTree::from_builder(builder)
+                            .map_err(|_| Error::Descriptor("invalid script tree"))?,
+                    )
+                };
+
+                Ok(Derived {
+                    address,
+                    origins,
+                    redeem_script: None,
+                    witness_script: None,
+                    taproot: Some((spend_info, tree)),
+                })
+            }
+        }
+    }
+
+    /// Maximum weight of the witness and script signature needed to spend an output derived
+    /// from this descriptor, for fee estimation. This is the weight of the most expensive
+    /// satisfaction, so it can be used to estimate fees before signing.
+    pub fn max_satisfaction_weight(&self) -> Result<usize, Error> {
+        match self {
+            Self::Miniscript(descriptor) => Ok(descriptor.max_satisfaction_weight()?),
+            // Single-key descriptors are valid miniscript descriptors too.
+            Self::Single { .. } => {
+                miniscript::Descriptor::<DescriptorPublicKey>::from_str(&self.to_string())?
+                    .max_satisfaction_weight()
+                    .map_err(Error::from)
+            }
+        }
+    }
+
+    /// Check a miniscript descriptor, which must have a wildcard and be safe to use.
+    fn miniscript(descriptor: miniscript::Descriptor<DescriptorPublicKey>) -> Result<Self, Error> {
+        descriptor.sanity_check()?;
+
+        if !descriptor.is_deriveable() {
+            return Err(Error::Descriptor("expected a wildcard derivation step"));
+        }
+        Ok(Self::Miniscript(descriptor))
+    }
+}
+
+impl FromStr for Descriptor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("wsh(") || s.starts_with("sh(") {
+            return Self::miniscript(s.parse()?);
+        }
+        let (kind, inner) = s
+            .strip_suffix(')')
+            .and_then(|s| s.split_once('('))
+            .ok_or(Error::Descriptor("expected `<type>(<key>)`"))?;
+        let kind = match kind {
+            "pkh" => ScriptKind::Pkh,
+            "wpkh" => ScriptKind::Wpkh,
+            "tr" => ScriptKind::Tr,
+            _ => return Err(Error::Descriptor("unsupported script type")),
+        };
+        let (key, tree) = match split(inner) {
+            Some((key, tree)) if kind == ScriptKind::Tr => (key, Some(tree.parse()?)),
+            Some(_) => return Err(Error::Descriptor("unexpected script tree")),
+            None => (inner, None),
+        };
+
+        Ok(Self::Single {
+            kind,
+            key: key.parse()?,
+            tree,
+        })
+    }
+}
+
+impl fmt::Display for Descriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Single { kind, key, tree } => {
+                write!(f, "{}({}", kind.as_str(), key)?;
+                if let Some(tree) = tree {
+                    write!(f, ",{}", tree)?;
+                }
+                write!(f, ")")
+            }
+            Self::Miniscript(descriptor) => write!(f, "{}", descriptor),
+        }
+    }
+}
+
+/// An address derived from a descriptor, with the information needed to spend from it.
+#[derive(Debug, Clone)]
+pub struct Derived {
+    /// The derived address.
+    pub address: Address,
+    /// The derived public keys, with their origin. For taproot outputs, the internal key
+    /// comes first, followed by the keys of the script tree leaves.
+    pub origins: Vec<(secp256k1::PublicKey, KeySource)>,
+    /// Redeem script of pay to script hash outputs, including nested witness outputs.
+    pub redeem_script: Option<Script>,
+    /// Witness script of pay to witness script hash outputs, nested or not.
+    pub witness_script: Option<Script>,
+    /// Spending information and script tree of taproot outputs.
+    pub taproot: Option<(TaprootSpendInfo, Option<TapTree>)>,
+}
+
+impl Derived {
+    /// Set the key and script fields of a PSBT input spending an output to this address.
+    pub fn update_input(&self, input: &mut psbt::Input) {
+        match &self.taproot {
+            Some((spend_info, _)) => {
+                input.tap_internal_key = Some(spend_info.internal_key());
+                input.tap_merkle_root = spend_info.merkle_root();
+
+                for script in spend_info.as_script_map().keys() {
+                    if let Some(control_block) = spend_info.control_block(script) {
+                        input.tap_scripts.insert(control_block, script.clone());
+                    }
+                }
+                input.tap_key_origins.extend(self.tap_key_origins());
+            }
+            None => {
+                input.redeem_script = self.redeem_script.clone();
+                input.witness_script = self.witness_script.clone();
+                input.bip32_derivation.extend(self.origins.iter().cloned());
+            }
+        }
+    }
+
+    /// Set the key and script fields of a PSBT output paying to this address.
+    pub fn update_output(&self, output: &mut psbt::Output) {
+        match &self.taproot {
+            Some((spend_info, tree)) => {
+                output.tap_internal_key = Some(spend_info.internal_key());
+                output.tap_tree = tree.clone();
+                output.tap_key_origins.extend(self.tap_key_origins());
+            }
+            None => {
+                output.redeem_script = self.redeem_script.clone();
+                output.witness_script = self.witness_script.clone();
+                output.bip32_derivation.extend(self.origins.iter().cloned());
+            }
+        }
+    }
+
+    /// Taproot key origins, with the hashes of the leaves each key appears in.
+    fn tap_key_origins(
+        &self,
+    ) -> impl Iterator<Item = (XOnlyPublicKey, (Vec<TapLeafHash>, KeySource))> + '_ {
+        self.origins.iter().enumerate().map(|(i, (key, origin))| {
+            let key = XOnlyPublicKey::from(*key);
+            let leaves = if i == 0 {
+                // The internal key isn't part of any leaf.
+                vec![]
+            } else {
+                let script = Builder::new()
+                    .push_slice(&key.serialize())
+                    .push_opcode(OP_CHECKSIG)
+                    .into_script();
+
+                vec![TapLeafHash::from_script(&script, LeafVersion::TapScript)]
+            };
+            (key, (leaves, origin.clone()))
+        })
+    }
+}
+
+/// Derivation state of a keychain.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Derivation {
+    /// Index of the next address that is neither used nor handed out.
+    pub next_unused: u32,
+    /// Index of the highest address used in a transaction, if any.
+    pub highest_used: Option<u32>,
+    /// End of the window of watched addresses, exclusive.
+    pub watched: u32,
+}
+
+/// Addresses derived from a descriptor, and the derivation state.
+#[derive(Debug, Clone)]
+pub struct Keychain {
+    /// Descriptor from which addresses are derived.
+    pub descriptor: Descriptor,
+    /// Number of addresses watched past the last used or handed out address.
+    pub gap_limit: u32,
+    /// Index of the highest used address.
+    highest_used: Option<u32>,
+    /// Number of addresses handed out.
+    handed_out: u32,
+    /// Scripts of the watched addresses, with their index.
+    scripts: HashMap<Script, u32>,
+}
+
+impl Keychain {
+    /// Create a new keychain, deriving the initial window of addresses.
+    pub fn new(descriptor: Descriptor, gap_limit: u32) -> Result<Self, Error> {
+        let mut keychain = Self {
+            descriptor,
+            gap_limit,
+            highest_used: None,
+            handed_out: 0,
+            scripts: HashMap::new(),
+        };
+        keychain.slide()?;
+
+        Ok(keychain)
+    }
+
+    /// Get the derivation state.
+    pub fn derivation(&self) -> Derivation {
+        Derivation {
+            next_unused: self.next_index(),
+            highest_used: self.highest_used,
+            watched: self.scripts.len() as u32,
+        }
+    }
+
+    /// Scripts of the addresses that should be watched.
+    pub fn scripts(&self) -> impl Iterator<Item = &Script> {
+        self.scripts.keys()
+    }
+
+    /// Get the derivation index of a watched script.
+    pub fn index_of(&self, script: &Script) -> Option<u32> {
+        self.scripts.get(script).copied()
+    }
+
+    /// Derive the spending information of a watched script.
+    pub fn spend_info(&self, script: &Script) -> Option<Derived> {
+        self.index_of(script)
+            .and_then(|index| self.descriptor.derive_spend_info(index).ok())
+    }
+
+    /// Mark the address with the given script as used, eg. because it was found in a
+    /// block. Returns the scripts of the newly derived addresses, if the window slid.
+    pub fn mark_used(&mut self, script: &Script) -> Result<Vec<Script>, Error> {
+        match self.index_of(script) {
+            Some(index) if !matches!(self.highest_used, Some(h) if h >= index) => {
+                self.highest_used = Some(index);
+                self.slide()
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Hand out the next unused address. Returns the address along with the scripts of
+    /// newly derived addresses, which must be watched before the address is shared.
+    pub fn next_unused(&mut self) -> Result<(Address, Vec<Script>), Error> {
+        let index = self.next_index();
+        let address = self.descriptor.derive(index)?;
+
+        self.handed_out = index + 1;
+
+        Ok((address, self.slide()?))
+    }
+
+    /// Change the number of addresses watched past the last used or handed out address.
+    /// Returns the scripts of the newly derived addresses, if the limit was raised.
+    /// Addresses that were already derived stay watched if the limit is lowered, until
+    /// the keychain is decoded again.
+    pub fn set_gap_limit(&mut self, gap_limit: u32) -> Result<Vec<Script>, Error> {
+        self.gap_limit = gap_limit;
+        self.slide()
+    }
+
+    /// Index of the next address that is neither used nor handed out.
+    fn next_index(&self) -> u32 {
+        self.highest_used.map_or(0, |h| h + 1).max(self.handed_out)
+    }
+
+    /// Derive addresses up to the end of the window. Returns the newly derived scripts.
+    fn slide(&mut self) -> Result<Vec<Script>, Error> {
+        let start = self.scripts.len() as u32;
+        let end = self.next_index() + self.gap_limit;
+        let mut derived = Vec::new();
+
+        for index in start..end {
+            let script = self.descriptor.derive(index)?.script_pubkey();
+
+            self.scripts.insert(script.clone(), index);
+            derived.push(script);
+        }
+        Ok(derived)
+    }
+}
+
+impl Encodable for Keychain {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = self.descriptor.to_string().consensus_encode(&mut w)?;
+        len += self.gap_limit.consensus_encode(&mut w)?;
+        len += match self.highest_used {
+            None => 0u8.consensus_encode(&mut w)?,
+            Some(index) => 1u8.consensus_encode(&mut w)? + index.consensus_encode(&mut w)?,
+        };
+        len += self.handed_out.consensus_encode(&mut w)?;
+
+        Ok(len)
+    }
+}
+
+impl Decodable for Keychain {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let descriptor = String::consensus_decode(&mut d)?
+            .parse()
+            .map_err(|_| encode::Error::ParseFailed("invalid descriptor"))?;
+        let gap_limit = u32::consensus_decode(&mut d)?;
+        let highest_used = match u8::consensus_decode(&mut d)? {
+            0 => None,
+            _ => Some(u32::consensus_decode(&mut d)?),
+        };
+        let handed_out = u32::consensus_decode(&mut d)?;
+        let mut keychain = Self {
+            descriptor,
+            gap_limit,
+            highest_used,
+            handed_out,
+            scripts: HashMap::new(),
+        };
+        keychain
+            .slide()
+            .map_err(|_| encode::Error::ParseFailed("address derivation failed"))?;
+
+        Ok(keychain)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Master key of BIP 32 test vector 1.
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    #[test]
+    fn test_descriptor() {
+        let secp = Secp256k1::verification_only();
+        let xpub = ExtendedPubKey::from_str(XPUB).unwrap();
+        let child = |i| {
+            xpub.ckd_pub(&secp, ChildNumber::from_normal_idx(0).unwrap())
+                .and_then(|k| k.ckd_pub(&secp, ChildNumber::from_normal_idx(i).unwrap()))
+                .unwrap()
+                .to_pub()
+        };
+
+        let descriptor = Descriptor::from_str(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        assert_eq!(descriptor.to_string(), format!("wpkh({}/0/*)", XPUB));
+        assert_eq!(
+            descriptor.derive(7).unwrap(),
+            Address::p2wpkh(&child(7), xpub.network).unwrap()
+        );
+
+        let descriptor = Descriptor::from_str(&format!("pkh({}/0/*)", XPUB)).unwrap();
+        assert_eq!(
+            descriptor.derive(7).unwrap(),
+            Address::p2pkh(&child(7), xpub.network)
+        );
+
+        assert!(Descriptor::from_str(&format!("sh({}/0/*)", XPUB)).is_err());
+        assert!(Descriptor::from_str(&format!("wpkh({}/0/*,pk({}/1/*))", XPUB, XPUB)).is_err());
+        assert!(Descriptor::from_str(&format!("wpkh({}/0h/*)", XPUB)).is_err());
+        assert!(Descriptor::from_str(&format!("wpkh({}/0)", XPUB)).is_err());
+    }
+
+    #[test]
+    fn test_taproot() {
+        let secp = Secp256k1::verification_only();
+        let key = |path: &str| {
+            Key::from_str(&format!("{}/{}/*", XPUB, path))
+                .unwrap()
+                .derive(&secp, 7)
+                .unwrap()
+                .0
+        };
+        let leaf = |path| {
+            Builder::new()
+                .push_slice(&XOnlyPublicKey::from(key(path)).serialize())
+                .push_opcode(OP_CHECKSIG)
+                .into_script()
+        };
+        let internal = XOnlyPublicKey::from(key("0"));
+
+        // Key path only.
+        let descriptor = Descriptor::from_str(&format!("tr({}/0/*)", XPUB)).unwrap();
+        let derived = descriptor.derive_spend_info(7).unwrap();
+        assert_eq!(
+            derived.address,
+            Address::p2tr(&secp, internal, None, Network::Bitcoin)
+        );
+
+        let mut input = psbt::Input::default();
+        derived.update_input(&mut input);
+        assert_eq!(input.tap_internal_key, Some(internal));
+        assert_eq!(input.tap_merkle_root, None);
+        assert!(input.tap_scripts.is_empty());
+        assert_eq!(input.tap_key_origins[&internal].0, vec![]);
+        assert!(input.bip32_derivation.is_empty());
+
+        // With a script tree.
+        let s = format!(
+            "tr({x}/0/*,{{pk({x}/1/*),{{pk({x}/2/*),pk({x}/3/*)}}}})",
+            x = XPUB
+        );
+        let descriptor = Descriptor::from_str(&s).unwrap();
+        assert_eq!(descriptor.to_string(), s);
+
+        let spend_info = TaprootBuilder::new()
+            .add_leaf(1, leaf("1"))
+            .and_then(|b| b.add_leaf(2, leaf("2")))
+            .and_then(|b| b.add_leaf(2, leaf("3")))
+            .unwrap()
+            .finalize(&secp, internal)
+            .unwrap();
+        let derived = descriptor.derive_spend_info(7).unwrap();
+        assert_eq!(
+            derived.address,
+            Address::p2tr(&secp, internal, spend_info.merkle_root(), Network::Bitcoin)
+        );
+
+        let mut input = psbt::Input::default();
+        derived.update_input(&mut input);
+        assert_eq!(input.tap_merkle_root, spend_info.merkle_root());
+        assert_eq!(input.tap_scripts.len(), 3);
+        assert_eq!(input.tap_key_origins.len(), 4);
+
+        // Script-path spends can be verified against the output key.
+        for (control_block, (script, version)) in &input.tap_scripts {
+            assert!(
+                control_block.verify_taproot_commitment(
+                    &secp,
+                    spend_info.output_key().to_inner(),
+                    &Script::from(script.to_bytes())
+                ) && *version == LeafVersion::TapScript
+            );
+        }
+        let (leaves, (fingerprint, path)) = &input.tap_key_origins[&XOnlyPublicKey::from(key("2"))];
+        assert_eq!(
+            leaves,
+            &vec![TapLeafHash::from_script(&leaf("2"), LeafVersion::TapScript)]
+        );
+        assert_eq!(
+            *fingerprint,
+            ExtendedPubKey::from_str(XPUB).unwrap().fingerprint()
+        );
+        assert_eq!(path.to_string(), "m/2/7");
+
+        let mut output = psbt::Output::default();
+        derived.update_output(&mut output);
+        assert_eq!(output.tap_internal_key, Some(internal));
+        assert!(output.tap_tree.is_some());
+
+        assert!(Descriptor::from_str(&format!("tr({}/0/*,pk({}/1/*)", XPUB, XPUB)).is_err());
+        assert!(Descriptor::from_str(&format!("tr({x}/0/*,{{pk({x}/1/*)}})", x = XPUB)).is_err());
+    }
+
+    #[test]
+    fn test_policy() {
+        use miniscript::psbt::PsbtExt as _;
+        use nakamoto_common::bitcoin::secp256k1::Message;
+        use nakamoto_common::bitcoin::util::bip32::ExtendedPrivKey;
+        use nakamoto_common::bitcoin::util::sighash::SighashCache;
+        use nakamoto_common::bitcoin::{
+            EcdsaSig, EcdsaSighashType, OutPoint, Transaction, TxIn, TxOut, Witness,
+        };
+
+        let secp = Secp256k1::new();
+        let policy = format!("or(pk({x}/0/*),and(pk({x}/1/*),older(144)))", x = XPUB);
+        let descriptor = Descriptor::compile(&policy).unwrap();
+
+        assert!(matches!(descriptor, Descriptor::Miniscript(_)));
+        assert!(descriptor.to_string().starts_with("wsh("));
+        assert_eq!(
+            Descriptor::from_str(&descriptor.to_string()).unwrap(),
+            descriptor
+        );
+        assert!(Descriptor::compile("pk(").is_err());
+        assert!(Descriptor::compile(&format!("pk({}/0/1)", XPUB)).is_err());
+
+        let derived = descriptor.derive_spend_info(3).unwrap();
+        let witness_script = derived.witness_script.clone().unwrap();
+        assert_eq!(
+            derived.address.script_pubkey(),
+            witness_script.to_v0_p2wsh()
+        );
+        assert_eq!(
+            derived
+                .origins
+                .iter()
+                .map(|(_, (_, path))| path.to_string())
+                .collect::<Vec<_>>(),
+            vec!["m/0/3", "m/1/3"]
+        );
+
+        // Single-key descriptors have the usual satisfaction weights.
+        let wpkh = Descriptor::from_str(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        let tr = Descriptor::from_str(&format!("tr({}/0/*)", XPUB)).unwrap();
+        assert_eq!(wpkh.max_satisfaction_weight().unwrap(), 112);
+        assert!(tr.max_satisfaction_weight().unwrap() < 112);
+        assert!(descriptor.max_satisfaction_weight().unwrap() > 112);
+
+        // Spend from the derived address with the first key, and finalize.
+        let prevout = TxOut {
+            value: 10_000,
+            script_pubkey: derived.address.script_pubkey(),
+        };
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: 9_000,
+                script_pubkey: wpkh.derive(0).unwrap().script_pubkey(),
+            }],
+        };
+        let mut psbt = psbt::PartiallySignedTransaction::from_unsigned_tx(tx.clone()).unwrap();
+        psbt.inputs[0].witness_utxo = Some(prevout.clone());
+        derived.update_input(&mut psbt.inputs[0]);
+
+        let xprv = ExtendedPrivKey::new_master(
+            Network::Bitcoin,
+            &[
+                0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+                0x0e, 0x0f,
+            ],
+        )
+        .unwrap();
+        let (key, (_, path)) = &derived.origins[0];
+        let secret = xprv.derive_priv(&secp, path).unwrap().private_key;
+        let sighash = SighashCache::new(&tx)
+            .segwit_signature_hash(0, &witness_script, prevout.value, EcdsaSighashType::All)
+            .unwrap();
+        let sig = secp.sign_ecdsa(&Message::from_slice(&sighash).unwrap(), &secret);
+
+        assert_eq!(ExtendedPubKey::from_priv(&secp, &xprv).to_string(), XPUB);
+        assert!(psbt.clone().finalize_mut(&secp).is_err());
+
+        psbt.inputs[0]
+            .partial_sigs
+            .insert(PublicKey::new(*key), EcdsaSig::sighash_all(sig));
+        psbt.finalize_mut(&secp).unwrap();
+
+        let signed = psbt.extract(&secp).unwrap();
+        assert_eq!(signed.input[0].witness.len(), 2);
+        assert_eq!(
+            signed.input[0].witness.last(),
+            Some(witness_script.as_bytes())
+        );
+    }
+
+    #[test]
+    fn test_nested_scripts() {
+        use miniscript::psbt::PsbtExt as _;
+        use nakamoto_common::bitcoin::blockdata::script::Instruction;
+        use nakamoto_common::bitcoin::secp256k1::Message;
+        use nakamoto_common::bitcoin::util::bip32::ExtendedPrivKey;
+        use nakamoto_common::bitcoin::util::sighash::SighashCache;
+        use nakamoto_common::bitcoin::{
+            EcdsaSig, EcdsaSighashType, OutPoint, Transaction, TxIn, TxOut, Witness,
+        };
+
+        let secp = Secp256k1::new();
+        let xprv = ExtendedPrivKey::new_master(
+            Network::Bitcoin,
+            &[
+                0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+                0x0e, 0x0f,
+            ],
+        )
+        .unwrap();
+        let multi = format!("multi(1,{x}/0/*,{x}/1/*)", x = XPUB);
+
+        for (descriptor, segwit) in [
+            (format!("sh({})", multi), false),
+            (format!("sh(wsh({}))", multi), true),
+        ] {
+            let descriptor = Descriptor::from_str(&descriptor).unwrap();
+            let derived = descriptor.derive_spend_info(3).unwrap();
+            let redeem_script = derived.redeem_script.clone().unwrap();
+
+            assert_eq!(derived.address.script_pubkey(), redeem_script.to_p2sh());
+            if segwit {
+                let witness_script = derived.witness_script.clone().unwrap();
+                assert_eq!(redeem_script, witness_script.to_v0_p2wsh());
+            } else {
+                assert_eq!(derived.witness_script, None);
+            }
+
+            // Spend from the derived address with the first key, and finalize.
+            let prevout = TxOut {
+                value: 10_000,
+                script_pubkey: derived.address.script_pubkey(),
+            };
+            let prev_tx = Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![],
+                output: vec![prevout.clone()],
+            };
+            let tx = Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![TxIn {
+                    previous_output: OutPoint::new(prev_tx.txid(), 0),
+                    script_sig: Script::new(),
+                    sequence: 0xffffffff,
+                    witness: Witness::new(),
+                }],
+                output: vec![TxOut {
+                    value: 9_000,
+                    script_pubkey: derived.address.script_pubkey(),
+                }],
+            };
+            let mut psbt = psbt::PartiallySignedTransaction::from_unsigned_tx(tx.clone()).unwrap();
+            if segwit {
+                psbt.inputs[0].witness_utxo = Some(prevout.clone());
+            } else {
+                psbt.inputs[0].non_witness_utxo = Some(prev_tx);
+            }
+            derived.update_input(&mut psbt.inputs[0]);
+            derived.update_output(&mut psbt.outputs[0]);
+
+            assert_eq!(psbt.inputs[0].redeem_script, derived.redeem_script);
+            assert_eq!(psbt.inputs[0].witness_script, derived.witness_script);
+            assert_eq!(psbt.outputs[0].redeem_script, derived.redeem_script);
+            assert_eq!(psbt.outputs[0].witness_script, derived.witness_script);
+
+            let (key, (_, path)) = &derived.origins[0];
+            let secret = xprv.derive_priv(&secp, path).unwrap().private_key;
+            let mut cache = SighashCache::new(&tx);
+            let sighash = match &derived.witness_script {
+                Some(script) => cache
+                    .segwit_signature_hash(0, script, prevout.value, EcdsaSighashType::All)
+                    .unwrap()
+                    .to_vec(),
+                None => cache
+                    .legacy_signature_hash(0, &redeem_script, EcdsaSighashType::All.to_u32())
+                    .unwrap()
+                    .to_vec(),
+            };
+            let sig = secp.sign_ecdsa(&Message::from_slice(&sighash).unwrap(), &secret);
+
+            psbt.inputs[0]
+                .partial_sigs
+                .insert(PublicKey::new(*key), EcdsaSig::sighash_all(sig));
+            psbt.finalize_mut(&secp).unwrap();
+
+            let signed = psbt.extract(&secp).unwrap();
+            let script_sig = signed.input[0].script_sig.instructions().last();
+
+            if segwit {
+                // The script signature only pushes the redeem script, ie. the witness
+                // program, and the witness script comes last in the witness.
+                assert_eq!(signed.input[0].script_sig.instructions().count(), 1);
+                assert_eq!(
+                    signed.input[0].witness.last(),
+                    derived.witness_script.as_ref().map(|s| s.as_bytes())
+                );
+            } else {
+                assert!(signed.input[0].witness.is_empty());
+            }
+            assert!(matches!(
+                script_sig,
+                Some(Ok(Instruction::PushBytes(bytes))) if bytes == redeem_script.as_bytes()
+            ));
+        }
+    }
+
+    #[test]
+    fn test_gap_limit() {
+        let descriptor = Descriptor::from_str(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        let mut keychain = Keychain::new(descriptor.clone(), 3).unwrap();
+        let script = |i| descriptor.derive(i).unwrap().script_pubkey();
+
+        assert_eq!(keychain.scripts().count(), 3);
+        assert_eq!(
+            keychain.derivation(),
+            Derivation {
+                next_unused: 0,
+                highest_used: None,
+                watched: 3,
+            }
+        );
+
+        // Using an address slides the window.
+        assert_eq!(
+            keychain.mark_used(&script(1)).unwrap(),
+            vec![script(3), script(4)]
+        );
+        assert!(keychain.mark_used(&script(0)).unwrap().is_empty());
+        assert!(keychain.mark_used(&script(9)).unwrap().is_empty());
+        assert_eq!(keychain.derivation().next_unused, 2);
+
+        // Handing out an address slides the window too.
+        let (address, derived) = keychain.next_unused().unwrap();
+        assert_eq!(address.script_pubkey(), script(2));
+        assert_eq!(derived, vec![script(5)]);
+        assert_eq!(
+            keychain.derivation(),
+            Derivation {
+                next_unused: 3,
+                highest_used: Some(1),
+                watched: 6,
+            }
+        );
+
+        let decoded: Keychain = encode::deserialize(&encode::serialize(&keychain)).unwrap();
+        assert_eq!(decoded.derivation(), keychain.derivation());
+        assert_eq!(decoded.index_of(&script(5)), Some(5));
+
+        // Raising the gap limit derives more addresses, lowering it keeps them.
+        assert_eq!(
+            keychain.set_gap_limit(5).unwrap(),
+            vec![script(6), script(7)]
+        );
+        assert!(keychain.set_gap_limit(1).unwrap().is_empty());
+        assert_eq!(keychain.derivation().watched, 8);
+
+        let decoded: Keychain = encode::deserialize(&encode::serialize(&keychain)).unwrap();
+        assert_eq!(decoded.gap_limit, 1);
+        assert_eq!(decoded.derivation().watched, 4);
+    }
+}
@@ -0,0 +1,292 @@
+//! Wallet labels.
+//!
+//! Labels can be attached to addresses, transactions and transaction outputs. They are
+//! persisted with the rest of the wallet state, and can be exported to and imported from
+//! the BIP 329 format, a JSON Lines format understood by other wallets, in which each
+//! line is an object with a `type`, a `ref` identifying the labeled item, and a `label`.
+//!
+//! Only the `tx`, `addr` and `output` types are supported. Records of other types are
+//! skipped on import, as recommended by the BIP.
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use microserde::json::{Object, Value};
+use thiserror::Error;
+
+use nakamoto_common::bitcoin::consensus::encode::{self, Decodable, Encodable, VarInt};
+use nakamoto_common::bitcoin::{Address, OutPoint, Txid};
+use nakamoto_common::error::{self, Classify};
+
+/// A label error.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// An I/O error.
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    /// A record could not be parsed.
+    #[error("invalid label record on line {line}: {reason}")]
+    Invalid {
+        /// Line number of the record, starting at one.
+        line: usize,
+        /// Why the record is invalid.
+        reason: &'static str,
+    },
+}
+
+impl Classify for Error {
+    fn code(&self) -> u32 {
+        match self {
+            Self::Io(_) => 2021,
+            Self::Invalid { .. } => 3021,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Io(err) => error::is_transient_io(err),
+            Self::Invalid { .. } => false,
+        }
+    }
+}
+
+/// A labeled item.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Ref {
+    /// A transaction.
+    Tx(Txid),
+    /// An address.
+    Addr(Address),
+    /// A transaction output.
+    Output(OutPoint),
+}
+
+impl Ref {
+    /// The BIP 329 type of the item.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Tx(_) => "tx",
+            Self::Addr(_) => "addr",
+            Self::Output(_) => "output",
+        }
+    }
+
+    /// Parse an item from its BIP 329 type and reference. Returns `Ok(None)` if the type
+    /// isn't supported.
+    pub fn parse(kind: &str, reference: &str) -> Result<Option<Self>, &'static str> {
+        match kind {
+            "tx" => Txid::from_str(reference)
+                .map(|txid| Some(Self::Tx(txid)))
+                .map_err(|_| "invalid transaction id"),
+            "addr" => Address::from_str(reference)
+                .map(|addr| Some(Self::Addr(addr)))
+                .map_err(|_| "invalid address"),
+            "output" => OutPoint::from_str(reference)
+                .map(|out| Some(Self::Output(out)))
+                .map_err(|_| "invalid output"),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl fmt::Display for Ref {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tx(txid) => write!(f, "{}", txid),
+            Self::Addr(addr) => write!(f, "{}", addr),
+            Self::Output(out) => write!(f, "{}:{}", out.txid, out.vout),
+        }
+    }
+}
+
+/// Labels attached to wallet items.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Labels {
+    labels: HashMap<Ref, String>,
+}
+
+impl Labels {
+    /// Create an empty label set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of labels.
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    /// Whether there are no labels.
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Get the label of an item.
+    pub fn get(&self, r: &Ref) -> Option<&str> {
+        self.labels.get(r).map(|l| l.as_str())
+    }
+
+    /// Label an item. Returns the previous label, if any.
+    pub fn set(&mut self, r: Ref, label: impl Into<String>) -> Option<String> {
+        self.labels.insert(r, label.into())
+    }
+
+    /// Remove the label of an item. Returns the removed label, if any.
+    pub fn remove(&mut self, r: &Ref) -> Option<String> {
+        self.labels.remove(r)
+    }
+
+    /// Iterate over all labels, ordered by type and reference.
+    pub fn iter(&self) -> impl Iterator<Item = (&Ref, &str)> {
+        let mut labels = self
+            .labels
+            .iter()
+            .map(|(r, l)| ((r.kind(), r.to_string()), (r, l.as_str())))
+            .collect::<Vec<_>>();
+
+        labels.sort_by(|(a, _), (b, _)| a.cmp(b));
+        labels.into_iter().map(|(_, label)| label)
+    }
+
+    /// Export all labels in the BIP 329 format.
+    pub fn export<W: io::Write>(&self, mut w: W) -> Result<(), Error> {
+        for (r, label) in self.iter() {
+            let record: Object = [
+                ("type", r.kind().to_owned()),
+                ("ref", r.to_string()),
+                ("label", label.to_owned()),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), Value::String(v)))
+            .collect();
+
+            writeln!(w, "{}", microserde::json::to_string(&Value::Object(record)))?;
+        }
+        Ok(())
+    }
+
+    /// Import labels in the BIP 329 format, replacing existing labels of the same items.
+    /// Returns the number of labels imported.
+    pub fn import<R: io::BufRead>(&mut self, r: R) -> Result<usize, Error> {
+        let mut imported = 0;
+
+        for (ix, line) in r.lines().enumerate() {
+            let line = line?;
+            let invalid = |reason| Error::Invalid {
+                line: ix + 1,
+                reason,
+            };
+
+            if line.trim().is_empty() {
+                continue;
+            }
+            let Ok(Value::Object(record)) = microserde::json::from_str(&line) else {
+                return Err(invalid("expected a JSON object"));
+            };
+            let field = |name| match record.get(name) {
+                Some(Value::String(s)) => Ok(Some(s.as_str())),
+                Some(Value::Null) | None => Ok(None),
+                Some(_) => Err(invalid("expected a string")),
+            };
+            let kind = field("type")?.ok_or_else(|| invalid("missing type"))?;
+            let reference = field("ref")?.ok_or_else(|| invalid("missing reference"))?;
+
+            // Records without a label, eg. outputs only marked as unspendable, are skipped.
+            let Some(label) = field("label")? else {
+                continue;
+            };
+            if let Some(r) = Ref::parse(kind, reference).map_err(invalid)? {
+                self.set(r, label);
+                imported += 1;
+            }
+        }
+        Ok(imported)
+    }
+}
+
+impl Encodable for Labels {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = VarInt(self.labels.len() as u64).consensus_encode(&mut w)?;
+
+        for (r, label) in self.iter() {
+            len += r.kind().to_owned().consensus_encode(&mut w)?;
+            len += r.to_string().consensus_encode(&mut w)?;
+            len += label.to_owned().consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Labels {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let mut labels = Labels::new();
+
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            let kind = String::consensus_decode(&mut d)?;
+            let reference = String::consensus_decode(&mut d)?;
+            let label = String::consensus_decode(&mut d)?;
+            let r = Ref::parse(&kind, &reference)
+                .map_err(encode::Error::ParseFailed)?
+                .ok_or(encode::Error::ParseFailed("unknown label type"))?;
+
+            labels.set(r, label);
+        }
+        Ok(labels)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_export_import() {
+        let txid =
+            Txid::from_str("f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd")
+                .unwrap();
+        let addr = Address::from_str("bc1q34aq5drpuwy3wgl9lhup9892qp6svr8ldzyy7c").unwrap();
+        let mut labels = Labels::new();
+
+        labels.set(Ref::Tx(txid), "Transaction");
+        labels.set(Ref::Addr(addr.clone()), "Address \"quoted\"");
+        labels.set(Ref::Output(OutPoint { txid, vout: 1 }), "Output");
+
+        let mut exported = Vec::new();
+        labels.export(&mut exported).unwrap();
+
+        let exported = String::from_utf8(exported).unwrap();
+        assert_eq!(exported.lines().count(), 3);
+        assert!(exported.contains(&format!("\"ref\":\"{}:1\",\"type\":\"output\"", txid)));
+
+        let mut imported = Labels::new();
+        assert_eq!(imported.import(exported.as_bytes()).unwrap(), 3);
+        assert_eq!(imported, labels);
+        assert_eq!(imported.get(&Ref::Addr(addr)), Some("Address \"quoted\""));
+
+        let decoded: Labels = encode::deserialize(&encode::serialize(&labels)).unwrap();
+        assert_eq!(decoded, labels);
+    }
+
+    #[test]
+    fn test_import() {
+        let records = r#"
+{"type": "tx", "ref": "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd", "label": "Transaction", "origin": "wpkh([d34db33f/84'/0'/0'])"}
+{"type": "xpub", "ref": "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8", "label": "Extended Public Key"}
+{"type": "output", "ref": "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd:0", "spendable": false}
+"#;
+        let mut labels = Labels::new();
+
+        assert_eq!(labels.import(records.as_bytes()).unwrap(), 1);
+        assert_eq!(labels.iter().next().map(|(_, l)| l), Some("Transaction"));
+
+        assert!(matches!(
+            labels.import(r#"{"type": "tx", "ref": "deadbeef", "label": "?"}"#.as_bytes()),
+            Err(Error::Invalid { line: 1, .. })
+        ));
+        assert!(matches!(
+            labels.import("\n[]".as_bytes()),
+            Err(Error::Invalid { line: 2, .. })
+        ));
+    }
+}
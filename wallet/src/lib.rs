@@ -1,19 +1,45 @@
 //! A watch-only wallet.
+pub mod history;
+pub mod keychain;
+pub mod labels;
 pub mod logger;
+pub mod store;
+pub mod timelock;
 
+use crossbeam_channel as chan;
 use thiserror::Error;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeBounds;
+use std::path::PathBuf;
 use std::{io, net, thread};
 
-use nakamoto_common::bitcoin::Address;
+use miniscript::psbt::PsbtExt as _;
+
+use nakamoto_common::bitcoin::secp256k1::Secp256k1;
+use nakamoto_common::bitcoin::util::psbt::{self, PartiallySignedTransaction};
+use nakamoto_common::bitcoin::{Address, OutPoint, Script, Transaction, TxOut, Txid, Witness};
 
 use nakamoto_client::handle::{self, Handle};
 use nakamoto_client::spv::utxos::Utxos;
+use nakamoto_client::spv::TxStatus;
 use nakamoto_client::Network;
-use nakamoto_client::{client, protocol, Client, Config, Event};
-use nakamoto_common::block::Height;
+use nakamoto_client::{client, protocol, Client, Config};
+use nakamoto_common::block::{BlockTime, Height};
+use nakamoto_common::error::{self, Classify};
 use nakamoto_common::network::Services;
+use nakamoto_common::nonempty::NonEmpty;
+
+use crate::history::{History, Status};
+use crate::keychain::{Derivation, Descriptor, Keychain};
+use crate::labels::{Labels, Ref};
+use crate::timelock::Maturity;
+
+/// Outputs worth less than this are considered dust, and aren't relayed.
+pub const DUST_LIMIT: u64 = 546;
+
+/// Highest input sequence number signaling replaceability. See BIP 125.
+const MAX_RBF_SEQUENCE: u32 = 0xfffffffd;
 
 /// An error occuring in the wallet.
 #[derive(Error, Debug)]
@@ -26,6 +52,88 @@ pub enum Error {
 
     #[error("io error: {0}")]
     Io(#[from] io::Error),
+
+    #[error("storage error: {0}")]
+    Store(#[from] store::Error),
+
+    #[error("label error: {0}")]
+    Labels(#[from] labels::Error),
+
+    #[error("address derivation error: {0}")]
+    Keychain(#[from] keychain::Error),
+
+    #[error("no descriptor to derive addresses from")]
+    NoDescriptor,
+
+    #[error("cannot bump fee: {0}")]
+    FeeBump(&'static str),
+
+    #[error("psbt error: {0}")]
+    Psbt(#[from] psbt::Error),
+
+    #[error("psbt finalization error: {0}")]
+    Finalize(miniscript::psbt::Error),
+
+    #[error("output {0} is locked")]
+    Locked(OutPoint),
+
+    #[error("insufficient funds: {available} sats available, {needed} needed")]
+    InsufficientFunds { available: u64, needed: u64 },
+}
+
+impl Classify for Error {
+    fn code(&self) -> u32 {
+        match self {
+            Self::Handle(err) => err.code(),
+            Self::Client(err) => err.code(),
+            Self::Store(err) => err.code(),
+            Self::Labels(err) => err.code(),
+            Self::Keychain(err) => err.code(),
+            Self::Io(_) => 2022,
+            Self::FeeBump(_) => 3025,
+            Self::Psbt(_) => 3026,
+            Self::Finalize(_) => 3027,
+            Self::NoDescriptor => 4022,
+            Self::Locked(_) => 4023,
+            Self::InsufficientFunds { .. } => 4024,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Handle(err) => err.is_transient(),
+            Self::Client(err) => err.is_transient(),
+            Self::Store(err) => err.is_transient(),
+            Self::Labels(err) => err.is_transient(),
+            Self::Io(err) => error::is_transient_io(err),
+            _ => false,
+        }
+    }
+}
+
+/// An event emitted by the wallet.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// The window of watched addresses of a descriptor slid forward, because an address
+    /// was used or handed out. The newly derived addresses were added to the watch list.
+    WindowSlid {
+        /// The descriptor whose window slid.
+        descriptor: Descriptor,
+        /// The new derivation state.
+        derivation: Derivation,
+        /// Number of addresses derived.
+        derived: usize,
+    },
+    /// A timelocked output became spendable, as of the given tip.
+    UtxoMatured {
+        /// The output.
+        outpoint: OutPoint,
+        /// When the output became spendable.
+        maturity: Maturity,
+        /// Height of the tip.
+        height: Height,
+    },
 }
 
 /// A Bitcoin wallet.
@@ -33,6 +141,16 @@ pub struct Wallet<H> {
     client: H,
     addresses: HashSet<Address>,
     utxos: Utxos,
+    locked: HashSet<OutPoint>,
+    history: History,
+    labels: Labels,
+    keychains: Vec<Keychain>,
+    /// Maturity of timelocked outputs.
+    maturities: HashMap<OutPoint, Maturity>,
+    /// Height and median time past of the chain tip.
+    tip: (Height, BlockTime),
+    subscribers: Vec<chan::Sender<Event>>,
+    store: Option<store::Store>,
 }
 
 impl<H: Handle> Wallet<H> {
@@ -42,13 +160,57 @@ impl<H: Handle> Wallet<H> {
             client,
             addresses: addresses.into_iter().collect(),
             utxos: Utxos::new(),
+            locked: HashSet::new(),
+            history: History::new(),
+            labels: Labels::new(),
+            keychains: Vec::new(),
+            maturities: HashMap::new(),
+            tip: (0, 0),
+            subscribers: Vec::new(),
+            store: None,
+        }
+    }
+
+    /// Create a new wallet with persistent state. The given watch addresses are added
+    /// to the ones previously saved.
+    pub fn load(client: H, addresses: Vec<Address>, store: store::Store) -> Result<Self, Error> {
+        let mut state = store.load()?;
+        state.addresses.extend(addresses);
+
+        Ok(Self {
+            client,
+            addresses: state.addresses,
+            utxos: state.utxos,
+            locked: state.locked,
+            history: state.history,
+            labels: state.labels,
+            keychains: state.keychains,
+            maturities: HashMap::new(),
+            tip: (0, 0),
+            subscribers: Vec::new(),
+            store: Some(store),
+        })
+    }
+
+    /// Save the wallet state, if the wallet has persistent state.
+    pub fn save(&self) -> Result<(), Error> {
+        if let Some(store) = &self.store {
+            store.save(&store::State {
+                addresses: self.addresses.clone(),
+                utxos: self.utxos.clone(),
+                locked: self.locked.clone(),
+                history: self.history.clone(),
+                labels: self.labels.clone(),
+                keychains: self.keychains.clone(),
+            })?;
         }
+        Ok(())
     }
 
     /// Rescan the blockchain for matching transactions.
     pub fn rescan(&mut self, birth: Height) -> Result<(), Error> {
         // Convert our address list into scripts.
-        let addresses: Vec<_> = self.addresses.iter().map(|a| a.script_pubkey()).collect();
+        let mut addresses = self.scripts();
         let events = self.client.subscribe();
 
         log::info!("Waiting for peers..");
@@ -58,23 +220,64 @@ impl<H: Handle> Wallet<H> {
         log::info!("Starting re-scan from block height {}", birth);
         self.client.rescan(birth.., addresses.iter().cloned())?;
 
+        let (height, _) = self.client.get_tip()?;
+        self.update_tip(height)?;
+        self.update_maturities()?;
+
         while let Ok(event) = events.recv() {
             match event {
-                Event::BlockMatched {
+                client::Event::BlockMatched {
                     transactions,
-                    height,
+                    block,
                     ..
                 } => {
+                    let height = block.height;
+
                     for t in &transactions {
+                        self.history.record(
+                            t,
+                            Status::Confirmed { height },
+                            &self.utxos,
+                            &addresses,
+                        );
                         self.utxos.apply(t, &addresses);
+
+                        for output in &t.output {
+                            addresses.extend(self.mark_used(&output.script_pubkey)?);
+                        }
                     }
+                    self.update_maturities()?;
+                    self.save()?;
+
                     log::info!(
                         "Processed block at height #{} (balance = {})",
                         height,
                         self.balance()
                     );
                 }
-                Event::Synced { height, tip } => {
+                client::Event::BlockDisconnected { block, .. } => {
+                    let height = block.height;
+
+                    // Transactions confirmed in the disconnected block are pending again,
+                    // and the outputs they created or spent are restored.
+                    self.history.revert(height);
+                    self.utxos = self.history.utxos(&addresses);
+                    self.update_tip(height - 1)?;
+                    self.update_maturities()?;
+                    self.save()?;
+                }
+                client::Event::BlockConnected { block, .. } => {
+                    self.update_tip(block.height)?;
+                }
+                client::Event::TxStatusChanged {
+                    txid,
+                    status: TxStatus::Stale { .. },
+                } => {
+                    // Stale transactions will never be confirmed.
+                    self.history.remove(&txid);
+                    self.save()?;
+                }
+                client::Event::Synced { height, tip } => {
                     log::info!(
                         "Synced up to height {} ({:.1}%) ({} remaining)",
                         height,
@@ -89,16 +292,486 @@ impl<H: Handle> Wallet<H> {
         Ok(())
     }
 
+    /// Construct a replacement for one of our unconfirmed transactions, paying `amount` more
+    /// in fees, taken from its change output. The change output is the first output paying
+    /// to one of our addresses.
+    ///
+    /// Since this is a watch-only wallet, the replacement is returned unsigned: its inputs
+    /// must be signed before it is submitted with [`Wallet::replace`].
+    pub fn bump_fee(&self, tx: &Transaction, amount: u64) -> Result<Transaction, Error> {
+        let change = self.scripts();
+
+        bump_fee(tx, amount, |script| change.contains(script))
+    }
+
+    /// Construct a PSBT for an unsigned transaction, so that it can be signed by the holder
+    /// of the wallet's keys. For inputs spending our outputs and outputs paying to our
+    /// derived addresses, the previous output and key origins are set, as well as the
+    /// internal key and script tree of taproot outputs, so that both key-path and
+    /// script-path spends can be signed. Legacy inputs get the previous transaction
+    /// instead of the previous output, if it is in the history.
+    ///
+    /// Fails if the transaction spends a locked output.
+    pub fn psbt(&self, tx: Transaction) -> Result<PartiallySignedTransaction, Error> {
+        if let Some(txin) = tx
+            .input
+            .iter()
+            .find(|txin| self.locked.contains(&txin.previous_output))
+        {
+            return Err(Error::Locked(txin.previous_output));
+        }
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx)?;
+        let spend_info = |script: &Script| {
+            self.keychains
+                .iter()
+                .find_map(|keychain| keychain.spend_info(script))
+        };
+
+        for (txin, input) in psbt.unsigned_tx.input.iter().zip(&mut psbt.inputs) {
+            if let Some(prevout) = self.utxos.get(&txin.previous_output) {
+                let derived = spend_info(&prevout.script_pubkey);
+                let segwit = prevout.script_pubkey.is_witness_program()
+                    || derived
+                        .as_ref()
+                        .and_then(|d| d.redeem_script.as_ref())
+                        .is_some_and(|s| s.is_witness_program());
+
+                if let Some(derived) = derived {
+                    derived.update_input(input);
+                }
+                // Legacy inputs sign over the whole previous transaction.
+                if segwit {
+                    input.witness_utxo = Some(prevout.clone());
+                } else if let Some(entry) = self.history.get(&txin.previous_output.txid) {
+                    input.non_witness_utxo = Some(entry.transaction.clone());
+                }
+            }
+        }
+        for (txout, output) in psbt.unsigned_tx.output.iter().zip(&mut psbt.outputs) {
+            if let Some(derived) = spend_info(&txout.script_pubkey) {
+                derived.update_output(output);
+            }
+        }
+        Ok(psbt)
+    }
+
+    /// Finalize a signed PSBT, constructing the witness and script signature of each input
+    /// from the signatures, hash preimages and timelocks it satisfies, and extract the
+    /// signed transaction.
+    pub fn finalize(&self, psbt: &mut PartiallySignedTransaction) -> Result<Transaction, Error> {
+        let secp = Secp256k1::verification_only();
+
+        // Report the first input that couldn't be finalized.
+        if let Err(Some(err)) = psbt
+            .finalize_mut(&secp)
+            .map_err(|errors| errors.into_iter().next())
+        {
+            return Err(Error::Finalize(err));
+        }
+        psbt.extract(&secp).map_err(Error::Finalize)
+    }
+
+    /// Maximum weight of the witness and script signature needed to spend one of the
+    /// wallet's outputs, for fee estimation. Returns `None` if the output wasn't derived
+    /// from one of the wallet's descriptors.
+    pub fn max_satisfaction_weight(&self, outpoint: &OutPoint) -> Option<usize> {
+        let script = &self.utxos.get(outpoint)?.script_pubkey;
+
+        self.keychains
+            .iter()
+            .find(|k| k.index_of(script).is_some())
+            .and_then(|k| k.descriptor.max_satisfaction_weight().ok())
+    }
+
+    /// Replace one of our unconfirmed transactions, eg. with a transaction constructed with
+    /// [`Wallet::bump_fee`]. Status updates for both transactions are emitted as
+    /// [`client::Event::TxStatusChanged`] events, until either one is confirmed.
+    ///
+    /// The replacement is added to the history as a pending transaction.
+    pub fn replace(
+        &mut self,
+        txid: Txid,
+        replacement: Transaction,
+    ) -> Result<NonEmpty<net::SocketAddr>, Error> {
+        let peers = self.client.replace_transaction(txid, replacement.clone())?;
+        let scripts = self.scripts();
+
+        self.history
+            .record(&replacement, Status::Pending, &self.utxos, &scripts);
+        self.save()?;
+
+        Ok(peers)
+    }
+
+    /// Get the transactions confirmed within the given height range, in height order.
+    /// If the range is unbounded on the right, pending transactions are included, last.
+    pub fn history(&self, range: impl RangeBounds<Height>) -> Vec<&history::Entry> {
+        self.history.range(range)
+    }
+
+    /// Get the label of an address, transaction or output.
+    pub fn label(&self, r: &Ref) -> Option<&str> {
+        self.labels.get(r)
+    }
+
+    /// Label an address, transaction or output. Returns the previous label, if any.
+    pub fn set_label(&mut self, r: Ref, label: impl Into<String>) -> Result<Option<String>, Error> {
+        let previous = self.labels.set(r, label);
+        self.save()?;
+
+        Ok(previous)
+    }
+
+    /// Remove the label of an address, transaction or output. Returns the removed label,
+    /// if any.
+    pub fn remove_label(&mut self, r: &Ref) -> Result<Option<String>, Error> {
+        let removed = self.labels.remove(r);
+        if removed.is_some() {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    /// Iterate over all labels.
+    pub fn labels(&self) -> impl Iterator<Item = (&Ref, &str)> {
+        self.labels.iter()
+    }
+
+    /// Export all labels in the BIP 329 format.
+    pub fn export_labels(&self, w: impl io::Write) -> Result<(), Error> {
+        self.labels.export(w).map_err(Error::from)
+    }
+
+    /// Import labels in the BIP 329 format. Returns the number of labels imported.
+    pub fn import_labels(&mut self, r: impl io::BufRead) -> Result<usize, Error> {
+        let imported = self.labels.import(r)?;
+        self.save()?;
+
+        Ok(imported)
+    }
+
+    /// Subscribe to wallet events.
+    pub fn subscribe(&mut self) -> chan::Receiver<Event> {
+        let (sender, receiver) = chan::unbounded();
+        self.subscribers.push(sender);
+
+        receiver
+    }
+
+    /// Derive addresses from the given descriptor, and watch the addresses within the
+    /// gap limit. Adding a descriptor that was already added has no effect.
+    pub fn add_descriptor(&mut self, descriptor: Descriptor, gap_limit: u32) -> Result<(), Error> {
+        if self.keychains.iter().any(|k| k.descriptor == descriptor) {
+            return Ok(());
+        }
+        let keychain = Keychain::new(descriptor, gap_limit)?;
+
+        self.client.watch(keychain.scripts().cloned())?;
+        self.keychains.push(keychain);
+        self.save()
+    }
+
+    /// Compile a spending policy to a descriptor, and add it with [`Wallet::add_descriptor`].
+    /// Returns the compiled descriptor.
+    pub fn register_policy(&mut self, policy: &str, gap_limit: u32) -> Result<Descriptor, Error> {
+        let descriptor = Descriptor::compile(policy)?;
+        self.add_descriptor(descriptor.clone(), gap_limit)?;
+
+        Ok(descriptor)
+    }
+
+    /// Change the gap limit of a descriptor, ie. the number of addresses watched past the
+    /// last used or handed out address. Newly derived addresses are watched.
+    pub fn set_gap_limit(&mut self, descriptor: &Descriptor, gap_limit: u32) -> Result<(), Error> {
+        let keychain = self
+            .keychains
+            .iter_mut()
+            .find(|k| &k.descriptor == descriptor)
+            .ok_or(Error::NoDescriptor)?;
+        let derived = keychain.set_gap_limit(gap_limit)?;
+        let derivation = keychain.derivation();
+
+        if !derived.is_empty() {
+            self.client.watch(derived.iter().cloned())?;
+            self.emit(Event::WindowSlid {
+                descriptor: descriptor.clone(),
+                derivation,
+                derived: derived.len(),
+            });
+        }
+        self.save()
+    }
+
+    /// Get the derivation state of each descriptor.
+    pub fn derivation(&self) -> impl Iterator<Item = (&Descriptor, Derivation)> {
+        self.keychains
+            .iter()
+            .map(|k| (&k.descriptor, k.derivation()))
+    }
+
+    /// Hand out the next unused address of the first descriptor. The address is not handed
+    /// out again, even if it remains unused. Newly derived addresses are watched before
+    /// the address is returned.
+    pub fn next_unused_address(&mut self) -> Result<Address, Error> {
+        let keychain = self.keychains.first_mut().ok_or(Error::NoDescriptor)?;
+        let (address, derived) = keychain.next_unused()?;
+        let descriptor = keychain.descriptor.clone();
+        let derivation = keychain.derivation();
+
+        if !derived.is_empty() {
+            self.client.watch(derived.iter().cloned())?;
+            self.emit(Event::WindowSlid {
+                descriptor,
+                derivation,
+                derived: derived.len(),
+            });
+        }
+        self.save()?;
+
+        Ok(address)
+    }
+
+    /// Mark a script as used, if it was derived from one of our descriptors. Returns the
+    /// scripts of newly derived addresses, which are also added to the watch list.
+    fn mark_used(&mut self, script: &Script) -> Result<Vec<Script>, Error> {
+        let mut derived = Vec::new();
+        let mut events = Vec::new();
+
+        for keychain in &mut self.keychains {
+            let scripts = keychain.mark_used(script)?;
+
+            if !scripts.is_empty() {
+                events.push(Event::WindowSlid {
+                    descriptor: keychain.descriptor.clone(),
+                    derivation: keychain.derivation(),
+                    derived: scripts.len(),
+                });
+                derived.extend(scripts);
+            }
+        }
+        if !derived.is_empty() {
+            self.client.watch(derived.iter().cloned())?;
+        }
+        for event in events {
+            self.emit(event);
+        }
+        Ok(derived)
+    }
+
+    fn emit(&mut self, event: Event) {
+        self.subscribers.retain(|s| s.send(event.clone()).is_ok());
+    }
+
+    fn scripts(&self) -> Vec<Script> {
+        self.addresses
+            .iter()
+            .map(|a| a.script_pubkey())
+            .chain(self.keychains.iter().flat_map(|k| k.scripts().cloned()))
+            .collect()
+    }
+
+    /// Lock one of the wallet's unspent outputs, so that it isn't selected for spending,
+    /// eg. because it carries an inscription, or for privacy reasons. Locks are kept
+    /// until the output is unlocked.
+    ///
+    /// Returns `false` if the output isn't one of the wallet's unspent outputs.
+    pub fn lock_utxo(&mut self, outpoint: OutPoint) -> Result<bool, Error> {
+        if !self.utxos.contains_key(&outpoint) {
+            return Ok(false);
+        }
+        if self.locked.insert(outpoint) {
+            self.save()?;
+        }
+        Ok(true)
+    }
+
+    /// Unlock a previously locked output. Returns `false` if the output wasn't locked.
+    pub fn unlock_utxo(&mut self, outpoint: &OutPoint) -> Result<bool, Error> {
+        if !self.locked.remove(outpoint) {
+            return Ok(false);
+        }
+        self.save()?;
+
+        Ok(true)
+    }
+
+    /// Check whether an output is locked.
+    pub fn is_locked(&self, outpoint: &OutPoint) -> bool {
+        self.locked.contains(outpoint)
+    }
+
+    /// Iterate over the locked outputs.
+    pub fn locked(&self) -> impl Iterator<Item = &OutPoint> {
+        self.locked.iter()
+    }
+
+    /// Iterate over the unspent outputs available for coin selection, ie. all unspent
+    /// outputs that aren't locked, and whose timelocks have matured.
+    pub fn spendable(&self) -> impl Iterator<Item = (&OutPoint, &TxOut)> {
+        self.utxos.iter().filter(move |(outpoint, _)| {
+            !self.locked.contains(outpoint) && self.is_mature(outpoint)
+        })
+    }
+
+    /// Select unspent outputs worth at least `amount`, largest first, for funding a
+    /// transaction. Only [`Wallet::spendable`] outputs are selected.
+    pub fn select_coins(&self, amount: u64) -> Result<Vec<OutPoint>, Error> {
+        select_coins(self.spendable(), amount)
+    }
+
+    /// Iterate over all unspent outputs, along with when they become spendable, for
+    /// timelocked outputs.
+    pub fn utxos(&self) -> impl Iterator<Item = (&OutPoint, &TxOut, Option<Maturity>)> {
+        self.utxos
+            .iter()
+            .map(move |(outpoint, txout)| (outpoint, txout, self.maturity(outpoint)))
+    }
+
+    /// Get the maturity of a timelocked output. Returns `None` if the output isn't one
+    /// of the wallet's timelocked unspent outputs.
+    pub fn maturity(&self, outpoint: &OutPoint) -> Option<Maturity> {
+        self.maturities.get(outpoint).copied()
+    }
+
+    /// Check whether an output can be spent in the next block, as far as timelocks are
+    /// concerned.
+    pub fn is_mature(&self, outpoint: &OutPoint) -> bool {
+        let (height, time) = self.tip;
+
+        !matches!(self.maturities.get(outpoint), Some(m) if !m.is_mature(height, time))
+    }
+
+    /// Update the chain tip, and emit events for outputs that matured.
+    fn update_tip(&mut self, height: Height) -> Result<(), Error> {
+        let time = self.client.median_time(height)?;
+        let (prev_height, prev_time) = std::mem::replace(&mut self.tip, (height, time));
+        let matured = self
+            .maturities
+            .iter()
+            .filter(|(_, m)| !m.is_mature(prev_height, prev_time) && m.is_mature(height, time))
+            .map(|(outpoint, maturity)| Event::UtxoMatured {
+                outpoint: *outpoint,
+                maturity: *maturity,
+                height,
+            })
+            .collect::<Vec<_>>();
+
+        for event in matured {
+            self.emit(event);
+        }
+        Ok(())
+    }
+
+    /// Compute the maturity of new timelocked outputs, and forget spent ones.
+    fn update_maturities(&mut self) -> Result<(), Error> {
+        self.maturities
+            .retain(|outpoint, _| self.utxos.contains_key(outpoint));
+
+        for (outpoint, txout) in self.utxos.iter() {
+            if self.maturities.contains_key(outpoint) {
+                continue;
+            }
+            let Some(keychain) = self
+                .keychains
+                .iter()
+                .find(|k| k.index_of(&txout.script_pubkey).is_some())
+            else {
+                continue;
+            };
+            let Some(height) = self
+                .history
+                .get(&outpoint.txid)
+                .and_then(|entry| entry.height())
+            else {
+                continue;
+            };
+            let time = self.client.median_time(height.saturating_sub(1))?;
+
+            if let Some(maturity) = Maturity::new(&keychain.descriptor, height, time) {
+                self.maturities.insert(*outpoint, maturity);
+            }
+        }
+        Ok(())
+    }
+
+    /// Balance of the wallet's unspent outputs, excluding locked outputs.
     fn balance(&self) -> u64 {
-        self.utxos.balance()
+        self.utxos
+            .iter()
+            .filter(|(outpoint, _)| !self.locked.contains(outpoint))
+            .map(|(_, output)| output.value)
+            .sum()
+    }
+}
+
+/// Select outputs worth at least `amount` from the given outputs, largest first.
+pub fn select_coins<'a>(
+    utxos: impl Iterator<Item = (&'a OutPoint, &'a TxOut)>,
+    amount: u64,
+) -> Result<Vec<OutPoint>, Error> {
+    let mut utxos = utxos.collect::<Vec<_>>();
+    let mut selected = Vec::new();
+    let mut total = 0;
+
+    // Sort by value, then by outpoint, so that the selection is deterministic.
+    utxos.sort_by(|(a, x), (b, y)| y.value.cmp(&x.value).then(a.cmp(b)));
+
+    for (outpoint, output) in utxos {
+        if total >= amount {
+            break;
+        }
+        total += output.value;
+        selected.push(*outpoint);
     }
+    if total < amount {
+        return Err(Error::InsufficientFunds {
+            available: total,
+            needed: amount,
+        });
+    }
+    Ok(selected)
+}
+
+/// Construct a replacement for a transaction, paying `amount` more in fees, taken from the
+/// first output for which `is_change` returns `true`. Inputs are marked as replaceable, and
+/// their signatures are cleared.
+pub fn bump_fee(
+    tx: &Transaction,
+    amount: u64,
+    is_change: impl Fn(&Script) -> bool,
+) -> Result<Transaction, Error> {
+    let mut replacement = tx.clone();
+    let change = replacement
+        .output
+        .iter_mut()
+        .find(|o| is_change(&o.script_pubkey))
+        .ok_or(Error::FeeBump("transaction has no change output"))?;
+
+    change.value = change
+        .value
+        .checked_sub(amount)
+        .filter(|v| *v >= DUST_LIMIT)
+        .ok_or(Error::FeeBump("change output is too small"))?;
+
+    for input in &mut replacement.input {
+        input.sequence = input.sequence.min(MAX_RBF_SEQUENCE);
+        input.script_sig = Script::new();
+        input.witness = Witness::new();
+    }
+    Ok(replacement)
 }
 
 /// The network reactor we're going to use.
 type Reactor = nakamoto_net_poll::Reactor<net::TcpStream, client::Publisher>;
 
-/// Entry point for running the wallet.
-pub fn run(addresses: Vec<Address>, birth: Height) -> Result<(), Error> {
+/// Entry point for running the wallet. If a state path is given, the wallet state is
+/// persisted there, encrypted with the passphrase, if any.
+pub fn run(
+    addresses: Vec<Address>,
+    birth: Height,
+    state: Option<PathBuf>,
+    passphrase: Option<String>,
+) -> Result<(), Error> {
     let cfg = Config {
         listen: vec![], // Don't listen for incoming connections.
         protocol: protocol::Config {
@@ -114,7 +787,15 @@ pub fn run(addresses: Vec<Address>, birth: Height) -> Result<(), Error> {
 
     // Create a new wallet and rescan the chain from the provided `birth` height for
     // matching addresses.
-    let mut wallet = Wallet::new(handle.clone(), addresses);
+    let mut wallet = if let Some(path) = state {
+        Wallet::load(
+            handle.clone(),
+            addresses,
+            store::Store::new(path, passphrase),
+        )?
+    } else {
+        Wallet::new(handle.clone(), addresses)
+    };
 
     // Start the network client in the background.
     thread::spawn(|| client.run(cfg).unwrap());
@@ -124,7 +805,219 @@ pub fn run(addresses: Vec<Address>, birth: Height) -> Result<(), Error> {
     log::info!("Balance is {} sats", wallet.balance());
     log::info!("Rescan complete.");
 
+    wallet.save()?;
+
     handle.shutdown()?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use nakamoto_common::bitcoin::hashes::Hash;
+    use nakamoto_common::bitcoin::{OutPoint, PubkeyHash, TxIn, TxOut, WPubkeyHash};
+
+    fn outpoint(vout: u32) -> OutPoint {
+        OutPoint {
+            txid: Txid::default(),
+            vout,
+        }
+    }
+
+    #[test]
+    fn test_codes() {
+        use nakamoto_common::bitcoin::consensus::encode;
+        use nakamoto_common::bitcoin::util::{address, bip32};
+
+        let io = || io::Error::from(io::ErrorKind::Other);
+        let errors: Vec<Box<dyn Classify>> = vec![
+            Box::new(store::Error::Io(io())),
+            Box::new(store::Error::Decode(encode::Error::ParseFailed(""))),
+            Box::new(store::Error::Format("")),
+            Box::new(store::Error::PassphraseRequired),
+            Box::new(store::Error::Decryption),
+            Box::new(store::Error::Kdf(argon2::Error::SaltTooShort)),
+            Box::new(labels::Error::Io(io())),
+            Box::new(labels::Error::Invalid {
+                line: 1,
+                reason: "",
+            }),
+            Box::new(keychain::Error::Descriptor("")),
+            Box::new(keychain::Error::Bip32(bip32::Error::InvalidChildNumber(0))),
+            Box::new(keychain::Error::Address(address::Error::EmptyBech32Payload)),
+            Box::new(keychain::Error::Miniscript(miniscript::Error::Unexpected(
+                String::new(),
+            ))),
+            Box::new(keychain::Error::Conversion(
+                miniscript::descriptor::ConversionError::Wildcard,
+            )),
+            Box::new(Error::Io(io())),
+            Box::new(Error::NoDescriptor),
+            Box::new(Error::FeeBump("")),
+            Box::new(Error::Psbt(psbt::Error::InvalidMagic)),
+            Box::new(Error::Finalize(
+                miniscript::psbt::Error::InputIdxOutofBounds {
+                    psbt_inp: 0,
+                    index: 1,
+                },
+            )),
+            Box::new(Error::Locked(outpoint(0))),
+            Box::new(Error::InsufficientFunds {
+                available: 0,
+                needed: 1,
+            }),
+        ];
+        let mut codes = HashMap::new();
+
+        for err in &errors {
+            if let Some(other) = codes.insert(err.code(), err) {
+                panic!("{:?} and {:?} share code {}", other, err, err.code());
+            }
+        }
+    }
+
+    #[test]
+    fn test_locked_utxos() {
+        let client = Client::<Reactor>::new().unwrap();
+        let mut wallet = Wallet::new(client.handle(), vec![]);
+
+        for (vout, value) in [(0, 5_000), (1, 3_000), (2, 1_000)] {
+            wallet.utxos.insert(
+                outpoint(vout),
+                TxOut {
+                    value,
+                    script_pubkey: Script::new(),
+                },
+            );
+        }
+        assert!(wallet.lock_utxo(outpoint(0)).unwrap());
+        assert!(!wallet.lock_utxo(outpoint(3)).unwrap());
+        assert!(wallet.is_locked(&outpoint(0)));
+
+        // Locked coins don't count towards the balance, and aren't selected.
+        assert_eq!(wallet.balance(), 4_000);
+        assert_eq!(
+            wallet.select_coins(3_500).unwrap(),
+            vec![outpoint(1), outpoint(2)]
+        );
+        assert!(matches!(
+            wallet.select_coins(4_500),
+            Err(Error::InsufficientFunds {
+                available: 4_000,
+                needed: 4_500
+            })
+        ));
+
+        // Nor can they be spent.
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: outpoint(0),
+                ..TxIn::default()
+            }],
+            output: vec![],
+        };
+        assert!(matches!(wallet.psbt(tx.clone()), Err(Error::Locked(o)) if o == outpoint(0)));
+
+        assert!(wallet.unlock_utxo(&outpoint(0)).unwrap());
+        assert!(!wallet.unlock_utxo(&outpoint(0)).unwrap());
+        assert_eq!(wallet.balance(), 9_000);
+        assert_eq!(wallet.select_coins(3_500).unwrap(), vec![outpoint(0)]);
+        assert!(wallet.psbt(tx).is_ok());
+    }
+
+    #[test]
+    fn test_psbt_utxos() {
+        let client = Client::<Reactor>::new().unwrap();
+        let mut wallet = Wallet::new(client.handle(), vec![]);
+        let scripts = [
+            Script::new_p2pkh(&PubkeyHash::from_inner([1; 20])),
+            Script::new_v0_p2wpkh(&WPubkeyHash::from_inner([2; 20])),
+        ];
+        let prev = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: scripts
+                .iter()
+                .map(|script| TxOut {
+                    value: 1_000,
+                    script_pubkey: script.clone(),
+                })
+                .collect(),
+        };
+        wallet.history.record(
+            &prev,
+            Status::Confirmed { height: 1 },
+            &wallet.utxos,
+            &scripts,
+        );
+        wallet.utxos.apply(&prev, &scripts);
+
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: (0..2)
+                .map(|vout| TxIn {
+                    previous_output: OutPoint::new(prev.txid(), vout),
+                    ..TxIn::default()
+                })
+                .collect(),
+            output: vec![],
+        };
+        let psbt = wallet.psbt(tx).unwrap();
+
+        assert_eq!(psbt.inputs[0].non_witness_utxo, Some(prev.clone()));
+        assert_eq!(psbt.inputs[0].witness_utxo, None);
+        assert_eq!(psbt.inputs[1].non_witness_utxo, None);
+        assert_eq!(psbt.inputs[1].witness_utxo, Some(prev.output[1].clone()));
+    }
+
+    #[test]
+    fn test_bump_fee() {
+        let change = Script::from(vec![0x51]);
+        let payee = Script::from(vec![0x52]);
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Script::from(vec![0x01, 0x01]),
+                sequence: 0xffffffff,
+                witness: Witness::from_vec(vec![vec![0xff]]),
+            }],
+            output: vec![
+                TxOut {
+                    value: 10_000,
+                    script_pubkey: payee.clone(),
+                },
+                TxOut {
+                    value: 2_000,
+                    script_pubkey: change.clone(),
+                },
+            ],
+        };
+
+        let replacement = bump_fee(&tx, 1_000, |s| s == &change).unwrap();
+        assert_eq!(replacement.output[0], tx.output[0]);
+        assert_eq!(replacement.output[1].value, 1_000);
+        assert_eq!(replacement.input[0].sequence, MAX_RBF_SEQUENCE);
+        assert!(replacement.input[0].witness.is_empty());
+        assert_eq!(
+            replacement.input[0].previous_output,
+            tx.input[0].previous_output
+        );
+
+        assert!(matches!(
+            bump_fee(&tx, 1_500, |s| s == &change),
+            Err(Error::FeeBump(_))
+        ));
+        assert!(matches!(
+            bump_fee(&tx, 1_000, |_| false),
+            Err(Error::FeeBump(_))
+        ));
+    }
+}
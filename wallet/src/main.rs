@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use argh::FromArgs;
 
 use nakamoto_common::bitcoin::Address;
@@ -14,6 +16,10 @@ pub struct Options {
     /// wallet genesis height, from which to start scanning
     #[argh(option)]
     pub genesis: Height,
+    /// file in which to persist the wallet state. The state is encrypted if a
+    /// passphrase is set in the `NAKAMOTO_WALLET_PASSPHRASE` environment variable
+    #[argh(option)]
+    pub state: Option<PathBuf>,
     /// enable debug logging
     #[argh(switch)]
     pub debug: bool,
@@ -40,7 +46,9 @@ fn main() {
         std::process::exit(1);
     }
 
-    if let Err(err) = nakamoto_wallet::run(opts.addresses, opts.genesis) {
+    let passphrase = std::env::var("NAKAMOTO_WALLET_PASSPHRASE").ok();
+
+    if let Err(err) = nakamoto_wallet::run(opts.addresses, opts.genesis, opts.state, passphrase) {
         log::error!("Fatal: {}", err);
         std::process::exit(1);
     }
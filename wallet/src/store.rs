@@ -0,0 +1,577 @@
+//! Wallet state storage.
+//!
+//! The wallet state, ie. the watch list, descriptors, UTXO set, locked coins, transaction
+//! history and labels, is stored in a single file. Since this reveals the wallet's
+//! contents to anyone with access to the file, it can optionally be encrypted at rest with
+//! XChaCha20-Poly1305, using a key derived from a user passphrase with Argon2id.
+//!
+//! The file starts with a short header, made of a magic string, the format version and
+//! whether the file is encrypted. Encrypted files then store the KDF salt and the nonce,
+//! followed by the ciphertext. The header is authenticated along with the ciphertext.
+//!
+//! Since key derivation is deliberately slow, the key is only derived once per session:
+//! it is kept by the [`Store`], and further saves reuse its salt with a fresh nonce.
+//!
+//! Files written with older versions of the format are migrated when loaded: version 1
+//! only stored confirmed transactions, and version 2 stored transaction labels in the
+//! history, and had no other labels, locked coins or descriptors.
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use thiserror::Error;
+
+use nakamoto_client::spv::utxos::Utxos;
+use nakamoto_common::bitcoin::consensus::encode::{self, Decodable, Encodable, VarInt};
+use nakamoto_common::bitcoin::{Address, OutPoint, Script, Transaction, TxOut};
+use nakamoto_common::block::Height;
+use nakamoto_common::error::{self, Classify};
+
+use crate::history::{History, Status};
+use crate::keychain::Keychain;
+use crate::labels::{Labels, Ref};
+
+/// Magic bytes identifying a wallet state file.
+const MAGIC: &[u8; 4] = b"NKWS";
+/// State file format version.
+const VERSION: u8 = 3;
+/// Size of the KDF salt, in bytes.
+const SALT_SIZE: usize = 16;
+/// Size of the XChaCha20-Poly1305 nonce, in bytes.
+const NONCE_SIZE: usize = 24;
+/// Size of the file header, in bytes.
+const HEADER_SIZE: usize = MAGIC.len() + 2;
+
+/// A wallet storage error.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// An I/O error.
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    /// The state could not be decoded.
+    #[error("decoding error: {0}")]
+    Decode(#[from] encode::Error),
+    /// The file is not a valid wallet state file.
+    #[error("invalid state file: {0}")]
+    Format(&'static str),
+    /// The state is encrypted, but no passphrase was given.
+    #[error("wallet state is encrypted, but no passphrase was given")]
+    PassphraseRequired,
+    /// The state could not be decrypted, most likely due to a wrong passphrase.
+    #[error("wallet state could not be decrypted: wrong passphrase or corrupted file")]
+    Decryption,
+    /// The encryption key could not be derived from the passphrase.
+    #[error("key derivation error: {0}")]
+    Kdf(argon2::Error),
+}
+
+impl Classify for Error {
+    fn code(&self) -> u32 {
+        match self {
+            Self::Io(_) => 2017,
+            Self::Decode(_) => 2018,
+            Self::Format(_) => 2019,
+            Self::Decryption => 2020,
+            Self::PassphraseRequired => 4019,
+            Self::Kdf(_) => 5005,
+        }
+    }
+
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Io(err) => error::is_transient_io(err),
+            _ => false,
+        }
+    }
+}
+
+/// Persisted wallet state.
+#[derive(Debug, Clone)]
+pub struct State {
+    /// Addresses being watched.
+    pub addresses: HashSet<Address>,
+    /// Unspent outputs belonging to the wallet.
+    pub utxos: Utxos,
+    /// Outputs that must not be spent.
+    pub locked: HashSet<OutPoint>,
+    /// Transactions relevant to the wallet.
+    pub history: History,
+    /// Labels attached to addresses, transactions and outputs.
+    pub labels: Labels,
+    /// Descriptors from which addresses are derived, with their derivation state.
+    pub keychains: Vec<Keychain>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            addresses: HashSet::new(),
+            utxos: Utxos::new(),
+            locked: HashSet::new(),
+            history: History::new(),
+            labels: Labels::new(),
+            keychains: Vec::new(),
+        }
+    }
+}
+
+impl Encodable for State {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, io::Error> {
+        let mut len = VarInt(self.addresses.len() as u64).consensus_encode(&mut w)?;
+        for addr in &self.addresses {
+            len += addr.to_string().consensus_encode(&mut w)?;
+        }
+        len += VarInt(self.utxos.len() as u64).consensus_encode(&mut w)?;
+        for (outpoint, output) in self.utxos.iter() {
+            len += outpoint.consensus_encode(&mut w)?;
+            len += output.consensus_encode(&mut w)?;
+        }
+        len += self.history.consensus_encode(&mut w)?;
+        len += self.labels.consensus_encode(&mut w)?;
+
+        let mut locked = self.locked.iter().collect::<Vec<_>>();
+        locked.sort();
+
+        len += VarInt(locked.len() as u64).consensus_encode(&mut w)?;
+        for outpoint in locked {
+            len += outpoint.consensus_encode(&mut w)?;
+        }
+        len += VarInt(self.keychains.len() as u64).consensus_encode(&mut w)?;
+        for keychain in &self.keychains {
+            len += keychain.consensus_encode(&mut w)?;
+        }
+
+        Ok(len)
+    }
+}
+
+impl State {
+    /// Decode state saved with the given format version.
+    fn decode(version: u8, bytes: &[u8]) -> Result<Self, Error> {
+        match version {
+            VERSION => encode::deserialize(bytes).map_err(Error::from),
+            2 => {
+                let mut d = bytes;
+                let mut state = State::decode_watchlist(&mut d)?;
+                let (history, labels) = History::decode_labeled(&mut d)?;
+
+                for (txid, label) in labels {
+                    state.labels.set(Ref::Tx(txid), label);
+                }
+                state.history = history;
+
+                if !d.is_empty() {
+                    return Err(Error::Format("trailing data"));
+                }
+                Ok(state)
+            }
+            1 => {
+                let mut d = bytes;
+                let mut state = State::decode_watchlist(&mut d)?;
+                let scripts = state
+                    .addresses
+                    .iter()
+                    .map(|a| a.script_pubkey())
+                    .collect::<Vec<Script>>();
+                let mut utxos = Utxos::new();
+
+                // Replay the confirmed transactions to compute their effect on the wallet.
+                for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+                    let height = Height::consensus_decode(&mut d)?;
+                    let tx = Transaction::consensus_decode(&mut d)?;
+
+                    state
+                        .history
+                        .record(&tx, Status::Confirmed { height }, &utxos, &scripts);
+                    utxos.apply(&tx, &scripts);
+                }
+                if !d.is_empty() {
+                    return Err(Error::Format("trailing data"));
+                }
+                Ok(state)
+            }
+            _ => Err(Error::Format("unsupported version")),
+        }
+    }
+
+    /// Decode the addresses and UTXOs, which are common to all format versions.
+    fn decode_watchlist<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let mut state = State::default();
+
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            let addr = String::consensus_decode(&mut d)?;
+            let addr = Address::from_str(&addr)
+                .map_err(|_| encode::Error::ParseFailed("invalid address"))?;
+
+            state.addresses.insert(addr);
+        }
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            let outpoint = OutPoint::consensus_decode(&mut d)?;
+            let output = TxOut::consensus_decode(&mut d)?;
+
+            state.utxos.insert(outpoint, output);
+        }
+        Ok(state)
+    }
+}
+
+impl Decodable for State {
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let mut state = State::decode_watchlist(&mut d)?;
+        state.history = History::consensus_decode(&mut d)?;
+        state.labels = Labels::consensus_decode(&mut d)?;
+
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            state.locked.insert(OutPoint::consensus_decode(&mut d)?);
+        }
+        for _ in 0..VarInt::consensus_decode(&mut d)?.0 {
+            state.keychains.push(Keychain::consensus_decode(&mut d)?);
+        }
+
+        Ok(state)
+    }
+}
+
+/// Encryption key derived from a passphrase, with the salt it was derived from.
+#[derive(Clone)]
+struct Key {
+    salt: [u8; SALT_SIZE],
+    cipher: XChaCha20Poly1305,
+}
+
+impl Key {
+    /// Derive a key from a passphrase and salt.
+    fn derive(passphrase: &str, salt: [u8; SALT_SIZE]) -> Result<Self, Error> {
+        let mut key = [0; 32];
+
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(Error::Kdf)?;
+
+        Ok(Self {
+            salt,
+            cipher: XChaCha20Poly1305::new(&key.into()),
+        })
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key").finish_non_exhaustive()
+    }
+}
+
+/// Wallet state store, backed by a single file.
+#[derive(Debug, Clone)]
+pub struct Store {
+    path: PathBuf,
+    passphrase: Option<String>,
+    /// Key derived from the passphrase, once it is needed. Shared between clones.
+    key: Arc<Mutex<Option<Key>>>,
+}
+
+impl Store {
+    /// Create a new store at the given path. If a passphrase is given, the state is
+    /// encrypted when saved.
+    pub fn new(path: impl AsRef<Path>, passphrase: Option<String>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            passphrase,
+            key: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Load the wallet state. Returns an empty state if the file doesn't exist.
+    ///
+    /// Unencrypted state can be loaded even if a passphrase is set, in which case it will be
+    /// encrypted the next time it is saved.
+    pub fn load(&self) -> Result<State, Error> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(State::default()),
+            Err(err) => return Err(err.into()),
+        };
+        if bytes.len() < HEADER_SIZE || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(Error::Format("unknown file format"));
+        }
+        let (header, body) = bytes.split_at(HEADER_SIZE);
+
+        let version = header[MAGIC.len()];
+
+        match header[MAGIC.len() + 1] {
+            0 => State::decode(version, body),
+            1 => {
+                let passphrase = self.passphrase.as_ref().ok_or(Error::PassphraseRequired)?;
+
+                if body.len() < SALT_SIZE + NONCE_SIZE {
+                    return Err(Error::Format("file is truncated"));
+                }
+                let (salt, body) = body.split_at(SALT_SIZE);
+                let (nonce, ciphertext) = body.split_at(NONCE_SIZE);
+                let key = self.key(passphrase, Some(salt.try_into().expect("salt is sized")))?;
+                let plaintext = key
+                    .cipher
+                    .decrypt(
+                        XNonce::from_slice(nonce),
+                        Payload {
+                            msg: ciphertext,
+                            aad: header,
+                        },
+                    )
+                    .map_err(|_| Error::Decryption)?;
+
+                State::decode(version, &plaintext)
+            }
+            _ => Err(Error::Format("unknown encryption flag")),
+        }
+    }
+
+    /// Save the wallet state, replacing any previously saved state.
+    pub fn save(&self, state: &State) -> Result<(), Error> {
+        let plaintext = encode::serialize(state);
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + plaintext.len());
+
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+
+        if let Some(passphrase) = &self.passphrase {
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let key = self.key(passphrase, None)?;
+
+            bytes.push(1);
+
+            let ciphertext = key
+                .cipher
+                .encrypt(
+                    &nonce,
+                    Payload {
+                        msg: &plaintext,
+                        aad: &bytes,
+                    },
+                )
+                .map_err(|_| Error::Format("state is too large to encrypt"))?;
+
+            bytes.extend_from_slice(&key.salt);
+            bytes.extend_from_slice(&nonce);
+            bytes.extend_from_slice(&ciphertext);
+        } else {
+            bytes.push(0);
+            bytes.extend_from_slice(&plaintext);
+        }
+
+        // Replace the file atomically, so that the state is never left half-written.
+        nakamoto_common::fs::atomic_write(&self.path, &bytes)?;
+
+        Ok(())
+    }
+
+    /// Get the key derived from the passphrase. The key is derived the first time, and
+    /// again only if it has to match a different salt. If no salt is given, the salt of
+    /// the current key is used, or a new one is drawn.
+    fn key(&self, passphrase: &str, salt: Option<[u8; SALT_SIZE]>) -> Result<Key, Error> {
+        let mut cached = self.key.lock().unwrap();
+
+        match (cached.as_ref(), salt) {
+            (Some(key), None) => return Ok(key.clone()),
+            (Some(key), Some(salt)) if key.salt == salt => return Ok(key.clone()),
+            _ => {}
+        }
+        let salt = salt.unwrap_or_else(|| {
+            let mut salt = [0; SALT_SIZE];
+            OsRng.fill_bytes(&mut salt);
+            salt
+        });
+        let key = Key::derive(passphrase, salt)?;
+        *cached = Some(key.clone());
+
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use nakamoto_common::bitcoin::blockdata::constants::genesis_block;
+    use nakamoto_common::bitcoin::Network;
+
+    fn state() -> State {
+        let tx = genesis_block(Network::Bitcoin).txdata[0].clone();
+        let addr = Address::from_str("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq").unwrap();
+        let mut state = State::default();
+
+        state.addresses.insert(addr);
+        state.utxos.insert(
+            OutPoint {
+                txid: tx.txid(),
+                vout: 0,
+            },
+            tx.output[0].clone(),
+        );
+        state.history.record(
+            &tx,
+            Status::Confirmed { height: 0 },
+            &Utxos::new(),
+            &[tx.output[0].script_pubkey.clone()],
+        );
+        state.labels.set(Ref::Tx(tx.txid()), "genesis");
+        state.locked.insert(OutPoint {
+            txid: tx.txid(),
+            vout: 0,
+        });
+        state
+    }
+
+    #[test]
+    fn test_save_load_encrypted() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("wallet.db");
+        let state = state();
+
+        let store = Store::new(&path, Some(String::from("hunter2")));
+        store.save(&state).unwrap();
+
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.addresses, state.addresses);
+        assert_eq!(*loaded.utxos, *state.utxos);
+        assert_eq!(loaded.history, state.history);
+        assert_eq!(loaded.labels, state.labels);
+        assert_eq!(loaded.locked, state.locked);
+
+        // The wallet contents can't be read off the file.
+        let bytes = fs::read(&path).unwrap();
+        let entry = state.history.iter().next().unwrap();
+        let script = entry.transaction.output[0].script_pubkey.as_bytes();
+        assert!(!bytes.windows(script.len()).any(|w| w == script));
+
+        assert!(matches!(
+            Store::new(&path, None).load(),
+            Err(Error::PassphraseRequired)
+        ));
+        assert!(matches!(
+            Store::new(&path, Some(String::from("hunter3"))).load(),
+            Err(Error::Decryption)
+        ));
+    }
+
+    #[test]
+    fn test_key_reused() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("wallet.db");
+        let salt = || fs::read(&path).unwrap()[HEADER_SIZE..HEADER_SIZE + SALT_SIZE].to_vec();
+        let store = Store::new(&path, Some(String::from("hunter2")));
+
+        // The key, and thus the salt, is kept across saves.
+        store.save(&state()).unwrap();
+        let first = salt();
+        store.save(&state()).unwrap();
+        assert_eq!(salt(), first);
+
+        // A new session uses the key of the file it loaded.
+        let store = Store::new(&path, Some(String::from("hunter2")));
+        store.load().unwrap();
+        store.save(&state()).unwrap();
+        assert_eq!(salt(), first);
+        assert_eq!(store.load().unwrap().locked, state().locked);
+    }
+
+    #[test]
+    fn test_save_load_plaintext() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("wallet.db");
+        let state = state();
+
+        Store::new(&path, None).save(&state).unwrap();
+
+        // Plaintext state can still be loaded when a passphrase is set.
+        let loaded = Store::new(&path, Some(String::from("hunter2")))
+            .load()
+            .unwrap();
+        assert_eq!(loaded.history, state.history);
+
+        assert!(Store::new(tmp.path().join("none.db"), None)
+            .load()
+            .unwrap()
+            .addresses
+            .is_empty());
+    }
+
+    #[test]
+    fn test_load_v1() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("wallet.db");
+        let addr = Address::from_str("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq").unwrap();
+        let mut tx = genesis_block(Network::Bitcoin).txdata[0].clone();
+        tx.output[0].script_pubkey = addr.script_pubkey();
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend([1, 0]);
+        bytes.extend(encode::serialize(&VarInt(1)));
+        bytes.extend(encode::serialize(&addr.to_string()));
+        bytes.extend(encode::serialize(&VarInt(0)));
+        bytes.extend(encode::serialize(&VarInt(1)));
+        bytes.extend(encode::serialize(&42u64));
+        bytes.extend(encode::serialize(&tx));
+        fs::write(&path, &bytes).unwrap();
+
+        let store = Store::new(&path, None);
+        let state = store.load().unwrap();
+        let entry = state.history.get(&tx.txid()).unwrap();
+
+        assert!(state.addresses.contains(&addr));
+        assert_eq!(entry.height(), Some(42));
+        assert_eq!(entry.amount, tx.output[0].value);
+
+        // The state is migrated to the current version when saved.
+        store.save(&state).unwrap();
+        assert_eq!(fs::read(&path).unwrap()[MAGIC.len()], VERSION);
+        assert_eq!(store.load().unwrap().history, state.history);
+    }
+
+    #[test]
+    fn test_load_v2() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("wallet.db");
+        let addr = Address::from_str("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq").unwrap();
+        let tx = genesis_block(Network::Bitcoin).txdata[0].clone();
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend([2, 0]);
+        bytes.extend(encode::serialize(&VarInt(1)));
+        bytes.extend(encode::serialize(&addr.to_string()));
+        bytes.extend(encode::serialize(&VarInt(0)));
+        // A confirmed, incoming transaction without fee, labeled "genesis".
+        bytes.extend(encode::serialize(&VarInt(1)));
+        bytes.extend(encode::serialize(&tx));
+        bytes.extend(encode::serialize(&1u8));
+        bytes.extend(encode::serialize(&42u64));
+        bytes.extend(encode::serialize(&0u8));
+        bytes.extend(encode::serialize(&tx.output[0].value));
+        bytes.extend(encode::serialize(&0u8));
+        bytes.extend(encode::serialize(&1u8));
+        bytes.extend(encode::serialize(&String::from("genesis")));
+        fs::write(&path, &bytes).unwrap();
+
+        let store = Store::new(&path, None);
+        let state = store.load().unwrap();
+        let entry = state.history.get(&tx.txid()).unwrap();
+
+        assert!(state.addresses.contains(&addr));
+        assert_eq!(entry.height(), Some(42));
+        assert_eq!(entry.fee, None);
+        assert_eq!(state.labels.get(&Ref::Tx(tx.txid())), Some("genesis"));
+
+        // The state is migrated to the current version when saved.
+        store.save(&state).unwrap();
+        assert_eq!(fs::read(&path).unwrap()[MAGIC.len()], VERSION);
+        assert_eq!(store.load().unwrap().labels, state.labels);
+    }
+}
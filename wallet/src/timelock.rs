@@ -0,0 +1,174 @@
+//! Timelock maturity of wallet outputs.
+//!
+//! Outputs derived from miniscript descriptors may only be spendable after a relative
+//! timelock (`older`, see BIP 68 and BIP 112), or an absolute timelock (`after`, see
+//! BIP 65), expressed either in blocks or in seconds. Time-based locks use the median time
+//! past of the header chain as their clock (BIP 113).
+//!
+//! When a policy has several spending paths, the output matures as soon as the earliest
+//! path can be taken.
+use miniscript::descriptor::DescriptorPublicKey;
+use miniscript::policy::{Liftable as _, Semantic};
+
+use nakamoto_common::block::{BlockTime, Height};
+
+use crate::keychain::Descriptor;
+
+/// Relative timelocks with this flag set are expressed in units of 512 seconds.
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+/// Mask of the value of relative timelocks.
+const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000ffff;
+/// Absolute timelocks below this value are block heights, and timestamps otherwise.
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+/// When an output becomes spendable.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Maturity {
+    /// Height of the first block in which the output can be spent.
+    pub height: Height,
+    /// Minimum median time past of the block preceding the one in which the output is spent.
+    pub time: BlockTime,
+}
+
+impl Maturity {
+    /// Compute the maturity of an output derived from the given descriptor, confirmed at
+    /// the given height. The `confirmed_time` is the median time past of the block
+    /// preceding the one the output was confirmed in, which time-based relative locks are
+    /// measured from.
+    ///
+    /// Returns `None` if the descriptor has no timelocks.
+    pub fn new(
+        descriptor: &Descriptor,
+        confirmed: Height,
+        confirmed_time: BlockTime,
+    ) -> Option<Self> {
+        let policy = match descriptor {
+            Descriptor::Miniscript(descriptor) => descriptor.lift().ok()?,
+            Descriptor::Single { .. } => return None,
+        };
+        let relative = policy.relative_timelocks();
+        let absolute = policy.absolute_timelocks();
+
+        if relative.is_empty() && absolute.is_empty() {
+            return None;
+        }
+        let mut earliest = None;
+
+        for sequence in std::iter::once(0).chain(relative) {
+            for locktime in std::iter::once(0).chain(absolute.iter().copied()) {
+                if !satisfiable(&policy, sequence, locktime) {
+                    continue;
+                }
+                let mut maturity = Self {
+                    height: confirmed,
+                    time: 0,
+                };
+                let value = sequence & SEQUENCE_LOCKTIME_MASK;
+
+                if sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+                    maturity.time = confirmed_time + value * 512;
+                } else {
+                    maturity.height = confirmed + value as Height;
+                }
+                if locktime >= LOCKTIME_THRESHOLD {
+                    maturity.time = maturity.time.max(locktime + 1);
+                } else if locktime > 0 {
+                    maturity.height = maturity.height.max(locktime as Height + 1);
+                }
+                earliest = Some(earliest.map_or(maturity, |e: Self| e.min(maturity)));
+            }
+        }
+        earliest
+    }
+
+    /// Check whether the output can be spent in the block following the given tip, given
+    /// the tip's median time past.
+    pub fn is_mature(&self, tip: Height, tip_time: BlockTime) -> bool {
+        tip + 1 >= self.height && tip_time >= self.time
+    }
+}
+
+/// Check whether a policy can be satisfied by an input with the given sequence number,
+/// in a transaction with the given lock time. Locks of a different type than the given
+/// ones, eg. time-based locks when a height is given, can't be satisfied.
+fn satisfiable(policy: &Semantic<DescriptorPublicKey>, sequence: u32, locktime: u32) -> bool {
+    match policy {
+        Semantic::Unsatisfiable => false,
+        Semantic::Older(t) => {
+            (t & SEQUENCE_LOCKTIME_TYPE_FLAG) == (sequence & SEQUENCE_LOCKTIME_TYPE_FLAG)
+                && (t & SEQUENCE_LOCKTIME_MASK) <= (sequence & SEQUENCE_LOCKTIME_MASK)
+        }
+        Semantic::After(t) => {
+            (*t >= LOCKTIME_THRESHOLD) == (locktime >= LOCKTIME_THRESHOLD) && *t <= locktime
+        }
+        Semantic::Threshold(k, subs) => {
+            subs.iter()
+                .filter(|sub| satisfiable(sub, sequence, locktime))
+                .count()
+                >= *k
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Master key of BIP 32 test vector 1.
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    #[test]
+    fn test_maturity() {
+        let compile = |policy: &str| Descriptor::compile(&policy.replace("X", XPUB)).unwrap();
+        let maturity = |policy| Maturity::new(&compile(policy), 100, 1_600_000_000);
+
+        assert_eq!(maturity("pk(X/0/*)"), None);
+        assert_eq!(
+            maturity("and(pk(X/0/*),older(144))"),
+            Some(Maturity {
+                height: 244,
+                time: 0
+            })
+        );
+        // 4 units of 512 seconds.
+        assert_eq!(
+            maturity("and(pk(X/0/*),older(4194308))"),
+            Some(Maturity {
+                height: 100,
+                time: 1_600_002_048
+            })
+        );
+        assert_eq!(
+            maturity("and(pk(X/0/*),after(700000))"),
+            Some(Maturity {
+                height: 700_001,
+                time: 0
+            })
+        );
+        // The earliest path is taken.
+        assert_eq!(
+            maturity("or(and(pk(X/0/*),older(144)),and(pk(X/1/*),older(6)))"),
+            Some(Maturity {
+                height: 106,
+                time: 0
+            })
+        );
+        // Outputs that can be spent right away with one of the keys are mature.
+        assert_eq!(
+            maturity("or(pk(X/0/*),and(pk(X/1/*),older(144)))"),
+            Some(Maturity {
+                height: 100,
+                time: 0
+            })
+        );
+
+        let m = maturity("and(pk(X/0/*),older(144))").unwrap();
+        assert!(!m.is_mature(242, 0));
+        assert!(m.is_mature(243, 0));
+
+        let m = maturity("and(pk(X/0/*),after(1700000000))").unwrap();
+        assert!(!m.is_mature(200, 1_700_000_000));
+        assert!(m.is_mature(200, 1_700_000_001));
+    }
+}